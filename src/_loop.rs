@@ -10,7 +10,7 @@ fn hadron_loop() -> Result<(), Box<dyn std::error::Error>> {
         .with_min_inner_size(window_inner_size)
         .with_max_inner_size(window_inner_size).build(&eventloop)?;
     
-    let window = Rc::new(window);
+    let window = Arc::new(window);
     
     let mut app: App = App::new(window.clone());
 
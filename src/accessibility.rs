@@ -0,0 +1,251 @@
+//!
+//! Accessibility settings
+//!
+//! Options a player sets once and expects every screen to respect: the UI scale, a colorblind friendly palette for
+//! debug visualizations, reduced flashing and their remapped input bindings. They're the `accessibility` config
+//! section, so they come from the config cascade like any other setting, and `update` writes changes to the runtime
+//! layer and saves them to the config file so they persist between runs. The UI layer reads them with `settings` and
+//! watches the `accessibility` keys of `ConfigChanged` to pick up changes
+//!
+//! With reduced flashing, full screen effects such as flashes, bloom and exposure adaptation should move their
+//! intensity through a `FlashLimiter`, which caps how fast it can change
+//!
+
+use std::{collections::BTreeMap, time::Duration};
+
+use serde::{Serialize, Deserialize};
+use winit::event::VirtualKeyCode;
+
+use crate::config::{self, ConfigError, ConfigSection};
+
+/// Smallest and largest UI scale, beyond which layouts stop fitting on screen or become unreadable
+const UI_SCALE_RANGE: (f32, f32) = (0.5, 3.0);
+
+/// Accessibility options from the `accessibility` config section
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct AccessibilityConfig {
+    /// Multiplies the size of UI elements and text, on top of the window's scale factor
+    pub ui_scale: f32,
+    pub palette: DebugPalette,
+    /// Limits how fast full screen effects can change brightness, for photosensitive players
+    pub reduced_flashing: bool,
+    /// Largest change per second of a full screen effect's intensity, from 0 to 1, with reduced flashing
+    pub max_intensity_rate: f32,
+    /// Keys bound to input actions by winit key name, e.g. `"F10"`, replacing the action's default keys
+    pub bindings: BTreeMap<String, Vec<String>>,
+}
+
+/// Colors debug visualizations are drawn in
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum DebugPalette {
+    #[default]
+    Standard,
+    /// For red-green color blindness with weak green cones, the most common kind
+    Deuteranopia,
+    /// For red-green color blindness with weak red cones, reds look dark
+    Protanopia,
+    /// For blue-yellow color blindness
+    Tritanopia,
+    /// Saturated colors that stand out against any background
+    HighContrast,
+}
+
+/// What a debug color means, the palette decides what it looks like
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DebugColor {
+    Good,
+    Warning,
+    Error,
+    Info,
+    Highlight,
+    Neutral,
+}
+
+/// Rate limits the intensity of a full screen effect between frames while reduced flashing is on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlashLimiter {
+    intensity: f32,
+    /// Change per second allowed, `None` without reduced flashing
+    max_rate: Option<f32>,
+}
+
+// Impls
+
+impl Default for AccessibilityConfig {
+    fn default() -> Self {
+        AccessibilityConfig {
+            ui_scale: 1.0,
+            palette: DebugPalette::Standard,
+            reduced_flashing: false,
+            max_intensity_rate: 1.0,
+            bindings: BTreeMap::new(),
+        }
+    }
+}
+
+impl ConfigSection for AccessibilityConfig {
+    const NAME: &'static str = "accessibility";
+}
+
+impl AccessibilityConfig {
+    /// The UI scale clamped to a usable range
+    pub fn ui_scale(&self) -> f32 {
+        let (min, max) = UI_SCALE_RANGE;
+        if self.ui_scale.is_finite() { self.ui_scale.clamp(min, max) } else { 1.0 }
+    }
+
+    /// Pixels per logical UI unit on a window with `scale_factor`
+    pub fn ui_scale_factor(&self, scale_factor: f64) -> f32 {
+        scale_factor as f32 * self.ui_scale()
+    }
+
+    /// The keys bound to `action`, `None` if it keeps its defaults
+    pub fn binding(&self, action: &str) -> Option<&[String]> {
+        self.bindings.get(action).map(Vec::as_slice)
+    }
+
+    /// Whether `key` triggers `action`, going by `defaults` unless the action was remapped
+    pub fn is_bound(&self, action: &str, key: VirtualKeyCode, defaults: &[VirtualKeyCode]) -> bool {
+        match self.binding(action) {
+            Some(keys) => keys.iter().any(|name| *name == format!("{:?}", key)),
+            None => defaults.contains(&key),
+        }
+    }
+
+    /// Binds `keys` to `action` in place of its defaults
+    pub fn bind(&mut self, action: &str, keys: &[VirtualKeyCode]) {
+        self.bindings.insert(String::from(action), keys.iter().map(|key| format!("{:?}", key)).collect());
+    }
+
+    /// Goes back to `action`'s default keys
+    pub fn unbind(&mut self, action: &str) {
+        self.bindings.remove(action);
+    }
+}
+
+impl DebugPalette {
+    /// The color drawn for `color`, as 8 bit RGBA
+    pub fn color(&self, color: DebugColor) -> [u8; 4] {
+        let [r, g, b] = match (self, color) {
+            (_, DebugColor::Neutral) => [136, 136, 136],
+            (DebugPalette::Standard, DebugColor::Good) => [0, 200, 0],
+            (DebugPalette::Standard, DebugColor::Warning) => [255, 200, 0],
+            (DebugPalette::Standard, DebugColor::Error) => [255, 0, 0],
+            (DebugPalette::Standard, DebugColor::Info) => [0, 120, 255],
+            (DebugPalette::Standard, DebugColor::Highlight) => [255, 0, 255],
+            // Okabe-Ito, telling good from bad by blue against orange rather than green against red
+            (DebugPalette::Deuteranopia, DebugColor::Good) => [0, 114, 178],
+            (DebugPalette::Deuteranopia, DebugColor::Warning) => [240, 228, 66],
+            (DebugPalette::Deuteranopia, DebugColor::Error) => [213, 94, 0],
+            (DebugPalette::Deuteranopia, DebugColor::Info) => [86, 180, 233],
+            (DebugPalette::Deuteranopia, DebugColor::Highlight) => [204, 121, 167],
+            // As above with a brighter error, deep reds fade towards black
+            (DebugPalette::Protanopia, DebugColor::Good) => [0, 114, 178],
+            (DebugPalette::Protanopia, DebugColor::Warning) => [240, 228, 66],
+            (DebugPalette::Protanopia, DebugColor::Error) => [230, 159, 0],
+            (DebugPalette::Protanopia, DebugColor::Info) => [86, 180, 233],
+            (DebugPalette::Protanopia, DebugColor::Highlight) => [204, 121, 167],
+            // Red against teal, avoiding blue against green and yellow against violet
+            (DebugPalette::Tritanopia, DebugColor::Good) => [0, 158, 150],
+            (DebugPalette::Tritanopia, DebugColor::Warning) => [255, 130, 170],
+            (DebugPalette::Tritanopia, DebugColor::Error) => [220, 0, 0],
+            (DebugPalette::Tritanopia, DebugColor::Info) => [0, 80, 90],
+            (DebugPalette::Tritanopia, DebugColor::Highlight) => [140, 0, 40],
+            (DebugPalette::HighContrast, DebugColor::Good) => [255, 255, 255],
+            (DebugPalette::HighContrast, DebugColor::Warning) => [255, 255, 0],
+            (DebugPalette::HighContrast, DebugColor::Error) => [255, 0, 255],
+            (DebugPalette::HighContrast, DebugColor::Info) => [0, 255, 255],
+            (DebugPalette::HighContrast, DebugColor::Highlight) => [255, 128, 0],
+        };
+        [r, g, b, 255]
+    }
+
+    /// The color drawn for `color`, as RGBA from 0 to 1 for shaders
+    pub fn color_f32(&self, color: DebugColor) -> [f32; 4] {
+        self.color(color).map(|channel| channel as f32 / 255.0)
+    }
+}
+
+impl FlashLimiter {
+    /// Starts at `intensity`, limited according to `settings`
+    pub fn new(intensity: f32, settings: &AccessibilityConfig) -> Self {
+        FlashLimiter { intensity, max_rate: settings.reduced_flashing.then_some(settings.max_intensity_rate.max(0.0)) }
+    }
+
+    pub fn intensity(&self) -> f32 {
+        self.intensity
+    }
+
+    /// Moves the intensity towards `target` as far as the limit allows over `delta`, returning the new intensity
+    pub fn update(&mut self, target: f32, delta: Duration) -> f32 {
+        self.intensity = match self.max_rate {
+            Some(rate) => {
+                let step = rate * delta.as_secs_f32();
+                self.intensity + (target - self.intensity).clamp(-step, step)
+            },
+            None => target,
+        };
+        self.intensity
+    }
+}
+
+/// The current accessibility settings
+pub fn settings() -> AccessibilityConfig {
+    config::get().section()
+}
+
+/// Changes the settings with `f` and saves them to the config file. Without a config file the change still applies
+/// for this run
+pub fn update(f: impl FnOnce(&mut AccessibilityConfig)) -> Result<AccessibilityConfig, ConfigError> {
+    let mut settings = settings();
+    f(&mut settings);
+    config::get().set(AccessibilityConfig::NAME, &settings)?;
+    match config::get().save() {
+        Ok(()) | Err(ConfigError::NoFile) => Ok(settings),
+        Err(err) => Err(err),
+    }
+}
+
+/// Whether `key` triggers `action` with the player's bindings, see `AccessibilityConfig::is_bound`
+pub fn is_bound(action: &str, key: VirtualKeyCode, defaults: &[VirtualKeyCode]) -> bool {
+    settings().is_bound(action, key, defaults)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_fill_in_defaults_and_remap_keys() {
+        let mut settings: AccessibilityConfig = serde_json::from_str(r#"{ "ui_scale": 9.0, "palette": "deuteranopia" }"#).unwrap();
+        assert_eq!(settings.ui_scale(), 3.0);
+        assert_eq!(settings.ui_scale_factor(2.0), 6.0);
+        assert!(!settings.reduced_flashing);
+
+        // Status colors stay distinct in every palette
+        for palette in [DebugPalette::Standard, DebugPalette::Deuteranopia, DebugPalette::Protanopia, DebugPalette::Tritanopia, DebugPalette::HighContrast] {
+            let status = [DebugColor::Good, DebugColor::Warning, DebugColor::Error].map(|color| palette.color(color));
+            assert!(status[0] != status[1] && status[1] != status[2] && status[0] != status[2]);
+        }
+
+        assert!(settings.is_bound("jump", VirtualKeyCode::Space, &[VirtualKeyCode::Space]));
+        settings.bind("jump", &[VirtualKeyCode::J]);
+        assert!(!settings.is_bound("jump", VirtualKeyCode::Space, &[VirtualKeyCode::Space]));
+        assert!(settings.is_bound("jump", VirtualKeyCode::J, &[VirtualKeyCode::Space]));
+        let saved: AccessibilityConfig = serde_json::from_value(serde_json::to_value(&settings).unwrap()).unwrap();
+        assert_eq!(saved.binding("jump"), Some(&[String::from("J")][..]));
+    }
+
+    #[test]
+    fn reduced_flashing_limits_intensity_changes() {
+        let settings = AccessibilityConfig { reduced_flashing: true, max_intensity_rate: 2.0, ..AccessibilityConfig::default() };
+        let mut limited = FlashLimiter::new(0.0, &settings);
+        assert_eq!(limited.update(1.0, Duration::from_millis(250)), 0.5);
+        assert_eq!(limited.update(0.0, Duration::from_millis(100)), 0.3);
+
+        let mut unlimited = FlashLimiter::new(0.0, &AccessibilityConfig::default());
+        assert_eq!(unlimited.update(1.0, Duration::from_millis(16)), 1.0);
+    }
+}
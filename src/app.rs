@@ -1,22 +1,94 @@
-use std::{rc::Rc, time::{Instant, Duration}, borrow::BorrowMut};
-use winit::{event::{ Event, WindowEvent }, event_loop::{ EventLoopWindowTarget, ControlFlow }};
+use std::{time::{Instant, Duration}, borrow::BorrowMut, sync::{Arc, mpsc::{self, Receiver}}};
+use winit::{event::{ Event, WindowEvent, MouseButton }, event_loop::{ EventLoopWindowTarget, ControlFlow }};
 
 use crate::{graphics::vulkangfx::TVulkanGraphics, debug::dump_backtrace};
+use ash::vk;
+use serde::{Serialize, Deserialize};
+
+use crate::graphics::surface::{AcquireResult, PresentResult};
+use crate::graphics::backend::{GraphicsBackend, BackendError, FrameStatus, RendererConfig};
+use crate::graphics::events::{PauseReason, RenderingPaused, RenderingResumed};
+use crate::graphics::{audit, extract, capture::{CaptureConfig, CapturedFrame, FrameCapture}, particles::{ParticleSettings, SimulationBackend}, ui::{self, layout::{self, UiViewport}}};
+use crate::debug::{log, crash, frame_step, profiler, benchmark::{Benchmark, BenchmarkConfig}, event_log::{EventLogConfig, EventRecorder}, latency::InputLatency};
 use crate::graphics::vulkan_experimental::VulkanResult;
 use crate::app::window::EventErrorResult;
 use crate::graphics::vulkan_experimental::VulkanGraphics as VulkanExperimental;
+use crate::system::{world::World, time, event, determinism::{self, DeterminismConfig}, schedule::{Schedule, stage}, state::AppState, prefab, transform};
+use crate::asset::AssetManager;
+use crate::{vfs, spatial, input::gesture};
+use crate::config::{self, ConfigSection, ConfigChanged, WatchId};
+use crate::memory::arena;
+
+mod builder;
+pub mod plugin;
+pub mod settings;
+pub use builder::{AppBuilder, GraphicsChoice, Subsystems};
+
+/// How often the config file is checked for edits
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct App {
     eventloop: Option<winit::event_loop::EventLoop<()>>,
-    window: Rc<winit::window::Window>,
+    /// `None` for headless apps
+    window: Option<Arc<winit::window::Window>>,
     graphics: GraphicsImpl,
+    world: World,
+    schedule: Schedule,
     counters: AppCounters,
+    /// Set while the surface is being rebuilt, rendering resumes once recreation succeeds
+    rendering_paused: Option<PauseReason>,
+    benchmark: Option<Benchmark>,
+    config: AppConfig,
+    config_watch: ConfigWatch,
+    event_recorder: EventRecorder,
+    /// Set while presented frames are being dumped, see `graphics::capture`
+    capture: Option<FrameCapture>,
+    exit_requested: bool,
+}
+
+/// Changes to the engine config, queued by a watcher for the app to apply between frames
+struct ConfigWatch {
+    id: WatchId,
+    changes: Receiver<Vec<String>>,
+    last_poll: Instant,
+}
+
+/// App options from the `app` config section
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AppConfig {
+    pub title: String,
+    /// Window size in logical pixels
+    pub width: u32,
+    pub height: u32,
+    /// Borderless fullscreen on the window's monitor
+    pub fullscreen: bool,
+    /// Frames per second the loop is held to, ignored in benchmark mode
+    pub frame_limit: Option<f64>,
+    pub redraw: RedrawMode,
 }
 
+/// When the window is redrawn
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedrawMode {
+    /// Every pass of the event loop, as fast as the frame limit and vsync allow
+    #[default]
+    Continuous,
+    /// Only after input, a resize or an `invalidate`, sleeping in between. For editors and tools that would otherwise
+    /// burn the GPU redrawing a still frame
+    Reactive,
+}
+
+/// World resource set when the window needs a redraw in reactive mode, see `invalidate`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Invalidated(pub bool);
+
 pub(crate) enum GraphicsImpl {
     None,
     VulkanGraphics(TVulkanGraphics),
     VulkanExperimental(VulkanExperimental),
+    Backend(Box<dyn GraphicsBackend>),
 }
 
 /// App-centric events
@@ -26,6 +98,12 @@ pub(crate) enum AppEventResult {
     Ok,
     NotImplemented,
     RedrawRequest,
+    /// The swapchain no longer matches the window surface and has to be rebuilt before the next frame
+    RecreateSwapchain,
+    /// The window surface was lost or destroyed, it and the swapchain have to be rebuilt
+    RecreateSurface(PauseReason),
+    /// Stops the event loop
+    Exit,
     GraphicsError(Box<dyn std::error::Error>),
 }
 
@@ -34,11 +112,15 @@ struct AppCounters {
     frame_begin: Option<Instant>,
     frame_end: Option<Instant>,
     frame_average: Option<Duration>,
+    last_update: Option<Instant>,
 }
 
 /// Anything related to the window/winit
 pub(crate) mod window {
+    use crate::debug::event_log::EventFrequency;
+
     /// Window-centric events
+    #[derive(Debug)]
     pub(crate) enum WindowEvent<'a> {
         // App events
         Redraw,
@@ -95,62 +177,219 @@ pub(crate) mod window {
     pub(crate) enum EventErrorResult {
         VulkanError(ash::vk::Result),
     }
+
+    impl WindowEvent<'_> {
+        /// Events from the user's input devices, which count towards input latency
+        pub(crate) fn is_input(&self) -> bool {
+            matches!(self,
+                WindowEvent::ReceivedCharacter(_) | WindowEvent::KeyboardInput(..) | WindowEvent::Ime(_) |
+                WindowEvent::CursorMoved(..) | WindowEvent::MouseWheel(..) | WindowEvent::MouseInput(..) |
+                WindowEvent::TouchPadPressure(..) | WindowEvent::AxisMotion(..) | WindowEvent::Touch(_) |
+                WindowEvent::DeviceMouseMotion(_) | WindowEvent::DeviceMouseWheel(_) | WindowEvent::DeviceMotion(..) |
+                WindowEvent::DeviceButton(..) | WindowEvent::DeviceKey(_) | WindowEvent::DeviceText(_))
+        }
+
+        /// Whether the event changes what's on screen, so a reactive app redraws after it
+        pub(crate) fn invalidates(&self) -> bool {
+            self.is_input() || matches!(self,
+                WindowEvent::Resized(_) | WindowEvent::ScaleFactorChanged(..) | WindowEvent::ThemeChanged(_) |
+                WindowEvent::Focused(_) | WindowEvent::Occluded(false) | WindowEvent::CursorEntered(_) | WindowEvent::CursorLeft(_))
+        }
+
+        /// How often events of this kind arrive, for throttling them in the event log
+        pub(crate) fn frequency(&self) -> EventFrequency {
+            match self {
+                WindowEvent::CursorMoved(..) | WindowEvent::TouchPadPressure(..) | WindowEvent::AxisMotion(..) |
+                WindowEvent::DeviceMouseMotion(_) | WindowEvent::DeviceMouseWheel(_) | WindowEvent::DeviceMotion(..) |
+                WindowEvent::MouseWheel(..) | WindowEvent::Touch(_) => EventFrequency::High,
+                WindowEvent::Redraw | WindowEvent::StartResume(..) | WindowEvent::StartWaitCancelled(..) |
+                WindowEvent::StartPolled | WindowEvent::MainEventsCleared | WindowEvent::RedrawEventsCleared => EventFrequency::Loop,
+                _ => EventFrequency::Discrete,
+            }
+        }
+
+        pub(crate) fn name(&self) -> &'static str {
+            match self {
+                WindowEvent::Redraw => "Redraw",
+                WindowEvent::Resized(_) => "Resized",
+                WindowEvent::Moved(_) => "Moved",
+                WindowEvent::CloseRequested => "CloseRequested",
+                WindowEvent::Destroyed => "Destroyed",
+                WindowEvent::DroppedFile(_) => "DroppedFile",
+                WindowEvent::HoveredFile(_) => "HoveredFile",
+                WindowEvent::HoveredFileCancelled() => "HoveredFileCancelled",
+                WindowEvent::ReceivedCharacter(_) => "ReceivedCharacter",
+                WindowEvent::Focused(_) => "Focused",
+                WindowEvent::KeyboardInput(..) => "KeyboardInput",
+                WindowEvent::ModifiersChanged(_) => "ModifiersChanged",
+                WindowEvent::Ime(_) => "Ime",
+                WindowEvent::CursorMoved(..) => "CursorMoved",
+                WindowEvent::CursorEntered(_) => "CursorEntered",
+                WindowEvent::CursorLeft(_) => "CursorLeft",
+                WindowEvent::MouseWheel(..) => "MouseWheel",
+                WindowEvent::MouseInput(..) => "MouseInput",
+                WindowEvent::TouchPadPressure(..) => "TouchPadPressure",
+                WindowEvent::AxisMotion(..) => "AxisMotion",
+                WindowEvent::Touch(_) => "Touch",
+                WindowEvent::ScaleFactorChanged(..) => "ScaleFactorChanged",
+                WindowEvent::ThemeChanged(_) => "ThemeChanged",
+                WindowEvent::Occluded(_) => "Occluded",
+                WindowEvent::DeviceAdded => "DeviceAdded",
+                WindowEvent::DeviceRemoved => "DeviceRemoved",
+                WindowEvent::DeviceMouseMotion(_) => "DeviceMouseMotion",
+                WindowEvent::DeviceMouseWheel(_) => "DeviceMouseWheel",
+                WindowEvent::DeviceMotion(..) => "DeviceMotion",
+                WindowEvent::DeviceButton(..) => "DeviceButton",
+                WindowEvent::DeviceKey(_) => "DeviceKey",
+                WindowEvent::DeviceText(_) => "DeviceText",
+                WindowEvent::StartResume(..) => "StartResume",
+                WindowEvent::StartWaitCancelled(..) => "StartWaitCancelled",
+                WindowEvent::StartPolled => "StartPolled",
+                WindowEvent::StartInit => "StartInit",
+                WindowEvent::MainEventsCleared => "MainEventsCleared",
+                WindowEvent::ExtensionEvent(_) => "ExtensionEvent",
+                WindowEvent::Suspended => "Suspended",
+                WindowEvent::RedrawEventsCleared => "RedrawEventsCleared",
+                WindowEvent::Resumed => "Resumed",
+                WindowEvent::LoopDestroyed => "LoopDestroyed",
+            }
+        }
+    }
 }
 
 impl App {
+    /// A windowed app with every subsystem at its default, see `builder` to choose
     pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        // The app is the consumer of hadron, the hadron should request
-        // a config from the app with a callback, if it doesn't receive one
-        // then it should try to load one from disk. If there isn't one to load
-        // then it should use a default configuration baked into the executable
+        App::builder().build()
+    }
 
-        const WINDOW_DIMENSIONS: (i32, i32) = (800, 600);
-        
-        let eventloop = winit::event_loop::EventLoop::new();
+    pub fn builder() -> AppBuilder {
+        AppBuilder::new()
+    }
 
-        let window_inner_size = winit::dpi::LogicalSize::new(WINDOW_DIMENSIONS.0, WINDOW_DIMENSIONS.1);
-        
-        let window = winit::window::WindowBuilder::new()
-            .with_min_inner_size(window_inner_size)
-            .with_max_inner_size(window_inner_size).build(&eventloop)?;
-        
-        let window = Rc::new(window);
-        
-        let vulkan_graphics = VulkanExperimental::new(window.clone()).unwrap();
-        let graphics = GraphicsImpl::VulkanExperimental(vulkan_graphics);
+    /// An app without a window or event loop, rendering through `backend`. Driven with `run_frames` rather than
+    /// `run`, for tests and tools that need the app loop but not a display
+    pub fn headless<B: GraphicsBackend + 'static>(backend: B) -> Self {
+        AppBuilder::new().assemble(None, None, GraphicsImpl::Backend(Box::new(backend)))
+    }
+
+    fn from_parts(eventloop: Option<winit::event_loop::EventLoop<()>>, window: Option<Arc<winit::window::Window>>, graphics: GraphicsImpl, config: AppConfig) -> Self {
+        let world = World::new();
+        let renderer: RendererConfig = config::get().section();
+        audit::set_strict(renderer.strict_vulkan);
+        world.insert_resource(renderer);
+        time::init_time(&world);
+        let determinism: DeterminismConfig = config::get().section();
+        if determinism.enabled {
+            determinism::enable(&world, determinism);
+        }
+        arena::init_frame_arena(&world);
+        // A renderer with a simulation pass places particles itself, the CPU only spawns and retires them
+        if let GraphicsImpl::VulkanGraphics(gfx) = &graphics {
+            if gfx.simulates_particles() {
+                world.insert_resource(ParticleSettings { backend: SimulationBackend::Compute });
+            }
+        }
+        // The first frame is drawn in reactive mode too
+        world.insert_resource(Invalidated(true));
+        world.insert_resource(window.as_deref().map(settings::detect_resolutions).unwrap_or_default());
+        world.insert_resource(window.as_deref().map_or_else(UiViewport::default, |window| {
+            let size = window.inner_size();
+            UiViewport { size: [size.width as f32, size.height as f32], scale_factor: window.scale_factor() }
+        }));
+        ui::input::init_ui_input(&world);
+        crash::watch(&world, config::get().section());
+        let capture: CaptureConfig = config::get().section();
+
+        let mut asset_manager = AssetManager::from_vfs(vfs::get(), "/assets");
+        prefab::init_prefabs(&world, &mut asset_manager);
+        world.insert_resource(asset_manager);
+
+        let mut schedule = Schedule::default();
+        schedule.add_state(&world, AppState::Loading);
+        schedule.add_system(stage::FIXED_UPDATE, "record previous transforms", transform::record_previous_transforms);
+        schedule.add_system(stage::POST_UPDATE, "propagate transforms", transform::propagate_transforms);
+        spatial::init_spatial_index(&world);
+        schedule.add_system(stage::POST_UPDATE, "update spatial index", spatial::update_spatial_index);
+        schedule.add_system(stage::POST_UPDATE, "layout ui", layout::layout_ui);
         
-        Ok(App {
-            eventloop: Some(eventloop),
+        App {
+            eventloop,
             window,
             graphics,
+            world,
+            schedule,
             counters: AppCounters::zero(),
-        })
+            rendering_paused: None,
+            benchmark: BenchmarkConfig::from_args(std::env::args()).map(Benchmark::new),
+            config,
+            config_watch: ConfigWatch::start(),
+            event_recorder: EventRecorder::new(config::get().section()),
+            capture: capture.enabled.then(|| FrameCapture::new(capture)),
+            exit_requested: false,
+        }
     }
 
     pub(crate) fn dispatch_window_event(&mut self, event: window::WindowEvent) -> AppEventResult {
+        self.event_recorder.record(event.name(), event.frequency(), || format!("{:?}", event), self.counters.redraws);
+        if event.is_input() {
+            let now = Instant::now();
+            self.world.with_resource_mut::<InputLatency, _>(|latency| latency.input(now));
+        }
+        if event.invalidates() {
+            self.invalidate();
+        }
+
         let result = match event {
-            window::WindowEvent::Redraw => self.event_redraw(),
-            window::WindowEvent::Resized(_) => self.event_resized(),
+            window::WindowEvent::Redraw => {
+                let started = Instant::now();
+                let result = self.event_redraw();
+                profiler::record_stage(&self.world, profiler::RENDER_STAGE, started.elapsed(), &[]);
+                result
+            },
+            window::WindowEvent::Resized(size) => {
+                self.world.with_resource_mut::<UiViewport, _>(|viewport| viewport.size = [size.width as f32, size.height as f32]);
+                self.event_resized()
+            },
             window::WindowEvent::Moved(_) => AppEventResult::NotImplemented,
             window::WindowEvent::CloseRequested => AppEventResult::NotImplemented,
-            window::WindowEvent::Destroyed => AppEventResult::NotImplemented,
+            window::WindowEvent::Destroyed => self.event_destroyed(),
             window::WindowEvent::DroppedFile(_) => AppEventResult::NotImplemented,
             window::WindowEvent::HoveredFile(_) => AppEventResult::NotImplemented,
             window::WindowEvent::HoveredFileCancelled() => AppEventResult::NotImplemented,
             window::WindowEvent::ReceivedCharacter(_) => AppEventResult::NotImplemented,
             window::WindowEvent::Focused(_) => self.event_focused(),
-            window::WindowEvent::KeyboardInput(_, _, _) => AppEventResult::NotImplemented,
-            window::WindowEvent::ModifiersChanged(_) => AppEventResult::NotImplemented,
+            window::WindowEvent::KeyboardInput(_, input, _) => match ui::input::handle_key(&self.world, &input) || frame_step::handle_key(&self.world, &input) {
+                true => AppEventResult::Ok,
+                false => AppEventResult::NotImplemented,
+            },
+            window::WindowEvent::ModifiersChanged(modifiers) => {
+                ui::input::modifiers_changed(&self.world, modifiers);
+                AppEventResult::Ok
+            },
             window::WindowEvent::Ime(_) => AppEventResult::NotImplemented,
-            window::WindowEvent::CursorMoved(_, _) => AppEventResult::NotImplemented,
+            window::WindowEvent::CursorMoved(_, position) => {
+                ui::input::pointer_moved(&self.world, Some([position.x as f32, position.y as f32]));
+                AppEventResult::Ok
+            },
             window::WindowEvent::CursorEntered(_) => self.event_cursor_entered(),
             window::WindowEvent::CursorLeft(_) => self.event_cursor_left(),
             window::WindowEvent::MouseWheel(_, _, _) => AppEventResult::NotImplemented,
+            window::WindowEvent::MouseInput(_, state, MouseButton::Left) => {
+                ui::input::pointer_button(&self.world, state);
+                AppEventResult::Ok
+            },
             window::WindowEvent::MouseInput(_, _, _) => AppEventResult::NotImplemented,
             window::WindowEvent::TouchPadPressure(_, _, _) => AppEventResult::NotImplemented,
             window::WindowEvent::AxisMotion(_, _, _) => AppEventResult::NotImplemented,
-            window::WindowEvent::Touch(_) => AppEventResult::NotImplemented,
-            window::WindowEvent::ScaleFactorChanged(_, _) => AppEventResult::NotImplemented,
+            window::WindowEvent::Touch(touch) => {
+                gesture::handle_touch(&self.world, &touch);
+                AppEventResult::Ok
+            },
+            window::WindowEvent::ScaleFactorChanged(scale_factor, size) => {
+                self.world.with_resource_mut::<UiViewport, _>(|viewport| *viewport = UiViewport { size: [size.width as f32, size.height as f32], scale_factor });
+                AppEventResult::Ok
+            },
             window::WindowEvent::ThemeChanged(_) => AppEventResult::NotImplemented,
             window::WindowEvent::Occluded(_) => AppEventResult::NotImplemented,
             window::WindowEvent::MainEventsCleared => self.event_main_events_cleared(),
@@ -179,28 +418,155 @@ impl App {
     }
     
     fn event_redraw(&mut self) -> AppEventResult {
+        if let Some(reason) = self.rendering_paused {
+            return AppEventResult::RecreateSurface(reason)
+        }
+
         match self.graphics.borrow_mut() {
             GraphicsImpl::None => {
                 AppEventResult::Ok
             },
             GraphicsImpl::VulkanGraphics(gfx) => {
-                gfx.wait_for_fences();
-                let image_index = gfx.next_image();
-                gfx.reset_fences();
-                gfx.submit_commandbuffer(image_index);
-                gfx.swapchain().present(image_index, gfx.graphics_device().graphics_queue());
+                // Suboptimal swapchains still present, so they're rebuilt here rather than in the middle of the last frame
+                if gfx.is_suboptimal() {
+                    match gfx.refresh_swapchain() {
+                        Ok(refreshed) => event::send_event(&self.world, refreshed),
+                        Err(error) => return AppEventResult::GraphicsError(Box::new(error)),
+                    }
+                }
+
+                match gfx.pace_frame() {
+                    Ok(()) => (),
+                    // The watchdog already reported the hang, a fresh device picks up from the next frame
+                    Err(vk::Result::ERROR_DEVICE_LOST) if gfx.recovers_from_hangs() => return match gfx.recover_device() {
+                        Ok(()) => AppEventResult::RedrawRequest,
+                        Err(error) => AppEventResult::GraphicsError(Box::new(error)),
+                    },
+                    Err(error) => return AppEventResult::GraphicsError(Box::new(error)),
+                }
+
+                // Fences are only reset once we know we'll submit, otherwise a skipped frame would leave them unsignaled
+                let image_index = match gfx.next_image() {
+                    Ok(AcquireResult::Acquired { index, .. }) => index,
+                    Ok(AcquireResult::OutOfDate) => return AppEventResult::RecreateSwapchain,
+                    Ok(AcquireResult::Timeout) => return AppEventResult::RedrawRequest,
+                    Err(vk::Result::ERROR_SURFACE_LOST_KHR) => return AppEventResult::RecreateSurface(PauseReason::SurfaceLost),
+                    Err(error) => return AppEventResult::GraphicsError(Box::new(error)),
+                };
+
+                let submitted = gfx.record_frame(Some(&self.world), image_index)
+                    .and_then(|_| gfx.reset_fences())
+                    .and_then(|_| gfx.submit_commandbuffer(image_index));
+                if let Err(error) = submitted {
+                    return AppEventResult::GraphicsError(Box::new(error))
+                }
+                capture_frame(&mut self.capture, &mut self.counters, || gfx.read_image(image_index));
+                let presented = gfx.present(image_index);
 
                 self.counters.increment_redraw_count();
-                AppEventResult::Ok
+                self.world.with_resource_mut::<InputLatency, _>(|latency| latency.presented(Instant::now()));
+                if let Some(timing) = gfx.frame_timing() {
+                    self.world.insert_resource(timing);
+                }
+                match presented {
+                    Ok(PresentResult::OutOfDate) => AppEventResult::RecreateSwapchain,
+                    Ok(_) => AppEventResult::Ok,
+                    Err(vk::Result::ERROR_SURFACE_LOST_KHR) => AppEventResult::RecreateSurface(PauseReason::SurfaceLost),
+                    Err(error) => AppEventResult::GraphicsError(Box::new(error)),
+                }
             },
             GraphicsImpl::VulkanExperimental(gfx) => {
+                gfx.update_memory_budget();
                 AppEventResult::NotImplemented
             },
+            GraphicsImpl::Backend(backend) => {
+                match backend.begin_frame() {
+                    Ok(FrameStatus::Ready) => (),
+                    Ok(FrameStatus::Skip) => return AppEventResult::RedrawRequest,
+                    Ok(FrameStatus::Recreate) => return AppEventResult::RecreateSwapchain,
+                    Err(BackendError::Vulkan(vk::Result::ERROR_SURFACE_LOST_KHR)) => return AppEventResult::RecreateSurface(PauseReason::SurfaceLost),
+                    Err(error) => return AppEventResult::GraphicsError(Box::new(error)),
+                }
+                if let Err(error) = backend.submit() {
+                    return AppEventResult::GraphicsError(Box::new(error))
+                }
+                capture_frame(&mut self.capture, &mut self.counters, || backend.read_frame());
+                let presented = backend.present();
+
+                self.counters.increment_redraw_count();
+                let presented_at = backend.presented_at().unwrap_or_else(Instant::now);
+                self.world.with_resource_mut::<InputLatency, _>(|latency| latency.presented(presented_at));
+                if let Some(timing) = backend.frame_timing() {
+                    self.world.insert_resource(timing);
+                }
+                match presented {
+                    Ok(FrameStatus::Recreate) => AppEventResult::RecreateSwapchain,
+                    Ok(_) => AppEventResult::Ok,
+                    Err(BackendError::Vulkan(vk::Result::ERROR_SURFACE_LOST_KHR)) => AppEventResult::RecreateSurface(PauseReason::SurfaceLost),
+                    Err(error) => AppEventResult::GraphicsError(Box::new(error)),
+                }
+            },
         }
     }
 
     fn event_resized(&self) -> AppEventResult {
-        AppEventResult::Ok
+        match self.graphics {
+            GraphicsImpl::VulkanGraphics(_) | GraphicsImpl::Backend(_) => AppEventResult::RecreateSwapchain,
+            _ => AppEventResult::Ok,
+        }
+    }
+
+    /// Rebuilds the swapchain after the window surface changed
+    fn recreate_swapchain(&mut self) -> AppEventResult {
+        match self.graphics.borrow_mut() {
+            GraphicsImpl::VulkanGraphics(gfx) => match gfx.recreate_swapchain() {
+                Ok(()) => AppEventResult::RedrawRequest,
+                Err(error) => AppEventResult::GraphicsError(Box::new(error)),
+            },
+            GraphicsImpl::Backend(backend) => match backend.recreate() {
+                Ok(refreshed) => {
+                    if let Some(refreshed) = refreshed {
+                        event::send_event(&self.world, refreshed);
+                    }
+                    AppEventResult::RedrawRequest
+                },
+                Err(error) => AppEventResult::GraphicsError(Box::new(error)),
+            },
+            _ => AppEventResult::Ok,
+        }
+    }
+
+    fn event_destroyed(&self) -> AppEventResult {
+        match self.graphics {
+            GraphicsImpl::VulkanGraphics(_) | GraphicsImpl::Backend(_) => AppEventResult::RecreateSurface(PauseReason::WindowDestroyed),
+            _ => AppEventResult::NotImplemented,
+        }
+    }
+
+    /// Rebuilds the surface and swapchain, pausing rendering until it succeeds. Failures are retried on the next redraw
+    fn recreate_surface(&mut self, reason: PauseReason) -> AppEventResult {
+        if self.rendering_paused.is_none() {
+            self.rendering_paused = Some(reason);
+            event::send_event(&self.world, RenderingPaused { reason });
+        }
+
+        let recreated: Result<(), Box<dyn std::error::Error>> = match self.graphics.borrow_mut() {
+            GraphicsImpl::VulkanGraphics(gfx) => gfx.recreate_surface().map_err(Into::into),
+            GraphicsImpl::Backend(backend) => backend.recreate_surface().map_err(Into::into),
+            _ => return AppEventResult::Ok,
+        };
+
+        match recreated {
+            Ok(()) => {
+                self.rendering_paused = None;
+                event::send_event(&self.world, RenderingResumed);
+                AppEventResult::RedrawRequest
+            },
+            Err(error) => {
+                log::get().warn(format!("Unable to recreate the window surface, rendering stays paused: {}", error));
+                AppEventResult::Ok
+            },
+        }
     }
 
     fn event_focused(&self) -> AppEventResult {
@@ -212,11 +578,28 @@ impl App {
     }
 
     fn event_cursor_left(&self) -> AppEventResult {
+        ui::input::pointer_moved(&self.world, None);
         AppEventResult::Ok
     }
 
-    fn event_main_events_cleared(&self) -> AppEventResult {
-        AppEventResult::RedrawRequest
+    fn event_main_events_cleared(&mut self) -> AppEventResult {
+        if let AppEventResult::GraphicsError(error) = self.apply_config_changes() {
+            return AppEventResult::GraphicsError(error)
+        }
+        self.update();
+        if self.exit_requested {
+            return AppEventResult::Exit
+        }
+        let invalidated = self.world.with_resource_mut::<Invalidated, _>(|invalidated| std::mem::take(&mut invalidated.0)).unwrap_or(false);
+        match (self.config.redraw, invalidated) {
+            (RedrawMode::Reactive, false) => AppEventResult::Ok,
+            _ => AppEventResult::RedrawRequest,
+        }
+    }
+
+    /// Redraws the window at the end of this pass of the event loop, in reactive mode as well
+    pub fn invalidate(&mut self) {
+        invalidate(&self.world);
     }
 
     fn event_start_resume(&mut self) -> AppEventResult {
@@ -238,10 +621,18 @@ impl App {
         println!("Start init");
         self.begin_frame();
         
-        match VulkanExperimental::new(self.window.clone()) {
+        let Some(window) = self.window.clone() else {
+            return AppEventResult::Ok
+        };
+        // Created by the builder already, only configured here
+        if !matches!(self.graphics, GraphicsImpl::None) {
+            return self.configure_graphics()
+        }
+
+        match VulkanExperimental::new(window) {
             Ok(graphics) => {
                 self.graphics = GraphicsImpl::VulkanExperimental(graphics);
-                AppEventResult::Ok
+                self.configure_graphics()
             },
             Err(result) => {
                 match result {
@@ -268,6 +659,95 @@ impl App {
 
     }
 
+    /// Applies app level settings to a freshly created backend
+    fn configure_graphics(&mut self) -> AppEventResult {
+        let vsync = self.benchmark.is_none() && self.world.with_resource::<RendererConfig, _>(|renderer| renderer.vsync).unwrap_or(true);
+        let (hang_threshold, recover) = self.world.with_resource::<RendererConfig, _>(|renderer| (renderer.hang_threshold_ms, renderer.recover_from_hangs)).unwrap_or((2000, false));
+        match self.graphics.borrow_mut() {
+            GraphicsImpl::VulkanGraphics(gfx) => {
+                gfx.set_watchdog(Duration::from_millis(hang_threshold), recover);
+                match gfx.set_vsync(vsync) {
+                    Ok(()) => AppEventResult::Ok,
+                    Err(error) => AppEventResult::GraphicsError(Box::new(error)),
+                }
+            },
+            GraphicsImpl::VulkanExperimental(_) => { /* No swapchain to configure yet */ AppEventResult::Ok },
+            GraphicsImpl::Backend(_) | GraphicsImpl::None => AppEventResult::Ok,
+        }
+    }
+
+    /// Picks up edits to the config file and applies whatever changed that's safe to change while running: the log
+    /// filter (through its own watcher), frame limit, window title, size and fullscreen, vsync and resolution scale.
+    /// Resizing the window recreates the swapchain through the usual `Resized` event. Everything else is
+    /// passed on to the world as a `ConfigChanged` event
+    fn apply_config_changes(&mut self) -> AppEventResult {
+        let config = config::get();
+        if self.config_watch.last_poll.elapsed() >= CONFIG_POLL_INTERVAL {
+            self.config_watch.last_poll = Instant::now();
+            if let Err(error) = config.reload_if_changed() {
+                log::get().warn(format!("keeping the previous config: {}", error));
+            }
+        }
+
+        let keys: Vec<String> = self.config_watch.changes.try_iter().flatten().collect();
+        if keys.is_empty() {
+            return AppEventResult::Ok
+        }
+        let changed = ConfigChanged { keys };
+        log::get().state("config changed", &changed.keys);
+        self.invalidate();
+
+        let mut result = AppEventResult::Ok;
+        if changed.touches(AppConfig::NAME) {
+            let app: AppConfig = config.section();
+            if let Some(window) = self.window.as_ref() {
+                configure_window(window, &app, &self.config);
+            }
+            self.config = app;
+        }
+        if changed.touches(EventLogConfig::NAME) {
+            self.event_recorder = EventRecorder::new(config.section());
+        }
+        if changed.touches(RendererConfig::NAME) {
+            let renderer: RendererConfig = config.section();
+            audit::set_strict(renderer.strict_vulkan);
+            self.world.insert_resource(renderer);
+            result = match self.graphics {
+                GraphicsImpl::Backend(_) => AppEventResult::RecreateSwapchain,
+                _ => self.configure_graphics(),
+            };
+        }
+
+        event::send_event(&self.world, changed);
+        self.resolve(result)
+    }
+
+    /// Runs in benchmark mode, see `debug::benchmark`. Also enabled by passing `--benchmark` on the command line
+    pub fn with_benchmark(mut self, config: BenchmarkConfig) -> Self {
+        self.benchmark = Some(Benchmark::new(config));
+        self
+    }
+
+    /// Replaces the options read from the engine config. Window options only apply to apps created after the change
+    pub fn with_config(mut self, config: AppConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn config(&self) -> &AppConfig {
+        &self.config
+    }
+
+    /// Dumps presented frames, replacing the options read from the engine config
+    pub fn with_capture(mut self, config: CaptureConfig) -> Self {
+        self.capture = config.enabled.then(|| FrameCapture::new(config));
+        self
+    }
+
+    pub fn capture_mut(&mut self) -> Option<&mut FrameCapture> {
+        self.capture.as_mut()
+    }
+
     fn begin_frame(&mut self) {
         self.counters.begin_frame_clock();
     }
@@ -275,9 +755,49 @@ impl App {
     fn end_frame(&mut self) -> Option<Duration> {
         self.counters.end_frame_clock()
     }
+
+    /// Advances world time and runs per-frame world updates
+    fn update(&mut self) {
+        let delta = self.counters.update_delta();
+        // Deterministic runs tick once a frame and captured frames are evenly spaced in world time, however long each
+        // frame took
+        let delta = match (determinism::frame_delta(&self.world), &self.capture) {
+            (Some(step), _) => step,
+            (None, Some(capture)) if !capture.is_finished() => capture.frame_delta(),
+            _ => delta,
+        };
+        arena::reset_frame_arena(&self.world);
+        time::advance_time(&self.world, delta);
+        self.schedule.run(&self.world);
+        extract::extract(&self.world);
+        event::update_events(&self.world);
+
+        if let Some(benchmark) = self.benchmark.as_mut() {
+            if let Some(report) = benchmark.frame(&self.world, delta) {
+                if let Err(error) = benchmark.finish(&report) {
+                    log::get().error(format!("unable to write the benchmark report to {}: {}", benchmark.config().output.display(), error));
+                }
+                self.exit_requested = true;
+            }
+        }
+
+        #[cfg(feature = "telemetry")]
+        crate::debug::telemetry::publish_frame(&self.world);
+    }
+
+    pub fn world(&self) -> &World {
+        &self.world
+    }
+
+    /// The schedule of systems run each frame, used to add systems and state hooks before `run()`
+    pub fn schedule_mut(&mut self) -> &mut Schedule {
+        &mut self.schedule
+    }
     
     
     fn event_redraw_events_cleared(&mut self) -> AppEventResult {
+        profiler::end_frame(&self.world);
+        self.limit_frame_rate();
         match self.end_frame() {
             Some(_) => {
                 match self.counters.average_frame_duration() {
@@ -298,9 +818,65 @@ impl App {
         AppEventResult::Ok
     }
 
+    /// Sleeps out the rest of the frame if it finished early
+    fn limit_frame_rate(&self) {
+        let Some(limit) = self.config.frame_limit.filter(|limit| *limit > 0.0 && self.benchmark.is_none()) else {
+            return
+        };
+        let Some(begin) = self.counters.frame_begin else {
+            return
+        };
+        if let Some(remaining) = Duration::from_secs_f64(1.0 / limit).checked_sub(begin.elapsed()) {
+            std::thread::sleep(remaining);
+        }
+    }
+
     pub fn run(self) -> ! {
         self.main_loop()
     }
+
+    /// Runs the steps of up to `frames` iterations of the main loop without an event loop, as a headless app would.
+    /// Returns the number of frames run, which is fewer if the app asked to exit
+    pub fn run_frames(&mut self, frames: u64) -> Result<u64, Box<dyn std::error::Error>> {
+        for frame in 0..frames {
+            let mut redraw = false;
+            for event in [window::WindowEvent::StartPolled, window::WindowEvent::MainEventsCleared] {
+                match self.dispatch_and_resolve(event) {
+                    AppEventResult::RedrawRequest => redraw = true,
+                    AppEventResult::Exit => return Ok(frame),
+                    AppEventResult::GraphicsError(error) => return Err(error),
+                    _ => (),
+                }
+            }
+
+            // A skipped or recreated frame asks to be redrawn, which the event loop would do straight away
+            while redraw {
+                redraw = false;
+                match self.dispatch_and_resolve(window::WindowEvent::Redraw) {
+                    AppEventResult::RedrawRequest => redraw = true,
+                    AppEventResult::GraphicsError(error) => return Err(error),
+                    _ => (),
+                }
+            }
+            self.dispatch_and_resolve(window::WindowEvent::RedrawEventsCleared);
+        }
+        Ok(frames)
+    }
+
+    fn dispatch_and_resolve(&mut self, event: window::WindowEvent) -> AppEventResult {
+        let result = self.dispatch_window_event(event);
+        self.resolve(result)
+    }
+
+    /// Handles results the app deals with itself before anything is passed back to the event loop. Swapchain
+    /// recreation can itself fail, or ask for a redraw
+    fn resolve(&mut self, result: AppEventResult) -> AppEventResult {
+        match result {
+            AppEventResult::RecreateSwapchain => self.recreate_swapchain(),
+            AppEventResult::RecreateSurface(reason) => self.recreate_surface(reason),
+            result => result,
+        }
+    }
     
     /// The main app loop
     fn main_loop(mut self) -> ! {
@@ -309,6 +885,14 @@ impl App {
         
         // Setup out event handler for the eventloop
         let event_handler = move |event: Event<()>, event_loop: &EventLoopWindowTarget<()>, control_flow: &mut ControlFlow| {
+            // Reactive apps still wake once per config poll, so edits to the config file are picked up while idle
+            if *control_flow != ControlFlow::Exit {
+                *control_flow = match self.config.redraw {
+                    RedrawMode::Continuous => ControlFlow::Poll,
+                    RedrawMode::Reactive => ControlFlow::WaitUntil(Instant::now() + CONFIG_POLL_INTERVAL),
+                };
+            }
+
             let mut result = AppEventResult::Ok;
             let app = &mut self;
 
@@ -372,13 +956,19 @@ impl App {
             };
 
             // Facilitates App -> Winit communication
-            match result {
+            match self.resolve(result) {
                 AppEventResult::Ok => { /* All's cool in coolsville */ },
                 AppEventResult::NotImplemented => { /* Handle not implemented events */ },
-                AppEventResult::RedrawRequest => self.window.request_redraw(),
+                AppEventResult::RedrawRequest => if let Some(window) = &self.window { window.request_redraw() },
+                AppEventResult::RecreateSwapchain | AppEventResult::RecreateSurface(_) => { /* Handled above */ },
+                AppEventResult::Exit => *control_flow = ControlFlow::Exit,
                 AppEventResult::GraphicsError(error) => {
                     dump_backtrace();
-                    panic!("{}", error);
+                    if audit::is_strict() {
+                        panic!("{}", error);
+                    }
+                    log::get().error(format!("stopping after a graphics error: {}", error));
+                    *control_flow = ControlFlow::Exit;
                 }
             }
         };
@@ -388,6 +978,37 @@ impl App {
     }
 }
 
+impl Default for AppConfig {
+    fn default() -> Self {
+        AppConfig {
+            title: String::from("Hadron"),
+            width: 800,
+            height: 600,
+            fullscreen: false,
+            frame_limit: None,
+            redraw: RedrawMode::Continuous,
+        }
+    }
+}
+
+impl ConfigSection for AppConfig {
+    const NAME: &'static str = "app";
+}
+
+impl Drop for App {
+    fn drop(&mut self) {
+        config::get().unwatch(self.config_watch.id);
+    }
+}
+
+impl ConfigWatch {
+    fn start() -> Self {
+        let (tx, changes) = mpsc::channel();
+        let id = config::get().watch("", move |_, keys| { let _ = tx.send(keys.to_vec()); });
+        ConfigWatch { id, changes, last_poll: Instant::now() }
+    }
+}
+
 impl From<VulkanResult> for AppEventResult {
     fn from(result: VulkanResult) -> Self {
         match result {
@@ -413,6 +1034,7 @@ impl AppCounters {
             frame_begin: None,
             frame_end: None,
             frame_average: None,
+            last_update: None,
         }
     }
 
@@ -474,9 +1096,170 @@ impl AppCounters {
     fn average_frame_duration(&self) -> Option<Duration> {
         self.frame_average
     }
+
+    /// Returns the time since the previous call, or zero on the first call
+    fn update_delta(&mut self) -> Duration {
+        let now = Instant::now();
+        let delta = self.last_update.map_or(Duration::ZERO, |last| now.duration_since(last));
+        self.last_update = Some(now);
+        delta
+    }
+
+    /// Leaves `stalled` out of the running frame and the next update delta, as if it never happened
+    fn exclude(&mut self, stalled: Duration) {
+        self.frame_begin = self.frame_begin.map(|begin| begin + stalled);
+        self.last_update = self.last_update.map(|last| last + stalled);
+    }
+}
+
+/// Counts a frame about to be presented and dumps it if the capture wants it. A failed capture is logged and stopped,
+/// the frame is presented either way
+fn capture_frame<E: std::fmt::Display>(capture: &mut Option<FrameCapture>, counters: &mut AppCounters, read: impl FnOnce() -> Result<CapturedFrame, E>) {
+    let Some(active) = capture.as_mut() else {
+        return
+    };
+    if active.wants_frame() {
+        let started = Instant::now();
+        let written = read().map_err(|error| error.to_string()).and_then(|frame| active.write(&frame).map_err(|error| error.to_string()));
+        counters.exclude(started.elapsed());
+        if let Err(error) = written {
+            log::get().with_topic("capture").warn(format!("stopping frame capture after {} frames: {}", active.written(), error));
+            *capture = None;
+            return
+        }
+        if active.is_finished() {
+            log::get().with_topic("capture").info(format!("captured {} frames", active.written()));
+        }
+    }
+    active.presented();
+}
+
+/// Asks for a redraw in reactive mode, for systems and anything else without the app itself
+pub fn invalidate(world: &World) {
+    world.insert_resource(Invalidated(true));
+}
+
+/// Applies the window options of `app` that differ from `previous`. The window's size is fixed, so its minimum and
+/// maximum move with it
+fn configure_window(window: &winit::window::Window, app: &AppConfig, previous: &AppConfig) {
+    if app.title != previous.title {
+        window.set_title(&app.title);
+    }
+    if (app.width, app.height) != (previous.width, previous.height) {
+        let size = winit::dpi::LogicalSize::new(app.width, app.height);
+        window.set_min_inner_size(Some(size));
+        window.set_max_inner_size(Some(size));
+        window.set_inner_size(size);
+    }
+    if app.fullscreen != previous.fullscreen {
+        window.set_fullscreen(app.fullscreen.then_some(winit::window::Fullscreen::Borderless(None)));
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use super::*;
+    use crate::graphics::{events::SwapchainRefreshed, mock::{MockGraphics, MockCall}};
+
+    #[test]
+    fn headless_frames_drive_backend_and_world() {
+        let mock = MockGraphics::new();
+        let recorder = mock.recorder();
+        let mut app = App::headless(mock);
+
+        assert_eq!(app.run_frames(3).unwrap(), 3);
+        assert_eq!(recorder.frames_presented(), 3);
+        assert_eq!(&recorder.calls()[..3], &[MockCall::BeginFrame, MockCall::Submit, MockCall::Present]);
+        assert_eq!(app.world().with_resource::<time::Time, _>(|time| time.frame()), Some(3));
+    }
+
+    #[test]
+    fn out_of_date_frames_recreate_and_retry() {
+        let mock = MockGraphics::new().with_begin_results(&[FrameStatus::Recreate]).with_present_results(&[FrameStatus::Recreate]);
+        let recorder = mock.recorder();
+        let mut app = App::headless(mock);
+
+        app.run_frames(2).unwrap();
+        assert_eq!(recorder.count(|call| *call == MockCall::Recreate), 2);
+        // The out of date present is redrawn straight after recreation
+        assert_eq!(recorder.frames_presented(), 3);
+
+        app.dispatch_and_resolve(window::WindowEvent::Resized(winit::dpi::PhysicalSize::new(640, 480)));
+        assert_eq!(recorder.calls().last(), Some(&MockCall::Recreate));
+    }
+
+    #[test]
+    fn suboptimal_presents_refresh_the_swapchain_before_the_next_frame() {
+        let refreshed = SwapchainRefreshed {
+            format: vk::Format::B8G8R8A8_UNORM,
+            color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+            extent: crate::extent::Extent2 { width: 640, height: 480 },
+            format_changed: true,
+        };
+        let mock = MockGraphics::new().with_swapchain_present_results(&[PresentResult::Suboptimal]).with_refreshed(refreshed);
+        let recorder = mock.recorder();
+        let mut app = App::headless(mock);
+
+        assert!(matches!(app.dispatch_and_resolve(window::WindowEvent::Redraw), AppEventResult::RedrawRequest));
+        assert_eq!(recorder.calls(), [MockCall::BeginFrame, MockCall::Submit, MockCall::Present, MockCall::Recreate]);
+        assert_eq!(event::drain_events::<SwapchainRefreshed>(app.world()), [refreshed]);
 
+        assert!(matches!(app.dispatch_and_resolve(window::WindowEvent::Redraw), AppEventResult::Ok));
+        assert_eq!(recorder.count(|call| *call == MockCall::Recreate), 1);
+    }
+
+    #[test]
+    fn lost_surfaces_pause_rendering_until_recreated() {
+        let mock = MockGraphics::new().with_lost_surface(1);
+        let recorder = mock.recorder();
+        let mut app = App::headless(mock);
+
+        // The first recreation fails, rendering stays paused and the next redraw retries without pausing again
+        assert!(matches!(app.dispatch_and_resolve(window::WindowEvent::Redraw), AppEventResult::Ok));
+        assert!(matches!(app.dispatch_and_resolve(window::WindowEvent::Redraw), AppEventResult::RedrawRequest));
+        assert_eq!(recorder.count(|call| *call == MockCall::RecreateSurface), 2);
+        assert_eq!(recorder.frames_presented(), 0);
+        assert_eq!(event::drain_events::<RenderingPaused>(app.world()), [RenderingPaused { reason: PauseReason::SurfaceLost }]);
+        assert_eq!(event::drain_events::<RenderingResumed>(app.world()), [RenderingResumed]);
+
+        app.dispatch_and_resolve(window::WindowEvent::Redraw);
+        assert_eq!(recorder.frames_presented(), 1);
+
+        app.dispatch_and_resolve(window::WindowEvent::Destroyed);
+        assert_eq!(recorder.count(|call| *call == MockCall::RecreateSurface), 3);
+        assert_eq!(event::drain_events::<RenderingPaused>(app.world()), [RenderingPaused { reason: PauseReason::WindowDestroyed }]);
+        assert_eq!(event::drain_events::<RenderingResumed>(app.world()), [RenderingResumed]);
+    }
+
+    #[test]
+    fn reactive_apps_only_redraw_when_invalidated() {
+        let mock = MockGraphics::new();
+        let recorder = mock.recorder();
+        let mut app = App::headless(mock).with_config(AppConfig { redraw: RedrawMode::Reactive, ..Default::default() });
+
+        // Only the first frame is drawn, the world still updates every pass
+        app.run_frames(3).unwrap();
+        assert_eq!(recorder.frames_presented(), 1);
+        assert_eq!(app.world().with_resource::<time::Time, _>(|time| time.frame()), Some(3));
+
+        invalidate(app.world());
+        app.run_frames(2).unwrap();
+        assert_eq!(recorder.frames_presented(), 2);
+    }
+
+    #[test]
+    fn captured_frames_advance_time_evenly() {
+        let directory = std::env::temp_dir().join(format!("hadron_app_capture_{}", std::process::id()));
+        let mock = MockGraphics::new();
+        let recorder = mock.recorder();
+        let config = CaptureConfig { enabled: true, every: 2, directory: directory.clone(), fps: 10.0, ..Default::default() };
+        let mut app = App::headless(mock).with_capture(config);
+
+        app.run_frames(4).unwrap();
+        assert_eq!(recorder.count(|call| *call == MockCall::ReadFrame), 2);
+        assert!(directory.join("frame_000001.png").exists());
+        let total = app.world().with_resource::<time::Time, _>(|time| time.total()).unwrap();
+        assert!((total.as_secs_f64() - 0.4).abs() < 1e-6);
+        let _ = std::fs::remove_dir_all(&directory);
+    }
 }
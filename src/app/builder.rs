@@ -0,0 +1,330 @@
+//!
+//! App builder
+//!
+//! `App::builder()` picks the graphics backend, switches engine subsystems on or off and queues the app's own
+//! plugins, resources and systems, which are added after the engine's so they can replace its defaults. `App::new` is
+//! the builder with everything left at its defaults
+//!
+
+use std::sync::Arc;
+
+use crate::debug::{log, benchmark::BenchmarkConfig};
+use crate::graphics::backend::GraphicsBackend;
+use crate::graphics::vulkangfx::TVulkanGraphics;
+use crate::graphics::vulkan_experimental::VulkanGraphics as VulkanExperimental;
+use crate::system::{world::World, event, resource::Resource, schedule::Schedule, state::State};
+
+use super::{App, AppConfig, GraphicsImpl};
+use super::plugin::{Plugin, InputPlugin, AssetLoadingPlugin, MemoryPressurePlugin, FrameBudgetPlugin, AudioPlugin, HapticsPlugin, LocalizationPlugin, ParticlesPlugin, StreamingPlugin, DebugOverlayPlugin, TelemetryPlugin};
+
+/// Runs once the engine's own resources and systems are in place
+type Setup = Box<dyn FnOnce(&World, &mut Schedule)>;
+
+/// Which renderer the app draws with
+pub enum GraphicsChoice {
+    /// The Vulkan renderer, in a window
+    Vulkan,
+    /// The original Vulkan renderer, in a window
+    LegacyVulkan,
+    /// A backend supplied by the app, without a window. Driven with `App::run_frames`
+    Backend(Box<dyn GraphicsBackend>),
+    /// No window and no rendering
+    None,
+}
+
+/// Engine subsystems that can be switched off. Hadron has no physics yet, simulation systems are added to the fixed
+/// update stage like any other
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subsystems {
+    /// Registers the `PlaySound` queue audio backends read from
+    pub audio: bool,
+    /// Plays `PlayHaptic` effects on the `Haptics` backend
+    pub haptics: bool,
+    /// Loads language packs for `localization::text`
+    pub localization: bool,
+    pub particles: bool,
+    pub terrain: bool,
+    /// Keeps an `Inspector` resource for a debug overlay to draw from
+    pub debug_overlay: bool,
+    /// Address to serve telemetry on, the engine's only networking. Ignored without the `telemetry` feature
+    pub telemetry: Option<String>,
+}
+
+pub struct AppBuilder {
+    graphics: GraphicsChoice,
+    config: Option<AppConfig>,
+    benchmark: Option<BenchmarkConfig>,
+    subsystems: Subsystems,
+    setup: Vec<Setup>,
+    /// Names of the plugins added so far
+    plugins: Vec<String>,
+}
+
+// Impls
+
+impl Default for Subsystems {
+    fn default() -> Self {
+        Subsystems { audio: true, haptics: true, localization: true, particles: true, terrain: true, debug_overlay: false, telemetry: None }
+    }
+}
+
+impl Default for AppBuilder {
+    fn default() -> Self {
+        AppBuilder { graphics: GraphicsChoice::Vulkan, config: None, benchmark: None, subsystems: Subsystems::default(), setup: Vec::new(), plugins: Vec::new() }
+    }
+}
+
+impl Subsystems {
+    /// The engine plugins for the enabled subsystems
+    pub fn plugins(&self) -> Vec<Box<dyn Plugin>> {
+        let mut plugins: Vec<Box<dyn Plugin>> = vec![Box::new(InputPlugin), Box::new(AssetLoadingPlugin), Box::new(MemoryPressurePlugin), Box::new(FrameBudgetPlugin)];
+        if self.audio {
+            plugins.push(Box::new(AudioPlugin));
+        }
+        if self.haptics {
+            plugins.push(Box::new(HapticsPlugin));
+        }
+        if self.localization {
+            plugins.push(Box::new(LocalizationPlugin));
+        }
+        if self.particles {
+            plugins.push(Box::new(ParticlesPlugin));
+        }
+        if self.terrain {
+            plugins.push(Box::new(StreamingPlugin));
+        }
+        if self.debug_overlay {
+            plugins.push(Box::new(DebugOverlayPlugin));
+        }
+        if let Some(addr) = &self.telemetry {
+            plugins.push(Box::new(TelemetryPlugin { addr: addr.clone() }));
+        }
+        plugins
+    }
+}
+
+impl AppBuilder {
+    pub fn new() -> Self {
+        AppBuilder::default()
+    }
+
+    pub fn with_graphics(mut self, graphics: GraphicsChoice) -> Self {
+        self.graphics = graphics;
+        self
+    }
+
+    /// Renders through `backend` without a window
+    pub fn with_backend<B: GraphicsBackend + 'static>(self, backend: B) -> Self {
+        self.with_graphics(GraphicsChoice::Backend(Box::new(backend)))
+    }
+
+    /// Uses `config` instead of the `app` section of the engine config
+    pub fn with_config(mut self, config: AppConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    pub fn with_benchmark(mut self, config: BenchmarkConfig) -> Self {
+        self.benchmark = Some(config);
+        self
+    }
+
+    pub fn with_subsystems(mut self, subsystems: Subsystems) -> Self {
+        self.subsystems = subsystems;
+        self
+    }
+
+    pub fn with_audio(mut self, enabled: bool) -> Self {
+        self.subsystems.audio = enabled;
+        self
+    }
+
+    pub fn with_localization(mut self, enabled: bool) -> Self {
+        self.subsystems.localization = enabled;
+        self
+    }
+
+    pub fn with_particles(mut self, enabled: bool) -> Self {
+        self.subsystems.particles = enabled;
+        self
+    }
+
+    pub fn with_terrain(mut self, enabled: bool) -> Self {
+        self.subsystems.terrain = enabled;
+        self
+    }
+
+    pub fn with_debug_overlay(mut self, enabled: bool) -> Self {
+        self.subsystems.debug_overlay = enabled;
+        self
+    }
+
+    pub fn with_telemetry(mut self, addr: &str) -> Self {
+        self.subsystems.telemetry = Some(String::from(addr));
+        self
+    }
+
+    pub fn subsystems(&self) -> &Subsystems {
+        &self.subsystems
+    }
+
+    pub fn with_plugin<P: Plugin>(mut self, plugin: P) -> Self {
+        self.add_plugin(plugin);
+        self
+    }
+
+    /// Inserts `resource` into the world, replacing any the engine inserted
+    pub fn with_resource<R: Resource>(mut self, resource: R) -> Self {
+        self.add_resource(resource);
+        self
+    }
+
+    pub fn with_system<F>(mut self, stage: &str, name: &str, run: F) -> Self
+    where
+        F: FnMut(&World) + Send + Sync + 'static
+    {
+        self.add_system(stage, name, run);
+        self
+    }
+
+    /// Registers a state type of the app's own, see `Schedule::add_state`
+    pub fn with_state<S: State>(mut self, initial: S) -> Self {
+        self.add_setup(move |world, schedule| schedule.add_state(world, initial));
+        self
+    }
+
+    pub fn with_setup<F>(mut self, setup: F) -> Self
+    where
+        F: FnOnce(&World, &mut Schedule) + 'static
+    {
+        self.add_setup(setup);
+        self
+    }
+
+    /// Builds `plugin` into the app, unless a plugin with the same name was added already
+    pub fn add_plugin<P: Plugin>(&mut self, plugin: P) -> &mut Self {
+        self.add_plugin_dyn(&plugin)
+    }
+
+    fn add_plugin_dyn(&mut self, plugin: &dyn Plugin) -> &mut Self {
+        let name = plugin.name();
+        if self.has_plugin(name) {
+            log::get().warn(format!("plugin {} was already added", name));
+            return self
+        }
+        self.plugins.push(String::from(name));
+        plugin.build(self);
+        self
+    }
+
+    pub fn has_plugin(&self, name: &str) -> bool {
+        self.plugins.iter().any(|plugin| plugin == name)
+    }
+
+    pub fn add_resource<R: Resource>(&mut self, resource: R) -> &mut Self {
+        self.add_setup(move |world, _| { world.insert_resource(resource); })
+    }
+
+    /// Registers the event queue for `T`, see `event::init_events`
+    pub fn add_event<T: Resource>(&mut self) -> &mut Self {
+        self.add_setup(|world, _| event::init_events::<T>(world))
+    }
+
+    pub fn add_system<F>(&mut self, stage: &str, name: &str, run: F) -> &mut Self
+    where
+        F: FnMut(&World) + Send + Sync + 'static
+    {
+        let (stage, name) = (String::from(stage), String::from(name));
+        self.add_setup(move |_, schedule| schedule.add_system(&stage, &name, run))
+    }
+
+    /// Runs `setup` with the world and schedule once the engine's own resources and systems are added
+    pub fn add_setup<F>(&mut self, setup: F) -> &mut Self
+    where
+        F: FnOnce(&World, &mut Schedule) + 'static
+    {
+        self.setup.push(Box::new(setup));
+        self
+    }
+
+    /// Creates the window and renderer the app was configured with
+    pub fn build(mut self) -> Result<App, Box<dyn std::error::Error>> {
+        let graphics = std::mem::replace(&mut self.graphics, GraphicsChoice::None);
+        let legacy = match graphics {
+            GraphicsChoice::Vulkan => false,
+            GraphicsChoice::LegacyVulkan => true,
+            GraphicsChoice::Backend(backend) => return Ok(self.assemble(None, None, GraphicsImpl::Backend(backend))),
+            GraphicsChoice::None => return Ok(self.assemble(None, None, GraphicsImpl::None)),
+        };
+
+        // Defaults baked into the executable, overridden by the config file, environment and command line
+        let config = self.config.get_or_insert_with(|| crate::config::get().section());
+        let eventloop = winit::event_loop::EventLoop::new();
+        let window_inner_size = winit::dpi::LogicalSize::new(config.width, config.height);
+        let window = winit::window::WindowBuilder::new()
+            .with_title(&config.title)
+            .with_min_inner_size(window_inner_size)
+            .with_max_inner_size(window_inner_size)
+            .with_fullscreen(config.fullscreen.then_some(winit::window::Fullscreen::Borderless(None)))
+            .build(&eventloop)?;
+        let window = Arc::new(window);
+
+        let graphics = match legacy {
+            true => GraphicsImpl::VulkanGraphics(TVulkanGraphics::init(window.clone())?),
+            false => GraphicsImpl::VulkanExperimental(VulkanExperimental::new(window.clone()).map_err(|result| result.into_error())?),
+        };
+        Ok(self.assemble(Some(eventloop), Some(window), graphics))
+    }
+
+    /// Builds an app around an already created window and renderer
+    pub(super) fn assemble(mut self, eventloop: Option<winit::event_loop::EventLoop<()>>, window: Option<Arc<winit::window::Window>>, graphics: GraphicsImpl) -> App {
+        // Engine plugins go first so the app's own setup runs after theirs, any the app added itself are kept
+        let app_setup = std::mem::take(&mut self.setup);
+        for plugin in self.subsystems.plugins() {
+            if !self.has_plugin(plugin.name()) {
+                self.add_plugin_dyn(plugin.as_ref());
+            }
+        }
+        self.setup.extend(app_setup);
+
+        let config = self.config.unwrap_or_else(|| crate::config::get().section());
+        let mut app = App::from_parts(eventloop, window, graphics, config);
+        if let Some(benchmark) = self.benchmark {
+            app = app.with_benchmark(benchmark);
+        }
+        for setup in self.setup {
+            setup(&app.world, &mut app.schedule);
+        }
+        app
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debug::inspector::Inspector;
+    use crate::graphics::mock::MockGraphics;
+    use crate::system::schedule::stage;
+
+    struct Score(u32);
+
+    #[test]
+    fn builder_toggles_subsystems_and_adds_app_systems() {
+        let mut app = App::builder()
+            .with_backend(MockGraphics::new())
+            .with_particles(false)
+            .with_debug_overlay(true)
+            .with_resource(Score(0))
+            .with_system(stage::UPDATE, "score", |world| { world.with_resource_mut::<Score, _>(|score| score.0 += 1); })
+            .build()
+            .unwrap();
+
+        let systems: Vec<String> = app.schedule_mut().stage(stage::UPDATE).unwrap().systems().iter().map(|system| String::from(system.name())).collect();
+        assert!(!systems.iter().any(|name| name == "simulate particles"));
+        assert!(systems.iter().any(|name| name == "stream terrain"));
+        assert!(app.world().contains_resource::<Inspector>());
+
+        app.run_frames(2).unwrap();
+        assert_eq!(app.world().with_resource::<Score, _>(|score| score.0), Some(2));
+    }
+}
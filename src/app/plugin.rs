@@ -0,0 +1,219 @@
+//!
+//! Plugins
+//!
+//! A `Plugin` adds a feature to an app by registering its resources, events and systems on the `AppBuilder`. The
+//! engine's optional subsystems are plugins themselves, added for whichever `Subsystems` are enabled before any of the
+//! app's own, so a feature is set up the same way wherever it comes from and can be tested on an app with nothing else
+//! in it. Each plugin is added once, a second plugin with the same name is skipped
+//!
+
+use std::marker::PhantomData;
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::asset::{self, loading};
+use crate::audio::{PlaySound, dsp::{self, AudioBuses, AutomateParam}, music::{self, MusicStreams, PlayMusic, StopMusic}};
+use crate::config;
+use crate::debug::{log, profiler, inspector::Inspector, latency::InputLatency};
+use crate::graphics::{backend, particles, terrain, tilemap};
+use crate::input::haptics::{self, Haptics, PlayHaptic, StopHaptics};
+use crate::localization::{self, LanguageChanged};
+use crate::memory::pressure;
+use crate::streaming::component;
+use crate::system::schedule::stage;
+
+use super::AppBuilder;
+
+pub trait Plugin {
+    /// Identifies the plugin so it's only added once
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+
+    fn build(&self, app: &mut AppBuilder);
+}
+
+/// Tracks the time from input to present
+pub struct InputPlugin;
+
+/// Stores queued assets as their reads finish and keeps `LoadingProgress` up to date
+pub struct AssetLoadingPlugin;
+
+/// Watches memory use, scaling resolution, texture mips and streaming budgets down under `MemoryPressure`
+pub struct MemoryPressurePlugin;
+
+/// Times stages into a `FrameProfile` and reports the ones over their `profiler.budgets`
+pub struct FrameBudgetPlugin;
+
+/// Registers the `PlaySound` queue audio backends read from, streams the `MusicStreams` they mix and ramps the
+/// `AudioBuses` effect parameters
+pub struct AudioPlugin;
+
+/// Plays `PlayHaptic` effects on the `Haptics` backend
+pub struct HapticsPlugin;
+
+/// Loads the language packs of `localization.language` and hot reloads them
+pub struct LocalizationPlugin;
+
+/// Simulates particle emitters on the CPU
+pub struct ParticlesPlugin;
+
+/// Streams terrain tiles and tilemap chunks in and out around the camera
+pub struct StreamingPlugin;
+
+/// Streams `StreamedComponent<T>`s in and out of the world's `ComponentStore`
+pub struct StreamedComponentPlugin<T>(PhantomData<fn() -> T>);
+
+/// Keeps an `Inspector` resource for a debug overlay to draw from
+pub struct DebugOverlayPlugin;
+
+/// Serves telemetry on an address. Without the `telemetry` feature it only warns that it can't
+pub struct TelemetryPlugin {
+    pub addr: String,
+}
+
+// Impls
+
+impl Plugin for InputPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_resource(InputLatency::default());
+    }
+}
+
+impl Plugin for AssetLoadingPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_resource(loading::LoadingProgress::default())
+            .add_system(stage::PRE_UPDATE, "load assets", loading::update_loading);
+    }
+}
+
+impl Plugin for MemoryPressurePlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_resource(pressure::MemoryPressureMonitor::default())
+            .add_event::<pressure::MemoryPressure>()
+            .add_system(stage::PRE_UPDATE, "monitor memory pressure", pressure::monitor_memory_pressure)
+            .add_system(stage::PRE_UPDATE, "scale resolution for memory pressure", backend::scale_resolution_for_pressure)
+            .add_system(stage::PRE_UPDATE, "drop texture mips for memory pressure", asset::drop_mips_for_pressure)
+            .add_system(stage::PRE_UPDATE, "scale component budget for memory pressure", component::scale_budget_for_pressure());
+    }
+}
+
+impl Plugin for FrameBudgetPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_resource(profiler::FrameProfile::default())
+            .add_event::<profiler::BudgetExceeded>();
+    }
+}
+
+impl Plugin for AudioPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_resource(MusicStreams::default())
+            .add_event::<PlaySound>()
+            .add_event::<PlayMusic>()
+            .add_event::<StopMusic>()
+            .add_resource(AudioBuses::default())
+            .add_event::<AutomateParam>()
+            .add_system(stage::PRE_UPDATE, "stream music", music::stream_music)
+            .add_system(stage::POST_UPDATE, "automate audio buses", dsp::automate_buses);
+    }
+}
+
+impl Plugin for HapticsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_resource(Haptics::default())
+            .add_event::<PlayHaptic>()
+            .add_event::<StopHaptics>()
+            .add_system(stage::POST_UPDATE, "update haptics", haptics::update_haptics);
+    }
+}
+
+impl Plugin for LocalizationPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_event::<LanguageChanged>()
+            .add_setup(|world, _| localization::init_localization(world, config::get().section()))
+            .add_system(stage::PRE_UPDATE, "reload language packs", localization::reload_language_packs);
+    }
+}
+
+impl Plugin for ParticlesPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_system(stage::UPDATE, "simulate particles", particles::simulate_particles);
+    }
+}
+
+impl Plugin for StreamingPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_system(stage::UPDATE, "stream terrain", terrain::stream_terrain)
+            .add_system(stage::UPDATE, "stream tilemaps", tilemap::stream_tilemaps);
+    }
+}
+
+impl<T> Default for StreamedComponentPlugin<T> {
+    fn default() -> Self {
+        StreamedComponentPlugin(PhantomData)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + Send + Sync + 'static> Plugin for StreamedComponentPlugin<T> {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_system(stage::POST_UPDATE, &format!("stream {}", std::any::type_name::<T>()), component::stream_components::<T>);
+    }
+}
+
+impl Plugin for DebugOverlayPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_resource(Inspector::default());
+    }
+}
+
+impl Plugin for TelemetryPlugin {
+    #[cfg(feature = "telemetry")]
+    fn build(&self, _app: &mut AppBuilder) {
+        match crate::debug::telemetry::start(self.addr.as_str()) {
+            Ok(addr) => log::get().info(format!("serving telemetry on {}", addr)),
+            Err(error) => log::get().warn(format!("unable to start telemetry on {}: {}", self.addr, error)),
+        }
+    }
+
+    #[cfg(not(feature = "telemetry"))]
+    fn build(&self, _app: &mut AppBuilder) {
+        log::get().warn(format!("not serving telemetry on {}, hadron was built without the telemetry feature", self.addr));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::App;
+    use crate::graphics::mock::MockGraphics;
+    use crate::system::world::World;
+
+    struct Ticks(u32);
+
+    struct TickPlugin;
+
+    impl Plugin for TickPlugin {
+        fn build(&self, app: &mut AppBuilder) {
+            app.add_resource(Ticks(0))
+                .add_system(stage::UPDATE, "tick", |world: &World| { world.with_resource_mut::<Ticks, _>(|ticks| ticks.0 += 1); });
+        }
+    }
+
+    #[test]
+    fn plugins_are_built_once() {
+        let mut app = App::builder()
+            .with_backend(MockGraphics::new())
+            .with_plugin(TickPlugin)
+            .with_plugin(TickPlugin)
+            // Already added by the app, so the engine's copy for the enabled subsystem is skipped
+            .with_plugin(ParticlesPlugin)
+            .build()
+            .unwrap();
+
+        app.run_frames(3).unwrap();
+        assert_eq!(app.world().with_resource::<Ticks, _>(|ticks| ticks.0), Some(3));
+        let systems = app.schedule_mut().stage(stage::UPDATE).unwrap().systems().iter().filter(|system| system.name() == "simulate particles").count();
+        assert_eq!(systems, 1);
+        assert!(app.world().contains_resource::<InputLatency>());
+    }
+}
@@ -0,0 +1,276 @@
+//!
+//! Settings screen widgets
+//!
+//! Ready-made widgets bound to config keys, for a UI to lay out and draw. Each widget reads its current value from the
+//! merged config and writes changes to the runtime layer, so they apply live the same way an edit to the config file
+//! does: the app picks up the `ConfigChanged` keys between frames, resizing the window or recreating the swapchain as
+//! needed. `SettingsScreen::save` then persists the changes to the config file, or `revert` drops the unsaved ones
+//!
+//! `graphics_settings` builds the screen for the window and renderer options, offering the resolutions of the monitor
+//! the window opened on
+//!
+
+use serde_json::{json, Value};
+
+use crate::config::{Config, ConfigError, Layer};
+use crate::system::world::World;
+
+/// Window sizes in logical pixels the monitor supports, largest first
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Resolutions(pub Vec<(u32, u32)>);
+
+/// What a widget edits and how
+#[derive(Debug, Clone, PartialEq)]
+pub enum WidgetKind {
+    /// A bool
+    Toggle,
+    /// A number kept within `min` and `max` and snapped to multiples of `step` from `min`
+    Slider { min: f64, max: f64, step: f64 },
+    /// One of a list of values
+    Choice(Vec<SettingChoice>),
+}
+
+/// An option of a choice widget. An object value sets each of its fields under the widget's key and leaves the rest
+#[derive(Debug, Clone, PartialEq)]
+pub struct SettingChoice {
+    pub label: String,
+    pub value: Value,
+}
+
+/// A control bound to a config key
+#[derive(Debug, Clone, PartialEq)]
+pub struct SettingWidget {
+    key: String,
+    label: String,
+    kind: WidgetKind,
+}
+
+/// A titled group of widgets
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SettingsScreen {
+    title: String,
+    widgets: Vec<SettingWidget>,
+}
+
+#[derive(Debug)]
+pub enum SettingError {
+    /// The value at the key doesn't fit the widget
+    Invalid(String, Value),
+    Config(ConfigError),
+}
+
+// Impls
+
+impl std::fmt::Display for SettingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SettingError::Invalid(key, value) => write!(f, "{} can't be set to {}", key, value),
+            SettingError::Config(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for SettingError {}
+
+impl From<ConfigError> for SettingError {
+    fn from(err: ConfigError) -> Self {
+        SettingError::Config(err)
+    }
+}
+
+impl SettingChoice {
+    pub fn new(label: impl Into<String>, value: Value) -> Self {
+        SettingChoice { label: label.into(), value }
+    }
+}
+
+impl SettingWidget {
+    pub fn toggle(key: &str, label: &str) -> Self {
+        SettingWidget { key: String::from(key), label: String::from(label), kind: WidgetKind::Toggle }
+    }
+
+    pub fn slider(key: &str, label: &str, min: f64, max: f64, step: f64) -> Self {
+        SettingWidget { key: String::from(key), label: String::from(label), kind: WidgetKind::Slider { min, max, step } }
+    }
+
+    pub fn choice(key: &str, label: &str, choices: Vec<SettingChoice>) -> Self {
+        SettingWidget { key: String::from(key), label: String::from(label), kind: WidgetKind::Choice(choices) }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn kind(&self) -> &WidgetKind {
+        &self.kind
+    }
+
+    /// The config keys the widget writes to
+    pub fn keys(&self) -> Vec<String> {
+        let WidgetKind::Choice(choices) = &self.kind else {
+            return vec![self.key.clone()]
+        };
+        let mut keys: Vec<String> = choices.iter().flat_map(|choice| match &choice.value {
+            Value::Object(fields) => fields.keys().map(|field| format!("{}.{}", self.key, field)).collect(),
+            _ => vec![self.key.clone()],
+        }).collect();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+
+    /// The current value of the widget's key
+    pub fn value(&self, config: &Config) -> Option<Value> {
+        config.value(&self.key)
+    }
+
+    /// Index of the choice matching the config, `None` for other widgets or a value that isn't one of the choices
+    pub fn selected(&self, config: &Config) -> Option<usize> {
+        let WidgetKind::Choice(choices) = &self.kind else {
+            return None
+        };
+        choices.iter().position(|choice| match &choice.value {
+            Value::Object(fields) => fields.iter().all(|(field, value)| config.value(&format!("{}.{}", self.key, field)).as_ref() == Some(value)),
+            value => self.value(config).as_ref() == Some(value),
+        })
+    }
+
+    /// Writes `value` to the runtime layer, where the app applies it before the next frame. Slider values are clamped
+    /// and snapped rather than rejected
+    pub fn set(&self, config: &Config, value: Value) -> Result<(), SettingError> {
+        match (&self.kind, &value) {
+            (WidgetKind::Toggle, Value::Bool(_)) => config.set(&self.key, value)?,
+            (WidgetKind::Slider { min, max, step }, Value::Number(number)) => {
+                let number = number.as_f64().unwrap_or(*min).clamp(*min, *max);
+                let snapped = match *step > 0.0 {
+                    true => (min + ((number - min) / step).round() * step).min(*max),
+                    false => number,
+                };
+                // Rounded so repeated steps don't accumulate float noise in the saved file
+                config.set(&self.key, (snapped * 1e6).round() / 1e6)?
+            },
+            (WidgetKind::Choice(choices), _) if choices.iter().any(|choice| choice.value == value) => match value {
+                Value::Object(fields) => {
+                    for (field, value) in fields {
+                        config.set(&format!("{}.{}", self.key, field), value)?;
+                    }
+                },
+                value => config.set(&self.key, value)?,
+            },
+            _ => return Err(SettingError::Invalid(self.key.clone(), value)),
+        }
+        Ok(())
+    }
+
+    /// Selects the choice at `index`
+    pub fn select(&self, config: &Config, index: usize) -> Result<(), SettingError> {
+        match &self.kind {
+            WidgetKind::Choice(choices) if index < choices.len() => self.set(config, choices[index].value.clone()),
+            _ => Err(SettingError::Invalid(self.key.clone(), json!(index))),
+        }
+    }
+}
+
+impl SettingsScreen {
+    pub fn new(title: &str) -> Self {
+        SettingsScreen { title: String::from(title), widgets: Vec::new() }
+    }
+
+    pub fn with_widget(mut self, widget: SettingWidget) -> Self {
+        self.widgets.push(widget);
+        self
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn widgets(&self) -> &[SettingWidget] {
+        &self.widgets
+    }
+
+    pub fn widget(&self, key: &str) -> Option<&SettingWidget> {
+        self.widgets.iter().find(|widget| widget.key == key)
+    }
+
+    /// Whether any of the widgets have changes that haven't been saved
+    pub fn is_modified(&self, config: &Config) -> bool {
+        let runtime = config.layer(Layer::Runtime);
+        self.keys().iter().any(|key| key.split('.').try_fold(&runtime, |value, part| value.get(part)).is_some())
+    }
+
+    /// Persists every runtime change to the config file, including any made outside the screen
+    pub fn save(&self, config: &Config) -> Result<(), SettingError> {
+        Ok(config.save()?)
+    }
+
+    /// Drops the widgets' unsaved changes, going back to the values from the file, environment and command line
+    pub fn revert(&self, config: &Config) {
+        for key in self.keys() {
+            config.reset(&key);
+        }
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.widgets.iter().flat_map(SettingWidget::keys).collect()
+    }
+}
+
+/// Resolution, fullscreen, vsync and render scale, with the resolutions from the world's `Resolutions`
+pub fn graphics_settings(world: &World) -> SettingsScreen {
+    let resolutions = world.with_resource::<Resolutions, _>(|resolutions| resolutions.0.clone()).unwrap_or_default();
+    let choices = resolutions.into_iter().map(|(width, height)| SettingChoice::new(format!("{} x {}", width, height), json!({ "width": width, "height": height }))).collect();
+    SettingsScreen::new("Graphics")
+        .with_widget(SettingWidget::choice("app", "Resolution", choices))
+        .with_widget(SettingWidget::toggle("app.fullscreen", "Fullscreen"))
+        .with_widget(SettingWidget::toggle("renderer.vsync", "Vsync"))
+        .with_widget(SettingWidget::slider("renderer.resolution_scale", "Render scale", 0.25, 2.0, 0.05))
+}
+
+/// The sizes of the video modes of the monitor `window` is on
+pub(crate) fn detect_resolutions(window: &winit::window::Window) -> Resolutions {
+    let Some(monitor) = window.current_monitor() else {
+        return Resolutions::default()
+    };
+    let scale_factor = monitor.scale_factor();
+    let mut sizes: Vec<(u32, u32)> = monitor.video_modes().map(|mode| {
+        let size = mode.size().to_logical::<u32>(scale_factor);
+        (size.width, size.height)
+    }).collect();
+    sizes.sort_unstable_by(|a, b| b.cmp(a));
+    sizes.dedup();
+    Resolutions(sizes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn widgets_write_the_runtime_layer() {
+        let config = Config::new();
+        let world = World::new();
+        world.insert_resource(Resolutions(vec![(1920, 1080), (1280, 720)]));
+        let screen = graphics_settings(&world);
+        assert!(!screen.is_modified(&config));
+
+        let resolution = screen.widget("app").unwrap();
+        resolution.select(&config, 1).unwrap();
+        assert_eq!(resolution.selected(&config), Some(1));
+        assert_eq!(config.get::<u32>("app.width").unwrap(), 1280);
+
+        let scale = screen.widget("renderer.resolution_scale").unwrap();
+        scale.set(&config, json!(0.77)).unwrap();
+        assert_eq!(config.get::<f32>("renderer.resolution_scale").unwrap(), 0.75);
+        assert!(matches!(screen.widget("renderer.vsync").unwrap().set(&config, json!(3)), Err(SettingError::Invalid(..))));
+
+        assert!(screen.is_modified(&config));
+        screen.revert(&config);
+        assert!(!screen.is_modified(&config));
+        assert_eq!(config.get::<u32>("app.width").unwrap(), 800);
+    }
+}
@@ -0,0 +1,340 @@
+//!
+//! Texture atlases
+//!
+//! An `AtlasBuilder` packs many small images into a few large pages, so a 2D scene's sprites and UI images draw from a
+//! handful of textures instead of binding one per image. Each image keeps its name and is looked up as an
+//! `AtlasRegion`, the page it's on with its pixel rectangle and UVs. Atlases are built at runtime or ahead of time by
+//! the pipeline from `.atlas` files listing their images, and load like any other asset
+//!
+//! Images are placed with a skyline packer, tallest first. Every image is surrounded by `padding` pixels copied from
+//! its edges, so filtering at the edge of a region doesn't bleed in its neighbours
+//!
+
+use std::collections::BTreeMap;
+
+use crate::{extent::Rect, unique::UniqueId};
+
+use super::pipeline::formats::{PayloadReader, Texture};
+
+/// Packs images into atlas pages
+#[derive(Debug, Clone)]
+pub struct AtlasBuilder {
+    page_width: u32,
+    page_height: u32,
+    padding: u32,
+    images: Vec<(String, Texture)>,
+}
+
+/// Images packed into pages
+#[derive(Debug, Clone, PartialEq)]
+pub struct Atlas {
+    pub uid: UniqueId,
+    pub pages: Vec<Texture>,
+    regions: BTreeMap<String, AtlasRegion>,
+}
+
+/// Where an image is in an atlas
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AtlasRegion {
+    pub page: u32,
+    /// In pixels from the page's top left, not including padding
+    pub rect: Rect,
+    pub uv_min: [f32; 2],
+    pub uv_max: [f32; 2],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AtlasError {
+    /// An image and its padding are bigger than a page
+    TooLarge(String, u32, u32),
+    /// Two images were added under the same name
+    Duplicate(String),
+}
+
+/// The free space along the top of a page, as segments of the height used so far
+#[derive(Debug, Clone)]
+struct Skyline {
+    width: u32,
+    height: u32,
+    /// `(x, y, width)` from left to right, covering the page's width
+    segments: Vec<(u32, u32, u32)>,
+}
+
+// Impls
+
+impl std::error::Error for AtlasError {}
+
+impl std::fmt::Display for AtlasError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AtlasError::TooLarge(name, width, height) => write!(f, "image {} doesn't fit in an atlas page of {}x{}", name, width, height),
+            AtlasError::Duplicate(name) => write!(f, "image {} was added to the atlas twice", name),
+        }
+    }
+}
+
+impl Default for AtlasBuilder {
+    fn default() -> Self {
+        AtlasBuilder { page_width: 2048, page_height: 2048, padding: 1, images: Vec::new() }
+    }
+}
+
+impl AtlasBuilder {
+    pub fn new() -> Self {
+        AtlasBuilder::default()
+    }
+
+    /// Largest size of a page, pages holding less are shrunk to the power of two around what they hold
+    pub fn with_page_size(mut self, width: u32, height: u32) -> Self {
+        self.page_width = width.max(1);
+        self.page_height = height.max(1);
+        self
+    }
+
+    pub fn with_padding(mut self, pixels: u32) -> Self {
+        self.padding = pixels;
+        self
+    }
+
+    pub fn add(&mut self, name: &str, image: Texture) -> &mut Self {
+        self.images.push((String::from(name), image));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.images.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.images.is_empty()
+    }
+
+    /// Packs every image added so far into an atlas with id `uid`, which its pages share
+    pub fn build(&self, uid: UniqueId) -> Result<Atlas, AtlasError> {
+        let mut order: Vec<usize> = (0..self.images.len()).collect();
+        order.sort_by(|a, b| {
+            let (a, b) = (&self.images[*a], &self.images[*b]);
+            b.1.height.cmp(&a.1.height).then(b.1.width.cmp(&a.1.width)).then(a.0.cmp(&b.0))
+        });
+
+        let mut pages: Vec<Skyline> = Vec::new();
+        let mut placed: BTreeMap<String, (u32, Rect)> = BTreeMap::new();
+        for index in order {
+            let (name, image) = &self.images[index];
+            let (width, height) = (image.width + 2 * self.padding, image.height + 2 * self.padding);
+            if width > self.page_width || height > self.page_height {
+                return Err(AtlasError::TooLarge(name.clone(), self.page_width, self.page_height))
+            }
+            if placed.contains_key(name) {
+                return Err(AtlasError::Duplicate(name.clone()))
+            }
+
+            let (page, (x, y)) = match pages.iter_mut().enumerate().find_map(|(page, skyline)| skyline.place(width, height).map(|at| (page, at))) {
+                Some(found) => found,
+                None => {
+                    let mut skyline = Skyline::new(self.page_width, self.page_height);
+                    let at = skyline.place(width, height).expect("an image no bigger than a page fits an empty one");
+                    pages.push(skyline);
+                    (pages.len() - 1, at)
+                },
+            };
+            let rect = Rect::new((x + self.padding) as i32, (y + self.padding) as i32, image.width, image.height);
+            placed.insert(name.clone(), (page as u32, rect));
+        }
+
+        let mut textures: Vec<Texture> = pages.iter().map(|skyline| {
+            let (width, height) = skyline.used();
+            let (width, height) = (width.next_power_of_two().min(self.page_width), height.next_power_of_two().min(self.page_height));
+            Texture { uid, width, height, pixels: vec![0; (width * height * 4) as usize] }
+        }).collect();
+        for (name, image) in &self.images {
+            let (page, rect) = placed[name];
+            blit(&mut textures[page as usize], image, rect, self.padding);
+        }
+
+        let regions = placed.into_iter().map(|(name, (page, rect))| (name, AtlasRegion::new(page, rect, &textures[page as usize]))).collect();
+        Ok(Atlas { uid, pages: textures, regions })
+    }
+}
+
+impl AtlasRegion {
+    fn new(page: u32, rect: Rect, texture: &Texture) -> Self {
+        let (width, height) = (texture.width as f32, texture.height as f32);
+        AtlasRegion {
+            page,
+            rect,
+            uv_min: [rect.x as f32 / width, rect.y as f32 / height],
+            uv_max: [rect.right() as f32 / width, rect.bottom() as f32 / height],
+        }
+    }
+}
+
+impl Atlas {
+    pub fn region(&self, name: &str) -> Option<AtlasRegion> {
+        self.regions.get(name).copied()
+    }
+
+    /// Every image's name and region, by name
+    pub fn regions(&self) -> impl Iterator<Item = (&str, AtlasRegion)> {
+        self.regions.iter().map(|(name, region)| (name.as_str(), *region))
+    }
+
+    pub fn len(&self) -> usize {
+        self.regions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    /// The built payload, every page's size and pixels followed by every region's name, page and rectangle
+    pub fn payload(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&(self.pages.len() as u32).to_le_bytes());
+        for page in &self.pages {
+            payload.extend_from_slice(&page.width.to_le_bytes());
+            payload.extend_from_slice(&page.height.to_le_bytes());
+            payload.extend_from_slice(&page.pixels);
+        }
+        payload.extend_from_slice(&(self.regions.len() as u32).to_le_bytes());
+        for (name, region) in &self.regions {
+            payload.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            payload.extend_from_slice(name.as_bytes());
+            [region.page, region.rect.x as u32, region.rect.y as u32, region.rect.width, region.rect.height].iter()
+                .for_each(|value| payload.extend_from_slice(&value.to_le_bytes()));
+        }
+        payload
+    }
+
+    pub fn from_payload(uid: UniqueId, payload: &[u8]) -> Option<Self> {
+        let mut reader = PayloadReader::new(payload);
+        let mut pages = Vec::new();
+        for _ in 0..reader.u32()? {
+            let (width, height) = (reader.u32()?, reader.u32()?);
+            let pixels = reader.take((width as usize).checked_mul(height as usize)?.checked_mul(4)?)?.to_vec();
+            pages.push(Texture { uid, width, height, pixels });
+        }
+        let mut regions = BTreeMap::new();
+        for _ in 0..reader.u32()? {
+            let length = reader.u32()? as usize;
+            let name = String::from_utf8(reader.take(length)?.to_vec()).ok()?;
+            let page = reader.u32()?;
+            let rect = Rect::new(reader.u32()? as i32, reader.u32()? as i32, reader.u32()?, reader.u32()?);
+            regions.insert(name, AtlasRegion::new(page, rect, pages.get(page as usize)?));
+        }
+        Some(Atlas { uid, pages, regions })
+    }
+}
+
+impl Skyline {
+    fn new(width: u32, height: u32) -> Self {
+        Skyline { width, height, segments: vec![(0, 0, width)] }
+    }
+
+    /// Reserves the lowest, then leftmost, space of `width` by `height` along the skyline, returning its top left
+    fn place(&mut self, width: u32, height: u32) -> Option<(u32, u32)> {
+        let mut best: Option<(u32, u32, usize)> = None;
+        for (index, &(x, _, _)) in self.segments.iter().enumerate() {
+            if x + width > self.width {
+                break
+            }
+            // Resting on the highest segment under its width
+            let mut y = 0;
+            let mut covered = 0;
+            for &(_, segment_y, segment_width) in &self.segments[index..] {
+                if covered >= width {
+                    break
+                }
+                y = y.max(segment_y);
+                covered += segment_width;
+            }
+            if y + height <= self.height && best.is_none_or(|(best_y, best_x, _)| (y, x) < (best_y, best_x)) {
+                best = Some((y, x, index));
+            }
+        }
+
+        let (y, x, index) = best?;
+        let right = x + width;
+        let mut rest = self.segments.split_off(index);
+        rest.retain_mut(|segment| {
+            if segment.0 + segment.2 <= right {
+                return false
+            }
+            if segment.0 < right {
+                segment.2 -= right - segment.0;
+                segment.0 = right;
+            }
+            true
+        });
+        self.segments.push((x, y + height, width));
+        self.segments.extend(rest);
+        self.segments.dedup_by(|next, previous| {
+            let merge = previous.1 == next.1;
+            if merge {
+                previous.2 += next.2;
+            }
+            merge
+        });
+        Some((x, y))
+    }
+
+    /// Width and height of the area holding everything placed so far
+    fn used(&self) -> (u32, u32) {
+        let width = self.segments.iter().filter(|segment| segment.1 > 0).map(|segment| segment.0 + segment.2).max().unwrap_or(0);
+        let height = self.segments.iter().map(|segment| segment.1).max().unwrap_or(0);
+        (width.max(1), height.max(1))
+    }
+}
+
+/// Copies `image` into `rect` of `page`, extending its edge pixels `padding` pixels out on every side
+fn blit(page: &mut Texture, image: &Texture, rect: Rect, padding: u32) {
+    let padding = padding as i32;
+    for y in rect.y - padding..rect.bottom() + padding {
+        for x in rect.x - padding..rect.right() + padding {
+            let source_x = (x - rect.x).clamp(0, image.width as i32 - 1) as u32;
+            let source_y = (y - rect.y).clamp(0, image.height as i32 - 1) as u32;
+            let source = ((source_y * image.width + source_x) * 4) as usize;
+            let target = ((y as u32 * page.width + x as u32) * 4) as usize;
+            page.pixels[target..target + 4].copy_from_slice(&image.pixels[source..source + 4]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(width: u32, height: u32, value: u8) -> Texture {
+        Texture { uid: UniqueId::get(), width, height, pixels: vec![value; (width * height * 4) as usize] }
+    }
+
+    #[test]
+    fn images_pack_into_pages() {
+        let mut builder = AtlasBuilder::new().with_page_size(32, 32).with_padding(1);
+        builder.add("wide", image(30, 6, 1)).add("tall", image(6, 30, 2));
+        for value in 0..12 {
+            builder.add(&format!("small{}", value), image(6, 6, 10 + value));
+        }
+        let atlas = builder.build(UniqueId::get()).unwrap();
+        assert_eq!((atlas.len(), atlas.pages.len()), (14, 2));
+
+        // No two regions on a page overlap, padding included
+        let regions: Vec<AtlasRegion> = atlas.regions().map(|(_, region)| region).collect();
+        for (i, a) in regions.iter().enumerate() {
+            for b in &regions[i + 1..] {
+                let grow = |rect: Rect| Rect::new(rect.x - 1, rect.y - 1, rect.width + 2, rect.height + 2);
+                assert!(a.page != b.page || grow(a.rect).intersection(&grow(b.rect)).is_none(), "{:?} overlaps {:?}", a, b);
+            }
+        }
+
+        let tall = atlas.region("tall").unwrap();
+        assert_eq!((tall.page, tall.rect), (0, Rect::new(1, 1, 6, 30)));
+        assert_eq!((tall.uv_min, tall.uv_max), ([1.0 / 32.0, 1.0 / 32.0], [7.0 / 32.0, 31.0 / 32.0]));
+        // The edge is extended into the padding
+        assert_eq!(atlas.pages[0].pixels[0], 2);
+
+        assert_eq!(Atlas::from_payload(atlas.uid, &atlas.payload()), Some(atlas));
+        builder.add("huge", image(32, 1, 0));
+        assert_eq!(builder.build(UniqueId::get()), Err(AtlasError::TooLarge(String::from("huge"), 32, 32)));
+    }
+}
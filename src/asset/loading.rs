@@ -0,0 +1,368 @@
+//!
+//! Queued asset loading
+//!
+//! `queue` requests an asset without waiting for it. Files are read on the vfs io threads a few at a time, highest
+//! priority first and at the matching `IoPriority`, and `update_loading` parses and stores the ones that finished at
+//! the start of each frame. Every queued asset is tracked in the `LoadingProgress` resource for a loading screen to draw
+//! from, and an asset a state transition is waiting on can be moved to the front of the queue with `boost`
+//!
+
+use std::{path::{Path, PathBuf}, sync::Mutex};
+
+use crate::{unique::UniqueId, system::world::World, debug::log, vfs::{IoPriority, ReadHandle}};
+
+use super::{Asset, AssetError, AssetManager, Assets, ErasedLoad};
+
+/// Reads running at once. Anything past this waits in the queue, so priorities decide what's read next
+const MAX_IN_FLIGHT: usize = 4;
+
+/// Order queued assets are read in, highest first
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LoadPriority {
+    /// Only needed eventually, e.g. prefetching the next level
+    Background,
+    #[default]
+    Normal,
+    High,
+    /// A state transition is waiting on it
+    Blocking,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadState {
+    Queued,
+    /// Being read on the io threads
+    Loading,
+    Loaded(UniqueId),
+    Failed(String),
+}
+
+/// Where a queued asset is up to
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssetProgress {
+    pub path: PathBuf,
+    pub priority: LoadPriority,
+    pub state: LoadState,
+    /// Size of the file, known once it has been read
+    pub bytes: u64,
+}
+
+/// World resource tracking every asset queued since the last `clear_finished`, in the order they were queued
+#[derive(Debug, Clone, Default)]
+pub struct LoadingProgress {
+    assets: Vec<AssetProgress>,
+}
+
+/// World resource holding loads that haven't been stored yet
+#[derive(Default)]
+pub(crate) struct LoadQueue {
+    pending: Vec<PendingLoad>,
+    /// Behind a mutex as read handles can't be shared between threads
+    in_flight: Mutex<Vec<InFlight>>,
+}
+
+struct PendingLoad {
+    path: PathBuf,
+    priority: LoadPriority,
+    load: ErasedLoad,
+}
+
+struct InFlight {
+    path: PathBuf,
+    read: ReadHandle,
+    load: ErasedLoad,
+}
+
+// Impls
+
+impl LoadState {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, LoadState::Loaded(_) | LoadState::Failed(_))
+    }
+}
+
+impl From<LoadPriority> for IoPriority {
+    fn from(priority: LoadPriority) -> Self {
+        match priority {
+            LoadPriority::Background => IoPriority::Prefetch,
+            LoadPriority::Normal => IoPriority::Normal,
+            LoadPriority::High => IoPriority::High,
+            LoadPriority::Blocking => IoPriority::Critical,
+        }
+    }
+}
+
+impl LoadingProgress {
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<&AssetProgress> {
+        self.assets.iter().find(|asset| asset.path == path.as_ref())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &AssetProgress> {
+        self.assets.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.assets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.assets.is_empty()
+    }
+
+    /// Assets waiting for a read to start
+    pub fn queued(&self) -> usize {
+        self.count(|state| *state == LoadState::Queued)
+    }
+
+    pub fn loading(&self) -> usize {
+        self.count(|state| *state == LoadState::Loading)
+    }
+
+    pub fn loaded(&self) -> usize {
+        self.count(|state| matches!(state, LoadState::Loaded(_)))
+    }
+
+    pub fn failed(&self) -> usize {
+        self.count(|state| matches!(state, LoadState::Failed(_)))
+    }
+
+    /// Bytes read for assets that finished loading
+    pub fn bytes_loaded(&self) -> u64 {
+        self.assets.iter().filter(|asset| asset.state.is_finished()).map(|asset| asset.bytes).sum()
+    }
+
+    /// Fraction of the queued assets that have finished, loaded or failed. 1 with nothing queued
+    pub fn fraction(&self) -> f32 {
+        match self.assets.len() {
+            0 => 1.0,
+            len => (self.loaded() + self.failed()) as f32 / len as f32,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.assets.iter().all(|asset| asset.state.is_finished())
+    }
+
+    /// Whether a `Blocking` asset is still loading, i.e. a state transition has to keep waiting
+    pub fn is_blocked(&self) -> bool {
+        self.assets.iter().any(|asset| asset.priority == LoadPriority::Blocking && !asset.state.is_finished())
+    }
+
+    /// Forgets finished assets, e.g. once a loading screen is done with them
+    pub fn clear_finished(&mut self) {
+        self.assets.retain(|asset| !asset.state.is_finished());
+    }
+
+    fn count(&self, f: impl Fn(&LoadState) -> bool) -> usize {
+        self.assets.iter().filter(|asset| f(&asset.state)).count()
+    }
+
+    fn get_mut(&mut self, path: &Path) -> Option<&mut AssetProgress> {
+        self.assets.iter_mut().find(|asset| asset.path == path)
+    }
+
+    fn set(&mut self, path: &Path, priority: LoadPriority, state: LoadState) {
+        match self.get_mut(path) {
+            Some(asset) => {
+                asset.priority = asset.priority.max(priority);
+                asset.state = state;
+            },
+            None => self.assets.push(AssetProgress { path: path.to_path_buf(), priority, state, bytes: 0 }),
+        }
+    }
+}
+
+impl LoadQueue {
+    /// Whether `path` is queued or being read
+    fn contains(&mut self, path: &Path) -> bool {
+        self.pending.iter().any(|load| load.path == path)
+            || self.in_flight.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner()).iter().any(|load| load.path == path)
+    }
+
+    /// Takes the highest priority pending load, the earliest queued among equals
+    fn next(&mut self) -> Option<PendingLoad> {
+        let index = self.pending.iter().enumerate()
+            .max_by_key(|(index, load)| (load.priority, std::cmp::Reverse(*index)))
+            .map(|(index, _)| index)?;
+        Some(self.pending.remove(index))
+    }
+}
+
+/// Queues an asset of type `T` to load from `path` in the background. Queueing an asset that's already queued raises its
+/// priority to `priority` if that's higher
+pub fn queue<T: Asset>(world: &World, path: impl AsRef<Path>, priority: LoadPriority) -> Result<(), AssetError> {
+    let path = path.as_ref();
+    if let Some(handle) = world.with_resource::<Assets<T>, _>(|assets| assets.id_of(path)).flatten() {
+        with_progress(world, |progress| progress.set(path, priority, LoadState::Loaded(handle.uid())));
+        return Ok(())
+    }
+    let load = world.with_resource::<AssetManager, _>(|manager| manager.typed_load::<T>(path))
+        .unwrap_or_else(|| panic!("no asset manager in world"))?;
+    enqueue(world, path, priority, load);
+    Ok(())
+}
+
+/// Queues an asset choosing the loader by file extension, see `AssetManager::load_untyped`
+pub fn queue_untyped(world: &World, path: impl AsRef<Path>, priority: LoadPriority) -> Result<(), AssetError> {
+    let path = path.as_ref();
+    let load = world.with_resource::<AssetManager, _>(|manager| manager.untyped_load(path))
+        .unwrap_or_else(|| panic!("no asset manager in world"))?;
+    enqueue(world, path, priority, load);
+    Ok(())
+}
+
+/// Moves a queued asset to the front of the queue, for assets the current state transition is waiting on. Returns false
+/// if `path` isn't waiting to be read
+pub fn boost(world: &World, path: impl AsRef<Path>) -> bool {
+    let path = path.as_ref();
+    let boosted = world.with_resource_mut::<LoadQueue, _>(|queue| {
+        queue.pending.iter_mut().filter(|load| load.path == path).map(|load| load.priority = LoadPriority::Blocking).count() > 0
+    }).unwrap_or(false);
+    if boosted {
+        with_progress(world, |progress| if let Some(asset) = progress.get_mut(path) {
+            asset.priority = LoadPriority::Blocking;
+        });
+    }
+    boosted
+}
+
+/// Stores the assets whose reads finished and starts reading the next ones in the queue
+pub fn update_loading(world: &World) {
+    let finished: Vec<(InFlight, Result<Vec<u8>, AssetError>)> = world.with_resource_mut::<LoadQueue, _>(|queue| {
+        let in_flight = queue.in_flight.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut finished = Vec::new();
+        let mut index = 0;
+        while index < in_flight.len() {
+            match in_flight[index].read.try_take() {
+                Some(result) => {
+                    let load = in_flight.swap_remove(index);
+                    let result = result.map_err(|err| AssetError::Io(PathBuf::from(load.read.path()), err.into()));
+                    finished.push((load, result));
+                },
+                None => index += 1,
+            }
+        }
+        finished
+    }).unwrap_or_default();
+
+    // Parsed outside the queue's lock, loaders may queue or load their own dependencies
+    for (load, result) in finished {
+        let bytes = result.as_ref().map_or(0, |bytes| bytes.len() as u64);
+        let state = match result.and_then(|bytes| (load.load)(world, &bytes, &load.path)) {
+            Ok(id) => LoadState::Loaded(id),
+            Err(error) => {
+                log::get().warn(format!("{}", error));
+                LoadState::Failed(error.to_string())
+            },
+        };
+        with_progress(world, |progress| if let Some(asset) = progress.get_mut(&load.path) {
+            asset.state = state;
+            asset.bytes = bytes;
+        });
+    }
+
+    let started: Vec<(PendingLoad, ReadHandle)> = world.with_resource_mut::<LoadQueue, _>(|queue| {
+        let free = MAX_IN_FLIGHT.saturating_sub(queue.in_flight.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner()).len());
+        (0..free).map_while(|_| queue.next()).collect::<Vec<_>>()
+    }).unwrap_or_default().into_iter().filter_map(|load| {
+        let read = world.with_resource::<AssetManager, _>(|manager| manager.read_async(&load.path, load.priority))?;
+        Some((load, read))
+    }).collect();
+
+    for (load, _) in &started {
+        with_progress(world, |progress| progress.set(&load.path, load.priority, LoadState::Loading));
+    }
+    world.with_resource_mut::<LoadQueue, _>(|queue| {
+        let in_flight = queue.in_flight.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner());
+        in_flight.extend(started.into_iter().map(|(load, read)| InFlight { path: load.path, read, load: load.load }));
+    });
+}
+
+fn enqueue(world: &World, path: &Path, priority: LoadPriority, load: ErasedLoad) {
+    if !world.contains_resource::<LoadQueue>() {
+        world.insert_resource(LoadQueue::default());
+    }
+    let queued = world.with_resource_mut::<LoadQueue, _>(|queue| {
+        if queue.contains(path) {
+            queue.pending.iter_mut().filter(|pending| pending.path == path).for_each(|pending| pending.priority = pending.priority.max(priority));
+            return false
+        }
+        queue.pending.push(PendingLoad { path: path.to_path_buf(), priority, load });
+        true
+    }).unwrap_or(false);
+
+    with_progress(world, |progress| match queued {
+        true => progress.set(path, priority, LoadState::Queued),
+        false => if let Some(asset) = progress.get_mut(path) {
+            asset.priority = asset.priority.max(priority);
+        },
+    });
+}
+
+fn with_progress(world: &World, f: impl FnOnce(&mut LoadingProgress)) {
+    if !world.contains_resource::<LoadingProgress>() {
+        world.insert_resource(LoadingProgress::default());
+    }
+    world.with_resource_mut::<LoadingProgress, _>(f);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::asset::AssetLoader;
+    use crate::vfs::{MemoryMount, Vfs};
+
+    struct TextLoader;
+
+    impl AssetLoader for TextLoader {
+        type Asset = String;
+
+        fn extensions(&self) -> &[&'static str] {
+            &["txt"]
+        }
+
+        fn load(&self, bytes: &[u8], path: &Path) -> Result<String, AssetError> {
+            String::from_utf8(bytes.to_vec()).map_err(|err| AssetError::Parse(path.to_path_buf(), err.to_string()))
+        }
+    }
+
+    #[test]
+    fn boosted_assets_are_read_first() {
+        let files = MemoryMount::new();
+        for index in 0..6 {
+            files.insert(&format!("{}.txt", index), format!("file {}", index).as_bytes());
+        }
+        let mut manager = AssetManager::from_vfs(Arc::new(Vfs::new().with_mount("/assets", files)), "/assets");
+        manager.add_loader(TextLoader);
+        let world = World::new();
+        world.insert_resource(manager);
+
+        for index in 0..6 {
+            queue::<String>(&world, format!("{}.txt", index), LoadPriority::Normal).unwrap();
+        }
+        queue::<String>(&world, "missing.txt", LoadPriority::Background).unwrap();
+        assert!(boost(&world, "5.txt"));
+
+        update_loading(&world);
+        let state = |path: &str| world.with_resource::<LoadingProgress, _>(|progress| progress.get(path).unwrap().state.clone()).unwrap();
+        assert_eq!(state("5.txt"), LoadState::Loading);
+        assert_eq!(state("4.txt"), LoadState::Queued);
+        assert!(world.with_resource::<LoadingProgress, _>(LoadingProgress::is_blocked).unwrap());
+
+        for _ in 0..1000 {
+            if world.with_resource::<LoadingProgress, _>(LoadingProgress::is_complete).unwrap() {
+                break
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            update_loading(&world);
+        }
+        world.with_resource::<LoadingProgress, _>(|progress| {
+            assert_eq!((progress.loaded(), progress.failed(), progress.fraction()), (6, 1, 1.0));
+            assert_eq!(progress.bytes_loaded(), 6 * 6);
+            assert!(!progress.is_blocked());
+        });
+        let LoadState::Loaded(id) = state("5.txt") else { panic!("not loaded") };
+        assert_eq!(crate::asset::get::<String>(&world, id.typed()).as_deref().map(String::as_str), Some("file 5"));
+    }
+}
@@ -0,0 +1,280 @@
+//!
+//! Asset loading and storage
+//!
+//! Loaders are registered with the `AssetManager` per asset type and per file extension, and files are read through
+//! the `Vfs` relative to the manager's root. Loaded assets are stored in
+//! an `Assets<T>` world resource and referred to by `Handle<T>`s. Source assets are converted into the engine's
+//! own formats ahead of time by the `pipeline`
+//!
+
+use std::{any::{Any, TypeId}, collections::HashMap, path::{Path, PathBuf}, sync::Arc};
+
+use crate::{unique::{Handle, UniqueId}, system::world::World, debug::log, memory::pressure, vfs::{self, DirectoryMount, ReadHandle, Vfs}};
+use pipeline::formats::TextureQuality;
+
+pub mod atlas;
+pub mod loading;
+pub mod pipeline;
+
+pub use loading::{LoadPriority, LoadState, LoadingProgress};
+
+/// Anything that can be loaded and stored as an asset
+pub trait Asset: Send + Sync + 'static {}
+
+impl<T: Send + Sync + 'static> Asset for T {}
+
+/// Converts raw file bytes into an asset
+pub trait AssetLoader: Send + Sync + 'static {
+    type Asset: Asset;
+
+    /// File extensions handled by this loader, without the leading dot
+    fn extensions(&self) -> &[&'static str];
+
+    fn load(&self, bytes: &[u8], path: &Path) -> Result<Self::Asset, AssetError>;
+}
+
+#[derive(Debug)]
+pub enum AssetError {
+    Io(PathBuf, std::io::Error),
+    NoLoader(PathBuf),
+    Parse(PathBuf, String),
+    NotLoaded(UniqueId),
+    /// An asset refers to a file that couldn't be read
+    MissingDependency(PathBuf, PathBuf),
+    /// A source asset couldn't be converted, e.g. an unsupported feature or a failing external tool
+    Convert(PathBuf, String),
+}
+
+/// World resource storing every loaded asset of type `T`
+#[derive(Debug)]
+pub struct Assets<T> {
+    assets: HashMap<UniqueId, Arc<T>>,
+    paths: HashMap<PathBuf, UniqueId>,
+}
+
+/// Type erased loader that stores its result into the right `Assets<T>`
+pub(crate) type ErasedLoad = Arc<dyn Fn(&World, &[u8], &Path) -> Result<UniqueId, AssetError> + Send + Sync>;
+
+/// World resource that owns asset loaders and resolves paths against an asset root in the vfs
+pub struct AssetManager {
+    vfs: Arc<Vfs>,
+    root: String,
+    typed: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+    by_extension: HashMap<String, ErasedLoad>,
+    log: log::Logger,
+}
+
+// Impls
+
+impl std::error::Error for AssetError {}
+
+impl std::fmt::Display for AssetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssetError::Io(path, err) => write!(f, "unable to read asset {}: {}", path.display(), err),
+            AssetError::NoLoader(path) => write!(f, "no loader for asset {}", path.display()),
+            AssetError::Parse(path, err) => write!(f, "unable to parse asset {}: {}", path.display(), err),
+            AssetError::NotLoaded(id) => write!(f, "asset {} is not loaded", id),
+            AssetError::MissingDependency(path, dependency) => write!(f, "asset {} depends on missing file {}", path.display(), dependency.display()),
+            AssetError::Convert(path, err) => write!(f, "unable to convert asset {}: {}", path.display(), err),
+        }
+    }
+}
+
+impl<T> Default for Assets<T> {
+    fn default() -> Self {
+        Assets {
+            assets: HashMap::new(),
+            paths: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Asset> Assets<T> {
+    /// Adds an asset that wasn't loaded from a file, returning its handle
+    pub fn add(&mut self, asset: T) -> Handle<T> {
+        let id = UniqueId::get();
+        self.assets.insert(id, Arc::new(asset));
+        id.typed()
+    }
+
+    pub fn get(&self, handle: Handle<T>) -> Option<Arc<T>> {
+        self.assets.get(&handle.uid()).cloned()
+    }
+
+    /// The handle of an asset previously loaded from `path`
+    pub fn id_of(&self, path: &Path) -> Option<Handle<T>> {
+        self.paths.get(path).map(|id| id.typed())
+    }
+
+    pub fn remove(&mut self, handle: Handle<T>) -> Option<Arc<T>> {
+        let id = handle.uid();
+        self.paths.retain(|_, v| *v != id);
+        self.assets.remove(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.assets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.assets.is_empty()
+    }
+
+    fn insert_loaded(&mut self, path: PathBuf, asset: T) -> Handle<T> {
+        let id = self.paths.get(&path).copied().unwrap_or_else(UniqueId::get);
+        self.assets.insert(id, Arc::new(asset));
+        self.paths.insert(path, id);
+        id.typed()
+    }
+}
+
+impl AssetManager {
+    /// Loads from a directory on disk, outside of the global vfs
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        AssetManager::from_vfs(Arc::new(Vfs::new().with_mount("/", DirectoryMount::read_only(root))), "/")
+    }
+
+    /// Loads from `root` in `vfs`, e.g. `/assets` in the global vfs
+    pub fn from_vfs(vfs: Arc<Vfs>, root: &str) -> Self {
+        AssetManager {
+            vfs,
+            root: vfs::normalize(root).expect("invalid asset root"),
+            typed: HashMap::new(),
+            by_extension: HashMap::new(),
+            log: log::get(),
+        }
+    }
+
+    /// Virtual path asset paths are relative to
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    pub fn vfs(&self) -> &Arc<Vfs> {
+        &self.vfs
+    }
+
+    pub fn add_loader<L: AssetLoader>(&mut self, loader: L) {
+        let loader = Arc::new(loader);
+
+        for extension in loader.extensions() {
+            let extension_loader = loader.clone();
+            let load: ErasedLoad = Arc::new(move |world, bytes, path| {
+                let asset = extension_loader.load(bytes, path)?;
+                Ok(store::<L::Asset>(world, path, asset).uid())
+            });
+            let replaced = self.by_extension.insert(String::from(*extension), load);
+            debug_assert!(replaced.is_none(), "duplicate loader for extension {}", extension);
+        }
+
+        let typed: Arc<dyn AssetLoader<Asset = L::Asset>> = loader;
+        self.typed.insert(TypeId::of::<L::Asset>(), Box::new(typed));
+    }
+
+    /// Loads an asset of type `T` from `path` relative to the asset root. Paths that were already loaded return the existing handle
+    pub fn load<T: Asset>(&self, world: &World, path: impl AsRef<Path>) -> Result<Handle<T>, AssetError> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(id) = world.with_resource::<Assets<T>, _>(|assets| assets.id_of(&path)).flatten() {
+            return Ok(id)
+        }
+
+        let loader = self.typed.get(&TypeId::of::<T>())
+            .and_then(|l| l.downcast_ref::<Arc<dyn AssetLoader<Asset = T>>>())
+            .ok_or(AssetError::NoLoader(path.clone()))?;
+
+        let bytes = self.read(&path)?;
+        let asset = loader.load(&bytes, &path)?;
+        Ok(store::<T>(world, &path, asset))
+    }
+
+    /// Loads an asset choosing the loader by file extension, for references whose type isn't known statically
+    pub fn load_untyped(&self, world: &World, path: impl AsRef<Path>) -> Result<UniqueId, AssetError> {
+        let path = path.as_ref().to_path_buf();
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+        let load = self.by_extension.get(extension).ok_or(AssetError::NoLoader(path.clone()))?;
+
+        let bytes = self.read(&path)?;
+        load(world, &bytes, &path)
+    }
+
+    /// Parses `bytes` as the asset at `path` and stores it, replacing whatever was loaded from the path before under the
+    /// same handle. For files read with `read_bytes`, e.g. to hot reload them
+    pub fn load_bytes<T: Asset>(&self, world: &World, path: impl AsRef<Path>, bytes: &[u8]) -> Result<Handle<T>, AssetError> {
+        let path = path.as_ref();
+        let load = self.typed_load::<T>(path)?;
+        load(world, bytes, path).map(UniqueId::typed)
+    }
+
+    /// Reads a file relative to the asset root without loading it
+    pub fn read_bytes(&self, path: impl AsRef<Path>) -> Result<Vec<u8>, AssetError> {
+        let full_path = self.full_path(path.as_ref());
+        self.vfs.read(&full_path).map_err(|err| AssetError::Io(PathBuf::from(full_path), err.into()))
+    }
+
+    /// The loader for `T`, storing into `Assets<T>`, for loads that read the file themselves
+    pub(crate) fn typed_load<T: Asset>(&self, path: &Path) -> Result<ErasedLoad, AssetError> {
+        let loader = self.typed.get(&TypeId::of::<T>())
+            .and_then(|l| l.downcast_ref::<Arc<dyn AssetLoader<Asset = T>>>())
+            .ok_or(AssetError::NoLoader(path.to_path_buf()))?
+            .clone();
+        Ok(Arc::new(move |world, bytes, path| Ok(store::<T>(world, path, loader.load(bytes, path)?).uid())))
+    }
+
+    /// The loader for `path`'s extension, see `load_untyped`
+    pub(crate) fn untyped_load(&self, path: &Path) -> Result<ErasedLoad, AssetError> {
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+        self.by_extension.get(extension).cloned().ok_or(AssetError::NoLoader(path.to_path_buf()))
+    }
+
+    /// Starts reading `path` on the vfs io threads
+    pub(crate) fn read_async(&self, path: &Path, priority: LoadPriority) -> ReadHandle {
+        let full_path = self.full_path(path);
+        self.log.info(format!("loading asset {}", full_path));
+        self.vfs.read_async_with(&full_path, priority.into())
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>, AssetError> {
+        let full_path = self.full_path(path);
+        self.log.info(format!("loading asset {}", full_path));
+        self.vfs.read(&full_path).map_err(|err| AssetError::Io(PathBuf::from(full_path), err.into()))
+    }
+
+    fn full_path(&self, path: &Path) -> String {
+        format!("{}/{}", self.root.trim_end_matches('/'), path.to_string_lossy().replace('\\', "/"))
+    }
+}
+
+impl std::fmt::Debug for AssetManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AssetManager").field("root", &self.root).field("extensions", &self.by_extension.keys()).finish()
+    }
+}
+
+fn store<T: Asset>(world: &World, path: &Path, asset: T) -> Handle<T> {
+    if !world.contains_resource::<Assets<T>>() {
+        world.insert_resource(Assets::<T>::default());
+    }
+    world.with_resource_mut::<Assets<T>, _>(|assets| assets.insert_loaded(path.to_path_buf(), asset)).expect("no asset storage")
+}
+
+/// Loads an asset through the world's `AssetManager`
+pub fn load<T: Asset>(world: &World, path: impl AsRef<Path>) -> Result<Handle<T>, AssetError> {
+    world.with_resource::<AssetManager, _>(|manager| manager.load::<T>(world, path))
+        .unwrap_or_else(|| panic!("no asset manager in world"))
+}
+
+/// Returns a loaded asset by handle
+pub fn get<T: Asset>(world: &World, handle: Handle<T>) -> Option<Arc<T>> {
+    world.with_resource::<Assets<T>, _>(|assets| assets.get(handle)).flatten()
+}
+
+/// Subscribes `TextureQuality` to `MemoryPressure`, textures uploaded under pressure leave out a mip for each level
+pub fn drop_mips_for_pressure(world: &World) {
+    let Some(level) = pressure::latest_pressure(world) else { return };
+    let dropped_mips = level as u32;
+    if !world.contains_resource::<TextureQuality>() {
+        world.insert_resource(TextureQuality::default());
+    }
+    world.with_resource_mut::<TextureQuality, _>(|quality| quality.dropped_mips = dropped_mips);
+}
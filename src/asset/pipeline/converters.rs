@@ -0,0 +1,381 @@
+//!
+//! Built in converters for PNG textures, glTF meshes, GLSL shaders and texture atlases
+//!
+
+use std::{collections::BTreeSet, path::{Path, PathBuf}, process::Command};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::super::{AssetError, atlas::AtlasBuilder};
+use super::formats::{AssetKind, MeshPrimitive, Mesh, ShaderModule, ShaderStage, Texture};
+use super::AssetConverter;
+use crate::unique::UniqueId;
+
+pub(super) const GLB_MAGIC: u32 = u32::from_le_bytes(*b"glTF");
+pub(super) const GLB_JSON_CHUNK: u32 = u32::from_le_bytes(*b"JSON");
+pub(super) const GLB_BIN_CHUNK: u32 = u32::from_le_bytes(*b"BIN\0");
+
+/// Decodes PNGs of any color type and bit depth into RGBA8
+pub struct PngConverter;
+
+/// Flattens every triangle primitive of every mesh in a glTF or GLB file, ignoring node transforms and materials
+pub struct GltfConverter;
+
+/// Compiles `.vert`, `.frag` and `.comp` shaders to SPIR-V with an external `glslc`
+pub struct GlslConverter {
+    compiler: PathBuf,
+}
+
+/// Packs the PNGs listed by a `.atlas` file into an atlas, each named by its path in the file without the extension
+pub struct AtlasConverter;
+
+/// The contents of a `.atlas` file
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+struct AtlasSource {
+    page_width: u32,
+    page_height: u32,
+    padding: u32,
+    /// Paths relative to the `.atlas` file
+    images: Vec<String>,
+}
+
+/// The JSON and binary chunk of a glTF file
+struct GltfDocument {
+    json: Value,
+    glb_buffer: Option<Vec<u8>>,
+}
+
+// Impls
+
+impl AssetConverter for PngConverter {
+    fn kind(&self) -> AssetKind {
+        AssetKind::Texture
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &["png"]
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn convert(&self, path: &Path, bytes: &[u8], uid: UniqueId) -> Result<Vec<u8>, AssetError> {
+        decode_png(path, bytes, uid).map(|texture| texture.payload())
+    }
+}
+
+impl AssetConverter for GltfConverter {
+    fn kind(&self) -> AssetKind {
+        AssetKind::Mesh
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &["gltf", "glb"]
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn dependencies(&self, path: &Path, bytes: &[u8]) -> Result<Vec<PathBuf>, AssetError> {
+        let document = GltfDocument::parse(path, bytes)?;
+        let directory = path.parent().unwrap_or(Path::new(""));
+        let uris = ["buffers", "images"].iter()
+            .filter_map(|key| document.json[key].as_array())
+            .flatten()
+            .filter_map(|entry| entry["uri"].as_str())
+            .filter(|uri| !uri.starts_with("data:"))
+            .map(|uri| directory.join(uri));
+        Ok(uris.collect())
+    }
+
+    fn convert(&self, path: &Path, bytes: &[u8], uid: UniqueId) -> Result<Vec<u8>, AssetError> {
+        let document = GltfDocument::parse(path, bytes)?;
+        let buffers = document.load_buffers(path)?;
+        let convert_error = |err: String| AssetError::Convert(path.to_path_buf(), err);
+
+        let mut primitives = Vec::new();
+        for mesh in document.json["meshes"].as_array().into_iter().flatten() {
+            for primitive in mesh["primitives"].as_array().into_iter().flatten() {
+                if primitive["mode"].as_u64().unwrap_or(4) != 4 {
+                    return Err(convert_error(String::from("only triangle list primitives are supported")))
+                }
+                let attributes = &primitive["attributes"];
+                let position = attributes["POSITION"].as_u64().ok_or_else(|| convert_error(String::from("primitive without positions")))?;
+
+                let positions = read_floats(&document.json, &buffers, position as usize, 3).map_err(convert_error)?;
+                let vertices = positions.len() / 3;
+                let optional = |name: &str, components: usize| match attributes[name].as_u64() {
+                    Some(accessor) => read_floats(&document.json, &buffers, accessor as usize, components).map(Some),
+                    None => Ok(None),
+                };
+                let normals = optional("NORMAL", 3).map_err(convert_error)?;
+                let uvs = optional("TEXCOORD_0", 2).map_err(convert_error)?;
+                let indices = match primitive["indices"].as_u64() {
+                    Some(accessor) => read_indices(&document.json, &buffers, accessor as usize).map_err(convert_error)?,
+                    None => (0..vertices as u32).collect(),
+                };
+
+                primitives.push(MeshPrimitive {
+                    positions: positions.chunks_exact(3).map(|v| [v[0], v[1], v[2]]).collect(),
+                    normals: normals.map_or_else(Vec::new, |n| n.chunks_exact(3).map(|v| [v[0], v[1], v[2]]).collect()),
+                    uvs: uvs.map_or_else(Vec::new, |uv| uv.chunks_exact(2).map(|v| [v[0], v[1]]).collect()),
+                    indices,
+                });
+            }
+        }
+        Ok(Mesh { uid, primitives }.payload())
+    }
+}
+
+impl Default for GlslConverter {
+    fn default() -> Self {
+        GlslConverter { compiler: PathBuf::from("glslc") }
+    }
+}
+
+impl GlslConverter {
+    /// Uses the compiler at `compiler` instead of `glslc` from the path
+    pub fn with_compiler<P: Into<PathBuf>>(mut self, compiler: P) -> Self {
+        self.compiler = compiler.into();
+        self
+    }
+}
+
+impl AssetConverter for GlslConverter {
+    fn kind(&self) -> AssetKind {
+        AssetKind::Shader
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &["vert", "frag", "comp"]
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    /// Every file pulled in through `#include`, recursively
+    fn dependencies(&self, path: &Path, bytes: &[u8]) -> Result<Vec<PathBuf>, AssetError> {
+        let mut found = BTreeSet::new();
+        let mut pending = includes(path, &String::from_utf8_lossy(bytes));
+        while let Some(include) = pending.pop() {
+            if found.insert(include.clone()) {
+                let source = std::fs::read_to_string(&include).map_err(|_| AssetError::MissingDependency(path.to_path_buf(), include.clone()))?;
+                pending.extend(includes(&include, &source));
+            }
+        }
+        Ok(found.into_iter().collect())
+    }
+
+    fn convert(&self, path: &Path, _bytes: &[u8], uid: UniqueId) -> Result<Vec<u8>, AssetError> {
+        let convert_error = |err: String| AssetError::Convert(path.to_path_buf(), err);
+        let stage = path.extension().and_then(|e| e.to_str()).and_then(ShaderStage::from_extension)
+            .ok_or_else(|| convert_error(String::from("unknown shader stage")))?;
+
+        let output = Command::new(&self.compiler)
+            .arg(format!("-fshader-stage={}", stage.glslc_name()))
+            .arg(path)
+            .args(["-o", "-"])
+            .output()
+            .map_err(|err| convert_error(format!("unable to run {}: {}", self.compiler.display(), err)))?;
+        if !output.status.success() {
+            return Err(convert_error(String::from_utf8_lossy(&output.stderr).trim().to_string()))
+        }
+        if output.stdout.len() % 4 != 0 {
+            return Err(convert_error(String::from("compiler output isn't SPIR-V")))
+        }
+
+        let spirv = output.stdout.chunks_exact(4).map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]])).collect();
+        Ok(ShaderModule { uid, stage, spirv }.payload())
+    }
+}
+
+impl Default for AtlasSource {
+    fn default() -> Self {
+        AtlasSource { page_width: 2048, page_height: 2048, padding: 1, images: Vec::new() }
+    }
+}
+
+impl AtlasSource {
+    fn parse(path: &Path, bytes: &[u8]) -> Result<Self, AssetError> {
+        serde_json::from_slice(bytes).map_err(|err| AssetError::Parse(path.to_path_buf(), err.to_string()))
+    }
+}
+
+impl AssetConverter for AtlasConverter {
+    fn kind(&self) -> AssetKind {
+        AssetKind::Atlas
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &["atlas"]
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn dependencies(&self, path: &Path, bytes: &[u8]) -> Result<Vec<PathBuf>, AssetError> {
+        let directory = path.parent().unwrap_or(Path::new(""));
+        Ok(AtlasSource::parse(path, bytes)?.images.iter().map(|image| directory.join(image)).collect())
+    }
+
+    fn convert(&self, path: &Path, bytes: &[u8], uid: UniqueId) -> Result<Vec<u8>, AssetError> {
+        let source = AtlasSource::parse(path, bytes)?;
+        let directory = path.parent().unwrap_or(Path::new(""));
+        let mut builder = AtlasBuilder::new().with_page_size(source.page_width, source.page_height).with_padding(source.padding);
+        for image in &source.images {
+            let file = directory.join(image);
+            let bytes = std::fs::read(&file).map_err(|_| AssetError::MissingDependency(path.to_path_buf(), file.clone()))?;
+            let name = Path::new(image).with_extension("");
+            builder.add(&name.to_string_lossy(), decode_png(&file, &bytes, uid)?);
+        }
+        let atlas = builder.build(uid).map_err(|err| AssetError::Convert(path.to_path_buf(), err.to_string()))?;
+        Ok(atlas.payload())
+    }
+}
+
+impl GltfDocument {
+    fn parse(path: &Path, bytes: &[u8]) -> Result<Self, AssetError> {
+        let parse_error = |err: String| AssetError::Parse(path.to_path_buf(), err);
+        let word = |offset: usize| bytes.get(offset..offset + 4).map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]));
+
+        if word(0) != Some(GLB_MAGIC) {
+            let json = serde_json::from_slice(bytes).map_err(|err| parse_error(err.to_string()))?;
+            return Ok(GltfDocument { json, glb_buffer: None })
+        }
+
+        // GLB: a 12 byte header then length prefixed chunks, JSON first and an optional binary buffer after it
+        let (mut json, mut glb_buffer) = (None, None);
+        let mut offset = 12;
+        while let (Some(len), Some(kind)) = (word(offset), word(offset + 4)) {
+            let chunk = bytes.get(offset + 8..offset + 8 + len as usize).ok_or_else(|| parse_error(String::from("truncated GLB chunk")))?;
+            match kind {
+                GLB_JSON_CHUNK => json = Some(serde_json::from_slice(chunk).map_err(|err| parse_error(err.to_string()))?),
+                GLB_BIN_CHUNK => glb_buffer = Some(chunk.to_vec()),
+                _ => (),
+            }
+            offset += 8 + len as usize;
+        }
+        let json = json.ok_or_else(|| parse_error(String::from("GLB without a JSON chunk")))?;
+        Ok(GltfDocument { json, glb_buffer })
+    }
+
+    fn load_buffers(&self, path: &Path) -> Result<Vec<Vec<u8>>, AssetError> {
+        let directory = path.parent().unwrap_or(Path::new(""));
+        self.json["buffers"].as_array().into_iter().flatten().map(|buffer| {
+            match buffer["uri"].as_str() {
+                Some(uri) if uri.starts_with("data:") => {
+                    let (_, encoded) = uri.split_once(";base64,").ok_or_else(|| AssetError::Convert(path.to_path_buf(), String::from("unsupported data uri")))?;
+                    decode_base64(encoded).ok_or_else(|| AssetError::Convert(path.to_path_buf(), String::from("invalid base64 buffer")))
+                },
+                Some(uri) => {
+                    let dependency = directory.join(uri);
+                    std::fs::read(&dependency).map_err(|_| AssetError::MissingDependency(path.to_path_buf(), dependency))
+                },
+                None => self.glb_buffer.clone().ok_or_else(|| AssetError::Convert(path.to_path_buf(), String::from("buffer without data"))),
+            }
+        }).collect()
+    }
+}
+
+/// Decodes a PNG of any color type and bit depth into an RGBA8 texture
+fn decode_png(path: &Path, bytes: &[u8], uid: UniqueId) -> Result<Texture, AssetError> {
+    let convert_error = |err: png::DecodingError| AssetError::Convert(path.to_path_buf(), err.to_string());
+
+    let mut decoder = png::Decoder::new(bytes);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().map_err(convert_error)?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut buffer).map_err(convert_error)?;
+    let buffer = &buffer[..frame.buffer_size()];
+
+    let pixels = match frame.color_type {
+        png::ColorType::Rgba => buffer.to_vec(),
+        png::ColorType::Rgb => buffer.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect(),
+        png::ColorType::GrayscaleAlpha => buffer.chunks_exact(2).flat_map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+        png::ColorType::Grayscale => buffer.iter().flat_map(|g| [*g, *g, *g, 255]).collect(),
+        png::ColorType::Indexed => return Err(AssetError::Convert(path.to_path_buf(), String::from("palette wasn't expanded"))),
+    };
+    Ok(Texture { uid, width: frame.width, height: frame.height, pixels })
+}
+
+/// Resolves `#include "file"` and `#include <file>` directives relative to the including file
+fn includes(path: &Path, source: &str) -> Vec<PathBuf> {
+    let directory = path.parent().unwrap_or(Path::new(""));
+    source.lines()
+        .filter_map(|line| line.trim_start().strip_prefix("#include"))
+        .filter_map(|rest| rest.trim().get(1..).and_then(|name| name.split(['"', '>']).next()))
+        .map(|name| directory.join(name))
+        .collect()
+}
+
+/// Element bytes of an accessor, with its component type
+fn accessor_elements<'a>(json: &Value, buffers: &'a [Vec<u8>], index: usize, components: usize) -> Result<(Vec<&'a [u8]>, u64), String> {
+    let accessor = &json["accessors"][index];
+    if accessor.get("sparse").is_some() {
+        return Err(String::from("sparse accessors are not supported"))
+    }
+    let count = accessor["count"].as_u64().ok_or("accessor without a count")? as usize;
+    let component_type = accessor["componentType"].as_u64().ok_or("accessor without a component type")?;
+    let component_size = match component_type {
+        5120 | 5121 => 1,
+        5122 | 5123 => 2,
+        5125 | 5126 => 4,
+        other => return Err(format!("unknown component type {}", other)),
+    };
+
+    let view = &json["bufferViews"][accessor["bufferView"].as_u64().ok_or("accessor without a buffer view")? as usize];
+    let buffer = buffers.get(view["buffer"].as_u64().unwrap_or(0) as usize).ok_or("buffer view refers to a missing buffer")?;
+    let element_size = component_size * components;
+    let stride = view["byteStride"].as_u64().map_or(element_size, |stride| stride as usize);
+    let start = view["byteOffset"].as_u64().unwrap_or(0) as usize + accessor["byteOffset"].as_u64().unwrap_or(0) as usize;
+
+    (0..count)
+        .map(|i| buffer.get(start + i * stride..start + i * stride + element_size).ok_or_else(|| String::from("accessor reads past the end of its buffer")))
+        .collect::<Result<Vec<_>, _>>()
+        .map(|elements| (elements, component_type))
+}
+
+fn read_floats(json: &Value, buffers: &[Vec<u8>], index: usize, components: usize) -> Result<Vec<f32>, String> {
+    let (elements, component_type) = accessor_elements(json, buffers, index, components)?;
+    if component_type != 5126 {
+        return Err(String::from("only float vertex attributes are supported"))
+    }
+    Ok(elements.iter().flat_map(|element| element.chunks_exact(4).map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))).collect())
+}
+
+fn read_indices(json: &Value, buffers: &[Vec<u8>], index: usize) -> Result<Vec<u32>, String> {
+    let (elements, component_type) = accessor_elements(json, buffers, index, 1)?;
+    Ok(elements.iter().map(|e| match component_type {
+        5121 => e[0] as u32,
+        5123 => u16::from_le_bytes([e[0], e[1]]) as u32,
+        _ => u32::from_le_bytes([e[0], e[1], e[2], e[3]]),
+    }).collect())
+}
+
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let value = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    };
+    let mut bytes = Vec::with_capacity(encoded.len() * 3 / 4);
+    let (mut bits, mut count) = (0u32, 0);
+    for c in encoded.bytes().filter(|c| *c != b'=') {
+        bits = bits << 6 | value(c)? as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+    Some(bytes)
+}
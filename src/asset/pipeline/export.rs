@@ -0,0 +1,208 @@
+//!
+//! glTF export of the world's scene
+//!
+//! Writes every entity with a `Transform` as a glTF node, keeping the parent/child hierarchy, names and the most
+//! detailed mesh of each `LodGroup`. Meshes shared between entities are written once. The importer drops materials, so
+//! none are exported. The output is a single GLB, which the `GltfConverter` reads back unchanged, handy for inspecting
+//! procedural placement in other tools or roundtripping scenes through them
+//!
+
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use collider::EntityId;
+use serde_json::{json, Value};
+
+use crate::{asset::{self, AssetError, AssetManager}, unique::UniqueId};
+use crate::graphics::lod::LodGroup;
+use crate::system::{world::World, hierarchy::{self, Parent}, prefab::Name, transform::Transform};
+
+use super::{converters::{GLB_BIN_CHUNK, GLB_JSON_CHUNK, GLB_MAGIC}, formats::{Mesh, MeshPrimitive}};
+
+/// An entity to export and its children
+#[derive(Debug, Clone, PartialEq)]
+pub struct SceneNode {
+    pub name: Option<String>,
+    pub transform: Transform,
+    pub mesh: Option<Arc<Mesh>>,
+    pub children: Vec<SceneNode>,
+}
+
+/// Accumulates the JSON arrays and binary buffer of a glTF document
+#[derive(Default)]
+struct GltfWriter {
+    nodes: Vec<Value>,
+    meshes: Vec<Value>,
+    accessors: Vec<Value>,
+    views: Vec<Value>,
+    buffer: Vec<u8>,
+    written: HashMap<UniqueId, usize>,
+}
+
+// Impls
+
+impl GltfWriter {
+    /// Writes `node` and its children, returning its index
+    fn node(&mut self, node: &SceneNode) -> usize {
+        let children: Vec<usize> = node.children.iter().map(|child| self.node(child)).collect();
+        let mut value = json!({
+            "translation": node.transform.translation,
+            "rotation": node.transform.rotation,
+            "scale": node.transform.scale,
+        });
+        if let Some(name) = &node.name {
+            value["name"] = json!(name);
+        }
+        if let Some(mesh) = &node.mesh {
+            value["mesh"] = json!(self.mesh(mesh));
+        }
+        if !children.is_empty() {
+            value["children"] = json!(children);
+        }
+        self.nodes.push(value);
+        self.nodes.len() - 1
+    }
+
+    fn mesh(&mut self, mesh: &Mesh) -> usize {
+        if let Some(index) = self.written.get(&mesh.uid) {
+            return *index
+        }
+
+        let primitives: Vec<Value> = mesh.primitives.iter().map(|primitive| self.primitive(primitive)).collect();
+        self.meshes.push(json!({ "primitives": primitives }));
+        self.written.insert(mesh.uid, self.meshes.len() - 1);
+        self.meshes.len() - 1
+    }
+
+    fn primitive(&mut self, primitive: &MeshPrimitive) -> Value {
+        let vertices = primitive.positions.len();
+        let mut min = [f32::MAX; 3];
+        let mut max = [f32::MIN; 3];
+        for position in &primitive.positions {
+            (0..3).for_each(|i| {
+                min[i] = min[i].min(position[i]);
+                max[i] = max[i].max(position[i]);
+            });
+        }
+
+        let position = self.accessor(bytes(primitive.positions.iter().flatten()), vertices, "VEC3", 5126, 34962);
+        self.accessors[position]["min"] = json!(min);
+        self.accessors[position]["max"] = json!(max);
+        let mut attributes = json!({ "POSITION": position });
+        if primitive.normals.len() == vertices {
+            attributes["NORMAL"] = json!(self.accessor(bytes(primitive.normals.iter().flatten()), vertices, "VEC3", 5126, 34962));
+        }
+        if primitive.uvs.len() == vertices {
+            attributes["TEXCOORD_0"] = json!(self.accessor(bytes(primitive.uvs.iter().flatten()), vertices, "VEC2", 5126, 34962));
+        }
+        let indices = self.accessor(primitive.indices.iter().flat_map(|index| index.to_le_bytes()).collect(), primitive.indices.len(), "SCALAR", 5125, 34963);
+
+        json!({ "attributes": attributes, "indices": indices, "mode": 4 })
+    }
+
+    /// Appends `data` as a buffer view and an accessor reading it, returning the accessor's index
+    fn accessor(&mut self, data: Vec<u8>, count: usize, kind: &str, component_type: u32, target: u32) -> usize {
+        self.views.push(json!({ "buffer": 0, "byteOffset": self.buffer.len(), "byteLength": data.len(), "target": target }));
+        self.buffer.extend(data);
+        self.buffer.resize(self.buffer.len().next_multiple_of(4), 0);
+        self.accessors.push(json!({ "bufferView": self.views.len() - 1, "componentType": component_type, "count": count, "type": kind }));
+        self.accessors.len() - 1
+    }
+
+    fn finish(self, roots: Vec<usize>) -> Vec<u8> {
+        let mut document = json!({
+            "asset": { "version": "2.0", "generator": "hadron" },
+            "scene": 0,
+            "scenes": [{ "nodes": roots }],
+            "nodes": self.nodes,
+        });
+        if !self.meshes.is_empty() {
+            document["meshes"] = json!(self.meshes);
+            document["accessors"] = json!(self.accessors);
+            document["bufferViews"] = json!(self.views);
+            document["buffers"] = json!([{ "byteLength": self.buffer.len() }]);
+        }
+
+        let mut json = serde_json::to_vec(&document).expect("unable to serialize glTF document");
+        json.resize(json.len().next_multiple_of(4), b' ');
+        let chunks = [(GLB_JSON_CHUNK, json), (GLB_BIN_CHUNK, self.buffer)];
+        let chunks: Vec<_> = chunks.into_iter().filter(|(_, chunk)| !chunk.is_empty()).collect();
+
+        let length = 12 + chunks.iter().map(|(_, chunk)| 8 + chunk.len()).sum::<usize>();
+        let mut glb = Vec::with_capacity(length);
+        [GLB_MAGIC, 2, length as u32].iter().for_each(|word| glb.extend(word.to_le_bytes()));
+        for (kind, chunk) in chunks {
+            glb.extend((chunk.len() as u32).to_le_bytes());
+            glb.extend(kind.to_le_bytes());
+            glb.extend(chunk);
+        }
+        glb
+    }
+}
+
+/// The world's scene as a tree, rooted at every entity with a `Transform` and no parent
+pub fn collect_scene(world: &World) -> Vec<SceneNode> {
+    world.query::<Transform, ()>().into_iter()
+        .filter(|entity| !world.has_component::<Parent>(*entity))
+        .map(|entity| collect_node(world, entity))
+        .collect()
+}
+
+fn collect_node(world: &World, entity: EntityId) -> SceneNode {
+    SceneNode {
+        name: world.component::<Name, _>(entity, |name| name.0.clone()),
+        transform: world.component::<Transform, _>(entity, |transform| *transform).unwrap_or_default(),
+        mesh: lod_mesh(world, entity),
+        children: hierarchy::children(world, entity).into_iter()
+            .filter(|child| world.has_component::<Transform>(*child))
+            .map(|child| collect_node(world, child))
+            .collect(),
+    }
+}
+
+/// The most detailed mesh of the entity's `LodGroup`, if it has loaded
+fn lod_mesh(world: &World, entity: EntityId) -> Option<Arc<Mesh>> {
+    let path = world.component::<LodGroup, _>(entity, |group| group.levels.first().map(|level| level.mesh.clone()))??;
+    world.with_resource::<AssetManager, _>(|_| ())?;
+    asset::load::<Mesh>(world, path).ok().and_then(|id| asset::get::<Mesh>(world, id))
+}
+
+/// Encodes a scene as a GLB
+pub fn export_glb(nodes: &[SceneNode]) -> Vec<u8> {
+    let mut writer = GltfWriter::default();
+    let roots = nodes.iter().map(|node| writer.node(node)).collect();
+    writer.finish(roots)
+}
+
+/// Writes the world's scene to a GLB file at `path`
+pub fn export_scene(world: &World, path: impl AsRef<Path>) -> Result<(), AssetError> {
+    let path = path.as_ref();
+    std::fs::write(path, export_glb(&collect_scene(world))).map_err(|err| AssetError::Convert(path.to_path_buf(), format!("unable to write scene: {}", err)))
+}
+
+fn bytes<'a>(values: impl Iterator<Item = &'a f32>) -> Vec<u8> {
+    values.flat_map(|value| value.to_le_bytes()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{AssetConverter, GltfConverter};
+
+    #[test]
+    fn exported_meshes_import_unchanged() {
+        let triangle = MeshPrimitive {
+            positions: vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]],
+            normals: vec![[0.0, 0.0, 1.0]; 3],
+            uvs: vec![[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]],
+            indices: vec![0, 1, 2],
+        };
+        let mesh = Arc::new(Mesh { uid: UniqueId::get(), primitives: vec![triangle] });
+        let node = |name: &str, children| SceneNode { name: Some(String::from(name)), transform: Transform::from_translation(1.0, 2.0, 3.0), mesh: Some(mesh.clone()), children };
+        let glb = export_glb(&[node("parent", vec![node("child", Vec::new())])]);
+
+        let uid = UniqueId::get();
+        let payload = GltfConverter.convert(Path::new("scene.glb"), &glb, uid).unwrap();
+        // Both nodes share the mesh, so it's written once
+        assert_eq!(Mesh::from_payload(uid, &payload).unwrap().primitives, mesh.primitives);
+    }
+}
@@ -0,0 +1,350 @@
+//!
+//! Engine-ready binary asset formats
+//!
+//! Every built asset starts with the same header: a magic number, the format version, the asset kind and the asset's
+//! `UniqueId`, followed by a little endian payload specific to the kind. Loaders for each format are provided so built
+//! assets can be loaded through the `AssetManager` like any other
+//!
+
+use std::path::Path;
+
+use serde::{Serialize, Deserialize};
+
+use crate::unique::UniqueId;
+
+use super::super::{AssetError, AssetLoader, atlas::Atlas};
+
+const ASSET_MAGIC: [u8; 4] = *b"HAST";
+
+/// Bumped whenever a payload layout changes, which rebuilds every asset
+pub const FORMAT_VERSION: u32 = 1;
+
+const HEADER_LEN: usize = 28;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AssetKind {
+    Texture,
+    Mesh,
+    Shader,
+    Atlas,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShaderStage {
+    Vertex,
+    Fragment,
+    Compute,
+}
+
+/// RGBA8 pixels, row major from the top left
+#[derive(Debug, Clone, PartialEq)]
+pub struct Texture {
+    pub uid: UniqueId,
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+/// World resource of how many of their largest mips textures leave out when they're uploaded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TextureQuality {
+    pub dropped_mips: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShaderModule {
+    pub uid: UniqueId,
+    pub stage: ShaderStage,
+    pub spirv: Vec<u32>,
+}
+
+/// Triangle list with one attribute entry per vertex, missing attributes are zero filled
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MeshPrimitive {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    pub uvs: Vec<[f32; 2]>,
+    pub indices: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mesh {
+    pub uid: UniqueId,
+    pub primitives: Vec<MeshPrimitive>,
+}
+
+pub struct TextureLoader;
+pub struct MeshLoader;
+pub struct ShaderLoader;
+pub struct AtlasLoader;
+
+/// Little endian cursor over a payload
+pub(crate) struct PayloadReader<'a> {
+    bytes: &'a [u8],
+}
+
+// Impls
+
+impl AssetKind {
+    /// Extension of the built file
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AssetKind::Texture => "htex",
+            AssetKind::Mesh => "hmesh",
+            AssetKind::Shader => "hspv",
+            AssetKind::Atlas => "hatlas",
+        }
+    }
+
+    fn tag(&self) -> u32 {
+        match self {
+            AssetKind::Texture => 0,
+            AssetKind::Mesh => 1,
+            AssetKind::Shader => 2,
+            AssetKind::Atlas => 3,
+        }
+    }
+
+    fn from_tag(tag: u32) -> Option<Self> {
+        match tag {
+            0 => Some(AssetKind::Texture),
+            1 => Some(AssetKind::Mesh),
+            2 => Some(AssetKind::Shader),
+            3 => Some(AssetKind::Atlas),
+            _ => None,
+        }
+    }
+}
+
+impl ShaderStage {
+    /// Stage from a shader's file extension
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "vert" => Some(ShaderStage::Vertex),
+            "frag" => Some(ShaderStage::Fragment),
+            "comp" => Some(ShaderStage::Compute),
+            _ => None,
+        }
+    }
+
+    /// Name `glslc` expects for `-fshader-stage`
+    pub fn glslc_name(&self) -> &'static str {
+        match self {
+            ShaderStage::Vertex => "vert",
+            ShaderStage::Fragment => "frag",
+            ShaderStage::Compute => "comp",
+        }
+    }
+}
+
+impl Texture {
+    pub fn payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(8 + self.pixels.len());
+        payload.extend_from_slice(&self.width.to_le_bytes());
+        payload.extend_from_slice(&self.height.to_le_bytes());
+        payload.extend_from_slice(&self.pixels);
+        payload
+    }
+
+    pub fn from_payload(uid: UniqueId, payload: &[u8]) -> Option<Self> {
+        let mut reader = PayloadReader { bytes: payload };
+        let (width, height) = (reader.u32()?, reader.u32()?);
+        let pixels = reader.take(width as usize * height as usize * 4)?.to_vec();
+        Some(Texture { uid, width, height, pixels })
+    }
+
+    /// Mip `level` of the texture, each level averaging 2x2 blocks of the one above down to 1x1
+    pub fn mip(&self, level: u32) -> Texture {
+        let mut mip = self.clone();
+        for _ in 0..level {
+            if mip.width <= 1 && mip.height <= 1 {
+                break
+            }
+            let (width, height) = ((mip.width / 2).max(1), (mip.height / 2).max(1));
+            let texel = |x: u32, y: u32, channel: usize| u32::from(mip.pixels[((y.min(mip.height - 1) * mip.width + x.min(mip.width - 1)) * 4) as usize + channel]);
+            let pixels = (0..height).flat_map(|y| (0..width).map(move |x| (x * 2, y * 2)))
+                .flat_map(|(x, y)| (0..4).map(move |channel| (x, y, channel)))
+                .map(|(x, y, channel)| ((texel(x, y, channel) + texel(x + 1, y, channel) + texel(x, y + 1, channel) + texel(x + 1, y + 1, channel) + 2) / 4) as u8)
+                .collect();
+            mip = Texture { uid: self.uid, width, height, pixels };
+        }
+        mip
+    }
+}
+
+impl ShaderModule {
+    pub fn payload(&self) -> Vec<u8> {
+        let stage = match self.stage {
+            ShaderStage::Vertex => 0u32,
+            ShaderStage::Fragment => 1,
+            ShaderStage::Compute => 2,
+        };
+        std::iter::once(stage).chain(self.spirv.iter().copied()).flat_map(u32::to_le_bytes).collect()
+    }
+
+    pub fn from_payload(uid: UniqueId, payload: &[u8]) -> Option<Self> {
+        let mut reader = PayloadReader { bytes: payload };
+        let stage = match reader.u32()? {
+            0 => ShaderStage::Vertex,
+            1 => ShaderStage::Fragment,
+            2 => ShaderStage::Compute,
+            _ => return None,
+        };
+        let mut spirv = Vec::with_capacity(reader.bytes.len() / 4);
+        while !reader.bytes.is_empty() {
+            spirv.push(reader.u32()?);
+        }
+        Some(ShaderModule { uid, stage, spirv })
+    }
+}
+
+impl Mesh {
+    pub fn payload(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&(self.primitives.len() as u32).to_le_bytes());
+        for primitive in &self.primitives {
+            let vertices = primitive.positions.len();
+            payload.extend_from_slice(&(vertices as u32).to_le_bytes());
+            payload.extend_from_slice(&(primitive.indices.len() as u32).to_le_bytes());
+
+            let normals = (0..vertices).map(|i| primitive.normals.get(i).copied().unwrap_or_default());
+            let uvs = (0..vertices).map(|i| primitive.uvs.get(i).copied().unwrap_or_default());
+            primitive.positions.iter().copied().chain(normals).flatten()
+                .chain(uvs.flatten())
+                .for_each(|value| payload.extend_from_slice(&value.to_le_bytes()));
+            primitive.indices.iter().for_each(|index| payload.extend_from_slice(&index.to_le_bytes()));
+        }
+        payload
+    }
+
+    pub fn from_payload(uid: UniqueId, payload: &[u8]) -> Option<Self> {
+        let mut reader = PayloadReader { bytes: payload };
+        let count = reader.u32()?;
+        let mut primitives = Vec::new();
+        for _ in 0..count {
+            let (vertices, indices) = (reader.u32()? as usize, reader.u32()? as usize);
+            let mut primitive = MeshPrimitive::default();
+            for _ in 0..vertices {
+                primitive.positions.push([reader.f32()?, reader.f32()?, reader.f32()?]);
+            }
+            for _ in 0..vertices {
+                primitive.normals.push([reader.f32()?, reader.f32()?, reader.f32()?]);
+            }
+            for _ in 0..vertices {
+                primitive.uvs.push([reader.f32()?, reader.f32()?]);
+            }
+            for _ in 0..indices {
+                primitive.indices.push(reader.u32()?);
+            }
+            primitives.push(primitive);
+        }
+        Some(Mesh { uid, primitives })
+    }
+}
+
+impl<'a> PayloadReader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        PayloadReader { bytes }
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Option<&[u8]> {
+        if self.bytes.len() < len {
+            return None
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    pub(crate) fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(|bytes| u32::from_le_bytes(bytes.try_into().expect("four bytes")))
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        self.u32().map(f32::from_bits)
+    }
+}
+
+impl AssetLoader for TextureLoader {
+    type Asset = Texture;
+
+    fn extensions(&self) -> &[&'static str] {
+        &["htex"]
+    }
+
+    fn load(&self, bytes: &[u8], path: &Path) -> Result<Texture, AssetError> {
+        let (uid, payload) = read_built(bytes, AssetKind::Texture, path)?;
+        Texture::from_payload(uid, payload).ok_or_else(|| truncated(path))
+    }
+}
+
+impl AssetLoader for MeshLoader {
+    type Asset = Mesh;
+
+    fn extensions(&self) -> &[&'static str] {
+        &["hmesh"]
+    }
+
+    fn load(&self, bytes: &[u8], path: &Path) -> Result<Mesh, AssetError> {
+        let (uid, payload) = read_built(bytes, AssetKind::Mesh, path)?;
+        Mesh::from_payload(uid, payload).ok_or_else(|| truncated(path))
+    }
+}
+
+impl AssetLoader for ShaderLoader {
+    type Asset = ShaderModule;
+
+    fn extensions(&self) -> &[&'static str] {
+        &["hspv"]
+    }
+
+    fn load(&self, bytes: &[u8], path: &Path) -> Result<ShaderModule, AssetError> {
+        let (uid, payload) = read_built(bytes, AssetKind::Shader, path)?;
+        ShaderModule::from_payload(uid, payload).ok_or_else(|| truncated(path))
+    }
+}
+
+impl AssetLoader for AtlasLoader {
+    type Asset = Atlas;
+
+    fn extensions(&self) -> &[&'static str] {
+        &["hatlas"]
+    }
+
+    fn load(&self, bytes: &[u8], path: &Path) -> Result<Atlas, AssetError> {
+        let (uid, payload) = read_built(bytes, AssetKind::Atlas, path)?;
+        Atlas::from_payload(uid, payload).ok_or_else(|| truncated(path))
+    }
+}
+
+/// Prefixes `payload` with the built asset header
+pub fn write_built(kind: AssetKind, uid: UniqueId, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&ASSET_MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&kind.tag().to_le_bytes());
+    bytes.extend_from_slice(&uid.to_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// Checks the header of a built asset of the `expected` kind, returning its id and payload
+pub fn read_built<'a>(bytes: &'a [u8], expected: AssetKind, path: &Path) -> Result<(UniqueId, &'a [u8]), AssetError> {
+    let parse_error = |err: &str| AssetError::Parse(path.to_path_buf(), String::from(err));
+    if bytes.len() < HEADER_LEN || bytes[0..4] != ASSET_MAGIC {
+        return Err(parse_error("not a built asset"))
+    }
+    let mut reader = PayloadReader { bytes: &bytes[4..] };
+    if reader.u32() != Some(FORMAT_VERSION) {
+        return Err(parse_error("built with a different asset format version, rebuild it"))
+    }
+    if reader.u32().and_then(AssetKind::from_tag) != Some(expected) {
+        return Err(parse_error("wrong asset kind"))
+    }
+    let uid = UniqueId::from_bytes(reader.take(16).and_then(|uid| uid.try_into().ok()).ok_or_else(|| truncated(path))?);
+    Ok((uid, reader.bytes))
+}
+
+fn truncated(path: &Path) -> AssetError {
+    AssetError::Parse(path.to_path_buf(), String::from("truncated asset payload"))
+}
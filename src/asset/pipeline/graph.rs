@@ -0,0 +1,75 @@
+//!
+//! Dependency graph between source assets and the files they read
+//!
+
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Edges from an asset to every file it depends on, keyed by path relative to the source directory
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DependencyGraph {
+    dependencies: BTreeMap<String, BTreeSet<String>>,
+    dependents: BTreeMap<String, BTreeSet<String>>,
+}
+
+// Impls
+
+impl DependencyGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_asset(&mut self, asset: &str) {
+        self.dependencies.entry(String::from(asset)).or_default();
+    }
+
+    pub fn add_dependency(&mut self, asset: &str, dependency: &str) {
+        self.dependencies.entry(String::from(asset)).or_default().insert(String::from(dependency));
+        self.dependents.entry(String::from(dependency)).or_default().insert(String::from(asset));
+    }
+
+    pub fn assets(&self) -> impl Iterator<Item = &str> {
+        self.dependencies.keys().map(String::as_str)
+    }
+
+    /// Files `asset` reads directly
+    pub fn dependencies(&self, asset: &str) -> impl Iterator<Item = &str> {
+        self.dependencies.get(asset).into_iter().flatten().map(String::as_str)
+    }
+
+    /// Assets reading `file` directly
+    pub fn dependents(&self, file: &str) -> impl Iterator<Item = &str> {
+        self.dependents.get(file).into_iter().flatten().map(String::as_str)
+    }
+
+    /// Every asset that has to be rebuilt when `file` changes, following dependencies on other assets
+    pub fn affected_by(&self, file: &str) -> BTreeSet<String> {
+        let mut affected = BTreeSet::new();
+        if self.dependencies.contains_key(file) {
+            affected.insert(String::from(file));
+        }
+        let mut pending: Vec<&str> = vec![file];
+        while let Some(file) = pending.pop() {
+            for dependent in self.dependents(file) {
+                if affected.insert(String::from(dependent)) {
+                    pending.push(dependent);
+                }
+            }
+        }
+        affected
+    }
+
+    /// Graphviz DOT, assets as boxes and plain files as ellipses
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph assets {\n    rankdir=LR;\n");
+        for asset in self.dependencies.keys() {
+            dot.push_str(&format!("    \"{}\" [shape=box];\n", asset));
+        }
+        for (asset, dependencies) in &self.dependencies {
+            for dependency in dependencies {
+                dot.push_str(&format!("    \"{}\" -> \"{}\";\n", asset, dependency));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
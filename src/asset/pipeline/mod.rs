@@ -0,0 +1,388 @@
+//!
+//! Asset pipeline: converts source assets into the engine's binary formats ahead of time
+//!
+//! A build scans the source directory for files a converter handles, fingerprints each one together with every file
+//! it depends on, and only converts assets whose fingerprint changed since the last build. Each asset keeps the same
+//! `UniqueId` across rebuilds. Results are recorded in a manifest next to the built files, which also holds the
+//! dependency graph so tools can ask what a change to any file affects
+//!
+
+use std::{collections::{BTreeMap, HashSet}, path::{Component, Path, PathBuf}, sync::Arc};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{debug::log, unique::UniqueId};
+
+use super::AssetError;
+
+pub mod converters;
+pub mod export;
+pub mod formats;
+pub mod graph;
+
+pub use converters::{AtlasConverter, GlslConverter, GltfConverter, PngConverter};
+pub use formats::{AssetKind, FORMAT_VERSION};
+pub use graph::DependencyGraph;
+
+const MANIFEST_NAME: &str = "manifest.json";
+
+/// Converts one kind of source asset into a built asset payload
+pub trait AssetConverter: Send + Sync + 'static {
+    fn kind(&self) -> AssetKind;
+
+    /// Source file extensions handled by this converter, without the leading dot
+    fn extensions(&self) -> &[&'static str];
+
+    /// Bump when the converter's output changes to rebuild everything it converted
+    fn version(&self) -> u32;
+
+    /// Files the asset reads besides its own, as paths resolved against the source file
+    fn dependencies(&self, _path: &Path, _bytes: &[u8]) -> Result<Vec<PathBuf>, AssetError> {
+        Ok(Vec::new())
+    }
+
+    /// Converts the source bytes into the payload of a built asset of this converter's kind
+    fn convert(&self, path: &Path, bytes: &[u8], uid: UniqueId) -> Result<Vec<u8>, AssetError>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AssetRecord {
+    pub uid: UniqueId,
+    pub kind: AssetKind,
+    /// Relative to the output directory
+    pub output: String,
+    pub fingerprint: u64,
+    /// Relative to the source directory
+    pub dependencies: Vec<String>,
+}
+
+/// Every built asset, keyed by source path relative to the source directory with `/` separators
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct AssetManifest {
+    pub format_version: u32,
+    pub assets: BTreeMap<String, AssetRecord>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BuildReport {
+    pub built: Vec<String>,
+    pub up_to_date: usize,
+    /// Assets whose source was deleted, along with their built files
+    pub removed: Vec<String>,
+    /// Assets that failed to build keep their previous output
+    pub failed: Vec<(String, String)>,
+}
+
+pub struct AssetPipeline {
+    source: PathBuf,
+    output: PathBuf,
+    converters: Vec<Arc<dyn AssetConverter>>,
+    log: log::Logger,
+}
+
+/// A source file and the converter handling it
+type SourceFile = (PathBuf, Arc<dyn AssetConverter>);
+
+/// FNV-1a, stable across toolchains so manifests stay valid
+struct Fingerprint(u64);
+
+// Impls
+
+impl AssetManifest {
+    pub fn record(&self, source: &str) -> Option<&AssetRecord> {
+        self.assets.get(source)
+    }
+
+    /// The source path of the asset with id `uid`
+    pub fn source_of(&self, uid: UniqueId) -> Option<&str> {
+        self.assets.iter().find(|(_, record)| record.uid == uid).map(|(source, _)| source.as_str())
+    }
+
+    pub fn graph(&self) -> DependencyGraph {
+        let mut graph = DependencyGraph::new();
+        for (source, record) in &self.assets {
+            graph.add_asset(source);
+            record.dependencies.iter().for_each(|dependency| graph.add_dependency(source, dependency));
+        }
+        graph
+    }
+}
+
+impl BuildReport {
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+impl AssetPipeline {
+    /// A pipeline with the built in PNG, glTF, GLSL and atlas converters
+    pub fn new<S: Into<PathBuf>, O: Into<PathBuf>>(source: S, output: O) -> Self {
+        AssetPipeline {
+            source: source.into(),
+            output: output.into(),
+            converters: Vec::new(),
+            log: log::get(),
+        }
+        .with_converter(PngConverter)
+        .with_converter(GltfConverter)
+        .with_converter(GlslConverter::default())
+        .with_converter(AtlasConverter)
+    }
+
+    /// Adds a converter, replacing any earlier one for the same extensions
+    pub fn with_converter<C: AssetConverter>(mut self, converter: C) -> Self {
+        let converter: Arc<dyn AssetConverter> = Arc::new(converter);
+        self.converters.retain(|existing| !existing.extensions().iter().any(|e| converter.extensions().contains(e)));
+        self.converters.push(converter);
+        self
+    }
+
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    pub fn output(&self) -> &Path {
+        &self.output
+    }
+
+    /// The manifest of the last build, empty if there wasn't one or it was built with another format version
+    pub fn manifest(&self) -> Result<AssetManifest, AssetError> {
+        let path = self.output.join(MANIFEST_NAME);
+        let bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(AssetManifest { format_version: FORMAT_VERSION, ..Default::default() }),
+            Err(err) => return Err(AssetError::Io(path, err)),
+        };
+        let manifest: AssetManifest = serde_json::from_slice(&bytes).map_err(|err| AssetError::Parse(path, err.to_string()))?;
+        match manifest.format_version == FORMAT_VERSION {
+            true => Ok(manifest),
+            false => Ok(AssetManifest { format_version: FORMAT_VERSION, ..Default::default() }),
+        }
+    }
+
+    /// Converts every out of date asset. A failing asset doesn't stop the rest of the build, it's listed in the report
+    pub fn build(&self) -> Result<BuildReport, AssetError> {
+        let mut manifest = self.manifest()?;
+        let mut report = BuildReport::default();
+        let mut seen = HashSet::new();
+
+        for (path, converter) in self.scan()? {
+            let source = self.relative(&path);
+            seen.insert(source.clone());
+            match self.build_asset(&path, converter.as_ref(), manifest.assets.get(&source)) {
+                Ok(Some(record)) => {
+                    manifest.assets.insert(source.clone(), record);
+                    report.built.push(source);
+                },
+                Ok(None) => report.up_to_date += 1,
+                Err(err) => {
+                    self.log.error(format!("asset pipeline: {}", err));
+                    report.failed.push((source, err.to_string()));
+                },
+            }
+        }
+
+        let removed: Vec<String> = manifest.assets.keys().filter(|source| !seen.contains(*source)).cloned().collect();
+        for source in removed {
+            if let Some(record) = manifest.assets.remove(&source) {
+                let output = self.output.join(&record.output);
+                match std::fs::remove_file(&output) {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(AssetError::Io(output, err)),
+                    _ => report.removed.push(source),
+                }
+            }
+        }
+
+        let path = self.output.join(MANIFEST_NAME);
+        let json = serde_json::to_string_pretty(&manifest).map_err(|err| AssetError::Parse(path.clone(), err.to_string()))?;
+        std::fs::create_dir_all(&self.output).and_then(|_| std::fs::write(&path, json)).map_err(|err| AssetError::Io(path, err))?;
+
+        self.log.info(format!("asset pipeline: {} built, {} up to date, {} removed, {} failed",
+            report.built.len(), report.up_to_date, report.removed.len(), report.failed.len()));
+        Ok(report)
+    }
+
+    /// Builds one asset, returning `None` if its previous output is still up to date
+    fn build_asset(&self, path: &Path, converter: &dyn AssetConverter, previous: Option<&AssetRecord>) -> Result<Option<AssetRecord>, AssetError> {
+        let bytes = std::fs::read(path).map_err(|err| AssetError::Io(path.to_path_buf(), err))?;
+        let dependencies = converter.dependencies(path, &bytes)?;
+
+        let mut fingerprint = Fingerprint::new();
+        fingerprint.write(converter.extensions().join(",").as_bytes());
+        fingerprint.write(&converter.version().to_le_bytes());
+        fingerprint.write(&FORMAT_VERSION.to_le_bytes());
+        fingerprint.write(&bytes);
+        for dependency in &dependencies {
+            let contents = std::fs::read(dependency).map_err(|_| AssetError::MissingDependency(path.to_path_buf(), dependency.clone()))?;
+            fingerprint.write(self.relative(dependency).as_bytes());
+            fingerprint.write(&contents);
+        }
+        let fingerprint = fingerprint.finish();
+
+        let kind = converter.kind();
+        let output = Path::new(&self.relative(path)).with_extension(kind.extension()).to_string_lossy().replace('\\', "/");
+        if let Some(previous) = previous {
+            if previous.fingerprint == fingerprint && previous.kind == kind && self.output.join(&previous.output).exists() {
+                return Ok(None)
+            }
+        }
+
+        let uid = previous.map_or_else(UniqueId::get, |previous| previous.uid);
+        let payload = converter.convert(path, &bytes, uid)?;
+
+        let output_path = self.output.join(&output);
+        if let Some(parent) = output_path.parent() {
+            std::fs::create_dir_all(parent).map_err(|err| AssetError::Io(parent.to_path_buf(), err))?;
+        }
+        std::fs::write(&output_path, formats::write_built(kind, uid, &payload)).map_err(|err| AssetError::Io(output_path, err))?;
+
+        Ok(Some(AssetRecord {
+            uid,
+            kind,
+            output,
+            fingerprint,
+            dependencies: dependencies.iter().map(|dependency| self.relative(dependency)).collect(),
+        }))
+    }
+
+    /// Source files with a converter, in a stable order. The output directory is skipped if it's inside the source
+    fn scan(&self) -> Result<Vec<SourceFile>, AssetError> {
+        let mut found = Vec::new();
+        let mut pending = vec![self.source.clone()];
+        while let Some(directory) = pending.pop() {
+            let entries = std::fs::read_dir(&directory).map_err(|err| AssetError::Io(directory.clone(), err))?;
+            for entry in entries {
+                let path = entry.map_err(|err| AssetError::Io(directory.clone(), err))?.path();
+                if path.is_dir() {
+                    if path != self.output {
+                        pending.push(path);
+                    }
+                } else if let Some(converter) = self.converter_for(&path) {
+                    found.push((path, converter));
+                }
+            }
+        }
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(found)
+    }
+
+    fn converter_for(&self, path: &Path) -> Option<Arc<dyn AssetConverter>> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        self.converters.iter().find(|converter| converter.extensions().contains(&extension.as_str())).cloned()
+    }
+
+    /// Path relative to the source directory with `/` separators, or as given if it's outside the source directory
+    fn relative(&self, path: &Path) -> String {
+        let path = normalize(path);
+        path.strip_prefix(normalize(&self.source)).unwrap_or(&path).to_string_lossy().replace('\\', "/")
+    }
+}
+
+impl std::fmt::Debug for AssetPipeline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let extensions: Vec<&str> = self.converters.iter().flat_map(|converter| converter.extensions().iter().copied()).collect();
+        f.debug_struct("AssetPipeline").field("source", &self.source).field("output", &self.output).field("extensions", &extensions).finish()
+    }
+}
+
+impl Fingerprint {
+    fn new() -> Self {
+        Fingerprint(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        // Length first, so consecutive writes can't run into each other
+        for byte in (bytes.len() as u64).to_le_bytes().iter().chain(bytes) {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Resolves `.` and `..` components without touching the filesystem, so `meshes/../textures/a.png` and
+/// `textures/a.png` are the same dependency
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir if matches!(normalized.components().next_back(), Some(Component::Normal(_))) => { normalized.pop(); },
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::formats::{Mesh, MeshLoader, Texture, TextureLoader};
+    use crate::asset::AssetLoader;
+
+    fn write_png(path: &Path) {
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut bytes, 2, 1);
+            encoder.set_color(png::ColorType::Rgb);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.write_header().unwrap().write_image_data(&[255, 0, 0, 0, 255, 0]).unwrap();
+        }
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    fn write_triangle(directory: &Path, z: f32) {
+        let buffer: Vec<u8> = [0.0f32, 0.0, z, 1.0, 0.0, z, 0.0, 1.0, z].iter().flat_map(|v| v.to_le_bytes()).collect();
+        std::fs::write(directory.join("triangle.bin"), buffer).unwrap();
+        std::fs::write(directory.join("triangle.gltf"), r#"{
+            "buffers": [{ "uri": "triangle.bin", "byteLength": 36 }],
+            "bufferViews": [{ "buffer": 0, "byteLength": 36 }],
+            "accessors": [{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3" }],
+            "images": [{ "uri": "../textures/checker.png" }],
+            "meshes": [{ "primitives": [{ "attributes": { "POSITION": 0 } }] }]
+        }"#).unwrap();
+    }
+
+    #[test]
+    fn incremental_builds_only_convert_what_changed() {
+        let root = std::env::temp_dir().join(format!("hadron_asset_pipeline_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let (source, output) = (root.join("source"), root.join("built"));
+        std::fs::create_dir_all(source.join("textures")).unwrap();
+        std::fs::create_dir_all(source.join("meshes")).unwrap();
+        write_png(&source.join("textures/checker.png"));
+        write_triangle(&source.join("meshes"), 0.0);
+
+        let pipeline = AssetPipeline::new(&source, &output);
+        let report = pipeline.build().unwrap();
+        assert_eq!(report.built, vec!["meshes/triangle.gltf", "textures/checker.png"]);
+
+        let texture = std::fs::read(output.join("textures/checker.htex")).unwrap();
+        let texture: Texture = TextureLoader.load(&texture, Path::new("checker.htex")).unwrap();
+        assert_eq!((texture.width, texture.height, texture.pixels), (2, 1, vec![255, 0, 0, 255, 0, 255, 0, 255]));
+
+        assert_eq!(pipeline.build().unwrap().up_to_date, 2);
+
+        // Only the binary buffer changes, the mesh rebuilds under the same id
+        let first = pipeline.manifest().unwrap().record("meshes/triangle.gltf").unwrap().uid;
+        write_triangle(&source.join("meshes"), 2.0);
+        let report = pipeline.build().unwrap();
+        assert_eq!((report.built, report.up_to_date), (vec![String::from("meshes/triangle.gltf")], 1));
+
+        let mesh = std::fs::read(output.join("meshes/triangle.hmesh")).unwrap();
+        let mesh: Mesh = MeshLoader.load(&mesh, Path::new("triangle.hmesh")).unwrap();
+        assert_eq!((mesh.uid, mesh.primitives[0].positions[1], mesh.primitives[0].indices.clone()), (first, [1.0, 0.0, 2.0], vec![0, 1, 2]));
+
+        let manifest = pipeline.manifest().unwrap();
+        assert!(manifest.graph().affected_by("textures/checker.png").contains("meshes/triangle.gltf"));
+        assert_eq!(manifest.source_of(first), Some("meshes/triangle.gltf"));
+
+        std::fs::remove_file(source.join("textures/checker.png")).unwrap();
+        let report = pipeline.build().unwrap();
+        assert_eq!(report.removed, vec!["textures/checker.png"]);
+        assert_eq!(report.failed.len(), 1, "the mesh's image dependency is gone");
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,489 @@
+//!
+//! Bus effects
+//!
+//! Sounds are mixed into named buses, like `music`, `sfx` and `voice`, and each `Bus` runs its mix through an effect
+//! chain before applying its gain: `LowPass`, `Reverb` and `Compressor`, or any other `Effect`. A bus belongs to the
+//! audio backend's mixer thread, which calls `process` on blocks of interleaved samples and never waits on the game
+//!
+//! Effect parameters are `Param`s, floats shared through an atomic, so gameplay can change them while the mixer reads
+//! them without either taking a lock. The mixer glides each parameter towards its latest value over a few milliseconds
+//! so changes don't click. Gameplay reaches a bus's parameters through the `BusControls` it hands out, kept in the
+//! `AudioBuses` resource, by names like `gain` or `lowpass.cutoff`. `AutomateParam` events ramp a parameter to a value
+//! over time, e.g. sweeping the `sfx` low pass down to muffle the game while it's paused or the camera is underwater
+//!
+
+use std::{collections::BTreeMap, sync::{Arc, atomic::{AtomicU32, Ordering}}, time::Duration};
+
+use crate::debug::log;
+use crate::system::{event, time::Time, world::World};
+
+/// Time parameters take to glide to a new value on the mixer thread
+const SMOOTHING: Duration = Duration::from_millis(10);
+
+/// Freeverb's comb and allpass delays at 44.1 kHz, scaled to the sample rate
+const COMB_DELAYS: [usize; 4] = [1116, 1188, 1277, 1356];
+const ALLPASS_DELAYS: [usize; 2] = [556, 441];
+
+/// A float shared between gameplay and the mixer thread without locking
+#[derive(Debug, Clone)]
+pub struct Param(Arc<AtomicU32>);
+
+/// Processes a bus's interleaved samples in place on the mixer thread
+pub trait Effect: Send + 'static {
+    /// The effect's parameters by name, for its bus to hand out
+    fn params(&self) -> Vec<(&'static str, Param)>;
+
+    fn process(&mut self, samples: &mut [f32], channels: usize, sample_rate: f32);
+
+    /// Forgets any sound still ringing, like a reverb's tail
+    fn reset(&mut self);
+}
+
+/// Two pole low pass filter, the cutoff in Hz
+pub struct LowPass {
+    cutoff: Smoothed,
+    /// Output of both poles per channel
+    state: Vec<[f32; 2]>,
+}
+
+/// Freeverb style reverb, with the room size and damping from 0 to 1 and the wet mix from 0 to 1
+pub struct Reverb {
+    room_size: Smoothed,
+    damping: Smoothed,
+    mix: Smoothed,
+    channels: Vec<ReverbChannel>,
+    sample_rate: f32,
+}
+
+/// Downward compressor with its channels linked, levels in dB and times in milliseconds
+pub struct Compressor {
+    threshold_db: Smoothed,
+    ratio: Smoothed,
+    attack_ms: Smoothed,
+    release_ms: Smoothed,
+    makeup_db: Smoothed,
+    /// The level being followed, in dB
+    envelope: f32,
+}
+
+/// A bus's effects, run in order, and its gain
+pub struct Bus {
+    name: String,
+    effects: Vec<(String, Box<dyn Effect>)>,
+    gain: Smoothed,
+}
+
+/// Gameplay's handles on a bus's parameters
+#[derive(Debug, Clone)]
+pub struct BusControls {
+    name: String,
+    params: BTreeMap<String, Param>,
+}
+
+/// Ramps a bus parameter to `value` over `over`, from wherever it is when the event is handled
+#[derive(Debug, Clone, PartialEq)]
+pub struct AutomateParam {
+    pub bus: String,
+    pub param: String,
+    pub value: f32,
+    pub over: Duration,
+}
+
+/// World resource of the controls of every bus and the ramps in progress
+#[derive(Debug, Default)]
+pub struct AudioBuses {
+    buses: BTreeMap<String, BusControls>,
+    ramps: Vec<Ramp>,
+}
+
+#[derive(Debug)]
+struct Ramp {
+    param: Param,
+    from: f32,
+    to: f32,
+    elapsed: Duration,
+    over: Duration,
+}
+
+/// A parameter as the mixer sees it, gliding towards the value gameplay last set
+#[derive(Debug)]
+struct Smoothed {
+    param: Param,
+    current: Option<f32>,
+}
+
+#[derive(Debug, Default)]
+struct ReverbChannel {
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+}
+
+#[derive(Debug, Default)]
+struct Comb {
+    buffer: Vec<f32>,
+    position: usize,
+    /// The damping filter's last output
+    filtered: f32,
+}
+
+#[derive(Debug, Default)]
+struct Allpass {
+    buffer: Vec<f32>,
+    position: usize,
+}
+
+// Impls
+
+impl Param {
+    pub fn new(value: f32) -> Self {
+        Param(Arc::new(AtomicU32::new(value.to_bits())))
+    }
+
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, value: f32) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
+
+impl Smoothed {
+    fn new(value: f32) -> Self {
+        Smoothed { param: Param::new(value), current: None }
+    }
+
+    /// Moves a step towards the parameter's value, by `coefficient` of the way
+    fn next(&mut self, coefficient: f32) -> f32 {
+        let target = self.param.get();
+        let current = self.current.get_or_insert(target);
+        *current += (target - *current) * coefficient;
+        *current
+    }
+}
+
+impl LowPass {
+    pub fn new(cutoff: f32) -> Self {
+        LowPass { cutoff: Smoothed::new(cutoff), state: Vec::new() }
+    }
+}
+
+impl Effect for LowPass {
+    fn params(&self) -> Vec<(&'static str, Param)> {
+        vec![("cutoff", self.cutoff.param.clone())]
+    }
+
+    fn process(&mut self, samples: &mut [f32], channels: usize, sample_rate: f32) {
+        self.state.resize(channels, [0.0; 2]);
+        let glide = smoothing(sample_rate);
+        for frame in samples.chunks_exact_mut(channels) {
+            let cutoff = self.cutoff.next(glide).clamp(10.0, sample_rate * 0.45);
+            let a = 1.0 - (-std::f32::consts::TAU * cutoff / sample_rate).exp();
+            for (sample, [first, second]) in frame.iter_mut().zip(self.state.iter_mut()) {
+                *first += a * (*sample - *first);
+                *second += a * (*first - *second);
+                *sample = *second;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state.clear();
+    }
+}
+
+impl Reverb {
+    pub fn new(room_size: f32, damping: f32, mix: f32) -> Self {
+        Reverb { room_size: Smoothed::new(room_size), damping: Smoothed::new(damping), mix: Smoothed::new(mix), channels: Vec::new(), sample_rate: 0.0 }
+    }
+}
+
+impl Effect for Reverb {
+    fn params(&self) -> Vec<(&'static str, Param)> {
+        vec![("room_size", self.room_size.param.clone()), ("damping", self.damping.param.clone()), ("mix", self.mix.param.clone())]
+    }
+
+    fn process(&mut self, samples: &mut [f32], channels: usize, sample_rate: f32) {
+        // Delay lines are only sized when the format changes, never while it plays
+        if self.channels.len() != channels || self.sample_rate != sample_rate {
+            let scale = sample_rate / 44100.0;
+            // Each channel's delays are spread a little apart so the channels decorrelate
+            let delay = |base: usize, channel: usize| ((base + channel * 23) as f32 * scale).max(1.0) as usize;
+            self.channels = (0..channels).map(|channel| ReverbChannel {
+                combs: COMB_DELAYS.iter().map(|base| Comb { buffer: vec![0.0; delay(*base, channel)], ..Comb::default() }).collect(),
+                allpasses: ALLPASS_DELAYS.iter().map(|base| Allpass { buffer: vec![0.0; delay(*base, channel)], ..Allpass::default() }).collect(),
+            }).collect();
+            self.sample_rate = sample_rate;
+        }
+        let glide = smoothing(sample_rate);
+        for frame in samples.chunks_exact_mut(channels) {
+            let feedback = 0.7 + 0.28 * self.room_size.next(glide).clamp(0.0, 1.0);
+            let damping = self.damping.next(glide).clamp(0.0, 1.0) * 0.4;
+            let mix = self.mix.next(glide).clamp(0.0, 1.0);
+            for (sample, channel) in frame.iter_mut().zip(self.channels.iter_mut()) {
+                let input = *sample * 0.015;
+                let mut wet: f32 = channel.combs.iter_mut().map(|comb| comb.process(input, feedback, damping)).sum();
+                for allpass in &mut channel.allpasses {
+                    wet = allpass.process(wet);
+                }
+                *sample = *sample * (1.0 - mix) + wet * mix;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.channels.clear();
+    }
+}
+
+impl Comb {
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.buffer[self.position];
+        self.filtered = output * (1.0 - damping) + self.filtered * damping;
+        self.buffer[self.position] = input + self.filtered * feedback;
+        self.position = (self.position + 1) % self.buffer.len();
+        output
+    }
+}
+
+impl Allpass {
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = self.buffer[self.position];
+        self.buffer[self.position] = input + delayed * 0.5;
+        self.position = (self.position + 1) % self.buffer.len();
+        delayed - input
+    }
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Compressor::new(-18.0, 4.0)
+    }
+}
+
+impl Compressor {
+    /// Compresses by `ratio` above `threshold_db`, with a 5 ms attack and 100 ms release
+    pub fn new(threshold_db: f32, ratio: f32) -> Self {
+        Compressor {
+            threshold_db: Smoothed::new(threshold_db),
+            ratio: Smoothed::new(ratio),
+            attack_ms: Smoothed::new(5.0),
+            release_ms: Smoothed::new(100.0),
+            makeup_db: Smoothed::new(0.0),
+            envelope: f32::NEG_INFINITY,
+        }
+    }
+}
+
+impl Effect for Compressor {
+    fn params(&self) -> Vec<(&'static str, Param)> {
+        vec![
+            ("threshold_db", self.threshold_db.param.clone()),
+            ("ratio", self.ratio.param.clone()),
+            ("attack_ms", self.attack_ms.param.clone()),
+            ("release_ms", self.release_ms.param.clone()),
+            ("makeup_db", self.makeup_db.param.clone()),
+        ]
+    }
+
+    fn process(&mut self, samples: &mut [f32], channels: usize, sample_rate: f32) {
+        let glide = smoothing(sample_rate);
+        let follow = |ms: f32| 1.0 - (-1000.0 / (ms.max(0.01) * sample_rate)).exp();
+        for frame in samples.chunks_exact_mut(channels) {
+            let threshold = self.threshold_db.next(glide);
+            let ratio = self.ratio.next(glide).max(1.0);
+            let (attack, release) = (follow(self.attack_ms.next(glide)), follow(self.release_ms.next(glide)));
+            let makeup = self.makeup_db.next(glide);
+
+            let peak = frame.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+            let level = 20.0 * peak.max(1e-6).log10();
+            if self.envelope.is_infinite() {
+                self.envelope = level;
+            }
+            self.envelope += (level - self.envelope) * if level > self.envelope { attack } else { release };
+            let reduction = (self.envelope - threshold).max(0.0) * (1.0 - 1.0 / ratio);
+            let gain = 10.0f32.powf((makeup - reduction) / 20.0);
+            frame.iter_mut().for_each(|sample| *sample *= gain);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.envelope = f32::NEG_INFINITY;
+    }
+}
+
+impl Bus {
+    pub fn new(name: &str) -> Self {
+        Bus { name: String::from(name), effects: Vec::new(), gain: Smoothed::new(1.0) }
+    }
+
+    /// Adds an effect to the end of the chain, its parameters named after `name`, e.g. `lowpass.cutoff`
+    pub fn with_effect(mut self, name: &str, effect: impl Effect) -> Self {
+        self.effects.push((String::from(name), Box::new(effect)));
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Handles on the bus's gain and every effect's parameters, for gameplay to set
+    pub fn controls(&self) -> BusControls {
+        let mut params = BTreeMap::from([(String::from("gain"), self.gain.param.clone())]);
+        for (name, effect) in &self.effects {
+            params.extend(effect.params().into_iter().map(|(param, handle)| (format!("{}.{}", name, param), handle)));
+        }
+        BusControls { name: self.name.clone(), params }
+    }
+
+    /// Runs the bus's interleaved samples through its effects and gain, on the mixer thread
+    pub fn process(&mut self, samples: &mut [f32], channels: usize, sample_rate: f32) {
+        if channels == 0 || sample_rate <= 0.0 {
+            return
+        }
+        for (_, effect) in &mut self.effects {
+            effect.process(samples, channels, sample_rate);
+        }
+        let glide = smoothing(sample_rate);
+        for frame in samples.chunks_exact_mut(channels) {
+            let gain = self.gain.next(glide).max(0.0);
+            frame.iter_mut().for_each(|sample| *sample *= gain);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.effects.iter_mut().for_each(|(_, effect)| effect.reset());
+    }
+}
+
+impl BusControls {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn param(&self, name: &str) -> Option<&Param> {
+        self.params.get(name)
+    }
+
+    /// Sets a parameter at once, returning false if the bus has no such parameter
+    pub fn set(&self, name: &str, value: f32) -> bool {
+        self.params.get(name).map(|param| param.set(value)).is_some()
+    }
+
+    /// Parameter names, like `gain` and `lowpass.cutoff`
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.params.keys().map(String::as_str)
+    }
+}
+
+impl Ramp {
+    /// Moves the parameter `delta` further along, returning whether it got there
+    fn advance(&mut self, delta: Duration) -> bool {
+        self.elapsed += delta;
+        let t = match self.over.is_zero() {
+            true => 1.0,
+            false => (self.elapsed.as_secs_f32() / self.over.as_secs_f32()).min(1.0),
+        };
+        self.param.set(self.from + (self.to - self.from) * t);
+        t >= 1.0
+    }
+}
+
+impl AudioBuses {
+    /// Adds a bus's controls, replacing any with the same name
+    pub fn add(&mut self, controls: BusControls) -> &mut Self {
+        self.buses.insert(controls.name.clone(), controls);
+        self
+    }
+
+    pub fn bus(&self, name: &str) -> Option<&BusControls> {
+        self.buses.get(name)
+    }
+
+    /// Starts ramping a parameter, replacing any ramp already moving it. Returns false if there's no such parameter
+    pub fn automate(&mut self, bus: &str, param: &str, value: f32, over: Duration) -> bool {
+        let Some(param) = self.buses.get(bus).and_then(|bus| bus.param(param)) else { return false };
+        self.ramps.retain(|ramp| !Arc::ptr_eq(&ramp.param.0, &param.0));
+        self.ramps.push(Ramp { param: param.clone(), from: param.get(), to: value, elapsed: Duration::ZERO, over });
+        true
+    }
+
+    /// Moves every ramp `delta` along, dropping the finished ones
+    pub fn update(&mut self, delta: Duration) {
+        self.ramps.retain_mut(|ramp| !ramp.advance(delta));
+    }
+}
+
+/// Starts this frame's `AutomateParam` ramps and moves every ramp along. Ramps follow real time, so they carry on
+/// while the game is paused
+pub fn automate_buses(world: &World) {
+    let automations = event::drain_events::<AutomateParam>(world);
+    let delta = world.with_resource::<Time, _>(Time::real_delta).unwrap_or_default();
+    world.with_resource_mut::<AudioBuses, _>(|buses| {
+        for automation in automations {
+            if !buses.automate(&automation.bus, &automation.param, automation.value, automation.over) {
+                log::get().with_topic("audio").warn(format!("no parameter {} on bus {}", automation.param, automation.bus));
+            }
+        }
+        buses.update(delta);
+    });
+}
+
+/// How far a parameter glides towards its value each sample
+fn smoothing(sample_rate: f32) -> f32 {
+    1.0 - (-1.0 / (SMOOTHING.as_secs_f32() * sample_rate)).exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f32, sample_rate: f32, frames: usize) -> Vec<f32> {
+        (0..frames).map(|frame| (std::f32::consts::TAU * frequency * frame as f32 / sample_rate).sin()).collect()
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0, |peak, sample| peak.max(sample.abs()))
+    }
+
+    #[test]
+    fn effects_follow_their_params() {
+        let mut bus = Bus::new("sfx").with_effect("lowpass", LowPass::new(20000.0)).with_effect("compressor", Compressor::new(-6.0, 10.0));
+        let controls = bus.controls();
+        assert_eq!(controls.names().collect::<Vec<_>>(), vec!["compressor.attack_ms", "compressor.makeup_db", "compressor.ratio", "compressor.release_ms", "compressor.threshold_db", "gain", "lowpass.cutoff"]);
+
+        // A quiet high tone passes through, until the low pass is swept down to muffle it
+        let mut quiet = sine(4000.0, 48000.0, 4800).iter().map(|sample| sample * 0.25).collect::<Vec<_>>();
+        bus.process(&mut quiet, 1, 48000.0);
+        assert!(peak(&quiet[2400..]) > 0.2);
+        assert!(controls.set("lowpass.cutoff", 200.0));
+        let mut muffled = sine(4000.0, 48000.0, 4800).iter().map(|sample| sample * 0.25).collect::<Vec<_>>();
+        bus.process(&mut muffled, 1, 48000.0);
+        assert!(peak(&muffled[2400..]) < 0.01);
+
+        // A loud low tone is pulled down towards the threshold
+        let mut compressor = Compressor::new(-12.0, 20.0);
+        let mut loud = sine(100.0, 48000.0, 9600);
+        compressor.process(&mut loud, 1, 48000.0);
+        assert!(peak(&loud[4800..]) < 0.35);
+    }
+
+    #[test]
+    fn automation_ramps_params() {
+        let bus = Bus::new("music").with_effect("reverb", Reverb::new(0.5, 0.5, 0.0));
+        let mut buses = AudioBuses::default();
+        buses.add(bus.controls());
+        assert!(buses.automate("music", "gain", 0.0, Duration::from_millis(100)));
+        assert!(!buses.automate("music", "chorus.depth", 1.0, Duration::ZERO));
+        buses.update(Duration::from_millis(25));
+        assert_eq!(buses.bus("music").and_then(|bus| bus.param("gain")).map(Param::get), Some(0.75));
+
+        // A new ramp takes over from wherever the old one got to
+        assert!(buses.automate("music", "gain", 1.0, Duration::from_millis(50)));
+        buses.update(Duration::from_millis(25));
+        assert_eq!(buses.bus("music").and_then(|bus| bus.param("gain")).map(Param::get), Some(0.875));
+        buses.update(Duration::from_millis(100));
+        assert_eq!(buses.bus("music").and_then(|bus| bus.param("gain")).map(Param::get), Some(1.0));
+        assert!(buses.ramps.is_empty());
+    }
+}
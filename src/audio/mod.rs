@@ -0,0 +1,16 @@
+//!
+//! Audio events, consumed by whichever audio backend is active
+//!
+//! Long tracks are streamed from disk by `music` rather than loaded whole, and buses run their mix through the effect
+//! chains in `dsp`
+//!
+
+pub mod dsp;
+pub mod music;
+
+/// Requests a one-shot sound by asset name
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaySound {
+    pub name: String,
+    pub volume: f32,
+}
@@ -0,0 +1,467 @@
+//!
+//! Streamed music
+//!
+//! Music tracks run for minutes, so rather than load a whole file a `MusicStream` reads its WAV data a chunk at a time
+//! through the vfs io pool and decodes it just ahead of the mixer. Two chunks are held: the front one the mixer is
+//! reading from with `read`, and the back one decoded and waiting. As soon as the mixer moves on to the back chunk the
+//! next one is requested, at `High` priority so it's served ahead of ordinary asset loads. If a chunk still hasn't
+//! arrived when less than `music.critical_ms` of audio is left to play, it's asked for again as a `Critical` read,
+//! which preempts prefetching and jumps every queue, so music doesn't run dry however much else is loading
+//!
+//! Gameplay starts and stops tracks with `PlayMusic` and `StopMusic`, and the audio backend mixes the `MusicStreams`
+//! resource, which `stream_music` keeps fed
+//!
+
+use std::{collections::BTreeMap, sync::{Arc, Mutex}, time::Duration};
+
+use serde::{Serialize, Deserialize};
+
+use crate::config::{self, ConfigSection};
+use crate::debug::log;
+use crate::system::{event, world::World};
+use crate::vfs::{self, IoPriority, ReadHandle, Vfs, VfsError};
+
+/// Bytes read to find the format and data chunks at the start of a WAV file
+const HEADER_BYTES: usize = 4096;
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// Music streaming options from the `music` config section
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct MusicConfig {
+    /// Audio read and decoded at a time
+    pub chunk_ms: u64,
+    /// Audio left to play below which a late chunk is read again as a critical read
+    pub critical_ms: u64,
+}
+
+/// How a WAV file's samples are laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WavFormat {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub encoding: SampleEncoding,
+    /// Where the sample data starts in the file
+    pub data_offset: u64,
+    pub data_len: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleEncoding {
+    Pcm16,
+    Float32,
+}
+
+#[derive(Debug)]
+pub enum MusicError {
+    NotWav,
+    /// A WAV file the stream can't decode, like compressed or 24 bit audio
+    Unsupported(String),
+    Io(VfsError),
+}
+
+/// A music track streamed in chunks, decoded into interleaved samples from -1 to 1
+pub struct MusicStream {
+    vfs: Arc<Vfs>,
+    path: String,
+    config: MusicConfig,
+    looping: bool,
+    format: Option<WavFormat>,
+    /// Next byte of the data chunk to read
+    cursor: u64,
+    /// Samples the mixer is reading from and how many it has read
+    front: Vec<f32>,
+    played: usize,
+    /// The next chunk, decoded ahead of the mixer
+    back: Option<Vec<f32>>,
+    pending: Option<PendingRead>,
+    /// The whole track has been read and it doesn't loop
+    ended: bool,
+    error: Option<MusicError>,
+    starved: u64,
+}
+
+/// Starts streaming the track at a vfs path, replacing it if it's already playing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayMusic {
+    pub path: String,
+    pub looping: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StopMusic {
+    pub path: String,
+}
+
+/// World resource of the music tracks playing, by path
+#[derive(Default)]
+pub struct MusicStreams {
+    streams: BTreeMap<String, MusicStream>,
+}
+
+struct PendingRead {
+    /// Behind a lock only so streams can be shared with the mixer's thread
+    handle: Mutex<ReadHandle>,
+    /// Offset into the data chunk, `None` for the header
+    offset: Option<u64>,
+    len: usize,
+    priority: IoPriority,
+}
+
+// Impls
+
+impl Default for MusicConfig {
+    fn default() -> Self {
+        MusicConfig { chunk_ms: 2000, critical_ms: 500 }
+    }
+}
+
+impl ConfigSection for MusicConfig {
+    const NAME: &'static str = "music";
+}
+
+impl std::error::Error for MusicError {}
+
+impl std::fmt::Display for MusicError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MusicError::NotWav => write!(f, "not a wav file"),
+            MusicError::Unsupported(reason) => write!(f, "unsupported wav file: {}", reason),
+            MusicError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl WavFormat {
+    /// Finds the format and data chunks in the start of a WAV file
+    pub fn parse(header: &[u8]) -> Result<Self, MusicError> {
+        if header.len() < 12 || &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+            return Err(MusicError::NotWav)
+        }
+        let u16_at = |at: usize| u16::from_le_bytes([header[at], header[at + 1]]);
+        let u32_at = |at: usize| u32::from_le_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]]);
+
+        let mut format = None;
+        let mut at = 12;
+        while at + 8 <= header.len() {
+            let (id, len) = (&header[at..at + 4], u32_at(at + 4) as usize);
+            let body = at + 8;
+            match id {
+                b"fmt " if body + 16 <= header.len() => {
+                    let tag = match u16_at(body) {
+                        // The real format is the first two bytes of the sub format guid
+                        FORMAT_EXTENSIBLE if len >= 26 && body + 26 <= header.len() => u16_at(body + 24),
+                        tag => tag,
+                    };
+                    let encoding = match (tag, u16_at(body + 14)) {
+                        (FORMAT_PCM, 16) => SampleEncoding::Pcm16,
+                        (FORMAT_FLOAT, 32) => SampleEncoding::Float32,
+                        (tag, bits) => return Err(MusicError::Unsupported(format!("format {} with {} bit samples", tag, bits))),
+                    };
+                    format = Some((u16_at(body + 2), u32_at(body + 4), encoding));
+                },
+                b"data" => {
+                    let (channels, sample_rate, encoding) = format.ok_or_else(|| MusicError::Unsupported(String::from("data before format")))?;
+                    if channels == 0 || sample_rate == 0 {
+                        return Err(MusicError::Unsupported(String::from("no channels or sample rate")))
+                    }
+                    return Ok(WavFormat { channels, sample_rate, encoding, data_offset: body as u64, data_len: len as u64 })
+                },
+                _ => (),
+            }
+            // Chunks are padded to an even length
+            at = body + len + (len & 1);
+        }
+        Err(MusicError::Unsupported(format!("no data chunk in the first {} bytes", HEADER_BYTES)))
+    }
+
+    /// Bytes per frame of one sample per channel
+    pub fn frame_len(&self) -> usize {
+        let sample = match self.encoding {
+            SampleEncoding::Pcm16 => 2,
+            SampleEncoding::Float32 => 4,
+        };
+        sample * self.channels as usize
+    }
+
+    /// Interleaved samples from whole frames of sample data
+    pub fn decode(&self, data: &[u8]) -> Vec<f32> {
+        let data = &data[..data.len() - data.len() % self.frame_len()];
+        match self.encoding {
+            SampleEncoding::Pcm16 => data.chunks_exact(2).map(|sample| i16::from_le_bytes([sample[0], sample[1]]) as f32 / 32768.0).collect(),
+            SampleEncoding::Float32 => data.chunks_exact(4).map(|sample| f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]])).collect(),
+        }
+    }
+
+    /// How long `samples` interleaved samples play for
+    pub fn duration(&self, samples: usize) -> Duration {
+        Duration::from_secs_f64(samples as f64 / self.channels as f64 / self.sample_rate as f64)
+    }
+}
+
+impl MusicStream {
+    /// Starts reading the track's header
+    pub fn open(vfs: Arc<Vfs>, path: &str, config: MusicConfig) -> Self {
+        let handle = vfs.read_range_async(path, 0, HEADER_BYTES, IoPriority::High);
+        MusicStream {
+            vfs,
+            path: String::from(path),
+            config,
+            looping: false,
+            format: None,
+            cursor: 0,
+            front: Vec::new(),
+            played: 0,
+            back: None,
+            pending: Some(PendingRead { handle: Mutex::new(handle), offset: None, len: HEADER_BYTES, priority: IoPriority::High }),
+            ended: false,
+            error: None,
+            starved: 0,
+        }
+    }
+
+    /// Starts over from the beginning after the end rather than finishing
+    pub fn with_looping(mut self, looping: bool) -> Self {
+        self.looping = looping;
+        self
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The track's format, once its header has been read
+    pub fn format(&self) -> Option<WavFormat> {
+        self.format
+    }
+
+    pub fn error(&self) -> Option<&MusicError> {
+        self.error.as_ref()
+    }
+
+    /// Times the mixer asked for more than was decoded
+    pub fn starved(&self) -> u64 {
+        self.starved
+    }
+
+    /// Decoded audio the mixer hasn't read yet
+    pub fn buffered(&self) -> Duration {
+        let samples = self.front.len() - self.played + self.back.as_ref().map_or(0, Vec::len);
+        self.format.map_or(Duration::ZERO, |format| format.duration(samples))
+    }
+
+    /// Played to the end, or failed
+    pub fn is_finished(&self) -> bool {
+        self.error.is_some() || (self.ended && self.pending.is_none() && self.back.is_none() && self.played >= self.front.len())
+    }
+
+    /// Takes finished reads, decoding them ahead of the mixer, and requests the next chunk once there's room for it.
+    /// Called once a frame
+    pub fn update(&mut self) {
+        if self.error.is_some() {
+            return
+        }
+        let running_dry = self.buffered() < Duration::from_millis(self.config.critical_ms);
+        let data_offset = self.format.map_or(0, |format| format.data_offset);
+        if let Some(pending) = self.pending.as_mut() {
+            match pending.handle.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner()).try_take() {
+                Some(Ok(data)) => {
+                    let offset = pending.offset;
+                    self.pending = None;
+                    self.receive(offset, data);
+                },
+                Some(Err(err)) => {
+                    self.error = Some(MusicError::Io(err));
+                    return
+                },
+                None => if let (Some(offset), true) = (pending.offset, running_dry && pending.priority < IoPriority::Critical) {
+                    // The earlier request is left to finish unread, asking again is how a queued read jumps ahead
+                    pending.handle = Mutex::new(self.vfs.read_range_async(&self.path, data_offset + offset, pending.len, IoPriority::Critical));
+                    pending.priority = IoPriority::Critical;
+                },
+            }
+        }
+        if let (None, None, false, Some(format)) = (&self.pending, &self.back, self.ended, self.format) {
+            self.request(format);
+        }
+    }
+
+    /// Copies decoded samples into `out`, returning how many. Anything past that is left as it was
+    pub fn read(&mut self, out: &mut [f32]) -> usize {
+        let mut written = 0;
+        while written < out.len() {
+            if self.played >= self.front.len() {
+                match self.back.take() {
+                    Some(back) => {
+                        self.front = back;
+                        self.played = 0;
+                    },
+                    None => break,
+                }
+            }
+            let count = (out.len() - written).min(self.front.len() - self.played);
+            out[written..written + count].copy_from_slice(&self.front[self.played..self.played + count]);
+            written += count;
+            self.played += count;
+        }
+        if written < out.len() && !self.is_finished() {
+            self.starved += 1;
+        }
+        written
+    }
+
+    fn receive(&mut self, offset: Option<u64>, data: Vec<u8>) {
+        let Some(offset) = offset else {
+            match WavFormat::parse(&data) {
+                Ok(format) => self.format = Some(format),
+                Err(err) => self.error = Some(err),
+            }
+            return
+        };
+        let Some(format) = self.format else { return };
+        self.cursor = offset + data.len() as u64;
+        // A file shorter than its data chunk claims ends where it does
+        if self.cursor >= format.data_len || data.is_empty() {
+            self.cursor = 0;
+            self.ended = !self.looping || data.is_empty();
+        }
+        let samples = format.decode(&data);
+        match self.played >= self.front.len() {
+            true => {
+                self.front = samples;
+                self.played = 0;
+            },
+            false => self.back = Some(samples),
+        }
+    }
+
+    fn request(&mut self, format: WavFormat) {
+        let frames = (format.sample_rate as u64 * self.config.chunk_ms / 1000).max(1);
+        let len = (frames * format.frame_len() as u64).min(format.data_len - self.cursor) as usize;
+        let priority = match self.buffered() < Duration::from_millis(self.config.critical_ms) {
+            true => IoPriority::Critical,
+            false => IoPriority::High,
+        };
+        let handle = self.vfs.read_range_async(&self.path, format.data_offset + self.cursor, len, priority);
+        self.pending = Some(PendingRead { handle: Mutex::new(handle), offset: Some(self.cursor), len, priority });
+    }
+}
+
+impl MusicStreams {
+    /// Plays `stream`, replacing a stream of the same track
+    pub fn play(&mut self, stream: MusicStream) {
+        self.streams.insert(String::from(stream.path()), stream);
+    }
+
+    pub fn stop(&mut self, path: &str) -> bool {
+        self.streams.remove(path).is_some()
+    }
+
+    pub fn get_mut(&mut self, path: &str) -> Option<&mut MusicStream> {
+        self.streams.get_mut(path)
+    }
+
+    /// Every stream playing, for the mixer to read from
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut MusicStream> {
+        self.streams.values_mut()
+    }
+
+    /// Updates every stream, dropping the ones that finished and logging the ones that failed
+    pub fn update(&mut self) {
+        self.streams.retain(|path, stream| {
+            stream.update();
+            if let Some(err) = stream.error() {
+                log::get().with_topic("music").warn(format!("unable to stream {}: {}", path, err));
+            }
+            !stream.is_finished()
+        });
+    }
+}
+
+/// Starts and stops tracks from this frame's `PlayMusic` and `StopMusic` events and keeps the `MusicStreams` decoded
+/// ahead of the mixer
+pub fn stream_music(world: &World) {
+    let stops = event::drain_events::<StopMusic>(world);
+    let plays = event::drain_events::<PlayMusic>(world);
+    let config: MusicConfig = config::get().section();
+    world.with_resource_mut::<MusicStreams, _>(|music| {
+        for stop in stops {
+            music.stop(&stop.path);
+        }
+        for play in plays {
+            music.play(MusicStream::open(vfs::get(), &play.path, config.clone()).with_looping(play.looping));
+        }
+        music.update();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryMount;
+
+    fn wav(samples: &[i16]) -> Vec<u8> {
+        let data: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        // Mono 16 bit pcm at 1000 Hz
+        for field in [FORMAT_PCM, 1] {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [1000u32, 2000] {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [2u16, 16] {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&data);
+        bytes
+    }
+
+    /// Updates the stream until `done`, the reads finish on the io threads
+    fn update_until(stream: &mut MusicStream, done: impl Fn(&MusicStream) -> bool) {
+        for _ in 0..2000 {
+            stream.update();
+            if done(stream) {
+                return
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("stream didn't get there");
+    }
+
+    #[test]
+    fn tracks_stream_in_chunks_ahead_of_the_mixer() {
+        let samples: Vec<i16> = (0..2500).map(|sample| sample as i16).collect();
+        let vfs = Arc::new(Vfs::new().with_mount("/music", MemoryMount::new().with_file("track.wav", &wav(&samples)).with_file("notes.txt", b"not audio")));
+        let config = MusicConfig { chunk_ms: 1000, critical_ms: 0 };
+
+        let mut stream = MusicStream::open(Arc::clone(&vfs), "/music/track.wav", config.clone());
+        update_until(&mut stream, |stream| stream.back.is_some());
+        assert_eq!(stream.format().map(|format| format.data_len), Some(5000));
+        // One chunk in front, one decoded behind it and nothing more read until the mixer moves on
+        assert_eq!(stream.buffered(), Duration::from_secs(2));
+        assert!(stream.pending.is_none());
+
+        let mut out = vec![0.0; 1500];
+        assert_eq!(stream.read(&mut out), 1500);
+        assert_eq!(out[1499], 1499.0 / 32768.0);
+        update_until(&mut stream, |stream| stream.back.is_some());
+        let mut rest = vec![0.0; 2000];
+        assert_eq!(stream.read(&mut rest), 1000);
+        assert_eq!(rest[999], 2499.0 / 32768.0);
+        assert!(stream.is_finished() && stream.starved() == 0);
+
+        let mut notes = MusicStream::open(vfs, "/music/notes.txt", config);
+        update_until(&mut notes, MusicStream::is_finished);
+        assert!(matches!(notes.error(), Some(MusicError::NotWav)));
+    }
+}
@@ -0,0 +1,574 @@
+//!
+//! Engine configuration
+//!
+//! Settings form a tree of JSON values built up from layers, each overriding the ones before it
+//!
+//! | layer          | source |
+//! |----------------|--------|
+//! | `Defaults`     | the `Default` of every section, built into the executable |
+//! | `File`         | `/config/hadron.json`, or the virtual path given with `--config=` |
+//! | `Environment`  | `HADRON_<SECTION>__<KEY>` variables, e.g. `HADRON_RENDERER__VSYNC=false` |
+//! | `CommandLine`  | `--set=<key>=<value>` arguments, e.g. `--set=app.frame_limit=30` |
+//! | `Runtime`      | `Config::set` |
+//!
+//! Keys are dot separated paths into the tree, `renderer.vsync` is the `vsync` field of the `renderer` section. Values
+//! from the environment and command line are parsed as JSON, falling back to a plain string, so `false`, `30` and
+//! `[1, 2]` keep their types. Subsystems read whole sections as typed structs with `Config::section`
+//!
+//! Changes from any layer, including edits to the file picked up by `Config::reload_if_changed`, are reported to
+//! watchers. The app polls the file each second and sends a `ConfigChanged` event with the keys that changed
+//!
+
+use std::{sync::{Arc, Mutex, RwLock, atomic::{AtomicU64, Ordering}}, collections::{BTreeSet, hash_map::DefaultHasher}, hash::{Hash, Hasher}};
+
+use once_cell::sync::Lazy;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::{Map, Value};
+
+use crate::{app::AppConfig, debug::log::{self, LogFilter}, graphics::backend::RendererConfig, vfs::{self, Vfs, VfsError}};
+
+/// Where the file layer is read from and saved to unless `--config=` says otherwise
+pub const DEFAULT_CONFIG_FILE: &str = "/config/hadron.json";
+
+const ENV_PREFIX: &str = "HADRON_";
+const ENV_SEPARATOR: &str = "__";
+const LAYER_COUNT: usize = 5;
+
+static GLOBAL_CONFIG: Lazy<Arc<Config>> = Lazy::new(|| {
+    let config = Arc::new(Config::load());
+    log::set_filter(config.section());
+    config.watch(LogFilter::NAME, |config, _| log::set_filter(config.section()));
+    config
+});
+
+/// A typed view of one top level section of the config
+pub trait ConfigSection: Serialize + DeserializeOwned + Default {
+    const NAME: &'static str;
+}
+
+/// Sources of configuration, in order of precedence from lowest to highest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Layer {
+    Defaults,
+    File,
+    Environment,
+    CommandLine,
+    Runtime,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Vfs(VfsError),
+    /// The config file at the path isn't valid JSON
+    Parse(String, String),
+    Missing(String),
+    /// The value at the key doesn't have the requested type
+    Type(String, String),
+    /// The config wasn't loaded from a file, so there's nowhere to save it
+    NoFile,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WatchId(u64);
+
+/// Sent to the world after config values changed, so subsystems can rebuild whatever depends on them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigChanged {
+    pub keys: Vec<String>,
+}
+
+/// Called with the config and the keys that changed under the watched prefix
+type WatchFn = Arc<dyn Fn(&Config, &[String]) + Send + Sync>;
+
+struct Watcher {
+    id: WatchId,
+    prefix: String,
+    callback: WatchFn,
+}
+
+struct Layers {
+    values: [Value; LAYER_COUNT],
+    merged: Value,
+}
+
+/// Layered engine settings with change notification
+pub struct Config {
+    layers: RwLock<Layers>,
+    /// The vfs and virtual path of the file layer
+    file: Option<(Arc<Vfs>, String)>,
+    /// Hash of the file contents last loaded, `None` if there was no file
+    file_hash: Mutex<Option<u64>>,
+    watchers: Mutex<Vec<Watcher>>,
+    next_watch: AtomicU64,
+}
+
+// Impls
+
+impl std::error::Error for ConfigError {}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Vfs(err) => write!(f, "{}", err),
+            ConfigError::Parse(path, err) => write!(f, "unable to parse config file {}: {}", path, err),
+            ConfigError::Missing(key) => write!(f, "no config value for {}", key),
+            ConfigError::Type(key, err) => write!(f, "config value {} has the wrong type: {}", key, err),
+            ConfigError::NoFile => write!(f, "the config has no file to save to"),
+        }
+    }
+}
+
+impl From<VfsError> for ConfigError {
+    fn from(error: VfsError) -> Self {
+        ConfigError::Vfs(error)
+    }
+}
+
+impl ConfigChanged {
+    /// Whether any of the changed keys is `prefix` or under it
+    pub fn touches(&self, prefix: &str) -> bool {
+        self.keys.iter().any(|key| is_under(key, prefix))
+    }
+}
+
+impl Layer {
+    pub const ALL: [Layer; LAYER_COUNT] = [Layer::Defaults, Layer::File, Layer::Environment, Layer::CommandLine, Layer::Runtime];
+}
+
+impl std::fmt::Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Config").field("file", &self.file_path()).field("values", &self.value("")).finish()
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::new()
+    }
+}
+
+impl Config {
+    /// Only the built-in defaults, without a file to save to
+    pub fn new() -> Self {
+        let mut values: [Value; LAYER_COUNT] = std::array::from_fn(|_| Value::Object(Map::new()));
+        values[Layer::Defaults as usize] = defaults();
+        Config {
+            layers: RwLock::new(Layers::new(values)),
+            file: None,
+            file_hash: Mutex::new(None),
+            watchers: Mutex::new(Vec::new()),
+            next_watch: AtomicU64::new(0),
+        }
+    }
+
+    /// Every layer, from the global vfs, this process' environment and its command line
+    pub fn load() -> Self {
+        Config::from_sources(vfs::get(), std::env::args(), std::env::vars())
+    }
+
+    /// Every layer, reading the config file through `vfs`. A missing file is an empty layer, an unreadable one is
+    /// logged and skipped
+    pub fn from_sources<A, E>(vfs: Arc<Vfs>, args: A, vars: E) -> Self
+    where
+        A: IntoIterator<Item = String>,
+        E: IntoIterator<Item = (String, String)>,
+    {
+        let mut path = String::from(DEFAULT_CONFIG_FILE);
+        let mut command_line = Value::Object(Map::new());
+        for arg in args {
+            match arg.split_once('=') {
+                Some(("--config", value)) => path = String::from(value),
+                Some(("--set", assignment)) => match assignment.split_once('=') {
+                    Some((key, value)) => insert(&mut command_line, key, parse_value(value)),
+                    None => log::get().warn(format!("ignoring --set={}, expected --set=<key>=<value>", assignment)),
+                },
+                _ => (),
+            }
+        }
+
+        let mut environment = Value::Object(Map::new());
+        for (name, value) in vars {
+            if let Some(key) = env_key(&name) {
+                insert(&mut environment, &key, parse_value(&value));
+            }
+        }
+
+        let data = read_file(&vfs, &path).unwrap_or_else(|err| {
+            log::get().warn(format!("ignoring config file: {}", err));
+            None
+        });
+        let file = parse_file(&path, data.as_deref()).unwrap_or_else(|err| {
+            log::get().warn(format!("ignoring config file: {}", err));
+            Value::Object(Map::new())
+        });
+
+        let mut config = Config::new();
+        config.file = Some((vfs, path));
+        config.file_hash = Mutex::new(data.as_deref().map(hash_bytes));
+        {
+            let layers = config.layers.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner());
+            layers.values[Layer::File as usize] = file;
+            layers.values[Layer::Environment as usize] = environment;
+            layers.values[Layer::CommandLine as usize] = command_line;
+            layers.merge();
+        }
+        config
+    }
+
+    /// Virtual path of the config file, if there is one
+    pub fn file_path(&self) -> Option<&str> {
+        self.file.as_ref().map(|(_, path)| path.as_str())
+    }
+
+    /// The merged value at `key`, `""` is the whole tree
+    pub fn value(&self, key: &str) -> Option<Value> {
+        lookup(&self.read().merged, key).cloned()
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<T, ConfigError> {
+        let value = self.value(key).ok_or_else(|| ConfigError::Missing(String::from(key)))?;
+        serde_json::from_value(value).map_err(|err| ConfigError::Type(String::from(key), err.to_string()))
+    }
+
+    pub fn get_or<T: DeserializeOwned>(&self, key: &str, default: T) -> T {
+        self.get(key).unwrap_or(default)
+    }
+
+    /// A whole section. Fields it doesn't set keep their defaults, if it can't be read at all the defaults are used
+    /// and a warning logged
+    pub fn section<S: ConfigSection>(&self) -> S {
+        match self.get::<S>(S::NAME) {
+            Ok(section) => section,
+            Err(ConfigError::Missing(_)) => S::default(),
+            Err(err) => {
+                log::get().warn(format!("using default {} settings: {}", S::NAME, err));
+                S::default()
+            },
+        }
+    }
+
+    /// The highest layer setting `key`
+    pub fn source_of(&self, key: &str) -> Option<Layer> {
+        let layers = self.read();
+        Layer::ALL.into_iter().rev().find(|layer| lookup(&layers.values[*layer as usize], key).is_some())
+    }
+
+    /// Values set by a single layer
+    pub fn layer(&self, layer: Layer) -> Value {
+        self.read().values[layer as usize].clone()
+    }
+
+    /// Sets `key` in the runtime layer, above everything else. Watchers of the key are notified if the merged value
+    /// changes
+    pub fn set<T: Serialize>(&self, key: &str, value: T) -> Result<(), ConfigError> {
+        let value = serde_json::to_value(value).map_err(|err| ConfigError::Type(String::from(key), err.to_string()))?;
+        self.update(|values| insert(&mut values[Layer::Runtime as usize], key, value));
+        Ok(())
+    }
+
+    /// Removes `key` from the runtime layer, uncovering the value beneath it
+    pub fn reset(&self, key: &str) {
+        self.update(|values| remove(&mut values[Layer::Runtime as usize], key));
+    }
+
+    /// Re-reads the config file into the file layer, returning the keys whose merged values changed. If the file
+    /// can't be parsed the previous file layer is kept
+    pub fn reload(&self) -> Result<Vec<String>, ConfigError> {
+        self.reload_file(true)
+    }
+
+    /// Like `reload`, but does nothing unless the file's contents changed since they were last loaded or saved. Cheap
+    /// enough to poll
+    pub fn reload_if_changed(&self) -> Result<Vec<String>, ConfigError> {
+        self.reload_file(false)
+    }
+
+    /// Calls `callback` whenever values under `prefix` change, `""` watches everything
+    pub fn watch<F>(&self, prefix: &str, callback: F) -> WatchId
+    where
+        F: Fn(&Config, &[String]) + Send + Sync + 'static,
+    {
+        let id = WatchId(self.next_watch.fetch_add(1, Ordering::Relaxed));
+        let watcher = Watcher { id, prefix: String::from(prefix), callback: Arc::new(callback) };
+        self.watchers.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(watcher);
+        id
+    }
+
+    pub fn unwatch(&self, id: WatchId) -> bool {
+        let mut watchers = self.watchers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let count = watchers.len();
+        watchers.retain(|watcher| watcher.id != id);
+        watchers.len() != count
+    }
+
+    /// Writes the file and runtime layers back to the config file. Environment and command line overrides only last
+    /// for the process, so they aren't saved
+    pub fn save(&self) -> Result<(), ConfigError> {
+        let (vfs, path) = self.file.as_ref().ok_or(ConfigError::NoFile)?;
+        let mut layers = self.layers.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let mut saved = layers.values[Layer::File as usize].clone();
+        merge(&mut saved, &layers.values[Layer::Runtime as usize]);
+        let json = serde_json::to_vec_pretty(&saved).map_err(|err| ConfigError::Parse(path.clone(), err.to_string()))?;
+        vfs.write(path, &json)?;
+        *self.file_hash.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(hash_bytes(&json));
+
+        layers.values[Layer::File as usize] = saved;
+        layers.values[Layer::Runtime as usize] = Value::Object(Map::new());
+        Ok(())
+    }
+
+    fn reload_file(&self, force: bool) -> Result<Vec<String>, ConfigError> {
+        let Some((vfs, path)) = &self.file else {
+            return Ok(Vec::new())
+        };
+        let data = read_file(vfs, path)?;
+        {
+            // Recorded even if parsing fails, so a broken file is reported once rather than on every poll
+            let hash = data.as_deref().map(hash_bytes);
+            let mut last = self.file_hash.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if !force && *last == hash {
+                return Ok(Vec::new())
+            }
+            *last = hash;
+        }
+        let file = parse_file(path, data.as_deref())?;
+        Ok(self.update(|values| values[Layer::File as usize] = file))
+    }
+
+    /// Changes layers, then notifies the watchers of whatever changed in the merged tree
+    fn update<F: FnOnce(&mut [Value; LAYER_COUNT])>(&self, change: F) -> Vec<String> {
+        let changed = {
+            let mut layers = self.layers.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let previous = std::mem::take(&mut layers.merged);
+            change(&mut layers.values);
+            layers.merge();
+
+            let mut changed = BTreeSet::new();
+            diff(&previous, &layers.merged, "", &mut changed);
+            changed
+        };
+        if !changed.is_empty() {
+            self.notify(&changed);
+        }
+        changed.into_iter().collect()
+    }
+
+    /// Runs without holding any locks, so callbacks are free to read the config or add watchers
+    fn notify(&self, changed: &BTreeSet<String>) {
+        let watchers: Vec<(String, WatchFn)> = self.watchers.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+            .iter()
+            .map(|watcher| (watcher.prefix.clone(), Arc::clone(&watcher.callback)))
+            .collect();
+
+        for (prefix, callback) in watchers {
+            let keys: Vec<String> = changed.iter().filter(|key| is_under(key, &prefix)).cloned().collect();
+            if !keys.is_empty() {
+                callback(self, &keys);
+            }
+        }
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Layers> {
+        self.layers.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Layers {
+    fn new(values: [Value; LAYER_COUNT]) -> Self {
+        let mut layers = Layers { values, merged: Value::Null };
+        layers.merge();
+        layers
+    }
+
+    fn merge(&mut self) {
+        let mut merged = Value::Object(Map::new());
+        self.values.iter().for_each(|layer| merge(&mut merged, layer));
+        self.merged = merged;
+    }
+}
+
+/// The process wide config, loaded on first use
+pub fn get() -> Arc<Config> {
+    Arc::clone(&GLOBAL_CONFIG)
+}
+
+/// Every engine section at its defaults
+fn defaults() -> Value {
+    let mut values = Value::Object(Map::new());
+    insert(&mut values, AppConfig::NAME, section_defaults::<AppConfig>());
+    insert(&mut values, RendererConfig::NAME, section_defaults::<RendererConfig>());
+    insert(&mut values, LogFilter::NAME, section_defaults::<LogFilter>());
+    values
+}
+
+fn section_defaults<S: ConfigSection>() -> Value {
+    serde_json::to_value(S::default()).expect("config section defaults must serialize")
+}
+
+/// The file's contents, `None` if there's no file
+fn read_file(vfs: &Vfs, path: &str) -> Result<Option<Vec<u8>>, ConfigError> {
+    match vfs.read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(VfsError::NotFound(_)) => Ok(None),
+        Err(err) => Err(ConfigError::Vfs(err)),
+    }
+}
+
+/// A missing file is an empty layer
+fn parse_file(path: &str, data: Option<&[u8]>) -> Result<Value, ConfigError> {
+    match data {
+        Some(data) => serde_json::from_slice(data).map_err(|err| ConfigError::Parse(String::from(path), err.to_string())),
+        None => Ok(Value::Object(Map::new())),
+    }
+}
+
+fn hash_bytes(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// `HADRON_RENDERER__VSYNC` to `renderer.vsync`. Variables without a separator, like `HADRON_LOG_DIR`, aren't config
+fn env_key(name: &str) -> Option<String> {
+    let name = name.strip_prefix(ENV_PREFIX)?;
+    name.contains(ENV_SEPARATOR).then(|| name.split(ENV_SEPARATOR).map(str::to_lowercase).collect::<Vec<_>>().join("."))
+}
+
+fn parse_value(text: &str) -> Value {
+    serde_json::from_str(text).unwrap_or_else(|_| Value::String(String::from(text)))
+}
+
+fn lookup<'a>(tree: &'a Value, key: &str) -> Option<&'a Value> {
+    if key.is_empty() {
+        return Some(tree)
+    }
+    key.split('.').try_fold(tree, |value, part| value.get(part))
+}
+
+/// Sets `key`, replacing anything in the way that isn't an object
+fn insert(tree: &mut Value, key: &str, value: Value) {
+    let mut node = tree;
+    for part in key.split('.') {
+        if !node.is_object() {
+            *node = Value::Object(Map::new());
+        }
+        node = node.as_object_mut().expect("just made an object").entry(part).or_insert(Value::Null);
+    }
+    *node = value;
+}
+
+fn remove(tree: &mut Value, key: &str) {
+    let (parent, last) = match key.rsplit_once('.') {
+        Some((parent, last)) => (parent, last),
+        None => ("", key),
+    };
+    let parent = parent.split('.').filter(|part| !part.is_empty()).try_fold(tree, |value, part| value.get_mut(part));
+    if let Some(Value::Object(map)) = parent {
+        map.remove(last);
+    }
+}
+
+/// Objects merge key by key, anything else in `overlay` replaces what's in `base`. Nulls leave `base` as it is
+fn merge(base: &mut Value, overlay: &Value) {
+    match (base, overlay) {
+        (_, Value::Null) => (),
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay.iter().filter(|(_, value)| !value.is_null()) {
+                merge(base.entry(key.as_str()).or_insert(Value::Null), value);
+            }
+        },
+        (base, overlay) => *base = overlay.clone(),
+    }
+}
+
+/// Collects the keys of every leaf that differs between two trees
+fn diff(old: &Value, new: &Value, key: &str, changed: &mut BTreeSet<String>) {
+    if old == new {
+        return
+    }
+    let (old_map, new_map) = match (old, new) {
+        (Value::Object(old_map), Value::Object(new_map)) => (old_map, new_map),
+        (Value::Object(_), _) | (_, Value::Object(_)) => {
+            // An object replacing a plain value, or the other way around, changes the key itself and every leaf
+            let empty = Map::new();
+            if !old.is_object() && !old.is_null() || !new.is_object() && !new.is_null() {
+                changed.insert(String::from(key));
+            }
+            let old = old.as_object().unwrap_or(&empty);
+            let new = new.as_object().unwrap_or(&empty);
+            return diff(&Value::Object(old.clone()), &Value::Object(new.clone()), key, changed)
+        },
+        _ => {
+            changed.insert(String::from(key));
+            return
+        },
+    };
+
+    let parts: BTreeSet<&String> = old_map.keys().chain(new_map.keys()).collect();
+    for part in parts {
+        let child = if key.is_empty() { part.clone() } else { format!("{}.{}", key, part) };
+        diff(old_map.get(part).unwrap_or(&Value::Null), new_map.get(part).unwrap_or(&Value::Null), &child, changed);
+    }
+}
+
+fn is_under(key: &str, prefix: &str) -> bool {
+    prefix.is_empty() || key == prefix || key.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryMount;
+
+    #[test]
+    fn layers_override_in_order() {
+        let vfs = Arc::new(Vfs::new().with_mount("/config", MemoryMount::new().with_file("hadron.json", br#"{"renderer": {"vsync": false, "resolution_scale": 0.5}, "app": {"title": "from file"}}"#)));
+        let args = ["hadron", "--set=renderer.resolution_scale=0.75", "--set=app.title=from args"].map(String::from);
+        let vars = [("HADRON_APP__TITLE", "from env"), ("HADRON_APP__FRAME_LIMIT", "30"), ("HADRON_LOG_DIR", "/tmp")].map(|(name, value)| (String::from(name), String::from(value)));
+        let config = Config::from_sources(Arc::clone(&vfs), args, vars);
+
+        assert!(!config.section::<RendererConfig>().vsync);
+        assert_eq!(config.get::<f32>("renderer.resolution_scale").unwrap(), 0.75);
+        assert_eq!(config.section::<AppConfig>().title, "from args");
+        assert_eq!(config.section::<AppConfig>().frame_limit, Some(30.0));
+        assert_eq!(config.source_of("app.frame_limit"), Some(Layer::Environment));
+        assert_eq!(config.source_of("app.width"), Some(Layer::Defaults));
+        assert!(config.value("log_dir").is_none());
+        assert!(matches!(config.get::<bool>("app.title"), Err(ConfigError::Type(..))));
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&changes);
+        config.watch("renderer", move |_, keys| seen.lock().unwrap().extend_from_slice(keys));
+        config.set("renderer.vsync", true).unwrap();
+        config.set("app.width", 1024).unwrap();
+        config.set("renderer.vsync", true).unwrap();
+        assert_eq!(*changes.lock().unwrap(), vec![String::from("renderer.vsync")]);
+
+        // Runtime values are saved along with the file, command line and environment overrides aren't
+        config.save().unwrap();
+        let saved = Config::from_sources(vfs, Vec::new(), Vec::new());
+        assert!(saved.section::<RendererConfig>().vsync);
+        assert_eq!(saved.section::<RendererConfig>().resolution_scale, 0.5);
+        assert_eq!(saved.section::<AppConfig>().width, 1024);
+        assert_eq!(saved.section::<AppConfig>().title, "from file");
+    }
+
+    #[test]
+    fn file_edits_reload() {
+        let vfs = Arc::new(Vfs::new().with_mount("/config", MemoryMount::new()));
+        let config = Config::from_sources(Arc::clone(&vfs), Vec::new(), Vec::new());
+        assert!(config.reload_if_changed().unwrap().is_empty());
+
+        vfs.write(DEFAULT_CONFIG_FILE, br#"{"log": {"level": "warning"}, "app": {"frame_limit": 60}}"#).unwrap();
+        assert_eq!(config.reload_if_changed().unwrap(), vec![String::from("app.frame_limit"), String::from("log.level")]);
+        assert!(config.reload_if_changed().unwrap().is_empty());
+        assert_eq!(config.section::<LogFilter>().level, log::LevelFilter::Warning);
+
+        // A broken edit keeps the last good values and is only reported once
+        vfs.write(DEFAULT_CONFIG_FILE, b"{ \"app\": ").unwrap();
+        assert!(matches!(config.reload_if_changed(), Err(ConfigError::Parse(..))));
+        assert!(config.reload_if_changed().unwrap().is_empty());
+        assert_eq!(config.get::<f64>("app.frame_limit").unwrap(), 60.0);
+    }
+}
@@ -0,0 +1,284 @@
+//!
+//! Benchmark mode for automated performance regression runs
+//!
+//! Started with `--benchmark`, the app flies the active camera along a scripted path for a fixed number of frames with
+//! vsync off, then writes frame time percentiles and memory use to a JSON report and exits. Warmup frames are run but
+//! not measured so shader compilation and first-use uploads don't skew the results
+//!
+
+use std::{path::{Path, PathBuf}, time::{Duration, Instant}};
+
+use serde::{Serialize, Deserialize};
+
+use crate::graphics::{camera::{self, Camera}, device_report};
+use crate::system::{world::World, transform::Transform};
+
+use super::log;
+
+/// Path the benchmark camera follows, sampled evenly over the measured frames
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraPath {
+    keyframes: Vec<Transform>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchmarkConfig {
+    pub frames: u64,
+    pub warmup_frames: u64,
+    pub output: PathBuf,
+    pub path: CameraPath,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct FrameTimePercentiles {
+    pub min: f64,
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct BenchmarkReport {
+    pub frames: u64,
+    pub warmup_frames: u64,
+    pub duration_secs: f64,
+    pub average_fps: f64,
+    /// Milliseconds
+    pub frame_time: FrameTimePercentiles,
+    /// Only present with the `memory-tracking` feature
+    pub peak_allocated_bytes: Option<u64>,
+    pub final_allocated_bytes: Option<u64>,
+    pub device: Option<String>,
+}
+
+/// A running benchmark, driven by the app once per frame
+pub struct Benchmark {
+    config: BenchmarkConfig,
+    frame: u64,
+    frame_times: Vec<f64>,
+    started: Option<Instant>,
+    peak_allocated: Option<u64>,
+    camera: Option<collider::EntityId>,
+}
+
+// Impls
+
+impl Default for CameraPath {
+    fn default() -> Self {
+        CameraPath::orbit(20.0, 5.0, 16)
+    }
+}
+
+impl CameraPath {
+    pub fn new(keyframes: Vec<Transform>) -> Self {
+        CameraPath { keyframes }
+    }
+
+    /// A circle around the origin at `height`, always facing the center
+    pub fn orbit(radius: f64, height: f64, keyframes: usize) -> Self {
+        let keyframes = (0..=keyframes.max(1))
+            .map(|i| {
+                let angle = std::f64::consts::TAU * i as f64 / keyframes.max(1) as f64;
+                Transform { translation: [radius * angle.sin(), height, radius * angle.cos()], ..Transform::from_yaw(angle) }
+            })
+            .collect();
+        CameraPath { keyframes }
+    }
+
+    /// Samples the path at `t` in `0..=1`
+    pub fn sample(&self, t: f64) -> Transform {
+        match self.keyframes.len() {
+            0 => Transform::IDENTITY,
+            1 => self.keyframes[0],
+            len => {
+                let position = t.clamp(0.0, 1.0) * (len - 1) as f64;
+                let index = (position.floor() as usize).min(len - 2);
+                self.keyframes[index].lerp(&self.keyframes[index + 1], position - index as f64)
+            },
+        }
+    }
+}
+
+impl Default for BenchmarkConfig {
+    fn default() -> Self {
+        BenchmarkConfig {
+            frames: 1000,
+            warmup_frames: 60,
+            output: PathBuf::from("benchmark.json"),
+            path: CameraPath::default(),
+        }
+    }
+}
+
+impl BenchmarkConfig {
+    /// Parses `--benchmark`, `--benchmark-frames=N`, `--benchmark-warmup=N` and `--benchmark-out=PATH`. Returns
+    /// `None` unless `--benchmark` is present
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Option<Self> {
+        let mut enabled = false;
+        let mut config = BenchmarkConfig::default();
+        for arg in args {
+            let (key, value) = arg.split_once('=').map_or((arg.as_str(), None), |(key, value)| (key, Some(value)));
+            match (key, value) {
+                ("--benchmark", _) => enabled = true,
+                ("--benchmark-frames", Some(value)) => config.frames = value.parse().unwrap_or(config.frames),
+                ("--benchmark-warmup", Some(value)) => config.warmup_frames = value.parse().unwrap_or(config.warmup_frames),
+                ("--benchmark-out", Some(value)) => config.output = PathBuf::from(value),
+                _ => (),
+            }
+        }
+        enabled.then_some(config)
+    }
+
+    pub fn with_frames(mut self, frames: u64) -> Self {
+        self.frames = frames;
+        self
+    }
+
+    pub fn with_warmup_frames(mut self, frames: u64) -> Self {
+        self.warmup_frames = frames;
+        self
+    }
+
+    pub fn with_output<P: Into<PathBuf>>(mut self, output: P) -> Self {
+        self.output = output.into();
+        self
+    }
+
+    pub fn with_path(mut self, path: CameraPath) -> Self {
+        self.path = path;
+        self
+    }
+}
+
+impl FrameTimePercentiles {
+    pub fn from_samples(samples: &[f64]) -> Self {
+        if samples.is_empty() {
+            return FrameTimePercentiles::default()
+        }
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        FrameTimePercentiles {
+            min: sorted[0],
+            mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+            p50: percentile(&sorted, 50.0),
+            p90: percentile(&sorted, 90.0),
+            p95: percentile(&sorted, 95.0),
+            p99: percentile(&sorted, 99.0),
+            max: sorted[sorted.len() - 1],
+        }
+    }
+}
+
+impl BenchmarkReport {
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+    }
+}
+
+impl Benchmark {
+    pub fn new(config: BenchmarkConfig) -> Self {
+        Benchmark {
+            frame_times: Vec::with_capacity(config.frames as usize),
+            config,
+            frame: 0,
+            started: None,
+            peak_allocated: None,
+            camera: None,
+        }
+    }
+
+    pub fn config(&self) -> &BenchmarkConfig {
+        &self.config
+    }
+
+    /// Records a frame and moves the camera along the path. Returns the report once every frame has run
+    pub fn frame(&mut self, world: &World, delta: Duration) -> Option<BenchmarkReport> {
+        let measured = self.frame.checked_sub(self.config.warmup_frames);
+        match measured {
+            Some(0) => self.started = Some(Instant::now()),
+            Some(_) => self.frame_times.push(delta.as_secs_f64() * 1000.0),
+            None => (),
+        }
+        self.frame += 1;
+
+        if let Some(allocated) = super::allocated_bytes() {
+            self.peak_allocated = Some(self.peak_allocated.map_or(allocated, |peak| peak.max(allocated)));
+        }
+
+        let t = measured.map_or(0.0, |frame| frame as f64 / self.config.frames.max(1) as f64);
+        self.move_camera(world, self.config.path.sample(t));
+
+        measured.is_some_and(|frame| frame >= self.config.frames).then(|| self.report())
+    }
+
+    pub fn report(&self) -> BenchmarkReport {
+        let duration_secs = self.started.map_or(0.0, |started| started.elapsed().as_secs_f64());
+        BenchmarkReport {
+            frames: self.frame_times.len() as u64,
+            warmup_frames: self.config.warmup_frames,
+            duration_secs,
+            average_fps: if duration_secs > 0.0 { self.frame_times.len() as f64 / duration_secs } else { 0.0 },
+            frame_time: FrameTimePercentiles::from_samples(&self.frame_times),
+            peak_allocated_bytes: self.peak_allocated,
+            final_allocated_bytes: super::allocated_bytes(),
+            device: device_report::device_report().and_then(|report| report.selected_device().map(|device| device.name.clone())),
+        }
+    }
+
+    /// Writes the report to the configured output and logs a summary
+    pub fn finish(&self, report: &BenchmarkReport) -> std::io::Result<()> {
+        log::get().state("benchmark finished", report);
+        report.write(&self.config.output)
+    }
+
+    /// Drives the active camera, spawning one if the world doesn't have any
+    fn move_camera(&mut self, world: &World, transform: Transform) {
+        let camera = match self.camera.or_else(|| camera::active_camera(world)) {
+            Some(camera) => camera,
+            None => {
+                let camera = world.spawn_entity();
+                world.insert_component(camera, Camera::default());
+                camera
+            },
+        };
+        self.camera = Some(camera);
+        world.insert_component(camera, transform);
+    }
+}
+
+/// Nearest-rank percentile of already sorted samples
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_and_args() {
+        let samples: Vec<f64> = (1..=100).map(|v| v as f64).collect();
+        let percentiles = FrameTimePercentiles::from_samples(&samples);
+        assert_eq!((percentiles.min, percentiles.p50, percentiles.p99, percentiles.max), (1.0, 50.0, 99.0, 100.0));
+        assert_eq!(percentiles.mean, 50.5);
+
+        let args = ["hadron", "--benchmark", "--benchmark-frames=10", "--benchmark-out=out/bench.json"].map(String::from);
+        let config = BenchmarkConfig::from_args(args).unwrap();
+        assert_eq!((config.frames, config.output), (10, PathBuf::from("out/bench.json")));
+        assert!(BenchmarkConfig::from_args([String::from("hadron")]).is_none());
+    }
+
+    #[test]
+    fn path_samples_between_keyframes() {
+        let path = CameraPath::new(vec![Transform::from_translation(0.0, 0.0, 0.0), Transform::from_translation(10.0, 0.0, 0.0)]);
+        assert_eq!(path.sample(0.25).translation, [2.5, 0.0, 0.0]);
+        assert_eq!(path.sample(2.0).translation, [10.0, 0.0, 0.0]);
+    }
+}
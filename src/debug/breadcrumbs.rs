@@ -0,0 +1,78 @@
+//!
+//! A ring buffer of recent notable events, attached to crash reports
+//!
+
+use std::{collections::VecDeque, sync::Mutex, time::{Duration, SystemTime}};
+
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+
+static BREADCRUMBS: Lazy<Mutex<Breadcrumbs>> = Lazy::new(|| Mutex::new(Breadcrumbs::new(32)));
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Breadcrumb {
+    /// Time since the unix epoch
+    pub time: Duration,
+    pub category: String,
+    pub message: String,
+}
+
+struct Breadcrumbs {
+    capacity: usize,
+    items: VecDeque<Breadcrumb>,
+}
+
+impl Breadcrumbs {
+    fn new(capacity: usize) -> Self {
+        Breadcrumbs { capacity, items: VecDeque::with_capacity(capacity) }
+    }
+
+    fn push(&mut self, breadcrumb: Breadcrumb) {
+        if self.capacity == 0 {
+            return
+        }
+        while self.items.len() >= self.capacity {
+            self.items.pop_front();
+        }
+        self.items.push_back(breadcrumb);
+    }
+}
+
+/// Records a breadcrumb, dropping the oldest if the buffer is full
+pub fn push(category: &str, message: &str) {
+    let breadcrumb = Breadcrumb {
+        time: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default(),
+        category: String::from(category),
+        message: String::from(message),
+    };
+    BREADCRUMBS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(breadcrumb);
+}
+
+/// Sets how many breadcrumbs are kept, discarding the oldest if there are already more
+pub fn set_capacity(capacity: usize) {
+    let mut breadcrumbs = BREADCRUMBS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    breadcrumbs.capacity = capacity;
+    while breadcrumbs.items.len() > capacity {
+        breadcrumbs.items.pop_front();
+    }
+}
+
+/// The recorded breadcrumbs, oldest first
+pub fn snapshot() -> Vec<Breadcrumb> {
+    BREADCRUMBS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).items.iter().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oldest_breadcrumbs_are_dropped() {
+        let mut breadcrumbs = Breadcrumbs::new(2);
+        for message in ["a", "b", "c"] {
+            breadcrumbs.push(Breadcrumb { time: Duration::ZERO, category: String::from("test"), message: String::from(message) });
+        }
+        let messages: Vec<_> = breadcrumbs.items.iter().map(|b| b.message.as_str()).collect();
+        assert_eq!(messages, vec!["b", "c"]);
+    }
+}
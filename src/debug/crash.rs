@@ -0,0 +1,183 @@
+//!
+//! Crash autosave
+//!
+//! When the app panics, the panic hook saves a snapshot of the watched `World` next to the structured log and records
+//! where in the panic's log entry. The save is a `Prefab` with one child per entity holding its registered components,
+//! so it can be read back for inspection or spawned into a fresh world with `replay`. Entities past the size limit are
+//! left out, and the save runs on its own thread so a panic that left a world lock held can't hang the crash
+//!
+
+use std::{collections::BTreeMap, path::{Path, PathBuf}, sync::{mpsc, Mutex, atomic::{AtomicBool, Ordering}}, thread, time::{Duration, SystemTime}};
+
+use collider::EntityId;
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+
+use crate::config::ConfigSection;
+use crate::unique::UniqueId;
+use crate::system::{world::World, component::Tick, registry, prefab::{self, Prefab, PrefabEntity, PrefabError}};
+use crate::asset::Assets;
+use super::{log, snapshot::WorldSnapshot};
+
+pub(crate) const AUTOSAVE_THREAD_NAME: &str = "crash autosave";
+
+static WATCHED: Lazy<Mutex<Option<(World, CrashSaveConfig)>>> = Lazy::new(|| Mutex::new(None));
+
+/// Set while a save is running, so a panic inside it doesn't start another
+static SAVING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct CrashSaveConfig {
+    pub enabled: bool,
+    /// Serialized component data saved at most, entities past it are left out
+    pub max_bytes: u64,
+    /// How long the panic waits for the save before giving up on it
+    pub timeout_ms: u64,
+}
+
+/// A world as it was when the app panicked
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CrashSave {
+    pub message: String,
+    /// Seconds since the unix epoch
+    pub time: u64,
+    /// The world's change tick
+    pub tick: Tick,
+    /// Entities left out to stay under the size limit
+    pub skipped: usize,
+    /// One child per entity
+    pub world: Prefab,
+}
+
+// Impls
+
+impl Default for CrashSaveConfig {
+    fn default() -> Self {
+        CrashSaveConfig { enabled: true, max_bytes: 4 * 1024 * 1024, timeout_ms: 500 }
+    }
+}
+
+impl ConfigSection for CrashSaveConfig {
+    const NAME: &'static str = "crash_save";
+}
+
+impl CrashSave {
+    /// Snapshots `world`, keeping at most `max_bytes` of component data
+    pub fn capture(world: &World, message: &str, max_bytes: u64) -> Self {
+        let snapshot = WorldSnapshot::take(world);
+        let mut entities: Vec<(EntityId, &BTreeMap<String, serde_json::Value>)> = snapshot.iter().collect();
+        entities.sort_by_cached_key(|(entity, _)| format!("{:?}", entity));
+
+        let (mut children, mut bytes, mut skipped) = (Vec::new(), 0u64, 0);
+        for (_, components) in entities {
+            let size = serde_json::to_vec(components).map_or(0, |json| json.len() as u64);
+            if bytes + size > max_bytes {
+                skipped += 1;
+                continue
+            }
+            bytes += size;
+            children.push(PrefabEntity { id: UniqueId::get(), name: None, components: components.clone(), assets: BTreeMap::new(), children: Vec::new() });
+        }
+
+        let root = PrefabEntity { id: UniqueId::get(), name: Some(String::from("crash save")), components: BTreeMap::new(), assets: BTreeMap::new(), children };
+        CrashSave { message: String::from(message), time: unix_time(), tick: snapshot.tick, skipped, world: Prefab::new(root).with_versions(registry::versions(world)) }
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        serde_json::from_slice(&bytes).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let json = serde_json::to_vec(self).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        std::fs::write(path, json)
+    }
+}
+
+/// Saves `world` if the app panics, replacing any world watched before
+pub fn watch(world: &World, config: CrashSaveConfig) {
+    *WATCHED.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some((world.clone(), config));
+}
+
+pub fn unwatch() {
+    WATCHED.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+}
+
+/// Where a save taken now goes, next to the structured log
+pub fn crash_save_path() -> PathBuf {
+    let config = log::config();
+    config.directory.join(format!("{}-crash-{}.json", config.file_stem, unix_time()))
+}
+
+/// Spawns the entities of a crash save into `world`, under one root entity
+pub fn replay(world: &World, save: &CrashSave) -> Result<EntityId, PrefabError> {
+    if !world.contains_resource::<Assets<Prefab>>() {
+        world.insert_resource(Assets::<Prefab>::default());
+    }
+    let handle = world.with_resource_mut::<Assets<Prefab>, _>(|prefabs| prefabs.add(save.world.clone())).expect("no prefab storage");
+    prefab::spawn_prefab(world, handle)
+}
+
+/// Saves the watched world for a panic, returning where it went. Called from the panic hook
+pub(crate) fn autosave(message: &str) -> Option<PathBuf> {
+    // The panic may have happened with the lock held
+    let (world, config) = WATCHED.try_lock().ok()?.clone()?;
+    if !config.enabled || SAVING.swap(true, Ordering::SeqCst) {
+        return None
+    }
+    let result = save(world, message, &config, crash_save_path());
+    if let Err(err) = &result {
+        eprintln!("unable to save the world after a panic: {}", err);
+    }
+    result.ok()
+}
+
+/// Captures and writes a save on another thread, waiting up to the configured timeout for it
+fn save(world: World, message: &str, config: &CrashSaveConfig, path: PathBuf) -> Result<PathBuf, String> {
+    let (tx, rx) = mpsc::channel();
+    let (message, max_bytes) = (String::from(message), config.max_bytes);
+    thread::Builder::new().name(String::from(AUTOSAVE_THREAD_NAME)).spawn(move || {
+        let result = CrashSave::capture(&world, &message, max_bytes).write(&path).map(|_| path);
+        let _ = tx.send(result);
+        SAVING.store(false, Ordering::SeqCst);
+    }).map_err(|err| err.to_string())?;
+
+    match rx.recv_timeout(Duration::from_millis(config.timeout_ms)) {
+        Ok(result) => result.map_err(|err| err.to_string()),
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(format!("timed out after {}ms", config.timeout_ms)),
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err(String::from("the save panicked")),
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |time| time.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::registry::ComponentRegistry;
+
+    #[test]
+    fn saves_are_written_within_the_timeout() {
+        let path = std::env::temp_dir().join(format!("hadron_crash_save_{}.json", std::process::id()));
+        let world = World::new();
+        let config = CrashSaveConfig { timeout_ms: 200, ..CrashSaveConfig::default() };
+
+        assert_eq!(save(world.clone(), "boom", &config, path.clone()), Ok(path.clone()));
+        let saved = CrashSave::read(&path).unwrap();
+        assert_eq!((saved.message.as_str(), saved.skipped), ("boom", 0));
+        let _ = std::fs::remove_file(&path);
+
+        // A lock left held by the panic stalls the snapshot, the save gives up rather than hang
+        let result = world.with_resource_mut::<ComponentRegistry, _>(|_| save(world.clone(), "stuck", &config, path.clone())).unwrap();
+        assert!(result.unwrap_err().starts_with("timed out"));
+        for _ in 0..100 {
+            if std::fs::remove_file(&path).is_ok() {
+                break
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//!
+//! Window event recording
+//!
+//! With `record` set in the `event_log` config section, every window and device event the app dispatches is written
+//! to the structured log under the "events" topic, along with the frame it arrived in. Events that arrive many times a
+//! frame, like cursor motion, are throttled to one entry per kind each `throttle_ms`, and each entry counts how many of
+//! its kind were skipped since the last. Attached to a bug report, the log shows exactly what input led up to it
+//!
+
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use serde::{Serialize, Deserialize};
+
+use crate::config::ConfigSection;
+
+use super::log;
+
+/// Options from the `event_log` config section
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EventLogConfig {
+    pub record: bool,
+    /// Least time between entries of a high frequency event kind
+    pub throttle_ms: u64,
+    /// Also records the loop's own events, several of which arrive every frame
+    pub loop_events: bool,
+}
+
+/// How often an event kind arrives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventFrequency {
+    /// User actions and window changes, always recorded
+    Discrete,
+    /// Motion and pressure updates, throttled
+    High,
+    /// The event loop's per frame events, only recorded with `loop_events`
+    Loop,
+}
+
+/// An event as written to the log
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RecordedEvent {
+    pub event: &'static str,
+    pub detail: String,
+    pub frame: u64,
+    /// Events of the same kind throttled away since the last entry
+    pub skipped: u64,
+}
+
+/// Writes dispatched events to the log, throttling high frequency ones
+#[derive(Debug, Clone)]
+pub struct EventRecorder {
+    config: EventLogConfig,
+    /// When each throttled kind was last recorded and how many were skipped since
+    throttled: HashMap<&'static str, (Instant, u64)>,
+}
+
+// Impls
+
+impl Default for EventLogConfig {
+    fn default() -> Self {
+        EventLogConfig { record: false, throttle_ms: 100, loop_events: false }
+    }
+}
+
+impl ConfigSection for EventLogConfig {
+    const NAME: &'static str = "event_log";
+}
+
+impl EventRecorder {
+    pub fn new(config: EventLogConfig) -> Self {
+        EventRecorder { config, throttled: HashMap::new() }
+    }
+
+    pub fn config(&self) -> &EventLogConfig {
+        &self.config
+    }
+
+    /// The entry for an event of kind `event` arriving at `now`, `None` if it's filtered or throttled. `detail` is only
+    /// formatted for events that are recorded
+    pub fn filter(&mut self, event: &'static str, frequency: EventFrequency, detail: impl FnOnce() -> String, frame: u64, now: Instant) -> Option<RecordedEvent> {
+        if !self.config.record {
+            return None
+        }
+
+        let skipped = match frequency {
+            EventFrequency::Discrete => 0,
+            EventFrequency::Loop if !self.config.loop_events => return None,
+            EventFrequency::Loop => 0,
+            EventFrequency::High => {
+                let throttle = Duration::from_millis(self.config.throttle_ms);
+                match self.throttled.get_mut(event) {
+                    Some((last, skipped)) if now.saturating_duration_since(*last) < throttle => {
+                        *skipped += 1;
+                        return None
+                    },
+                    Some((last, skipped)) => {
+                        *last = now;
+                        std::mem::take(skipped)
+                    },
+                    None => {
+                        self.throttled.insert(event, (now, 0));
+                        0
+                    },
+                }
+            },
+        };
+        Some(RecordedEvent { event, detail: detail(), frame, skipped })
+    }
+
+    /// Logs an event of kind `event` unless it's filtered or throttled
+    pub fn record(&mut self, event: &'static str, frequency: EventFrequency, detail: impl FnOnce() -> String, frame: u64) {
+        if let Some(recorded) = self.filter(event, frequency, detail, frame, Instant::now()) {
+            log::get().with_topic("events").state(recorded.event, &recorded);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn high_frequency_events_are_throttled() {
+        let mut recorder = EventRecorder::new(EventLogConfig { record: true, ..Default::default() });
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let detail = || String::from("(12.0, 34.0)");
+
+        assert_eq!(recorder.filter("CursorMoved", EventFrequency::High, detail, 1, at(0)).map(|event| event.skipped), Some(0));
+        assert!(recorder.filter("CursorMoved", EventFrequency::High, detail, 1, at(30)).is_none());
+        assert!(recorder.filter("CursorMoved", EventFrequency::High, detail, 2, at(60)).is_none());
+        // Other kinds aren't held back by it
+        assert!(recorder.filter("MouseInput", EventFrequency::Discrete, detail, 2, at(61)).is_some());
+        assert!(recorder.filter("MainEventsCleared", EventFrequency::Loop, detail, 2, at(62)).is_none());
+
+        let recorded = recorder.filter("CursorMoved", EventFrequency::High, detail, 3, at(120)).unwrap();
+        assert_eq!((recorded.frame, recorded.skipped), (3, 2));
+    }
+}
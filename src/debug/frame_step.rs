@@ -0,0 +1,164 @@
+//!
+//! Frame stepping
+//!
+//! While stepping, scaled time is held and the fixed stage only ticks when asked to, exactly once per request, so the
+//! effect of a single tick on every fixed system can be inspected. Frames keep rendering the last tick in the meantime.
+//! `TOGGLE_KEY` switches stepping on and off and `STEP_KEY` requests a tick, or from the console `step`, `step 10`,
+//! `step on` and `step off`. The keys can be rebound through the `frame_step.toggle` and `frame_step.step` actions of
+//! the accessibility settings
+//!
+
+use winit::event::{ElementState, KeyboardInput, VirtualKeyCode};
+
+use crate::accessibility;
+use crate::system::world::World;
+use super::log;
+
+pub const TOGGLE_KEY: VirtualKeyCode = VirtualKeyCode::Pause;
+pub const STEP_KEY: VirtualKeyCode = VirtualKeyCode::F10;
+pub const TOGGLE_ACTION: &str = "frame_step.toggle";
+pub const STEP_ACTION: &str = "frame_step.step";
+
+/// World resource holding the fixed timestep while frame stepping
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStep {
+    enabled: bool,
+    /// Ticks requested but not run yet
+    requested: u32,
+}
+
+// Impls
+
+impl FrameStep {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn requested(&self) -> u32 {
+        self.requested
+    }
+
+    pub fn enable(&mut self) {
+        self.enabled = true;
+    }
+
+    /// Stops stepping, the fixed timestep carries on from the last tick
+    pub fn disable(&mut self) {
+        self.enabled = false;
+        self.requested = 0;
+    }
+
+    pub fn toggle(&mut self) {
+        match self.enabled {
+            true => self.disable(),
+            false => self.enable(),
+        }
+    }
+
+    /// Requests `ticks` more fixed ticks, starting to step if it wasn't already
+    pub fn step(&mut self, ticks: u32) {
+        self.enabled = true;
+        self.requested = self.requested.saturating_add(ticks);
+    }
+
+    /// `None` when not stepping, otherwise whether a requested tick was taken
+    pub(crate) fn take(&mut self) -> Option<bool> {
+        if !self.enabled {
+            return None
+        }
+        let take = self.requested > 0;
+        self.requested = self.requested.saturating_sub(1);
+        Some(take)
+    }
+}
+
+/// Whether the world is frame stepping
+pub fn is_stepping(world: &World) -> bool {
+    world.with_resource::<FrameStep, _>(FrameStep::is_enabled).unwrap_or(false)
+}
+
+/// Runs `f` on the world's `FrameStep`, adding one if it has none
+pub fn with_frame_step<R>(world: &World, f: impl FnOnce(&mut FrameStep) -> R) -> R {
+    if !world.contains_resource::<FrameStep>() {
+        world.insert_resource(FrameStep::default());
+    }
+    world.with_resource_mut::<FrameStep, _>(f).expect("no frame step")
+}
+
+/// Applies the stepping keys, returns whether `input` was one of them
+pub fn handle_key(world: &World, input: &KeyboardInput) -> bool {
+    if input.state != ElementState::Pressed {
+        return false
+    }
+    let Some(key) = input.virtual_keycode else {
+        return false
+    };
+    let settings = accessibility::settings();
+    match key {
+        key if settings.is_bound(TOGGLE_ACTION, key, &[TOGGLE_KEY]) => {
+            let enabled = with_frame_step(world, |frame_step| {
+                frame_step.toggle();
+                frame_step.is_enabled()
+            });
+            log::get().with_topic("frame step").info(format!("frame stepping {}", if enabled { "on" } else { "off" }));
+            true
+        },
+        key if settings.is_bound(STEP_ACTION, key, &[STEP_KEY]) => {
+            with_frame_step(world, |frame_step| frame_step.step(1));
+            true
+        },
+        _ => false,
+    }
+}
+
+/// Console binding, runs `step`, `step <ticks>`, `step on` or `step off` and returns a line describing the result
+pub fn command(world: &World, line: &str) -> Result<String, String> {
+    let line = line.trim();
+    let argument = line.strip_prefix("step").unwrap_or(line).trim();
+    with_frame_step(world, |frame_step| {
+        match argument {
+            "on" => frame_step.enable(),
+            "off" => frame_step.disable(),
+            "" => frame_step.step(1),
+            ticks => frame_step.step(ticks.parse().map_err(|_| format!("invalid tick count {}", ticks))?),
+        }
+        Ok(match frame_step.is_enabled() {
+            true => format!("frame stepping, {} ticks requested", frame_step.requested()),
+            false => String::from("frame stepping off"),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::system::{schedule::{Schedule, stage}, time::{self, FixedTime}};
+
+    #[test]
+    fn stepping_runs_one_fixed_tick_per_request() {
+        let world = World::new();
+        time::init_time(&world);
+        world.insert_resource(FixedTime::new(Duration::from_millis(10)));
+        world.insert_resource(0u32);
+        let mut schedule = Schedule::default();
+        schedule.add_system(stage::FIXED_UPDATE, "count", |world| { world.with_resource_mut::<u32, _>(|n| *n += 1); });
+
+        let mut run = |delta| {
+            time::advance_time(&world, Duration::from_millis(delta));
+            schedule.run(&world);
+            world.with_resource::<u32, _>(|n| *n).unwrap()
+        };
+        assert_eq!(run(20), 2);
+        assert_eq!(command(&world, "step on"), Ok(String::from("frame stepping, 0 ticks requested")));
+        assert_eq!(run(50), 2);
+        assert!(command(&world, "step").is_ok());
+        assert_eq!(run(50), 3);
+        assert!(command(&world, "step 2").is_ok());
+        assert_eq!(run(0), 5);
+        assert!(command(&world, "step many").is_err());
+
+        assert!(command(&world, "step off").is_ok());
+        assert_eq!(run(10), 6);
+    }
+}
@@ -0,0 +1,239 @@
+//!
+//! Entity inspector
+//!
+//! Lists entities and their components, and edits simple values in place. Components show up once their type is
+//! registered as serializable in the component registry, and can be edited if it's deserializable too. The inspector
+//! only builds a panel model, `InspectorPanel::lines` flattens it to text for an overlay to draw
+//!
+
+use std::collections::HashSet;
+
+use collider::EntityId;
+use serde_json::{Number, Value};
+
+use crate::system::{world::World, prefab::Name, registry::{self, RegistryError}};
+
+#[derive(Debug)]
+pub enum InspectorError {
+    Registry(RegistryError),
+    /// The entity doesn't have the component
+    Missing(String),
+    /// No field at the path, or it isn't a simple value
+    Field(String, String),
+}
+
+/// A leaf of a component's serialized form
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Bool(bool),
+    Number(f64),
+    Text(String),
+    /// Nulls and anything else that can't be edited, as JSON
+    Other(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    /// Dot separated path into the component, array elements by index
+    pub path: String,
+    pub value: FieldValue,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ComponentView {
+    pub name: String,
+    pub fields: Vec<Field>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntityRow {
+    pub entity: EntityId,
+    /// The entity's `Name`, if it has one
+    pub name: Option<String>,
+}
+
+/// Everything the inspector shows for one frame
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InspectorPanel {
+    pub entities: Vec<EntityRow>,
+    pub selected: Option<(EntityId, Vec<ComponentView>)>,
+}
+
+/// Selection state of the inspector
+#[derive(Debug, Clone, Default)]
+pub struct Inspector {
+    selected: Option<EntityId>,
+    /// Only entities whose name contains this are listed
+    filter: Option<String>,
+}
+
+// Impls
+
+impl std::error::Error for InspectorError {}
+
+impl std::fmt::Display for InspectorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InspectorError::Registry(err) => write!(f, "{}", err),
+            InspectorError::Missing(name) => write!(f, "the entity has no {}", name),
+            InspectorError::Field(path, err) => write!(f, "can't edit {}: {}", path, err),
+        }
+    }
+}
+
+impl From<RegistryError> for InspectorError {
+    fn from(err: RegistryError) -> Self {
+        InspectorError::Registry(err)
+    }
+}
+
+impl std::fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FieldValue::Bool(value) => write!(f, "{}", value),
+            FieldValue::Number(value) => write!(f, "{}", value),
+            FieldValue::Text(value) => write!(f, "{:?}", value),
+            FieldValue::Other(json) => write!(f, "{}", json),
+        }
+    }
+}
+
+impl Inspector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn select(&mut self, entity: Option<EntityId>) {
+        self.selected = entity;
+    }
+
+    pub fn selected(&self) -> Option<EntityId> {
+        self.selected
+    }
+
+    pub fn set_filter(&mut self, filter: Option<&str>) {
+        self.filter = filter.map(String::from);
+    }
+
+    /// Builds the panel from the world's current state. A selected entity that no longer has any inspectable
+    /// component is deselected
+    pub fn panel(&mut self, world: &World) -> InspectorPanel {
+        let registry = registry::registry(world);
+        let inspectable: Vec<_> = registry.iter().filter(|registration| registration.is_serializable()).collect();
+        let mut entities: Vec<EntityId> = inspectable.iter().flat_map(|registration| registration.entities(world)).collect();
+        let mut seen = HashSet::new();
+        entities.retain(|entity| seen.insert(*entity));
+
+        let mut rows: Vec<EntityRow> = entities.into_iter()
+            .map(|entity| EntityRow { entity, name: world.component::<Name, _>(entity, |name| name.0.clone()) })
+            .filter(|row| self.filter.as_ref().is_none_or(|filter| row.name.as_ref().is_some_and(|name| name.contains(filter.as_str()))))
+            .collect();
+        rows.sort_by_cached_key(|row| (row.name.clone(), format!("{:?}", row.entity)));
+
+        if self.selected.is_some_and(|entity| !seen.contains(&entity)) {
+            self.selected = None;
+        }
+        let selected = self.selected.map(|entity| {
+            let components = inspectable.iter()
+                .filter_map(|registration| {
+                    let value = registration.serialize(world, entity).ok()??;
+                    Some(ComponentView { name: String::from(registration.name()), fields: fields(&value) })
+                })
+                .collect();
+            (entity, components)
+        });
+        InspectorPanel { entities: rows, selected }
+    }
+
+    /// Sets one simple field of a component, writing the whole component back so change detection sees it
+    pub fn edit(&self, world: &World, entity: EntityId, component: &str, path: &str, value: FieldValue) -> Result<(), InspectorError> {
+        let registration = registry::registration(world, component)?;
+        let mut serialized = registration.serialize(world, entity)?.ok_or_else(|| InspectorError::Missing(String::from(component)))?;
+        set_field(&mut serialized, path, value)?;
+        Ok(registration.deserialize(world, entity, serialized)?)
+    }
+}
+
+impl InspectorPanel {
+    /// One line per entity, the selected one followed by its components and fields
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for row in &self.entities {
+            let marker = if self.selected.as_ref().is_some_and(|(entity, _)| *entity == row.entity) { ">" } else { " " };
+            lines.push(format!("{} {:?} {}", marker, row.entity, row.name.as_deref().unwrap_or("")));
+        }
+        if let Some((_, components)) = &self.selected {
+            for component in components {
+                lines.push(format!("[{}]", component.name));
+                lines.extend(component.fields.iter().map(|field| format!("  {} = {}", field.path, field.value)));
+            }
+        }
+        lines
+    }
+}
+
+/// Every leaf of a serialized component
+fn fields(value: &Value) -> Vec<Field> {
+    fn walk(value: &Value, path: String, fields: &mut Vec<Field>) {
+        let child = |key: &dyn std::fmt::Display| if path.is_empty() { key.to_string() } else { format!("{}.{}", path, key) };
+        match value {
+            Value::Object(map) => map.iter().for_each(|(key, value)| walk(value, child(key), fields)),
+            Value::Array(values) => values.iter().enumerate().for_each(|(index, value)| walk(value, child(&index), fields)),
+            Value::Bool(value) => fields.push(Field { path, value: FieldValue::Bool(*value) }),
+            Value::Number(number) => fields.push(Field { path, value: number.as_f64().map_or_else(|| FieldValue::Other(number.to_string()), FieldValue::Number) }),
+            Value::String(text) => fields.push(Field { path, value: FieldValue::Text(text.clone()) }),
+            Value::Null => fields.push(Field { path, value: FieldValue::Other(String::from("null")) }),
+        }
+    }
+    let mut fields = Vec::new();
+    walk(value, String::new(), &mut fields);
+    fields
+}
+
+/// Replaces the leaf at `path`, which has to hold a value of the same kind. Whole numbers stay integers where the
+/// field was one
+fn set_field(root: &mut Value, path: &str, value: FieldValue) -> Result<(), InspectorError> {
+    let error = |message: &str| InspectorError::Field(String::from(path), String::from(message));
+    let mut target = root;
+    for part in path.split('.').filter(|part| !part.is_empty()) {
+        target = match target {
+            Value::Object(map) => map.get_mut(part),
+            Value::Array(values) => part.parse::<usize>().ok().and_then(|index| values.get_mut(index)),
+            _ => None,
+        }.ok_or_else(|| error("no such field"))?;
+    }
+
+    *target = match (&*target, value) {
+        (Value::Bool(_), FieldValue::Bool(value)) => Value::Bool(value),
+        (Value::String(_), FieldValue::Text(text)) => Value::String(text),
+        (Value::Number(number), FieldValue::Number(value)) if number.as_f64().is_some() => {
+            let integer = (number.is_i64() || number.is_u64()) && value.fract() == 0.0;
+            match integer {
+                true => Value::Number(Number::from(value as i64)),
+                false => Number::from_f64(value).map(Value::Number).ok_or_else(|| error("not a finite number"))?,
+            }
+        },
+        _ => return Err(error("not a simple value of that kind")),
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flatten_and_edit_fields() {
+        let mut value = serde_json::json!({ "position": [1.5, 2.0], "visible": true, "label": "crate", "count": 3 });
+        let paths: Vec<String> = fields(&value).into_iter().map(|field| field.path).collect();
+        assert_eq!(paths, ["count", "label", "position.0", "position.1", "visible"]);
+
+        set_field(&mut value, "position.1", FieldValue::Number(4.25)).unwrap();
+        set_field(&mut value, "count", FieldValue::Number(7.0)).unwrap();
+        set_field(&mut value, "visible", FieldValue::Bool(false)).unwrap();
+        assert_eq!(value, serde_json::json!({ "position": [1.5, 4.25], "visible": false, "label": "crate", "count": 7 }));
+
+        assert!(matches!(set_field(&mut value, "label", FieldValue::Bool(true)), Err(InspectorError::Field(..))));
+        assert!(matches!(set_field(&mut value, "position.2", FieldValue::Number(1.0)), Err(InspectorError::Field(..))));
+    }
+}
@@ -0,0 +1,119 @@
+//!
+//! Input latency
+//!
+//! Input events are timestamped as the event loop receives them, and each present closes off the inputs that arrived
+//! before it. The time from the earliest of those inputs to the present is one latency sample: how long the oldest
+//! input waited to reach the screen. Backends that get present feedback from the swapchain report when the frame was
+//! actually shown, otherwise the time `present` returned is used
+//!
+
+use std::{collections::VecDeque, time::{Duration, Instant}};
+
+use serde::Serialize;
+
+/// World resource collecting event to present latency over the most recent presented frames
+#[derive(Debug, Clone)]
+pub struct InputLatency {
+    /// The earliest input not yet presented
+    pending: Option<Instant>,
+    samples: VecDeque<Duration>,
+    capacity: usize,
+}
+
+/// Summary of the recent latency samples, in milliseconds
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct LatencyStats {
+    pub last_ms: f64,
+    pub average_ms: f64,
+    pub max_ms: f64,
+    pub samples: usize,
+}
+
+// Impls
+
+impl Default for InputLatency {
+    fn default() -> Self {
+        InputLatency::new(120)
+    }
+}
+
+impl InputLatency {
+    /// Keeps the latency of the last `capacity` frames that presented input
+    pub fn new(capacity: usize) -> Self {
+        InputLatency {
+            pending: None,
+            samples: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Records an input event received at `at`
+    pub fn input(&mut self, at: Instant) {
+        self.pending = Some(self.pending.map_or(at, |pending| pending.min(at)));
+    }
+
+    /// Whether inputs are waiting on a present
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Records a frame presented at `at`, returning the latency of its earliest input if it had any
+    pub fn presented(&mut self, at: Instant) -> Option<Duration> {
+        let latency = at.saturating_duration_since(self.pending.take()?);
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency);
+        Some(latency)
+    }
+
+    pub fn last(&self) -> Option<Duration> {
+        self.samples.back().copied()
+    }
+
+    pub fn average(&self) -> Option<Duration> {
+        let count = u32::try_from(self.samples.len()).ok().filter(|count| *count > 0)?;
+        Some(self.samples.iter().sum::<Duration>() / count)
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.samples.iter().max().copied()
+    }
+
+    /// `None` until a frame with input has been presented
+    pub fn stats(&self) -> Option<LatencyStats> {
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        Some(LatencyStats {
+            last_ms: ms(self.last()?),
+            average_ms: ms(self.average()?),
+            max_ms: ms(self.max()?),
+            samples: self.samples.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn earliest_input_to_present() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut latency = InputLatency::new(2);
+
+        assert_eq!(latency.presented(at(5)), None);
+        latency.input(at(10));
+        latency.input(at(8));
+        assert_eq!(latency.presented(at(20)), Some(Duration::from_millis(12)));
+        assert!(!latency.is_pending());
+
+        latency.input(at(30));
+        latency.presented(at(34));
+        latency.input(at(40));
+        latency.presented(at(46));
+        // Only the last two samples are kept
+        assert_eq!(latency.average(), Some(Duration::from_millis(5)));
+        assert_eq!(latency.stats().map(|stats| (stats.samples, stats.max_ms)), Some((2, 6.0)));
+    }
+}
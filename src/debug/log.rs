@@ -1,11 +1,17 @@
 use std::{
     sync::{
         Mutex, 
+        RwLock, 
         mpsc::{
-            Sender, 
+            SyncSender, 
+            TrySendError, 
             Receiver, 
             self
         }, 
+        atomic::{
+            AtomicU64, 
+            Ordering
+        }, 
         Arc
     }, 
     thread::{
@@ -15,12 +21,12 @@ use std::{
     panic::{
         PanicInfo, 
     }, 
-    path::Path, 
+    path::{Path, PathBuf}, 
     fs::File, 
     io::Write, 
     time::{
         Duration, Instant, self, SystemTime
-    }, fmt::Debug
+    }, fmt::Debug, collections::BTreeMap, cell::RefCell, marker::PhantomData
 };
 
 use once_cell::sync::Lazy;
@@ -28,13 +34,43 @@ use serde::{Serialize, Deserialize};
 
 use self::structured::StructuredLogMessage;
 
+pub use self::structured::{SpanRecord, ThreadRecord};
+
 static GLOBAL_LOG: Lazy<Mutex<Option<LogHandle>>> = Lazy::new(|| Mutex::new(None));
+static LOG_CONFIG: Lazy<RwLock<LogConfig>> = Lazy::new(|| RwLock::new(LogConfig::default()));
+static LOG_FILTER: Lazy<RwLock<LogFilter>> = Lazy::new(|| RwLock::new(LogFilter::default()));
+static DROPPED_MESSAGES: AtomicU64 = AtomicU64::new(0);
+static NEXT_SPAN: AtomicU64 = AtomicU64::new(1);
+static NEXT_THREAD: AtomicU64 = AtomicU64::new(1);
+/// The app's current frame plus one, 0 before the first frame
+static FRAME: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// Spans open on this thread, outermost first
+    static SPANS: RefCell<Vec<SpanRecord>> = const { RefCell::new(Vec::new()) };
+    /// This thread's id and name, given out as each thread first logs
+    static THREAD: ThreadRecord = ThreadRecord {
+        id: NEXT_THREAD.fetch_add(1, Ordering::Relaxed),
+        name: thread::current().name().map(String::from),
+    };
+}
+
+const LOG_THREAD_NAME: &str = "hadron log";
 
 pub struct Logger {
-    tx: Sender<StructuredLogMessage>,
+    tx: SyncSender<StructuredLogMessage>,
     topic: String,
 }
 
+/// A span open on the current thread, closed when dropped. Messages logged on the thread while it's open carry its id
+/// and name, after those of the spans it's nested in
+#[must_use = "the span closes as soon as the guard is dropped"]
+pub struct SpanGuard {
+    id: u64,
+    /// Spans belong to the thread that opened them
+    _thread: PhantomData<*const ()>,
+}
+
 impl Default for Logger {
     fn default() -> Self {
         get()
@@ -43,36 +79,27 @@ impl Default for Logger {
 
 impl Logger {
     pub fn info<T>(&self, info: T) where T: Into<String> {
-        let mut message = StructuredLogMessage {
-            time: Logger::time_stamp_now(),
-            level: structured::LogKind::Information,
-            topic: self.topic.clone(),
-            message: info.into(),
-        };
+        if !self.enabled(LevelFilter::Info) {
+            return
+        }
 
-        self.tx.send(message).expect("unable to send log message");
+        self.send(self.message(structured::LogKind::Information, info.into()));
     }
 
     pub fn warn<T>(&self, info: T) where T: Into<String> {
-        let mut message = StructuredLogMessage {
-            time: Logger::time_stamp_now(),
-            level: structured::LogKind::Warning,
-            topic: self.topic.clone(),
-            message: info.into(),
-        };
+        if !self.enabled(LevelFilter::Warning) {
+            return
+        }
 
-        self.tx.send(message).expect("unable to send log message");
+        self.send(self.message(structured::LogKind::Warning, info.into()));
     }
 
     pub fn error<T>(&self, info: T) where T: Into<String> {
-        let mut message = StructuredLogMessage {
-            time: Logger::time_stamp_now(),
-            level: structured::LogKind::Error,
-            topic: self.topic.clone(),
-            message: info.into(),
-        };
+        if !self.enabled(LevelFilter::Error) {
+            return
+        }
 
-        self.tx.send(message).expect("unable to send log message");
+        self.send(self.message(structured::LogKind::Error, info.into()));
     }
 
     pub fn state<T, S>(&self, message: T, item: &S)
@@ -80,16 +107,53 @@ impl Logger {
         T: Into<String>,
         S: Serialize + Debug,
     {
-        let item_state = serde_json::to_string(item).expect(format!("unable to serialize {:?}", item).as_str());
+        if !self.enabled(LevelFilter::Info) {
+            return
+        }
+
+        let item_state = serde_json::to_string(item).unwrap_or_else(|err| format!("unable to serialize {:?}: {}", item, err));
         
-        let mut message = StructuredLogMessage {
+        self.send(self.message(structured::LogKind::State(item_state), message.into()));
+    }
+
+    /// Opens a span on the current thread, see `SpanGuard`
+    pub fn span(&self, name: &str) -> SpanGuard {
+        span(name)
+    }
+
+    /// Messages logged through the returned logger are filed under `topic` rather than `general`
+    pub fn with_topic(mut self, topic: &str) -> Self {
+        self.topic = String::from(topic);
+        self
+    }
+
+    /// Whether messages at `level` pass the current filter for this logger's topic
+    pub fn enabled(&self, level: LevelFilter) -> bool {
+        LOG_FILTER.read().unwrap_or_else(|poisoned| poisoned.into_inner()).allows(level, &self.topic)
+    }
+
+    /// Never blocks. Messages are dropped and counted if the log thread falls behind, or written to stderr if it died
+    fn send(&self, message: StructuredLogMessage) {
+        match self.tx.try_send(message) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                DROPPED_MESSAGES.fetch_add(1, Ordering::Relaxed);
+            },
+            Err(TrySendError::Disconnected(message)) => fallback(&message),
+        }
+    }
+
+    /// A message stamped with the time, frame and the current thread's open spans
+    fn message(&self, level: structured::LogKind, message: String) -> StructuredLogMessage {
+        StructuredLogMessage {
             time: Logger::time_stamp_now(),
-            level: structured::LogKind::State(item_state),
+            level,
             topic: self.topic.clone(),
-            message: message.into(),
-        };
-
-        self.tx.send(message).expect("unable to send log message");
+            message,
+            frame: frame(),
+            spans: spans(),
+            thread: current_thread(),
+        }
     }
 
     fn time_stamp_now() -> Duration {
@@ -111,28 +175,27 @@ enum LogMessage {
 }
 
 struct LogHandle {
-    tx: Sender<StructuredLogMessage>,
+    tx: SyncSender<StructuredLogMessage>,
     join_handle: Option<JoinHandle<()>>,
 }
 
 pub fn get() -> Logger {
     let tx = {
-        match GLOBAL_LOG.lock() {
-            Ok(mut guard) => {
-                if let Some(ref sink) = *guard {
-                    sink.tx.clone()
-                } else {
-                    let (tx, rx) = mpsc::channel();
-                    let join_handle = thread::spawn(|| structured::log_receiver(rx));
-                    let log_handle = LogHandle { tx: tx.clone(), join_handle: Some(join_handle) };
-                    *guard = Some(log_handle);
-                    set_panic_hook(tx.clone());
-                    tx.clone()
-                }
-            },
-            Err(err) => {
-                panic!("unable to lock log handle: {}", err);
-            },
+        let mut guard = GLOBAL_LOG.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(ref sink) = *guard {
+            sink.tx.clone()
+        } else {
+            let (tx, rx) = mpsc::sync_channel(config().channel_capacity);
+            // If the thread can't be spawned the receiver is dropped and every message falls back to stderr
+            let join_handle = thread::Builder::new()
+                .name(String::from(LOG_THREAD_NAME))
+                .spawn(|| structured::log_receiver(rx))
+                .map_err(|err| eprintln!("unable to spawn log thread: {}", err))
+                .ok();
+            let log_handle = LogHandle { tx: tx.clone(), join_handle };
+            *guard = Some(log_handle);
+            set_panic_hook(tx.clone());
+            tx
         }
     };
     Logger {
@@ -141,21 +204,253 @@ pub fn get() -> Logger {
     }
 }
 
-fn set_panic_hook(tx: Sender<structured::StructuredLogMessage>) {
+/// Where structured logs are written and how many are kept
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogConfig {
+    pub directory: PathBuf,
+    pub file_stem: String,
+    /// The current log is rotated once it grows beyond this many bytes
+    pub max_size: u64,
+    /// The current log is rotated once it has been open this long
+    pub max_age: Option<Duration>,
+    /// Number of rotated logs kept besides the current one
+    pub retention: usize,
+    /// Messages that can be queued for the log thread before new ones are dropped
+    pub channel_capacity: usize,
+    pub format: LogFormat,
+}
+
+/// How log files are encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum LogFormat {
+    /// One JSON document rewritten as messages arrive, readable as is
+    #[default]
+    Json,
+    /// Length prefixed bincode records appended as messages arrive, far cheaper to write for performance captures.
+    /// Read back or converted to JSON with `binary`
+    #[cfg(feature = "binary-log")]
+    Binary,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            directory: default_log_directory(),
+            file_stem: String::from("log"),
+            max_size: 16 * 1024 * 1024,
+            max_age: None,
+            retention: 5,
+            channel_capacity: 4096,
+            format: LogFormat::Json,
+        }
+    }
+}
+
+impl LogConfig {
+    pub fn with_directory<P: Into<PathBuf>>(mut self, directory: P) -> Self {
+        self.directory = directory.into();
+        self
+    }
+
+    pub fn with_max_size(mut self, bytes: u64) -> Self {
+        self.max_size = bytes;
+        self
+    }
+
+    pub fn with_max_age(mut self, age: Duration) -> Self {
+        self.max_age = Some(age);
+        self
+    }
+
+    pub fn with_retention(mut self, count: usize) -> Self {
+        self.retention = count;
+        self
+    }
+
+    pub fn with_channel_capacity(mut self, capacity: usize) -> Self {
+        self.channel_capacity = capacity.max(1);
+        self
+    }
+
+    pub fn with_format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Path of a log generation, 0 is the current log, 1 the most recently rotated and so on
+    pub fn path(&self, generation: usize) -> PathBuf {
+        let extension = self.format.extension();
+        match generation {
+            0 => self.directory.join(format!("{}.{}", self.file_stem, extension)),
+            n => self.directory.join(format!("{}.{}.{}", self.file_stem, n, extension)),
+        }
+    }
+}
+
+impl LogFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            LogFormat::Json => "json",
+            #[cfg(feature = "binary-log")]
+            LogFormat::Binary => "bin",
+        }
+    }
+}
+
+/// How verbose logging is, from nothing at all to everything
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LevelFilter {
+    Off,
+    Error,
+    Warning,
+    Info,
+}
+
+/// Which messages are recorded, checked before they're queued for the log thread. Panics are always recorded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogFilter {
+    pub level: LevelFilter,
+    /// Overrides `level` for messages filed under these topics
+    pub topics: BTreeMap<String, LevelFilter>,
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        LogFilter {
+            level: LevelFilter::Info,
+            topics: BTreeMap::new(),
+        }
+    }
+}
+
+impl crate::config::ConfigSection for LogFilter {
+    const NAME: &'static str = "log";
+}
+
+impl LogFilter {
+    pub fn with_level(mut self, level: LevelFilter) -> Self {
+        self.level = level;
+        self
+    }
+
+    pub fn with_topic(mut self, topic: &str, level: LevelFilter) -> Self {
+        self.topics.insert(String::from(topic), level);
+        self
+    }
+
+    /// Whether a message at `level` filed under `topic` is recorded
+    pub fn allows(&self, level: LevelFilter, topic: &str) -> bool {
+        level != LevelFilter::Off && level <= self.topics.get(topic).copied().unwrap_or(self.level)
+    }
+}
+
+/// `HADRON_LOG_DIR` if set, otherwise the platform's per-user data directory
+pub fn default_log_directory() -> PathBuf {
+    if let Some(dir) = std::env::var_os("HADRON_LOG_DIR") {
+        return PathBuf::from(dir)
+    }
+
+    crate::vfs::platform::data_dir(crate::vfs::APP_NAME).join("logs")
+}
+
+/// Replaces the log configuration. Only takes effect if called before the first logger is created, returns false otherwise
+pub fn configure(config: LogConfig) -> bool {
+    let started = GLOBAL_LOG.lock().map(|guard| guard.is_some()).unwrap_or(true);
+    if !started {
+        *LOG_CONFIG.write().expect("unable to lock log config") = config;
+    }
+    !started
+}
+
+pub fn config() -> LogConfig {
+    LOG_CONFIG.read().expect("unable to lock log config").clone()
+}
+
+/// Path of the current structured log
+pub fn log_path() -> PathBuf {
+    config().path(0)
+}
+
+/// Replaces the log filter, takes effect for every logger straight away
+pub fn set_filter(filter: LogFilter) {
+    *LOG_FILTER.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = filter;
+}
+
+pub fn filter() -> LogFilter {
+    LOG_FILTER.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        // Guards dropped out of order still only close their own span
+        let _ = SPANS.try_with(|spans| spans.borrow_mut().retain(|span| span.id != self.id));
+    }
+}
+
+/// Opens a span on the current thread, see `SpanGuard`
+pub fn span(name: &str) -> SpanGuard {
+    let id = NEXT_SPAN.fetch_add(1, Ordering::Relaxed);
+    let _ = SPANS.try_with(|spans| spans.borrow_mut().push(SpanRecord { id, name: String::from(name) }));
+    SpanGuard { id, _thread: PhantomData }
+}
+
+/// The spans open on the current thread, outermost first
+pub fn spans() -> Vec<SpanRecord> {
+    SPANS.try_with(|spans| spans.borrow().clone()).unwrap_or_default()
+}
+
+/// The id and name messages from the current thread are stamped with
+pub fn current_thread() -> ThreadRecord {
+    THREAD.try_with(ThreadRecord::clone).unwrap_or_default()
+}
+
+/// Stamps messages from every thread with `frame` until the next call. Set by the app as each frame starts
+pub fn set_frame(frame: u64) {
+    FRAME.store(frame.saturating_add(1), Ordering::Relaxed);
+}
+
+/// The frame messages are stamped with, `None` before the first
+pub fn frame() -> Option<u64> {
+    FRAME.load(Ordering::Relaxed).checked_sub(1)
+}
+
+/// Number of messages dropped because the log thread couldn't keep up
+pub fn dropped_messages() -> u64 {
+    DROPPED_MESSAGES.load(Ordering::Relaxed)
+}
+
+/// Last resort output for messages the log thread can't take
+fn fallback(message: &StructuredLogMessage) {
+    eprintln!("[{}] {}: {}", message.level.name(), message.topic, message.message);
+}
+
+fn set_panic_hook(tx: SyncSender<structured::StructuredLogMessage>) {
     let default_panic_hook = std::panic::take_hook();
 
     let tx_panic = Arc::new(Mutex::new(tx));
 
     std::panic::set_hook(Box::new(move |info| {
-        signal_panic(tx_panic.clone(), info);
+        // A panic while saving is only reported by the default hook, the save just gives up
+        if thread::current().name() != Some(crate::debug::crash::AUTOSAVE_THREAD_NAME) {
+            let message = structured::StructuredPanicInfo::payload_message(info);
+            let autosave = crate::debug::crash::autosave(&message);
+            signal_panic(tx_panic.clone(), info, autosave);
+        }
         default_panic_hook(info);
     }));
 }
 
-fn signal_panic(tx: Arc<Mutex<Sender<StructuredLogMessage>>>, panic_info: &PanicInfo) {
+fn signal_panic(tx: Arc<Mutex<SyncSender<StructuredLogMessage>>>, panic_info: &PanicInfo, autosave: Option<PathBuf>) {
     dbg!(panic_info);
 
-    let structured_info = structured::StructuredPanicInfo::from_panic_info(panic_info);
+    // The log thread can't wait on itself, let the default hook report its own panic
+    if thread::current().name() == Some(LOG_THREAD_NAME) {
+        return
+    }
+
+    let structured_info = structured::StructuredPanicInfo::from_panic_info(panic_info, autosave);
     let message = structured_info.message();
     
     let panic_message = StructuredLogMessage {
@@ -163,47 +458,39 @@ fn signal_panic(tx: Arc<Mutex<Sender<StructuredLogMessage>>>, panic_info: &Panic
         level: structured::LogKind::Panic(Arc::new(structured_info)),
         topic: String::from("panic"),
         message: message,
+        frame: frame(),
+        spans: spans(),
+        thread: current_thread(),
     };
 
-    match tx.lock() {
-        Ok(guard) => {
-            match guard.send(panic_message) {
-                Ok(_) => {
-                    join_global_log_handle()
-                },
-                Err(err) => {
-                    panic!("{:?}", err);
-                },
-            }
-        },
-        Err(_) => todo!(),
+    // Block here rather than drop the message, this may be the last chance to record it
+    let guard = tx.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    match guard.send(panic_message) {
+        Ok(_) => join_global_log_handle(),
+        Err(err) => fallback(&err.0),
     }
 }
 
 fn join_global_log_handle() {
-    match GLOBAL_LOG.lock() {
-        Ok(mut guard) => {
-            let log_handle = guard.take().expect("no log handle");
-            let join_handle = log_handle.join_handle.expect("no log thread handle");
-            join_handle.join().expect("unable to join log thread handle");
-        },
-        Err(err) => {
-            panic!("unable to lock global log handle: {}", err);
-        },
+    let handle = GLOBAL_LOG.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+    if let Some(join_handle) = handle.and_then(|h| h.join_handle) {
+        if join_handle.join().is_err() {
+            eprintln!("log thread panicked");
+        }
     }
 }
 
 
 mod structured {
-    use std::{time::Duration, thread::ThreadId, sync::{mpsc::Receiver, Arc}, fs::{File, OpenOptions, self}, path::Path, io::{Write, Read}, panic::PanicInfo, fmt::Debug, any::Any, backtrace::Backtrace};
+    use std::{time::{Duration, Instant}, thread::ThreadId, sync::{mpsc::Receiver, Arc}, fs::{File, OpenOptions, self}, path::{Path, PathBuf}, io::Write, panic::PanicInfo, fmt::Debug, any::Any, backtrace::Backtrace};
     
     use serde::{Serialize, Deserialize};
 
     use crate::unique::UniqueId;
 
-    use super::StructuredItemState;
+    use super::{StructuredItemState, LogConfig, LogFormat};
 
-    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
     pub enum LogKind {
         Error,
         Warning,
@@ -212,27 +499,49 @@ mod structured {
         State(String)
     }
 
+    impl LogKind {
+        pub fn name(&self) -> &'static str {
+            match self {
+                LogKind::Error => "error",
+                LogKind::Warning => "warning",
+                LogKind::Information => "info",
+                LogKind::Panic(_) => "panic",
+                LogKind::State(_) => "state",
+            }
+        }
+    }
+
     #[derive(Serialize, Deserialize, PartialEq, Debug)]
     pub struct StructuredPanicInfo{
         line: u32,
         file: String,
         message: String,
         backtrace: String,
+        #[serde(default)]
+        breadcrumbs: Vec<crate::debug::breadcrumbs::Breadcrumb>,
+        /// Where the world was saved, see `crash`
+        #[serde(default)]
+        autosave: Option<PathBuf>,
     }
 
     impl StructuredPanicInfo {
-        pub fn from_panic_info(panic_info: &PanicInfo) -> Self {
+        pub fn from_panic_info(panic_info: &PanicInfo, autosave: Option<PathBuf>) -> Self {
             let location = panic_info.location();
-            let payload = panic_info.payload();
             let info = StructuredPanicInfo {
                 line: location.map_or_else(|| 0, |l| l.line()),
                 file: location.map_or_else(|| String::from("no file data"), |l| String::from(l.file())),
-                message: payload.downcast_ref::<&str>().map_or_else(|| String::from("no string payload data"), |p| String::from(*p)),
+                message: StructuredPanicInfo::payload_message(panic_info),
                 backtrace: Backtrace::force_capture().to_string(),
+                breadcrumbs: crate::debug::breadcrumbs::snapshot(),
+                autosave,
             };
             info
         }
 
+        pub fn payload_message(panic_info: &PanicInfo) -> String {
+            panic_info.payload().downcast_ref::<&str>().map_or_else(|| String::from("no string payload data"), |p| String::from(*p))
+        }
+
         pub fn message(&self) -> String {
             self.message.clone()
         }
@@ -244,19 +553,43 @@ mod structured {
         pub level: LogKind,
         pub topic: String,
         pub message: String,
+        /// The app frame the message was logged in
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub frame: Option<u64>,
+        /// Spans open on the logging thread, outermost first
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub spans: Vec<SpanRecord>,
+        /// The thread the message was logged from
+        #[serde(default)]
+        pub thread: ThreadRecord,
+    }
+
+    /// A logging thread. Ids are given out from 1 as threads first log and never reused within a run, 0 is unknown
+    #[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq, Debug)]
+    pub struct ThreadRecord {
+        pub id: u64,
+        pub name: Option<String>,
+    }
+
+    /// A span a message was logged in. Ids are unique within a run, so messages with the same span id came from the
+    /// same pass through the span
+    #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+    pub struct SpanRecord {
+        pub id: u64,
+        pub name: String,
     }
 
     #[derive(Serialize, Deserialize, PartialEq, Debug)]
-    struct StructuredLogOutput {
+    pub struct StructuredLogOutput {
         pub index: usize,
         pub message: StructuredLogMessage,
     }
 
     #[derive(Serialize, Deserialize, PartialEq, Debug)]
-    struct LogData {
-        id: UniqueId,
-        timestamp: chrono::DateTime<chrono::Utc>,
-        messages: Vec<StructuredLogOutput>
+    pub struct LogData {
+        pub id: UniqueId,
+        pub timestamp: chrono::DateTime<chrono::Utc>,
+        pub messages: Vec<StructuredLogOutput>
     }
 
     pub fn log_receiver(rx: Receiver<StructuredLogMessage>) {
@@ -265,19 +598,38 @@ mod structured {
         let mut panicking = false;
         let mut next_write = 0usize;
         let skip = 1usize;
-        let path = Path::new("log.json");
+        let config = super::config();
+        let path = config.path(0);
+        let path = path.as_path();
 
-        create_log_file(path);
+        create_log_file(&config);
+        let mut opened = Instant::now();
+        let mut reported_dropped = 0u64;
+        #[cfg(feature = "binary-log")]
+        let mut binary = super::binary::BinaryWriter::default();
 
-        loop {
-            let message = match rx.recv() {
-                Ok(message) => {
-                    message
-                },
-                Err(err) => {
-                    panic!("log receiver error: {}", err)
-                },
-            };
+        // Ends once every logger has been dropped
+        while let Ok(message) = rx.recv() {
+            let dropped = super::dropped_messages();
+            if dropped > reported_dropped {
+                buffer.push(StructuredLogOutput {
+                    index: message_count,
+                    message: StructuredLogMessage {
+                        time: message.time,
+                        level: LogKind::Warning,
+                        topic: String::from("log"),
+                        message: format!("{} log messages dropped", dropped - reported_dropped),
+                        frame: message.frame,
+                        spans: Vec::new(),
+                        thread: super::current_thread(),
+                    },
+                });
+                message_count += 1;
+                reported_dropped = dropped;
+            }
+
+            #[cfg(feature = "telemetry")]
+            crate::debug::telemetry::publish("log", &message);
 
             panicking = match &message.level {
                 LogKind::Panic(_) => {
@@ -295,17 +647,47 @@ mod structured {
             
             message_count += 1;
 
+            // Binary logs are appended to rather than rewritten, so every message is written straight away
+            #[cfg(feature = "binary-log")]
+            if config.format == LogFormat::Binary {
+                binary.append(path, &mut buffer);
+                let too_large = fs::metadata(path).is_ok_and(|m| m.len() > config.max_size);
+                let too_old = config.max_age.is_some_and(|age| opened.elapsed() > age);
+                if !panicking && (too_large || too_old) {
+                    binary = super::binary::BinaryWriter::default();
+                    create_log_file(&config);
+                    opened = Instant::now();
+                }
+                if panicking {
+                    break;
+                }
+                continue;
+            }
+
             // Write to file if we're panicking (we might not get another chance) or we've accumulated enough messages in the buffer
             if panicking || (buffer.len() > next_write)  {
                 
                 next_write += skip;
 
-                let mut data = read_log_data(path);
+                // A log that can't be read back is started over rather than losing the new messages
+                let mut data = read_log_data(path).unwrap_or_else(|err| {
+                    eprintln!("{}", err);
+                    create_log_file(&config);
+                    empty_log_data()
+                });
                 data.messages.append(&mut buffer);
-                
-                write_log_data_truncated(path, data);
-                
-                buffer.clear();
+
+                if let Err(err) = write_log_data_truncated(path, &data) {
+                    eprintln!("{}", err);
+                    data.messages.iter().for_each(|output| super::fallback(&output.message));
+                }
+
+                let too_large = fs::metadata(path).is_ok_and(|m| m.len() > config.max_size);
+                let too_old = config.max_age.is_some_and(|age| opened.elapsed() > age);
+                if !panicking && (too_large || too_old) {
+                    create_log_file(&config);
+                    opened = Instant::now();
+                }
             }
             
             if panicking {
@@ -314,66 +696,633 @@ mod structured {
         }
     }
 
-    fn create_log_file(path: &Path) {
-        let data = LogData {
+    /// Shifts every existing log back one generation, dropping the oldest, then starts a fresh current log
+    fn create_log_file(config: &LogConfig) {
+        if let Err(err) = fs::create_dir_all(&config.directory) {
+            eprintln!("unable to create log directory {}: {}", config.directory.display(), err);
+        }
+
+        rotate(config);
+
+        let created = match config.format {
+            LogFormat::Json => write_log_data_truncated(&config.path(0), &empty_log_data()),
+            #[cfg(feature = "binary-log")]
+            LogFormat::Binary => super::binary::create(&config.path(0), &empty_log_data()),
+        };
+        if let Err(err) = created {
+            eprintln!("{}", err);
+        }
+    }
+
+    pub(super) fn empty_log_data() -> LogData {
+        LogData {
             id: UniqueId::get(),
             timestamp: chrono::Utc::now(),
             messages: Vec::new(),
-        };
-        
-        let old_path = path.with_extension("json.old");
-
-        match std::fs::remove_file(&old_path) {
-            Ok(_) => (),
-            Err(err) => println!("{}", err),
         }
-        match std::fs::rename(path, &old_path) {
-            Ok(_) => (),
-            Err(err) => println!("{}", err),
-        }
-
-        let mut file = File::create(path).expect("unable to create log.json");
-        
-        let buf = serde_json::to_string_pretty(&data).expect("unable to serialize json log header");
+    }
 
-        file.write(buf.as_bytes()).expect("unable to write json log header");        
+    fn rotate(config: &LogConfig) {
+        let _ = fs::remove_file(config.path(config.retention));
+        for generation in (0..config.retention).rev() {
+            let from = config.path(generation);
+            if from.exists() {
+                if let Err(err) = fs::rename(&from, config.path(generation + 1)) {
+                    eprintln!("unable to rotate log {}: {}", from.display(), err);
+                }
+            }
+        }
     }
 
-    fn write_log_data_truncated(path: &Path, data: LogData) {
-        let mut file = File::options()
+    fn write_log_data_truncated(path: &Path, data: &LogData) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(data).map_err(|err| format!("unable to serialize log: {}", err))?;
+        File::options()
             .create(true)
             .write(true)
             .truncate(true)
             .open(path)
-            .expect(format!("unable to open log {:?} for writing", path.display()).as_str());
-        
-        match serde_json::to_string_pretty(&data) {
-            Ok(yaml) => {
-                match file.write(yaml.as_bytes()) {
-                    Ok(_) => (),
-                    Err(err) => panic!("unable to write log master file: {}", err),
+            .and_then(|mut file| file.write_all(json.as_bytes()))
+            .map_err(|err| format!("unable to write log {}: {}", path.display(), err))
+    }
+
+    fn read_log_data(path: &Path) -> Result<LogData, String> {
+        let buf = fs::read_to_string(path).map_err(|err| format!("unable to read log {}: {}", path.display(), err))?;
+        serde_json::from_str(&buf).map_err(|err| format!("unable to deserialize log {}: {}", path.display(), err))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn rotation_keeps_retained_generations() {
+            let dir = std::env::temp_dir().join(format!("hadron_log_rotate_{}", std::process::id()));
+            let config = LogConfig::default().with_directory(&dir).with_retention(2);
+
+            for _ in 0..4 {
+                create_log_file(&config);
+            }
+            assert!(config.path(0).exists());
+            assert!(config.path(1).exists());
+            assert!(config.path(2).exists());
+            assert!(!config.path(3).exists());
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
+
+/// The binary log encoding, enabled with the `binary-log` feature
+///
+/// A binary log starts with `MAGIC`, followed by the bincode encoded header and then one record per message, each
+/// prefixed with its length as a little endian `u32`. Records hold the same fields as the JSON log's messages, so a
+/// binary log converts to exactly the JSON log that would have been written. A record cut short by a crash ends the
+/// log rather than failing it
+#[cfg(feature = "binary-log")]
+pub mod binary {
+    use std::{fs::{self, File}, io::Write, path::Path, time::Duration};
+
+    use serde::{Serialize, Deserialize};
+
+    use crate::unique::UniqueId;
+    use super::{query::{self, QueryError}, structured::{LogData, LogKind, SpanRecord, ThreadRecord, StructuredLogMessage, StructuredLogOutput}};
+
+    pub const MAGIC: &[u8; 8] = b"HDRNLOG\0";
+    /// 2 added the logging thread
+    const VERSION: u32 = 2;
+
+    #[derive(Serialize, Deserialize)]
+    struct Header {
+        version: u32,
+        id: UniqueId,
+        timestamp: chrono::DateTime<chrono::Utc>,
+    }
+
+    /// A message with none of the JSON log's optional fields skipped, bincode needs every field present
+    #[derive(Serialize, Deserialize)]
+    struct Record {
+        index: u64,
+        time: Duration,
+        level: LogKind,
+        topic: String,
+        message: String,
+        frame: Option<u64>,
+        spans: Vec<SpanRecord>,
+        thread: ThreadRecord,
+    }
+
+    /// A record of version 1 logs
+    #[derive(Deserialize)]
+    struct RecordV1 {
+        index: u64,
+        time: Duration,
+        level: LogKind,
+        topic: String,
+        message: String,
+        frame: Option<u64>,
+        spans: Vec<SpanRecord>,
+    }
+
+    /// Appends records to the current log, keeping it open between messages
+    #[derive(Default)]
+    pub(super) struct BinaryWriter {
+        file: Option<File>,
+    }
+
+    // Impls
+
+    impl From<RecordV1> for Record {
+        fn from(record: RecordV1) -> Self {
+            Record { index: record.index, time: record.time, level: record.level, topic: record.topic, message: record.message, frame: record.frame, spans: record.spans, thread: ThreadRecord::default() }
+        }
+    }
+
+    impl BinaryWriter {
+        /// Writes out and clears `buffer`. Messages that can't be written go to stderr
+        pub(super) fn append(&mut self, path: &Path, buffer: &mut Vec<StructuredLogOutput>) {
+            for output in buffer.drain(..) {
+                if let Err(err) = self.write(path, &output) {
+                    eprintln!("unable to write log {}: {}", path.display(), err);
+                    super::fallback(&output.message);
+                    self.file = None;
                 }
-            },
-            Err(err) => panic!("unable to serialize log master buffer: {}", err),
+            }
+        }
+
+        fn write(&mut self, path: &Path, output: &StructuredLogOutput) -> Result<(), String> {
+            if self.file.is_none() {
+                self.file = Some(File::options().append(true).open(path).map_err(|err| err.to_string())?);
+            }
+            let file = self.file.as_mut().expect("just opened");
+            let message = &output.message;
+            let record = Record {
+                index: output.index as u64,
+                time: message.time,
+                level: message.level.clone(),
+                topic: message.topic.clone(),
+                message: message.message.clone(),
+                frame: message.frame,
+                spans: message.spans.clone(),
+                thread: message.thread.clone(),
+            };
+            let bytes = bincode::serialize(&record).map_err(|err| err.to_string())?;
+            let length = u32::try_from(bytes.len()).map_err(|_| String::from("message too large"))?;
+            let mut framed = Vec::with_capacity(bytes.len() + 4);
+            framed.extend(length.to_le_bytes());
+            framed.extend(bytes);
+            file.write_all(&framed).map_err(|err| err.to_string())
         }
     }
 
-    fn read_log_data(path: &Path) -> LogData {
-        let mut file = File::options().read(true).open(path).expect("unable to open log.json");
-        
-        let mut buf = String::new();
-        match file.read_to_string(&mut buf) {
-            Ok(_length) => {
-                match serde_json::from_str(&buf.as_str()) {
-                    Ok(deserialized) => {
-                        return deserialized
+    /// Starts a binary log at `path` with `data`'s id and timestamp
+    pub(super) fn create(path: &Path, data: &LogData) -> Result<(), String> {
+        let header = bincode::serialize(&Header { version: VERSION, id: data.id, timestamp: data.timestamp }).map_err(|err| format!("unable to serialize log header: {}", err))?;
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(header);
+        fs::write(path, bytes).map_err(|err| format!("unable to write log {}: {}", path.display(), err))
+    }
+
+    pub fn is_binary(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    /// Decodes a binary log into the messages a JSON log holds
+    pub(super) fn decode(bytes: &[u8]) -> Result<LogData, String> {
+        let mut rest = bytes.strip_prefix(MAGIC.as_slice()).ok_or_else(|| String::from("not a binary log"))?;
+        let mut cursor = std::io::Cursor::new(rest);
+        let header: Header = bincode::deserialize_from(&mut cursor).map_err(|err| format!("invalid header: {}", err))?;
+        if header.version > VERSION {
+            return Err(format!("written by a newer version of the format, {}", header.version))
+        }
+        rest = &rest[cursor.position() as usize..];
+
+        let mut data = LogData { id: header.id, timestamp: header.timestamp, messages: Vec::new() };
+        while rest.len() >= 4 {
+            let length = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            let Some(bytes) = rest.get(4..4 + length) else {
+                break
+            };
+            let record = match header.version {
+                1 => bincode::deserialize::<RecordV1>(bytes).map(Record::from),
+                _ => bincode::deserialize::<Record>(bytes),
+            };
+            let record = record.map_err(|err| format!("invalid record {}: {}", data.messages.len(), err))?;
+            data.messages.push(StructuredLogOutput {
+                index: record.index as usize,
+                message: StructuredLogMessage { time: record.time, level: record.level, topic: record.topic, message: record.message, frame: record.frame, spans: record.spans, thread: record.thread },
+            });
+            rest = &rest[4 + length..];
+        }
+        Ok(data)
+    }
+
+    /// The log at `path` as the JSON a JSON log would hold. JSON logs are returned reformatted
+    pub fn to_json(path: &Path) -> Result<String, QueryError> {
+        let data = query::read(path)?;
+        serde_json::to_string_pretty(&data).map_err(|err| QueryError::Parse(path.to_path_buf(), err.to_string()))
+    }
+
+    /// Converts the log at `from` to a JSON log at `to`
+    pub fn convert(from: &Path, to: &Path) -> Result<(), QueryError> {
+        fs::write(to, to_json(from)?).map_err(|err| QueryError::Io(to.to_path_buf(), err))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use super::super::query::LogQuery;
+
+        #[test]
+        fn binary_logs_convert_to_the_json_schema() {
+            let dir = std::env::temp_dir().join(format!("hadron_log_binary_{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("log.bin");
+            let data = super::super::structured::empty_log_data();
+            create(&path, &data).unwrap();
+
+            let message = |index: usize, level| StructuredLogOutput {
+                index,
+                message: StructuredLogMessage { time: Duration::from_secs(index as u64), level, topic: String::from("render"), message: format!("message {}", index), frame: Some(7), spans: vec![SpanRecord { id: 3, name: String::from("draw") }], thread: ThreadRecord { id: 2, name: Some(String::from("render")) } },
+            };
+            let mut writer = BinaryWriter::default();
+            writer.append(&path, &mut vec![message(0, LogKind::Information), message(1, LogKind::State(String::from("{}")))]);
+            writer.append(&path, &mut vec![message(2, LogKind::Error)]);
+            // A record cut short by a crash is dropped
+            File::options().append(true).open(&path).unwrap().write_all(&[200, 0, 0, 0, 1]).unwrap();
+
+            let decoded = decode(&fs::read(&path).unwrap()).unwrap();
+            assert_eq!((decoded.id, decoded.messages.len()), (data.id, 3));
+            assert_eq!(decoded.messages[2], message(2, LogKind::Error));
+
+            let errors = LogQuery::new().span("draw").frame(7).thread("render").containing("message 2").run_on(&path).unwrap();
+            assert_eq!(errors.len(), 1);
+            let json: serde_json::Value = serde_json::from_str(&to_json(&path).unwrap()).unwrap();
+            assert_eq!(json["messages"][1]["message"]["frame"], 7);
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
+
+/// Reading and filtering structured logs written by previous or current runs
+pub mod query {
+    use std::{path::{Path, PathBuf}, time::{Duration, SystemTime}, str::FromStr, fs};
+
+    use super::structured::{LogData, LogKind, SpanRecord, ThreadRecord};
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum Level {
+        Error,
+        Warning,
+        Information,
+        Panic,
+        State,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct LogEntry {
+        pub index: usize,
+        /// Time since the unix epoch
+        pub time: Duration,
+        pub level: Level,
+        pub topic: String,
+        pub message: String,
+        pub frame: Option<u64>,
+        /// Spans the entry was logged in, outermost first
+        pub spans: Vec<SpanRecord>,
+        pub thread: ThreadRecord,
+    }
+
+    #[derive(Debug)]
+    pub enum QueryError {
+        Io(PathBuf, std::io::Error),
+        Parse(PathBuf, String),
+        Syntax(String),
+    }
+
+    /// A filter over log entries. Every set criterion must match
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct LogQuery {
+        levels: Vec<Level>,
+        topic: Option<String>,
+        since: Option<Duration>,
+        until: Option<Duration>,
+        containing: Option<String>,
+        frame: Option<u64>,
+        span: Option<String>,
+        thread: Option<String>,
+        old: bool,
+    }
+
+    // Impls
+
+    impl std::error::Error for QueryError {}
+
+    impl std::fmt::Display for QueryError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                QueryError::Io(path, err) => write!(f, "unable to read log {}: {}", path.display(), err),
+                QueryError::Parse(path, err) => write!(f, "unable to parse log {}: {}", path.display(), err),
+                QueryError::Syntax(err) => write!(f, "invalid log query: {}", err),
+            }
+        }
+    }
+
+    impl From<&LogKind> for Level {
+        fn from(kind: &LogKind) -> Self {
+            match kind {
+                LogKind::Error => Level::Error,
+                LogKind::Warning => Level::Warning,
+                LogKind::Information => Level::Information,
+                LogKind::Panic(_) => Level::Panic,
+                LogKind::State(_) => Level::State,
+            }
+        }
+    }
+
+    impl std::fmt::Display for LogEntry {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "[{}] {:?} {}: {}", self.index, self.level, self.topic, self.message)
+        }
+    }
+
+    impl LogQuery {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Matches entries of `level`, may be given more than once
+        pub fn level(mut self, level: Level) -> Self {
+            self.levels.push(level);
+            self
+        }
+
+        pub fn topic(mut self, topic: &str) -> Self {
+            self.topic = Some(String::from(topic));
+            self
+        }
+
+        pub fn since(mut self, time: SystemTime) -> Self {
+            self.since = time.duration_since(SystemTime::UNIX_EPOCH).ok();
+            self
+        }
+
+        pub fn until(mut self, time: SystemTime) -> Self {
+            self.until = time.duration_since(SystemTime::UNIX_EPOCH).ok();
+            self
+        }
+
+        /// Matches entries logged within `duration` of now. Fails if that reaches back further than the clock goes
+        pub fn last(self, duration: Duration) -> Result<Self, QueryError> {
+            let since = SystemTime::now().checked_sub(duration).ok_or_else(|| QueryError::Syntax(format!("duration {:?} is too long", duration)))?;
+            Ok(self.since(since))
+        }
+
+        pub fn containing(mut self, text: &str) -> Self {
+            self.containing = Some(String::from(text));
+            self
+        }
+
+        /// Matches entries logged during `frame`
+        pub fn frame(mut self, frame: u64) -> Self {
+            self.frame = Some(frame);
+            self
+        }
+
+        /// Matches entries logged inside a span named `name`, at any depth
+        pub fn span(mut self, name: &str) -> Self {
+            self.span = Some(String::from(name));
+            self
+        }
+
+        /// Matches entries logged from a thread named `name`
+        pub fn thread(mut self, name: &str) -> Self {
+            self.thread = Some(String::from(name));
+            self
+        }
+
+        /// Reads the most recently rotated log instead of the current one
+        pub fn old(mut self) -> Self {
+            self.old = true;
+            self
+        }
+
+        pub fn matches(&self, entry: &LogEntry) -> bool {
+            (self.levels.is_empty() || self.levels.contains(&entry.level))
+                && self.topic.as_ref().is_none_or(|topic| *topic == entry.topic)
+                && self.since.is_none_or(|since| entry.time >= since)
+                && self.until.is_none_or(|until| entry.time <= until)
+                && self.containing.as_ref().is_none_or(|text| entry.message.contains(text.as_str()))
+                && self.frame.is_none_or(|frame| entry.frame == Some(frame))
+                && self.span.as_ref().is_none_or(|name| entry.spans.iter().any(|span| span.name == *name))
+                && self.thread.as_ref().is_none_or(|name| entry.thread.name.as_ref() == Some(name))
+        }
+
+        /// Runs the query against the current (or old) log file
+        pub fn run(&self) -> Result<Vec<LogEntry>, QueryError> {
+            let generation = if self.old { 1 } else { 0 };
+            self.run_on(&super::config().path(generation))
+        }
+
+        /// Runs the query against a log file, JSON or binary
+        pub fn run_on(&self, path: &Path) -> Result<Vec<LogEntry>, QueryError> {
+            let data = read(path)?;
+
+            Ok(data.messages.into_iter()
+                .map(|output| LogEntry {
+                    index: output.index,
+                    time: output.message.time,
+                    level: Level::from(&output.message.level),
+                    topic: output.message.topic,
+                    message: output.message.message,
+                    frame: output.message.frame,
+                    spans: output.message.spans,
+                    thread: output.message.thread,
+                })
+                .filter(|entry| self.matches(entry))
+                .collect())
+        }
+    }
+
+    /// Parses queries such as `errors warnings last 60s topic render containing swapchain` or
+    /// `frame 120 thread streaming span physics`
+    ///
+    /// Levels are `errors`, `warnings`, `info`, `panics` and `state`. Durations take an `s`, `m` or `h` suffix.
+    /// `containing` consumes the rest of the query
+    impl FromStr for LogQuery {
+        type Err = QueryError;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let mut query = LogQuery::new();
+            let mut words = s.split_whitespace();
+
+            while let Some(word) = words.next() {
+                query = match word {
+                    "errors" | "error" => query.level(Level::Error),
+                    "warnings" | "warning" => query.level(Level::Warning),
+                    "info" => query.level(Level::Information),
+                    "panics" | "panic" => query.level(Level::Panic),
+                    "state" => query.level(Level::State),
+                    "old" => query.old(),
+                    "last" => {
+                        let duration = words.next().ok_or_else(|| QueryError::Syntax(String::from("expected a duration after last")))?;
+                        query.last(parse_duration(duration)?)?
                     },
-                    Err(err) => {
-                        panic!("unable to deserialize log master: {}", err)
+                    "topic" => {
+                        let topic = words.next().ok_or_else(|| QueryError::Syntax(String::from("expected a topic")))?;
+                        query.topic(topic)
                     },
-                }
-            },
-            Err(err) => panic!("unable to read log master: {}", err),
+                    "frame" => {
+                        let frame = words.next().and_then(|frame| frame.parse().ok()).ok_or_else(|| QueryError::Syntax(String::from("expected a frame number")))?;
+                        query.frame(frame)
+                    },
+                    "thread" => {
+                        let thread = words.next().ok_or_else(|| QueryError::Syntax(String::from("expected a thread name")))?;
+                        query.thread(thread)
+                    },
+                    "span" => {
+                        let span = words.next().ok_or_else(|| QueryError::Syntax(String::from("expected a span name")))?;
+                        query.span(span)
+                    },
+                    "containing" => {
+                        let text = words.by_ref().collect::<Vec<_>>().join(" ");
+                        query.containing(&text)
+                    },
+                    other => return Err(QueryError::Syntax(format!("unexpected {}", other))),
+                };
+            }
+
+            Ok(query)
+        }
+    }
+
+    pub(super) fn read(path: &Path) -> Result<LogData, QueryError> {
+        let buf = fs::read(path).map_err(|err| QueryError::Io(path.to_path_buf(), err))?;
+        #[cfg(feature = "binary-log")]
+        if super::binary::is_binary(&buf) {
+            return super::binary::decode(&buf).map_err(|err| QueryError::Parse(path.to_path_buf(), err))
+        }
+        serde_json::from_slice(&buf).map_err(|err| QueryError::Parse(path.to_path_buf(), err.to_string()))
+    }
+
+    fn parse_duration(s: &str) -> Result<Duration, QueryError> {
+        let invalid = || QueryError::Syntax(format!("invalid duration {}", s));
+        let (value, scale) = match s.char_indices().last() {
+            Some((i, 's')) => (&s[..i], 1),
+            Some((i, 'm')) => (&s[..i], 60),
+            Some((i, 'h')) => (&s[..i], 3600),
+            _ => (s, 1),
+        };
+        let value: u64 = value.parse().map_err(|_| invalid())?;
+        Ok(Duration::from_secs(value.checked_mul(scale).ok_or_else(invalid)?))
+    }
+
+    /// Console binding, runs a query like `log errors last 60s` and returns one formatted line per entry
+    pub fn command(line: &str) -> Result<Vec<String>, QueryError> {
+        let line = line.trim();
+        let line = line.strip_prefix("log").unwrap_or(line);
+        let query: LogQuery = line.parse()?;
+        Ok(query.run()?.iter().map(|entry| entry.to_string()).collect())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::{unique::UniqueId, debug::log::structured::{StructuredLogOutput, StructuredLogMessage}};
+
+        #[test]
+        fn parse_query() {
+            let query: LogQuery = "errors warnings topic render containing lost device".parse().unwrap();
+            assert_eq!(query, LogQuery::new().level(Level::Error).level(Level::Warning).topic("render").containing("lost device"));
+            assert!("last soon".parse::<LogQuery>().is_err());
+            assert!(matches!("last 99999999999999999h".parse::<LogQuery>(), Err(QueryError::Syntax(_))));
+            assert!(matches!("last 18000000000000000000s".parse::<LogQuery>(), Err(QueryError::Syntax(_))));
         }
+
+        #[test]
+        fn filter_log_file() {
+            let message = |index, level, topic: &str, time| StructuredLogOutput {
+                index,
+                message: StructuredLogMessage {
+                    time: Duration::from_secs(time),
+                    level,
+                    topic: String::from(topic),
+                    message: format!("message {}", index),
+                    frame: Some(index as u64),
+                    spans: vec![SpanRecord { id: 1, name: String::from(topic) }],
+                    thread: ThreadRecord::default(),
+                },
+            };
+            let data = LogData {
+                id: UniqueId::get(),
+                timestamp: chrono::Utc::now(),
+                messages: vec![
+                    message(0, LogKind::Information, "general", 100),
+                    message(1, LogKind::Error, "render", 200),
+                    message(2, LogKind::Error, "general", 300),
+                ],
+            };
+            let path = std::env::temp_dir().join(format!("hadron_log_query_{}.json", std::process::id()));
+            fs::write(&path, serde_json::to_string(&data).unwrap()).unwrap();
+
+            let errors = LogQuery::new().level(Level::Error).run_on(&path).unwrap();
+            assert_eq!(errors.iter().map(|e| e.index).collect::<Vec<_>>(), vec![1, 2]);
+
+            let late_general = LogQuery::new().topic("general").since(SystemTime::UNIX_EPOCH + Duration::from_secs(150)).run_on(&path).unwrap();
+            assert_eq!(late_general.iter().map(|e| e.index).collect::<Vec<_>>(), vec![2]);
+
+            let render_frame = "frame 1 span render".parse::<LogQuery>().unwrap().run_on(&path).unwrap();
+            assert_eq!(render_frame.iter().map(|e| e.index).collect::<Vec<_>>(), vec![1]);
+
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_never_block_or_panic() {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let logger = Logger { tx, topic: String::from("test") };
+        let dropped = dropped_messages();
+
+        logger.info("queued");
+        logger.info("dropped");
+        assert!(dropped_messages() > dropped);
+
+        drop(rx);
+        logger.error("written to stderr");
+    }
+
+    #[test]
+    fn messages_carry_their_spans() {
+        let (tx, rx) = mpsc::sync_channel(4);
+        let logger = Logger { tx, topic: String::from("test") };
+        let outer = logger.span("stage");
+        {
+            let _inner = span("system");
+            logger.info("nested");
+        }
+        logger.info("outer only");
+        drop(outer);
+        logger.info("no spans");
+
+        let spans: Vec<Vec<String>> = rx.try_iter().map(|message| message.spans.into_iter().map(|span| span.name).collect()).collect();
+        assert_eq!(spans, vec![vec![String::from("stage"), String::from("system")], vec![String::from("stage")], vec![]]);
+    }
+
+    #[test]
+    fn messages_name_their_thread() {
+        let (tx, rx) = mpsc::sync_channel(4);
+        let logger = Logger { tx: tx.clone(), topic: String::from("test") };
+        logger.info("here");
+        thread::Builder::new().name(String::from("streaming")).spawn(move || Logger { tx, topic: String::from("test") }.info("there")).unwrap().join().unwrap();
+
+        let threads: Vec<ThreadRecord> = rx.try_iter().map(|message| message.thread).collect();
+        assert_eq!(threads[1].name.as_deref(), Some("streaming"));
+        assert!(threads[0].id != 0 && threads[0].id != threads[1].id);
     }
 }
@@ -1,56 +1,183 @@
 pub mod log;
+pub mod breadcrumbs;
+pub mod benchmark;
+pub mod snapshot;
+pub mod crash;
+pub mod frame_step;
+pub mod inspector;
+pub mod latency;
+pub mod event_log;
+pub mod profiler;
+#[cfg(feature = "telemetry")]
+pub mod telemetry;
 
 
 
-use std::{alloc::{GlobalAlloc, Layout}, sync::atomic::{Ordering, AtomicU64}};
+use std::{alloc::{GlobalAlloc, Layout}, cell::Cell, sync::atomic::{Ordering, AtomicI64, AtomicUsize}};
 
-/// Overrides the global allocator with a memory tracking wrapper. Should be disabled in optimized builds
-#[cfg(debug_assertions)]
+/// Overrides the global allocator with a memory tracking wrapper. Enabled with the `memory-tracking` feature
+#[cfg(feature = "memory-tracking")]
 #[global_allocator]
 static GLOBAL_ALLOCATOR: TrackingAllocator<std::alloc::System> = TrackingAllocator::new(std::alloc::System);
 
-pub struct TrackingAllocator<A: GlobalAlloc>(pub A, AtomicU64);
+const SHARDS: usize = 16;
+
+/// Per-shard counters, padded to a cache line so threads on different shards don't contend
+#[repr(align(64))]
+struct Shard {
+    bytes: AtomicI64,
+    allocations: AtomicI64,
+}
+
+/// Counts live bytes and allocations. Counters are sharded per thread and only summed when read, so the
+/// overhead is low enough for optimized builds
+pub struct TrackingAllocator<A: GlobalAlloc> {
+    pub inner: A,
+    shards: [Shard; SHARDS],
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllocationStats {
+    pub bytes: u64,
+    pub allocations: u64,
+}
 
 unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
     unsafe fn alloc(&self, l: Layout) -> *mut u8 {
-        self.1.fetch_add(l.size() as u64, Ordering::SeqCst);
-        self.0.alloc(l)
+        let ptr = self.inner.alloc(l);
+        if !ptr.is_null() {
+            self.record(l.size() as i64, 1);
+        }
+        ptr
     }
+
+    unsafe fn alloc_zeroed(&self, l: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(l);
+        if !ptr.is_null() {
+            self.record(l.size() as i64, 1);
+        }
+        ptr
+    }
+
     unsafe fn dealloc(&self, ptr: *mut u8, l: Layout) {
-        self.0.dealloc(ptr, l);
-        self.1.fetch_sub(l.size() as u64, Ordering::SeqCst);
+        self.inner.dealloc(ptr, l);
+        self.record(-(l.size() as i64), -1);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, l: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.inner.realloc(ptr, l, new_size);
+        if !new_ptr.is_null() {
+            self.record(new_size as i64 - l.size() as i64, 0);
+        }
+        new_ptr
+    }
+}
+
+impl Shard {
+    const fn new() -> Self {
+        Shard { bytes: AtomicI64::new(0), allocations: AtomicI64::new(0) }
     }
 }
 
 impl<A: GlobalAlloc> TrackingAllocator<A> {
     pub const fn new(a: A) -> Self {
-        TrackingAllocator(a, AtomicU64::new(0))
+        #[allow(clippy::declare_interior_mutable_const)]
+        const SHARD: Shard = Shard::new();
+        TrackingAllocator { inner: a, shards: [SHARD; SHARDS] }
     }
 
     pub fn reset_tracking(&self) {
-        self.1.store(0, Ordering::SeqCst);
+        for shard in &self.shards {
+            shard.bytes.store(0, Ordering::Relaxed);
+            shard.allocations.store(0, Ordering::Relaxed);
+        }
     }
+
+    /// Live bytes, summed over every shard
     pub fn get_stats(&self) -> u64 {
-        self.1.load(Ordering::SeqCst)
+        self.stats().bytes
+    }
+
+    pub fn stats(&self) -> AllocationStats {
+        let (bytes, allocations) = self.shards.iter().fold((0i64, 0i64), |(bytes, allocations), shard| {
+            (bytes + shard.bytes.load(Ordering::Relaxed), allocations + shard.allocations.load(Ordering::Relaxed))
+        });
+        AllocationStats { bytes: bytes.max(0) as u64, allocations: allocations.max(0) as u64 }
+    }
+
+    #[inline(always)]
+    fn record(&self, bytes: i64, allocations: i64) {
+        let shard = &self.shards[shard_index()];
+        shard.bytes.fetch_add(bytes, Ordering::Relaxed);
+        shard.allocations.fetch_add(allocations, Ordering::Relaxed);
     }
 }
 
-/// Prints the current memory use to stdout, compiles to NOP in release builds
+/// Source of shard indices, each thread takes the next one the first time it allocates
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+/// The calling thread's shard, handed out round robin so threads spread evenly across shards. Cached in a const
+/// initialized thread local, so it never allocates
+#[inline(always)]
+fn shard_index() -> usize {
+    thread_local!(static SHARD: Cell<usize> = const { Cell::new(usize::MAX) });
+    SHARD.try_with(|shard| {
+        if shard.get() == usize::MAX {
+            shard.set(NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % SHARDS);
+        }
+        shard.get()
+    }).unwrap_or(0)
+}
+
+/// Prints the current memory use to stdout, compiles to NOP without the `memory-tracking` feature
 pub fn print_global_alloc_mem_use() {
-    #[cfg(debug_assertions)]
+    #[cfg(feature = "memory-tracking")]
     {
         let mem_used = GLOBAL_ALLOCATOR.get_stats() as f64;
         println!("mem: {:.2} MB", mem_used / 1024f64 / 1024f64);
     }
 }
 
+/// Bytes currently allocated through the tracking allocator, `None` when it isn't installed
+pub fn allocated_bytes() -> Option<u64> {
+    #[cfg(feature = "memory-tracking")]
+    {
+        Some(GLOBAL_ALLOCATOR.get_stats())
+    }
+    #[cfg(not(feature = "memory-tracking"))]
+    {
+        None
+    }
+}
+
+/// Live bytes and allocation count of the global tracking allocator, `None` when it isn't installed
+pub fn allocation_stats() -> Option<AllocationStats> {
+    #[cfg(feature = "memory-tracking")]
+    {
+        Some(GLOBAL_ALLOCATOR.stats())
+    }
+    #[cfg(not(feature = "memory-tracking"))]
+    {
+        None
+    }
+}
+
+/// Traps into an attached debugger. Without one the process is terminated, so only call this when asked to
+#[inline(always)]
+pub fn debug_break() {
+    #[cfg(target_arch = "x86_64")]
+    unsafe { std::arch::asm!("int3") };
+    #[cfg(target_arch = "aarch64")]
+    unsafe { std::arch::asm!("brk #0xf000") };
+}
+
 #[inline(always)]
 pub fn dump_backtrace() {
     #[cfg(debug_assertions)]
     {
         let backtrace = std::backtrace::Backtrace::force_capture();
         dbg!(&backtrace);
-        std::fs::write("backtrace.txt", format!("{}", backtrace)).expect("Failed to write backtrace.txt");
+        crate::vfs::get().write("/logs/backtrace.txt", format!("{}", backtrace).as_bytes()).expect("Failed to write backtrace.txt");
     }
 }
 
@@ -66,10 +193,23 @@ mod tests {
         dump_backtrace()
     }
 
-    #[cfg(debug_assertions)]
+    #[cfg(feature = "memory-tracking")]
     #[test]
     #[ignore]
     fn test_print_global_alloc_mem_use() {
         print_global_alloc_mem_use()
     }
+
+    #[test]
+    fn tracking_allocator_counts() {
+        let allocator = TrackingAllocator::new(std::alloc::System);
+        let layout = Layout::from_size_align(128, 8).unwrap();
+        unsafe {
+            let ptr = allocator.alloc(layout);
+            let ptr = allocator.realloc(ptr, layout, 256);
+            assert_eq!(allocator.stats(), AllocationStats { bytes: 256, allocations: 1 });
+            allocator.dealloc(ptr, Layout::from_size_align(256, 8).unwrap());
+        }
+        assert_eq!(allocator.stats(), AllocationStats::default());
+    }
 }
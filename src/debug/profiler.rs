@@ -0,0 +1,238 @@
+//!
+//! Stage timings and frame time budgets
+//!
+//! While the world has a `FrameProfile`, the schedule times every stage and the systems in it, and the app times
+//! rendering as the `render` stage. At the end of each frame the timings are checked against `profiler.budgets`, stage
+//! names mapped to milliseconds, e.g. `{ "fixed_update": 4.0, "render": 8.0 }`. A stage over its budget is logged as a
+//! warning with the systems that took longest, followed by a `BudgetReport` as structured state, and sent as a
+//! `BudgetExceeded` event. A stage that stays over budget is reported again at most every `report_interval_ms`, with
+//! the number of frames it went over in between
+//!
+
+use std::{collections::BTreeMap, time::{Duration, Instant}};
+
+use serde::{Serialize, Deserialize};
+
+use crate::config::{self, ConfigSection};
+use crate::system::{world::World, event};
+use super::log;
+
+/// Stage name rendering is timed under
+pub const RENDER_STAGE: &str = "render";
+
+/// Systems named in a budget report, the slowest first
+const REPORTED_SYSTEMS: usize = 5;
+
+/// Profiler options from the `profiler` config section
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ProfilerConfig {
+    /// Checks stage timings against their budgets, they're still collected without
+    pub enabled: bool,
+    /// Milliseconds each stage may take per frame, stages without one aren't checked
+    pub budgets: BTreeMap<String, f64>,
+    /// Least time between two reports for the same stage
+    pub report_interval_ms: u64,
+}
+
+/// Sent when a stage goes over its budget
+#[derive(Debug, Clone, PartialEq)]
+pub struct BudgetExceeded {
+    pub stage: String,
+    pub budget: Duration,
+    pub actual: Duration,
+    /// The stage's slowest systems with their time this frame
+    pub systems: Vec<(String, Duration)>,
+    /// Frames over budget since the stage was last reported, including this one
+    pub frames: u32,
+}
+
+/// A stage's timings over one frame
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StageTiming {
+    pub total: Duration,
+    pub systems: BTreeMap<String, Duration>,
+}
+
+/// World resource collecting the current frame's stage timings
+#[derive(Debug, Clone, Default)]
+pub struct FrameProfile {
+    stages: BTreeMap<String, StageTiming>,
+    /// The last frame's timings, once the frame has ended
+    last: BTreeMap<String, StageTiming>,
+    reports: BTreeMap<String, ReportState>,
+}
+
+/// How a budget exceedance is written to the log
+#[derive(Serialize, Debug)]
+struct BudgetReport<'a> {
+    stage: &'a str,
+    budget_ms: f64,
+    actual_ms: f64,
+    frames: u32,
+    systems: Vec<(&'a str, f64)>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ReportState {
+    last_reported: Option<Instant>,
+    /// Frames over budget since `last_reported`
+    pending: u32,
+}
+
+// Impls
+
+impl Default for ProfilerConfig {
+    fn default() -> Self {
+        ProfilerConfig { enabled: true, budgets: BTreeMap::new(), report_interval_ms: 1000 }
+    }
+}
+
+impl ConfigSection for ProfilerConfig {
+    const NAME: &'static str = "profiler";
+}
+
+impl ProfilerConfig {
+    pub fn with_budget(mut self, stage: &str, milliseconds: f64) -> Self {
+        self.budgets.insert(String::from(stage), milliseconds);
+        self
+    }
+
+    pub fn budget(&self, stage: &str) -> Option<Duration> {
+        self.budgets.get(stage).filter(|ms| ms.is_finite() && **ms >= 0.0).map(|ms| Duration::from_secs_f64(ms / 1000.0))
+    }
+}
+
+impl std::fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} took {:.2}ms of its {:.2}ms budget", self.stage, self.actual.as_secs_f64() * 1000.0, self.budget.as_secs_f64() * 1000.0)?;
+        if self.frames > 1 {
+            write!(f, ", over budget {} frames", self.frames)?;
+        }
+        for (index, (system, time)) in self.systems.iter().enumerate() {
+            write!(f, "{} {} {:.2}ms", if index == 0 { ", slowest" } else { "," }, system, time.as_secs_f64() * 1000.0)?;
+        }
+        Ok(())
+    }
+}
+
+impl StageTiming {
+    /// The slowest `count` systems, slowest first
+    pub fn slowest(&self, count: usize) -> Vec<(String, Duration)> {
+        let mut systems: Vec<(String, Duration)> = self.systems.iter().map(|(name, time)| (name.clone(), *time)).collect();
+        systems.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        systems.truncate(count);
+        systems
+    }
+}
+
+impl FrameProfile {
+    /// Adds a run of `stage` to the frame, a fixed stage can run more than once
+    pub fn record_stage(&mut self, stage: &str, total: Duration, systems: &[(&str, Duration)]) {
+        if !self.stages.contains_key(stage) {
+            self.stages.insert(String::from(stage), StageTiming::default());
+        }
+        let timing = self.stages.get_mut(stage).expect("just inserted");
+        timing.total += total;
+        for (system, time) in systems {
+            match timing.systems.get_mut(*system) {
+                Some(accumulated) => *accumulated += *time,
+                None => { timing.systems.insert(String::from(*system), *time); },
+            }
+        }
+    }
+
+    /// The current frame's timing of `stage` so far
+    pub fn stage(&self, stage: &str) -> Option<&StageTiming> {
+        self.stages.get(stage)
+    }
+
+    /// The last finished frame's timings
+    pub fn last_frame(&self) -> &BTreeMap<String, StageTiming> {
+        &self.last
+    }
+
+    /// Ends the frame, returning the stages over budget that are due to be reported at `now`
+    pub fn end_frame(&mut self, config: &ProfilerConfig, now: Instant) -> Vec<BudgetExceeded> {
+        let interval = Duration::from_millis(config.report_interval_ms);
+        let mut exceeded = Vec::new();
+        for (stage, timing) in &self.stages {
+            let Some(budget) = config.budget(stage).filter(|budget| timing.total > *budget) else {
+                continue
+            };
+            let report = self.reports.entry(stage.clone()).or_default();
+            report.pending += 1;
+            if report.last_reported.is_some_and(|last| now.duration_since(last) < interval) {
+                continue
+            }
+            exceeded.push(BudgetExceeded { stage: stage.clone(), budget, actual: timing.total, systems: timing.slowest(REPORTED_SYSTEMS), frames: report.pending });
+            *report = ReportState { last_reported: Some(now), pending: 0 };
+        }
+        self.last = std::mem::take(&mut self.stages);
+        exceeded
+    }
+}
+
+/// Whether stages should be timed this frame
+pub fn is_profiling(world: &World) -> bool {
+    world.contains_resource::<FrameProfile>()
+}
+
+/// Adds a run of `stage` to the world's profile, if it has one
+pub fn record_stage(world: &World, stage: &str, total: Duration, systems: &[(&str, Duration)]) {
+    world.with_resource_mut::<FrameProfile, _>(|profile| profile.record_stage(stage, total, systems));
+}
+
+/// Checks the frame's stage timings against their budgets, reporting the stages over. Run by the app after rendering
+pub fn end_frame(world: &World) {
+    let config: ProfilerConfig = config::get().section();
+    let Some(exceeded) = world.with_resource_mut::<FrameProfile, _>(|profile| profile.end_frame(&config, Instant::now())) else {
+        return
+    };
+    if !config.enabled {
+        return
+    }
+
+    for exceeded in exceeded {
+        log::get().with_topic("budget").warn(exceeded.to_string());
+        let report = BudgetReport {
+            stage: &exceeded.stage,
+            budget_ms: exceeded.budget.as_secs_f64() * 1000.0,
+            actual_ms: exceeded.actual.as_secs_f64() * 1000.0,
+            frames: exceeded.frames,
+            systems: exceeded.systems.iter().map(|(system, time)| (system.as_str(), time.as_secs_f64() * 1000.0)).collect(),
+        };
+        log::get().with_topic("budget").state("budget exceeded", &report);
+        event::send_event(world, exceeded);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stages_over_budget_name_their_slowest_systems() {
+        let config = ProfilerConfig::default().with_budget("fixed_update", 4.0).with_budget(RENDER_STAGE, 8.0);
+        let mut profile = FrameProfile::default();
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+
+        // Two fixed ticks in one frame add up past the budget
+        profile.record_stage("fixed_update", ms(3), &[("physics", ms(2)), ("ai", ms(1))]);
+        profile.record_stage("fixed_update", ms(3), &[("physics", ms(2)), ("ai", ms(1))]);
+        profile.record_stage(RENDER_STAGE, ms(5), &[]);
+        let exceeded = profile.end_frame(&config, start);
+        assert_eq!(exceeded.len(), 1);
+        assert_eq!((exceeded[0].stage.as_str(), exceeded[0].actual), ("fixed_update", ms(6)));
+        assert_eq!(exceeded[0].systems, vec![(String::from("physics"), ms(4)), (String::from("ai"), ms(2))]);
+        assert_eq!(profile.last_frame()["fixed_update"].total, ms(6));
+
+        // Staying over budget is reported again once the interval passes, counting the frames in between
+        profile.record_stage("fixed_update", ms(5), &[]);
+        assert!(profile.end_frame(&config, start + ms(500)).is_empty());
+        profile.record_stage("fixed_update", ms(5), &[]);
+        let again = profile.end_frame(&config, start + ms(1000));
+        assert_eq!(again.iter().map(|exceeded| exceeded.frames).collect::<Vec<_>>(), vec![2]);
+    }
+}
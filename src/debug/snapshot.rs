@@ -0,0 +1,211 @@
+//!
+//! World snapshots and the differences between them
+//!
+//! A snapshot serializes every component whose type is registered as serializable in the component registry. Diffing
+//! two snapshots, say from consecutive frames, lists the entities that appeared or disappeared and every component
+//! that was added, removed or changed in between. Diffs serialize to JSON, so they can go straight into the log with
+//! `log_diff`
+//!
+
+use std::collections::{BTreeMap, HashMap};
+
+use collider::EntityId;
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+
+use crate::{random, system::{world::World, component::Tick, registry}};
+use super::log;
+
+/// Serialized components of every entity at one point in time
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WorldSnapshot {
+    /// The world's change tick when the snapshot was taken
+    pub tick: Tick,
+    entities: HashMap<EntityId, BTreeMap<String, Value>>,
+}
+
+/// An entity with its components, as it was when it disappeared or as it is after appearing
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EntityEntry {
+    #[serde(serialize_with = "serialize_entity")]
+    pub entity: EntityId,
+    pub components: BTreeMap<String, Value>,
+}
+
+/// A component added (`before` is `None`), removed (`after` is `None`) or changed on an entity present in both snapshots
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ComponentChange {
+    #[serde(serialize_with = "serialize_entity")]
+    pub entity: EntityId,
+    pub component: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+/// Everything that differs between two snapshots
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct WorldDiff {
+    pub from_tick: Tick,
+    pub to_tick: Tick,
+    pub added: Vec<EntityEntry>,
+    pub removed: Vec<EntityEntry>,
+    pub changed: Vec<ComponentChange>,
+}
+
+// Impls
+
+impl WorldSnapshot {
+    /// Snapshots the serializable components of `world`
+    pub fn take(world: &World) -> Self {
+        let mut snapshot = WorldSnapshot { tick: world.change_tick(), entities: HashMap::new() };
+        for registration in registry::registry(world).iter().filter(|registration| registration.is_serializable()) {
+            for entity in registration.entities(world) {
+                let value = match registration.serialize(world, entity) {
+                    Ok(Some(value)) => value,
+                    Ok(None) => continue,
+                    Err(err) => Value::String(err.to_string()),
+                };
+                snapshot.entities.entry(entity).or_default().insert(String::from(registration.name()), value);
+            }
+        }
+        snapshot
+    }
+
+    /// Number of entities with at least one registered component
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    pub fn contains(&self, entity: EntityId) -> bool {
+        self.entities.contains_key(&entity)
+    }
+
+    /// Every entity with its serialized components, keyed by registered name
+    pub fn iter(&self) -> impl Iterator<Item = (EntityId, &BTreeMap<String, Value>)> {
+        self.entities.iter().map(|(entity, components)| (*entity, components))
+    }
+
+    /// The serialized component registered as `name` on `entity`
+    pub fn component(&self, entity: EntityId, name: &str) -> Option<&Value> {
+        self.entities.get(&entity)?.get(name)
+    }
+
+    /// Hash of every entity's serialized components, equal for snapshots holding the same components whatever order
+    /// they were inserted in, and stable between runs
+    pub fn checksum(&self) -> u64 {
+        let mut entities: Vec<(String, &BTreeMap<String, Value>)> = self.entities.iter().map(|(entity, components)| (format!("{:?}", entity), components)).collect();
+        entities.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut bytes = Vec::new();
+        for (entity, components) in entities {
+            bytes.extend_from_slice(entity.as_bytes());
+            for (name, value) in components {
+                bytes.extend_from_slice(name.as_bytes());
+                bytes.extend_from_slice(value.to_string().as_bytes());
+            }
+        }
+        random::stable_hash(&bytes)
+    }
+
+    /// What changed going from this snapshot to `newer`
+    pub fn diff(&self, newer: &WorldSnapshot) -> WorldDiff {
+        let mut diff = WorldDiff { from_tick: self.tick, to_tick: newer.tick, ..WorldDiff::default() };
+
+        for (&entity, components) in &newer.entities {
+            let Some(previous) = self.entities.get(&entity) else {
+                diff.added.push(EntityEntry { entity, components: components.clone() });
+                continue
+            };
+            for (name, after) in components {
+                if previous.get(name) != Some(after) {
+                    diff.changed.push(ComponentChange { entity, component: name.clone(), before: previous.get(name).cloned(), after: Some(after.clone()) });
+                }
+            }
+            for (name, before) in previous.iter().filter(|(name, _)| !components.contains_key(*name)) {
+                diff.changed.push(ComponentChange { entity, component: name.clone(), before: Some(before.clone()), after: None });
+            }
+        }
+        for (&entity, components) in self.entities.iter().filter(|(entity, _)| !newer.entities.contains_key(entity)) {
+            diff.removed.push(EntityEntry { entity, components: components.clone() });
+        }
+
+        // Entity ids only promise `Debug`, sort by that so diffs of the same worlds always read the same
+        diff.added.sort_by_cached_key(|entry| format!("{:?}", entry.entity));
+        diff.removed.sort_by_cached_key(|entry| format!("{:?}", entry.entity));
+        diff.changed.sort_by_cached_key(|change| (format!("{:?}", change.entity), change.component.clone()));
+        diff
+    }
+}
+
+impl WorldDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// Changes to entities present in both snapshots, for one component type
+    pub fn changes_to<'a>(&'a self, component: &'a str) -> impl Iterator<Item = &'a ComponentChange> {
+        self.changed.iter().filter(move |change| change.component == component)
+    }
+}
+
+/// Logs a diff as state under the `snapshot` topic, unless nothing changed
+pub fn log_diff(diff: &WorldDiff) {
+    if !diff.is_empty() {
+        log::get().with_topic("snapshot").state(format!("world changed between ticks {} and {}", diff.from_tick, diff.to_tick), diff);
+    }
+}
+
+fn serialize_entity<S: Serializer>(entity: &EntityId, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!("{:?}", entity))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Health(u32);
+
+    #[derive(Serialize, Deserialize)]
+    struct Armor(u32);
+
+    #[test]
+    fn diff_lists_added_removed_and_changed_components() {
+        let world = World::new();
+        registry::register_component::<Health>(&world, "health");
+        registry::register_component::<Armor>(&world, "armor");
+        let [player, enemy, fallen, spawned] = std::array::from_fn(|_| world.spawn_entity());
+        world.insert_component(player, Health(10));
+        world.insert_component(player, Armor(3));
+        world.insert_component(enemy, Health(5));
+        world.insert_component(fallen, Health(1));
+        let before = WorldSnapshot::take(&world);
+
+        world.insert_component(player, Health(7));
+        world.remove_component::<Armor>(player);
+        world.insert_component(enemy, Armor(2));
+        world.despawn_entity(fallen);
+        world.insert_component(spawned, Health(20));
+        let diff = before.diff(&WorldSnapshot::take(&world));
+
+        assert_eq!(diff.added, vec![EntityEntry { entity: spawned, components: BTreeMap::from([(String::from("health"), json!(20))]) }]);
+        assert_eq!(diff.removed, vec![EntityEntry { entity: fallen, components: BTreeMap::from([(String::from("health"), json!(1))]) }]);
+        let change = |entity, component: &str, before: Option<Value>, after: Option<Value>| ComponentChange { entity, component: String::from(component), before, after };
+        let mut expected = vec![
+            change(player, "health", Some(json!(10)), Some(json!(7))),
+            change(player, "armor", Some(json!(3)), None),
+            change(enemy, "armor", None, Some(json!(2))),
+        ];
+        expected.sort_by_cached_key(|change| (format!("{:?}", change.entity), change.component.clone()));
+        assert_eq!(diff.changed, expected);
+        assert_eq!(diff.changes_to("armor").count(), 2);
+
+        assert!(before.diff(&before).is_empty());
+    }
+}
@@ -0,0 +1,211 @@
+//!
+//! Remote telemetry, enabled with the `telemetry` feature
+//!
+//! Streams log messages, frame stats, memory use and io stats to connected clients as json. Browsers connect with a
+//! WebSocket, other tools can open a plain TCP connection and read one json object per line. Every message has the
+//! form `{"kind": ..., "data": ...}`
+//!
+
+use std::{
+    io::{self, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{mpsc::{self, Receiver, SyncSender}, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tungstenite::{WebSocket, Message};
+
+use crate::{system::{world::World, time::Time}, graphics::{backend::FrameTiming, memory_budget}, vfs};
+use super::latency::{InputLatency, LatencyStats};
+
+static SERVER: Lazy<Mutex<Option<TelemetryServer>>> = Lazy::new(|| Mutex::new(None));
+
+/// Messages waiting for the broadcast thread. Past this new messages are dropped rather than holding up the frame
+const QUEUE_CAPACITY: usize = 1024;
+
+/// How long a client has to take a message before it's disconnected, so one slow client can't stall the rest
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How long a client has to open its connection, including the WebSocket handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+struct TelemetryServer {
+    addr: SocketAddr,
+    tx: SyncSender<String>,
+    clients: Arc<Mutex<Vec<Client>>>,
+}
+
+enum Client {
+    Tcp(TcpStream),
+    WebSocket(Box<WebSocket<TcpStream>>),
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, T: Serialize> {
+    kind: &'a str,
+    data: &'a T,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct FrameStats {
+    pub frame: u64,
+    pub delta_ms: f64,
+    pub fps: f64,
+    /// Recent event to present latency, once input has been presented
+    pub input_latency: Option<LatencyStats>,
+    /// Measured time between frames reaching the display, if the renderer tracks it
+    pub display_interval_ms: Option<f64>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Bytes currently allocated, if the tracking allocator is active
+    pub allocated: Option<u64>,
+}
+
+// Impls
+
+impl Client {
+    fn send(&mut self, json: &str) -> bool {
+        match self {
+            Client::Tcp(stream) => stream.write_all(json.as_bytes()).and_then(|_| stream.write_all(b"\n")).is_ok(),
+            Client::WebSocket(socket) => socket.send(Message::Text(String::from(json))).is_ok(),
+        }
+    }
+}
+
+/// Starts the telemetry server on `addr`, returning the bound address. Returns the existing address if already running
+pub fn start<A: ToSocketAddrs>(addr: A) -> io::Result<SocketAddr> {
+    let mut guard = SERVER.lock().expect("unable to lock telemetry server");
+    if let Some(server) = guard.as_ref() {
+        return Ok(server.addr)
+    }
+
+    let listener = TcpListener::bind(addr)?;
+    let addr = listener.local_addr()?;
+    let clients = Arc::new(Mutex::new(Vec::new()));
+    let (tx, rx) = mpsc::sync_channel(QUEUE_CAPACITY);
+
+    let accept_clients = clients.clone();
+    thread::Builder::new().name(String::from("telemetry accept")).spawn(move || accept(listener, accept_clients))?;
+    let broadcast_clients = clients.clone();
+    thread::Builder::new().name(String::from("telemetry broadcast")).spawn(move || broadcast(rx, broadcast_clients))?;
+
+    *guard = Some(TelemetryServer { addr, tx, clients });
+    Ok(addr)
+}
+
+pub fn is_running() -> bool {
+    SERVER.lock().map(|guard| guard.is_some()).unwrap_or(false)
+}
+
+pub fn client_count() -> usize {
+    SERVER.lock().ok()
+        .and_then(|guard| guard.as_ref().map(|server| server.clients.lock().map(|c| c.len()).unwrap_or(0)))
+        .unwrap_or(0)
+}
+
+/// Queues a message for every connected client. Does nothing if the server isn't running, or the queue is full
+pub fn publish<T: Serialize>(kind: &str, data: &T) {
+    let guard = match SERVER.lock() {
+        Ok(guard) => guard,
+        Err(_) => return,
+    };
+
+    if let Some(server) = guard.as_ref() {
+        if let Ok(json) = serde_json::to_string(&Envelope { kind, data }) {
+            let _ = server.tx.try_send(json);
+        }
+    }
+}
+
+/// Publishes frame and memory stats, called once per frame by the main loop
+pub(crate) fn publish_frame(world: &World) {
+    if !is_running() {
+        return
+    }
+
+    let input_latency = world.with_resource::<InputLatency, _>(InputLatency::stats).flatten();
+    let display_interval_ms = world.with_resource::<FrameTiming, _>(|timing| timing.interval).flatten().map(|interval| interval.as_secs_f64() * 1000.0);
+    if let Some(stats) = world.with_resource::<Time, _>(|time| {
+        let delta = time.real_delta_secs();
+        FrameStats {
+            frame: time.frame(),
+            delta_ms: delta * 1000.0,
+            fps: if delta > 0.0 { 1.0 / delta } else { 0.0 },
+            input_latency,
+            display_interval_ms,
+        }
+    }) {
+        publish("frame", &stats);
+    }
+    publish("memory", &MemoryStats { allocated: super::allocated_bytes() });
+    publish("io", &vfs::io_stats());
+    if let Some(budget) = memory_budget::memory_budget() {
+        publish("gpu_memory", &budget);
+    }
+}
+
+fn accept(listener: TcpListener, clients: Arc<Mutex<Vec<Client>>>) {
+    for stream in listener.incoming().flatten() {
+        // A client that stops reading, or never finishes its handshake, times out instead of blocking the thread
+        if stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).and_then(|_| stream.set_write_timeout(Some(WRITE_TIMEOUT))).is_err() {
+            continue
+        }
+
+        // WebSocket clients open with an http upgrade request, anything else is treated as a raw stream
+        let mut head = [0u8; 4];
+        let websocket = matches!(stream.peek(&mut head), Ok(4) if &head == b"GET ");
+
+        let client = if websocket {
+            match tungstenite::accept(stream) {
+                Ok(socket) => Client::WebSocket(Box::new(socket)),
+                Err(_) => continue,
+            }
+        } else {
+            Client::Tcp(stream)
+        };
+
+        match clients.lock() {
+            Ok(mut clients) => clients.push(client),
+            Err(_) => break,
+        }
+    }
+}
+
+/// Sends each message to every client, disconnecting clients whose writes fail or time out
+fn broadcast(rx: Receiver<String>, clients: Arc<Mutex<Vec<Client>>>) {
+    for json in rx {
+        match clients.lock() {
+            Ok(mut clients) => clients.retain_mut(|client| client.send(&json)),
+            Err(_) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+
+    use super::*;
+
+    #[test]
+    fn tcp_client_receives_json_lines() {
+        let addr = start("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(addr).unwrap();
+        while client_count() == 0 {
+            thread::yield_now();
+        }
+
+        publish("test", &42);
+        // Other tests may be logging at the same time
+        let line = BufReader::new(stream).lines()
+            .map(|line| line.unwrap())
+            .find(|line| line.starts_with(r#"{"kind":"test""#))
+            .unwrap();
+        assert_eq!(line, r#"{"kind":"test","data":42}"#);
+    }
+}
@@ -1,3 +1,4 @@
+use ash::vk;
 
 #[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd)]
 pub struct Extent3 {
@@ -23,3 +24,162 @@ impl Extent3 {
         ( self.x.abs() as usize, self.y.abs() as usize, self.z.abs() as usize )
     }
 }
+
+/// A width and height in pixels
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Extent2 {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A rectangle in pixels, from its top left corner
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Extent2 {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self { width, height }
+    }
+
+    pub fn area(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// Width over height, zero when there's no height
+    pub fn aspect(&self) -> f32 {
+        if self.height == 0 { 0.0 } else { self.width as f32 / self.height as f32 }
+    }
+}
+
+impl From<vk::Extent2D> for Extent2 {
+    fn from(extent: vk::Extent2D) -> Self {
+        Extent2::new(extent.width, extent.height)
+    }
+}
+
+impl From<Extent2> for vk::Extent2D {
+    fn from(extent: Extent2) -> Self {
+        vk::Extent2D { width: extent.width, height: extent.height }
+    }
+}
+
+impl Rect {
+    pub fn new(x: i32, y: i32, width: u32, height: u32) -> Self {
+        Self { x, y, width, height }
+    }
+
+    /// The rectangle of a whole surface of `extent`
+    pub fn from_extent(extent: impl Into<Extent2>) -> Self {
+        let extent = extent.into();
+        Rect::new(0, 0, extent.width, extent.height)
+    }
+
+    /// The rectangle between two corners, empty if `right` or `bottom` is before the other
+    pub fn from_corners(left: i32, top: i32, right: i32, bottom: i32) -> Self {
+        Rect::new(left, top, right.saturating_sub(left).max(0) as u32, bottom.saturating_sub(top).max(0) as u32)
+    }
+
+    pub fn extent(&self) -> Extent2 {
+        Extent2::new(self.width, self.height)
+    }
+
+    pub fn right(&self) -> i32 {
+        self.x.saturating_add_unsigned(self.width)
+    }
+
+    pub fn bottom(&self) -> i32 {
+        self.y.saturating_add_unsigned(self.height)
+    }
+
+    pub fn area(&self) -> u64 {
+        self.extent().area()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.extent().is_empty()
+    }
+
+    pub fn contains_point(&self, x: i32, y: i32) -> bool {
+        x >= self.x && x < self.right() && y >= self.y && y < self.bottom()
+    }
+
+    /// Whether `other` lies entirely within the rectangle, empty rectangles are contained anywhere inside it
+    pub fn contains(&self, other: &Rect) -> bool {
+        other.x >= self.x && other.y >= self.y && other.right() <= self.right() && other.bottom() <= self.bottom()
+    }
+
+    /// The overlap of both rectangles, `None` if they don't overlap
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let rect = Rect::from_corners(self.x.max(other.x), self.y.max(other.y), self.right().min(other.right()), self.bottom().min(other.bottom()));
+        (!rect.is_empty()).then_some(rect)
+    }
+
+    /// Cuts the rectangle `at` pixels from its left edge, clamped to its width
+    pub fn split_columns(&self, at: u32) -> (Rect, Rect) {
+        let at = at.min(self.width);
+        (Rect::new(self.x, self.y, at, self.height), Rect::new(self.x.saturating_add_unsigned(at), self.y, self.width - at, self.height))
+    }
+
+    /// Cuts the rectangle `at` pixels from its top edge, clamped to its height
+    pub fn split_rows(&self, at: u32) -> (Rect, Rect) {
+        let at = at.min(self.height);
+        (Rect::new(self.x, self.y, self.width, at), Rect::new(self.x, self.y.saturating_add_unsigned(at), self.width, self.height - at))
+    }
+
+    /// Cells of a grid over the rectangle, row by row. Cells differ by at most a pixel so they tile it exactly
+    pub fn grid(&self, columns: u32, rows: u32) -> Vec<Rect> {
+        let edge = |start: i32, length: u32, cells: u32, i: u32| start.saturating_add_unsigned((length as u64 * i as u64 / cells.max(1) as u64) as u32);
+        (0..rows).flat_map(|row| (0..columns).map(move |column| (column, row))).map(|(column, row)| Rect::from_corners(
+            edge(self.x, self.width, columns, column),
+            edge(self.y, self.height, rows, row),
+            edge(self.x, self.width, columns, column + 1),
+            edge(self.y, self.height, rows, row + 1),
+        )).collect()
+    }
+}
+
+impl From<vk::Rect2D> for Rect {
+    fn from(rect: vk::Rect2D) -> Self {
+        Rect::new(rect.offset.x, rect.offset.y, rect.extent.width, rect.extent.height)
+    }
+}
+
+impl From<Rect> for vk::Rect2D {
+    fn from(rect: Rect) -> Self {
+        vk::Rect2D { offset: vk::Offset2D { x: rect.x, y: rect.y }, extent: rect.extent().into() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rects_intersect_and_split() {
+        let window = Rect::from_extent(Extent2::new(100, 50));
+        let panel = Rect::new(80, -10, 40, 30);
+        assert_eq!(window.intersection(&panel), Some(Rect::new(80, 0, 20, 20)));
+        assert_eq!(window.intersection(&Rect::new(100, 0, 10, 10)), None);
+        assert!(window.contains(&Rect::new(10, 10, 90, 40)));
+        assert!(!window.contains(&panel));
+        assert!(window.contains_point(99, 49) && !window.contains_point(100, 0));
+
+        let (left, right) = window.split_columns(30);
+        assert_eq!((left.width, right.x, right.width), (30, 30, 70));
+        assert_eq!(window.split_rows(80).1, Rect::new(0, 50, 100, 0));
+
+        let cells = Rect::from_extent(Extent2::new(101, 51)).grid(2, 2);
+        assert_eq!(cells.iter().map(Rect::area).sum::<u64>(), 101 * 51);
+        assert_eq!(cells[3], Rect::new(50, 25, 51, 26));
+        assert_eq!(Rect::from(vk::Rect2D::from(panel)), panel);
+    }
+}
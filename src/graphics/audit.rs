@@ -0,0 +1,57 @@
+//!
+//! Vulkan call auditing
+//!
+//! Fallible Vulkan calls go through `check`, which logs the failing call under the "vulkan" topic and hands the error
+//! back for the caller to propagate, ending up as an `AppEventResult::GraphicsError` that stops the app cleanly. In
+//! strict mode, set with `strict_vulkan` in the renderer config, a failure panics right at the call instead so it can
+//! be caught in a debugger during development
+//!
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use ash::vk;
+
+use crate::debug::log;
+
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+pub fn set_strict(strict: bool) {
+    STRICT.store(strict, Ordering::Relaxed);
+}
+
+pub fn is_strict() -> bool {
+    STRICT.load(Ordering::Relaxed)
+}
+
+/// Logs `result` if `call` failed, panicking in strict mode
+pub(crate) fn check<T>(result: Result<T, vk::Result>, call: &str) -> Result<T, vk::Result> {
+    check_with(is_strict(), result, call)
+}
+
+/// The value a call needed, or `error` when it's missing, audited like any other failure
+pub(crate) fn require<T>(value: Option<T>, call: &str, error: vk::Result) -> Result<T, vk::Result> {
+    check(value.ok_or(error), call)
+}
+
+fn check_with<T>(strict: bool, result: Result<T, vk::Result>, call: &str) -> Result<T, vk::Result> {
+    if let Err(error) = &result {
+        if strict {
+            panic!("{} failed: {}", call, error);
+        }
+        log::get().with_topic("vulkan").error(format!("{} failed: {}", call, error));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_return_unless_strict() {
+        assert_eq!(check_with(false, Err::<(), _>(vk::Result::ERROR_DEVICE_LOST), "vkQueueSubmit"), Err(vk::Result::ERROR_DEVICE_LOST));
+        assert_eq!(check_with(true, Ok(3), "vkQueueSubmit"), Ok(3));
+        let strict = std::panic::catch_unwind(|| check_with(true, Err::<(), _>(vk::Result::ERROR_DEVICE_LOST), "vkQueueSubmit"));
+        assert!(strict.is_err());
+    }
+}
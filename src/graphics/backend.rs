@@ -0,0 +1,211 @@
+//!
+//! The interface the app loop drives a renderer through
+//!
+//! The app only ever talks to a backend through `GraphicsBackend`, so the Vulkan renderer can be swapped for
+//! `MockGraphics` in tests that need the app loop but not a GPU
+//!
+
+use std::time::{Duration, Instant};
+
+use ash::vk;
+use serde::{Serialize, Deserialize};
+
+use crate::{config::{self, ConfigSection}, graphics::{capabilities::FeatureTier, capture::CapturedFrame, events::SwapchainRefreshed, render_graph::ResourceKind}, memory::pressure, system::world::World, unique::UniqueId};
+
+/// Whether a frame can be rendered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameStatus {
+    Ready,
+    /// Nothing to render into this time around, e.g. an acquire timed out
+    Skip,
+    /// The swapchain has to be recreated before rendering continues
+    Recreate,
+}
+
+#[derive(Debug)]
+pub enum BackendError {
+    Vulkan(vk::Result),
+    /// The backend doesn't implement the operation
+    Unsupported(&'static str),
+    Other(String),
+}
+
+/// Where a backend's display timings come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimingSource {
+    /// The presentation engine reported the frame shown, through `VK_KHR_present_wait`
+    PresentWait,
+    /// Estimated from the frame's fence signaling, when present wait isn't supported
+    Fence,
+}
+
+/// When frames have been reaching the display
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameTiming {
+    pub source: TimingSource,
+    /// When the most recently confirmed frame was shown
+    pub displayed_at: Instant,
+    /// Smoothed time between shown frames, once there have been two
+    pub interval: Option<Duration>,
+}
+
+/// Renderer options from the `renderer` config section
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RendererConfig {
+    /// Ignored in benchmark mode, which always renders unsynchronized
+    pub vsync: bool,
+    /// Size of the render targets relative to the window
+    pub resolution_scale: f32,
+    /// Panics at the first failing Vulkan call instead of returning the error, see `audit`
+    pub strict_vulkan: bool,
+    /// Milliseconds a frame fence may go unsignalled before the watchdog reports a hang
+    pub hang_threshold_ms: u64,
+    /// Rebuilds the device after a reported hang or device loss rather than waiting on
+    pub recover_from_hangs: bool,
+    /// Logs the full Vulkan environment at startup, see `diagnostics`
+    pub dump_vulkan_environment: bool,
+    /// Highest feature tier systems may use whatever the device supports, see `capabilities`
+    pub max_feature_tier: Option<FeatureTier>,
+}
+
+pub trait GraphicsBackend {
+    fn name(&self) -> &'static str;
+
+    /// Waits until a frame can be recorded and acquires its target
+    fn begin_frame(&mut self) -> Result<FrameStatus, BackendError>;
+
+    /// Submits the frame's recorded work
+    fn submit(&mut self) -> Result<(), BackendError>;
+
+    /// Presents the frame, reporting whether the swapchain should be recreated
+    fn present(&mut self) -> Result<FrameStatus, BackendError>;
+
+    /// When the last presented frame was actually shown, for backends with swapchain present feedback
+    fn presented_at(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Display timings used to pace frames, for backends that track them
+    fn frame_timing(&self) -> Option<FrameTiming> {
+        None
+    }
+
+    /// Reads back the submitted frame before it's presented, waiting for it to finish rendering
+    fn read_frame(&mut self) -> Result<CapturedFrame, BackendError> {
+        Err(BackendError::Unsupported("read_frame"))
+    }
+
+    /// Rebuilds the swapchain after a resize or an out of date or suboptimal frame, describing the new swapchain when
+    /// the backend requeried the surface for it
+    fn recreate(&mut self) -> Result<Option<SwapchainRefreshed>, BackendError>;
+
+    /// Rebuilds the window surface and the swapchain on it after the surface was lost or the window destroyed
+    fn recreate_surface(&mut self) -> Result<(), BackendError> {
+        Err(BackendError::Unsupported("recreate_surface"))
+    }
+
+    fn create_resource(&mut self, name: &str, kind: ResourceKind) -> Result<UniqueId, BackendError>;
+
+    fn destroy_resource(&mut self, resource: UniqueId);
+}
+
+// Impls
+
+impl std::error::Error for BackendError {}
+
+impl std::fmt::Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackendError::Vulkan(result) => write!(f, "vulkan error: {}", result),
+            BackendError::Unsupported(operation) => write!(f, "{} is not supported by this backend", operation),
+            BackendError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Default for RendererConfig {
+    fn default() -> Self {
+        RendererConfig {
+            vsync: true,
+            resolution_scale: 1.0,
+            strict_vulkan: false,
+            hang_threshold_ms: 2000,
+            recover_from_hangs: false,
+            dump_vulkan_environment: false,
+            max_feature_tier: None,
+        }
+    }
+}
+
+impl ConfigSection for RendererConfig {
+    const NAME: &'static str = "renderer";
+}
+
+impl RendererConfig {
+    pub fn with_vsync(mut self, vsync: bool) -> Self {
+        self.vsync = vsync;
+        self
+    }
+
+    pub fn with_resolution_scale(mut self, scale: f32) -> Self {
+        self.resolution_scale = scale;
+        self
+    }
+
+    /// `resolution_scale` kept to a range the renderer can allocate targets for
+    pub fn clamped_scale(&self) -> f32 {
+        match self.resolution_scale.is_finite() {
+            true => self.resolution_scale.clamp(0.25, 2.0),
+            false => 1.0,
+        }
+    }
+}
+
+impl FrameTiming {
+    /// Timing after a frame from `source` was shown at `at`, following on from `previous`
+    pub fn next(previous: Option<FrameTiming>, source: TimingSource, at: Instant) -> Self {
+        let sample = previous.map(|previous| at.saturating_duration_since(previous.displayed_at));
+        let interval = match (previous.and_then(|previous| previous.interval), sample) {
+            (Some(interval), Some(sample)) => Some(interval.mul_f64(0.9) + sample.mul_f64(0.1)),
+            (_, sample) => sample,
+        };
+        FrameTiming { source, displayed_at: at, interval }
+    }
+}
+
+impl From<vk::Result> for BackendError {
+    fn from(result: vk::Result) -> Self {
+        BackendError::Vulkan(result)
+    }
+}
+
+/// Subscribes the world's `RendererConfig` to `MemoryPressure`, rendering at `PressureLevel::quality` of the configured
+/// resolution scale while the pressure lasts
+pub fn scale_resolution_for_pressure(world: &World) {
+    let Some(level) = pressure::latest_pressure(world) else { return };
+    let configured = config::get().section::<RendererConfig>().resolution_scale;
+    world.with_resource_mut::<RendererConfig, _>(|renderer| renderer.resolution_scale = configured * level.quality() as f32);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_timing_smooths_the_interval_between_shown_frames() {
+        let start = Instant::now();
+        let first = FrameTiming::next(None, TimingSource::Fence, start);
+        assert_eq!(first.interval, None);
+
+        let second = FrameTiming::next(Some(first), TimingSource::PresentWait, start + Duration::from_millis(20));
+        assert_eq!(second.source, TimingSource::PresentWait);
+        assert_eq!(second.interval, Some(Duration::from_millis(20)));
+
+        // A single slow frame only nudges the interval, and a clock going backwards counts as no time at all
+        let third = FrameTiming::next(Some(second), TimingSource::PresentWait, start + Duration::from_millis(50));
+        assert_eq!(third.interval, Some(Duration::from_millis(21)));
+        let fourth = FrameTiming::next(Some(third), TimingSource::PresentWait, start);
+        assert_eq!(fourth.interval, Some(Duration::from_millis(21).mul_f64(0.9)));
+    }
+}
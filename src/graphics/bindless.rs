@@ -0,0 +1,298 @@
+//!
+//! Bindless textures
+//!
+//! With descriptor indexing every texture lives in one large, partially bound descriptor array which stays bound for
+//! the whole frame. Materials refer to their textures by index into the array, passed to shaders as push constants,
+//! so drawing a different material only pushes a few integers instead of binding a new descriptor set
+//!
+//! `TextureTable` hands out the indices and tracks which slots changed, `BindlessDescriptors` owns the Vulkan side and
+//! writes those slots. Shaders declare the array as `layout(set = 0, binding = 0) uniform sampler2D textures[];`
+//!
+
+use std::collections::HashMap;
+
+use ash::vk;
+
+use crate::unique::UniqueId;
+
+/// Slot a texture occupies in the bindless array
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TextureIndex(pub u32);
+
+/// Assigns textures to slots of the bindless array, reusing the slots of removed textures
+#[derive(Debug, Clone, Default)]
+pub struct TextureTable {
+    capacity: u32,
+    slots: HashMap<UniqueId, TextureIndex>,
+    free: Vec<TextureIndex>,
+    next: u32,
+    /// Slots written since the last `take_dirty`
+    dirty: Vec<TextureIndex>,
+}
+
+/// Per draw push constants selecting a material's textures
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MaterialIndices {
+    pub albedo: u32,
+    pub normal: u32,
+    pub roughness_metallic: u32,
+    pub emissive: u32,
+}
+
+/// Descriptor indexing limits of a device that supports bindless textures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BindlessSupport {
+    /// Most sampled images a single update after bind set can hold
+    pub max_textures: u32,
+}
+
+/// The bindless descriptor set and the objects it's allocated from
+pub(crate) struct BindlessDescriptors {
+    layout: vk::DescriptorSetLayout,
+    pool: vk::DescriptorPool,
+    set: vk::DescriptorSet,
+    capacity: u32,
+}
+
+// Impls
+
+impl TextureTable {
+    pub fn new(capacity: u32) -> Self {
+        TextureTable { capacity, ..Default::default() }
+    }
+
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// The texture's slot, assigning one if it doesn't have one yet. `None` once the table is full
+    pub fn insert(&mut self, texture: UniqueId) -> Option<TextureIndex> {
+        if let Some(index) = self.slots.get(&texture) {
+            return Some(*index)
+        }
+
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None if self.next < self.capacity => {
+                self.next += 1;
+                TextureIndex(self.next - 1)
+            },
+            None => return None,
+        };
+        self.slots.insert(texture, index);
+        self.dirty.push(index);
+        Some(index)
+    }
+
+    /// Frees the texture's slot. The descriptor is left in place, partially bound arrays allow stale entries as long
+    /// as no shader reads them
+    pub fn remove(&mut self, texture: UniqueId) -> Option<TextureIndex> {
+        let index = self.slots.remove(&texture)?;
+        self.dirty.retain(|dirty| *dirty != index);
+        self.free.push(index);
+        Some(index)
+    }
+
+    pub fn get(&self, texture: UniqueId) -> Option<TextureIndex> {
+        self.slots.get(&texture).copied()
+    }
+
+    /// Slots that need their descriptors written, with the texture now in each
+    pub fn take_dirty(&mut self) -> Vec<(TextureIndex, UniqueId)> {
+        let dirty = std::mem::take(&mut self.dirty);
+        dirty.into_iter()
+            .filter_map(|index| self.slots.iter().find(|(_, slot)| **slot == index).map(|(texture, _)| (index, *texture)))
+            .collect()
+    }
+}
+
+impl MaterialIndices {
+    pub fn as_bytes(&self) -> &[u8] {
+        // Safety: `MaterialIndices` is `repr(C)` and made only of `u32`s, so it has no padding
+        unsafe { std::slice::from_raw_parts(self as *const Self as *const u8, std::mem::size_of::<Self>()) }
+    }
+
+    /// The push constant range shaders read the indices from
+    pub fn push_constant_range() -> vk::PushConstantRange {
+        vk::PushConstantRange::builder()
+            .stage_flags(vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT)
+            .offset(0)
+            .size(std::mem::size_of::<Self>() as u32)
+            .build()
+    }
+}
+
+impl BindlessSupport {
+    /// Checks for the descriptor indexing features bindless textures rely on
+    pub(crate) fn query(instance: &ash::Instance, physical_device: vk::PhysicalDevice) -> Option<Self> {
+        let mut indexing = vk::PhysicalDeviceDescriptorIndexingFeatures::default();
+        let mut features = vk::PhysicalDeviceFeatures2::builder().push_next(&mut indexing);
+        unsafe { instance.get_physical_device_features2(physical_device, &mut features) };
+        let supported = [
+            indexing.shader_sampled_image_array_non_uniform_indexing,
+            indexing.descriptor_binding_sampled_image_update_after_bind,
+            indexing.descriptor_binding_partially_bound,
+            indexing.descriptor_binding_variable_descriptor_count,
+            indexing.runtime_descriptor_array,
+        ].iter().all(|feature| *feature == vk::TRUE);
+        if !supported {
+            return None
+        }
+
+        let mut limits = vk::PhysicalDeviceDescriptorIndexingProperties::default();
+        let mut properties = vk::PhysicalDeviceProperties2::builder().push_next(&mut limits);
+        unsafe { instance.get_physical_device_properties2(physical_device, &mut properties) };
+        Some(BindlessSupport { max_textures: limits.max_descriptor_set_update_after_bind_sampled_images })
+    }
+
+    /// The features to enable at device creation
+    pub(crate) fn features() -> vk::PhysicalDeviceDescriptorIndexingFeatures {
+        vk::PhysicalDeviceDescriptorIndexingFeatures::builder()
+            .shader_sampled_image_array_non_uniform_indexing(true)
+            .descriptor_binding_sampled_image_update_after_bind(true)
+            .descriptor_binding_partially_bound(true)
+            .descriptor_binding_variable_descriptor_count(true)
+            .runtime_descriptor_array(true)
+            .build()
+    }
+
+    /// Turns on the same features through the Vulkan 1.2 feature struct, for devices created with it chained. The two
+    /// can't both be chained
+    pub(crate) fn enable(features: &mut vk::PhysicalDeviceVulkan12Features) {
+        features.shader_sampled_image_array_non_uniform_indexing = vk::TRUE;
+        features.descriptor_binding_sampled_image_update_after_bind = vk::TRUE;
+        features.descriptor_binding_partially_bound = vk::TRUE;
+        features.descriptor_binding_variable_descriptor_count = vk::TRUE;
+        features.runtime_descriptor_array = vk::TRUE;
+    }
+}
+
+impl BindlessDescriptors {
+    /// Creates a set with room for `capacity` textures, clamped to what the device supports
+    pub(crate) fn new(device: &ash::Device, support: BindlessSupport, capacity: u32) -> Result<Self, vk::Result> {
+        let capacity = capacity.min(support.max_textures).max(1);
+
+        let bindings = [vk::DescriptorSetLayoutBinding::builder()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(capacity)
+            .stage_flags(vk::ShaderStageFlags::ALL_GRAPHICS)
+            .build()];
+        let binding_flags = [vk::DescriptorBindingFlags::PARTIALLY_BOUND
+            | vk::DescriptorBindingFlags::UPDATE_AFTER_BIND
+            | vk::DescriptorBindingFlags::VARIABLE_DESCRIPTOR_COUNT];
+        let mut binding_flags_info = vk::DescriptorSetLayoutBindingFlagsCreateInfo::builder().binding_flags(&binding_flags);
+        let layout_info = vk::DescriptorSetLayoutCreateInfo::builder()
+            .flags(vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL)
+            .bindings(&bindings)
+            .push_next(&mut binding_flags_info);
+        let layout = unsafe { device.create_descriptor_set_layout(&layout_info, None)? };
+
+        let pool_sizes = [vk::DescriptorPoolSize { ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER, descriptor_count: capacity }];
+        let pool_info = vk::DescriptorPoolCreateInfo::builder()
+            .flags(vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND)
+            .max_sets(1)
+            .pool_sizes(&pool_sizes);
+        let pool = match unsafe { device.create_descriptor_pool(&pool_info, None) } {
+            Ok(pool) => pool,
+            Err(error) => {
+                unsafe { device.destroy_descriptor_set_layout(layout, None) };
+                return Err(error)
+            },
+        };
+
+        let counts = [capacity];
+        let mut count_info = vk::DescriptorSetVariableDescriptorCountAllocateInfo::builder().descriptor_counts(&counts);
+        let layouts = [layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(pool)
+            .set_layouts(&layouts)
+            .push_next(&mut count_info);
+        let set = match unsafe { device.allocate_descriptor_sets(&allocate_info) } {
+            Ok(sets) => sets[0],
+            Err(error) => {
+                unsafe {
+                    device.destroy_descriptor_pool(pool, None);
+                    device.destroy_descriptor_set_layout(layout, None);
+                }
+                return Err(error)
+            },
+        };
+
+        Ok(BindlessDescriptors { layout, pool, set, capacity })
+    }
+
+    pub(crate) fn layout(&self) -> vk::DescriptorSetLayout {
+        self.layout
+    }
+
+    pub(crate) fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    /// Points a slot at a texture. Update after bind allows this while the set is bound, just not while a submitted
+    /// frame reading the slot is in flight
+    pub(crate) fn write(&self, device: &ash::Device, index: TextureIndex, view: vk::ImageView, sampler: vk::Sampler) {
+        debug_assert!(index.0 < self.capacity, "bindless slot {} out of range", index.0);
+        let images = [vk::DescriptorImageInfo {
+            sampler,
+            image_view: view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        let writes = [vk::WriteDescriptorSet::builder()
+            .dst_set(self.set)
+            .dst_binding(0)
+            .dst_array_element(index.0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(&images)
+            .build()];
+        unsafe { device.update_descriptor_sets(&writes, &[]) };
+    }
+
+    /// Binds the set once for every draw in a command buffer recorded with `pipeline_layout`
+    pub(crate) fn bind(&self, device: &ash::Device, command_buffer: vk::CommandBuffer, pipeline_layout: vk::PipelineLayout) {
+        unsafe { device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline_layout, 0, &[self.set], &[]) };
+    }
+
+    /// Selects a material's textures for the following draws
+    pub(crate) fn push_material(device: &ash::Device, command_buffer: vk::CommandBuffer, pipeline_layout: vk::PipelineLayout, material: &MaterialIndices) {
+        let range = MaterialIndices::push_constant_range();
+        unsafe { device.cmd_push_constants(command_buffer, pipeline_layout, range.stage_flags, 0, material.as_bytes()) };
+    }
+
+    pub(crate) unsafe fn destroy(self, device: &ash::Device) {
+        device.destroy_descriptor_pool(self.pool, None);
+        device.destroy_descriptor_set_layout(self.layout, None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_are_reused() {
+        let mut table = TextureTable::new(2);
+        let (a, b, c) = (UniqueId::get(), UniqueId::get(), UniqueId::get());
+
+        assert_eq!(table.insert(a), Some(TextureIndex(0)));
+        assert_eq!(table.insert(b), Some(TextureIndex(1)));
+        assert_eq!(table.insert(a), Some(TextureIndex(0)));
+        assert_eq!(table.insert(c), None);
+        assert_eq!(table.take_dirty(), vec![(TextureIndex(0), a), (TextureIndex(1), b)]);
+
+        assert_eq!(table.remove(a), Some(TextureIndex(0)));
+        assert_eq!(table.insert(c), Some(TextureIndex(0)));
+        assert_eq!(table.take_dirty(), vec![(TextureIndex(0), c)]);
+        assert_eq!(table.len(), 2);
+    }
+}
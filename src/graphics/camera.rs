@@ -0,0 +1,48 @@
+//!
+//! Cameras
+//!
+//! An entity with a `Camera` and a `Transform` views the world from that transform, looking down its local -Z axis, and
+//! draws to the window or to a texture depending on its `target`
+//!
+
+use collider::EntityId;
+
+use crate::system::world::World;
+
+use super::{layers::RenderLayers, targets::RenderTarget, viewport::Viewport};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Camera {
+    /// Vertical field of view in radians
+    pub fov_y: f64,
+    pub near: f64,
+    pub far: f64,
+    /// Inactive cameras are skipped when rendering
+    pub active: bool,
+    /// Layers of the entities this camera draws
+    pub layers: RenderLayers,
+    pub target: RenderTarget,
+    /// Part of the window drawn to, ignored when drawing to a texture
+    pub viewport: Viewport,
+}
+
+// Impls
+
+impl Default for Camera {
+    fn default() -> Self {
+        Camera {
+            fov_y: 60f64.to_radians(),
+            near: 0.1,
+            far: 1000.0,
+            active: true,
+            layers: RenderLayers::default(),
+            target: RenderTarget::Window,
+            viewport: Viewport::FULL,
+        }
+    }
+}
+
+/// The first active camera drawing to the window
+pub fn active_camera(world: &World) -> Option<EntityId> {
+    world.query::<Camera, ()>().into_iter().find(|entity| world.component::<Camera, _>(*entity, |camera| camera.active && camera.target == RenderTarget::Window).unwrap_or(false))
+}
@@ -0,0 +1,213 @@
+//!
+//! Renderer capabilities and feature tiers
+//!
+//! The device's relevant limits and features are read once while the renderer initializes, and condensed into a
+//! `FeatureTier`. Systems with more than one code path ask for the tier or a single `Feature` here rather than checking
+//! extensions themselves, so every fallback decision is made from the same report and can be forced lower with
+//! `renderer.max_feature_tier` to exercise the fallbacks on capable hardware
+//!
+//! | tier       | adds |
+//! |------------|------|
+//! | `Baseline` | graphics only, everything else runs on the CPU or binds per draw |
+//! | `Standard` | compute, indirect count draws and 8K textures, for GPU culling and particles |
+//! | `Modern`   | bindless textures, timeline semaphores and dynamic rendering |
+//!
+
+use std::{ffi::CStr, sync::RwLock};
+
+use ash::vk;
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+
+use crate::config;
+use crate::debug::log;
+use super::{backend::RendererConfig, bindless::BindlessSupport};
+
+/// Smallest 2D texture limit of a `Standard` device
+const STANDARD_TEXTURE_SIZE: u32 = 8192;
+
+static CAPABILITIES: Lazy<RwLock<Option<Capabilities>>> = Lazy::new(|| RwLock::new(None));
+
+/// Groups of features a device supports all of, ordered from least to most capable
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum FeatureTier {
+    #[default]
+    Baseline,
+    Standard,
+    Modern,
+}
+
+/// A code path choice a system makes from the capabilities
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Feature {
+    /// Materials index one descriptor array instead of binding their textures, see `bindless`
+    BindlessTextures,
+    /// Frustum culling in a compute pass, see `culling`
+    GpuCulling,
+    /// Particle simulation in a compute pass, see `particles`
+    GpuParticles,
+    /// Compute passes on a queue of their own, overlapping graphics work
+    AsyncCompute,
+    TimelineSemaphores,
+    DynamicRendering,
+}
+
+/// What the renderer's device can do
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub device: String,
+    /// Formatted as major.minor.patch
+    pub api_version: String,
+    pub max_texture_size: u32,
+    /// Textures a bindless descriptor array can hold, `None` without descriptor indexing
+    pub max_bindless_textures: Option<u32>,
+    pub compute: bool,
+    /// Has a compute queue family without graphics
+    pub async_compute: bool,
+    pub draw_indirect_count: bool,
+    pub timeline_semaphores: bool,
+    pub dynamic_rendering: bool,
+    /// Highest tier the device qualifies for, before `renderer.max_feature_tier`
+    pub detected_tier: FeatureTier,
+    /// The tier systems go by
+    pub tier: FeatureTier,
+}
+
+// Impls
+
+impl Feature {
+    /// The lowest tier guaranteeing the feature
+    pub fn tier(&self) -> FeatureTier {
+        match self {
+            Feature::GpuCulling | Feature::GpuParticles | Feature::AsyncCompute => FeatureTier::Standard,
+            Feature::BindlessTextures | Feature::TimelineSemaphores | Feature::DynamicRendering => FeatureTier::Modern,
+        }
+    }
+}
+
+impl Capabilities {
+    /// Reads the device's limits and features, capping the tier at `max_tier`
+    pub(crate) fn query(instance: &ash::Instance, physical_device: vk::PhysicalDevice, max_tier: Option<FeatureTier>) -> Self {
+        let properties = unsafe { instance.get_physical_device_properties(physical_device) };
+        let version = properties.api_version;
+        let families = unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
+        let extensions = unsafe { instance.enumerate_device_extension_properties(physical_device) }.unwrap_or_default();
+        let has_extension = |name: &CStr| extensions.iter().any(|extension| unsafe { CStr::from_ptr(extension.extension_name.as_ptr()) } == name);
+
+        // Structures newer than the device's API version may only be chained when their extension is there
+        let core_1_2 = version >= vk::API_VERSION_1_2;
+        let dynamic_rendering_known = version >= vk::API_VERSION_1_3 || has_extension(vk::KhrDynamicRenderingFn::name());
+        let mut vulkan_12 = vk::PhysicalDeviceVulkan12Features::default();
+        let mut dynamic_rendering = vk::PhysicalDeviceDynamicRenderingFeatures::default();
+        let mut features = vk::PhysicalDeviceFeatures2::builder();
+        if core_1_2 {
+            features = features.push_next(&mut vulkan_12);
+        }
+        if dynamic_rendering_known {
+            features = features.push_next(&mut dynamic_rendering);
+        }
+        unsafe { instance.get_physical_device_features2(physical_device, &mut features) };
+
+        let name = unsafe { CStr::from_ptr(properties.device_name.as_ptr()) };
+        let capabilities = Capabilities {
+            device: name.to_string_lossy().into_owned(),
+            api_version: format!("{}.{}.{}", vk::api_version_major(version), vk::api_version_minor(version), vk::api_version_patch(version)),
+            max_texture_size: properties.limits.max_image_dimension2_d,
+            max_bindless_textures: BindlessSupport::query(instance, physical_device).map(|support| support.max_textures),
+            compute: families.iter().any(|family| family.queue_flags.contains(vk::QueueFlags::COMPUTE)),
+            async_compute: families.iter().any(|family| family.queue_flags.contains(vk::QueueFlags::COMPUTE) && !family.queue_flags.contains(vk::QueueFlags::GRAPHICS)),
+            draw_indirect_count: core_1_2 && vulkan_12.draw_indirect_count == vk::TRUE,
+            timeline_semaphores: core_1_2 && vulkan_12.timeline_semaphore == vk::TRUE,
+            dynamic_rendering: dynamic_rendering_known && dynamic_rendering.dynamic_rendering == vk::TRUE,
+            ..Default::default()
+        };
+        capabilities.with_max_tier(max_tier)
+    }
+
+    /// Fills in the tiers from the features, the tier systems use being at most `max_tier`
+    pub fn with_max_tier(mut self, max_tier: Option<FeatureTier>) -> Self {
+        self.detected_tier = self.qualifying_tier();
+        self.tier = max_tier.map_or(self.detected_tier, |max| self.detected_tier.min(max));
+        self
+    }
+
+    /// Whether systems should take the path that relies on `feature`. A feature the device has beyond its tier is
+    /// still used, unless the tier was capped below the feature's
+    pub fn supports(&self, feature: Feature) -> bool {
+        let available = match feature {
+            Feature::BindlessTextures => self.max_bindless_textures.is_some(),
+            Feature::GpuCulling => self.compute && self.draw_indirect_count,
+            Feature::GpuParticles => self.compute,
+            Feature::AsyncCompute => self.async_compute,
+            Feature::TimelineSemaphores => self.timeline_semaphores,
+            Feature::DynamicRendering => self.dynamic_rendering,
+        };
+        available && (self.tier >= feature.tier() || self.tier == self.detected_tier)
+    }
+
+    fn qualifying_tier(&self) -> FeatureTier {
+        let standard = self.compute && self.draw_indirect_count && self.max_texture_size >= STANDARD_TEXTURE_SIZE;
+        let modern = self.max_bindless_textures.is_some() && self.timeline_semaphores && self.dynamic_rendering;
+        match (standard, modern) {
+            (true, true) => FeatureTier::Modern,
+            (true, false) => FeatureTier::Standard,
+            _ => FeatureTier::Baseline,
+        }
+    }
+}
+
+/// The capabilities read when the renderer initialized, `None` before that
+pub fn capabilities() -> Option<Capabilities> {
+    CAPABILITIES.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+}
+
+/// The tier systems should pick their code paths by, `Baseline` before the renderer initializes
+pub fn tier() -> FeatureTier {
+    CAPABILITIES.read().unwrap_or_else(|poisoned| poisoned.into_inner()).as_ref().map_or(FeatureTier::Baseline, |capabilities| capabilities.tier)
+}
+
+/// Whether systems should take the path relying on `feature`, `false` before the renderer initializes
+pub fn supports(feature: Feature) -> bool {
+    CAPABILITIES.read().unwrap_or_else(|poisoned| poisoned.into_inner()).as_ref().is_some_and(|capabilities| capabilities.supports(feature))
+}
+
+/// Queries the device and publishes the result for `capabilities`, `tier` and `supports`
+pub(crate) fn detect(instance: &ash::Instance, physical_device: vk::PhysicalDevice) -> FeatureTier {
+    let max_tier = config::get().section::<RendererConfig>().max_feature_tier;
+    let capabilities = Capabilities::query(instance, physical_device, max_tier);
+    let tier = capabilities.tier;
+    publish(capabilities);
+    tier
+}
+
+pub(crate) fn publish(capabilities: Capabilities) {
+    log::get().state("capabilities", &capabilities);
+    if capabilities.tier != capabilities.detected_tier {
+        log::get().with_topic("capabilities").info(format!("feature tier capped at {:?}, the device supports {:?}", capabilities.tier, capabilities.detected_tier));
+    }
+    *CAPABILITIES.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(capabilities);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiers_condense_features_and_can_be_capped() {
+        let standard = Capabilities { max_texture_size: 16384, compute: true, draw_indirect_count: true, timeline_semaphores: true, ..Default::default() };
+        let detected = standard.clone().with_max_tier(None);
+        assert_eq!(detected.tier, FeatureTier::Standard);
+        // Features past the tier are still used while it isn't capped
+        assert!(detected.supports(Feature::GpuCulling) && detected.supports(Feature::TimelineSemaphores));
+        assert!(!detected.supports(Feature::BindlessTextures));
+
+        let modern = Capabilities { max_bindless_textures: Some(4096), dynamic_rendering: true, ..standard }.with_max_tier(Some(FeatureTier::Standard));
+        assert_eq!((modern.detected_tier, modern.tier), (FeatureTier::Modern, FeatureTier::Standard));
+        assert!(modern.supports(Feature::GpuParticles));
+        assert!(!modern.supports(Feature::BindlessTextures));
+
+        let small = Capabilities { max_texture_size: 4096, ..modern }.with_max_tier(None);
+        assert_eq!(small.tier, FeatureTier::Baseline);
+    }
+}
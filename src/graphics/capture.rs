@@ -0,0 +1,214 @@
+//!
+//! Frame capture
+//!
+//! With capture enabled, every `every`th presented frame is read back from the renderer just before it's presented,
+//! and written either as a numbered PNG or as raw RGBA to stdout for piping into a video encoder, e.g.
+//! `game | ffmpeg -f rawvideo -pix_fmt rgba -s 800x600 -r 60 -i - trailer.mp4`. A read back waits for the GPU to
+//! finish the frame, so while capturing the world advances a fixed `1 / fps` every frame instead of by wall clock time,
+//! which keeps the dump playing at the right speed, and the time spent capturing is left out of the frame clock so it
+//! doesn't show up in frame timing metrics
+//!
+
+use std::{fs::File, io::{self, BufReader, BufWriter, Write}, path::PathBuf, time::Duration};
+
+use serde::{Serialize, Deserialize};
+
+use crate::config::ConfigSection;
+
+/// Channel order of a captured frame's pixels, 8 bits each
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PixelFormat {
+    Rgba8,
+    Bgra8,
+}
+
+/// A frame read back from the renderer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedFrame {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    /// Tightly packed rows, top row first
+    pub pixels: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureOutput {
+    /// Numbered PNGs in the capture directory
+    Files,
+    /// Raw RGBA frames back to back on stdout
+    Stdout,
+    /// Kept for `FrameCapture::take_frames`, for tools driving a headless app
+    #[serde(skip)]
+    Memory,
+}
+
+/// Capture options from the `capture` config section
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct CaptureConfig {
+    pub enabled: bool,
+    /// Captures every Nth presented frame
+    pub every: u32,
+    pub output: CaptureOutput,
+    pub directory: PathBuf,
+    /// Frame rate the world advances at while capturing
+    pub fps: f64,
+    /// Stops capturing after this many frames
+    pub max_frames: Option<u64>,
+}
+
+/// Decides which frames are captured and writes them out
+#[derive(Debug)]
+pub struct FrameCapture {
+    config: CaptureConfig,
+    presented: u64,
+    written: u64,
+    /// Frames captured to memory and not taken yet
+    frames: Vec<CapturedFrame>,
+}
+
+// Impls
+
+impl CapturedFrame {
+    /// A frame of one color
+    pub fn filled(width: u32, height: u32, rgba: [u8; 4]) -> Self {
+        CapturedFrame { width, height, format: PixelFormat::Rgba8, pixels: rgba.repeat((width * height) as usize) }
+    }
+
+    /// The pixels in RGBA order
+    pub fn rgba(&self) -> Vec<u8> {
+        match self.format {
+            PixelFormat::Rgba8 => self.pixels.clone(),
+            PixelFormat::Bgra8 => self.pixels.chunks_exact(4).flat_map(|pixel| [pixel[2], pixel[1], pixel[0], pixel[3]]).collect(),
+        }
+    }
+
+    /// Reads a PNG of any 8 bit color type
+    pub fn read_png(path: impl AsRef<std::path::Path>) -> io::Result<Self> {
+        let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let mut reader = decoder.read_info().map_err(io::Error::other)?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer).map_err(io::Error::other)?;
+        let buffer = &buffer[..info.buffer_size()];
+
+        let pixels = match info.color_type {
+            png::ColorType::Rgba => buffer.to_vec(),
+            png::ColorType::Rgb => buffer.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect(),
+            png::ColorType::GrayscaleAlpha => buffer.chunks_exact(2).flat_map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+            png::ColorType::Grayscale => buffer.iter().flat_map(|g| [*g, *g, *g, 255]).collect(),
+            png::ColorType::Indexed => return Err(io::Error::new(io::ErrorKind::InvalidData, "palette wasn't expanded")),
+        };
+        Ok(CapturedFrame { width: info.width, height: info.height, format: PixelFormat::Rgba8, pixels })
+    }
+
+    pub fn write_png(&self, path: impl AsRef<std::path::Path>) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(io::Error::other)?;
+        writer.write_image_data(&self.rgba()).map_err(io::Error::other)
+    }
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        CaptureConfig {
+            enabled: false,
+            every: 1,
+            output: CaptureOutput::Files,
+            directory: PathBuf::from("captures"),
+            fps: 60.0,
+            max_frames: None,
+        }
+    }
+}
+
+impl ConfigSection for CaptureConfig {
+    const NAME: &'static str = "capture";
+}
+
+impl FrameCapture {
+    pub fn new(config: CaptureConfig) -> Self {
+        FrameCapture { config, presented: 0, written: 0, frames: Vec::new() }
+    }
+
+    pub fn config(&self) -> &CaptureConfig {
+        &self.config
+    }
+
+    /// Frames written so far
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.config.max_frames.is_some_and(|max| self.written >= max)
+    }
+
+    /// Whether the frame about to be presented should be captured
+    pub fn wants_frame(&self) -> bool {
+        !self.is_finished() && self.presented.is_multiple_of(u64::from(self.config.every.max(1)))
+    }
+
+    /// Time the world advances each frame while capturing
+    pub fn frame_delta(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.config.fps.max(1.0))
+    }
+
+    /// Takes the frames captured to memory, oldest first
+    pub fn take_frames(&mut self) -> Vec<CapturedFrame> {
+        std::mem::take(&mut self.frames)
+    }
+
+    /// Counts a presented frame, captured or not
+    pub fn presented(&mut self) {
+        self.presented += 1;
+    }
+
+    pub fn write(&mut self, frame: &CapturedFrame) -> io::Result<()> {
+        match self.config.output {
+            CaptureOutput::Files => {
+                std::fs::create_dir_all(&self.config.directory)?;
+                frame.write_png(self.config.directory.join(format!("frame_{:06}.png", self.written)))?;
+            },
+            CaptureOutput::Stdout => {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(&frame.rgba())?;
+                stdout.flush()?;
+            },
+            CaptureOutput::Memory => self.frames.push(frame.clone()),
+        }
+        self.written += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_nth_frame_is_written() {
+        let directory = std::env::temp_dir().join(format!("hadron_capture_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let mut capture = FrameCapture::new(CaptureConfig { enabled: true, every: 2, directory: directory.clone(), max_frames: Some(2), ..CaptureConfig::default() });
+        let frame = CapturedFrame { width: 1, height: 2, format: PixelFormat::Bgra8, pixels: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+        assert_eq!(frame.rgba(), vec![3, 2, 1, 4, 7, 6, 5, 8]);
+
+        let mut captured = Vec::new();
+        for index in 0..8 {
+            if capture.wants_frame() {
+                capture.write(&frame).unwrap();
+                captured.push(index);
+            }
+            capture.presented();
+        }
+        assert_eq!(captured, vec![0, 2]);
+        assert!(capture.is_finished());
+        assert!(directory.join("frame_000001.png").exists());
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
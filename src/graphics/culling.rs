@@ -0,0 +1,241 @@
+//!
+//! GPU culling
+//!
+//! Frustum culling as a compute pre-pass. Every instance is a bounding sphere and the indexed draw that renders it;
+//! `GpuCulling` tests each against the view's frustum and appends the draws of visible ones to an indirect buffer,
+//! counting them in a separate buffer so the graphics pass draws exactly the survivors with one
+//! `vkCmdDrawIndexedIndirectCount`. The pass is a compute queue pass in the render graph, which orders it against the
+//! draw and inserts the barrier between them. `cull_instances` does the same on the CPU
+//!
+
+use ash::vk;
+
+use crate::system::transform::Transform;
+
+use super::{camera::Camera, capabilities::{self, Feature}, render_graph::{Access, PassId, QueueKind, RenderGraph, ResourceId}};
+
+/// Planes of a view frustum facing inwards, `[normal, distance]` with points inside when `normal . p + distance >= 0`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frustum {
+    pub planes: [[f32; 4]; 6],
+}
+
+/// A cullable instance, laid out to match the culling shader's storage buffer
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CullInstance {
+    pub center: [f32; 3],
+    pub radius: f32,
+    pub index_count: u32,
+    pub first_index: u32,
+    pub vertex_offset: i32,
+    /// Passed to the draw as its first instance, for the vertex shader to find the instance's data
+    pub instance: u32,
+}
+
+/// Push constants of the culling shader
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CullParams {
+    pub planes: [[f32; 4]; 6],
+    pub count: u32,
+}
+
+/// The compute pipeline compacting visible `CullInstance`s into indirect draws
+pub(crate) struct GpuCulling {
+    set_layout: vk::DescriptorSetLayout,
+    layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+}
+
+// Impls
+
+impl Frustum {
+    /// The frustum of `camera` placed at `transform`, drawing to a surface `aspect` times wider than it's tall
+    pub fn new(camera: &Camera, transform: &Transform, aspect: f64) -> Self {
+        let dot = |a: [f64; 3], b: [f64; 3]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+        let position = transform.translation;
+        let forward = transform.rotate([0.0, 0.0, -1.0]);
+        let right = transform.rotate([1.0, 0.0, 0.0]);
+        let up = transform.rotate([0.0, 1.0, 0.0]);
+        let vertical = (camera.fov_y * 0.5).tan();
+        let horizontal = vertical * aspect;
+
+        let plane = |normal: [f64; 3], point: [f64; 3]| {
+            let length = dot(normal, normal).sqrt();
+            let normal = normal.map(|v| v / length);
+            [normal[0] as f32, normal[1] as f32, normal[2] as f32, -dot(normal, point) as f32]
+        };
+        let along = |scale: f64| std::array::from_fn(|i| position[i] + forward[i] * scale);
+        let side = |axis: [f64; 3], sign: f64, extent: f64| std::array::from_fn(|i| forward[i] * extent + axis[i] * sign);
+
+        Frustum {
+            planes: [
+                plane(forward, along(camera.near)),
+                plane(forward.map(|v| -v), along(camera.far)),
+                plane(side(right, 1.0, horizontal), position),
+                plane(side(right, -1.0, horizontal), position),
+                plane(side(up, 1.0, vertical), position),
+                plane(side(up, -1.0, vertical), position),
+            ],
+        }
+    }
+
+    pub fn contains_sphere(&self, center: [f32; 3], radius: f32) -> bool {
+        self.planes.iter().all(|plane| plane[0] * center[0] + plane[1] * center[1] + plane[2] * center[2] + plane[3] >= -radius)
+    }
+}
+
+impl CullParams {
+    pub fn new(frustum: &Frustum, count: u32) -> Self {
+        CullParams { planes: frustum.planes, count }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        // Safety: `CullParams` is `repr(C)` and made only of 4 byte fields, so it has no padding
+        unsafe { std::slice::from_raw_parts(self as *const Self as *const u8, std::mem::size_of::<Self>()) }
+    }
+}
+
+impl GpuCulling {
+    pub(crate) fn new(device: &ash::Device) -> Result<Self, vk::Result> {
+        let bindings: Vec<_> = (0..3).map(|binding| vk::DescriptorSetLayoutBinding::builder()
+            .binding(binding)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::COMPUTE)
+            .build()).collect();
+        let set_layout = unsafe { device.create_descriptor_set_layout(&vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings), None)? };
+
+        let push_constants = [vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: std::mem::size_of::<CullParams>() as u32,
+        }];
+        let set_layouts = [set_layout];
+        let layout_info = vk::PipelineLayoutCreateInfo::builder().set_layouts(&set_layouts).push_constant_ranges(&push_constants);
+        let layout = match unsafe { device.create_pipeline_layout(&layout_info, None) } {
+            Ok(layout) => layout,
+            Err(error) => {
+                unsafe { device.destroy_descriptor_set_layout(set_layout, None) };
+                return Err(error)
+            },
+        };
+
+        let shader_info = vk::ShaderModuleCreateInfo::builder()
+            .code(vk_shader_macros::include_glsl!("src/graphics/culling.comp", kind: comp));
+        let shader = unsafe { device.create_shader_module(&shader_info, None) };
+        let pipeline = shader.and_then(|shader| {
+            let name = std::ffi::CString::new("main").unwrap();
+            let stage = vk::PipelineShaderStageCreateInfo::builder().stage(vk::ShaderStageFlags::COMPUTE).module(shader).name(&name);
+            let pipeline_info = [vk::ComputePipelineCreateInfo::builder().stage(*stage).layout(layout).build()];
+            let pipelines = unsafe { device.create_compute_pipelines(vk::PipelineCache::null(), &pipeline_info, None) };
+            unsafe { device.destroy_shader_module(shader, None) };
+            pipelines.map(|pipelines| pipelines[0]).map_err(|(_, error)| error)
+        });
+
+        match pipeline {
+            Ok(pipeline) => Ok(GpuCulling { set_layout, layout, pipeline }),
+            Err(error) => {
+                unsafe {
+                    device.destroy_pipeline_layout(layout, None);
+                    device.destroy_descriptor_set_layout(set_layout, None);
+                }
+                Err(error)
+            },
+        }
+    }
+
+    /// Layout of the set holding the instance, draw command and count storage buffers in bindings 0 to 2
+    pub(crate) fn set_layout(&self) -> vk::DescriptorSetLayout {
+        self.set_layout
+    }
+
+    /// Records culling `params.count` instances from the buffers bound in `set`, first zeroing the count at
+    /// `count_offset` in `count_buffer`. Other counts in the buffer are left alone, so views can share it
+    pub(crate) fn dispatch(&self, device: &ash::Device, command_buffer: vk::CommandBuffer, set: vk::DescriptorSet, count_buffer: vk::Buffer, count_offset: u64, params: &CullParams) {
+        let count_size = std::mem::size_of::<u32>() as u64;
+        let reset = vk::BufferMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .buffer(count_buffer)
+            .offset(count_offset)
+            .size(count_size)
+            .build();
+        unsafe {
+            device.cmd_fill_buffer(command_buffer, count_buffer, count_offset, count_size, 0);
+            device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TRANSFER, vk::PipelineStageFlags::COMPUTE_SHADER, vk::DependencyFlags::empty(), &[], &[reset], &[]);
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.pipeline);
+            device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE, self.layout, 0, &[set], &[]);
+            device.cmd_push_constants(command_buffer, self.layout, vk::ShaderStageFlags::COMPUTE, 0, params.as_bytes());
+            device.cmd_dispatch(command_buffer, params.count.div_ceil(64), 1, 1);
+        }
+    }
+
+    pub(crate) unsafe fn destroy(self, device: &ash::Device) {
+        device.destroy_pipeline(self.pipeline, None);
+        device.destroy_pipeline_layout(self.layout, None);
+        device.destroy_descriptor_set_layout(self.set_layout, None);
+    }
+}
+
+/// The draws of every instance inside `frustum`, in the form the culling shader writes them
+pub fn cull_instances(frustum: &Frustum, instances: &[CullInstance]) -> Vec<vk::DrawIndexedIndirectCommand> {
+    instances.iter()
+        .filter(|instance| frustum.contains_sphere(instance.center, instance.radius))
+        .map(|instance| vk::DrawIndexedIndirectCommand {
+            index_count: instance.index_count,
+            instance_count: 1,
+            first_index: instance.first_index,
+            vertex_offset: instance.vertex_offset,
+            first_instance: instance.instance,
+        })
+        .collect()
+}
+
+/// Whether to cull with the compute pass rather than `cull_instances`, by the renderer's capabilities
+pub fn culls_on_gpu() -> bool {
+    capabilities::supports(Feature::GpuCulling)
+}
+
+/// Adds the culling pass to `graph` on the compute queue. The pass drawing the result should read `commands` and
+/// `count` as `Access::IndirectBuffer`
+pub fn add_culling_pass(graph: &mut RenderGraph, instances: ResourceId, commands: ResourceId, count: ResourceId) -> PassId {
+    graph.add_pass("gpu culling", QueueKind::Compute)
+        .reads(instances, Access::StorageRead)
+        .writes(commands, Access::StorageWrite)
+        .writes(count, Access::StorageWrite)
+        .id()
+}
+
+/// Draws the commands the culling pass wrote at `commands_offset`, as many as the count at `count_offset` but at most
+/// `max_draws`, which mustn't be over the device's `maxDrawIndirectCount`
+pub(crate) fn draw_culled(device: &ash::Device, command_buffer: vk::CommandBuffer, commands: vk::Buffer, commands_offset: u64, count: vk::Buffer, count_offset: u64, max_draws: u32) {
+    let stride = std::mem::size_of::<vk::DrawIndexedIndirectCommand>() as u32;
+    unsafe { device.cmd_draw_indexed_indirect_count(command_buffer, commands, commands_offset, count, count_offset, max_draws, stride) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frustum_culls_outside_instances() {
+        // Looking down -Z from the origin, turned a quarter to face -X
+        let camera = Camera { fov_y: 90f64.to_radians(), near: 0.1, far: 100.0, ..Default::default() };
+        let frustum = Frustum::new(&camera, &Transform::from_yaw(std::f64::consts::FRAC_PI_2), 1.0);
+        let instance = |center: [f32; 3], instance: u32| CullInstance { center, radius: 1.0, index_count: 3, instance, ..Default::default() };
+        let instances = [
+            instance([-10.0, 0.0, 0.0], 0),
+            instance([10.0, 0.0, 0.0], 1),
+            instance([-10.0, 10.5, 0.0], 2),
+            instance([-200.0, 0.0, 0.0], 3),
+            instance([2.0, 0.0, 0.0], 4),
+        ];
+
+        let visible: Vec<u32> = cull_instances(&frustum, &instances).iter().map(|draw| draw.first_instance).collect();
+        assert_eq!(visible, vec![0, 2]);
+    }
+}
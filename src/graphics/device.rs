@@ -0,0 +1,87 @@
+//!
+//! Shared device
+//!
+//! The logical device and its queues, shared between threads behind an `Arc`. Creating and destroying objects through
+//! the device is already thread safe, but Vulkan requires queue access to be externally synchronized, so each queue
+//! sits behind its own lock and submissions and presents go through `with_queue`. Command pools are externally
+//! synchronized too, so every thread recording or uploading creates its own
+//!
+
+use std::{ops::Deref, sync::{Mutex, MutexGuard}};
+
+use ash::vk;
+
+/// A device queue and the family it belongs to
+struct DeviceQueue {
+    family: u32,
+    queue: Mutex<vk::Queue>,
+}
+
+pub struct SharedDevice {
+    device: ash::Device,
+    queues: Vec<DeviceQueue>,
+}
+
+// Impls
+
+impl Deref for SharedDevice {
+    type Target = ash::Device;
+
+    fn deref(&self) -> &Self::Target {
+        &self.device
+    }
+}
+
+impl SharedDevice {
+    /// Takes ownership of `device`, fetching the first queue of each family in `families`
+    pub(crate) fn new(device: ash::Device, families: &[u32]) -> Self {
+        let queues = families.iter().map(|family| DeviceQueue {
+            family: *family,
+            queue: Mutex::new(unsafe { device.get_device_queue(*family, 0) }),
+        }).collect();
+        SharedDevice { device, queues }
+    }
+
+    pub fn queue_count(&self) -> usize {
+        self.queues.len()
+    }
+
+    pub fn queue_family(&self, index: usize) -> Option<u32> {
+        self.queues.get(index).map(|queue| queue.family)
+    }
+
+    /// Runs `f` with exclusive access to the queue at `index`, `None` if there's no such queue
+    pub fn with_queue<R>(&self, index: usize, f: impl FnOnce(vk::Queue) -> R) -> Option<R> {
+        let queue = self.queues.get(index)?.queue.lock().expect("device queue poisoned");
+        Some(f(*queue))
+    }
+
+    pub fn submit(&self, index: usize, submits: &[vk::SubmitInfo], fence: vk::Fence) -> Result<(), vk::Result> {
+        self.with_queue(index, |queue| unsafe { self.device.queue_submit(queue, submits, fence) })
+            .unwrap_or(Err(vk::Result::ERROR_INITIALIZATION_FAILED))
+    }
+
+    /// Waits for the whole device to go idle, holding every queue while it does
+    pub fn wait_idle(&self) -> Result<(), vk::Result> {
+        let _queues: Vec<MutexGuard<vk::Queue>> = self.queues.iter().map(|queue| queue.queue.lock().expect("device queue poisoned")).collect();
+        unsafe { self.device.device_wait_idle() }
+    }
+
+    /// Destroys the device. Nothing else may be using it, which owning the only `Arc` to it guarantees
+    pub(crate) unsafe fn destroy(self) {
+        self.device.destroy_device(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_device_crosses_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<std::sync::Arc<SharedDevice>>();
+        assert_send_sync::<crate::graphics::resources::Buffer>();
+        assert_send_sync::<crate::graphics::resources::Framebuffer>();
+    }
+}
@@ -0,0 +1,162 @@
+//!
+//! Report of every physical device on the system
+//!
+//! Built once while the renderer initializes and logged as state, then kept around so diagnostics and device override
+//! UIs can query it at runtime without touching Vulkan
+//!
+
+use std::sync::RwLock;
+
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+
+use crate::debug::log;
+
+static DEVICE_REPORT: Lazy<RwLock<Option<DeviceReport>>> = Lazy::new(|| RwLock::new(None));
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    Discrete,
+    Integrated,
+    Virtual,
+    Cpu,
+    Other,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MemoryHeapInfo {
+    pub size: u64,
+    pub device_local: bool,
+    /// Replicated across every device in a device group
+    pub multi_instance: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QueueFamilyReport {
+    pub index: u32,
+    pub queue_count: u32,
+    pub graphics: bool,
+    pub compute: bool,
+    pub transfer: bool,
+    pub sparse_binding: bool,
+    /// Can present to the window surface
+    pub present: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+    /// Position in the driver's enumeration order
+    pub index: usize,
+    pub name: String,
+    pub device_type: DeviceType,
+    pub vendor_id: u32,
+    pub vendor: String,
+    pub device_id: u32,
+    /// Formatted as major.minor.patch
+    pub api_version: String,
+    pub driver_version: u32,
+    pub max_image_dimension_2d: u32,
+    pub memory_heaps: Vec<MemoryHeapInfo>,
+    pub queue_families: Vec<QueueFamilyReport>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct DeviceReport {
+    pub devices: Vec<DeviceInfo>,
+    /// Device groups as indices into `devices`, any group with more than one member can be driven as a single device
+    pub device_groups: Vec<Vec<usize>>,
+    /// Index of the device the renderer chose
+    pub selected: Option<usize>,
+}
+
+// Impls
+
+impl DeviceInfo {
+    /// Sum of the device local heaps, a rough measure of dedicated video memory
+    pub fn device_local_memory(&self) -> u64 {
+        self.memory_heaps.iter().filter(|heap| heap.device_local).map(|heap| heap.size).sum()
+    }
+
+    pub fn can_present(&self) -> bool {
+        self.queue_families.iter().any(|family| family.present)
+    }
+}
+
+impl DeviceReport {
+    pub fn selected_device(&self) -> Option<&DeviceInfo> {
+        self.selected.and_then(|index| self.devices.get(index))
+    }
+
+    /// Finds a device by case insensitive substring of its name, for device override settings
+    pub fn find(&self, name: &str) -> Option<&DeviceInfo> {
+        let name = name.to_lowercase();
+        self.devices.iter().find(|device| device.name.to_lowercase().contains(&name))
+    }
+
+    /// Groups with more than one physical device
+    pub fn multi_device_groups(&self) -> impl Iterator<Item = &Vec<usize>> {
+        self.device_groups.iter().filter(|group| group.len() > 1)
+    }
+}
+
+/// Human readable name for a PCI vendor id
+pub fn vendor_name(vendor_id: u32) -> &'static str {
+    match vendor_id {
+        0x1002 => "AMD",
+        0x1010 => "ImgTec",
+        0x10DE => "NVIDIA",
+        0x13B5 => "ARM",
+        0x5143 => "Qualcomm",
+        0x8086 => "Intel",
+        0x10005 => "Mesa",
+        _ => "unknown",
+    }
+}
+
+/// The report built when the renderer initialized, `None` before that
+pub fn device_report() -> Option<DeviceReport> {
+    DEVICE_REPORT.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+}
+
+/// Logs the report and makes it available through `device_report`
+pub(crate) fn publish(report: DeviceReport) {
+    log::get().state("physical devices", &report);
+    *DEVICE_REPORT.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(report);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(index: usize, name: &str, heaps: &[(u64, bool)]) -> DeviceInfo {
+        DeviceInfo {
+            index,
+            name: String::from(name),
+            device_type: DeviceType::Discrete,
+            vendor_id: 0x10DE,
+            vendor: String::from(vendor_name(0x10DE)),
+            device_id: 0,
+            api_version: String::from("1.3.0"),
+            driver_version: 0,
+            max_image_dimension_2d: 16384,
+            memory_heaps: heaps.iter().map(|(size, device_local)| MemoryHeapInfo { size: *size, device_local: *device_local, multi_instance: false }).collect(),
+            queue_families: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn report_queries_and_round_trips() {
+        let report = DeviceReport {
+            devices: vec![device(0, "Integrated Thing", &[(1 << 30, false)]), device(1, "GeForce Test", &[(8 << 30, true), (1 << 30, false)])],
+            device_groups: vec![vec![0], vec![1]],
+            selected: Some(1),
+        };
+
+        assert_eq!(report.selected_device().unwrap().device_local_memory(), 8 << 30);
+        assert_eq!(report.find("geforce").map(|d| d.index), Some(1));
+        assert_eq!(report.multi_device_groups().count(), 0);
+
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(serde_json::from_str::<DeviceReport>(&json).unwrap(), report);
+    }
+}
@@ -0,0 +1,296 @@
+//!
+//! Vulkan environment dump
+//!
+//! With `dump_vulkan_environment` set in the renderer config, startup collects everything the loader and drivers
+//! report: instance extensions and layers, and for each physical device its properties, full limits, features,
+//! extensions, memory types and queue family table. It's logged as state under the "vulkan" topic, so a log attached to
+//! a bug report carries the exact GPU environment, and kept for `vulkan_environment` to return at runtime. It's opt-in
+//! because the dump runs to several hundred entries per device
+//!
+
+use std::{collections::BTreeMap, ffi::CStr, os::raw::c_char, sync::RwLock};
+
+use ash::vk;
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::{debug::log, graphics::device_report::DeviceType};
+
+static VULKAN_ENVIRONMENT: Lazy<RwLock<Option<VulkanEnvironment>>> = Lazy::new(|| RwLock::new(None));
+
+/// A named set of values, keyed by the field names of the Vulkan struct they were read from
+pub type FieldTable = BTreeMap<String, Value>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExtensionInfo {
+    pub name: String,
+    pub spec_version: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LayerInfo {
+    pub name: String,
+    pub description: String,
+    /// Formatted as major.minor.patch
+    pub spec_version: String,
+    pub implementation_version: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MemoryTypeInfo {
+    pub heap_index: u32,
+    pub flags: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QueueFamilyInfo {
+    pub index: u32,
+    pub queue_count: u32,
+    pub flags: String,
+    pub timestamp_valid_bits: u32,
+    pub min_image_transfer_granularity: [u32; 3],
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeviceEnvironment {
+    /// Position in the driver's enumeration order, matching the device report
+    pub index: usize,
+    pub name: String,
+    pub device_type: DeviceType,
+    pub vendor_id: u32,
+    pub device_id: u32,
+    /// Formatted as major.minor.patch
+    pub api_version: String,
+    pub driver_version: u32,
+    pub pipeline_cache_uuid: String,
+    pub extensions: Vec<ExtensionInfo>,
+    pub limits: FieldTable,
+    pub sparse_properties: FieldTable,
+    pub features: FieldTable,
+    pub memory_types: Vec<MemoryTypeInfo>,
+    pub memory_heaps: Vec<u64>,
+    pub queue_families: Vec<QueueFamilyInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VulkanEnvironment {
+    /// Highest instance version the loader supports
+    pub loader_version: String,
+    pub instance_extensions: Vec<ExtensionInfo>,
+    pub instance_layers: Vec<LayerInfo>,
+    pub devices: Vec<DeviceEnvironment>,
+}
+
+/// Builds a `FieldTable` from the named fields of `source`, each converted to a json value with `convert`
+macro_rules! field_table {
+    ($source:expr, $convert:expr; $($field:ident),* $(,)?) => {
+        FieldTable::from([$((String::from(stringify!($field)), $convert($source.$field))),*])
+    };
+}
+
+// Impls
+
+impl VulkanEnvironment {
+    pub fn device(&self, name: &str) -> Option<&DeviceEnvironment> {
+        let name = name.to_lowercase();
+        self.devices.iter().find(|device| device.name.to_lowercase().contains(&name))
+    }
+}
+
+impl DeviceEnvironment {
+    pub fn supports_extension(&self, name: &str) -> bool {
+        self.extensions.iter().any(|extension| extension.name == name)
+    }
+}
+
+/// The environment dumped at startup, `None` unless the dump was enabled
+pub fn vulkan_environment() -> Option<VulkanEnvironment> {
+    VULKAN_ENVIRONMENT.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+}
+
+/// Logs `environment` and makes it available through `vulkan_environment`
+pub(crate) fn publish(environment: VulkanEnvironment) {
+    log::get().with_topic("vulkan").state("vulkan environment", &environment);
+    *VULKAN_ENVIRONMENT.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(environment);
+}
+
+/// Queries the loader and every physical device for the full environment
+pub(crate) fn collect(entry: &ash::Entry, instance: &ash::Instance) -> Result<VulkanEnvironment, vk::Result> {
+    let loader_version = entry.try_enumerate_instance_version()?.unwrap_or(vk::API_VERSION_1_0);
+    let instance_extensions = entry.enumerate_instance_extension_properties(None)?.iter().map(extension_info).collect();
+    let instance_layers = entry.enumerate_instance_layer_properties()?.iter()
+        .map(|layer| LayerInfo {
+            name: c_string(&layer.layer_name),
+            description: c_string(&layer.description),
+            spec_version: version_string(layer.spec_version),
+            implementation_version: layer.implementation_version,
+        })
+        .collect();
+
+    let physical_devices = unsafe { instance.enumerate_physical_devices()? };
+    let mut devices = Vec::with_capacity(physical_devices.len());
+    for (index, &device) in physical_devices.iter().enumerate() {
+        let properties = unsafe { instance.get_physical_device_properties(device) };
+        let features = unsafe { instance.get_physical_device_features(device) };
+        let memory = unsafe { instance.get_physical_device_memory_properties(device) };
+        let queue_families = unsafe { instance.get_physical_device_queue_family_properties(device) };
+        let extensions = unsafe { instance.enumerate_device_extension_properties(device)? };
+
+        devices.push(DeviceEnvironment {
+            index,
+            name: c_string(&properties.device_name),
+            device_type: device_type(properties.device_type),
+            vendor_id: properties.vendor_id,
+            device_id: properties.device_id,
+            api_version: version_string(properties.api_version),
+            driver_version: properties.driver_version,
+            pipeline_cache_uuid: properties.pipeline_cache_uuid.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            extensions: extensions.iter().map(extension_info).collect(),
+            limits: limits_table(&properties.limits),
+            sparse_properties: field_table!(properties.sparse_properties, |value: vk::Bool32| Value::from(value == vk::TRUE);
+                residency_standard2_d_block_shape, residency_standard2_d_multisample_block_shape,
+                residency_standard3_d_block_shape, residency_aligned_mip_size, residency_non_resident_strict,
+            ),
+            features: features_table(&features),
+            memory_types: memory.memory_types[..memory.memory_type_count as usize].iter()
+                .map(|memory_type| MemoryTypeInfo { heap_index: memory_type.heap_index, flags: format!("{:?}", memory_type.property_flags) })
+                .collect(),
+            memory_heaps: memory.memory_heaps[..memory.memory_heap_count as usize].iter().map(|heap| heap.size).collect(),
+            queue_families: queue_families.iter().enumerate()
+                .map(|(family_index, family)| QueueFamilyInfo {
+                    index: family_index as u32,
+                    queue_count: family.queue_count,
+                    flags: format!("{:?}", family.queue_flags),
+                    timestamp_valid_bits: family.timestamp_valid_bits,
+                    min_image_transfer_granularity: [
+                        family.min_image_transfer_granularity.width,
+                        family.min_image_transfer_granularity.height,
+                        family.min_image_transfer_granularity.depth,
+                    ],
+                })
+                .collect(),
+        });
+    }
+
+    Ok(VulkanEnvironment { loader_version: version_string(loader_version), instance_extensions, instance_layers, devices })
+}
+
+/// Every field of `VkPhysicalDeviceLimits`, sample counts as their raw flag bits
+pub(crate) fn limits_table(limits: &vk::PhysicalDeviceLimits) -> FieldTable {
+    let mut table = field_table!(limits, |value| serde_json::json!(value);
+        max_image_dimension1_d, max_image_dimension2_d, max_image_dimension3_d, max_image_dimension_cube,
+        max_image_array_layers, max_texel_buffer_elements, max_uniform_buffer_range, max_storage_buffer_range,
+        max_push_constants_size, max_memory_allocation_count, max_sampler_allocation_count, buffer_image_granularity,
+        sparse_address_space_size, max_bound_descriptor_sets, max_per_stage_descriptor_samplers,
+        max_per_stage_descriptor_uniform_buffers, max_per_stage_descriptor_storage_buffers,
+        max_per_stage_descriptor_sampled_images, max_per_stage_descriptor_storage_images,
+        max_per_stage_descriptor_input_attachments, max_per_stage_resources, max_descriptor_set_samplers,
+        max_descriptor_set_uniform_buffers, max_descriptor_set_uniform_buffers_dynamic,
+        max_descriptor_set_storage_buffers, max_descriptor_set_storage_buffers_dynamic,
+        max_descriptor_set_sampled_images, max_descriptor_set_storage_images, max_descriptor_set_input_attachments,
+        max_vertex_input_attributes, max_vertex_input_bindings, max_vertex_input_attribute_offset,
+        max_vertex_input_binding_stride, max_vertex_output_components, max_tessellation_generation_level,
+        max_tessellation_patch_size, max_tessellation_control_per_vertex_input_components,
+        max_tessellation_control_per_vertex_output_components, max_tessellation_control_per_patch_output_components,
+        max_tessellation_control_total_output_components, max_tessellation_evaluation_input_components,
+        max_tessellation_evaluation_output_components, max_geometry_shader_invocations, max_geometry_input_components,
+        max_geometry_output_components, max_geometry_output_vertices, max_geometry_total_output_components,
+        max_fragment_input_components, max_fragment_output_attachments, max_fragment_dual_src_attachments,
+        max_fragment_combined_output_resources, max_compute_shared_memory_size, max_compute_work_group_count,
+        max_compute_work_group_invocations, max_compute_work_group_size, sub_pixel_precision_bits,
+        sub_texel_precision_bits, mipmap_precision_bits, max_draw_indexed_index_value, max_draw_indirect_count,
+        max_sampler_lod_bias, max_sampler_anisotropy, max_viewports, max_viewport_dimensions, viewport_bounds_range,
+        viewport_sub_pixel_bits, min_memory_map_alignment, min_texel_buffer_offset_alignment,
+        min_uniform_buffer_offset_alignment, min_storage_buffer_offset_alignment, min_texel_offset, max_texel_offset,
+        min_texel_gather_offset, max_texel_gather_offset, min_interpolation_offset, max_interpolation_offset,
+        sub_pixel_interpolation_offset_bits, max_framebuffer_width, max_framebuffer_height, max_framebuffer_layers,
+        max_color_attachments, max_sample_mask_words, timestamp_period, max_clip_distances, max_cull_distances,
+        max_combined_clip_and_cull_distances, discrete_queue_priorities, point_size_range, line_width_range,
+        point_size_granularity, line_width_granularity, optimal_buffer_copy_offset_alignment,
+        optimal_buffer_copy_row_pitch_alignment, non_coherent_atom_size,
+    );
+    table.append(&mut field_table!(limits, |value: vk::SampleCountFlags| Value::from(value.as_raw());
+        framebuffer_color_sample_counts, framebuffer_depth_sample_counts, framebuffer_stencil_sample_counts,
+        framebuffer_no_attachments_sample_counts, sampled_image_color_sample_counts,
+        sampled_image_integer_sample_counts, sampled_image_depth_sample_counts, sampled_image_stencil_sample_counts,
+        storage_image_sample_counts,
+    ));
+    table.append(&mut field_table!(limits, |value: vk::Bool32| Value::from(value == vk::TRUE);
+        timestamp_compute_and_graphics, strict_lines, standard_sample_locations,
+    ));
+    table
+}
+
+/// Every field of `VkPhysicalDeviceFeatures`
+pub(crate) fn features_table(features: &vk::PhysicalDeviceFeatures) -> FieldTable {
+    field_table!(features, |value: vk::Bool32| Value::from(value == vk::TRUE);
+        robust_buffer_access, full_draw_index_uint32, image_cube_array, independent_blend, geometry_shader,
+        tessellation_shader, sample_rate_shading, dual_src_blend, logic_op, multi_draw_indirect,
+        draw_indirect_first_instance, depth_clamp, depth_bias_clamp, fill_mode_non_solid, depth_bounds, wide_lines,
+        large_points, alpha_to_one, multi_viewport, sampler_anisotropy, texture_compression_etc2,
+        texture_compression_astc_ldr, texture_compression_bc, occlusion_query_precise, pipeline_statistics_query,
+        vertex_pipeline_stores_and_atomics, fragment_stores_and_atomics, shader_tessellation_and_geometry_point_size,
+        shader_image_gather_extended, shader_storage_image_extended_formats, shader_storage_image_multisample,
+        shader_storage_image_read_without_format, shader_storage_image_write_without_format,
+        shader_uniform_buffer_array_dynamic_indexing, shader_sampled_image_array_dynamic_indexing,
+        shader_storage_buffer_array_dynamic_indexing, shader_storage_image_array_dynamic_indexing,
+        shader_clip_distance, shader_cull_distance, shader_float64, shader_int64, shader_int16,
+        shader_resource_residency, shader_resource_min_lod, sparse_binding, sparse_residency_buffer,
+        sparse_residency_image2_d, sparse_residency_image3_d, sparse_residency2_samples, sparse_residency4_samples,
+        sparse_residency8_samples, sparse_residency16_samples, sparse_residency_aliased, variable_multisample_rate,
+        inherited_queries,
+    )
+}
+
+/// Formats a packed Vulkan version as major.minor.patch
+pub(crate) fn version_string(version: u32) -> String {
+    format!("{}.{}.{}", vk::api_version_major(version), vk::api_version_minor(version), vk::api_version_patch(version))
+}
+
+pub(crate) fn device_type(device_type: vk::PhysicalDeviceType) -> DeviceType {
+    match device_type {
+        vk::PhysicalDeviceType::DISCRETE_GPU => DeviceType::Discrete,
+        vk::PhysicalDeviceType::INTEGRATED_GPU => DeviceType::Integrated,
+        vk::PhysicalDeviceType::VIRTUAL_GPU => DeviceType::Virtual,
+        vk::PhysicalDeviceType::CPU => DeviceType::Cpu,
+        _ => DeviceType::Other,
+    }
+}
+
+fn extension_info(extension: &vk::ExtensionProperties) -> ExtensionInfo {
+    ExtensionInfo { name: c_string(&extension.extension_name), spec_version: extension.spec_version }
+}
+
+/// A fixed size, nul terminated string from a Vulkan struct
+fn c_string(chars: &[c_char]) -> String {
+    match chars.iter().position(|c| *c == 0) {
+        Some(_) => unsafe { CStr::from_ptr(chars.as_ptr()) }.to_string_lossy().into_owned(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tables_cover_every_field() {
+        let limits = vk::PhysicalDeviceLimits {
+            max_compute_work_group_size: [1024, 1024, 64],
+            framebuffer_color_sample_counts: vk::SampleCountFlags::TYPE_1 | vk::SampleCountFlags::TYPE_4,
+            strict_lines: vk::TRUE,
+            ..Default::default()
+        };
+        let limits = limits_table(&limits);
+        assert_eq!(limits.len(), 106);
+        assert_eq!(limits["max_compute_work_group_size"], serde_json::json!([1024, 1024, 64]));
+        assert_eq!(limits["framebuffer_color_sample_counts"], Value::from(5));
+        assert_eq!(limits["strict_lines"], Value::from(true));
+
+        let features = features_table(&vk::PhysicalDeviceFeatures { geometry_shader: vk::TRUE, ..Default::default() });
+        assert_eq!(features.len(), 55);
+        assert_eq!(features.values().filter(|enabled| enabled.as_bool() == Some(true)).count(), 1);
+        assert_eq!(version_string(vk::make_api_version(0, 1, 3, 238)), "1.3.238");
+    }
+}
@@ -0,0 +1,37 @@
+//!
+//! Events sent by the renderer so game code can react to interruptions
+//!
+
+use ash::vk;
+
+use crate::extent::Extent2;
+
+/// Why rendering stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PauseReason {
+    /// The driver reported the window surface as lost
+    SurfaceLost,
+    /// The window was destroyed underneath the renderer
+    WindowDestroyed,
+}
+
+/// Rendering stopped while the surface and swapchain are rebuilt, no frames are presented until `RenderingResumed`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderingPaused {
+    pub reason: PauseReason,
+}
+
+/// Rendering restarted after a `RenderingPaused`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderingResumed;
+
+/// The swapchain was rebuilt after the driver reported it suboptimal, like after the window moved to another monitor.
+/// Sent before the first frame on the new swapchain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwapchainRefreshed {
+    pub format: vk::Format,
+    pub color_space: vk::ColorSpaceKHR,
+    pub extent: Extent2,
+    /// The surface's format changed, so the renderpass and pipeline were rebuilt along with it
+    pub format_changed: bool,
+}
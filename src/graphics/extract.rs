@@ -0,0 +1,44 @@
+//!
+//! Extraction
+//!
+//! Runs once a frame after the schedule and pulls what the renderer needs out of the world. Simulation runs at the
+//! fixed tick rate, so entities with `Interpolated` are drawn part way between their last two ticked transforms,
+//! keeping motion smooth when the display refreshes faster than the simulation ticks
+//!
+
+use crate::system::{world::World, time::FixedTime, transform::{self, Transform}};
+
+use super::{camera::{self, Camera}, lod, particles, targets, terrain, tilemap, viewport};
+
+/// World resource holding the view the renderer draws this frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExtractedView {
+    pub camera: Camera,
+    pub transform: Transform,
+    /// How far the frame is between fixed ticks
+    pub alpha: f64,
+}
+
+/// Interpolates transforms to the current frame and extracts the active camera's view, then the level of detail meshes,
+/// particles, terrain tiles and tilemap chunks on the layers it sees, and the views of every other camera
+pub fn extract(world: &World) {
+    let alpha = world.with_resource::<FixedTime, _>(FixedTime::alpha).unwrap_or(1.0);
+    transform::interpolate_transforms(world, alpha);
+
+    let view = camera::active_camera(world).and_then(|entity| {
+        let camera = world.component::<Camera, _>(entity, |camera| *camera)?;
+        let transform = transform::render_transform(world, entity)?;
+        Some(ExtractedView { camera, transform, alpha })
+    });
+    let layers = view.map(|view| view.camera.layers).unwrap_or_default();
+    match view {
+        Some(view) => { world.insert_resource(view); },
+        None => { world.remove_resource::<ExtractedView>(); },
+    }
+    lod::extract_lods(world);
+    particles::extract_particles(world, layers);
+    terrain::extract_terrain(world, layers);
+    tilemap::extract_tilemaps(world, layers);
+    viewport::extract_viewports(world, alpha);
+    targets::extract_targets(world, alpha);
+}
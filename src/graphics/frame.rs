@@ -0,0 +1,865 @@
+//!
+//! Frame recording
+//!
+//! The acquired swapchain image's command buffer is recorded afresh every frame from what extraction left in the world.
+//! The main pass draws each window view in `ExtractedViewports` as its own range of draws, setting the view's viewport
+//! and scissor before them, so split screen views share one render pass into the same image. Each view draws its meshes
+//! and then the frame's particle batches
+//!
+//! The frame's passes are built into a `RenderGraph` every frame, the mesh upload and particle simulation only when
+//! there's something to upload or simulate. Every pass is recorded into the frame's one command buffer in the compiled
+//! order, so of what the graph works out only the barriers between passes apply
+//!
+//! Each view's instances and draws are built in the world's `FrameArena`, so preparing a frame doesn't allocate once
+//! the arena has grown to fit it. Everything the GPU reads that changes per frame goes through the `StagingBelt`. Each
+//! view's instance data is written to it and read straight from it, and meshes are written to it the first frame
+//! they're drawn and copied from it into the device local mesh buffers, where they stay for the renderer's lifetime.
+//! The belt reclaims a frame's writes once that frame's fence has signalled
+//!
+//! Meshes are frustum culled per view. With `GpuCulling` each view's bounding spheres go through the belt to the
+//! culling pass, which writes the draws the main pass issues with one indirect count draw. Views it has no room for,
+//! and every view on devices without it, are culled on the CPU while their draws are built
+//!
+//! Particle batches sample their texture through `Textures`, bindless when the device has descriptor indexing, and
+//! textures drawn for the first time are uploaded in the same pass as meshes
+//!
+
+use std::{collections::HashMap, sync::Arc};
+
+use ash::vk;
+
+use crate::{asset::{self, pipeline::formats::Mesh}, debug::log, extent::Rect, memory::arena::{FrameArena, FrameSlice}, unique::Handle};
+use crate::system::{world::World, transform::Transform};
+
+use super::{audit, bindless::{BindlessSupport, MaterialIndices}, device::SharedDevice, extract::ExtractedView, render, resources::Buffer, surface};
+use super::culling::{self, CullInstance, CullParams, Frustum, GpuCulling};
+use super::{staging::{BeltSlice, StagingBelt}, textures::{self, TextureBinding, Textures}, viewport::{self, ExtractedViewports, ViewportView}};
+use super::particles::{self, EmitterRange, ExtractedParticles, Particle, ParticleCompute, ParticleInstance, SimulationBackend, SimulationParams};
+use super::render_graph::{Access, Barrier, PassId, QueueKind, RenderGraph, ResourceKind};
+
+/// Size of the staging belt, enough for a few frames of instance data and the meshes first drawn in them
+const BELT_CAPACITY: u64 = 16 * 1024 * 1024;
+
+/// Size of the device local buffers every mesh is packed into
+const MESH_VERTEX_CAPACITY: u64 = 32 * 1024 * 1024;
+const MESH_INDEX_CAPACITY: u64 = 16 * 1024 * 1024;
+
+/// Views culled on the GPU per frame, views past this are culled on the CPU
+const CULLED_VIEWS: usize = 8;
+
+/// Draws a view culled on the GPU may have, views with more are culled on the CPU
+const CULLED_DRAWS: usize = 4096;
+
+/// Spacing of each view's count in the count buffers, the largest storage buffer offset alignment a device may ask for
+const CULL_SLOT_ALIGN: u64 = 256;
+
+/// Bytes of each view's draw commands, a multiple of `CULL_SLOT_ALIGN` so every view's commands start aligned
+const CULLED_COMMANDS_SIZE: u64 = CULLED_DRAWS as u64 * std::mem::size_of::<vk::DrawIndexedIndirectCommand>() as u64;
+
+/// Offset of `ViewParams` in the main pass's push constants, they follow the material indices
+pub(crate) const VIEW_PARAMS_OFFSET: u32 = std::mem::size_of::<MaterialIndices>() as u32;
+
+/// A mesh vertex, laid out to match the main pass's per vertex binding
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MeshVertex {
+    pub position: [f32; 3],
+    pub normal: [f32; 3],
+    pub uv: [f32; 2],
+}
+
+/// Per instance data of a mesh draw, laid out to match the main pass's per instance binding
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MeshInstance {
+    /// Column major, placing the mesh relative to the camera so precision doesn't fall off far from the origin
+    pub model: [[f32; 4]; 4],
+}
+
+/// Push constants of the main pass describing the view being drawn
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ViewParams {
+    /// Column major, from positions relative to the camera to clip space
+    pub view_projection: [[f32; 4]; 4],
+    /// The camera's right and up axes, which particle quads are spanned by
+    pub right: [f32; 4],
+    pub up: [f32; 4],
+    /// The camera's translation, subtracted from particle positions
+    pub origin: [f32; 4],
+}
+
+/// The command buffer a frame is recorded into and the framebuffer its main pass draws to
+pub(crate) struct FrameTarget {
+    pub command_buffer: vk::CommandBuffer,
+    pub render_pass: vk::RenderPass,
+    pub framebuffer: vk::Framebuffer,
+    pub extent: vk::Extent2D,
+    pub format: vk::Format,
+    /// Which of the `FRAMES_IN_FLIGHT` frames this is
+    pub frame: usize,
+}
+
+/// Records each frame, owning the staging belt and the mesh buffers and textures it uploads into
+pub(crate) struct FrameRenderer {
+    device: Arc<SharedDevice>,
+    belt: StagingBelt,
+    meshes: MeshBuffers,
+    textures: Textures,
+    /// `None` when particles are simulated on the CPU
+    simulation: Option<ParticleSimulation>,
+    /// `None` when meshes are culled on the CPU
+    culling: Option<MeshCulling>,
+    graph: RenderGraph,
+}
+
+/// The culling pipeline and, per frame in flight, a draw command buffer and a count buffer with a slot for each view
+/// culled on the GPU, along with a descriptor set per slot pointed at them when recording
+struct MeshCulling {
+    culling: GpuCulling,
+    pool: vk::DescriptorPool,
+    /// `CULLED_VIEWS` sets for each frame in flight, one after another
+    sets: Vec<vk::DescriptorSet>,
+    commands: Vec<Buffer>,
+    counts: Vec<Buffer>,
+    max_draw_indirect_count: u32,
+}
+
+/// The simulation pipeline and a descriptor set per frame in flight, pointed at that frame's particles when recording
+struct ParticleSimulation {
+    compute: ParticleCompute,
+    pool: vk::DescriptorPool,
+    sets: Vec<vk::DescriptorSet>,
+}
+
+/// Every mesh drawn so far, packed into one vertex and one index buffer
+struct MeshBuffers {
+    vertices: Buffer,
+    indices: Buffer,
+    vertex_count: u32,
+    index_count: u32,
+    /// `None` for meshes that can never be drawn, being empty or too big for what's left of the buffers
+    uploaded: HashMap<Handle<Mesh>, Option<GpuMesh>>,
+}
+
+/// Where a mesh's vertices and indices sit in the mesh buffers
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct GpuMesh {
+    first_index: u32,
+    index_count: u32,
+    vertex_offset: i32,
+    /// Of the bounding sphere around the mesh's origin
+    radius: f32,
+}
+
+/// A copy out of the belt recorded before the frame's passes
+#[derive(Debug, Clone, Copy)]
+struct BufferCopy {
+    source: BeltSlice,
+    destination: vk::Buffer,
+    offset: u64,
+}
+
+/// A view ready to record, its instance data already in the belt and its draws in the world's frame arena
+struct PreparedView {
+    viewport: viewport::Viewport,
+    params: ViewParams,
+    instances: Option<BeltSlice>,
+    /// Only the first `drawn` are filled in, draws of meshes that aren't uploaded or are outside the view are skipped.
+    /// Empty when the view is culled on the GPU
+    draws: Option<FrameSlice<vk::DrawIndexedIndirectCommand>>,
+    drawn: usize,
+    culled: Option<CulledView>,
+}
+
+/// A view the culling pass picks the draws of, out of its `drawn` instances
+struct CulledView {
+    /// One for each instance, in the belt for the culling pass to read
+    bounds: BeltSlice,
+    frustum: Frustum,
+    /// The view's descriptor set, draw commands and count in the frame's culling buffers
+    slot: usize,
+}
+
+/// The frame's particle batches, their instances one after another in the belt
+struct PreparedParticles {
+    instances: BeltSlice,
+    /// First instance, instance count and texture of each batch
+    batches: Vec<(u32, u32, TextureBinding)>,
+    /// The particles behind the instances and each emitter's range of them, when the simulation pass places them
+    simulation: Option<(BeltSlice, Vec<EmitterRange>)>,
+}
+
+// Impls
+
+impl MeshVertex {
+    /// The per vertex binding meshes are read from
+    pub fn binding_description(binding: u32) -> vk::VertexInputBindingDescription {
+        vk::VertexInputBindingDescription {
+            binding,
+            stride: std::mem::size_of::<MeshVertex>() as u32,
+            input_rate: vk::VertexInputRate::VERTEX,
+        }
+    }
+
+    /// Position at location 0, normal at location 1 and uv at location 2
+    pub fn attribute_descriptions(binding: u32) -> [vk::VertexInputAttributeDescription; 3] {
+        [
+            vk::VertexInputAttributeDescription { location: 0, binding, format: vk::Format::R32G32B32_SFLOAT, offset: 0 },
+            vk::VertexInputAttributeDescription { location: 1, binding, format: vk::Format::R32G32B32_SFLOAT, offset: 12 },
+            vk::VertexInputAttributeDescription { location: 2, binding, format: vk::Format::R32G32_SFLOAT, offset: 24 },
+        ]
+    }
+}
+
+impl MeshInstance {
+    /// `transform` relative to a camera at `origin`
+    pub fn new(transform: &Transform, origin: [f64; 3]) -> Self {
+        let axis = |axis: [f64; 3], scale: f64| {
+            let [x, y, z] = transform.rotate(axis).map(|v| (v * scale) as f32);
+            [x, y, z, 0.0]
+        };
+        let [x, y, z] = std::array::from_fn(|i| (transform.translation[i] - origin[i]) as f32);
+        MeshInstance {
+            model: [
+                axis([1.0, 0.0, 0.0], transform.scale[0]),
+                axis([0.0, 1.0, 0.0], transform.scale[1]),
+                axis([0.0, 0.0, 1.0], transform.scale[2]),
+                [x, y, z, 1.0],
+            ],
+        }
+    }
+
+    /// The per instance binding mesh draws are read from
+    pub fn binding_description(binding: u32) -> vk::VertexInputBindingDescription {
+        vk::VertexInputBindingDescription {
+            binding,
+            stride: std::mem::size_of::<MeshInstance>() as u32,
+            input_rate: vk::VertexInputRate::INSTANCE,
+        }
+    }
+
+    /// The model matrix's columns at locations 3 to 6
+    pub fn attribute_descriptions(binding: u32) -> [vk::VertexInputAttributeDescription; 4] {
+        std::array::from_fn(|column| vk::VertexInputAttributeDescription {
+            location: 3 + column as u32,
+            binding,
+            format: vk::Format::R32G32B32A32_SFLOAT,
+            offset: 16 * column as u32,
+        })
+    }
+}
+
+impl ViewParams {
+    /// Parameters of `view` drawing to a viewport `aspect` times wider than it's tall
+    pub fn new(view: &ExtractedView, aspect: f64) -> Self {
+        let camera = &view.camera;
+        let rows = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]].map(|axis| view.transform.rotate(axis));
+
+        // Vulkan's clip space has Y down and depth from 0 at the near plane to 1 at the far plane
+        let focal = 1.0 / (camera.fov_y * 0.5).tan();
+        let depth = camera.far / (camera.near - camera.far);
+        let projection = [
+            [focal / aspect, 0.0, 0.0, 0.0],
+            [0.0, -focal, 0.0, 0.0],
+            [0.0, 0.0, depth, depth * camera.near],
+            [0.0, 0.0, -1.0, 0.0],
+        ];
+
+        // The view matrix is the inverse of the camera's rotation, its rows are the camera's axes
+        let view_matrix = |row: usize, column: usize| match (row, column) {
+            (3, 3) => 1.0,
+            (3, _) | (_, 3) => 0.0,
+            (row, column) => rows[row][column],
+        };
+        let view_projection = std::array::from_fn(|column| std::array::from_fn(|row| {
+            (0..4).map(|k| projection[row][k] * view_matrix(k, column)).sum::<f64>() as f32
+        }));
+        let vec4 = |[x, y, z]: [f64; 3]| [x as f32, y as f32, z as f32, 0.0];
+        ViewParams { view_projection, right: vec4(rows[0]), up: vec4(rows[1]), origin: vec4(view.transform.translation) }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        // Safety: `ViewParams` is `repr(C)` and made only of `f32`s, so it has no padding
+        unsafe { std::slice::from_raw_parts(self as *const Self as *const u8, std::mem::size_of::<Self>()) }
+    }
+}
+
+impl FrameRenderer {
+    /// Textures are bindless when `bindless` is given, see `GraphicsDevice::bindless`, and meshes are culled on the GPU
+    /// when `gpu_culling` is, see `GraphicsDevice::culls_on_gpu`
+    pub(crate) fn new(instance: &ash::Instance, physical_device: vk::PhysicalDevice, device: &Arc<SharedDevice>, bindless: Option<BindlessSupport>, gpu_culling: bool) -> Result<Self, vk::Result> {
+        let properties = unsafe { instance.get_physical_device_memory_properties(physical_device) };
+        let local = vk::MemoryPropertyFlags::DEVICE_LOCAL;
+        let vertices = Buffer::new(device, &properties, MESH_VERTEX_CAPACITY, vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::TRANSFER_DST, local)?;
+        let indices = Buffer::new(device, &properties, MESH_INDEX_CAPACITY, vk::BufferUsageFlags::INDEX_BUFFER | vk::BufferUsageFlags::TRANSFER_DST, local)?;
+        let mut belt = StagingBelt::new(instance, physical_device, device, BELT_CAPACITY)?;
+        let textures = Textures::new(instance, physical_device, device, bindless, &mut belt)?;
+        let simulation = match SimulationBackend::detect() {
+            SimulationBackend::Compute => Some(ParticleSimulation::new(device)?),
+            SimulationBackend::Cpu => None,
+        };
+        let culling = match gpu_culling {
+            true => Some(MeshCulling::new(instance, physical_device, device)?),
+            false => None,
+        };
+
+        Ok(FrameRenderer {
+            device: device.clone(),
+            belt,
+            meshes: MeshBuffers { vertices, indices, vertex_count: 0, index_count: 0, uploaded: HashMap::new() },
+            textures,
+            simulation,
+            culling,
+            graph: RenderGraph::new(),
+        })
+    }
+
+    /// The descriptors draws select their textures with, which the pipeline layout is built around
+    pub(crate) fn textures(&self) -> &Textures {
+        &self.textures
+    }
+
+    /// Whether particles should be left for the simulation pass to place, see `SimulationBackend::Compute`
+    pub(crate) fn simulates_particles(&self) -> bool {
+        self.simulation.is_some()
+    }
+
+    /// Records the frame's commands into `target`, drawing each of `world`'s window views into its viewport. Without a
+    /// world the image is only cleared. Call once the frame's fence has been waited on and before it's reset, the belt
+    /// reclaims the space of every frame whose fence has signalled first
+    pub(crate) fn record(&mut self, target: &FrameTarget, pipeline: &render::Pipeline, world: Option<&World>) -> Result<(), vk::Result> {
+        self.belt.recall(&self.device);
+
+        let mut copies = Vec::new();
+        let mut culled_views = 0;
+        let views = world.and_then(|world| world.with_resource::<ExtractedViewports, _>(|viewports| {
+            viewports.views.iter()
+                .filter(|view| !view.viewport.rect(target.extent.into()).extent().is_empty())
+                .map(|view| self.prepare_view(world, view, target.extent, &mut copies, &mut culled_views))
+                .collect::<Vec<_>>()
+        })).unwrap_or_default();
+
+        let particles = world.and_then(|world| self.prepare_particles(world));
+        let image_uploads = self.textures.take_uploads();
+
+        self.graph.clear();
+        let image = self.graph.import_resource("swapchain image", ResourceKind::Image {
+            width: target.extent.width,
+            height: target.extent.height,
+            format: target.format,
+        });
+        self.graph.mark_output(image);
+        let vertices = self.graph.import_resource("mesh vertices", ResourceKind::Buffer { size: MESH_VERTEX_CAPACITY });
+        let indices = self.graph.import_resource("mesh indices", ResourceKind::Buffer { size: MESH_INDEX_CAPACITY });
+        let instances = self.graph.import_resource("particle instances", ResourceKind::Buffer {
+            size: particles.as_ref().map_or(0, |particles| particles.instances.size),
+        });
+        // Stands in for every texture, only to order their uploads before the draws sampling them
+        let texture_images = self.graph.import_resource("textures", ResourceKind::Image { width: 0, height: 0, format: vk::Format::R8G8B8A8_SRGB });
+
+        let upload = (!copies.is_empty() || !image_uploads.is_empty()).then(|| self.graph.add_pass("upload", QueueKind::Transfer)
+            .writes(vertices, Access::TransferDst)
+            .writes(indices, Access::TransferDst)
+            .writes(texture_images, Access::TransferDst)
+            .id());
+        let simulation = particles.as_ref().and_then(|particles| particles.simulation.as_ref()).map(|(source, _)| {
+            let source = self.graph.import_resource("particles", ResourceKind::Buffer { size: source.size });
+            particles::add_simulation_pass(&mut self.graph, source, instances)
+        });
+        let culling = (culled_views > 0).then(|| {
+            let bounds = views.iter().filter_map(|view| view.culled.as_ref()).map(|culled| culled.bounds.size).sum();
+            let bounds = self.graph.import_resource("mesh bounds", ResourceKind::Buffer { size: bounds });
+            let commands = self.graph.import_resource("draw commands", ResourceKind::Buffer { size: CULLED_VIEWS as u64 * CULLED_COMMANDS_SIZE });
+            let counts = self.graph.import_resource("draw counts", ResourceKind::Buffer { size: CULLED_VIEWS as u64 * CULL_SLOT_ALIGN });
+            (culling::add_culling_pass(&mut self.graph, bounds, commands, counts), commands, counts)
+        });
+        let main = self.graph.add_pass("main", QueueKind::Graphics)
+            .reads(vertices, Access::VertexBuffer)
+            .reads(indices, Access::IndexBuffer)
+            .reads(instances, Access::VertexBuffer)
+            .reads(texture_images, Access::ShaderRead)
+            .writes(image, Access::ColorAttachment);
+        if let Some((_, commands, counts)) = culling {
+            main.reads(commands, Access::IndirectBuffer).reads(counts, Access::IndirectBuffer);
+        }
+        let culling = culling.map(|(pass, ..)| pass);
+        let compiled = self.graph.compile().map_err(|error| {
+            log::get().with_topic("renderer").error(format!("unable to compile the frame graph: {}", error));
+            vk::Result::ERROR_UNKNOWN
+        })?;
+
+        let device = &*self.device;
+        let command_buffer = target.command_buffer;
+        let begin_info = vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        audit::check(unsafe { device.begin_command_buffer(command_buffer, &begin_info) }, "vkBeginCommandBuffer")?;
+        for pass in compiled.order() {
+            record_barriers(device, command_buffer, compiled.barriers(), *pass);
+            match *pass {
+                pass if Some(pass) == upload => {
+                    record_copies(device, command_buffer, &copies);
+                    textures::record_uploads(device, command_buffer, &image_uploads);
+                },
+                pass if Some(pass) == culling => {
+                    if let Some(culling) = &self.culling {
+                        culling.record(device, command_buffer, target.frame, &views);
+                    }
+                },
+                pass if Some(pass) == simulation => {
+                    if let (Some(simulation), Some(particles)) = (&self.simulation, &particles) {
+                        simulation.record(device, command_buffer, target.frame, particles);
+                    }
+                },
+                _ => self.record_main_pass(target, pipeline, world, &views, particles.as_ref()),
+            }
+        }
+        audit::check(unsafe { device.end_command_buffer(command_buffer) }, "vkEndCommandBuffer")
+    }
+
+    /// Holds the frame's belt writes until `fence`, the one the frame was submitted with, signals
+    pub(crate) fn finish_frame(&mut self, fence: vk::Fence) {
+        self.belt.finish_frame(fence);
+    }
+
+    /// Nothing may be in flight, the buffers are destroyed as they drop
+    pub(crate) unsafe fn destroy(self) {
+        self.belt.destroy(&self.device);
+        self.textures.destroy();
+        if let Some(simulation) = self.simulation {
+            simulation.destroy(&self.device);
+        }
+        if let Some(culling) = self.culling {
+            culling.destroy(&self.device);
+        }
+    }
+
+    /// Draws each view's meshes and then the frame's particles into its viewport
+    fn record_main_pass(&self, target: &FrameTarget, pipeline: &render::Pipeline, world: Option<&World>, views: &[PreparedView], particles: Option<&PreparedParticles>) {
+        let device = &*self.device;
+        let command_buffer = target.command_buffer;
+        let clear_values = [vk::ClearValue { color: vk::ClearColorValue { float32: [0.0, 0.0, 0.0, 1.0] } }];
+        let render_pass_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(target.render_pass)
+            .framebuffer(target.framebuffer)
+            .render_area(Rect::from_extent(target.extent).into())
+            .clear_values(&clear_values);
+
+        // Both pipelines share a layout and dynamic viewport, so a view's state holds across binding either. The push range
+        // is shared with the material indices, so the view's parameters are pushed to the same stages
+        let view_stages = MaterialIndices::push_constant_range().stage_flags;
+        let draw_views = |arena: Option<&FrameArena>| unsafe {
+            for view in views {
+                device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline.pipeline());
+                viewport::set_viewport(device, command_buffer, &view.viewport, target.extent);
+                device.cmd_push_constants(command_buffer, pipeline.layout(), view_stages, VIEW_PARAMS_OFFSET, view.params.as_bytes());
+
+                if let Some(instances) = view.instances {
+                    device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.meshes.vertices.handle(), instances.buffer], &[0, instances.offset]);
+                    device.cmd_bind_index_buffer(command_buffer, self.meshes.indices.handle(), 0, vk::IndexType::UINT32);
+                    match (&self.culling, &view.culled) {
+                        (Some(culling), Some(culled)) => culling.draw(device, command_buffer, target.frame, culled.slot, view.drawn as u32),
+                        _ => {
+                            let draws = arena.zip(view.draws).and_then(|(arena, draws)| arena.get(&draws)).unwrap_or_default();
+                            for draw in draws.iter().take(view.drawn) {
+                                device.cmd_draw_indexed(command_buffer, draw.index_count, draw.instance_count, draw.first_index, draw.vertex_offset, draw.first_instance);
+                            }
+                        },
+                    }
+                }
+
+                let Some(particles) = particles else { continue };
+                device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline.particles());
+                for (first, count, texture) in &particles.batches {
+                    self.textures.select(command_buffer, pipeline.layout(), *texture);
+                    let offset = particles.instances.offset + u64::from(*first) * std::mem::size_of::<ParticleInstance>() as u64;
+                    particles::draw_batch(device, command_buffer, particles.instances.buffer, offset, *count);
+                }
+            }
+        };
+
+        unsafe { device.cmd_begin_render_pass(command_buffer, &render_pass_info, vk::SubpassContents::INLINE) };
+        self.textures.bind(command_buffer, pipeline.layout());
+        // Views only have draws when the world has a frame arena to hold them
+        if world.and_then(|world| world.with_resource::<FrameArena, _>(|arena| draw_views(Some(arena)))).is_none() {
+            draw_views(None);
+        }
+        unsafe { device.cmd_end_render_pass(command_buffer) };
+    }
+
+    /// Gathers every particle batch's instances, and with the compute backend their particles, in the world's frame
+    /// arena and writes them to the belt
+    fn prepare_particles(&mut self, world: &World) -> Option<PreparedParticles> {
+        world.with_resource::<ExtractedParticles, _>(|extracted| world.with_resource_mut::<FrameArena, _>(|arena| {
+            let total = extracted.batches().map(|batch| batch.instances.len()).sum();
+            if total == 0 {
+                return None
+            }
+            // Batches carry their particles only when extracted for the compute backend
+            let simulate = self.simulation.is_some() && extracted.batches().all(|batch| batch.particles.len() == batch.instances.len());
+
+            let instances = arena.alloc_filled(ParticleInstance::default(), total);
+            let sources = arena.alloc_filled(Particle::default(), if simulate { total } else { 0 });
+            let mut batches = Vec::new();
+            let mut emitters = Vec::new();
+            let mut first = 0;
+            for batch in extracted.batches() {
+                let range = first..first + batch.instances.len();
+                arena.get_mut(&instances).expect("fresh arena slice")[range.clone()].copy_from_slice(&batch.instances);
+                if simulate {
+                    arena.get_mut(&sources).expect("fresh arena slice")[range.clone()].copy_from_slice(&batch.particles);
+                    emitters.extend(batch.emitters.iter().map(|emitter| EmitterRange { first: first as u32 + emitter.first, ..*emitter }));
+                }
+                let texture = self.textures.get_or_upload(world, batch.texture, &mut self.belt);
+                batches.push((first as u32, batch.instances.len() as u32, texture));
+                first = range.end;
+            }
+
+            let instances = self.belt.write(arena.get(&instances).expect("fresh arena slice"))?;
+            let simulation = match emitters.is_empty() {
+                true => None,
+                false => Some((self.belt.write(arena.get(&sources).expect("fresh arena slice"))?, emitters)),
+            };
+            Some(PreparedParticles { instances, batches, simulation })
+        }).flatten()).flatten()
+    }
+
+    /// Builds the view's instances and draws in the world's frame arena and writes the instances to the belt, uploading
+    /// any mesh drawn for the first time. Views are culled on the GPU while there are free slots, counted in
+    /// `culled_views`, and otherwise culled here
+    fn prepare_view(&mut self, world: &World, view: &ViewportView, extent: vk::Extent2D, copies: &mut Vec<BufferCopy>, culled_views: &mut usize) -> PreparedView {
+        let aspect = f64::from(view.viewport.aspect(extent));
+        let params = ViewParams::new(&view.view, aspect);
+        let origin = view.view.transform.translation;
+        // Instances are placed relative to the view, and culled there too
+        let frustum = Frustum::new(&view.view.camera, &Transform { translation: [0.0; 3], ..view.view.transform }, aspect);
+        let gpu_culled = self.culling.is_some() && *culled_views < CULLED_VIEWS && view.meshes.draws.len() <= CULLED_DRAWS;
+
+        let prepared = world.with_resource_mut::<FrameArena, _>(|arena| {
+            let candidates = view.meshes.draws.len();
+            let instances = arena.alloc_filled(MeshInstance::default(), candidates);
+            let draws = arena.alloc_filled(vk::DrawIndexedIndirectCommand::default(), if gpu_culled { 0 } else { candidates });
+            let bounds = arena.alloc_filled(CullInstance::default(), if gpu_culled { candidates } else { 0 });
+
+            let mut drawn = 0;
+            for draw in &view.meshes.draws {
+                let Some(mesh) = self.meshes.get_or_upload(world, draw.mesh, &mut self.belt, copies) else { continue };
+                let center = std::array::from_fn(|i| (draw.transform.translation[i] - origin[i]) as f32);
+                let scale = draw.transform.scale.iter().fold(0.0f64, |largest, scale| largest.max(scale.abs()));
+                let radius = mesh.radius * scale as f32;
+                if !gpu_culled && !frustum.contains_sphere(center, radius) {
+                    continue
+                }
+
+                arena.get_mut(&instances).expect("fresh arena slice")[drawn] = MeshInstance::new(&draw.transform, origin);
+                let bound = CullInstance {
+                    center,
+                    radius,
+                    index_count: mesh.index_count,
+                    first_index: mesh.first_index,
+                    vertex_offset: mesh.vertex_offset,
+                    instance: drawn as u32,
+                };
+                match gpu_culled {
+                    true => arena.get_mut(&bounds).expect("fresh arena slice")[drawn] = bound,
+                    false => arena.get_mut(&draws).expect("fresh arena slice")[drawn] = vk::DrawIndexedIndirectCommand {
+                        index_count: bound.index_count,
+                        instance_count: 1,
+                        first_index: bound.first_index,
+                        vertex_offset: bound.vertex_offset,
+                        first_instance: bound.instance,
+                    },
+                }
+                drawn += 1;
+            }
+
+            let (instances, bounds) = match drawn {
+                0 => (None, None),
+                drawn => (
+                    self.belt.write(&arena.get(&instances).expect("fresh arena slice")[..drawn]),
+                    gpu_culled.then(|| self.belt.write(&arena.get(&bounds).expect("fresh arena slice")[..drawn])).flatten(),
+                ),
+            };
+            (instances, draws, drawn, bounds)
+        });
+
+        let (instances, draws, drawn, bounds) = match prepared {
+            Some((instances, draws, drawn, bounds)) => (instances, Some(draws), drawn, bounds),
+            None => (None, None, 0, None),
+        };
+        // A view whose bounds didn't fit in the belt draws nothing this frame, as when its instances don't
+        let culled = bounds.map(|bounds| {
+            *culled_views += 1;
+            CulledView { bounds, frustum, slot: *culled_views - 1 }
+        });
+        PreparedView { viewport: view.viewport, params, instances, draws, drawn, culled }
+    }
+}
+
+impl MeshBuffers {
+    /// Where `handle` sits in the mesh buffers, writing it to the belt and queueing its copies the first time. `None`
+    /// while the mesh is loading, or if it can't be drawn
+    fn get_or_upload(&mut self, world: &World, handle: Handle<Mesh>, belt: &mut StagingBelt, copies: &mut Vec<BufferCopy>) -> Option<GpuMesh> {
+        if let Some(uploaded) = self.uploaded.get(&handle) {
+            return *uploaded
+        }
+        let mesh = asset::get::<Mesh>(world, handle)?;
+        let (vertices, indices) = flatten(&mesh);
+
+        let vertex_end = (u64::from(self.vertex_count) + vertices.len() as u64) * std::mem::size_of::<MeshVertex>() as u64;
+        let index_end = (u64::from(self.index_count) + indices.len() as u64) * std::mem::size_of::<u32>() as u64;
+        if indices.is_empty() || vertex_end > MESH_VERTEX_CAPACITY || index_end > MESH_INDEX_CAPACITY {
+            if !indices.is_empty() {
+                log::get().with_topic("renderer").warn(format!("mesh {} doesn't fit in what's left of the mesh buffers", handle));
+            }
+            self.uploaded.insert(handle, None);
+            return None
+        }
+
+        // A full belt is only full this frame, the mesh is tried again next frame
+        let vertex_source = belt.write(&vertices)?;
+        let index_source = belt.write(&indices)?;
+        copies.push(BufferCopy {
+            source: vertex_source,
+            destination: self.vertices.handle(),
+            offset: u64::from(self.vertex_count) * std::mem::size_of::<MeshVertex>() as u64,
+        });
+        copies.push(BufferCopy {
+            source: index_source,
+            destination: self.indices.handle(),
+            offset: u64::from(self.index_count) * std::mem::size_of::<u32>() as u64,
+        });
+
+        let radius = vertices.iter().map(|vertex| vertex.position.iter().map(|v| v * v).sum::<f32>()).fold(0.0, f32::max).sqrt();
+        let uploaded = GpuMesh { first_index: self.index_count, index_count: indices.len() as u32, vertex_offset: self.vertex_count as i32, radius };
+        self.vertex_count += vertices.len() as u32;
+        self.index_count += indices.len() as u32;
+        self.uploaded.insert(handle, Some(uploaded));
+        Some(uploaded)
+    }
+}
+
+/// The mesh's primitives as one indexed triangle list
+fn flatten(mesh: &Mesh) -> (Vec<MeshVertex>, Vec<u32>) {
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    for primitive in &mesh.primitives {
+        let base = vertices.len() as u32;
+        vertices.extend(primitive.positions.iter().enumerate().map(|(i, position)| MeshVertex {
+            position: *position,
+            normal: primitive.normals.get(i).copied().unwrap_or_default(),
+            uv: primitive.uvs.get(i).copied().unwrap_or_default(),
+        }));
+        indices.extend(primitive.indices.iter().map(|index| base + index));
+    }
+    (vertices, indices)
+}
+
+impl ParticleSimulation {
+    fn new(device: &ash::Device) -> Result<Self, vk::Result> {
+        let compute = ParticleCompute::new(device)?;
+        let sizes = [vk::DescriptorPoolSize { ty: vk::DescriptorType::STORAGE_BUFFER, descriptor_count: 2 * surface::FRAMES_IN_FLIGHT as u32 }];
+        let pool_info = vk::DescriptorPoolCreateInfo::builder().max_sets(surface::FRAMES_IN_FLIGHT as u32).pool_sizes(&sizes);
+        let pool = match audit::check(unsafe { device.create_descriptor_pool(&pool_info, None) }, "vkCreateDescriptorPool") {
+            Ok(pool) => pool,
+            Err(error) => {
+                unsafe { compute.destroy(device) };
+                return Err(error)
+            },
+        };
+
+        let set_layouts = vec![compute.set_layout(); surface::FRAMES_IN_FLIGHT];
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder().descriptor_pool(pool).set_layouts(&set_layouts);
+        match audit::check(unsafe { device.allocate_descriptor_sets(&allocate_info) }, "vkAllocateDescriptorSets") {
+            Ok(sets) => Ok(ParticleSimulation { compute, pool, sets }),
+            Err(error) => {
+                unsafe {
+                    device.destroy_descriptor_pool(pool, None);
+                    compute.destroy(device);
+                }
+                Err(error)
+            },
+        }
+    }
+
+    /// Points the frame's set at its particles and instances, then places each emitter's particles. The frame's fence
+    /// has been waited on, so nothing still reads the set
+    fn record(&self, device: &ash::Device, command_buffer: vk::CommandBuffer, frame: usize, particles: &PreparedParticles) {
+        let Some((sources, emitters)) = &particles.simulation else { return };
+        let set = self.sets[frame];
+
+        let infos = [sources, &particles.instances].map(|slice| [vk::DescriptorBufferInfo { buffer: slice.buffer, offset: slice.offset, range: slice.size }]);
+        let writes = [0, 1].map(|binding| vk::WriteDescriptorSet::builder()
+            .dst_set(set)
+            .dst_binding(binding as u32)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .buffer_info(&infos[binding])
+            .build());
+        unsafe { device.update_descriptor_sets(&writes, &[]) };
+
+        for emitter in emitters {
+            let params = SimulationParams { gravity: emitter.gravity, first: emitter.first, count: emitter.count };
+            self.compute.dispatch(device, command_buffer, set, &params);
+        }
+    }
+
+    unsafe fn destroy(self, device: &ash::Device) {
+        device.destroy_descriptor_pool(self.pool, None);
+        self.compute.destroy(device);
+    }
+}
+
+impl MeshCulling {
+    fn new(instance: &ash::Instance, physical_device: vk::PhysicalDevice, device: &Arc<SharedDevice>) -> Result<Self, vk::Result> {
+        let memory_properties = unsafe { instance.get_physical_device_memory_properties(physical_device) };
+        let max_draw_indirect_count = unsafe { instance.get_physical_device_properties(physical_device) }.limits.max_draw_indirect_count;
+        let usage = vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::INDIRECT_BUFFER | vk::BufferUsageFlags::TRANSFER_DST;
+        let local = vk::MemoryPropertyFlags::DEVICE_LOCAL;
+        let commands = (0..surface::FRAMES_IN_FLIGHT)
+            .map(|_| Buffer::new(device, &memory_properties, CULLED_VIEWS as u64 * CULLED_COMMANDS_SIZE, usage, local))
+            .collect::<Result<Vec<_>, _>>()?;
+        let counts = (0..surface::FRAMES_IN_FLIGHT)
+            .map(|_| Buffer::new(device, &memory_properties, CULLED_VIEWS as u64 * CULL_SLOT_ALIGN, usage, local))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let culling = GpuCulling::new(device)?;
+        let sets = (surface::FRAMES_IN_FLIGHT * CULLED_VIEWS) as u32;
+        let sizes = [vk::DescriptorPoolSize { ty: vk::DescriptorType::STORAGE_BUFFER, descriptor_count: 3 * sets }];
+        let pool_info = vk::DescriptorPoolCreateInfo::builder().max_sets(sets).pool_sizes(&sizes);
+        let pool = match audit::check(unsafe { device.create_descriptor_pool(&pool_info, None) }, "vkCreateDescriptorPool") {
+            Ok(pool) => pool,
+            Err(error) => {
+                unsafe { culling.destroy(device) };
+                return Err(error)
+            },
+        };
+
+        let set_layouts = vec![culling.set_layout(); sets as usize];
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder().descriptor_pool(pool).set_layouts(&set_layouts);
+        match audit::check(unsafe { device.allocate_descriptor_sets(&allocate_info) }, "vkAllocateDescriptorSets") {
+            Ok(sets) => Ok(MeshCulling { culling, pool, sets, commands, counts, max_draw_indirect_count }),
+            Err(error) => {
+                unsafe {
+                    device.destroy_descriptor_pool(pool, None);
+                    culling.destroy(device);
+                }
+                Err(error)
+            },
+        }
+    }
+
+    /// Points each culled view's set at its bounds and its slot of the frame's buffers, then culls it. The frame's
+    /// fence has been waited on, so nothing still reads the sets or buffers
+    fn record(&self, device: &ash::Device, command_buffer: vk::CommandBuffer, frame: usize, views: &[PreparedView]) {
+        for (culled, drawn) in views.iter().filter_map(|view| view.culled.as_ref().map(|culled| (culled, view.drawn))) {
+            let set = self.sets[frame * CULLED_VIEWS + culled.slot];
+            let count_offset = culled.slot as u64 * CULL_SLOT_ALIGN;
+            let infos = [
+                [vk::DescriptorBufferInfo { buffer: culled.bounds.buffer, offset: culled.bounds.offset, range: culled.bounds.size }],
+                [vk::DescriptorBufferInfo { buffer: self.commands[frame].handle(), offset: culled.slot as u64 * CULLED_COMMANDS_SIZE, range: CULLED_COMMANDS_SIZE }],
+                [vk::DescriptorBufferInfo { buffer: self.counts[frame].handle(), offset: count_offset, range: std::mem::size_of::<u32>() as u64 }],
+            ];
+            let writes = [0, 1, 2].map(|binding| vk::WriteDescriptorSet::builder()
+                .dst_set(set)
+                .dst_binding(binding as u32)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&infos[binding])
+                .build());
+            unsafe { device.update_descriptor_sets(&writes, &[]) };
+
+            let params = CullParams::new(&culled.frustum, drawn as u32);
+            self.culling.dispatch(device, command_buffer, set, self.counts[frame].handle(), count_offset, &params);
+        }
+    }
+
+    /// Draws what the culling pass left of the view in `slot`, out of `drawn` instances
+    fn draw(&self, device: &ash::Device, command_buffer: vk::CommandBuffer, frame: usize, slot: usize, drawn: u32) {
+        let commands = self.commands[frame].handle();
+        let counts = self.counts[frame].handle();
+        let commands_offset = slot as u64 * CULLED_COMMANDS_SIZE;
+        let count_offset = slot as u64 * CULL_SLOT_ALIGN;
+        let max_draws = drawn.min(self.max_draw_indirect_count);
+        culling::draw_culled(device, command_buffer, commands, commands_offset, counts, count_offset, max_draws);
+    }
+
+    /// Nothing may be in flight, the buffers are destroyed as they drop
+    unsafe fn destroy(self, device: &ash::Device) {
+        device.destroy_descriptor_pool(self.pool, None);
+        self.culling.destroy(device);
+    }
+}
+
+/// Records the frame's copies out of the belt
+fn record_copies(device: &ash::Device, command_buffer: vk::CommandBuffer, copies: &[BufferCopy]) {
+    unsafe {
+        for copy in copies {
+            let region = vk::BufferCopy { src_offset: copy.source.offset, dst_offset: copy.offset, size: copy.source.size };
+            device.cmd_copy_buffer(command_buffer, copy.source.buffer, copy.destination, &[region]);
+        }
+    }
+}
+
+/// Records the graph's barriers ahead of `pass`. The frame's resources are buffers, and the swapchain image only the
+/// render pass uses, so memory barriers cover them
+fn record_barriers(device: &ash::Device, command_buffer: vk::CommandBuffer, barriers: &[Barrier], pass: PassId) {
+    for barrier in barriers.iter().filter(|barrier| barrier.before == pass) {
+        let memory = vk::MemoryBarrier::builder()
+            .src_access_mask(barrier.from.access_mask())
+            .dst_access_mask(barrier.to.access_mask())
+            .build();
+        unsafe { device.cmd_pipeline_barrier(command_buffer, barrier.from.stage(), barrier.to.stage(), vk::DependencyFlags::empty(), &[memory], &[], &[]) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asset::pipeline::formats::MeshPrimitive, graphics::camera::Camera, unique::UniqueId};
+
+    #[test]
+    fn primitives_flatten_into_one_draw() {
+        let triangle = |x: f32| MeshPrimitive {
+            positions: vec![[x, 0.0, 0.0], [x + 1.0, 0.0, 0.0], [x, 1.0, 0.0]],
+            normals: vec![[0.0, 0.0, 1.0]],
+            uvs: Vec::new(),
+            indices: vec![0, 1, 2],
+        };
+        let mesh = Mesh { uid: UniqueId::get(), primitives: vec![triangle(0.0), triangle(5.0)] };
+
+        let (vertices, indices) = flatten(&mesh);
+        assert_eq!(vertices.len(), 6);
+        assert_eq!(indices, vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(vertices[3].position, [5.0, 0.0, 0.0]);
+        // Missing attributes are zero filled
+        assert_eq!((vertices[0].normal, vertices[1].normal), ([0.0, 0.0, 1.0], [0.0; 3]));
+    }
+
+    #[test]
+    fn view_projection_maps_the_frustum() {
+        // Turned a quarter to face -X, a long way from the origin
+        let camera = Camera { fov_y: 90f64.to_radians(), near: 1.0, far: 100.0, ..Default::default() };
+        let transform = Transform { translation: [1.0e7, 0.0, 0.0], ..Transform::from_yaw(std::f64::consts::FRAC_PI_2) };
+        let params = ViewParams::new(&ExtractedView { camera, transform, alpha: 1.0 }, 2.0);
+
+        let clip = |point: [f32; 3]| -> [f32; 4] {
+            let [x, y, z] = point;
+            std::array::from_fn(|row| (0..3).map(|column| params.view_projection[column][row] * [x, y, z][column]).sum::<f32>() + params.view_projection[3][row])
+        };
+        let ndc = |point: [f32; 3]| { let [x, y, z, w] = clip(point); [x / w, y / w, z / w] };
+        let near = ndc([-1.0, 0.0, 0.0]);
+        let far = ndc([-100.0, 0.0, 0.0]);
+        assert!(near[0].abs() < 1e-5 && (near[2] - 0.0).abs() < 1e-5);
+        assert!((far[2] - 1.0).abs() < 1e-5);
+
+        // Up on screen is -Y in Vulkan, and the view's top right corner lands on the edge with the wider aspect
+        let corner = ndc([-10.0, 10.0, -20.0]);
+        assert!((corner[0] - 1.0).abs() < 1e-5 && (corner[1] + 1.0).abs() < 1e-5);
+
+        // Particle quads face the camera, spanned by its right and up axes
+        assert!((params.right[2] + 1.0).abs() < 1e-6 && (params.up[1] - 1.0).abs() < 1e-6);
+        assert_eq!(params.origin[0], 1.0e7);
+
+        // Instances are placed relative to the camera
+        let instance = MeshInstance::new(&Transform::from_translation(1.0e7 - 10.0, 0.0, 0.0), transform.translation);
+        assert_eq!(instance.model[3], [-10.0, 0.0, 0.0, 1.0]);
+    }
+}
@@ -0,0 +1,363 @@
+//!
+//! Golden image tests
+//!
+//! A `GoldenScene` sets up a headless app, runs it for a few frames and captures the last one presented. While
+//! capturing the world advances a fixed step each frame, so a scene renders the same way on every run. The frame is
+//! compared against `<directory>/<scene>.png` with a perceptual per-pixel difference, small differences in brightness
+//! count for more than the same differences in hue, and a scene fails when too many pixels differ by more than the
+//! threshold. Failures leave `<scene>.expected.png`, `<scene>.actual.png` and `<scene>.diff.png` in the artifact
+//! directory, the diff marks mismatched pixels in the debug palette's error color over a faded copy of the reference
+//!
+//! References are written instead of compared with `golden.update`, e.g. `HADRON_GOLDEN__UPDATE=true cargo test`
+//!
+
+use std::{io, path::{Path, PathBuf}};
+
+use serde::{Serialize, Deserialize};
+
+use crate::accessibility::{self, DebugColor};
+use crate::app::App;
+use crate::config::ConfigSection;
+use crate::debug::log;
+use super::{backend::GraphicsBackend, capture::{CaptureConfig, CaptureOutput, CapturedFrame, PixelFormat}};
+
+/// Largest YIQ difference between two colors, between black and white
+const MAX_YIQ_DELTA: f64 = 35215.0;
+
+/// How different a frame may be from its reference
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Tolerance {
+    /// Perceptual difference between 0 and 1 a pixel may have before it counts as mismatched
+    pub threshold: f64,
+    /// Fraction of mismatched pixels a frame may have and still pass
+    pub max_mismatched: f64,
+}
+
+/// Golden image options from the `golden` config section
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct GoldenConfig {
+    /// Where reference images are kept
+    pub directory: PathBuf,
+    /// Where failing scenes leave their images
+    pub artifacts: PathBuf,
+    /// Writes rendered frames as the new references instead of comparing them
+    pub update: bool,
+    pub tolerance: Tolerance,
+}
+
+/// A named setup rendered headlessly for comparison against a reference image
+pub struct GoldenScene {
+    name: String,
+    frames: u64,
+    fps: f64,
+    setup: Box<dyn Fn(&mut App)>,
+}
+
+/// Scenes checked together against one set of references
+pub struct GoldenSuite {
+    config: GoldenConfig,
+    scenes: Vec<GoldenScene>,
+}
+
+/// How a frame compared to its reference
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    pub mismatched: u64,
+    pub total: u64,
+    /// Largest perceptual difference of any pixel
+    pub max_delta: f64,
+    pub diff: CapturedFrame,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GoldenOutcome {
+    Matched(Comparison),
+    /// The reference was written from the rendered frame
+    Updated(PathBuf),
+}
+
+#[derive(Debug)]
+pub enum GoldenError {
+    /// The scene failed to render or never presented a frame
+    Render(String, String),
+    /// No reference image, the rendered frame is left in the artifacts to be reviewed and copied over
+    Missing(String, PathBuf),
+    /// Rendered at a different size than the reference, width and height of each
+    SizeMismatch(String, (u32, u32), (u32, u32)),
+    Mismatch { scene: String, mismatched: u64, total: u64, artifacts: PathBuf },
+    Io(PathBuf, io::Error),
+}
+
+/// Results of a suite run in scene order
+#[derive(Debug)]
+pub struct GoldenReport {
+    pub results: Vec<(String, Result<GoldenOutcome, GoldenError>)>,
+}
+
+// Impls
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Tolerance { threshold: 0.1, max_mismatched: 0.0 }
+    }
+}
+
+impl Default for GoldenConfig {
+    fn default() -> Self {
+        GoldenConfig {
+            directory: PathBuf::from("tests/golden"),
+            artifacts: PathBuf::from("target/golden"),
+            update: false,
+            tolerance: Tolerance::default(),
+        }
+    }
+}
+
+impl ConfigSection for GoldenConfig {
+    const NAME: &'static str = "golden";
+}
+
+impl std::error::Error for GoldenError {}
+
+impl std::fmt::Display for GoldenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GoldenError::Render(scene, error) => write!(f, "scene {} failed to render: {}", scene, error),
+            GoldenError::Missing(scene, path) => write!(f, "scene {} has no reference image at {}", scene, path.display()),
+            GoldenError::SizeMismatch(scene, expected, actual) => write!(f, "scene {} rendered at {}x{}, the reference is {}x{}", scene, actual.0, actual.1, expected.0, expected.1),
+            GoldenError::Mismatch { scene, mismatched, total, artifacts } => write!(f, "scene {} differs in {} of {} pixels, see {}", scene, mismatched, total, artifacts.display()),
+            GoldenError::Io(path, error) => write!(f, "{}: {}", path.display(), error),
+        }
+    }
+}
+
+impl GoldenScene {
+    /// A scene rendered for one frame after `setup` has run on a fresh headless app
+    pub fn new(name: &str, setup: impl Fn(&mut App) + 'static) -> Self {
+        GoldenScene { name: String::from(name), frames: 1, fps: 60.0, setup: Box::new(setup) }
+    }
+
+    /// Renders `frames` frames and compares the last
+    pub fn with_frames(mut self, frames: u64) -> Self {
+        self.frames = frames.max(1);
+        self
+    }
+
+    /// Frame rate the world advances at, so animated scenes land on the same time on every run
+    pub fn with_fps(mut self, fps: f64) -> Self {
+        self.fps = fps;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Runs the scene on `backend` and returns the last frame presented
+    pub fn render<B: GraphicsBackend + 'static>(&self, backend: B) -> Result<CapturedFrame, GoldenError> {
+        let capture = CaptureConfig { enabled: true, output: CaptureOutput::Memory, fps: self.fps, ..CaptureConfig::default() };
+        let mut app = App::headless(backend).with_capture(capture);
+        (self.setup)(&mut app);
+        app.run_frames(self.frames).map_err(|error| GoldenError::Render(self.name.clone(), error.to_string()))?;
+        app.capture_mut()
+            .and_then(|capture| capture.take_frames().pop())
+            .ok_or_else(|| GoldenError::Render(self.name.clone(), String::from("no frame was presented")))
+    }
+
+    /// Renders the scene and compares it against its reference, or writes the reference when updating
+    pub fn check<B: GraphicsBackend + 'static>(&self, backend: B, config: &GoldenConfig) -> Result<GoldenOutcome, GoldenError> {
+        let actual = self.render(backend)?;
+        let reference = config.directory.join(format!("{}.png", self.name));
+        if config.update {
+            std::fs::create_dir_all(&config.directory).map_err(|error| GoldenError::Io(config.directory.clone(), error))?;
+            write_png(&actual, &reference)?;
+            log::get().with_topic("golden").info(format!("updated {}", reference.display()));
+            return Ok(GoldenOutcome::Updated(reference))
+        }
+        if !reference.exists() {
+            self.write_artifacts(config, None, &actual, None)?;
+            return Err(GoldenError::Missing(self.name.clone(), reference))
+        }
+
+        let expected = CapturedFrame::read_png(&reference).map_err(|error| GoldenError::Io(reference.clone(), error))?;
+        let Some(comparison) = compare(&expected, &actual, config.tolerance.threshold) else {
+            self.write_artifacts(config, Some(&expected), &actual, None)?;
+            return Err(GoldenError::SizeMismatch(self.name.clone(), (expected.width, expected.height), (actual.width, actual.height)))
+        };
+        if comparison.passes(&config.tolerance) {
+            return Ok(GoldenOutcome::Matched(comparison))
+        }
+
+        self.write_artifacts(config, Some(&expected), &actual, Some(&comparison.diff))?;
+        Err(GoldenError::Mismatch { scene: self.name.clone(), mismatched: comparison.mismatched, total: comparison.total, artifacts: config.artifacts.clone() })
+    }
+
+    fn write_artifacts(&self, config: &GoldenConfig, expected: Option<&CapturedFrame>, actual: &CapturedFrame, diff: Option<&CapturedFrame>) -> Result<(), GoldenError> {
+        std::fs::create_dir_all(&config.artifacts).map_err(|error| GoldenError::Io(config.artifacts.clone(), error))?;
+        let images = [("expected", expected), ("actual", Some(actual)), ("diff", diff)];
+        for (kind, image) in images.into_iter().filter_map(|(kind, image)| Some((kind, image?))) {
+            write_png(image, &config.artifacts.join(format!("{}.{}.png", self.name, kind)))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for GoldenScene {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GoldenScene").field("name", &self.name).field("frames", &self.frames).finish()
+    }
+}
+
+impl GoldenSuite {
+    pub fn new(config: GoldenConfig) -> Self {
+        GoldenSuite { config, scenes: Vec::new() }
+    }
+
+    /// A suite configured from the `golden` config section
+    pub fn from_config() -> Self {
+        GoldenSuite::new(crate::config::get().section())
+    }
+
+    pub fn with_scene(mut self, scene: GoldenScene) -> Self {
+        self.scenes.push(scene);
+        self
+    }
+
+    pub fn config(&self) -> &GoldenConfig {
+        &self.config
+    }
+
+    /// Checks every scene, each on a fresh backend from `backend`
+    pub fn run<B: GraphicsBackend + 'static>(&self, mut backend: impl FnMut() -> B) -> GoldenReport {
+        let results = self.scenes.iter()
+            .map(|scene| (scene.name.clone(), scene.check(backend(), &self.config)))
+            .inspect(|(_, result)| if let Err(error) = result {
+                log::get().with_topic("golden").warn(error.to_string());
+            })
+            .collect();
+        GoldenReport { results }
+    }
+}
+
+impl Comparison {
+    pub fn mismatched_fraction(&self) -> f64 {
+        self.mismatched as f64 / self.total.max(1) as f64
+    }
+
+    pub fn passes(&self, tolerance: &Tolerance) -> bool {
+        self.mismatched_fraction() <= tolerance.max_mismatched
+    }
+}
+
+impl GoldenReport {
+    pub fn is_success(&self) -> bool {
+        self.results.iter().all(|(_, result)| result.is_ok())
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &GoldenError> {
+        self.results.iter().filter_map(|(_, result)| result.as_ref().err())
+    }
+}
+
+impl std::fmt::Display for GoldenReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (scene, result) in &self.results {
+            match result {
+                Ok(GoldenOutcome::Matched(comparison)) => writeln!(f, "{}: ok, max difference {:.3}", scene, comparison.max_delta)?,
+                Ok(GoldenOutcome::Updated(path)) => writeln!(f, "{}: updated {}", scene, path.display())?,
+                Err(error) => writeln!(f, "{}: {}", scene, error)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compares two frames pixel by pixel, `None` when their sizes differ. Pixels with a perceptual difference above
+/// `threshold` are counted as mismatched
+pub fn compare(expected: &CapturedFrame, actual: &CapturedFrame, threshold: f64) -> Option<Comparison> {
+    if (expected.width, expected.height) != (actual.width, actual.height) {
+        return None
+    }
+
+    let (expected_rgba, actual_rgba) = (expected.rgba(), actual.rgba());
+    let (mut mismatched, mut max_delta) = (0, 0.0f64);
+    let mut diff = Vec::with_capacity(expected_rgba.len());
+    let mismatch_color = accessibility::settings().palette.color(DebugColor::Error);
+    for (a, b) in expected_rgba.chunks_exact(4).zip(actual_rgba.chunks_exact(4)) {
+        let delta = perceptual_delta(a, b);
+        max_delta = max_delta.max(delta);
+        if delta > threshold {
+            mismatched += 1;
+            diff.extend(mismatch_color);
+        } else {
+            // Faded towards white so the mismatches stand out
+            let luma = 255.0 - (255.0 - yiq(blend(a))[0]) * 0.1;
+            diff.extend([luma as u8, luma as u8, luma as u8, 255]);
+        }
+    }
+
+    let diff = CapturedFrame { width: expected.width, height: expected.height, format: PixelFormat::Rgba8, pixels: diff };
+    Some(Comparison { mismatched, total: u64::from(expected.width) * u64::from(expected.height), max_delta, diff })
+}
+
+/// Perceptual difference of two RGBA pixels between 0 and 1, from their distance in YIQ with brightness weighted most
+pub fn perceptual_delta(a: &[u8], b: &[u8]) -> f64 {
+    let (a, b) = (yiq(blend(a)), yiq(blend(b)));
+    let (y, i, q) = (a[0] - b[0], a[1] - b[1], a[2] - b[2]);
+    ((0.5053 * y * y + 0.299 * i * i + 0.1957 * q * q) / MAX_YIQ_DELTA).sqrt()
+}
+
+/// Blends a pixel onto white by its alpha
+fn blend(pixel: &[u8]) -> [f64; 3] {
+    let alpha = f64::from(pixel[3]) / 255.0;
+    [0, 1, 2].map(|channel| 255.0 + (f64::from(pixel[channel]) - 255.0) * alpha)
+}
+
+fn yiq([r, g, b]: [f64; 3]) -> [f64; 3] {
+    [
+        r * 0.29889531 + g * 0.58662247 + b * 0.11448223,
+        r * 0.59597799 - g * 0.27417610 - b * 0.32180189,
+        r * 0.21147017 - g * 0.52261711 + b * 0.31114694,
+    ]
+}
+
+fn write_png(frame: &CapturedFrame, path: &Path) -> Result<(), GoldenError> {
+    frame.write_png(path).map_err(|error| GoldenError::Io(path.to_path_buf(), error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::mock::MockGraphics;
+
+    #[test]
+    fn scenes_match_within_tolerance_and_leave_diffs() {
+        let root = std::env::temp_dir().join(format!("hadron_golden_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let config = GoldenConfig { directory: root.join("references"), artifacts: root.join("artifacts"), update: true, ..GoldenConfig::default() };
+        let scene = GoldenScene::new("grey", |_| ()).with_frames(2);
+        let grey = CapturedFrame::filled(4, 4, [128, 128, 128, 255]);
+        let render = |frame: &CapturedFrame| MockGraphics::new().with_frame(frame.clone());
+
+        assert!(matches!(scene.check(render(&grey), &config), Ok(GoldenOutcome::Updated(_))));
+        let config = GoldenConfig { update: false, ..config };
+
+        // Slightly brighter is within the threshold, one pixel of a different color isn't
+        let brighter = CapturedFrame::filled(4, 4, [131, 131, 131, 255]);
+        assert!(matches!(scene.check(render(&brighter), &config), Ok(GoldenOutcome::Matched(_))));
+        let mut spotted = grey.clone();
+        spotted.pixels[..4].copy_from_slice(&[200, 60, 60, 255]);
+        match scene.check(render(&spotted), &config) {
+            Err(GoldenError::Mismatch { mismatched, total, .. }) => assert_eq!((mismatched, total), (1, 16)),
+            other => panic!("expected a mismatch, got {:?}", other),
+        }
+        let diff = CapturedFrame::read_png(config.artifacts.join("grey.diff.png")).unwrap();
+        assert_eq!(&diff.pixels[..4], &accessibility::settings().palette.color(DebugColor::Error));
+
+        let lenient = GoldenConfig { tolerance: Tolerance { max_mismatched: 0.1, ..Tolerance::default() }, ..config };
+        assert!(GoldenSuite::new(lenient).with_scene(scene).run(|| render(&spotted)).is_success());
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,79 @@
+//!
+//! Render layers
+//!
+//! Every renderable entity is on one or more of 32 layers, set by its `RenderLayers` component or the main layer when
+//! it has none. Each camera carries a mask of the layers it sees, and extraction only gathers entities sharing a layer
+//! with the view's mask, so the main view, UI, minimap and shadow cameras can each draw their own set of entities
+//!
+
+use collider::EntityId;
+use serde::{Serialize, Deserialize};
+
+use crate::system::world::World;
+
+/// Layer indices the engine's own passes use, games are free to use the rest
+pub mod layer {
+    pub const MAIN: u32 = 0;
+    pub const UI: u32 = 1;
+    pub const MINIMAP: u32 = 2;
+    pub const SHADOW: u32 = 3;
+}
+
+/// A set of render layers, as a bit per layer
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RenderLayers(pub u32);
+
+// Impls
+
+impl Default for RenderLayers {
+    fn default() -> Self {
+        RenderLayers::layer(layer::MAIN)
+    }
+}
+
+impl RenderLayers {
+    pub const NONE: RenderLayers = RenderLayers(0);
+    pub const ALL: RenderLayers = RenderLayers(u32::MAX);
+
+    /// Just `layer`, which must be below 32
+    pub const fn layer(layer: u32) -> Self {
+        RenderLayers(1 << layer)
+    }
+
+    pub const fn with(self, layer: u32) -> Self {
+        RenderLayers(self.0 | 1 << layer)
+    }
+
+    pub const fn without(self, layer: u32) -> Self {
+        RenderLayers(self.0 & !(1 << layer))
+    }
+
+    pub const fn contains(&self, layer: u32) -> bool {
+        self.0 & 1 << layer != 0
+    }
+
+    pub const fn intersects(&self, other: RenderLayers) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+/// Whether a camera with the layer mask `mask` sees `entity`
+pub fn is_visible(world: &World, entity: EntityId, mask: RenderLayers) -> bool {
+    world.component::<RenderLayers, _>(entity, |layers| *layers).unwrap_or_default().intersects(mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks() {
+        let ui = RenderLayers::layer(layer::UI);
+        let world_and_shadow = RenderLayers::default().with(layer::SHADOW);
+        assert!(world_and_shadow.contains(layer::MAIN) && !world_and_shadow.contains(layer::UI));
+        assert!(!ui.intersects(world_and_shadow));
+        assert!(RenderLayers::ALL.intersects(ui));
+        assert_eq!(world_and_shadow.without(layer::SHADOW), RenderLayers::default());
+        assert!(!RenderLayers::NONE.intersects(RenderLayers::ALL));
+    }
+}
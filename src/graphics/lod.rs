@@ -0,0 +1,213 @@
+//!
+//! Level of detail
+//!
+//! An entity with a `LodGroup` and a `Transform` is drawn with one of several meshes, from most to least detailed.
+//! Extraction picks the level from the camera's distance to the entity or from how much of the screen its bounds
+//! cover, and only moves to another level once the measure is past the switch point by the group's hysteresis, so an
+//! entity sitting near a switch point doesn't pop back and forth between meshes
+//!
+
+use collider::EntityId;
+use serde::{Serialize, Deserialize};
+
+use crate::{asset::{self, pipeline::formats::Mesh}, debug::log, unique::Handle};
+use crate::system::{world::World, transform::{self, Transform}};
+
+use super::{extract::ExtractedView, layers};
+
+/// What a `LodGroup`'s switch points measure
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LodMetric {
+    /// Each level is used until the camera is `switch` world units away
+    #[default]
+    Distance,
+    /// Each level is used while the bounds span at least `switch` of the screen's height
+    Coverage,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LodLevel {
+    /// Mesh asset path relative to the asset root
+    pub mesh: String,
+    pub switch: f64,
+}
+
+/// Meshes for an entity ordered from most to least detailed, past the last level's switch point it isn't drawn
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct LodGroup {
+    pub levels: Vec<LodLevel>,
+    pub metric: LodMetric,
+    /// Radius of a sphere around the translation bounding every level, used for coverage
+    pub radius: f64,
+    /// Fraction a switch point must be passed by before changing level
+    pub hysteresis: f64,
+}
+
+/// Runtime state of a group, added alongside it the first time it's extracted
+#[derive(Debug, Clone, Default)]
+pub struct LodState {
+    /// The level drawn last frame, `None` when culled or not yet selected
+    level: Option<usize>,
+    meshes: Vec<Option<Handle<Mesh>>>,
+    resolved: bool,
+}
+
+/// A mesh to draw this frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeshDraw {
+    pub mesh: Handle<Mesh>,
+    pub level: usize,
+    pub transform: Transform,
+}
+
+/// World resource of this frame's selected meshes, written by extraction
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExtractedMeshes {
+    pub draws: Vec<MeshDraw>,
+    /// Triangles across every draw whose mesh has finished loading
+    pub triangles: usize,
+}
+
+// Impls
+
+impl Default for LodGroup {
+    fn default() -> Self {
+        LodGroup {
+            levels: Vec::new(),
+            metric: LodMetric::Distance,
+            radius: 1.0,
+            hysteresis: 0.1,
+        }
+    }
+}
+
+impl LodGroup {
+    pub fn with_level(mut self, mesh: impl Into<String>, switch: f64) -> Self {
+        self.levels.push(LodLevel { mesh: mesh.into(), switch });
+        self
+    }
+
+    pub fn with_metric(mut self, metric: LodMetric) -> Self {
+        self.metric = metric;
+        self
+    }
+
+    pub fn with_radius(mut self, radius: f64) -> Self {
+        self.radius = radius;
+        self
+    }
+
+    pub fn with_hysteresis(mut self, hysteresis: f64) -> Self {
+        self.hysteresis = hysteresis;
+        self
+    }
+
+    /// The measure this group switches on, for a camera `distance` away with a vertical field of view `fov_y`
+    pub fn measure(&self, distance: f64, fov_y: f64) -> f64 {
+        match self.metric {
+            LodMetric::Distance => distance,
+            LodMetric::Coverage => match distance > self.radius {
+                true => self.radius / (distance * (fov_y * 0.5).tan()),
+                false => 1.0,
+            },
+        }
+    }
+
+    /// The level to draw for a measure, given the level drawn last frame. Switch points on the coarse side of the
+    /// current level are pushed out by the hysteresis and those on the fine side pulled in, so returning to the
+    /// previous level takes a real change rather than jitter. `None` once past the last level
+    pub fn select(&self, measure: f64, current: Option<usize>) -> Option<usize> {
+        let current = current.unwrap_or(self.levels.len());
+        let level = self.levels.iter().enumerate().take_while(|(index, level)| {
+            let toward = if *index < current { -self.hysteresis } else { self.hysteresis };
+            match self.metric {
+                LodMetric::Distance => measure >= level.switch * (1.0 + toward),
+                LodMetric::Coverage => measure < level.switch * (1.0 - toward),
+            }
+        }).count();
+        (level < self.levels.len()).then_some(level)
+    }
+}
+
+/// Selects a level for every `LodGroup` on the view's layers and collects the meshes into `ExtractedMeshes`
+pub fn extract_lods(world: &World) {
+    let extracted = world.with_resource::<ExtractedView, _>(|view| *view)
+        .map(|view| select_meshes(world, &view, true))
+        .unwrap_or_default();
+    world.insert_resource(extracted);
+}
+
+/// Selects a level for every `LodGroup` `view` sees. Only the main view should `remember` its selection, other views
+/// start from it without moving it, so they can't drag the main view's levels across their switch points
+pub fn select_meshes(world: &World, view: &ExtractedView, remember: bool) -> ExtractedMeshes {
+    let mut extracted = ExtractedMeshes::default();
+    for entity in world.query::<LodGroup, ()>().into_iter().filter(|entity| layers::is_visible(world, *entity, view.camera.layers)) {
+        let Some(group) = world.component::<LodGroup, _>(entity, LodGroup::clone) else { continue };
+        let Some(transform) = transform::render_transform(world, entity) else { continue };
+        resolve_meshes(world, entity, &group);
+
+        let distance = distance(view.transform.translation, transform.translation);
+        let measure = group.measure(distance, view.camera.fov_y);
+        let draw = world.component_mut::<LodState, _>(entity, |mut state| {
+            let state = state.bypass_change_detection();
+            let level = group.select(measure, state.level);
+            if remember {
+                state.level = level;
+            }
+            level.and_then(|level| Some(MeshDraw { mesh: state.meshes.get(level).copied().flatten()?, level, transform }))
+        }).flatten();
+
+        if let Some(draw) = draw {
+            extracted.triangles += asset::get::<Mesh>(world, draw.mesh)
+                .map(|mesh| mesh.primitives.iter().map(|primitive| primitive.indices.len() / 3).sum::<usize>())
+                .unwrap_or(0);
+            extracted.draws.push(draw);
+        }
+    }
+    extracted
+}
+
+/// Starts loading every level's mesh the first time a group is seen
+fn resolve_meshes(world: &World, entity: EntityId, group: &LodGroup) {
+    if world.component::<LodState, _>(entity, |state| state.resolved).unwrap_or(false) {
+        return
+    }
+
+    let meshes = group.levels.iter().map(|level| match asset::load::<Mesh>(world, &level.mesh) {
+        Ok(mesh) => Some(mesh),
+        Err(error) => {
+            log::get().with_topic("lod").warn(format!("unable to load lod mesh {}: {}", level.mesh, error));
+            None
+        },
+    }).collect();
+    world.insert_component(entity, LodState { level: None, meshes, resolved: true });
+}
+
+fn distance(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum::<f64>().sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selection_with_hysteresis() {
+        let group = LodGroup::default().with_level("near", 10.0).with_level("far", 50.0).with_hysteresis(0.1);
+        assert_eq!(group.select(5.0, None), Some(0));
+        assert_eq!(group.select(60.0, None), None);
+
+        // Past the switch point but inside the band keeps the current level, in both directions
+        assert_eq!(group.select(10.5, Some(0)), Some(0));
+        assert_eq!(group.select(11.5, Some(0)), Some(1));
+        assert_eq!(group.select(9.5, Some(1)), Some(1));
+        assert_eq!(group.select(8.5, Some(1)), Some(0));
+
+        let coverage = LodGroup::default().with_metric(LodMetric::Coverage).with_level("near", 0.2).with_level("far", 0.05).with_radius(1.0);
+        assert_eq!(coverage.select(coverage.measure(0.5, 1.0), None), Some(0));
+        assert_eq!(coverage.select(0.19, Some(0)), Some(0));
+        assert_eq!(coverage.select(0.17, Some(0)), Some(1));
+        assert_eq!(coverage.select(0.01, Some(1)), None);
+    }
+}
@@ -0,0 +1,129 @@
+//!
+//! GPU memory budgets
+//!
+//! With `VK_EXT_memory_budget` the driver reports, per memory heap, how much this process may allocate before
+//! performance suffers and how much it's using, both of which change as other processes come and go. Without the
+//! extension the heap sizes stand in for budgets and usage is unknown. The renderer refreshes the budget as it runs, and
+//! anything uploading to the GPU, like streamed units or textures, should check `admits` before it allocates
+//!
+
+use std::{ffi::CStr, sync::RwLock};
+
+use ash::vk;
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+
+static MEMORY_BUDGET: Lazy<RwLock<Option<MemoryBudget>>> = Lazy::new(|| RwLock::new(None));
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapBudget {
+    pub index: u32,
+    pub device_local: bool,
+    pub size: u64,
+    /// Bytes the process can use before the driver starts evicting or failing allocations
+    pub budget: u64,
+    pub usage: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryBudget {
+    pub heaps: Vec<HeapBudget>,
+    /// Reported by the driver, rather than estimated from heap sizes
+    pub measured: bool,
+}
+
+// Impls
+
+impl HeapBudget {
+    pub fn available(&self) -> u64 {
+        self.budget.saturating_sub(self.usage)
+    }
+}
+
+impl MemoryBudget {
+    fn device_local(&self) -> impl Iterator<Item = &HeapBudget> {
+        self.heaps.iter().filter(|heap| heap.device_local)
+    }
+
+    /// Budget across the device local heaps, the closest thing to a VRAM limit
+    pub fn device_local_budget(&self) -> u64 {
+        self.device_local().map(|heap| heap.budget).sum()
+    }
+
+    pub fn device_local_usage(&self) -> u64 {
+        self.device_local().map(|heap| heap.usage).sum()
+    }
+
+    /// Device local bytes left in the budget
+    pub fn available(&self) -> u64 {
+        self.device_local().map(HeapBudget::available).sum()
+    }
+
+    /// Fraction of the device local budget in use, from 0 upwards. Past 1 the driver is over budget
+    pub fn pressure(&self) -> f64 {
+        match self.device_local_budget() {
+            0 => 0.0,
+            budget => self.device_local_usage() as f64 / budget as f64,
+        }
+    }
+
+    /// Whether `bytes` more can go into a single device local heap without exceeding its budget
+    pub fn admits(&self, bytes: u64) -> bool {
+        self.device_local().any(|heap| heap.available() >= bytes)
+    }
+}
+
+/// Whether the device has `VK_EXT_memory_budget`
+pub(crate) fn is_supported(instance: &ash::Instance, physical_device: vk::PhysicalDevice) -> bool {
+    let extensions = unsafe { instance.enumerate_device_extension_properties(physical_device) }.unwrap_or_default();
+    extensions.iter().any(|extension| unsafe { CStr::from_ptr(extension.extension_name.as_ptr()) } == vk::ExtMemoryBudgetFn::name())
+}
+
+/// Reads the current budget, `extension` says whether `VK_EXT_memory_budget` was enabled on the device
+pub(crate) fn query(instance: &ash::Instance, physical_device: vk::PhysicalDevice, extension: bool) -> MemoryBudget {
+    let mut budget_properties = vk::PhysicalDeviceMemoryBudgetPropertiesEXT::default();
+    let mut properties = vk::PhysicalDeviceMemoryProperties2::builder();
+    if extension {
+        properties = properties.push_next(&mut budget_properties);
+    }
+    unsafe { instance.get_physical_device_memory_properties2(physical_device, &mut properties) };
+    let memory = properties.memory_properties;
+
+    let heaps = memory.memory_heaps[..memory.memory_heap_count as usize].iter().enumerate()
+        .map(|(index, heap)| HeapBudget {
+            index: index as u32,
+            device_local: heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL),
+            size: heap.size,
+            budget: if extension { budget_properties.heap_budget[index] } else { heap.size },
+            usage: if extension { budget_properties.heap_usage[index] } else { 0 },
+        })
+        .collect();
+    MemoryBudget { heaps, measured: extension }
+}
+
+/// The latest budget the renderer read, `None` before it initializes
+pub fn memory_budget() -> Option<MemoryBudget> {
+    MEMORY_BUDGET.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+}
+
+pub(crate) fn publish(budget: MemoryBudget) {
+    *MEMORY_BUDGET.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(budget);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_local_headroom() {
+        let heap = |index, device_local, budget, usage| HeapBudget { index, device_local, size: budget * 2, budget, usage };
+        let budget = MemoryBudget { heaps: vec![heap(0, true, 1000, 600), heap(1, false, 4000, 0), heap(2, true, 500, 100)], measured: true };
+
+        assert_eq!(budget.device_local_budget(), 1500);
+        assert_eq!(budget.available(), 800);
+        assert!((budget.pressure() - 0.4666).abs() < 0.001);
+        assert!(budget.admits(400));
+        // Neither device local heap has 500 bytes left on its own
+        assert!(!budget.admits(500));
+    }
+}
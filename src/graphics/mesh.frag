@@ -0,0 +1,12 @@
+#version 450
+
+layout (location = 0) in vec3 world_normal;
+
+layout (location = 0) out vec4 colour;
+
+const vec3 LIGHT = normalize(vec3(0.4, 1.0, 0.3));
+
+void main() {
+    float light = max(dot(normalize(world_normal), LIGHT), 0.0);
+    colour = vec4(vec3(0.7) * (0.25 + 0.75 * light), 1.0);
+}
@@ -0,0 +1,17 @@
+#version 450
+
+layout (location = 0) in vec3 position;
+layout (location = 1) in vec3 normal;
+layout (location = 2) in vec2 uv;
+layout (location = 3) in mat4 model;
+
+layout (push_constant) uniform View {
+    layout (offset = 16) mat4 view_projection;
+} view;
+
+layout (location = 0) out vec3 world_normal;
+
+void main() {
+    world_normal = mat3(model) * normal;
+    gl_Position = view.view_projection * model * vec4(position, 1.0);
+}
@@ -0,0 +1,244 @@
+//!
+//! A graphics backend that records calls instead of rendering
+//!
+//! Lets the app loop, scheduler and event dispatch run in tests without a GPU or a display. The calls are kept in a
+//! shared `MockRecorder`, so a test can hand the backend to an app and still inspect what it was asked to do
+//!
+
+use std::{collections::{HashMap, VecDeque}, sync::{Arc, Mutex}};
+
+use ash::vk;
+
+use crate::{graphics::{capture::CapturedFrame, events::SwapchainRefreshed, render_graph::ResourceKind}, unique::UniqueId};
+
+use super::{backend::{BackendError, FrameStatus, GraphicsBackend}, surface::{AcquireResult, PresentResult}};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MockCall {
+    BeginFrame,
+    Submit,
+    Present,
+    ReadFrame,
+    Recreate,
+    RecreateSurface,
+    CreateResource(String, ResourceKind),
+    DestroyResource(UniqueId),
+}
+
+/// Shared view of the calls made to a `MockGraphics`
+#[derive(Debug, Clone, Default)]
+pub struct MockRecorder {
+    calls: Arc<Mutex<Vec<MockCall>>>,
+}
+
+#[derive(Default)]
+pub struct MockGraphics {
+    recorder: MockRecorder,
+    /// Queued results for upcoming `begin_frame` calls, `Ready` once it runs dry
+    begin_results: VecDeque<FrameStatus>,
+    /// Queued results for upcoming `present` calls, `Ready` once it runs dry
+    present_results: VecDeque<FrameStatus>,
+    /// Queued swapchain outcomes for once `begin_results` and `present_results` run dry, turned into statuses the way
+    /// the Vulkan backend turns them
+    acquires: VecDeque<AcquireResult>,
+    presents: VecDeque<PresentResult>,
+    /// The last acquire was suboptimal
+    suboptimal: bool,
+    /// The next `begin_frame` reports the surface lost
+    surface_lost: bool,
+    /// Number of upcoming `recreate_surface` calls that fail
+    surface_failures: usize,
+    resources: HashMap<UniqueId, (String, ResourceKind)>,
+    /// Returned by `recreate`, for tests of apps reacting to a refreshed swapchain
+    refreshed: Option<SwapchainRefreshed>,
+    /// Returned by `read_frame`, a single black pixel unless set
+    frame: Option<CapturedFrame>,
+}
+
+// Impls
+
+impl MockRecorder {
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.lock().clone()
+    }
+
+    /// Number of recorded calls matching `predicate`
+    pub fn count(&self, predicate: impl Fn(&MockCall) -> bool) -> usize {
+        self.lock().iter().filter(|call| predicate(call)).count()
+    }
+
+    pub fn frames_presented(&self) -> usize {
+        self.count(|call| *call == MockCall::Present)
+    }
+
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn record(&self, call: MockCall) {
+        self.lock().push(call);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<MockCall>> {
+        self.calls.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl MockGraphics {
+    pub fn new() -> Self {
+        MockGraphics::default()
+    }
+
+    /// Queues the statuses the next `begin_frame` calls return
+    pub fn with_begin_results(mut self, results: &[FrameStatus]) -> Self {
+        self.begin_results.extend(results);
+        self
+    }
+
+    /// Queues the statuses the next `present` calls return
+    pub fn with_present_results(mut self, results: &[FrameStatus]) -> Self {
+        self.present_results.extend(results);
+        self
+    }
+
+    /// Queues the swapchain outcomes the next `begin_frame` calls acquire
+    pub fn with_acquire_results(mut self, results: &[AcquireResult]) -> Self {
+        self.acquires.extend(results);
+        self
+    }
+
+    /// Queues the swapchain outcomes the next `present` calls present with
+    pub fn with_swapchain_present_results(mut self, results: &[PresentResult]) -> Self {
+        self.presents.extend(results);
+        self
+    }
+
+    /// Loses the surface at the next `begin_frame`, after which the first `failures` attempts to recreate it fail
+    pub fn with_lost_surface(mut self, failures: usize) -> Self {
+        self.surface_lost = true;
+        self.surface_failures = failures;
+        self
+    }
+
+    /// Sets the swapchain every `recreate` reports refreshed
+    pub fn with_refreshed(mut self, refreshed: SwapchainRefreshed) -> Self {
+        self.refreshed = Some(refreshed);
+        self
+    }
+
+    /// Sets the frame `read_frame` returns
+    pub fn with_frame(mut self, frame: CapturedFrame) -> Self {
+        self.frame = Some(frame);
+        self
+    }
+
+    pub fn recorder(&self) -> MockRecorder {
+        self.recorder.clone()
+    }
+
+    /// Resources created and not yet destroyed
+    pub fn live_resources(&self) -> usize {
+        self.resources.len()
+    }
+}
+
+impl GraphicsBackend for MockGraphics {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn begin_frame(&mut self) -> Result<FrameStatus, BackendError> {
+        self.recorder.record(MockCall::BeginFrame);
+        if std::mem::take(&mut self.surface_lost) {
+            return Err(BackendError::Vulkan(vk::Result::ERROR_SURFACE_LOST_KHR))
+        }
+        if let Some(status) = self.begin_results.pop_front() {
+            return Ok(status)
+        }
+        let acquired = self.acquires.pop_front().unwrap_or(AcquireResult::Acquired { index: 0, suboptimal: false });
+        self.suboptimal = matches!(acquired, AcquireResult::Acquired { suboptimal: true, .. });
+        Ok(acquired.status())
+    }
+
+    fn submit(&mut self) -> Result<(), BackendError> {
+        self.recorder.record(MockCall::Submit);
+        Ok(())
+    }
+
+    fn present(&mut self) -> Result<FrameStatus, BackendError> {
+        self.recorder.record(MockCall::Present);
+        if let Some(status) = self.present_results.pop_front() {
+            return Ok(status)
+        }
+        let presented = self.presents.pop_front().unwrap_or(PresentResult::Presented);
+        Ok(presented.status(std::mem::take(&mut self.suboptimal)))
+    }
+
+    fn read_frame(&mut self) -> Result<CapturedFrame, BackendError> {
+        self.recorder.record(MockCall::ReadFrame);
+        Ok(self.frame.clone().unwrap_or_else(|| CapturedFrame::filled(1, 1, [0, 0, 0, 255])))
+    }
+
+    fn recreate(&mut self) -> Result<Option<SwapchainRefreshed>, BackendError> {
+        self.recorder.record(MockCall::Recreate);
+        Ok(self.refreshed)
+    }
+
+    fn recreate_surface(&mut self) -> Result<(), BackendError> {
+        self.recorder.record(MockCall::RecreateSurface);
+        match self.surface_failures {
+            0 => Ok(()),
+            _ => {
+                self.surface_failures -= 1;
+                Err(BackendError::Vulkan(vk::Result::ERROR_SURFACE_LOST_KHR))
+            },
+        }
+    }
+
+    fn create_resource(&mut self, name: &str, kind: ResourceKind) -> Result<UniqueId, BackendError> {
+        self.recorder.record(MockCall::CreateResource(String::from(name), kind));
+        let id = UniqueId::get();
+        self.resources.insert(id, (String::from(name), kind));
+        Ok(id)
+    }
+
+    fn destroy_resource(&mut self, resource: UniqueId) {
+        self.recorder.record(MockCall::DestroyResource(resource));
+        self.resources.remove(&resource);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_calls_and_replays_results() {
+        let mut mock = MockGraphics::new().with_begin_results(&[FrameStatus::Skip]);
+        let recorder = mock.recorder();
+
+        assert_eq!(mock.begin_frame().unwrap(), FrameStatus::Skip);
+        assert_eq!(mock.begin_frame().unwrap(), FrameStatus::Ready);
+        let buffer = mock.create_resource("vertices", ResourceKind::Buffer { size: 64 }).unwrap();
+        assert_eq!(mock.live_resources(), 1);
+        mock.destroy_resource(buffer);
+        assert_eq!(mock.live_resources(), 0);
+
+        assert_eq!(recorder.count(|call| *call == MockCall::BeginFrame), 2);
+        assert_eq!(recorder.calls().last(), Some(&MockCall::DestroyResource(buffer)));
+    }
+
+    #[test]
+    fn out_of_date_and_suboptimal_swapchains_request_a_recreate() {
+        let mock = MockGraphics::new()
+            .with_acquire_results(&[AcquireResult::OutOfDate, AcquireResult::Acquired { index: 0, suboptimal: true }])
+            .with_swapchain_present_results(&[PresentResult::Presented, PresentResult::Suboptimal, PresentResult::OutOfDate]);
+        let recorder = mock.recorder();
+        let mut app = crate::app::App::headless(mock);
+
+        // The out of date acquire, the suboptimal acquire once presented, then the suboptimal and out of date presents
+        assert_eq!(app.run_frames(3).unwrap(), 3);
+        assert_eq!(recorder.count(|call| *call == MockCall::Recreate), 4);
+        assert_eq!(recorder.calls().last(), Some(&MockCall::Present));
+    }
+}
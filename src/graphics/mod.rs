@@ -1,8 +1,38 @@
 mod vulkan_debug;
 pub mod vulkan_experimental;
+pub mod render_graph;
+pub mod events;
+pub mod device_report;
+pub mod diagnostics;
+pub mod camera;
+pub mod extract;
+pub mod backend;
+pub mod memory_budget;
+pub mod bindless;
+pub mod particles;
+pub mod terrain;
+pub mod tilemap;
+pub mod ui;
+pub mod lod;
+pub mod layers;
+pub mod targets;
+pub mod viewport;
+pub mod culling;
+pub mod staging;
+pub mod resources;
+pub mod device;
+pub mod mock;
+pub mod audit;
+pub mod watchdog;
+pub mod submission;
+pub mod capture;
+pub mod golden;
+pub mod capabilities;
 
 // old
 pub mod debug;
 pub mod render;
 pub mod surface;
-pub mod vulkangfx;
\ No newline at end of file
+pub mod vulkangfx;
+pub mod frame;
+pub mod textures;
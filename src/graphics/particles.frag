@@ -0,0 +1,12 @@
+#version 450
+
+layout (location = 0) in vec4 particle_color;
+layout (location = 1) in vec2 uv;
+
+layout (set = 0, binding = 0) uniform sampler2D albedo;
+
+layout (location = 0) out vec4 colour;
+
+void main() {
+    colour = particle_color * texture(albedo, uv);
+}
@@ -0,0 +1,613 @@
+//!
+//! Particles
+//!
+//! An entity with a `ParticleEmitter` and a `Transform` emits particles from its translation. Every frame the emitter's
+//! particles age, spawn and retire on the CPU, and are integrated either on the CPU or, once a renderer has set the
+//! simulation backend to `Compute`, by the simulation pass the renderer adds to its frame graph. Compute particles keep
+//! the position and velocity they spawned with, `ParticleCompute` places each one from its age and writes the result
+//! into its instance. Extraction turns live particles into `ParticleInstance`s batched by texture, drawn as instanced
+//! quads. Batches are recycled through a `Pool` from frame to frame, so their buffers stop reallocating once they've
+//! grown to the scene's particle count
+//!
+
+use std::collections::{BTreeMap, btree_map::Entry};
+
+use ash::vk;
+use collider::EntityId;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use serde::{Serialize, Deserialize};
+
+use crate::{asset::{self, pipeline::formats::Texture}, debug::log, memory::pool::{Pool, PoolHandle, PoolStats}, random, unique::Handle};
+use crate::system::{world::World, time::Time, transform::Transform};
+
+use super::{capabilities::{self, Feature}, layers::{self, RenderLayers}, render_graph::{Access, PassId, QueueKind, RenderGraph, ResourceId}};
+
+/// Emits particles from the entity's translation, in a cone around +Y
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ParticleEmitter {
+    /// Particles spawned per second
+    pub rate: f32,
+    /// Seconds each particle lives for
+    pub lifetime: f32,
+    pub speed: f32,
+    /// Half angle of the emission cone in radians
+    pub spread: f32,
+    pub gravity: [f32; 3],
+    pub start_size: f32,
+    pub end_size: f32,
+    pub start_color: [f32; 4],
+    pub end_color: [f32; 4],
+    pub max_particles: u32,
+    /// Texture asset path relative to the asset root, untextured particles are drawn as solid quads
+    pub texture: Option<String>,
+}
+
+/// One simulated particle, laid out to match the compute shader's storage buffer
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Particle {
+    pub position: [f32; 3],
+    pub age: f32,
+    pub velocity: [f32; 3],
+    pub lifetime: f32,
+}
+
+/// Runtime state of an emitter, added alongside it the first time it's simulated
+#[derive(Debug, Clone)]
+pub struct ParticleState {
+    particles: Vec<Particle>,
+    /// Fractional particles owed from earlier frames
+    owed: f32,
+    rng: StdRng,
+    texture: Option<Handle<Texture>>,
+    texture_resolved: bool,
+}
+
+/// Who integrates particle motion
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimulationBackend {
+    Cpu,
+    /// The renderer dispatches `ParticleCompute` each frame, the CPU only spawns and retires particles
+    Compute,
+}
+
+/// World resource selecting the simulation backend, the CPU until a renderer takes over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParticleSettings {
+    pub backend: SimulationBackend,
+}
+
+/// Per instance data of a particle quad
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ParticleInstance {
+    pub position: [f32; 3],
+    pub size: f32,
+    pub color: [f32; 4],
+}
+
+/// Instances sharing a texture, drawn with one instanced call
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParticleBatch {
+    pub texture: Option<Handle<Texture>>,
+    pub instances: Vec<ParticleInstance>,
+    /// With the `Compute` backend, the particle behind each instance, for the simulation pass to place it
+    pub particles: Vec<Particle>,
+    /// With the `Compute` backend, which of `particles` each emitter owns
+    pub emitters: Vec<EmitterRange>,
+}
+
+/// One emitter's particles within a batch, and the gravity they fall with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EmitterRange {
+    pub first: u32,
+    pub count: u32,
+    pub gravity: [f32; 3],
+}
+
+/// Batches the pool starts out with room for, it doubles from there
+const BATCH_POOL_CAPACITY: usize = 16;
+
+/// World resource of this frame's particle batches, written by extraction
+pub struct ExtractedParticles {
+    pool: Pool<ParticleBatch>,
+    batches: Vec<PoolHandle<ParticleBatch>>,
+}
+
+/// Push constants of the simulation shader, placing `count` particles starting at `first`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SimulationParams {
+    pub gravity: [f32; 3],
+    pub first: u32,
+    pub count: u32,
+}
+
+/// The compute pipeline placing a buffer of `Particle`s, writing their positions into a buffer of `ParticleInstance`s
+pub(crate) struct ParticleCompute {
+    set_layout: vk::DescriptorSetLayout,
+    layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+}
+
+// Impls
+
+impl Default for ParticleEmitter {
+    fn default() -> Self {
+        ParticleEmitter {
+            rate: 20.0,
+            lifetime: 2.0,
+            speed: 1.0,
+            spread: 0.3,
+            gravity: [0.0, -9.81, 0.0],
+            start_size: 0.1,
+            end_size: 0.0,
+            start_color: [1.0, 1.0, 1.0, 1.0],
+            end_color: [1.0, 1.0, 1.0, 0.0],
+            max_particles: 1000,
+            texture: None,
+        }
+    }
+}
+
+impl ParticleEmitter {
+    pub fn with_rate(mut self, rate: f32) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    pub fn with_lifetime(mut self, lifetime: f32) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    pub fn with_velocity(mut self, speed: f32, spread: f32) -> Self {
+        self.speed = speed;
+        self.spread = spread;
+        self
+    }
+
+    pub fn with_gravity(mut self, gravity: [f32; 3]) -> Self {
+        self.gravity = gravity;
+        self
+    }
+
+    pub fn with_size(mut self, start: f32, end: f32) -> Self {
+        self.start_size = start;
+        self.end_size = end;
+        self
+    }
+
+    pub fn with_color(mut self, start: [f32; 4], end: [f32; 4]) -> Self {
+        self.start_color = start;
+        self.end_color = end;
+        self
+    }
+
+    pub fn with_max_particles(mut self, max: u32) -> Self {
+        self.max_particles = max;
+        self
+    }
+
+    pub fn with_texture(mut self, path: &str) -> Self {
+        self.texture = Some(String::from(path));
+        self
+    }
+}
+
+impl ParticleBatch {
+    fn clear(&mut self) {
+        self.texture = None;
+        self.instances.clear();
+        self.particles.clear();
+        self.emitters.clear();
+    }
+}
+
+impl Default for ExtractedParticles {
+    fn default() -> Self {
+        ExtractedParticles {
+            pool: Pool::new(BATCH_POOL_CAPACITY, ParticleBatch::default).with_reset(ParticleBatch::clear),
+            batches: Vec::new(),
+        }
+    }
+}
+
+impl std::fmt::Debug for ExtractedParticles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExtractedParticles").field("batches", &self.batches.len()).field("pool", &self.pool.stats()).finish()
+    }
+}
+
+impl ExtractedParticles {
+    /// This frame's batches in texture order, none of them empty
+    pub fn batches(&self) -> impl Iterator<Item = &ParticleBatch> {
+        self.batches.iter().filter_map(|handle| self.pool.get(*handle))
+    }
+
+    /// Occupancy of the batch pool
+    pub fn pool_stats(&self) -> PoolStats {
+        self.pool.stats()
+    }
+
+    /// Hands the last frame's batches back to the pool
+    fn recycle(&mut self) {
+        for handle in self.batches.drain(..) {
+            self.pool.release(handle);
+        }
+    }
+
+    /// The batch for `texture` this frame, acquiring one from the pool the first time it's asked for
+    fn batch(&mut self, handles: &mut BTreeMap<Option<Handle<Texture>>, PoolHandle<ParticleBatch>>, texture: Option<Handle<Texture>>) -> Option<&mut ParticleBatch> {
+        let handle = match handles.entry(texture) {
+            Entry::Occupied(entry) => *entry.get(),
+            Entry::Vacant(entry) => *entry.insert(self.pool.acquire().ok()?),
+        };
+        let batch = self.pool.get_mut(handle)?;
+        batch.texture = texture;
+        Some(batch)
+    }
+}
+
+impl Default for ParticleState {
+    fn default() -> Self {
+        ParticleState::with_seed(rand::random())
+    }
+}
+
+impl ParticleState {
+    pub fn with_seed(seed: u64) -> Self {
+        ParticleState {
+            particles: Vec::new(),
+            owed: 0.0,
+            rng: StdRng::seed_from_u64(seed),
+            texture: None,
+            texture_resolved: false,
+        }
+    }
+
+    pub fn particles(&self) -> &[Particle] {
+        &self.particles
+    }
+
+    pub fn texture(&self) -> Option<Handle<Texture>> {
+        self.texture
+    }
+
+    /// Ages and retires particles, integrates them if `integrate` is set, then spawns new ones at `origin`
+    pub fn step(&mut self, emitter: &ParticleEmitter, origin: [f32; 3], delta: f32, integrate: bool) {
+        for particle in &mut self.particles {
+            particle.age += delta;
+            if integrate {
+                (0..3).for_each(|i| {
+                    particle.velocity[i] += emitter.gravity[i] * delta;
+                    particle.position[i] += particle.velocity[i] * delta;
+                });
+            }
+        }
+        self.particles.retain(|particle| particle.age < particle.lifetime);
+
+        self.owed += emitter.rate.max(0.0) * delta;
+        while self.owed >= 1.0 {
+            self.owed -= 1.0;
+            if self.particles.len() >= emitter.max_particles as usize {
+                continue
+            }
+            let direction = self.cone_direction(emitter.spread);
+            self.particles.push(Particle {
+                position: origin,
+                age: 0.0,
+                velocity: direction.map(|component| component * emitter.speed),
+                lifetime: emitter.lifetime,
+            });
+        }
+    }
+
+    /// A random unit vector within `spread` radians of +Y
+    fn cone_direction(&mut self, spread: f32) -> [f32; 3] {
+        let theta = self.rng.gen_range(0.0..std::f32::consts::TAU);
+        let cos_phi = 1.0 - self.rng.gen::<f32>() * (1.0 - spread.clamp(0.0, std::f32::consts::PI).cos());
+        let sin_phi = (1.0 - cos_phi * cos_phi).max(0.0).sqrt();
+        [sin_phi * theta.cos(), cos_phi, sin_phi * theta.sin()]
+    }
+
+    fn instances<'a>(&'a self, emitter: &'a ParticleEmitter) -> impl Iterator<Item = ParticleInstance> + 'a {
+        self.particles.iter().map(|particle| {
+            let t = if particle.lifetime > 0.0 { (particle.age / particle.lifetime).clamp(0.0, 1.0) } else { 1.0 };
+            ParticleInstance {
+                position: particle.position,
+                size: emitter.start_size + (emitter.end_size - emitter.start_size) * t,
+                color: std::array::from_fn(|i| emitter.start_color[i] + (emitter.end_color[i] - emitter.start_color[i]) * t),
+            }
+        })
+    }
+}
+
+impl SimulationBackend {
+    /// The compute backend where the renderer's capabilities allow it
+    pub fn detect() -> Self {
+        match capabilities::supports(Feature::GpuParticles) {
+            true => SimulationBackend::Compute,
+            false => SimulationBackend::Cpu,
+        }
+    }
+}
+
+impl Default for ParticleSettings {
+    fn default() -> Self {
+        ParticleSettings { backend: SimulationBackend::Cpu }
+    }
+}
+
+impl SimulationParams {
+    pub fn as_bytes(&self) -> &[u8] {
+        // Safety: `SimulationParams` is `repr(C)` and made only of 4 byte fields, so it has no padding
+        unsafe { std::slice::from_raw_parts(self as *const Self as *const u8, std::mem::size_of::<Self>()) }
+    }
+}
+
+impl ParticleInstance {
+    /// The instance rate vertex binding the particle quads read from
+    pub fn binding_description(binding: u32) -> vk::VertexInputBindingDescription {
+        vk::VertexInputBindingDescription {
+            binding,
+            stride: std::mem::size_of::<ParticleInstance>() as u32,
+            input_rate: vk::VertexInputRate::INSTANCE,
+        }
+    }
+
+    /// Position and size at location 0, color at location 1
+    pub fn attribute_descriptions(binding: u32) -> [vk::VertexInputAttributeDescription; 2] {
+        [
+            vk::VertexInputAttributeDescription { location: 0, binding, format: vk::Format::R32G32B32A32_SFLOAT, offset: 0 },
+            vk::VertexInputAttributeDescription { location: 1, binding, format: vk::Format::R32G32B32A32_SFLOAT, offset: 16 },
+        ]
+    }
+}
+
+impl ParticleCompute {
+    pub(crate) fn new(device: &ash::Device) -> Result<Self, vk::Result> {
+        let bindings = [0, 1].map(|binding| vk::DescriptorSetLayoutBinding::builder()
+            .binding(binding)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::COMPUTE)
+            .build());
+        let set_layout = unsafe { device.create_descriptor_set_layout(&vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings), None)? };
+
+        let push_constants = [vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: std::mem::size_of::<SimulationParams>() as u32,
+        }];
+        let set_layouts = [set_layout];
+        let layout_info = vk::PipelineLayoutCreateInfo::builder().set_layouts(&set_layouts).push_constant_ranges(&push_constants);
+        let layout = match unsafe { device.create_pipeline_layout(&layout_info, None) } {
+            Ok(layout) => layout,
+            Err(error) => {
+                unsafe { device.destroy_descriptor_set_layout(set_layout, None) };
+                return Err(error)
+            },
+        };
+
+        let shader_info = vk::ShaderModuleCreateInfo::builder()
+            .code(vk_shader_macros::include_glsl!("src/graphics/particles.comp", kind: comp));
+        let shader = unsafe { device.create_shader_module(&shader_info, None) };
+        let pipeline = shader.and_then(|shader| {
+            let name = std::ffi::CString::new("main").unwrap();
+            let stage = vk::PipelineShaderStageCreateInfo::builder().stage(vk::ShaderStageFlags::COMPUTE).module(shader).name(&name);
+            let pipeline_info = [vk::ComputePipelineCreateInfo::builder().stage(*stage).layout(layout).build()];
+            let pipelines = unsafe { device.create_compute_pipelines(vk::PipelineCache::null(), &pipeline_info, None) };
+            unsafe { device.destroy_shader_module(shader, None) };
+            pipelines.map(|pipelines| pipelines[0]).map_err(|(_, error)| error)
+        });
+
+        match pipeline {
+            Ok(pipeline) => Ok(ParticleCompute { set_layout, layout, pipeline }),
+            Err(error) => {
+                unsafe {
+                    device.destroy_pipeline_layout(layout, None);
+                    device.destroy_descriptor_set_layout(set_layout, None);
+                }
+                Err(error)
+            },
+        }
+    }
+
+    /// Layout of the set holding the particles at binding 0 and their instances at binding 1
+    pub(crate) fn set_layout(&self) -> vk::DescriptorSetLayout {
+        self.set_layout
+    }
+
+    /// Records the placement of `params.count` particles from the buffers bound in `set`
+    pub(crate) fn dispatch(&self, device: &ash::Device, command_buffer: vk::CommandBuffer, set: vk::DescriptorSet, params: &SimulationParams) {
+        unsafe {
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.pipeline);
+            device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE, self.layout, 0, &[set], &[]);
+            device.cmd_push_constants(command_buffer, self.layout, vk::ShaderStageFlags::COMPUTE, 0, params.as_bytes());
+            device.cmd_dispatch(command_buffer, params.count.div_ceil(64), 1, 1);
+        }
+    }
+
+    pub(crate) unsafe fn destroy(self, device: &ash::Device) {
+        device.destroy_pipeline(self.pipeline, None);
+        device.destroy_pipeline_layout(self.layout, None);
+        device.destroy_descriptor_set_layout(self.set_layout, None);
+    }
+}
+
+/// Steps every emitter by the frame's delta, run once a frame
+pub fn simulate_particles(world: &World) {
+    let delta = world.with_resource::<Time, _>(|time| time.delta_secs() as f32).unwrap_or(0.0);
+    let integrate = world.with_resource::<ParticleSettings, _>(|settings| settings.backend == SimulationBackend::Cpu).unwrap_or(true);
+
+    let emitters = world.query::<ParticleEmitter, ()>();
+    for entity in &emitters {
+        if !world.has_component::<ParticleState>(*entity) {
+            world.insert_component(*entity, ParticleState::with_seed(random::seed(world, "particles")));
+        }
+    }
+
+    // Each emitter steps with its own rng, so splitting them across threads doesn't change the result
+    world.par_for_each_mut::<ParticleState>(|entity, mut state| {
+        let Some(emitter) = world.component::<ParticleEmitter, _>(entity, ParticleEmitter::clone) else { return };
+        let origin = world.component::<Transform, _>(entity, |transform| transform.translation.map(|v| v as f32)).unwrap_or_default();
+        state.bypass_change_detection().step(&emitter, origin, delta, integrate);
+    });
+
+    for entity in emitters {
+        if let Some(emitter) = world.component::<ParticleEmitter, _>(entity, ParticleEmitter::clone) {
+            resolve_texture(world, entity, &emitter);
+        }
+    }
+}
+
+/// Loads the emitter's texture the first time it's simulated
+fn resolve_texture(world: &World, entity: EntityId, emitter: &ParticleEmitter) {
+    if world.component::<ParticleState, _>(entity, |state| state.texture_resolved).unwrap_or(true) {
+        return
+    }
+
+    let texture = emitter.texture.as_ref().and_then(|path| match asset::load::<Texture>(world, path) {
+        Ok(texture) => Some(texture),
+        Err(error) => {
+            log::get().with_topic("particles").warn(format!("unable to load particle texture {}: {}", path, error));
+            None
+        },
+    });
+    world.component_mut::<ParticleState, _>(entity, |mut state| {
+        let state = state.bypass_change_detection();
+        state.texture = texture;
+        state.texture_resolved = true;
+    });
+}
+
+/// Batches live particles of emitters on `layers` by texture into `ExtractedParticles`
+pub fn extract_particles(world: &World, layers: RenderLayers) {
+    let compute = world.with_resource::<ParticleSettings, _>(|settings| settings.backend == SimulationBackend::Compute).unwrap_or(false);
+
+    if !world.contains_resource::<ExtractedParticles>() {
+        world.insert_resource(ExtractedParticles::default());
+    }
+
+    world.with_resource_mut::<ExtractedParticles, _>(|extracted| {
+        extracted.recycle();
+        let mut handles = BTreeMap::new();
+        for entity in world.query::<ParticleState, ()>().into_iter().filter(|entity| layers::is_visible(world, *entity, layers)) {
+            let Some(emitter) = world.component::<ParticleEmitter, _>(entity, ParticleEmitter::clone) else { continue };
+            world.component::<ParticleState, _>(entity, |state| {
+                let Some(batch) = extracted.batch(&mut handles, state.texture) else { return };
+                if compute && !state.particles.is_empty() {
+                    let range = EmitterRange { first: batch.particles.len() as u32, count: state.particles.len() as u32, gravity: emitter.gravity };
+                    batch.emitters.push(range);
+                    batch.particles.extend_from_slice(&state.particles);
+                }
+                batch.instances.extend(state.instances(&emitter));
+            });
+        }
+
+        for handle in handles.into_values() {
+            if extracted.pool.get(handle).is_some_and(|batch| !batch.instances.is_empty()) {
+                extracted.batches.push(handle);
+            } else {
+                extracted.pool.release(handle);
+            }
+        }
+    });
+}
+
+/// Adds the simulation pass to `graph` on the compute queue, overlapping graphics work that doesn't draw particles.
+/// It reads the frame's `particles` and writes their positions into `instances`, both imported into the graph
+pub fn add_simulation_pass(graph: &mut RenderGraph, particles: ResourceId, instances: ResourceId) -> PassId {
+    graph.add_pass("particle simulation", QueueKind::Compute)
+        .reads(particles, Access::StorageRead)
+        .writes(instances, Access::StorageWrite)
+        .id()
+}
+
+/// Draws a batch as camera facing quads, four strip vertices per instance generated in the vertex shader. The batch's
+/// `count` instances start `offset` bytes into `instances`
+pub(crate) fn draw_batch(device: &ash::Device, command_buffer: vk::CommandBuffer, instances: vk::Buffer, offset: u64, count: u32) {
+    unsafe {
+        device.cmd_bind_vertex_buffers(command_buffer, 0, &[instances], &[offset]);
+        device.cmd_draw(command_buffer, 4, count, 0, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spawn_integrate_and_retire() {
+        let emitter = ParticleEmitter::default().with_rate(10.0).with_lifetime(0.15).with_max_particles(3).with_gravity([0.0; 3]).with_velocity(1.0, 0.0);
+        let mut state = ParticleState::with_seed(7);
+
+        state.step(&emitter, [1.0, 0.0, 0.0], 0.25, true);
+        assert_eq!(state.particles().len(), 2);
+        state.step(&emitter, [1.0, 0.0, 0.0], 0.1, true);
+        assert_eq!(state.particles().len(), 3);
+        // A spread of zero emits straight up at `speed`
+        assert!((state.particles()[0].position[1] - 0.1).abs() < 1e-5);
+
+        // The first two age out and one more spawns
+        state.step(&emitter, [1.0, 0.0, 0.0], 0.1, true);
+        assert_eq!(state.particles().len(), 2);
+        let instances: Vec<_> = state.instances(&emitter).collect();
+        assert!(instances.iter().all(|instance| instance.size <= emitter.start_size && instance.color[3] <= 1.0));
+    }
+
+    #[test]
+    fn compute_batches_carry_each_emitters_particles() {
+        let world = World::new();
+        world.insert_resource(ParticleSettings { backend: SimulationBackend::Compute });
+        for (seed, gravity) in [(1, [0.0, -1.0, 0.0]), (2, [0.0, -2.0, 0.0])] {
+            let emitter = ParticleEmitter::default().with_rate(10.0).with_gravity(gravity);
+            let mut state = ParticleState::with_seed(seed);
+            state.step(&emitter, [0.0; 3], 0.35, false);
+            let entity = world.spawn_entity();
+            world.insert_component(entity, emitter);
+            world.insert_component(entity, state);
+        }
+
+        extract_particles(&world, RenderLayers::default());
+        let batches = world.with_resource::<ExtractedParticles, _>(|extracted| extracted.batches().cloned().collect::<Vec<_>>()).unwrap();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.particles.len(), batch.instances.len());
+        assert_eq!(batch.emitters.len(), 2);
+        // Ranges follow each other, each with its own emitter's gravity
+        assert_eq!((batch.emitters[0].first, batch.emitters[1].first), (0, batch.emitters[0].count));
+        assert_eq!(batch.emitters[0].count + batch.emitters[1].count, batch.particles.len() as u32);
+        assert!(batch.emitters.iter().any(|range| range.gravity == [0.0, -2.0, 0.0]));
+    }
+
+    #[test]
+    fn batches_are_recycled_between_frames() {
+        let world = World::new();
+        let emitter = ParticleEmitter::default().with_rate(10.0);
+        let mut state = ParticleState::with_seed(3);
+        state.step(&emitter, [0.0; 3], 0.35, true);
+        let entity = world.spawn_entity();
+        world.insert_component(entity, emitter);
+        world.insert_component(entity, state);
+
+        for _ in 0..3 {
+            extract_particles(&world, RenderLayers::default());
+        }
+        let (stats, capacity) = world.with_resource::<ExtractedParticles, _>(|extracted| {
+            (extracted.pool_stats(), extracted.batches().next().unwrap().instances.capacity())
+        }).unwrap();
+        assert_eq!((stats.allocated, stats.in_use, stats.grows), (1, 1, 0));
+        assert!(capacity >= 3);
+
+        // Without particles the batch goes back to the pool rather than being drawn empty
+        world.despawn_entity(entity);
+        extract_particles(&world, RenderLayers::default());
+        world.with_resource::<ExtractedParticles, _>(|extracted| {
+            assert_eq!(extracted.batches().count(), 0);
+            assert_eq!((extracted.pool_stats().allocated, extracted.pool_stats().in_use), (1, 0));
+        });
+    }
+}
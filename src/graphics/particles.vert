@@ -0,0 +1,25 @@
+#version 450
+
+layout (location = 0) in vec4 position_size;
+layout (location = 1) in vec4 color;
+
+layout (push_constant) uniform View {
+    layout (offset = 16) mat4 view_projection;
+    vec4 right;
+    vec4 up;
+    vec4 origin;
+} view;
+
+layout (location = 0) out vec4 particle_color;
+layout (location = 1) out vec2 uv;
+
+// Four strip vertices per instance, the corners of a quad facing the camera
+void main() {
+    vec2 corner = vec2(gl_VertexIndex & 1, gl_VertexIndex >> 1);
+    vec3 offset = (corner.x - 0.5) * view.right.xyz + (corner.y - 0.5) * view.up.xyz;
+    vec3 position = position_size.xyz - view.origin.xyz + offset * position_size.w;
+
+    particle_color = color;
+    uv = vec2(corner.x, 1.0 - corner.y);
+    gl_Position = view.view_projection * vec4(position, 1.0);
+}
@@ -0,0 +1,20 @@
+#version 450
+#extension GL_EXT_nonuniform_qualifier : require
+
+layout (location = 0) in vec4 particle_color;
+layout (location = 1) in vec2 uv;
+
+layout (set = 0, binding = 0) uniform sampler2D textures[];
+
+layout (push_constant) uniform Material {
+    uint albedo;
+    uint normal;
+    uint roughness_metallic;
+    uint emissive;
+} material;
+
+layout (location = 0) out vec4 colour;
+
+void main() {
+    colour = particle_color * texture(textures[nonuniformEXT(material.albedo)], uv);
+}
@@ -1,15 +1,9 @@
 use ash::vk;
-use crate::graphics::{ surface, vulkangfx::GraphicsDevice };
+use crate::graphics::{ audit, surface, vulkangfx::GraphicsDevice, bindless::MaterialIndices, frame::{MeshInstance, MeshVertex, ViewParams, VIEW_PARAMS_OFFSET}, particles::ParticleInstance, textures::Textures };
 
 pub(crate) fn init_renderpass(graphics_device: &GraphicsDevice, physical_device: vk::PhysicalDevice, surfaces: &surface::GraphicsSurface) -> Result<vk::RenderPass, vk::Result> {
     let attachments = [vk::AttachmentDescription::builder()
-        .format(
-            surfaces
-                .get_formats(physical_device)?
-                .first()
-                .unwrap()
-                .format,
-        )
+        .format(audit::require(surfaces.get_formats(physical_device)?.first(), "choosing a surface format", vk::Result::ERROR_FORMAT_NOT_SUPPORTED)?.format)
         .load_op(vk::AttachmentLoadOp::CLEAR)
         .store_op(vk::AttachmentStoreOp::STORE)
         .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
@@ -49,6 +43,8 @@ pub(crate) fn init_renderpass(graphics_device: &GraphicsDevice, physical_device:
 
 pub(crate) struct Pipeline {
     pipeline: vk::Pipeline,
+    /// Draws particle batches, sharing the mesh pipeline's layout
+    particles: vk::Pipeline,
     layout: vk::PipelineLayout,
 }
 
@@ -56,6 +52,7 @@ impl Pipeline {
     pub(crate) fn cleanup(&self, logical_device: &ash::Device) {
         unsafe {
             logical_device.destroy_pipeline(self.pipeline, None);
+            logical_device.destroy_pipeline(self.particles, None);
             logical_device.destroy_pipeline_layout(self.layout, None);
         }
     }
@@ -63,14 +60,24 @@ impl Pipeline {
     pub(crate) fn pipeline(&self) -> vk::Pipeline {
         self.pipeline
     }
+
+    pub(crate) fn particles(&self) -> vk::Pipeline {
+        self.particles
+    }
+
+    pub(crate) fn layout(&self) -> vk::PipelineLayout {
+        self.layout
+    }
     
-    pub(crate) fn init(graphics_device: &GraphicsDevice, swapchain: &surface::Swapchain, renderpass: &vk::RenderPass) -> Result<Self, vk::Result> {
+    /// Draws meshes from a per vertex binding 0 and a per instance binding 1, and particles from a per instance binding
+    /// 0 sampling `textures` at set 0. Viewport and scissor are dynamic, every view sets its own while recording
+    pub(crate) fn init(graphics_device: &GraphicsDevice, renderpass: &vk::RenderPass, textures: &Textures) -> Result<Self, vk::Result> {
         let vertexshader_createinfo = vk::ShaderModuleCreateInfo::builder()
-            .code(vk_shader_macros::include_glsl!("src/graphics/shader.vert", kind: vert));
+            .code(vk_shader_macros::include_glsl!("src/graphics/mesh.vert", kind: vert));
         let vertexshader_module = graphics_device.create_shader_module(&vertexshader_createinfo)?;
         
         let fragmentshader_createinfo = vk::ShaderModuleCreateInfo::builder()
-            .code(vk_shader_macros::include_glsl!("src/graphics/shader.frag"));
+            .code(vk_shader_macros::include_glsl!("src/graphics/mesh.frag"));
         let fragmentshader_module = graphics_device.create_shader_module(&fragmentshader_createinfo)?;
 
         let mainfunctionname = std::ffi::CString::new("main").unwrap();
@@ -86,43 +93,53 @@ impl Pipeline {
         
         let shader_stages = vec![vertexshader_stage.build(), fragmentshader_stage.build()];
 
-// *** cont allocators
-        let vertex_attrib_descs = [vk::VertexInputAttributeDescription {
-            binding: 0,
-            location: 0,
-            offset: 0,
-            format: vk::Format::R32G32B32A32_SFLOAT,
-        }];
-
-        let vertex_binding_descs = [vk::VertexInputBindingDescription {
-            binding: 0,
-            stride: 16,
-            input_rate: vk::VertexInputRate::VERTEX,
-        }];
+        let particle_vertex_info = vk::ShaderModuleCreateInfo::builder()
+            .code(vk_shader_macros::include_glsl!("src/graphics/particles.vert", kind: vert));
+        let particle_vertex_module = graphics_device.create_shader_module(&particle_vertex_info)?;
+
+        let particle_fragment_code: &[u32] = match textures.is_bindless() {
+            true => vk_shader_macros::include_glsl!("src/graphics/particles_bindless.frag"),
+            false => vk_shader_macros::include_glsl!("src/graphics/particles.frag"),
+        };
+        let particle_fragment_info = vk::ShaderModuleCreateInfo::builder().code(particle_fragment_code);
+        let particle_fragment_module = graphics_device.create_shader_module(&particle_fragment_info)?;
+
+        let particle_stages = [
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::VERTEX)
+                .module(particle_vertex_module)
+                .name(&mainfunctionname)
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::FRAGMENT)
+                .module(particle_fragment_module)
+                .name(&mainfunctionname)
+                .build(),
+        ];
+
+        let vertex_attrib_descs: Vec<_> = MeshVertex::attribute_descriptions(0).into_iter().chain(MeshInstance::attribute_descriptions(1)).collect();
+        let vertex_binding_descs = [MeshVertex::binding_description(0), MeshInstance::binding_description(1)];
 
         let vertex_input_info = vk::PipelineVertexInputStateCreateInfo::builder()
             .vertex_attribute_descriptions(&vertex_attrib_descs)
             .vertex_binding_descriptions(&vertex_binding_descs);
 
         let input_assembly_info = vk::PipelineInputAssemblyStateCreateInfo::builder()
-            .topology(vk::PrimitiveTopology::POINT_LIST);
-
-        let viewports = [vk::Viewport {
-            x: 0.0,
-            y: 0.0,
-            width: swapchain.extent().width as f32,
-            height: swapchain.extent().height as f32,
-            min_depth: 0.0,
-            max_depth: 1.0,
-        }];
-        let scissors = [vk::Rect2D {
-            offset: vk::Offset2D { x: 0, y: 0 },
-            extent: swapchain.extent(),
-        }];
-        
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+
+        let particle_attrib_descs = ParticleInstance::attribute_descriptions(0);
+        let particle_binding_descs = [ParticleInstance::binding_description(0)];
+        let particle_input_info = vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_attribute_descriptions(&particle_attrib_descs)
+            .vertex_binding_descriptions(&particle_binding_descs);
+        let particle_assembly_info = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_STRIP);
+
         let viewport_info = vk::PipelineViewportStateCreateInfo::builder()
-            .viewports(&viewports)
-            .scissors(&scissors);
+            .viewport_count(1)
+            .scissor_count(1);
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_info = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
 
         let rasterizer_info = vk::PipelineRasterizationStateCreateInfo::builder()
             .line_width(1.0)
@@ -152,7 +169,15 @@ impl Pipeline {
         let colourblend_info =
             vk::PipelineColorBlendStateCreateInfo::builder().attachments(&colourblend_attachments);
 
-        let pipelinelayout_info = vk::PipelineLayoutCreateInfo::builder();
+        // One range for the material indices and the view's parameters after them, ranges can't share a stage
+        let push_constant_ranges = [vk::PushConstantRange {
+            size: VIEW_PARAMS_OFFSET + std::mem::size_of::<ViewParams>() as u32,
+            ..MaterialIndices::push_constant_range()
+        }];
+        let set_layouts = [textures.layout()];
+        let pipelinelayout_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&push_constant_ranges);
         let pipelinelayout = graphics_device.create_pipeline_layout(&pipelinelayout_info)?;
             
         let pipeline_info = vk::GraphicsPipelineCreateInfo::builder()
@@ -163,19 +188,36 @@ impl Pipeline {
             .rasterization_state(&rasterizer_info)
             .multisample_state(&multisampler_info)
             .color_blend_state(&colourblend_info)
+            .dynamic_state(&dynamic_info)
+            .layout(pipelinelayout)
+            .render_pass(*renderpass)
+            .subpass(0);
+
+        let particle_pipeline_info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&particle_stages)
+            .vertex_input_state(&particle_input_info)
+            .input_assembly_state(&particle_assembly_info)
+            .viewport_state(&viewport_info)
+            .rasterization_state(&rasterizer_info)
+            .multisample_state(&multisampler_info)
+            .color_blend_state(&colourblend_info)
+            .dynamic_state(&dynamic_info)
             .layout(pipelinelayout)
             .render_pass(*renderpass)
             .subpass(0);
         
-        let graphicspipeline = graphics_device.create_graphics_pipelines(&[pipeline_info.build()])[0];
+        let graphicspipelines = graphics_device.create_graphics_pipelines(&[pipeline_info.build(), particle_pipeline_info.build()])?;
         
         unsafe {
             graphics_device.destroy_shader_module(fragmentshader_module);
             graphics_device.destroy_shader_module(vertexshader_module);
+            graphics_device.destroy_shader_module(particle_fragment_module);
+            graphics_device.destroy_shader_module(particle_vertex_module);
         }
 
         Ok(Pipeline {
-            pipeline: graphicspipeline,
+            pipeline: graphicspipelines[0],
+            particles: graphicspipelines[1],
             layout: pipelinelayout,
         })
     }
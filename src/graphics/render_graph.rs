@@ -0,0 +1,608 @@
+//!
+//! Render graph: passes declare the resources they read and write, compiling orders them, culls passes whose output
+//! is never used, and derives the barriers and resource lifetimes the backend needs
+//!
+//! Passes may run on the graphics, compute or transfer queues. Where a pass uses a resource last touched on another
+//! queue the compiled graph records a `QueueSync`, and passes are grouped into per queue `Submission`s that wait on
+//! the submissions they depend on through semaphores. A compute pass nothing graphics is waiting on, like particle
+//! simulation or culling for the next view, gets its own submission and overlaps the graphics work around it
+//!
+//! A compiled graph can be dumped as Graphviz DOT or json to debug pass ordering. With `with_dump` set, every compile
+//! writes a dump, so each rebuild of the graph leaves a record
+//!
+
+use std::{collections::{HashMap, HashSet}, path::{Path, PathBuf}};
+
+use ash::vk;
+use serde::Serialize;
+
+use crate::debug::log;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub struct ResourceId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub struct PassId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceKind {
+    Image { width: u32, height: u32, format: vk::Format },
+    Buffer { size: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum QueueKind {
+    Graphics,
+    Compute,
+    Transfer,
+}
+
+/// How a pass uses a resource
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum Access {
+    ColorAttachment,
+    DepthAttachment,
+    ShaderRead,
+    StorageRead,
+    StorageWrite,
+    TransferSrc,
+    TransferDst,
+    VertexBuffer,
+    IndexBuffer,
+    IndirectBuffer,
+    Present,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum DumpFormat {
+    Dot,
+    Json,
+}
+
+#[derive(Debug)]
+pub enum RenderGraphError {
+    /// A pass reads a transient resource nothing wrote before it
+    ReadBeforeWrite { pass: String, resource: String },
+    UnknownResource(ResourceId),
+    Dump(PathBuf, std::io::Error),
+}
+
+#[derive(Debug, Clone)]
+struct Resource {
+    name: String,
+    kind: ResourceKind,
+    /// Owned outside the graph, e.g. a swapchain image. Imported resources may be read without a prior write
+    imported: bool,
+    /// Passes writing an output are never culled
+    output: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Pass {
+    name: String,
+    queue: QueueKind,
+    reads: Vec<(ResourceId, Access)>,
+    writes: Vec<(ResourceId, Access)>,
+}
+
+/// A render graph under construction
+#[derive(Debug, Default)]
+pub struct RenderGraph {
+    resources: Vec<Resource>,
+    passes: Vec<Pass>,
+    dump: Option<(PathBuf, DumpFormat)>,
+    builds: u64,
+}
+
+/// Declares a pass's resource usage, returned by `RenderGraph::add_pass`
+pub struct PassBuilder<'a> {
+    graph: &'a mut RenderGraph,
+    pass: PassId,
+}
+
+/// Synchronization needed before `before` can use `resource` as `to`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Barrier {
+    pub resource: ResourceId,
+    pub before: PassId,
+    pub from: Access,
+    pub to: Access,
+}
+
+/// A resource handed between queues, `before` waits on a semaphore the submission holding `after` signals
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct QueueSync {
+    pub resource: ResourceId,
+    pub after: PassId,
+    pub before: PassId,
+    pub from: QueueKind,
+    pub to: QueueKind,
+    /// How `before` uses the resource, which decides the stage its wait blocks
+    pub access: Access,
+}
+
+/// Passes submitted together to one queue, in order. Submissions are submitted in the order they're listed, each
+/// waiting on the earlier submissions in `waits` first
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Submission {
+    pub queue: QueueKind,
+    pub passes: Vec<PassId>,
+    /// Indices of the submissions this one waits on
+    pub waits: Vec<usize>,
+}
+
+/// First and last pass using a resource, in execution order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Lifetime {
+    pub resource: ResourceId,
+    pub first: PassId,
+    pub last: PassId,
+}
+
+/// The result of compiling a `RenderGraph`
+#[derive(Debug, Clone)]
+pub struct CompiledGraph {
+    resources: Vec<Resource>,
+    passes: Vec<Pass>,
+    order: Vec<PassId>,
+    culled: Vec<PassId>,
+    barriers: Vec<Barrier>,
+    lifetimes: Vec<Lifetime>,
+    syncs: Vec<QueueSync>,
+    submissions: Vec<Submission>,
+}
+
+#[derive(Serialize)]
+struct ResourceDump<'a> {
+    id: ResourceId,
+    name: &'a str,
+    kind: String,
+    imported: bool,
+    output: bool,
+}
+
+#[derive(Serialize)]
+struct PassDump<'a> {
+    id: PassId,
+    name: &'a str,
+    queue: QueueKind,
+    reads: &'a [(ResourceId, Access)],
+    writes: &'a [(ResourceId, Access)],
+}
+
+#[derive(Serialize)]
+struct GraphDump<'a> {
+    resources: Vec<ResourceDump<'a>>,
+    passes: Vec<PassDump<'a>>,
+    order: &'a [PassId],
+    culled: &'a [PassId],
+    barriers: &'a [Barrier],
+    lifetimes: &'a [Lifetime],
+    syncs: &'a [QueueSync],
+    submissions: &'a [Submission],
+}
+
+// Impls
+
+impl std::error::Error for RenderGraphError {}
+
+impl std::fmt::Display for RenderGraphError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RenderGraphError::ReadBeforeWrite { pass, resource } => write!(f, "pass {} reads {} before anything writes it", pass, resource),
+            RenderGraphError::UnknownResource(id) => write!(f, "unknown render graph resource {:?}", id),
+            RenderGraphError::Dump(path, err) => write!(f, "unable to write render graph dump {}: {}", path.display(), err),
+        }
+    }
+}
+
+impl Access {
+    pub fn is_write(&self) -> bool {
+        matches!(self, Access::ColorAttachment | Access::DepthAttachment | Access::StorageWrite | Access::TransferDst)
+    }
+
+    /// Pipeline stages that use a resource this way
+    pub fn stage(&self) -> vk::PipelineStageFlags {
+        match self {
+            Access::ColorAttachment => vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            Access::DepthAttachment => vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            Access::ShaderRead | Access::StorageRead | Access::StorageWrite => vk::PipelineStageFlags::VERTEX_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER,
+            Access::TransferSrc | Access::TransferDst => vk::PipelineStageFlags::TRANSFER,
+            Access::VertexBuffer | Access::IndexBuffer => vk::PipelineStageFlags::VERTEX_INPUT,
+            Access::IndirectBuffer => vk::PipelineStageFlags::DRAW_INDIRECT,
+            Access::Present => vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+        }
+    }
+
+    /// Memory accesses of a resource used this way
+    pub fn access_mask(&self) -> vk::AccessFlags {
+        match self {
+            Access::ColorAttachment => vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            Access::DepthAttachment => vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            Access::ShaderRead | Access::StorageRead => vk::AccessFlags::SHADER_READ,
+            Access::StorageWrite => vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+            Access::TransferSrc => vk::AccessFlags::TRANSFER_READ,
+            Access::TransferDst => vk::AccessFlags::TRANSFER_WRITE,
+            Access::VertexBuffer => vk::AccessFlags::VERTEX_ATTRIBUTE_READ,
+            Access::IndexBuffer => vk::AccessFlags::INDEX_READ,
+            Access::IndirectBuffer => vk::AccessFlags::INDIRECT_COMMAND_READ,
+            Access::Present => vk::AccessFlags::empty(),
+        }
+    }
+}
+
+impl<'a> PassBuilder<'a> {
+    pub fn id(&self) -> PassId {
+        self.pass
+    }
+
+    pub fn reads(self, resource: ResourceId, access: Access) -> Self {
+        self.graph.passes[self.pass.0].reads.push((resource, access));
+        self
+    }
+
+    pub fn writes(self, resource: ResourceId, access: Access) -> Self {
+        self.graph.passes[self.pass.0].writes.push((resource, access));
+        self
+    }
+}
+
+impl RenderGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Writes a dump to `directory` every time the graph is compiled
+    pub fn with_dump<P: Into<PathBuf>>(mut self, directory: P, format: DumpFormat) -> Self {
+        self.dump = Some((directory.into(), format));
+        self
+    }
+
+    /// A resource created and owned by the graph
+    pub fn add_resource(&mut self, name: &str, kind: ResourceKind) -> ResourceId {
+        self.push_resource(name, kind, false)
+    }
+
+    /// A resource owned outside the graph, like a swapchain image
+    pub fn import_resource(&mut self, name: &str, kind: ResourceKind) -> ResourceId {
+        self.push_resource(name, kind, true)
+    }
+
+    /// Marks a resource as a final output of the graph, keeping every pass that contributes to it
+    pub fn mark_output(&mut self, resource: ResourceId) {
+        if let Some(resource) = self.resources.get_mut(resource.0) {
+            resource.output = true;
+        }
+    }
+
+    /// Adds a pass, which runs after every previously added pass it depends on
+    pub fn add_pass(&mut self, name: &str, queue: QueueKind) -> PassBuilder<'_> {
+        self.passes.push(Pass { name: String::from(name), queue, reads: Vec::new(), writes: Vec::new() });
+        let pass = PassId(self.passes.len() - 1);
+        PassBuilder { graph: self, pass }
+    }
+
+    pub fn clear(&mut self) {
+        self.resources.clear();
+        self.passes.clear();
+    }
+
+    /// Number of times the graph has been compiled
+    pub fn builds(&self) -> u64 {
+        self.builds
+    }
+
+    pub fn compile(&mut self) -> Result<CompiledGraph, RenderGraphError> {
+        self.validate()?;
+
+        // Walk backwards from the outputs, keeping the last writer of every resource a live pass reads. Every writer
+        // of an output is kept, later passes may only draw over part of it
+        let mut live = vec![false; self.passes.len()];
+        let mut needed: HashSet<ResourceId> = self.resources.iter().enumerate()
+            .filter(|(_, r)| r.output)
+            .map(|(i, _)| ResourceId(i))
+            .collect();
+        for (index, pass) in self.passes.iter().enumerate().rev() {
+            if pass.writes.iter().any(|(r, _)| needed.contains(r)) {
+                live[index] = true;
+                pass.writes.iter().filter(|(r, _)| !self.resources[r.0].output).for_each(|(r, _)| { needed.remove(r); });
+                needed.extend(pass.reads.iter().map(|(r, _)| *r));
+            }
+        }
+
+        let order: Vec<PassId> = (0..self.passes.len()).filter(|i| live[*i]).map(PassId).collect();
+        let culled: Vec<PassId> = (0..self.passes.len()).filter(|i| !live[*i]).map(PassId).collect();
+
+        let mut barriers = Vec::new();
+        let mut syncs = Vec::new();
+        let mut last_access: Vec<Option<(Access, PassId)>> = vec![None; self.resources.len()];
+        let mut lifetimes: Vec<Option<Lifetime>> = vec![None; self.resources.len()];
+        for pass_id in &order {
+            let pass = &self.passes[pass_id.0];
+            for (resource, access) in pass.reads.iter().chain(pass.writes.iter()) {
+                if let Some((from, after)) = last_access[resource.0] {
+                    if from != *access || from.is_write() {
+                        barriers.push(Barrier { resource: *resource, before: *pass_id, from, to: *access });
+                    }
+                    let from_queue = self.passes[after.0].queue;
+                    if from_queue != pass.queue {
+                        syncs.push(QueueSync { resource: *resource, after, before: *pass_id, from: from_queue, to: pass.queue, access: *access });
+                    }
+                }
+                last_access[resource.0] = Some((*access, *pass_id));
+
+                let lifetime = lifetimes[resource.0].get_or_insert(Lifetime { resource: *resource, first: *pass_id, last: *pass_id });
+                lifetime.last = *pass_id;
+            }
+        }
+
+        let submissions = self.submissions(&order, &syncs);
+        let compiled = CompiledGraph {
+            resources: self.resources.clone(),
+            passes: self.passes.clone(),
+            order,
+            culled,
+            barriers,
+            lifetimes: lifetimes.into_iter().flatten().collect(),
+            syncs,
+            submissions,
+        };
+
+        self.builds += 1;
+        if let Some((directory, format)) = &self.dump {
+            let path = compiled.write_dump(directory, *format, self.builds)?;
+            log::get().info(format!("render graph build {} dumped to {}", self.builds, path.display()));
+        }
+
+        Ok(compiled)
+    }
+
+    /// Groups passes into submissions. A pass joins its queue's open submission when that already waits on everything
+    /// the pass needs from other queues, otherwise it starts a new one so the passes before it don't wait needlessly.
+    /// A submission another queue waits on is closed, so nothing added later delays its signal
+    fn submissions(&self, order: &[PassId], syncs: &[QueueSync]) -> Vec<Submission> {
+        let mut submissions: Vec<Submission> = Vec::new();
+        let mut open: HashMap<QueueKind, usize> = HashMap::new();
+        let mut submission_of: HashMap<PassId, usize> = HashMap::new();
+
+        for pass in order {
+            let queue = self.passes[pass.0].queue;
+            let mut waits: Vec<usize> = syncs.iter().filter(|sync| sync.before == *pass).map(|sync| submission_of[&sync.after]).collect();
+            waits.sort_unstable();
+            waits.dedup();
+            for wait in &waits {
+                open.retain(|_, submission| submission != wait);
+            }
+
+            let index = match open.get(&queue) {
+                Some(index) if waits.iter().all(|wait| submissions[*index].waits.contains(wait)) => *index,
+                _ => {
+                    submissions.push(Submission { queue, passes: Vec::new(), waits });
+                    open.insert(queue, submissions.len() - 1);
+                    submissions.len() - 1
+                },
+            };
+            submissions[index].passes.push(*pass);
+            submission_of.insert(*pass, index);
+        }
+        submissions
+    }
+
+    fn push_resource(&mut self, name: &str, kind: ResourceKind, imported: bool) -> ResourceId {
+        self.resources.push(Resource { name: String::from(name), kind, imported, output: false });
+        ResourceId(self.resources.len() - 1)
+    }
+
+    fn validate(&self) -> Result<(), RenderGraphError> {
+        let mut written = vec![false; self.resources.len()];
+        for pass in &self.passes {
+            for (resource, _) in pass.reads.iter().chain(pass.writes.iter()) {
+                if resource.0 >= self.resources.len() {
+                    return Err(RenderGraphError::UnknownResource(*resource))
+                }
+            }
+            for (resource, _) in &pass.reads {
+                let r = &self.resources[resource.0];
+                if !r.imported && !written[resource.0] {
+                    return Err(RenderGraphError::ReadBeforeWrite { pass: pass.name.clone(), resource: r.name.clone() })
+                }
+            }
+            pass.writes.iter().for_each(|(r, _)| written[r.0] = true);
+        }
+        Ok(())
+    }
+}
+
+impl CompiledGraph {
+    /// Passes in execution order
+    pub fn order(&self) -> &[PassId] {
+        &self.order
+    }
+
+    /// Passes removed because nothing used their output
+    pub fn culled(&self) -> &[PassId] {
+        &self.culled
+    }
+
+    pub fn barriers(&self) -> &[Barrier] {
+        &self.barriers
+    }
+
+    pub fn lifetimes(&self) -> &[Lifetime] {
+        &self.lifetimes
+    }
+
+    /// Resources handed between queues
+    pub fn syncs(&self) -> &[QueueSync] {
+        &self.syncs
+    }
+
+    /// Passes grouped per queue, in submission order
+    pub fn submissions(&self) -> &[Submission] {
+        &self.submissions
+    }
+
+    pub fn pass_name(&self, pass: PassId) -> &str {
+        &self.passes[pass.0].name
+    }
+
+    pub fn resource_name(&self, resource: ResourceId) -> &str {
+        &self.resources[resource.0].name
+    }
+
+    /// Graphviz DOT: passes are boxes, resources ellipses, culled passes dashed. Edges into a pass that needs a
+    /// barrier are labelled with the transition
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph render_graph {\n    rankdir=LR;\n");
+
+        for (index, resource) in self.resources.iter().enumerate() {
+            let shape = if resource.imported { "doubleoctagon" } else { "ellipse" };
+            let bold = if resource.output { ", style=bold" } else { "" };
+            dot.push_str(&format!("    r{} [label=\"{}\\n{}\", shape={}{}];\n", index, escape(&resource.name), resource.kind.describe(), shape, bold));
+        }
+
+        for (index, pass) in self.passes.iter().enumerate() {
+            let style = if self.culled.contains(&PassId(index)) { ", style=dashed" } else { "" };
+            let position = self.order.iter().position(|p| p.0 == index).map_or_else(|| String::from("culled"), |p| format!("#{}", p));
+            dot.push_str(&format!("    p{} [label=\"{}\\n{:?} {}\", shape=box{}];\n", index, escape(&pass.name), pass.queue, position, style));
+
+            for (resource, access) in &pass.reads {
+                let label = self.barrier_label(*resource, PassId(index)).unwrap_or_else(|| format!("{:?}", access));
+                dot.push_str(&format!("    r{} -> p{} [label=\"{}\"];\n", resource.0, index, label));
+            }
+            for (resource, access) in &pass.writes {
+                dot.push_str(&format!("    p{} -> r{} [label=\"{:?}\"];\n", index, resource.0, access));
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    pub fn to_json(&self) -> String {
+        let dump = GraphDump {
+            resources: self.resources.iter().enumerate().map(|(i, r)| ResourceDump {
+                id: ResourceId(i),
+                name: &r.name,
+                kind: r.kind.describe(),
+                imported: r.imported,
+                output: r.output,
+            }).collect(),
+            passes: self.passes.iter().enumerate().map(|(i, p)| PassDump {
+                id: PassId(i),
+                name: &p.name,
+                queue: p.queue,
+                reads: &p.reads,
+                writes: &p.writes,
+            }).collect(),
+            order: &self.order,
+            culled: &self.culled,
+            barriers: &self.barriers,
+            lifetimes: &self.lifetimes,
+            syncs: &self.syncs,
+            submissions: &self.submissions,
+        };
+        serde_json::to_string_pretty(&dump).expect("unable to serialize render graph")
+    }
+
+    fn write_dump(&self, directory: &Path, format: DumpFormat, build: u64) -> Result<PathBuf, RenderGraphError> {
+        let (contents, extension) = match format {
+            DumpFormat::Dot => (self.to_dot(), "dot"),
+            DumpFormat::Json => (self.to_json(), "json"),
+        };
+        let path = directory.join(format!("render_graph_{}.{}", build, extension));
+        std::fs::create_dir_all(directory)
+            .and_then(|_| std::fs::write(&path, contents))
+            .map_err(|err| RenderGraphError::Dump(path.clone(), err))?;
+        Ok(path)
+    }
+
+    fn barrier_label(&self, resource: ResourceId, pass: PassId) -> Option<String> {
+        self.barriers.iter()
+            .find(|b| b.resource == resource && b.before == pass)
+            .map(|b| format!("{:?} -> {:?}", b.from, b.to))
+    }
+}
+
+impl ResourceKind {
+    fn describe(&self) -> String {
+        match self {
+            ResourceKind::Image { width, height, format } => format!("{}x{} {:?}", width, height, format),
+            ResourceKind::Buffer { size } => format!("{} bytes", size),
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image() -> ResourceKind {
+        ResourceKind::Image { width: 1920, height: 1080, format: vk::Format::R8G8B8A8_UNORM }
+    }
+
+    #[test]
+    fn compile_orders_culls_and_inserts_barriers() {
+        let mut graph = RenderGraph::new();
+        let backbuffer = graph.import_resource("backbuffer", image());
+        let gbuffer = graph.add_resource("gbuffer", image());
+        let debug = graph.add_resource("debug overlay", image());
+        graph.mark_output(backbuffer);
+
+        let geometry = graph.add_pass("geometry", QueueKind::Graphics).writes(gbuffer, Access::ColorAttachment).id();
+        let unused = graph.add_pass("debug", QueueKind::Graphics).writes(debug, Access::ColorAttachment).id();
+        let lighting = graph.add_pass("lighting", QueueKind::Graphics)
+            .reads(gbuffer, Access::ShaderRead)
+            .writes(backbuffer, Access::ColorAttachment)
+            .id();
+
+        let compiled = graph.compile().unwrap();
+        assert_eq!(compiled.order(), &[geometry, lighting]);
+        assert_eq!(compiled.culled(), &[unused]);
+        assert_eq!(compiled.barriers(), &[Barrier { resource: gbuffer, before: lighting, from: Access::ColorAttachment, to: Access::ShaderRead }]);
+        assert!(compiled.to_dot().contains("ColorAttachment -> ShaderRead"));
+        assert_eq!(graph.builds(), 1);
+    }
+
+    #[test]
+    fn async_compute_overlaps_independent_graphics() {
+        let mut graph = RenderGraph::new();
+        let backbuffer = graph.import_resource("backbuffer", image());
+        let instances = graph.import_resource("instances", ResourceKind::Buffer { size: 1024 });
+        let draws = graph.add_resource("draws", ResourceKind::Buffer { size: 1024 });
+        let shadow = graph.add_resource("shadow map", image());
+        graph.mark_output(backbuffer);
+
+        let culling = graph.add_pass("culling", QueueKind::Compute)
+            .reads(instances, Access::StorageRead)
+            .writes(draws, Access::StorageWrite)
+            .id();
+        let shadows = graph.add_pass("shadows", QueueKind::Graphics).writes(shadow, Access::DepthAttachment).id();
+        let main = graph.add_pass("main", QueueKind::Graphics)
+            .reads(draws, Access::IndirectBuffer)
+            .reads(shadow, Access::ShaderRead)
+            .writes(backbuffer, Access::ColorAttachment)
+            .id();
+
+        let compiled = graph.compile().unwrap();
+        assert_eq!(compiled.syncs(), &[QueueSync { resource: draws, after: culling, before: main, from: QueueKind::Compute, to: QueueKind::Graphics, access: Access::IndirectBuffer }]);
+        // Shadows don't wait on culling, only the main pass does
+        assert_eq!(compiled.submissions(), &[
+            Submission { queue: QueueKind::Compute, passes: vec![culling], waits: vec![] },
+            Submission { queue: QueueKind::Graphics, passes: vec![shadows], waits: vec![] },
+            Submission { queue: QueueKind::Graphics, passes: vec![main], waits: vec![0] },
+        ]);
+    }
+
+    #[test]
+    fn reading_unwritten_transient_fails() {
+        let mut graph = RenderGraph::new();
+        let shadow = graph.add_resource("shadow map", image());
+        graph.add_pass("lighting", QueueKind::Graphics).reads(shadow, Access::ShaderRead);
+        assert!(matches!(graph.compile(), Err(RenderGraphError::ReadBeforeWrite { .. })));
+    }
+}
@@ -0,0 +1,347 @@
+//!
+//! Owned Vulkan resources
+//!
+//! Each wrapper owns its handle, and its memory where it has any, and destroys them on drop through the shared device
+//! it holds. Resources that depend on others hold them too, a view keeps its image alive and a framebuffer its views,
+//! so nothing can be destroyed while something built from it still exists. Every live wrapper is counted, and the
+//! device checks in debug builds that none are left when it's destroyed, naming the kinds that leaked
+//!
+
+use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+
+use ash::vk;
+
+use super::device::SharedDevice;
+
+static BUFFERS: AtomicUsize = AtomicUsize::new(0);
+static IMAGES: AtomicUsize = AtomicUsize::new(0);
+static IMAGE_VIEWS: AtomicUsize = AtomicUsize::new(0);
+static SAMPLERS: AtomicUsize = AtomicUsize::new(0);
+static FRAMEBUFFERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Wrappers alive right now, by kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LiveResources {
+    pub buffers: usize,
+    pub images: usize,
+    pub image_views: usize,
+    pub samplers: usize,
+    pub framebuffers: usize,
+}
+
+pub struct Buffer {
+    device: Arc<SharedDevice>,
+    buffer: vk::Buffer,
+    memory: vk::DeviceMemory,
+    size: u64,
+}
+
+pub struct Image {
+    device: Arc<SharedDevice>,
+    image: vk::Image,
+    memory: vk::DeviceMemory,
+    extent: vk::Extent3D,
+    format: vk::Format,
+}
+
+/// A view of an owned image, or of a swapchain image which the swapchain owns
+pub struct ImageView {
+    device: Arc<SharedDevice>,
+    view: vk::ImageView,
+    image: Option<Arc<Image>>,
+}
+
+pub struct Sampler {
+    device: Arc<SharedDevice>,
+    sampler: vk::Sampler,
+}
+
+/// A framebuffer and the views attached to it. The render pass it's compatible with isn't owned
+pub struct Framebuffer {
+    device: Arc<SharedDevice>,
+    framebuffer: vk::Framebuffer,
+    extent: vk::Extent2D,
+    _attachments: Vec<Arc<ImageView>>,
+}
+
+// Impls
+
+impl LiveResources {
+    pub fn is_empty(&self) -> bool {
+        *self == LiveResources::default()
+    }
+}
+
+impl std::fmt::Display for LiveResources {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} buffers, {} images, {} image views, {} samplers, {} framebuffers", self.buffers, self.images, self.image_views, self.samplers, self.framebuffers)
+    }
+}
+
+impl Buffer {
+    pub fn new(device: &Arc<SharedDevice>, memory_properties: &vk::PhysicalDeviceMemoryProperties, size: u64, usage: vk::BufferUsageFlags, flags: vk::MemoryPropertyFlags) -> Result<Self, vk::Result> {
+        let buffer_info = vk::BufferCreateInfo::builder().size(size).usage(usage).sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let buffer = unsafe { device.create_buffer(&buffer_info, None)? };
+
+        let requirements = unsafe { device.get_buffer_memory_requirements(buffer) };
+        let memory = allocate(device, memory_properties, requirements, flags)
+            .and_then(|memory| match unsafe { device.bind_buffer_memory(buffer, memory, 0) } {
+                Ok(()) => Ok(memory),
+                Err(error) => {
+                    unsafe { device.free_memory(memory, None) };
+                    Err(error)
+                },
+            });
+        match memory {
+            Ok(memory) => {
+                BUFFERS.fetch_add(1, Ordering::Relaxed);
+                Ok(Buffer { device: device.clone(), buffer, memory, size })
+            },
+            Err(error) => {
+                unsafe { device.destroy_buffer(buffer, None) };
+                Err(error)
+            },
+        }
+    }
+
+    pub fn handle(&self) -> vk::Buffer {
+        self.buffer
+    }
+
+    pub fn memory(&self) -> vk::DeviceMemory {
+        self.memory
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_buffer(self.buffer, None);
+            self.device.free_memory(self.memory, None);
+        }
+        BUFFERS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Image {
+    /// A single mip, single layer 2D image in device local memory
+    pub fn new_2d(device: &Arc<SharedDevice>, memory_properties: &vk::PhysicalDeviceMemoryProperties, extent: vk::Extent2D, format: vk::Format, usage: vk::ImageUsageFlags) -> Result<Self, vk::Result> {
+        let extent = vk::Extent3D { width: extent.width, height: extent.height, depth: 1 };
+        let image_info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(extent)
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+        let image = unsafe { device.create_image(&image_info, None)? };
+
+        let requirements = unsafe { device.get_image_memory_requirements(image) };
+        let memory = allocate(device, memory_properties, requirements, vk::MemoryPropertyFlags::DEVICE_LOCAL)
+            .and_then(|memory| match unsafe { device.bind_image_memory(image, memory, 0) } {
+                Ok(()) => Ok(memory),
+                Err(error) => {
+                    unsafe { device.free_memory(memory, None) };
+                    Err(error)
+                },
+            });
+        match memory {
+            Ok(memory) => {
+                IMAGES.fetch_add(1, Ordering::Relaxed);
+                Ok(Image { device: device.clone(), image, memory, extent, format })
+            },
+            Err(error) => {
+                unsafe { device.destroy_image(image, None) };
+                Err(error)
+            },
+        }
+    }
+
+    pub fn handle(&self) -> vk::Image {
+        self.image
+    }
+
+    pub fn extent(&self) -> vk::Extent3D {
+        self.extent
+    }
+
+    pub fn format(&self) -> vk::Format {
+        self.format
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_image(self.image, None);
+            self.device.free_memory(self.memory, None);
+        }
+        IMAGES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ImageView {
+    /// A view of every mip and layer of `image`
+    pub fn new(image: &Arc<Image>, aspect: vk::ImageAspectFlags) -> Result<Self, vk::Result> {
+        let view = create_view(&image.device, image.image, image.format, aspect)?;
+        IMAGE_VIEWS.fetch_add(1, Ordering::Relaxed);
+        Ok(ImageView { device: image.device.clone(), view, image: Some(image.clone()) })
+    }
+
+    /// A color view of a swapchain image, which must outlive the view
+    pub fn swapchain(device: &Arc<SharedDevice>, image: vk::Image, format: vk::Format) -> Result<Self, vk::Result> {
+        let view = create_view(device, image, format, vk::ImageAspectFlags::COLOR)?;
+        IMAGE_VIEWS.fetch_add(1, Ordering::Relaxed);
+        Ok(ImageView { device: device.clone(), view, image: None })
+    }
+
+    pub fn handle(&self) -> vk::ImageView {
+        self.view
+    }
+
+    /// The owned image viewed, `None` for swapchain images
+    pub fn image(&self) -> Option<&Arc<Image>> {
+        self.image.as_ref()
+    }
+}
+
+impl Drop for ImageView {
+    fn drop(&mut self) {
+        unsafe { self.device.destroy_image_view(self.view, None) };
+        IMAGE_VIEWS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Sampler {
+    pub fn new(device: &Arc<SharedDevice>, info: &vk::SamplerCreateInfo) -> Result<Self, vk::Result> {
+        let sampler = unsafe { device.create_sampler(info, None)? };
+        SAMPLERS.fetch_add(1, Ordering::Relaxed);
+        Ok(Sampler { device: device.clone(), sampler })
+    }
+
+    /// Linear filtering, repeating in every direction
+    pub fn linear(device: &Arc<SharedDevice>) -> Result<Self, vk::Result> {
+        let info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::REPEAT)
+            .address_mode_v(vk::SamplerAddressMode::REPEAT)
+            .address_mode_w(vk::SamplerAddressMode::REPEAT)
+            .max_lod(vk::LOD_CLAMP_NONE);
+        Sampler::new(device, &info)
+    }
+
+    pub fn handle(&self) -> vk::Sampler {
+        self.sampler
+    }
+}
+
+impl Drop for Sampler {
+    fn drop(&mut self) {
+        unsafe { self.device.destroy_sampler(self.sampler, None) };
+        SAMPLERS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Framebuffer {
+    pub fn new(device: &Arc<SharedDevice>, render_pass: vk::RenderPass, attachments: Vec<Arc<ImageView>>, extent: vk::Extent2D) -> Result<Self, vk::Result> {
+        let views: Vec<vk::ImageView> = attachments.iter().map(|view| view.handle()).collect();
+        let info = vk::FramebufferCreateInfo::builder()
+            .render_pass(render_pass)
+            .attachments(&views)
+            .width(extent.width)
+            .height(extent.height)
+            .layers(1);
+        let framebuffer = unsafe { device.create_framebuffer(&info, None)? };
+        FRAMEBUFFERS.fetch_add(1, Ordering::Relaxed);
+        Ok(Framebuffer { device: device.clone(), framebuffer, extent, _attachments: attachments })
+    }
+
+    pub fn handle(&self) -> vk::Framebuffer {
+        self.framebuffer
+    }
+
+    pub fn extent(&self) -> vk::Extent2D {
+        self.extent
+    }
+}
+
+impl Drop for Framebuffer {
+    fn drop(&mut self) {
+        unsafe { self.device.destroy_framebuffer(self.framebuffer, None) };
+        FRAMEBUFFERS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts of every wrapper not yet dropped
+pub fn live_resources() -> LiveResources {
+    LiveResources {
+        buffers: BUFFERS.load(Ordering::Relaxed),
+        images: IMAGES.load(Ordering::Relaxed),
+        image_views: IMAGE_VIEWS.load(Ordering::Relaxed),
+        samplers: SAMPLERS.load(Ordering::Relaxed),
+        framebuffers: FRAMEBUFFERS.load(Ordering::Relaxed),
+    }
+}
+
+/// Index of a memory type allowed by `type_bits` with every flag in `flags`
+pub(crate) fn memory_type(properties: &vk::PhysicalDeviceMemoryProperties, type_bits: u32, flags: vk::MemoryPropertyFlags) -> Option<u32> {
+    (0..properties.memory_type_count).find(|index| {
+        type_bits & (1 << index) != 0 && properties.memory_types[*index as usize].property_flags.contains(flags)
+    })
+}
+
+fn allocate(device: &ash::Device, properties: &vk::PhysicalDeviceMemoryProperties, requirements: vk::MemoryRequirements, flags: vk::MemoryPropertyFlags) -> Result<vk::DeviceMemory, vk::Result> {
+    let memory_type = memory_type(properties, requirements.memory_type_bits, flags).ok_or(vk::Result::ERROR_OUT_OF_DEVICE_MEMORY)?;
+    let info = vk::MemoryAllocateInfo::builder().allocation_size(requirements.size).memory_type_index(memory_type);
+    unsafe { device.allocate_memory(&info, None) }
+}
+
+fn create_view(device: &ash::Device, image: vk::Image, format: vk::Format, aspect: vk::ImageAspectFlags) -> Result<vk::ImageView, vk::Result> {
+    let info = vk::ImageViewCreateInfo::builder()
+        .image(image)
+        .view_type(vk::ImageViewType::TYPE_2D)
+        .format(format)
+        .subresource_range(vk::ImageSubresourceRange {
+            aspect_mask: aspect,
+            base_mip_level: 0,
+            level_count: vk::REMAINING_MIP_LEVELS,
+            base_array_layer: 0,
+            layer_count: vk::REMAINING_ARRAY_LAYERS,
+        });
+    unsafe { device.create_image_view(&info, None) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_types_need_every_flag_and_an_allowed_bit() {
+        let mut properties = vk::PhysicalDeviceMemoryProperties { memory_type_count: 3, ..Default::default() };
+        properties.memory_types[0].property_flags = vk::MemoryPropertyFlags::DEVICE_LOCAL;
+        properties.memory_types[1].property_flags = vk::MemoryPropertyFlags::HOST_VISIBLE;
+        properties.memory_types[2].property_flags = vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+        let host = vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+
+        assert_eq!(memory_type(&properties, 0b111, vk::MemoryPropertyFlags::HOST_VISIBLE), Some(1));
+        assert_eq!(memory_type(&properties, 0b111, host), Some(2));
+        assert_eq!(memory_type(&properties, 0b011, host), None);
+    }
+
+    #[test]
+    fn leaks_are_reported_by_kind() {
+        let leaked = LiveResources { images: 2, framebuffers: 1, ..Default::default() };
+        assert!(!leaked.is_empty());
+        assert_eq!(leaked.to_string(), "0 buffers, 2 images, 0 image views, 0 samplers, 1 framebuffers");
+    }
+}
@@ -0,0 +1,187 @@
+//!
+//! Staging belt
+//!
+//! One persistently mapped, host coherent buffer that per frame uniform, vertex and upload data is written into,
+//! instead of creating and mapping buffers every frame. Writes go around the buffer as a ring. Each frame's writes
+//! are fenced when the frame is submitted, and the space is reclaimed once that fence signals, so the CPU never
+//! overwrites data the GPU may still read
+//!
+
+use std::{collections::VecDeque, sync::Arc};
+
+use ash::vk;
+
+use crate::debug::log;
+
+use super::{device::SharedDevice, resources::Buffer};
+
+/// Ring bookkeeping of the belt, offsets into a buffer of `capacity` bytes
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RingAllocator {
+    capacity: u64,
+    head: u64,
+    tail: u64,
+    used: u64,
+    /// Bytes consumed by the frame being written, including alignment and wrap padding
+    current: u64,
+    /// Bytes consumed by each finished frame still in flight, oldest first
+    in_flight: VecDeque<u64>,
+}
+
+/// A range of the belt's buffer holding written data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BeltSlice {
+    pub buffer: vk::Buffer,
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// The belt's buffer, its memory mapped for its whole lifetime, and the fences guarding frames in flight
+pub(crate) struct StagingBelt {
+    buffer: Buffer,
+    mapped: *mut u8,
+    /// Least alignment of every write, enough for the buffer to be bound as a uniform or storage buffer at any slice
+    alignment: u64,
+    ring: RingAllocator,
+    fences: VecDeque<vk::Fence>,
+}
+
+// Impls
+
+impl RingAllocator {
+    pub fn new(capacity: u64) -> Self {
+        RingAllocator { capacity, ..Default::default() }
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Bytes held by frames in flight and the frame being written
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
+    pub fn frames_in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Offset of `size` free bytes aligned to `align`, wrapping to the start when they don't fit before the end. `None`
+    /// when the ring is too full
+    pub fn allocate(&mut self, size: u64, align: u64) -> Option<u64> {
+        if self.used == 0 {
+            self.head = 0;
+            self.tail = 0;
+        }
+
+        let free = self.capacity - self.used;
+        let aligned = self.head.next_multiple_of(align.max(1));
+        let (offset, consumed) = match aligned + size <= self.capacity {
+            true => (aligned, aligned + size - self.head),
+            false => (0, self.capacity - self.head + size),
+        };
+        if consumed > free {
+            return None
+        }
+
+        self.head = (offset + size) % self.capacity.max(1);
+        self.used += consumed;
+        self.current += consumed;
+        Some(offset)
+    }
+
+    /// Ends the frame being written, its space is held until `release_frame`
+    pub fn finish_frame(&mut self) {
+        self.in_flight.push_back(std::mem::take(&mut self.current));
+    }
+
+    /// Frees the oldest finished frame's space, returning false when no frame is in flight
+    pub fn release_frame(&mut self) -> bool {
+        let Some(consumed) = self.in_flight.pop_front() else { return false };
+        self.used -= consumed;
+        self.tail = (self.tail + consumed) % self.capacity.max(1);
+        true
+    }
+}
+
+impl StagingBelt {
+    pub(crate) fn new(instance: &ash::Instance, physical_device: vk::PhysicalDevice, device: &Arc<SharedDevice>, capacity: u64) -> Result<Self, vk::Result> {
+        let limits = unsafe { instance.get_physical_device_properties(physical_device).limits };
+        let alignment = limits.min_uniform_buffer_offset_alignment.max(limits.min_storage_buffer_offset_alignment).max(16);
+
+        let usage = vk::BufferUsageFlags::UNIFORM_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::VERTEX_BUFFER
+            | vk::BufferUsageFlags::INDEX_BUFFER | vk::BufferUsageFlags::TRANSFER_SRC;
+        let properties = unsafe { instance.get_physical_device_memory_properties(physical_device) };
+        let flags = vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+        let buffer = Buffer::new(device, &properties, capacity, usage, flags)?;
+        let mapped = unsafe { device.map_memory(buffer.memory(), 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())? };
+
+        log::get().with_topic("staging").info(format!("staging belt of {} bytes", capacity));
+        Ok(StagingBelt { buffer, mapped: mapped as *mut u8, alignment, ring: RingAllocator::new(capacity), fences: VecDeque::new() })
+    }
+
+    /// Copies `values` into the belt for this frame. `None` when the belt is full, which means too much is written per
+    /// frame or frames aren't being recalled
+    pub(crate) fn write<T: Copy>(&mut self, values: &[T]) -> Option<BeltSlice> {
+        let size = std::mem::size_of_val(values) as u64;
+        let align = self.alignment.max(std::mem::align_of::<T>() as u64);
+        let Some(offset) = self.ring.allocate(size, align) else {
+            log::get().with_topic("staging").warn(format!("staging belt full, dropped a {} byte write", size));
+            return None
+        };
+
+        // Safety: the ring only hands out ranges inside the mapped buffer that no frame in flight still holds
+        unsafe { std::ptr::copy_nonoverlapping(values.as_ptr() as *const u8, self.mapped.add(offset as usize), size as usize) };
+        Some(BeltSlice { buffer: self.buffer.handle(), offset, size })
+    }
+
+    /// Ends the frame's writes, holding them until `fence` signals
+    pub(crate) fn finish_frame(&mut self, fence: vk::Fence) {
+        self.ring.finish_frame();
+        self.fences.push_back(fence);
+    }
+
+    /// Reclaims the space of every frame whose fence has signalled. Call before the frame's fence is reset for reuse
+    pub(crate) fn recall(&mut self, device: &ash::Device) {
+        while let Some(fence) = self.fences.front() {
+            if !unsafe { device.get_fence_status(*fence) }.unwrap_or(false) {
+                break
+            }
+            self.fences.pop_front();
+            self.ring.release_frame();
+        }
+    }
+
+    /// Unmaps the buffer, dropping it destroys it
+    pub(crate) unsafe fn destroy(self, device: &ash::Device) {
+        device.unmap_memory(self.buffer.memory());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_wraps_and_waits_for_frames() {
+        let mut ring = RingAllocator::new(256);
+        assert_eq!(ring.allocate(100, 16), Some(0));
+        assert_eq!(ring.allocate(100, 16), Some(112));
+        ring.finish_frame();
+
+        // The next write doesn't fit before the end and the start is still in flight
+        assert_eq!(ring.allocate(64, 16), None);
+        assert!(ring.release_frame());
+        assert_eq!(ring.used(), 0);
+
+        assert_eq!(ring.allocate(200, 16), Some(0));
+        ring.finish_frame();
+        assert_eq!(ring.allocate(40, 16), Some(208));
+        // Only wraps to the start once the frame holding it is released
+        assert_eq!(ring.allocate(100, 16), None);
+        assert!(ring.release_frame());
+        assert_eq!(ring.allocate(100, 16), Some(0));
+        ring.finish_frame();
+        assert_eq!(ring.frames_in_flight(), 1);
+    }
+}
@@ -0,0 +1,164 @@
+//!
+//! Queue submission batching
+//!
+//! Passes hand their command buffers to a `SubmissionBatcher` instead of calling `vkQueueSubmit` themselves. Work is
+//! held per queue until a flush, which submits everything pending on that queue in a single call. Submissions that
+//! don't wait on anything are folded into the previous one's command buffers when it signals nothing, so a frame of
+//! plain passes becomes one `VkSubmitInfo`. The end of the frame flushes every queue with `flush_all`, and work that
+//! something is waiting on right away, like an upload the next pass reads or a frame about to be presented, can be
+//! flushed early with `flush`
+//!
+
+use ash::vk;
+
+use crate::graphics::device::SharedDevice;
+
+/// Command buffers to submit together, with the semaphores they wait on and signal
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Submission {
+    command_buffers: Vec<vk::CommandBuffer>,
+    waits: Vec<(vk::Semaphore, vk::PipelineStageFlags)>,
+    signals: Vec<vk::Semaphore>,
+}
+
+/// Submission counts since the batcher was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BatchStats {
+    /// Submissions pushed
+    pub submissions: u64,
+    /// `vkQueueSubmit` calls they were flushed in
+    pub queue_submits: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct SubmissionBatcher {
+    /// Pending submissions, indexed by the device's queue index
+    pending: Vec<Vec<Submission>>,
+    stats: BatchStats,
+}
+
+// Impls
+
+impl Submission {
+    pub fn new(command_buffers: &[vk::CommandBuffer]) -> Self {
+        Submission { command_buffers: command_buffers.to_vec(), ..Default::default() }
+    }
+
+    /// Waits for `semaphore` before `stage` runs
+    pub fn with_wait(mut self, semaphore: vk::Semaphore, stage: vk::PipelineStageFlags) -> Self {
+        self.waits.push((semaphore, stage));
+        self
+    }
+
+    /// Signals `semaphore` once the command buffers complete
+    pub fn with_signal(mut self, semaphore: vk::Semaphore) -> Self {
+        self.signals.push(semaphore);
+        self
+    }
+
+    /// Appends `next` to this submission if it runs the same either way
+    fn merge(&mut self, next: &Submission) -> bool {
+        if !self.signals.is_empty() || !next.waits.is_empty() {
+            return false
+        }
+        self.command_buffers.extend_from_slice(&next.command_buffers);
+        self.signals.extend_from_slice(&next.signals);
+        true
+    }
+}
+
+impl SubmissionBatcher {
+    pub fn new() -> Self {
+        SubmissionBatcher::default()
+    }
+
+    pub fn stats(&self) -> BatchStats {
+        self.stats
+    }
+
+    /// Submissions waiting on the queue at `queue`
+    pub fn pending(&self, queue: usize) -> usize {
+        self.pending.get(queue).map_or(0, Vec::len)
+    }
+
+    /// Queues `submission` on the queue at `queue` until the next flush
+    pub fn push(&mut self, queue: usize, submission: Submission) {
+        if self.pending.len() <= queue {
+            self.pending.resize_with(queue + 1, Vec::new);
+        }
+        self.pending[queue].push(submission);
+        self.stats.submissions += 1;
+    }
+
+    /// Submits everything pending on the queue at `queue` in one call, signalling `fence` when it completes. With nothing
+    /// pending the fence is still submitted so it signals
+    pub fn flush(&mut self, device: &SharedDevice, queue: usize, fence: vk::Fence) -> Result<(), vk::Result> {
+        self.flush_with(queue, fence, |submits, fence| device.submit(queue, submits, fence))
+    }
+
+    /// Flushes every queue with pending work, without fences
+    pub fn flush_all(&mut self, device: &SharedDevice) -> Result<(), vk::Result> {
+        (0..self.pending.len()).try_for_each(|queue| self.flush(device, queue, vk::Fence::null()))
+    }
+
+    fn flush_with(&mut self, queue: usize, fence: vk::Fence, submit: impl FnOnce(&[vk::SubmitInfo], vk::Fence) -> Result<(), vk::Result>) -> Result<(), vk::Result> {
+        let pending = self.pending.get_mut(queue).map(std::mem::take).unwrap_or_default();
+        if pending.is_empty() && fence == vk::Fence::null() {
+            return Ok(())
+        }
+
+        let mut batched: Vec<Submission> = Vec::with_capacity(pending.len());
+        for submission in pending {
+            if !batched.last_mut().is_some_and(|last| last.merge(&submission)) {
+                batched.push(submission);
+            }
+        }
+
+        // The submit infos point into these, so they're split out up front and outlive the call
+        let stages: Vec<(Vec<vk::Semaphore>, Vec<vk::PipelineStageFlags>)> = batched.iter()
+            .map(|submission| submission.waits.iter().copied().unzip())
+            .collect();
+        let submits: Vec<vk::SubmitInfo> = batched.iter().zip(&stages).map(|(submission, (semaphores, stages))| {
+            vk::SubmitInfo::builder()
+                .wait_semaphores(semaphores)
+                .wait_dst_stage_mask(stages)
+                .command_buffers(&submission.command_buffers)
+                .signal_semaphores(&submission.signals)
+                .build()
+        }).collect();
+
+        self.stats.queue_submits += 1;
+        submit(&submits, fence)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ash::vk::Handle;
+
+    #[test]
+    fn submissions_are_batched_per_queue() {
+        let buffer = |raw: u64| vk::CommandBuffer::from_raw(raw);
+        let (acquired, finished) = (vk::Semaphore::from_raw(1), vk::Semaphore::from_raw(2));
+        let mut batcher = SubmissionBatcher::new();
+        batcher.push(0, Submission::new(&[buffer(1)]));
+        batcher.push(0, Submission::new(&[buffer(2), buffer(3)]));
+        // Has to wait, so it can't join the first two
+        batcher.push(0, Submission::new(&[buffer(4)]).with_wait(acquired, vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT).with_signal(finished));
+        batcher.push(1, Submission::new(&[buffer(5)]));
+        assert_eq!((batcher.pending(0), batcher.pending(1)), (3, 1));
+
+        let mut calls = Vec::new();
+        batcher.flush_with(0, vk::Fence::null(), |submits, _| {
+            calls.push(submits.iter().map(|submit| (submit.command_buffer_count, submit.wait_semaphore_count, submit.signal_semaphore_count)).collect::<Vec<_>>());
+            Ok(())
+        }).unwrap();
+        assert_eq!(calls, vec![vec![(3, 0, 0), (1, 1, 1)]]);
+        assert_eq!((batcher.pending(0), batcher.pending(1)), (0, 1));
+
+        // An empty flush only submits when there's a fence to signal
+        batcher.flush_with(0, vk::Fence::null(), |_, _| panic!("nothing to submit")).unwrap();
+        assert_eq!(batcher.stats(), BatchStats { submissions: 4, queue_submits: 1 });
+    }
+}
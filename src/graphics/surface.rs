@@ -1,6 +1,9 @@
 use ash::vk;
 
-use crate::graphics::vulkangfx::{GraphicsDevice, QueueFamilies};
+use crate::graphics::{audit, backend::FrameStatus, vulkangfx::{GraphicsDevice, QueueFamilies}};
+
+/// Frames the CPU may record ahead of the GPU. Synchronization objects are per frame, not per swapchain image
+pub(crate) const FRAMES_IN_FLIGHT: usize = 2;
 
 pub(crate) struct GraphicsSurface {
     _wayland_surface_loader: ash::extensions::khr::WaylandSurface,
@@ -11,8 +14,8 @@ pub(crate) struct GraphicsSurface {
 impl GraphicsSurface {
     pub(crate) fn init(window: &winit::window::Window, entry: &ash::Entry, instance: &ash::Instance) -> Result<Self, vk::Result> {
         use winit::platform::unix::WindowExtUnix;
-        let wayland_display = window.wayland_display().unwrap();
-        let wayland_surface = window.wayland_surface().unwrap();
+        let wayland_display = window.wayland_display().ok_or(vk::Result::ERROR_SURFACE_LOST_KHR)?;
+        let wayland_surface = window.wayland_surface().ok_or(vk::Result::ERROR_SURFACE_LOST_KHR)?;
         let wayland_create_info = vk::WaylandSurfaceCreateInfoKHR::builder()
             .display(wayland_display)
             .surface(wayland_surface);
@@ -27,6 +30,15 @@ impl GraphicsSurface {
         })
     }
 
+    /// A placeholder holding no surface, used while a lost surface is being replaced
+    pub(crate) fn null(entry: &ash::Entry, instance: &ash::Instance) -> Self {
+        Self {
+            _wayland_surface_loader: ash::extensions::khr::WaylandSurface::new(entry, instance),
+            surface_loader: ash::extensions::khr::Surface::new(entry, instance),
+            surface: vk::SurfaceKHR::null(),
+        }
+    }
+
     pub(crate) fn get_capabilities(&self, physical_device: vk::PhysicalDevice) -> Result<vk::SurfaceCapabilitiesKHR, vk::Result> {
         unsafe {
             self.surface_loader.get_physical_device_surface_capabilities(physical_device, self.surface)
@@ -61,33 +73,95 @@ impl Drop for GraphicsSurface {
     }
 }
 
+/// Outcome of acquiring the next swapchain image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcquireResult {
+    /// An image was acquired. A suboptimal swapchain can still be presented to, but should be recreated soon
+    Acquired { index: usize, suboptimal: bool },
+    /// The swapchain no longer matches the surface and must be recreated before rendering
+    OutOfDate,
+    /// No image became available in time, the frame should be skipped
+    Timeout,
+}
+
+/// Outcome of presenting a swapchain image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PresentResult {
+    Presented,
+    Suboptimal,
+    OutOfDate,
+}
+
 pub(crate) struct Swapchain {
     swapchain_loader: ash::extensions::khr::Swapchain,
     swapchain: vk::SwapchainKHR,
     images: Vec<vk::Image>,
     imageviews: Vec<vk::ImageView>,
     framebuffers: Vec<vk::Framebuffer>,
-    _surface_format: vk::SurfaceFormatKHR,
+    surface_format: vk::SurfaceFormatKHR,
     extent: vk::Extent2D,
+    /// Per frame in flight
     image_available: Vec<vk::Semaphore>,
+    /// Per frame in flight
     rendering_finished: Vec<vk::Semaphore>,
+    /// Per frame in flight
     draw_fences: Vec<vk::Fence>,
-    current_image: usize,
+    /// Per swapchain image, the fence of the frame last rendering into it, or null
+    images_in_flight: Vec<vk::Fence>,
+    current_frame: usize,
+    /// Whether presents are tagged with ids, which `VK_KHR_present_wait` waits on
+    present_ids: bool,
+    /// Id of the latest present, ids start at 1 for each swapchain
+    present_id: u64,
+    /// Whether images can be copied from, which frame capture needs
+    readable: bool,
+}
+
+impl AcquireResult {
+    /// What the frame does next, the status a backend's `begin_frame` returns
+    pub(crate) fn status(&self) -> FrameStatus {
+        match self {
+            AcquireResult::Acquired { .. } => FrameStatus::Ready,
+            AcquireResult::OutOfDate => FrameStatus::Recreate,
+            AcquireResult::Timeout => FrameStatus::Skip,
+        }
+    }
+}
+
+impl PresentResult {
+    /// What the next frame does first, the status a backend's `present` returns. A swapchain that was suboptimal when
+    /// the image was acquired is recreated too, once the image has been presented
+    pub(crate) fn status(&self, acquired_suboptimal: bool) -> FrameStatus {
+        match self {
+            PresentResult::Presented if !acquired_suboptimal => FrameStatus::Ready,
+            _ => FrameStatus::Recreate,
+        }
+    }
 }
 
 impl Swapchain {
-    pub fn init(instance: &ash::Instance, physical_device: vk::PhysicalDevice, graphics_device: &GraphicsDevice, surfaces: &GraphicsSurface, queue_families: &QueueFamilies) -> Result<Self, vk::Result> {
+    /// Creates the swapchain. Without `vsync` an immediate or mailbox present mode is used when the surface offers one
+    pub fn init(instance: &ash::Instance, physical_device: vk::PhysicalDevice, graphics_device: &GraphicsDevice, surfaces: &GraphicsSurface, queue_families: &QueueFamilies, vsync: bool) -> Result<Self, vk::Result> {
         let surface_capabilities = surfaces.get_capabilities(physical_device)?;
 
-        let _surface_present_modes = surfaces.get_present_modes(physical_device)?;
-        let surface_format = *surfaces.get_formats(physical_device)?.first().unwrap();
-        let vec_queue_families = vec![queue_families.graphics_queue_index().unwrap()];
+        let surface_present_modes = surfaces.get_present_modes(physical_device)?;
+        let present_mode = match vsync {
+            true => vk::PresentModeKHR::FIFO,
+            false => [vk::PresentModeKHR::IMMEDIATE, vk::PresentModeKHR::MAILBOX].into_iter()
+                .find(|mode| surface_present_modes.contains(mode))
+                .unwrap_or(vk::PresentModeKHR::FIFO),
+        };
+        let surface_format = *audit::require(surfaces.get_formats(physical_device)?.first(), "choosing a surface format", vk::Result::ERROR_FORMAT_NOT_SUPPORTED)?;
+        let vec_queue_families = vec![audit::require(queue_families.graphics_queue_index(), "finding a graphics queue family", vk::Result::ERROR_INITIALIZATION_FAILED)?];
         let vk_surface = surfaces.surface;
         let logical_device = graphics_device.logical_device();
 
         let (image_width, image_height) = (800,600);
         let extent = vk::Extent2D::builder().width(image_width).height(image_height).build();
 
+        // Frame capture copies out of the presented images
+        let image_usage = vk::ImageUsageFlags::COLOR_ATTACHMENT | (surface_capabilities.supported_usage_flags & vk::ImageUsageFlags::TRANSFER_SRC);
+
         let swapchain_create_info = vk::SwapchainCreateInfoKHR::builder()
             .surface(vk_surface)
             .min_image_count(3.max(surface_capabilities.min_image_count))
@@ -95,12 +169,12 @@ impl Swapchain {
             .image_color_space(surface_format.color_space)
             .image_extent(extent)             //  <--- Change this to a real extent
             .image_array_layers(1)
-            .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
+            .image_usage(image_usage)
             .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
             .queue_family_indices(&vec_queue_families)
             .pre_transform(surface_capabilities.current_transform)
             .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
-            .present_mode(vk::PresentModeKHR::FIFO);    
+            .present_mode(present_mode);    
         let swapchain_loader = ash::extensions::khr::Swapchain::new(&instance, &logical_device);
         let swapchain = unsafe { swapchain_loader.create_swapchain(&swapchain_create_info, None)? };
 
@@ -119,7 +193,7 @@ impl Swapchain {
             let imageview_create_info = vk::ImageViewCreateInfo::builder()
                 .image(*image)
                 .view_type(vk::ImageViewType::TYPE_2D)
-                .format(vk::Format::B8G8R8A8_SRGB)
+                .format(surface_format.format)
                 .subresource_range(*subresource_range);
 
             let imageview = unsafe { logical_device.create_image_view(&imageview_create_info, None) }?;
@@ -131,7 +205,7 @@ impl Swapchain {
         let mut draw_fences = vec![];
         let semaphore_create_info = vk::SemaphoreCreateInfo::builder();
         let fence_create_info = vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
-        for _ in 0..images.len() {
+        for _ in 0..FRAMES_IN_FLIGHT {
             let semaphore_available = unsafe { logical_device.create_semaphore(&semaphore_create_info, None) }?;
             let semaphore_finished = unsafe { logical_device.create_semaphore(&semaphore_create_info, None) }?;
             let drawing_fence = unsafe { logical_device.create_fence(&fence_create_info, None) }?; 
@@ -143,29 +217,47 @@ impl Swapchain {
         Ok( Swapchain {
             swapchain_loader,
             swapchain,
-            images,
             imageviews,
             framebuffers: Vec::new(),
-            _surface_format: surface_format,
+            surface_format,
             extent,
             image_available,
             rendering_finished,
             draw_fences,
-            current_image: 0usize,
+            images_in_flight: vec![vk::Fence::null(); images.len()],
+            images,
+            current_frame: 0usize,
+            present_ids: graphics_device.present_wait().is_some(),
+            present_id: 0,
+            readable: image_usage.contains(vk::ImageUsageFlags::TRANSFER_SRC),
         })
     }
     
-    pub fn present(&self, image_index: usize, queue: vk::Queue) {
-        let semaphores_finished = [self.rendering_finished[self.current_image()]];
+    /// Queues an acquired image for presentation and moves on to the next frame. Out of date and suboptimal
+    /// swapchains are reported rather than treated as errors
+    pub fn present(&mut self, image_index: usize, queue: vk::Queue) -> Result<PresentResult, vk::Result> {
+        let semaphores_finished = [self.rendering_finished[self.current_frame]];
         let swapchains = [self.swapchain];
         let indices = [image_index as u32];
-        let present_info = vk::PresentInfoKHR::builder()
+        let present_ids = [self.present_id + 1];
+        let mut present_id_info = vk::PresentIdKHR::builder().present_ids(&present_ids);
+        let mut present_info = vk::PresentInfoKHR::builder()
             .wait_semaphores(&semaphores_finished)
             .swapchains(&swapchains)
             .image_indices(&indices);
+        if self.present_ids {
+            present_info = present_info.push_next(&mut present_id_info);
+            self.present_id += 1;
+        }
 
-        unsafe {
-            self.swapchain_loader.queue_present(queue, &present_info).expect("queue presentation error");
+        let presented = unsafe { self.swapchain_loader.queue_present(queue, &present_info) };
+        self.current_frame = (self.current_frame + 1) % FRAMES_IN_FLIGHT;
+
+        match presented {
+            Ok(false) => Ok(PresentResult::Presented),
+            Ok(true) => Ok(PresentResult::Suboptimal),
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => Ok(PresentResult::OutOfDate),
+            Err(error) => Err(error),
         }
     }
 
@@ -184,6 +276,15 @@ impl Swapchain {
         Ok(())
     }
 
+    pub(crate) fn handle(&self) -> vk::SwapchainKHR {
+        self.swapchain
+    }
+
+    /// Id of the latest present, if presents are tagged with ids and there's been one
+    pub(crate) fn last_present_id(&self) -> Option<u64> {
+        (self.present_ids && self.present_id > 0).then_some(self.present_id)
+    }
+
     pub fn framebuffer_count(&self) -> usize {
         self.framebuffers.len()
     }
@@ -192,55 +293,86 @@ impl Swapchain {
         self.extent
     }
 
-    pub fn next_image(&mut self) -> usize {
-        self.current_image = (self.current_image + 1) % self.images.len();
+    pub(crate) fn format(&self) -> vk::SurfaceFormatKHR {
+        self.surface_format
+    }
+
+    pub(crate) fn image(&self, index: usize) -> vk::Image {
+        self.images[index]
+    }
+
+    pub(crate) fn is_readable(&self) -> bool {
+        self.readable
+    }
 
-        let (_image_index, _) = unsafe {
+    /// Acquires the next image to render into. Anything other than an unexpected Vulkan error is reported as an
+    /// `AcquireResult` so the caller can skip the frame or recreate the swapchain
+    ///
+    /// The returned index is the driver's choice of image and selects the framebuffer, command buffer and present target
+    pub fn next_image(&mut self) -> Result<AcquireResult, vk::Result> {
+        let acquired = unsafe {
             self.swapchain_loader.acquire_next_image(
                 self.swapchain,
                 10_000_000u64,
-                self.image_available[self.current_image],
+                self.image_available[self.current_frame],
                 vk::Fence::null()
-            ).expect("next_image unable to acquire")
+            )
         };
 
-        self.current_image
+        match acquired {
+            Ok((image_index, suboptimal)) => Ok(AcquireResult::Acquired { index: image_index as usize, suboptimal }),
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => Ok(AcquireResult::OutOfDate),
+            Err(vk::Result::TIMEOUT) | Err(vk::Result::NOT_READY) => Ok(AcquireResult::Timeout),
+            Err(error) => Err(error),
+        }
     }
     
+    /// Destroys the swapchain and everything created with it. Safe to call more than once, as handles are cleared
+    /// as they're destroyed
     pub unsafe fn cleanup(&mut self, graphics_device: &GraphicsDevice) {
-        graphics_device.logical_device().device_wait_idle().expect("Error during device_wait_idle in swapchain cleanup");
+        // Destroying is all that's left to do either way, a failure here is only logged
+        let _ = audit::check(graphics_device.logical_device().device_wait_idle(), "vkDeviceWaitIdle in swapchain cleanup");
         
-        for fence in &self.draw_fences {
-            graphics_device.destroy_fence(*fence);
+        for fence in self.draw_fences.drain(..) {
+            graphics_device.destroy_fence(fence);
         }
 
-        for semaphore in &self.image_available {
-            graphics_device.destroy_semaphore(*semaphore);
+        for semaphore in self.image_available.drain(..) {
+            graphics_device.destroy_semaphore(semaphore);
         }
 
-        for semaphore in &self.rendering_finished {
-            graphics_device.destroy_semaphore(*semaphore);
+        for semaphore in self.rendering_finished.drain(..) {
+            graphics_device.destroy_semaphore(semaphore);
         }
 
-        for framebuffer in &self.framebuffers {
-            unsafe { graphics_device.destroy_framebuffer(*framebuffer) }
+        for framebuffer in self.framebuffers.drain(..) {
+            unsafe { graphics_device.destroy_framebuffer(framebuffer) }
         }
-        for imageview in &self.imageviews {
-            unsafe { graphics_device.destroy_image_view(*imageview) };
+        for imageview in self.imageviews.drain(..) {
+            unsafe { graphics_device.destroy_image_view(imageview) };
         }
+        self.images_in_flight.clear();
         self.swapchain_loader.destroy_swapchain(self.swapchain, None);
+        self.swapchain = vk::SwapchainKHR::null();
     }
 
     pub(crate) fn framebuffer(&self, i: usize) -> vk::Framebuffer {
         self.framebuffers[i]
     }
 
-    pub(crate) fn current_image(&self) -> usize {
-        self.current_image
+    /// Which of the `FRAMES_IN_FLIGHT` frames is current, for resources kept per frame in flight
+    pub(crate) fn frame_index(&self) -> usize {
+        self.current_frame
+    }
+
+    /// Fence signaled when the current frame's submission completes
+    pub(crate) fn frame_fence(&self) -> vk::Fence {
+        self.draw_fences[self.current_frame]
     }
 
-    pub(crate) fn draw_fences(&self) -> &Vec<vk::Fence> {
-        &self.draw_fences
+    /// Hands an acquired image to the current frame, returning the fence of an earlier frame still rendering into it
+    pub(crate) fn claim_image(&mut self, image_index: usize) -> Option<vk::Fence> {
+        claim_image(&mut self.images_in_flight, image_index, self.draw_fences[self.current_frame])
     }
 
     //pub(crate) fn submit_commandbuffer(&self, image_index: usize, command_buffers: &[vk::CommandBuffer]) {
@@ -248,10 +380,39 @@ impl Swapchain {
     //}
 
     pub(crate) fn image_finished_semaphore(&self) -> vk::Semaphore {
-        self.rendering_finished[self.current_image()]
+        self.rendering_finished[self.current_frame]
     }
 
     pub(crate) fn image_available_semaphore(&self) -> vk::Semaphore {
-        self.image_available[self.current_image()]
+        self.image_available[self.current_frame]
+    }
+}
+
+/// Marks an image as rendered into by the frame signaling `frame_fence`, returning the fence of another frame that
+/// claimed it before
+fn claim_image(images_in_flight: &mut [vk::Fence], image_index: usize, frame_fence: vk::Fence) -> Option<vk::Fence> {
+    let previous = std::mem::replace(&mut images_in_flight[image_index], frame_fence);
+    (previous != vk::Fence::null() && previous != frame_fence).then_some(previous)
+}
+
+#[cfg(test)]
+mod tests {
+    use ash::vk::Handle;
+
+    use super::*;
+
+    #[test]
+    fn images_acquired_out_of_order_wait_on_the_frame_that_last_used_them() {
+        let frames = [vk::Fence::from_raw(1), vk::Fence::from_raw(2)];
+        let mut images_in_flight = vec![vk::Fence::null(); 3];
+
+        // The driver hands out images 2 then 0, neither has been rendered into
+        assert_eq!(claim_image(&mut images_in_flight, 2, frames[0]), None);
+        assert_eq!(claim_image(&mut images_in_flight, 0, frames[1]), None);
+        // Image 2 comes back around while the other frame is current, so that frame waits on the first
+        assert_eq!(claim_image(&mut images_in_flight, 2, frames[1]), Some(frames[0]));
+        // The same frame reclaiming its own image has already waited on its fence
+        assert_eq!(claim_image(&mut images_in_flight, 2, frames[1]), None);
+        assert_eq!(images_in_flight, [frames[1], vk::Fence::null(), frames[1]]);
     }
 }
@@ -0,0 +1,133 @@
+//!
+//! Render targets
+//!
+//! Cameras draw to the window unless their `target` is a texture. Every active camera drawing to a texture is extracted
+//! alongside the main view into `ExtractedTargets`, each becoming a pass in the render graph that writes its texture
+//! before the main pass samples it. Target textures take a slot in the bindless `TextureTable` under their id, so
+//! mirrors, minimaps and portals use them as material textures like any other
+//!
+
+use ash::vk;
+
+use crate::{debug::log, unique::UniqueId};
+use crate::system::{world::World, transform};
+
+use super::{bindless::{TextureIndex, TextureTable}, camera::Camera, extract::ExtractedView, lod::{self, ExtractedMeshes}};
+use super::render_graph::{Access, PassBuilder, PassId, QueueKind, RenderGraph, ResourceId, ResourceKind};
+
+pub const TARGET_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+pub const TARGET_DEPTH_FORMAT: vk::Format = vk::Format::D32_SFLOAT;
+
+/// Where a camera draws
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RenderTarget {
+    #[default]
+    Window,
+    /// An offscreen texture, bound as a material texture under `texture`
+    Texture { texture: UniqueId, width: u32, height: u32 },
+}
+
+/// A camera drawing to a texture, with what it sees
+#[derive(Debug, Clone, PartialEq)]
+pub struct TargetView {
+    pub texture: UniqueId,
+    pub width: u32,
+    pub height: u32,
+    pub view: ExtractedView,
+    pub meshes: ExtractedMeshes,
+}
+
+/// World resource of this frame's offscreen views, written by extraction
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExtractedTargets {
+    pub views: Vec<TargetView>,
+}
+
+/// The graph pass drawing one target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TargetPass {
+    pub texture: UniqueId,
+    pub pass: PassId,
+    pub color: ResourceId,
+    /// The target's bindless slot, `None` when the texture table is full
+    pub index: Option<TextureIndex>,
+}
+
+/// Every target's pass, returned by `add_target_passes`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TargetPasses {
+    pub passes: Vec<TargetPass>,
+}
+
+// Impls
+
+impl TargetPasses {
+    /// Declares that `pass` samples every target, ordering it after the passes drawing them
+    pub fn read_by<'a>(&self, pass: PassBuilder<'a>) -> PassBuilder<'a> {
+        self.passes.iter().fold(pass, |pass, target| pass.reads(target.color, Access::ShaderRead))
+    }
+
+    pub fn get(&self, texture: UniqueId) -> Option<&TargetPass> {
+        self.passes.iter().find(|pass| pass.texture == texture)
+    }
+}
+
+/// Extracts every active camera drawing to a texture into `ExtractedTargets`
+pub fn extract_targets(world: &World, alpha: f64) {
+    let mut views = Vec::new();
+    for entity in world.query::<Camera, ()>() {
+        let Some(camera) = world.component::<Camera, _>(entity, |camera| *camera).filter(|camera| camera.active) else { continue };
+        let RenderTarget::Texture { texture, width, height } = camera.target else { continue };
+        let Some(transform) = transform::render_transform(world, entity) else { continue };
+
+        let view = ExtractedView { camera, transform, alpha };
+        let meshes = lod::select_meshes(world, &view, false);
+        views.push(TargetView { texture, width, height, view, meshes });
+    }
+    world.insert_resource(ExtractedTargets { views });
+}
+
+/// Adds a pass drawing each target to `graph` and gives each target texture a bindless slot
+pub fn add_target_passes(graph: &mut RenderGraph, targets: &ExtractedTargets, table: &mut TextureTable) -> TargetPasses {
+    let passes = targets.views.iter().map(|target| {
+        let name = format!("target {:?}", target.texture);
+        let color = graph.add_resource(&name, ResourceKind::Image { width: target.width, height: target.height, format: TARGET_FORMAT });
+        let depth = graph.add_resource(&format!("{} depth", name), ResourceKind::Image { width: target.width, height: target.height, format: TARGET_DEPTH_FORMAT });
+        let pass = graph.add_pass(&name, QueueKind::Graphics)
+            .writes(color, Access::ColorAttachment)
+            .writes(depth, Access::DepthAttachment)
+            .id();
+
+        let index = table.insert(target.texture);
+        if index.is_none() {
+            log::get().with_topic("targets").warn(format!("no bindless slot left for render target {:?}", target.texture));
+        }
+        TargetPass { texture: target.texture, pass, color, index }
+    }).collect();
+    TargetPasses { passes }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::transform::Transform;
+
+    #[test]
+    fn targets_draw_before_the_main_pass() {
+        let texture = UniqueId::get();
+        let view = ExtractedView { camera: Camera::default(), transform: Transform::default(), alpha: 1.0 };
+        let targets = ExtractedTargets { views: vec![TargetView { texture, width: 256, height: 256, view, meshes: ExtractedMeshes::default() }] };
+
+        let mut graph = RenderGraph::new();
+        let mut table = TextureTable::new(4);
+        let passes = add_target_passes(&mut graph, &targets, &mut table);
+        assert_eq!(passes.get(texture).and_then(|pass| pass.index), table.get(texture));
+
+        let swapchain = graph.import_resource("swapchain", ResourceKind::Image { width: 1280, height: 720, format: vk::Format::B8G8R8A8_SRGB });
+        graph.mark_output(swapchain);
+        let main = passes.read_by(graph.add_pass("main", QueueKind::Graphics)).writes(swapchain, Access::ColorAttachment).id();
+
+        let compiled = graph.compile().unwrap();
+        assert_eq!(compiled.order(), &[passes.passes[0].pass, main]);
+    }
+}
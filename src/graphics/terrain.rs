@@ -0,0 +1,447 @@
+//!
+//! Heightmap terrain
+//!
+//! A `Terrain` is a grid of square heightmap tiles around the entity's translation. Tiles are stored as units in a
+//! `Streaming` store, made available to the world as a `TerrainStore`, and are streamed in and out around the active
+//! camera. Each loaded tile picks a level of detail from its distance to the camera, which sets the stride its mesh
+//! samples the heightmap at. Tiles missing from the store are generated from noise when the terrain has a generator.
+//! Gameplay and physics read heights and normals straight from loaded tiles through
+//! `height_at` and `normal_at`
+//!
+
+use std::{collections::{HashMap, HashSet}, sync::Arc};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{debug::log, random::{Fbm, Simplex}, streaming::{Streaming, StreamingError}, unique::UniqueId};
+use crate::system::{world::World, transform::{self, Transform}};
+
+use super::{camera, layers::{self, RenderLayers}};
+
+/// A streamed heightmap terrain
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Terrain {
+    /// Tile units in the store are derived from this id and their coordinates
+    pub id: UniqueId,
+    /// Width of a tile in world units
+    pub tile_size: f64,
+    /// Distances from the camera at which tiles drop to the next level of detail, in increasing order
+    pub lod_distances: Vec<f64>,
+    /// Tiles with any part closer than this to the camera are loaded
+    pub view_distance: f64,
+    /// Generates tiles the store doesn't have
+    pub generator: Option<TerrainNoise>,
+}
+
+/// Heights generated from fractal simplex noise, sampled in terrain space so neighbouring tiles meet
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct TerrainNoise {
+    pub seed: u64,
+    /// Samples to a side of each generated tile
+    pub resolution: u32,
+    /// Height of the highest peaks, the lowest valleys are as deep
+    pub height: f64,
+    /// World units across one cell of the noise's first octave
+    pub scale: f64,
+    pub fbm: Fbm,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TileCoord {
+    pub x: i32,
+    pub z: i32,
+}
+
+/// A square grid of heights, `resolution` samples to a side with the edges shared with neighbouring tiles
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeightTile {
+    resolution: u32,
+    heights: Vec<f32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadedTile {
+    pub tile: Arc<HeightTile>,
+    pub lod: u32,
+}
+
+/// Tiles a terrain has loaded, added alongside it the first time it streams
+#[derive(Debug, Clone, Default)]
+pub struct TerrainState {
+    tiles: HashMap<TileCoord, LoadedTile>,
+    /// Tiles the store doesn't have, so they aren't looked up every frame
+    missing: HashSet<TileCoord>,
+}
+
+/// World resource of the store terrain tiles stream from
+#[derive(Clone)]
+pub struct TerrainStore(pub Arc<Streaming>);
+
+/// A tile's mesh at one level of detail, in tile space with the origin at the tile's corner
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TerrainMesh {
+    pub positions: Vec<[f32; 3]>,
+    pub indices: Vec<u32>,
+}
+
+/// A loaded tile to draw this frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct TerrainDraw {
+    pub coord: TileCoord,
+    pub lod: u32,
+    /// World position of the tile's corner
+    pub origin: [f64; 3],
+    pub tile: Arc<HeightTile>,
+}
+
+/// World resource of this frame's terrain tiles, written by extraction
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExtractedTerrain {
+    pub tiles: Vec<TerrainDraw>,
+}
+
+// Impls
+
+impl Default for Terrain {
+    fn default() -> Self {
+        Terrain {
+            id: UniqueId::get(),
+            tile_size: 64.0,
+            lod_distances: vec![96.0, 192.0, 384.0],
+            view_distance: 512.0,
+            generator: None,
+        }
+    }
+}
+
+impl Default for TerrainNoise {
+    fn default() -> Self {
+        TerrainNoise { seed: 0, resolution: 65, height: 32.0, scale: 256.0, fbm: Fbm::default() }
+    }
+}
+
+impl TerrainNoise {
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_height(mut self, height: f64) -> Self {
+        self.height = height;
+        self
+    }
+
+    pub fn with_scale(mut self, scale: f64) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Generates the tile at `coord` of a terrain with `tile_size` wide tiles
+    pub fn generate(&self, tile_size: f64, coord: TileCoord) -> HeightTile {
+        let noise = Simplex::new(self.seed);
+        let resolution = self.resolution.max(2);
+        let spacing = tile_size / (resolution - 1) as f64;
+        let heights = (0..resolution).flat_map(|z| (0..resolution).map(move |x| (x, z))).map(|(x, z)| {
+            let world_x = (coord.x as f64 * tile_size + x as f64 * spacing) / self.scale;
+            let world_z = (coord.z as f64 * tile_size + z as f64 * spacing) / self.scale;
+            (self.fbm.sample(world_x, world_z, |x, z| noise.noise2(x, z)) * self.height) as f32
+        }).collect();
+        HeightTile { resolution, heights }
+    }
+}
+
+impl Terrain {
+    pub fn with_tile_size(mut self, size: f64) -> Self {
+        self.tile_size = size;
+        self
+    }
+
+    pub fn with_lod_distances(mut self, distances: &[f64]) -> Self {
+        self.lod_distances = distances.to_vec();
+        self
+    }
+
+    pub fn with_view_distance(mut self, distance: f64) -> Self {
+        self.view_distance = distance;
+        self
+    }
+
+    pub fn with_generator(mut self, generator: TerrainNoise) -> Self {
+        self.generator = Some(generator);
+        self
+    }
+
+    /// The streaming unit holding a tile
+    pub fn tile_unit(&self, coord: TileCoord) -> UniqueId {
+        let mut bytes = self.id.to_bytes();
+        let coords = ((coord.x as u32 as u64) << 32 | coord.z as u32 as u64).to_le_bytes();
+        bytes[..8].iter_mut().zip(coords).for_each(|(byte, coord)| *byte ^= coord);
+        UniqueId::from_bytes(bytes)
+    }
+
+    /// The tile containing a point in terrain space
+    pub fn tile_at(&self, x: f64, z: f64) -> TileCoord {
+        TileCoord { x: (x / self.tile_size).floor() as i32, z: (z / self.tile_size).floor() as i32 }
+    }
+
+    /// Distance from a point in terrain space to the nearest part of a tile
+    pub fn distance_to(&self, coord: TileCoord, x: f64, z: f64) -> f64 {
+        let axis = |point: f64, tile: i32| {
+            let min = tile as f64 * self.tile_size;
+            (min - point).max(point - (min + self.tile_size)).max(0.0)
+        };
+        axis(x, coord.x).hypot(axis(z, coord.z))
+    }
+
+    /// Every tile within `distance` of a point in terrain space
+    pub fn tiles_within(&self, x: f64, z: f64, distance: f64) -> Vec<TileCoord> {
+        let (min, max) = (self.tile_at(x - distance, z - distance), self.tile_at(x + distance, z + distance));
+        (min.x..=max.x)
+            .flat_map(|tile_x| (min.z..=max.z).map(move |tile_z| TileCoord { x: tile_x, z: tile_z }))
+            .filter(|coord| self.distance_to(*coord, x, z) <= distance)
+            .collect()
+    }
+
+    /// Level of detail for a tile `distance` from the camera, 0 being the most detailed
+    pub fn lod_for(&self, distance: f64) -> u32 {
+        self.lod_distances.iter().take_while(|lod_distance| distance >= **lod_distance).count() as u32
+    }
+}
+
+impl HeightTile {
+    pub fn new(resolution: u32, heights: Vec<f32>) -> Option<Self> {
+        (resolution >= 2 && heights.len() == (resolution * resolution) as usize).then_some(HeightTile { resolution, heights })
+    }
+
+    /// A tile at a single height
+    pub fn flat(resolution: u32, height: f32) -> Self {
+        let resolution = resolution.max(2);
+        HeightTile { resolution, heights: vec![height; (resolution * resolution) as usize] }
+    }
+
+    pub fn resolution(&self) -> u32 {
+        self.resolution
+    }
+
+    /// The sample at a grid position, clamped to the tile
+    pub fn get(&self, x: u32, z: u32) -> f32 {
+        let (x, z) = (x.min(self.resolution - 1), z.min(self.resolution - 1));
+        self.heights[(z * self.resolution + x) as usize]
+    }
+
+    /// Bilinearly interpolated height, `u` and `v` running from 0 to 1 across the tile
+    pub fn sample(&self, u: f64, v: f64) -> f64 {
+        let scale = (self.resolution - 1) as f64;
+        let (x, z) = (u.clamp(0.0, 1.0) * scale, v.clamp(0.0, 1.0) * scale);
+        let (x0, z0) = (x.floor() as u32, z.floor() as u32);
+        let (tx, tz) = (x - x0 as f64, z - z0 as f64);
+        let lerp = |a: f32, b: f32, t: f64| a as f64 + (b as f64 - a as f64) * t;
+        let near = lerp(self.get(x0, z0), self.get(x0 + 1, z0), tx);
+        let far = lerp(self.get(x0, z0 + 1), self.get(x0 + 1, z0 + 1), tx);
+        near + (far - near) * tz
+    }
+
+    /// The stored form, the resolution followed by every height, all little endian
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + self.heights.len() * 4);
+        bytes.extend_from_slice(&self.resolution.to_le_bytes());
+        self.heights.iter().for_each(|height| bytes.extend_from_slice(&height.to_le_bytes()));
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let resolution = u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?);
+        let heights = bytes[4..].chunks_exact(4).map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])).collect();
+        HeightTile::new(resolution, heights)
+    }
+
+    /// A grid mesh sampling every `2^lod`th height, always keeping the edges so neighbouring tiles meet
+    pub fn mesh(&self, tile_size: f64, lod: u32) -> TerrainMesh {
+        let last = self.resolution - 1;
+        let stride = 1u32.checked_shl(lod).unwrap_or(u32::MAX).min(last);
+        let mut samples: Vec<u32> = (0..last).step_by(stride as usize).collect();
+        samples.push(last);
+
+        let spacing = tile_size / last as f64;
+        let mut mesh = TerrainMesh::default();
+        for &z in &samples {
+            for &x in &samples {
+                mesh.positions.push([(x as f64 * spacing) as f32, self.get(x, z), (z as f64 * spacing) as f32]);
+            }
+        }
+        let side = samples.len() as u32;
+        for row in 0..side - 1 {
+            for column in 0..side - 1 {
+                let corner = row * side + column;
+                mesh.indices.extend([corner, corner + side, corner + 1, corner + 1, corner + side, corner + side + 1]);
+            }
+        }
+        mesh
+    }
+}
+
+impl TerrainState {
+    pub fn tile(&self, coord: TileCoord) -> Option<&LoadedTile> {
+        self.tiles.get(&coord)
+    }
+
+    pub fn tiles(&self) -> impl Iterator<Item = (TileCoord, &LoadedTile)> {
+        self.tiles.iter().map(|(coord, tile)| (*coord, tile))
+    }
+
+    /// Height at a point in terrain space, if its tile is loaded
+    pub fn height_at(&self, terrain: &Terrain, x: f64, z: f64) -> Option<f64> {
+        let coord = terrain.tile_at(x, z);
+        let tile = self.tiles.get(&coord)?;
+        let u = x / terrain.tile_size - coord.x as f64;
+        let v = z / terrain.tile_size - coord.z as f64;
+        Some(tile.tile.sample(u, v))
+    }
+}
+
+impl std::fmt::Debug for TerrainStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("TerrainStore").field(&self.0.directory()).finish()
+    }
+}
+
+/// Loads tiles near the active camera and unloads far ones, updating each loaded tile's level of detail. Tiles are
+/// kept until they're a tile further than the view distance, so moving along a tile edge doesn't reload them
+pub fn stream_terrain(world: &World) {
+    let Some(camera) = camera::active_camera(world).and_then(|entity| transform::render_transform(world, entity)) else { return };
+    let store = world.with_resource::<TerrainStore, _>(TerrainStore::clone);
+
+    for entity in world.query::<Terrain, ()>() {
+        if !world.has_component::<TerrainState>(entity) {
+            world.insert_component(entity, TerrainState::default());
+        }
+        let Some(terrain) = world.component::<Terrain, _>(entity, Terrain::clone) else { continue };
+        let origin = world.component::<Transform, _>(entity, |transform| transform.translation).unwrap_or_default();
+        let (x, z) = (camera.translation[0] - origin[0], camera.translation[2] - origin[2]);
+
+        world.component_mut::<TerrainState, _>(entity, |mut state| {
+            let state = &mut *state;
+            let keep = terrain.view_distance + terrain.tile_size;
+            state.tiles.retain(|coord, _| terrain.distance_to(*coord, x, z) <= keep);
+            state.missing.retain(|coord| terrain.distance_to(*coord, x, z) <= keep);
+
+            if store.is_some() || terrain.generator.is_some() {
+                for coord in terrain.tiles_within(x, z, terrain.view_distance) {
+                    if state.tiles.contains_key(&coord) || state.missing.contains(&coord) {
+                        continue
+                    }
+                    let tile = store.as_ref().and_then(|TerrainStore(store)| load_tile(store, &terrain, coord))
+                        .or_else(|| terrain.generator.map(|generator| generator.generate(terrain.tile_size, coord)));
+                    match tile {
+                        Some(tile) => { state.tiles.insert(coord, LoadedTile { tile: Arc::new(tile), lod: 0 }); },
+                        None => { state.missing.insert(coord); },
+                    }
+                }
+            }
+
+            for (coord, tile) in state.tiles.iter_mut() {
+                tile.lod = terrain.lod_for(terrain.distance_to(*coord, x, z));
+            }
+        });
+    }
+}
+
+fn load_tile(store: &Streaming, terrain: &Terrain, coord: TileCoord) -> Option<HeightTile> {
+    match store.load(terrain.tile_unit(coord)) {
+        Ok(bytes) => HeightTile::from_bytes(&bytes).or_else(|| {
+            log::get().with_topic("terrain").warn(format!("terrain tile {:?} is malformed", coord));
+            None
+        }),
+        Err(StreamingError::NotFound(_)) => None,
+        Err(error) => {
+            log::get().with_topic("terrain").warn(format!("unable to load terrain tile {:?}: {}", coord, error));
+            None
+        },
+    }
+}
+
+/// Writes a tile to the store, for tools building terrain
+pub fn store_tile(store: &Streaming, terrain: &Terrain, coord: TileCoord, tile: &HeightTile) -> Result<(), StreamingError> {
+    store.store(terrain.tile_unit(coord), &tile.to_bytes()).map(|_| ())
+}
+
+/// Terrain height at a world position, from whichever terrain has the tile there loaded
+pub fn height_at(world: &World, x: f64, z: f64) -> Option<f64> {
+    world.query::<TerrainState, ()>().into_iter().find_map(|entity| {
+        let terrain = world.component::<Terrain, _>(entity, Terrain::clone)?;
+        let origin = world.component::<Transform, _>(entity, |transform| transform.translation).unwrap_or_default();
+        let height = world.component::<TerrainState, _>(entity, |state| state.height_at(&terrain, x - origin[0], z - origin[2]))??;
+        Some(height + origin[1])
+    })
+}
+
+/// Unit surface normal at a world position, from the slope over `step` world units either side
+pub fn normal_at(world: &World, x: f64, z: f64, step: f64) -> Option<[f64; 3]> {
+    let dx = height_at(world, x + step, z)? - height_at(world, x - step, z)?;
+    let dz = height_at(world, x, z + step)? - height_at(world, x, z - step)?;
+    let normal = [-dx, 2.0 * step, -dz];
+    let length = normal.iter().map(|v| v * v).sum::<f64>().sqrt();
+    Some(normal.map(|v| v / length))
+}
+
+/// Collects every loaded tile of terrains on `layers` with its level of detail into `ExtractedTerrain`
+pub fn extract_terrain(world: &World, layers: RenderLayers) {
+    let mut tiles = Vec::new();
+    for entity in world.query::<TerrainState, ()>().into_iter().filter(|entity| layers::is_visible(world, *entity, layers)) {
+        let Some(tile_size) = world.component::<Terrain, _>(entity, |terrain| terrain.tile_size) else { continue };
+        let origin = world.component::<Transform, _>(entity, |transform| transform.translation).unwrap_or_default();
+        world.component::<TerrainState, _>(entity, |state| {
+            tiles.extend(state.tiles().map(|(coord, loaded)| TerrainDraw {
+                coord,
+                lod: loaded.lod,
+                origin: [origin[0] + coord.x as f64 * tile_size, origin[1], origin[2] + coord.z as f64 * tile_size],
+                tile: loaded.tile.clone(),
+            }));
+        });
+    }
+    tiles.sort_by_key(|draw| (draw.lod, draw.coord));
+    world.insert_resource(ExtractedTerrain { tiles });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiles_sample_and_mesh() {
+        let tile = HeightTile::new(3, vec![0.0, 1.0, 2.0, 1.0, 2.0, 3.0, 2.0, 3.0, 4.0]).unwrap();
+        assert_eq!(HeightTile::from_bytes(&tile.to_bytes()), Some(tile.clone()));
+        assert_eq!(tile.sample(0.25, 0.0), 0.5);
+        assert_eq!(tile.sample(0.5, 0.5), 2.0);
+        assert_eq!(tile.sample(2.0, 2.0), 4.0);
+
+        assert_eq!(tile.mesh(10.0, 0).positions.len(), 9);
+        assert_eq!(tile.mesh(10.0, 0).indices.len(), 24);
+        // Coarser levels keep the corners so neighbours still meet
+        let coarse = tile.mesh(10.0, 1);
+        assert_eq!(coarse.positions, vec![[0.0, 0.0, 0.0], [10.0, 2.0, 0.0], [0.0, 2.0, 10.0], [10.0, 4.0, 10.0]]);
+    }
+
+    #[test]
+    fn streaming_area_and_lod() {
+        let terrain = Terrain::default().with_tile_size(10.0).with_lod_distances(&[5.0, 20.0]);
+        assert_eq!(terrain.tile_at(-0.5, 15.0), TileCoord { x: -1, z: 1 });
+        assert_eq!(terrain.tiles_within(5.0, 5.0, 4.0), vec![TileCoord { x: 0, z: 0 }]);
+        // Corner tiles are further than the edge neighbours
+        assert_eq!(terrain.tiles_within(5.0, 5.0, 6.0).len(), 5);
+
+        assert_eq!(terrain.lod_for(0.0), 0);
+        assert_eq!(terrain.lod_for(10.0), 1);
+        assert_eq!(terrain.lod_for(50.0), 2);
+        assert_ne!(terrain.tile_unit(TileCoord { x: 1, z: 0 }), terrain.tile_unit(TileCoord { x: 0, z: 1 }));
+
+        // Generated neighbours share their edge heights
+        let noise = TerrainNoise::default().with_seed(3).with_scale(20.0);
+        let (left, right) = (noise.generate(10.0, TileCoord { x: 0, z: 0 }), noise.generate(10.0, TileCoord { x: 1, z: 0 }));
+        let last = left.resolution() - 1;
+        assert!((0..=last).all(|z| (left.get(last, z) - right.get(0, z)).abs() < 1e-4));
+        assert!((0..=last).any(|z| left.get(0, z) != left.get(last, z)));
+    }
+}
@@ -0,0 +1,298 @@
+//!
+//! Textures on the GPU
+//!
+//! A texture is uploaded through the staging belt the first frame something draws with it and stays resident for the
+//! renderer's lifetime, leaving out the largest mips `TextureQuality` asks for at the time. With descriptor indexing
+//! every texture takes a slot in the bindless array, which is bound once for the main pass, and draws select theirs by
+//! pushing `MaterialIndices`. Without it each texture gets a descriptor set of its own, bound ahead of the draws that
+//! sample it. Untextured draws, and draws whose texture is still loading, sample a white texture
+//!
+
+use std::{collections::HashMap, sync::Arc};
+
+use ash::vk;
+
+use crate::{asset::{self, pipeline::formats::{Texture, TextureQuality}}, debug::log, unique::{Handle, UniqueId}};
+use crate::system::world::World;
+
+use super::{audit, device::SharedDevice, resources::{Image, ImageView, Sampler}, staging::{BeltSlice, StagingBelt}};
+use super::bindless::{BindlessDescriptors, BindlessSupport, MaterialIndices, TextureIndex, TextureTable};
+
+/// Slots asked of the bindless array, clamped to what the device supports
+const BINDLESS_TEXTURES: u32 = 4096;
+
+/// Descriptor sets the fallback pool holds, textures past this draw white
+const FALLBACK_TEXTURES: u32 = 256;
+
+/// How a draw selects its texture
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TextureBinding {
+    /// A slot of the bindless array, pushed as the material's albedo
+    Bindless(TextureIndex),
+    /// The texture's own set, bound at set 0
+    Set(vk::DescriptorSet),
+}
+
+/// A copy out of the belt into a texture's image
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ImageUpload {
+    source: BeltSlice,
+    image: vk::Image,
+    extent: vk::Extent2D,
+}
+
+/// Every texture drawn so far, and the descriptors draws select them with
+pub(crate) struct Textures {
+    device: Arc<SharedDevice>,
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    sampler: Sampler,
+    descriptors: Descriptors,
+    white: TextureBinding,
+    /// `None` for textures that can never be drawn, being malformed or failing to upload
+    uploaded: HashMap<Handle<Texture>, Option<GpuTexture>>,
+    /// Uploads waiting for the next frame to record them
+    pending: Vec<ImageUpload>,
+}
+
+enum Descriptors {
+    Bindless { descriptors: BindlessDescriptors, table: TextureTable },
+    Fallback { layout: vk::DescriptorSetLayout, pool: vk::DescriptorPool },
+}
+
+struct GpuTexture {
+    /// Holds the image alive
+    _view: ImageView,
+    binding: TextureBinding,
+}
+
+// Impls
+
+impl Textures {
+    /// Bindless when `bindless` is given, which it only is when the device was created with descriptor indexing
+    pub(crate) fn new(instance: &ash::Instance, physical_device: vk::PhysicalDevice, device: &Arc<SharedDevice>, bindless: Option<BindlessSupport>, belt: &mut StagingBelt) -> Result<Self, vk::Result> {
+        let descriptors = match bindless {
+            Some(support) => {
+                let descriptors = audit::check(BindlessDescriptors::new(device, support, BINDLESS_TEXTURES), "creating the bindless descriptors")?;
+                let table = TextureTable::new(descriptors.capacity());
+                Descriptors::Bindless { descriptors, table }
+            },
+            None => Descriptors::fallback(device)?,
+        };
+        log::get().with_topic("renderer").info(match bindless {
+            Some(_) => "textures are bindless",
+            None => "descriptor indexing is unsupported, textures are bound per draw",
+        });
+
+        let white = Texture { uid: UniqueId::get(), width: 1, height: 1, pixels: vec![255; 4] };
+        let source = audit::require(belt.write(&white.pixels), "staging the white texture", vk::Result::ERROR_OUT_OF_DEVICE_MEMORY)?;
+        let mut textures = Textures {
+            device: device.clone(),
+            memory_properties: unsafe { instance.get_physical_device_memory_properties(physical_device) },
+            sampler: Sampler::linear(device)?,
+            descriptors,
+            white: TextureBinding::Set(vk::DescriptorSet::null()),
+            uploaded: HashMap::new(),
+            pending: Vec::new(),
+        };
+        let uploaded = textures.create(&white, source)?;
+        textures.white = uploaded.binding;
+        // Kept with the rest under a handle no asset has
+        textures.uploaded.insert(Handle::from_uid(white.uid), Some(uploaded));
+        Ok(textures)
+    }
+
+    /// Layout of set 0 of the main pass
+    pub(crate) fn layout(&self) -> vk::DescriptorSetLayout {
+        match &self.descriptors {
+            Descriptors::Bindless { descriptors, .. } => descriptors.layout(),
+            Descriptors::Fallback { layout, .. } => *layout,
+        }
+    }
+
+    pub(crate) fn is_bindless(&self) -> bool {
+        matches!(self.descriptors, Descriptors::Bindless { .. })
+    }
+
+    /// How draws select `handle`, creating and staging it the first time. The white texture while it's loading, or if
+    /// it can't be drawn
+    pub(crate) fn get_or_upload(&mut self, world: &World, handle: Option<Handle<Texture>>, belt: &mut StagingBelt) -> TextureBinding {
+        let white = self.white;
+        let Some(handle) = handle else { return white };
+        if let Some(uploaded) = self.uploaded.get(&handle) {
+            return uploaded.as_ref().map_or(white, |texture| texture.binding)
+        }
+        let Some(texture) = asset::get::<Texture>(world, handle) else { return white };
+
+        let dropped_mips = world.with_resource::<TextureQuality, _>(|quality| quality.dropped_mips).unwrap_or(0);
+        let mip;
+        let texture = match dropped_mips {
+            0 => &*texture,
+            dropped_mips => {
+                mip = texture.mip(dropped_mips);
+                &mip
+            },
+        };
+        if texture.width == 0 || texture.height == 0 || texture.pixels.len() != (texture.width * texture.height * 4) as usize {
+            log::get().with_topic("renderer").warn(format!("texture {} isn't {}x{} RGBA8", handle, texture.width, texture.height));
+            self.uploaded.insert(handle, None);
+            return white
+        }
+
+        // A full belt is only full this frame, the texture is tried again next frame
+        let Some(source) = belt.write(&texture.pixels) else { return white };
+        match self.create(texture, source) {
+            Ok(uploaded) => {
+                let binding = uploaded.binding;
+                self.uploaded.insert(handle, Some(uploaded));
+                binding
+            },
+            Err(error) => {
+                log::get().with_topic("renderer").warn(format!("unable to upload texture {}: {:?}", handle, error));
+                self.uploaded.insert(handle, None);
+                white
+            },
+        }
+    }
+
+    /// Uploads staged since the last call, for the frame being recorded
+    pub(crate) fn take_uploads(&mut self) -> Vec<ImageUpload> {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Binds the bindless array for every draw after, recorded with `pipeline_layout`. Nothing to bind per texture
+    pub(crate) fn bind(&self, command_buffer: vk::CommandBuffer, pipeline_layout: vk::PipelineLayout) {
+        if let Descriptors::Bindless { descriptors, .. } = &self.descriptors {
+            descriptors.bind(&self.device, command_buffer, pipeline_layout);
+        }
+    }
+
+    /// Selects the texture the following draws sample
+    pub(crate) fn select(&self, command_buffer: vk::CommandBuffer, pipeline_layout: vk::PipelineLayout, binding: TextureBinding) {
+        match binding {
+            TextureBinding::Bindless(index) => {
+                let material = MaterialIndices { albedo: index.0, ..Default::default() };
+                BindlessDescriptors::push_material(&self.device, command_buffer, pipeline_layout, &material);
+            },
+            TextureBinding::Set(set) => unsafe {
+                self.device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline_layout, 0, &[set], &[]);
+            },
+        }
+    }
+
+    /// Nothing may be in flight, the images and sampler are destroyed as they drop
+    pub(crate) unsafe fn destroy(self) {
+        match self.descriptors {
+            Descriptors::Bindless { descriptors, .. } => descriptors.destroy(&self.device),
+            Descriptors::Fallback { layout, pool } => {
+                self.device.destroy_descriptor_pool(pool, None);
+                self.device.destroy_descriptor_set_layout(layout, None);
+            },
+        }
+    }
+
+    /// Creates the texture's image and descriptor, queueing its upload from `source`
+    fn create(&mut self, texture: &Texture, source: BeltSlice) -> Result<GpuTexture, vk::Result> {
+        let extent = vk::Extent2D { width: texture.width, height: texture.height };
+        let usage = vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST;
+        let image = Arc::new(Image::new_2d(&self.device, &self.memory_properties, extent, vk::Format::R8G8B8A8_SRGB, usage)?);
+        let view = ImageView::new(&image, vk::ImageAspectFlags::COLOR)?;
+        let binding = self.descriptors.allocate(&self.device, texture.uid, view.handle(), self.sampler.handle())?;
+
+        self.pending.push(ImageUpload { source, image: image.handle(), extent });
+        Ok(GpuTexture { _view: view, binding })
+    }
+}
+
+impl Descriptors {
+    fn fallback(device: &ash::Device) -> Result<Self, vk::Result> {
+        let bindings = [vk::DescriptorSetLayoutBinding::builder()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            .build()];
+        let layout_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+        let layout = audit::check(unsafe { device.create_descriptor_set_layout(&layout_info, None) }, "vkCreateDescriptorSetLayout")?;
+
+        let sizes = [vk::DescriptorPoolSize { ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER, descriptor_count: FALLBACK_TEXTURES }];
+        let pool_info = vk::DescriptorPoolCreateInfo::builder().max_sets(FALLBACK_TEXTURES).pool_sizes(&sizes);
+        match audit::check(unsafe { device.create_descriptor_pool(&pool_info, None) }, "vkCreateDescriptorPool") {
+            Ok(pool) => Ok(Descriptors::Fallback { layout, pool }),
+            Err(error) => {
+                unsafe { device.destroy_descriptor_set_layout(layout, None) };
+                Err(error)
+            },
+        }
+    }
+
+    /// Points a new slot or set at the texture
+    fn allocate(&mut self, device: &ash::Device, texture: UniqueId, view: vk::ImageView, sampler: vk::Sampler) -> Result<TextureBinding, vk::Result> {
+        match self {
+            Descriptors::Bindless { descriptors, table } => {
+                let index = table.insert(texture).ok_or(vk::Result::ERROR_OUT_OF_POOL_MEMORY)?;
+                // Slots are written as their textures are created, nothing is left dirty for later
+                table.take_dirty();
+                descriptors.write(device, index, view, sampler);
+                Ok(TextureBinding::Bindless(index))
+            },
+            Descriptors::Fallback { layout, pool } => {
+                let layouts = [*layout];
+                let allocate_info = vk::DescriptorSetAllocateInfo::builder().descriptor_pool(*pool).set_layouts(&layouts);
+                let set = unsafe { device.allocate_descriptor_sets(&allocate_info)? }[0];
+                let images = [vk::DescriptorImageInfo { sampler, image_view: view, image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL }];
+                let writes = [vk::WriteDescriptorSet::builder()
+                    .dst_set(set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(&images)
+                    .build()];
+                unsafe { device.update_descriptor_sets(&writes, &[]) };
+                Ok(TextureBinding::Set(set))
+            },
+        }
+    }
+}
+
+/// Records the copies into each image, moving it from undefined into the layout the main pass samples it in. The
+/// transitions order the copies before every fragment shader after them
+pub(crate) fn record_uploads(device: &ash::Device, command_buffer: vk::CommandBuffer, uploads: &[ImageUpload]) {
+    if uploads.is_empty() {
+        return
+    }
+
+    let range = vk::ImageSubresourceRange { aspect_mask: vk::ImageAspectFlags::COLOR, base_mip_level: 0, level_count: 1, base_array_layer: 0, layer_count: 1 };
+    let transition = |upload: &ImageUpload, from: vk::ImageLayout, to: vk::ImageLayout, src_access: vk::AccessFlags, dst_access: vk::AccessFlags| {
+        vk::ImageMemoryBarrier::builder()
+            .image(upload.image)
+            .old_layout(from)
+            .new_layout(to)
+            .src_access_mask(src_access)
+            .dst_access_mask(dst_access)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .subresource_range(range)
+            .build()
+    };
+    let to_transfer: Vec<_> = uploads.iter()
+        .map(|upload| transition(upload, vk::ImageLayout::UNDEFINED, vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::AccessFlags::empty(), vk::AccessFlags::TRANSFER_WRITE))
+        .collect();
+    let to_shader: Vec<_> = uploads.iter()
+        .map(|upload| transition(upload, vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, vk::AccessFlags::TRANSFER_WRITE, vk::AccessFlags::SHADER_READ))
+        .collect();
+
+    unsafe {
+        device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TOP_OF_PIPE, vk::PipelineStageFlags::TRANSFER, vk::DependencyFlags::empty(), &[], &[], &to_transfer);
+        for upload in uploads {
+            let region = vk::BufferImageCopy {
+                buffer_offset: upload.source.offset,
+                buffer_row_length: 0,
+                buffer_image_height: 0,
+                image_subresource: vk::ImageSubresourceLayers { aspect_mask: vk::ImageAspectFlags::COLOR, mip_level: 0, base_array_layer: 0, layer_count: 1 },
+                image_offset: vk::Offset3D::default(),
+                image_extent: vk::Extent3D { width: upload.extent.width, height: upload.extent.height, depth: 1 },
+            };
+            device.cmd_copy_buffer_to_image(command_buffer, upload.source.buffer, upload.image, vk::ImageLayout::TRANSFER_DST_OPTIMAL, &[region]);
+        }
+        device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TRANSFER, vk::PipelineStageFlags::FRAGMENT_SHADER, vk::DependencyFlags::empty(), &[], &[], &to_shader);
+    }
+}
@@ -0,0 +1,499 @@
+//!
+//! 2D tilemaps
+//!
+//! A `Tilemap` lays square tiles over the XY plane from the entity's translation, split into chunks of
+//! `chunk_size` tiles to a side. Every chunk holds one grid of tiles for each of the map's layers. Chunks are stored as
+//! units in a `Streaming` store, made available to the world as a `TilemapStore`, and are streamed in and out as they
+//! enter and leave the area the active camera sees. When a chunk loads its drawable tiles are packed into one
+//! instance buffer and the tiles of its solid layers are merged into collision rectangles, so drawing and collision
+//! don't visit tiles one by one
+//!
+
+use std::{collections::{HashMap, HashSet}, sync::Arc};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{debug::log, extent::Rect, spatial::Aabb, streaming::{Streaming, StreamingError}, unique::UniqueId};
+use crate::system::{world::World, transform::{self, Transform}};
+
+use super::{camera::{self, Camera}, layers::{self, RenderLayers}};
+
+/// A streamed, chunked tilemap
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Tilemap {
+    /// Chunk units in the store are derived from this id and their coordinates
+    pub id: UniqueId,
+    /// Width of a tile in world units
+    pub tile_size: f64,
+    /// Tiles to a side of a chunk
+    pub chunk_size: u32,
+    /// Drawn back to front in this order
+    pub layers: Vec<TileLayer>,
+    /// World units beyond what the camera sees that chunks are loaded for
+    pub view_margin: f64,
+    /// Widest width over height of the views the camera draws to
+    pub max_aspect: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct TileLayer {
+    pub name: String,
+    /// Whether the layer's tiles block movement and make up the map's collision
+    pub solid: bool,
+    /// Hidden layers are still loaded and collide, e.g. a layer painting collision alone
+    pub visible: bool,
+}
+
+/// Index of a tile in the tileset, 0 being no tile
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Tile(pub u16);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ChunkCoord {
+    pub x: i32,
+    pub y: i32,
+}
+
+/// Square grids of tiles, one for each layer, rows from the bottom up
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileChunk {
+    size: u32,
+    layers: Vec<Vec<Tile>>,
+}
+
+/// A tile to draw, laid out to match the tilemap vertex shader's instance buffer
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TileInstance {
+    /// Position in tiles from the chunk's corner
+    pub x: u16,
+    pub y: u16,
+    pub tile: u16,
+    pub layer: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadedChunk {
+    pub chunk: Arc<TileChunk>,
+    /// Every tile on a visible layer, back to front
+    pub instances: Arc<[TileInstance]>,
+    /// Solid areas in tiles from the chunk's corner
+    pub colliders: Vec<Rect>,
+}
+
+/// Chunks a tilemap has loaded, added alongside it the first time it streams
+#[derive(Debug, Clone, Default)]
+pub struct TilemapState {
+    chunks: HashMap<ChunkCoord, LoadedChunk>,
+    /// Chunks the store doesn't have, so they aren't looked up every frame
+    missing: HashSet<ChunkCoord>,
+}
+
+/// World resource of the store tilemap chunks stream from
+#[derive(Clone)]
+pub struct TilemapStore(pub Arc<Streaming>);
+
+/// A loaded chunk to draw this frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkDraw {
+    pub coord: ChunkCoord,
+    /// World position of the chunk's corner
+    pub origin: [f64; 3],
+    pub tile_size: f64,
+    pub instances: Arc<[TileInstance]>,
+}
+
+/// World resource of this frame's tilemap chunks, written by extraction
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExtractedTilemaps {
+    pub chunks: Vec<ChunkDraw>,
+}
+
+// Impls
+
+impl Default for Tilemap {
+    fn default() -> Self {
+        Tilemap {
+            id: UniqueId::get(),
+            tile_size: 1.0,
+            chunk_size: 32,
+            layers: vec![TileLayer::default()],
+            view_margin: 4.0,
+            max_aspect: 2.0,
+        }
+    }
+}
+
+impl Default for TileLayer {
+    fn default() -> Self {
+        TileLayer { name: String::from("ground"), solid: false, visible: true }
+    }
+}
+
+impl TileLayer {
+    pub fn new(name: &str) -> Self {
+        TileLayer { name: String::from(name), ..TileLayer::default() }
+    }
+
+    pub fn with_solid(mut self, solid: bool) -> Self {
+        self.solid = solid;
+        self
+    }
+
+    pub fn with_visible(mut self, visible: bool) -> Self {
+        self.visible = visible;
+        self
+    }
+}
+
+impl Tile {
+    pub const EMPTY: Tile = Tile(0);
+
+    pub fn is_empty(&self) -> bool {
+        *self == Tile::EMPTY
+    }
+}
+
+impl Tilemap {
+    pub fn with_tile_size(mut self, size: f64) -> Self {
+        self.tile_size = size;
+        self
+    }
+
+    pub fn with_chunk_size(mut self, tiles: u32) -> Self {
+        self.chunk_size = tiles.max(1);
+        self
+    }
+
+    pub fn with_layers(mut self, layers: Vec<TileLayer>) -> Self {
+        self.layers = layers;
+        self
+    }
+
+    pub fn with_view_margin(mut self, margin: f64) -> Self {
+        self.view_margin = margin;
+        self
+    }
+
+    /// Width of a chunk in world units
+    pub fn chunk_width(&self) -> f64 {
+        self.tile_size * self.chunk_size as f64
+    }
+
+    /// The streaming unit holding a chunk
+    pub fn chunk_unit(&self, coord: ChunkCoord) -> UniqueId {
+        let mut bytes = self.id.to_bytes();
+        let coords = ((coord.x as u32 as u64) << 32 | coord.y as u32 as u64).to_le_bytes();
+        bytes[..8].iter_mut().zip(coords).for_each(|(byte, coord)| *byte ^= coord);
+        UniqueId::from_bytes(bytes)
+    }
+
+    /// The chunk containing a point in tilemap space
+    pub fn chunk_at(&self, x: f64, y: f64) -> ChunkCoord {
+        let width = self.chunk_width();
+        ChunkCoord { x: (x / width).floor() as i32, y: (y / width).floor() as i32 }
+    }
+
+    /// Every chunk overlapping an area in tilemap space, given by its corners
+    pub fn chunks_in(&self, min: [f64; 2], max: [f64; 2]) -> Vec<ChunkCoord> {
+        let (first, last) = (self.chunk_at(min[0], min[1]), self.chunk_at(max[0], max[1]));
+        (first.y..=last.y).flat_map(|y| (first.x..=last.x).map(move |x| ChunkCoord { x, y })).collect()
+    }
+
+    /// The area in tilemap space a camera at `translation` sees of the map, grown by the view margin. The camera looks
+    /// down -Z at the map from `translation[2]` units in front of it
+    pub fn visible_area(&self, camera: &Camera, translation: [f64; 3]) -> ([f64; 2], [f64; 2]) {
+        let seen = translation[2].abs() * (camera.fov_y * 0.5).tan();
+        let (half_width, half_height) = (seen * self.max_aspect.max(1.0) + self.view_margin, seen + self.view_margin);
+        ([translation[0] - half_width, translation[1] - half_height], [translation[0] + half_width, translation[1] + half_height])
+    }
+
+    /// The world box covering `rect` tiles from the corner of the chunk at `coord`
+    fn tile_box(&self, origin: [f64; 3], coord: ChunkCoord, rect: &Rect) -> Aabb {
+        let corner = [origin[0] + coord.x as f64 * self.chunk_width(), origin[1] + coord.y as f64 * self.chunk_width()];
+        Aabb::new(
+            [corner[0] + rect.x as f64 * self.tile_size, corner[1] + rect.y as f64 * self.tile_size, origin[2]],
+            [corner[0] + rect.right() as f64 * self.tile_size, corner[1] + rect.bottom() as f64 * self.tile_size, origin[2]],
+        )
+    }
+}
+
+impl TileChunk {
+    /// An empty chunk of `size` tiles to a side with `layers` layers
+    pub fn new(size: u32, layers: usize) -> Self {
+        TileChunk { size, layers: vec![vec![Tile::EMPTY; (size * size) as usize]; layers] }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn layer_count(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// The tile at a position on a layer, empty outside the chunk
+    pub fn get(&self, layer: usize, x: u32, y: u32) -> Tile {
+        if x >= self.size || y >= self.size {
+            return Tile::EMPTY
+        }
+        self.layers.get(layer).map_or(Tile::EMPTY, |tiles| tiles[(y * self.size + x) as usize])
+    }
+
+    /// Sets a tile, returning false if the position or layer is outside the chunk
+    pub fn set(&mut self, layer: usize, x: u32, y: u32, tile: Tile) -> bool {
+        match self.layers.get_mut(layer) {
+            Some(tiles) if x < self.size && y < self.size => {
+                tiles[(y * self.size + x) as usize] = tile;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// The stored form, the size and layer count followed by every tile row by row, all little endian
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + self.layers.iter().map(Vec::len).sum::<usize>() * 2);
+        bytes.extend_from_slice(&self.size.to_le_bytes());
+        bytes.extend_from_slice(&(self.layers.len() as u32).to_le_bytes());
+        self.layers.iter().flatten().for_each(|tile| bytes.extend_from_slice(&tile.0.to_le_bytes()));
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let size = u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?);
+        let layers = u32::from_le_bytes(bytes.get(4..8)?.try_into().ok()?) as usize;
+        let per_layer = (size as usize).checked_mul(size as usize)?;
+        if bytes.len() != 8 + per_layer.checked_mul(layers)?.checked_mul(2)? {
+            return None
+        }
+        let tiles: Vec<Tile> = bytes[8..].chunks_exact(2).map(|chunk| Tile(u16::from_le_bytes([chunk[0], chunk[1]]))).collect();
+        let per_layer = (size * size) as usize;
+        Some(TileChunk { size, layers: (0..layers).map(|layer| tiles[layer * per_layer..(layer + 1) * per_layer].to_vec()).collect() })
+    }
+
+    /// Every tile on the visible layers of `tilemap`, layer by layer from the back
+    pub fn instances(&self, tilemap: &Tilemap) -> Vec<TileInstance> {
+        let mut instances = Vec::new();
+        for (layer, tiles) in self.layers.iter().enumerate() {
+            if !tilemap.layers.get(layer).is_none_or(|layer| layer.visible) {
+                continue
+            }
+            instances.extend(tiles.iter().enumerate().filter(|(_, tile)| !tile.is_empty()).map(|(index, tile)| TileInstance {
+                x: (index as u32 % self.size) as u16,
+                y: (index as u32 / self.size) as u16,
+                tile: tile.0,
+                layer: layer as u16,
+            }));
+        }
+        instances
+    }
+
+    /// Rectangles covering every tile with a tile on any solid layer of `tilemap`. Runs of solid tiles along a row are
+    /// merged, then stacked with identical runs in the rows above
+    pub fn colliders(&self, tilemap: &Tilemap) -> Vec<Rect> {
+        let solid = |x: u32, y: u32| tilemap.layers.iter().enumerate().any(|(layer, info)| info.solid && !self.get(layer, x, y).is_empty());
+        let mut done = Vec::new();
+        // Rectangles that reached the row below, by their first column and width
+        let mut open: HashMap<(u32, u32), Rect> = HashMap::new();
+        for y in 0..self.size {
+            let mut runs = Vec::new();
+            let mut x = 0;
+            while x < self.size {
+                if !solid(x, y) {
+                    x += 1;
+                    continue
+                }
+                let start = x;
+                while x < self.size && solid(x, y) {
+                    x += 1;
+                }
+                runs.push((start, x - start));
+            }
+
+            let mut next = HashMap::with_capacity(runs.len());
+            for run in runs {
+                let rect = match open.remove(&run) {
+                    Some(rect) => Rect { height: rect.height + 1, ..rect },
+                    None => Rect::new(run.0 as i32, y as i32, run.1, 1),
+                };
+                next.insert(run, rect);
+            }
+            done.extend(open.into_values());
+            open = next;
+        }
+        done.extend(open.into_values());
+        done.sort_by_key(|rect| (rect.y, rect.x));
+        done
+    }
+}
+
+impl TileInstance {
+    /// The instance buffer's contents
+    pub fn as_bytes(instances: &[TileInstance]) -> &[u8] {
+        // Safety: `TileInstance` is `repr(C)` and made only of 2 byte fields, so it has no padding
+        unsafe { std::slice::from_raw_parts(instances.as_ptr() as *const u8, std::mem::size_of_val(instances)) }
+    }
+}
+
+impl LoadedChunk {
+    pub fn new(chunk: TileChunk, tilemap: &Tilemap) -> Self {
+        LoadedChunk { instances: chunk.instances(tilemap).into(), colliders: chunk.colliders(tilemap), chunk: Arc::new(chunk) }
+    }
+}
+
+impl TilemapState {
+    pub fn chunk(&self, coord: ChunkCoord) -> Option<&LoadedChunk> {
+        self.chunks.get(&coord)
+    }
+
+    pub fn chunks(&self) -> impl Iterator<Item = (ChunkCoord, &LoadedChunk)> {
+        self.chunks.iter().map(|(coord, chunk)| (*coord, chunk))
+    }
+}
+
+impl std::fmt::Debug for TilemapStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("TilemapStore").field(&self.0.directory()).finish()
+    }
+}
+
+/// Loads the chunks of every tilemap the active camera sees and unloads the rest. Chunks are kept until they're a
+/// chunk outside the visible area, so panning back and forth over a chunk edge doesn't reload them
+pub fn stream_tilemaps(world: &World) {
+    let Some(entity) = camera::active_camera(world) else { return };
+    let Some(camera) = world.component::<Camera, _>(entity, |camera| *camera) else { return };
+    let Some(view) = transform::render_transform(world, entity) else { return };
+    let Some(store) = world.with_resource::<TilemapStore, _>(TilemapStore::clone) else { return };
+
+    for entity in world.query::<Tilemap, ()>() {
+        if !world.has_component::<TilemapState>(entity) {
+            world.insert_component(entity, TilemapState::default());
+        }
+        let Some(tilemap) = world.component::<Tilemap, _>(entity, Tilemap::clone) else { continue };
+        let origin = world.component::<Transform, _>(entity, |transform| transform.translation).unwrap_or_default();
+        let (min, max) = tilemap.visible_area(&camera, std::array::from_fn(|i| view.translation[i] - origin[i]));
+        let keep = tilemap.chunk_width();
+        let kept: HashSet<ChunkCoord> = tilemap.chunks_in([min[0] - keep, min[1] - keep], [max[0] + keep, max[1] + keep]).into_iter().collect();
+
+        world.component_mut::<TilemapState, _>(entity, |mut state| {
+            let state = &mut *state;
+            state.chunks.retain(|coord, _| kept.contains(coord));
+            state.missing.retain(|coord| kept.contains(coord));
+
+            for coord in tilemap.chunks_in(min, max) {
+                if state.chunks.contains_key(&coord) || state.missing.contains(&coord) {
+                    continue
+                }
+                match load_chunk(&store.0, &tilemap, coord) {
+                    Some(chunk) => { state.chunks.insert(coord, LoadedChunk::new(chunk, &tilemap)); },
+                    None => { state.missing.insert(coord); },
+                }
+            }
+        });
+    }
+}
+
+fn load_chunk(store: &Streaming, tilemap: &Tilemap, coord: ChunkCoord) -> Option<TileChunk> {
+    match store.load(tilemap.chunk_unit(coord)) {
+        Ok(bytes) => TileChunk::from_bytes(&bytes).or_else(|| {
+            log::get().with_topic("tilemap").warn(format!("tilemap chunk {:?} is malformed", coord));
+            None
+        }),
+        Err(StreamingError::NotFound(_)) => None,
+        Err(error) => {
+            log::get().with_topic("tilemap").warn(format!("unable to load tilemap chunk {:?}: {}", coord, error));
+            None
+        },
+    }
+}
+
+/// Writes a chunk to the store, for tools building tilemaps
+pub fn store_chunk(store: &Streaming, tilemap: &Tilemap, coord: ChunkCoord, chunk: &TileChunk) -> Result<(), StreamingError> {
+    store.store(tilemap.chunk_unit(coord), &chunk.to_bytes()).map(|_| ())
+}
+
+/// World boxes of the solid tiles of every loaded chunk overlapping `area`, flat on each tilemap's plane
+pub fn colliders_in(world: &World, area: &Aabb) -> Vec<Aabb> {
+    let mut boxes = Vec::new();
+    for entity in world.query::<TilemapState, ()>() {
+        let Some(tilemap) = world.component::<Tilemap, _>(entity, Tilemap::clone) else { continue };
+        let origin = world.component::<Transform, _>(entity, |transform| transform.translation).unwrap_or_default();
+        world.component::<TilemapState, _>(entity, |state| {
+            for (coord, chunk) in state.chunks() {
+                boxes.extend(chunk.colliders.iter().map(|rect| tilemap.tile_box(origin, coord, rect)).filter(|collider| collider.intersects(area)));
+            }
+        });
+    }
+    boxes
+}
+
+/// Collects every loaded chunk of tilemaps on `layers` into `ExtractedTilemaps`
+pub fn extract_tilemaps(world: &World, layers: RenderLayers) {
+    let mut chunks = Vec::new();
+    for entity in world.query::<TilemapState, ()>().into_iter().filter(|entity| layers::is_visible(world, *entity, layers)) {
+        let Some(tilemap) = world.component::<Tilemap, _>(entity, Tilemap::clone) else { continue };
+        let origin = world.component::<Transform, _>(entity, |transform| transform.translation).unwrap_or_default();
+        world.component::<TilemapState, _>(entity, |state| {
+            chunks.extend(state.chunks().filter(|(_, loaded)| !loaded.instances.is_empty()).map(|(coord, loaded)| ChunkDraw {
+                coord,
+                origin: [origin[0] + coord.x as f64 * tilemap.chunk_width(), origin[1] + coord.y as f64 * tilemap.chunk_width(), origin[2]],
+                tile_size: tilemap.tile_size,
+                instances: loaded.instances.clone(),
+            }));
+        });
+    }
+    chunks.sort_by_key(|draw| draw.coord);
+    world.insert_resource(ExtractedTilemaps { chunks });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_pack_instances_and_colliders() {
+        let tilemap = Tilemap::default().with_chunk_size(4).with_layers(vec![
+            TileLayer::new("ground"),
+            TileLayer::new("walls").with_solid(true),
+            TileLayer::new("collision").with_solid(true).with_visible(false),
+        ]);
+        let mut chunk = TileChunk::new(4, 3);
+        for x in 0..4 {
+            chunk.set(0, x, 0, Tile(1));
+        }
+        // An L of wall along the left and bottom, with the bottom row painted on the hidden layer
+        for y in 1..4 {
+            chunk.set(1, 0, y, Tile(7));
+        }
+        for x in 0..3 {
+            chunk.set(2, x, 0, Tile(9));
+        }
+        chunk.set(1, 2, 2, Tile(7));
+        assert!(!chunk.set(3, 0, 0, Tile(1)) && !chunk.set(0, 4, 0, Tile(1)));
+        assert_eq!(TileChunk::from_bytes(&chunk.to_bytes()), Some(chunk.clone()));
+        assert_eq!(TileChunk::from_bytes(&chunk.to_bytes()[..20]), None);
+
+        let instances = chunk.instances(&tilemap);
+        assert_eq!(instances.len(), 8);
+        assert_eq!(instances[4], TileInstance { x: 0, y: 1, tile: 7, layer: 1 });
+        assert_eq!(TileInstance::as_bytes(&instances).len(), 64);
+
+        assert_eq!(chunk.colliders(&tilemap), vec![Rect::new(0, 0, 3, 1), Rect::new(0, 1, 1, 3), Rect::new(2, 2, 1, 1)]);
+    }
+
+    #[test]
+    fn camera_area_selects_chunks() {
+        let tilemap = Tilemap::default().with_tile_size(2.0).with_chunk_size(8).with_view_margin(0.0);
+        assert_eq!(tilemap.chunk_at(-0.5, 16.0), ChunkCoord { x: -1, y: 1 });
+        assert_ne!(tilemap.chunk_unit(ChunkCoord { x: 1, y: 0 }), tilemap.chunk_unit(ChunkCoord { x: 0, y: 1 }));
+
+        // A 90 degree camera 4 units away sees 4 units either side vertically, twice that horizontally
+        let camera = Camera { fov_y: std::f64::consts::FRAC_PI_2, ..Camera::default() };
+        let (min, max) = tilemap.visible_area(&camera, [8.0, 8.0, 4.0]);
+        assert!((min[0] - 0.0).abs() < 1e-9 && (max[1] - 12.0).abs() < 1e-9);
+        assert_eq!(tilemap.chunks_in(min, max), vec![ChunkCoord { x: 0, y: 0 }, ChunkCoord { x: 1, y: 0 }]);
+    }
+}
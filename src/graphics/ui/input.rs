@@ -0,0 +1,224 @@
+//!
+//! UI input
+//!
+//! The app hands pointer and keyboard events to the UI before anything else. The pointer is hit tested against the
+//! `UiLayout` tree, and presses, clicks and focus changes are sent as `UiEvent`s. Tab and Shift+Tab move keyboard focus
+//! between focusable boxes in tree order, Enter or Space activate the focused box and Escape drops focus. The keys are
+//! the `focus_next`, `activate` and `unfocus` actions of the `UI` input context and can be rebound like any other
+//!
+//! While the pointer is over the UI or a press that started on it is held, the `UI` input context captures the pointer,
+//! and while a box has focus it captures the keyboard, so those events don't reach gameplay underneath
+//!
+
+use winit::event::{ElementState, KeyboardInput, ModifiersState, VirtualKeyCode};
+
+use crate::input::{self, ActionMap, InputContext};
+use crate::system::{event, world::World};
+
+use super::layout::{NodeId, UiLayout};
+
+pub const FOCUS_NEXT_KEY: VirtualKeyCode = VirtualKeyCode::Tab;
+pub const ACTIVATE_KEYS: [VirtualKeyCode; 3] = [VirtualKeyCode::Return, VirtualKeyCode::NumpadEnter, VirtualKeyCode::Space];
+pub const UNFOCUS_KEY: VirtualKeyCode = VirtualKeyCode::Escape;
+pub const FOCUS_NEXT_ACTION: &str = "focus_next";
+pub const ACTIVATE_ACTION: &str = "activate";
+pub const UNFOCUS_ACTION: &str = "unfocus";
+
+/// World resource of the pointer and keyboard state of the UI
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct UiInput {
+    /// In physical pixels from the top left of the view, `None` while outside the window
+    pub pointer: Option<[f32; 2]>,
+    /// The box under the pointer
+    pub hovered: Option<NodeId>,
+    /// The box a held press started on
+    pub pressed: Option<NodeId>,
+    /// The box with keyboard focus
+    pub focused: Option<NodeId>,
+    shift: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UiEvent {
+    /// The primary button went down over the box
+    Pressed(NodeId),
+    /// The primary button went down and up again over the box
+    Clicked(NodeId),
+    /// Keyboard focus moved to the box, or was dropped
+    Focused(Option<NodeId>),
+    /// The focused box was activated from the keyboard
+    Activated(NodeId),
+}
+
+// Impls
+
+impl UiInput {
+    /// Whether the UI is holding on to the pointer
+    pub fn captures_pointer(&self) -> bool {
+        self.hovered.is_some() || self.pressed.is_some()
+    }
+
+    /// Whether the UI is holding on to the keyboard
+    pub fn captures_keyboard(&self) -> bool {
+        self.focused.is_some()
+    }
+
+    /// Forgets boxes that are no longer in the tree
+    fn retain(&mut self, contains: impl Fn(NodeId) -> bool) {
+        for node in [&mut self.hovered, &mut self.pressed, &mut self.focused] {
+            if node.is_some_and(|node| !contains(node)) {
+                *node = None;
+            }
+        }
+    }
+}
+
+/// Adds the `UiInput` resource and puts the `UI` input context above gameplay
+pub fn init_ui_input(world: &World) {
+    world.insert_resource(UiInput::default());
+    input::with_input_contexts(world, |contexts| contexts.push(InputContext::new(input::UI).with_actions(actions(false, false))));
+}
+
+/// Moves the pointer to `position`, or out of the window, returning whether it's over the UI
+pub fn pointer_moved(world: &World, position: Option<[f32; 2]>) -> bool {
+    if !input::receives_pointer(world, input::UI) {
+        return false
+    }
+    let hovered = position.and_then(|position| hit_test(world, position));
+    with_ui_input(world, |state| {
+        state.pointer = position;
+        state.hovered = hovered;
+    });
+    update_capture(world);
+    hovered.is_some()
+}
+
+/// Presses or releases the primary button at the pointer, returning whether the UI took it
+pub fn pointer_button(world: &World, state: ElementState) -> bool {
+    if !input::receives_pointer(world, input::UI) {
+        return false
+    }
+    update_capture(world);
+    let pointer = with_ui_input(world, |input| input.pointer);
+    let hit = pointer.and_then(|pointer| hit_test(world, pointer));
+    let taken = match state {
+        ElementState::Pressed => {
+            with_ui_input(world, |input| input.pressed = hit);
+            let focusable = hit.is_some_and(|node| {
+                world.with_resource::<UiLayout, _>(|UiLayout(tree)| tree.is_focusable(node)).unwrap_or(false)
+            });
+            // Pressing anywhere but a focusable box, including outside the UI, drops focus
+            focus(world, hit.filter(|_| focusable));
+            if let Some(node) = hit {
+                event::send_event(world, UiEvent::Pressed(node));
+            }
+            hit.is_some()
+        },
+        ElementState::Released => match with_ui_input(world, |input| input.pressed.take()) {
+            Some(pressed) => {
+                if hit == Some(pressed) {
+                    event::send_event(world, UiEvent::Clicked(pressed));
+                }
+                true
+            },
+            None => false,
+        },
+    };
+    update_capture(world);
+    taken
+}
+
+pub fn modifiers_changed(world: &World, modifiers: ModifiersState) {
+    with_ui_input(world, |input| input.shift = modifiers.shift());
+}
+
+/// Applies the focus keys, returns whether the UI took `input`. Every key is taken while a box has focus
+pub fn handle_key(world: &World, input: &KeyboardInput) -> bool {
+    if !input::receives_keyboard(world, input::UI) {
+        return false
+    }
+    update_capture(world);
+    let (focused, shift) = with_ui_input(world, |state| (state.focused, state.shift));
+    let pressed = input.state == ElementState::Pressed;
+    let triggers = |key, action| input::triggers(world, input::UI, action, key);
+    let taken = match input.virtual_keycode {
+        Some(key) if triggers(key, FOCUS_NEXT_ACTION) => {
+            let focusables = world.with_resource::<UiLayout, _>(|UiLayout(tree)| tree.focusables()).unwrap_or_default();
+            if pressed && !focusables.is_empty() {
+                let count = focusables.len();
+                let next = match focused.and_then(|focused| focusables.iter().position(|node| *node == focused)) {
+                    Some(index) if shift => (index + count - 1) % count,
+                    Some(index) => (index + 1) % count,
+                    None if shift => count - 1,
+                    None => 0,
+                };
+                focus(world, Some(focusables[next]));
+            }
+            true
+        },
+        Some(key) if triggers(key, ACTIVATE_ACTION) => {
+            if let (true, Some(focused)) = (pressed, focused) {
+                event::send_event(world, UiEvent::Activated(focused));
+            }
+            true
+        },
+        Some(key) if triggers(key, UNFOCUS_ACTION) => {
+            if pressed {
+                focus(world, None);
+            }
+            true
+        },
+        _ => focused.is_some(),
+    };
+    update_capture(world);
+    taken
+}
+
+/// Runs `f` on the world's `UiInput`, adding one if it has none
+pub fn with_ui_input<R>(world: &World, f: impl FnOnce(&mut UiInput) -> R) -> R {
+    if !world.contains_resource::<UiInput>() {
+        world.insert_resource(UiInput::default());
+    }
+    world.with_resource_mut::<UiInput, _>(f).expect("no ui input")
+}
+
+fn hit_test(world: &World, point: [f32; 2]) -> Option<NodeId> {
+    world.with_resource::<UiLayout, _>(|UiLayout(tree)| tree.hit_test(point)).flatten()
+}
+
+/// Moves keyboard focus, sending a `UiEvent::Focused` if it changed
+fn focus(world: &World, node: Option<NodeId>) {
+    if with_ui_input(world, |input| std::mem::replace(&mut input.focused, node)) != node {
+        event::send_event(world, UiEvent::Focused(node));
+    }
+}
+
+/// Drops boxes removed from the tree and sets what the `UI` input context captures to match
+fn update_capture(world: &World) {
+    let contains = |node| world.with_resource::<UiLayout, _>(|UiLayout(tree)| tree.contains(node)).unwrap_or(false);
+    let navigable = world.with_resource::<UiLayout, _>(|UiLayout(tree)| !tree.focusables().is_empty()).unwrap_or(false);
+    let (pointer, keyboard) = with_ui_input(world, |input| {
+        input.retain(contains);
+        (input.captures_pointer(), input.captures_keyboard())
+    });
+    input::with_input_contexts(world, |contexts| {
+        if let Some(context) = contexts.get_mut(input::UI) {
+            context.captures_pointer = pointer;
+            context.captures_keyboard = keyboard;
+            context.actions = actions(navigable, keyboard);
+        }
+    });
+}
+
+/// The `UI` context's actions. Each only takes its keys while it can do something, moving focus while there are boxes
+/// to focus and activating or dropping focus while a box has it, leaving the keys to gameplay otherwise
+fn actions(navigable: bool, focused: bool) -> ActionMap {
+    let mut actions = ActionMap::default();
+    if navigable {
+        actions.add(FOCUS_NEXT_ACTION, &[FOCUS_NEXT_KEY]);
+    }
+    if focused {
+        actions.add(ACTIVATE_ACTION, &ACTIVATE_KEYS).add(UNFOCUS_ACTION, &[UNFOCUS_KEY]);
+    }
+    actions
+}
@@ -0,0 +1,548 @@
+//!
+//! UI layout
+//!
+//! A `LayoutTree` is a retained tree of boxes laid out like a simplified flexbox. Each box stacks its children in a row
+//! or a column with a gap between them, inside its padding. A child's size along that direction is fixed in logical
+//! pixels, a percentage of the parent's content box, fitted to its content, or a share of the space left over. Across
+//! it, children are aligned to the start, centre or end of the parent, or stretched to fill it. The root box is the
+//! whole view
+//!
+//! Sizes are in logical pixels, multiplied by the window's scale factor and the accessibility UI scale when the tree is
+//! laid out. The tree only lays itself out again after it's edited or the `UiViewport` changes, which the app updates
+//! as the window is resized or moved to a display with a different scale. `layout_ui` then redraws the boxes' visuals
+//! into the `UiMesh` resource
+//!
+
+use crate::accessibility;
+use crate::system::world::World;
+
+use super::{Fill, NineSlice, UiMesh, UiRect};
+
+/// Identifies a box in its `LayoutTree`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Size {
+    /// Fits the box's content, or its children if it has no content of its own
+    #[default]
+    Auto,
+    /// Logical pixels
+    Pixels(f32),
+    /// Of the parent's content box, from 0 to 100
+    Percent(f32),
+    /// A share of the space the parent has left along its direction, weighted against its other growing children.
+    /// Across the parent's direction it fills the parent
+    Grow(f32),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Direction {
+    Row,
+    #[default]
+    Column,
+}
+
+/// Where children sit across their parent's direction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Align {
+    Start,
+    Center,
+    End,
+    /// Auto sized children fill the parent
+    #[default]
+    Stretch,
+}
+
+/// Where children sit along their parent's direction when they don't fill it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Justify {
+    #[default]
+    Start,
+    Center,
+    End,
+    /// The first and last child at either end and the space shared out between the rest
+    SpaceBetween,
+}
+
+/// Space inside a box's edges, in logical pixels
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Edges {
+    pub left: f32,
+    pub top: f32,
+    pub right: f32,
+    pub bottom: f32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Style {
+    pub width: Size,
+    pub height: Size,
+    /// How children are stacked
+    pub direction: Direction,
+    pub padding: Edges,
+    /// Logical pixels between children
+    pub gap: f32,
+    pub align: Align,
+    pub justify: Justify,
+}
+
+/// What's drawn over a box's area
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Visual {
+    Rect(Fill),
+    /// Corner radius in logical pixels
+    RoundedRect(f32, Fill),
+    NineSlice(NineSlice, Fill),
+}
+
+/// A retained tree of boxes and where they were last laid out
+#[derive(Debug, Clone)]
+pub struct LayoutTree {
+    nodes: Vec<Option<Node>>,
+    /// Size and scale of the last layout, `None` when it's out of date
+    laid_out: Option<([f32; 2], f32)>,
+}
+
+/// World resource of the UI's layout tree
+#[derive(Debug, Clone, Default)]
+pub struct UiLayout(pub LayoutTree);
+
+/// World resource of the view the UI is laid out in, kept up to date by the app
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UiViewport {
+    /// In physical pixels
+    pub size: [f32; 2],
+    /// The window's physical pixels per logical pixel
+    pub scale_factor: f64,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    style: Style,
+    /// Measured size of text, images or other content, in logical pixels
+    content: Option<[f32; 2]>,
+    visual: Option<Visual>,
+    /// Catches the pointer even without a visual
+    interactive: bool,
+    /// Takes keyboard focus
+    focusable: bool,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    rect: UiRect,
+}
+
+// Impls
+
+impl Edges {
+    pub fn all(value: f32) -> Self {
+        Edges { left: value, top: value, right: value, bottom: value }
+    }
+
+    /// `horizontal` on the left and right, `vertical` on the top and bottom
+    pub fn symmetric(horizontal: f32, vertical: f32) -> Self {
+        Edges { left: horizontal, top: vertical, right: horizontal, bottom: vertical }
+    }
+}
+
+impl Style {
+    pub fn row() -> Self {
+        Style { direction: Direction::Row, ..Style::default() }
+    }
+
+    pub fn column() -> Self {
+        Style { direction: Direction::Column, ..Style::default() }
+    }
+
+    pub fn with_size(mut self, width: Size, height: Size) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    pub fn with_padding(mut self, padding: Edges) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    pub fn with_gap(mut self, gap: f32) -> Self {
+        self.gap = gap;
+        self
+    }
+
+    pub fn with_align(mut self, align: Align) -> Self {
+        self.align = align;
+        self
+    }
+
+    pub fn with_justify(mut self, justify: Justify) -> Self {
+        self.justify = justify;
+        self
+    }
+
+    /// The size along `direction`, then across it
+    fn sizes(&self, direction: Direction) -> (Size, Size) {
+        match direction {
+            Direction::Row => (self.width, self.height),
+            Direction::Column => (self.height, self.width),
+        }
+    }
+}
+
+impl Default for UiViewport {
+    fn default() -> Self {
+        UiViewport { size: [0.0; 2], scale_factor: 1.0 }
+    }
+}
+
+impl Default for LayoutTree {
+    fn default() -> Self {
+        LayoutTree::new(Style::default())
+    }
+}
+
+impl LayoutTree {
+    /// A tree with only its root, laid out with `style` over the whole view
+    pub fn new(style: Style) -> Self {
+        let root = Node { style, content: None, visual: None, interactive: false, focusable: false, parent: None, children: Vec::new(), rect: UiRect::default() };
+        LayoutTree { nodes: vec![Some(root)], laid_out: None }
+    }
+
+    pub fn root(&self) -> NodeId {
+        NodeId(0)
+    }
+
+    /// Adds a box as the last child of `parent`
+    pub fn add(&mut self, parent: NodeId, style: Style) -> NodeId {
+        let id = NodeId(self.nodes.iter().position(Option::is_none).unwrap_or(self.nodes.len()));
+        let node = Node { style, content: None, visual: None, interactive: false, focusable: false, parent: Some(parent), children: Vec::new(), rect: UiRect::default() };
+        match self.nodes.get_mut(id.0) {
+            Some(slot) => *slot = Some(node),
+            None => self.nodes.push(Some(node)),
+        }
+        self.node_mut(parent).children.push(id);
+        id
+    }
+
+    /// Removes a box and everything in it. The root can't be removed
+    pub fn remove(&mut self, node: NodeId) {
+        let Some(parent) = self.nodes.get(node.0).and_then(Option::as_ref).and_then(|node| node.parent) else { return };
+        self.node_mut(parent).children.retain(|child| *child != node);
+        let mut pending = vec![node];
+        while let Some(next) = pending.pop() {
+            if let Some(removed) = self.nodes[next.0].take() {
+                pending.extend(removed.children);
+            }
+        }
+        self.laid_out = None;
+    }
+
+    pub fn contains(&self, node: NodeId) -> bool {
+        self.nodes.get(node.0).is_some_and(Option::is_some)
+    }
+
+    pub fn style(&self, node: NodeId) -> Style {
+        self.node(node).style
+    }
+
+    pub fn set_style(&mut self, node: NodeId, style: Style) {
+        self.node_mut(node).style = style;
+    }
+
+    /// Sets the measured size of the box's content, which auto sized boxes fit
+    pub fn set_content_size(&mut self, node: NodeId, size: Option<[f32; 2]>) {
+        self.node_mut(node).content = size;
+    }
+
+    pub fn set_visual(&mut self, node: NodeId, visual: Option<Visual>) {
+        self.node_mut(node).visual = visual;
+    }
+
+    /// Makes the box catch the pointer even where it draws nothing
+    pub fn set_interactive(&mut self, node: NodeId, interactive: bool) {
+        self.nodes[node.0].as_mut().expect("no layout node").interactive = interactive;
+    }
+
+    /// Lets the box take keyboard focus, which also makes it interactive
+    pub fn set_focusable(&mut self, node: NodeId, focusable: bool) {
+        let node = self.nodes[node.0].as_mut().expect("no layout node");
+        node.focusable = focusable;
+        node.interactive |= focusable;
+    }
+
+    pub fn is_focusable(&self, node: NodeId) -> bool {
+        self.node(node).focusable
+    }
+
+    pub fn children(&self, node: NodeId) -> &[NodeId] {
+        &self.node(node).children
+    }
+
+    /// Where the box was last laid out, in physical pixels from the top left of the view
+    pub fn rect(&self, node: NodeId) -> UiRect {
+        self.node(node).rect
+    }
+
+    /// Lays the tree out over a view of `size` physical pixels at `scale` physical pixels per logical pixel. Does
+    /// nothing if neither they nor the tree changed since the last layout, returning whether it laid out again
+    pub fn layout(&mut self, size: [f32; 2], scale: f32) -> bool {
+        if self.laid_out == Some((size, scale)) {
+            return false
+        }
+        let root = self.root();
+        let style = self.node(root).style;
+        let resolve = |size: Size, available: f32| match size {
+            Size::Pixels(pixels) => pixels * scale,
+            Size::Percent(percent) => available * percent / 100.0,
+            Size::Auto | Size::Grow(_) => available,
+        };
+        self.place(root, UiRect::new(0.0, 0.0, resolve(style.width, size[0]), resolve(style.height, size[1])), scale);
+        self.laid_out = Some((size, scale));
+        true
+    }
+
+    /// Draws the visual of every box, each before the boxes inside it
+    pub fn draw(&self, mesh: &mut UiMesh, scale: f32) {
+        for next in self.preorder() {
+            let node = self.node(next);
+            match node.visual {
+                Some(Visual::Rect(fill)) => mesh.rect(node.rect, fill),
+                Some(Visual::RoundedRect(radius, fill)) => mesh.rounded_rect(node.rect, radius * scale, fill),
+                Some(Visual::NineSlice(slice, fill)) => {
+                    let insets = slice.insets.map(|inset| inset * scale);
+                    mesh.nine_slice(node.rect, &NineSlice { insets, ..slice }, fill);
+                },
+                None => (),
+            }
+        }
+    }
+
+    /// The topmost box with a visual or marked interactive under `point`, in physical pixels from the top left of the
+    /// view. Boxes are on top of the boxes they're in and of their earlier siblings
+    pub fn hit_test(&self, point: [f32; 2]) -> Option<NodeId> {
+        self.preorder().filter(|id| {
+            let node = self.node(*id);
+            let rect = node.rect;
+            (node.interactive || node.visual.is_some())
+                && point[0] >= rect.x && point[0] < rect.right() && point[1] >= rect.y && point[1] < rect.bottom()
+        }).last()
+    }
+
+    /// Focusable boxes in the order keyboard navigation visits them, which is tree order
+    pub fn focusables(&self) -> Vec<NodeId> {
+        self.preorder().filter(|id| self.node(*id).focusable).collect()
+    }
+
+    /// Every box, each before the boxes inside it and after its earlier siblings
+    fn preorder(&self) -> impl Iterator<Item = NodeId> + '_ {
+        let mut pending = vec![self.root()];
+        std::iter::from_fn(move || {
+            let next = pending.pop()?;
+            pending.extend(self.node(next).children.iter().rev());
+            Some(next)
+        })
+    }
+
+    fn node(&self, node: NodeId) -> &Node {
+        self.nodes[node.0].as_ref().expect("no layout node")
+    }
+
+    /// Editing a node puts the layout out of date
+    fn node_mut(&mut self, node: NodeId) -> &mut Node {
+        self.laid_out = None;
+        self.nodes[node.0].as_mut().expect("no layout node")
+    }
+
+    /// Physical size a box takes when auto sized, from its own sizes, its content or its children
+    fn measure(&self, node: NodeId, scale: f32) -> [f32; 2] {
+        let node = self.node(node);
+        let style = &node.style;
+        let padding = [(style.padding.left + style.padding.right) * scale, (style.padding.top + style.padding.bottom) * scale];
+        let inner = match node.content {
+            Some(content) => content.map(|length| length * scale),
+            None => {
+                let (mut along, mut across) = (0.0f32, 0.0f32);
+                for child in &node.children {
+                    let [width, height] = self.measure(*child, scale);
+                    let (child_along, child_across) = match style.direction {
+                        Direction::Row => (width, height),
+                        Direction::Column => (height, width),
+                    };
+                    along += child_along;
+                    across = across.max(child_across);
+                }
+                along += style.gap * scale * node.children.len().saturating_sub(1) as f32;
+                match style.direction {
+                    Direction::Row => [along, across],
+                    Direction::Column => [across, along],
+                }
+            },
+        };
+        let fixed = |size: Size, measured: f32| match size {
+            Size::Pixels(pixels) => pixels * scale,
+            _ => measured,
+        };
+        [fixed(style.width, inner[0] + padding[0]), fixed(style.height, inner[1] + padding[1])]
+    }
+
+    /// Sets a box's rect and lays out its children inside it
+    fn place(&mut self, node: NodeId, rect: UiRect, scale: f32) {
+        self.nodes[node.0].as_mut().expect("no layout node").rect = rect;
+        let style = self.node(node).style;
+        let children = self.node(node).children.clone();
+        if children.is_empty() {
+            return
+        }
+
+        let padding = style.padding;
+        let content = UiRect::new(
+            rect.x + padding.left * scale,
+            rect.y + padding.top * scale,
+            (rect.width - (padding.left + padding.right) * scale).max(0.0),
+            (rect.height - (padding.top + padding.bottom) * scale).max(0.0),
+        );
+        let (content_along, content_across) = match style.direction {
+            Direction::Row => (content.width, content.height),
+            Direction::Column => (content.height, content.width),
+        };
+        let gap = style.gap * scale;
+
+        // Everything but growing children takes its size first, growing children share what's left
+        let mut alongs = Vec::with_capacity(children.len());
+        let mut acrosses = Vec::with_capacity(children.len());
+        let mut weights = 0.0;
+        for child in &children {
+            let (along, across) = self.node(*child).style.sizes(style.direction);
+            let measured = self.measure(*child, scale);
+            let (measured_along, measured_across) = match style.direction {
+                Direction::Row => (measured[0], measured[1]),
+                Direction::Column => (measured[1], measured[0]),
+            };
+            alongs.push(match along {
+                Size::Pixels(pixels) => pixels * scale,
+                Size::Percent(percent) => content_along * percent / 100.0,
+                Size::Auto => measured_along,
+                Size::Grow(weight) => {
+                    weights += weight.max(0.0);
+                    0.0
+                },
+            });
+            acrosses.push(match across {
+                Size::Pixels(pixels) => pixels * scale,
+                Size::Percent(percent) => content_across * percent / 100.0,
+                Size::Auto if style.align == Align::Stretch => content_across,
+                Size::Auto => measured_across,
+                Size::Grow(_) => content_across,
+            });
+        }
+        let gaps = gap * (children.len() - 1) as f32;
+        let left = (content_along - alongs.iter().sum::<f32>() - gaps).max(0.0);
+        if weights > 0.0 {
+            for (child, along) in children.iter().zip(alongs.iter_mut()) {
+                if let (Size::Grow(weight), _) = self.node(*child).style.sizes(style.direction) {
+                    *along = left * weight.max(0.0) / weights;
+                }
+            }
+        }
+
+        let left = if weights > 0.0 { 0.0 } else { left };
+        let (mut position, spacing) = match style.justify {
+            Justify::Start => (0.0, gap),
+            Justify::Center => (left * 0.5, gap),
+            Justify::End => (left, gap),
+            Justify::SpaceBetween if children.len() > 1 => (0.0, gap + left / (children.len() - 1) as f32),
+            Justify::SpaceBetween => (0.0, gap),
+        };
+        for ((child, along), across) in children.iter().zip(alongs).zip(acrosses) {
+            let offset = match style.align {
+                Align::Start | Align::Stretch => 0.0,
+                Align::Center => (content_across - across) * 0.5,
+                Align::End => content_across - across,
+            };
+            let rect = match style.direction {
+                Direction::Row => UiRect::new(content.x + position, content.y + offset, along, across),
+                Direction::Column => UiRect::new(content.x + offset, content.y + position, across, along),
+            };
+            self.place(*child, rect, scale);
+            position += along + spacing;
+        }
+    }
+}
+
+/// Lays out the `UiLayout` tree over the `UiViewport` at the accessibility UI scale, redrawing the `UiMesh` when it
+/// changed
+pub fn layout_ui(world: &World) {
+    let viewport = world.with_resource::<UiViewport, _>(|viewport| *viewport).unwrap_or_default();
+    let scale = accessibility::settings().ui_scale_factor(viewport.scale_factor);
+    let mesh = world.with_resource_mut::<UiLayout, _>(|UiLayout(tree)| {
+        tree.layout(viewport.size, scale).then(|| {
+            let mut mesh = UiMesh::default();
+            tree.draw(&mut mesh, scale);
+            mesh
+        })
+    }).flatten();
+    if let Some(mesh) = mesh {
+        world.insert_resource(mesh);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_and_columns_reflow() {
+        let mut tree = LayoutTree::new(Style::row().with_padding(Edges::all(10.0)).with_gap(5.0));
+        let sidebar = tree.add(tree.root(), Style::column().with_size(Size::Percent(25.0), Size::Auto));
+        let main = tree.add(tree.root(), Style::column().with_size(Size::Grow(1.0), Size::Auto).with_align(Align::Center).with_justify(Justify::End));
+        let button = tree.add(main, Style::default().with_size(Size::Pixels(100.0), Size::Pixels(20.0)));
+        let label = tree.add(main, Style::default());
+        tree.set_content_size(label, Some([40.0, 10.0]));
+
+        assert!(tree.layout([420.0, 300.0], 1.0));
+        assert!(!tree.layout([420.0, 300.0], 1.0));
+        assert_eq!(tree.rect(sidebar), UiRect::new(10.0, 10.0, 100.0, 280.0));
+        assert_eq!(tree.rect(main), UiRect::new(115.0, 10.0, 295.0, 280.0));
+        // Justified to the bottom and centred across
+        assert_eq!(tree.rect(label), UiRect::new(242.5, 280.0, 40.0, 10.0));
+        assert_eq!(tree.rect(button), UiRect::new(212.5, 260.0, 100.0, 20.0));
+
+        // Twice the scale doubles fixed sizes and padding, percentages follow the view
+        assert!(tree.layout([840.0, 600.0], 2.0));
+        assert_eq!(tree.rect(sidebar), UiRect::new(20.0, 20.0, 200.0, 560.0));
+        assert_eq!(tree.rect(button).width, 200.0);
+
+        tree.remove(main);
+        assert!(!tree.contains(button) && tree.children(tree.root()) == [sidebar]);
+        let mut mesh = UiMesh::default();
+        tree.set_visual(sidebar, Some(Visual::Rect(Fill::WHITE)));
+        tree.layout([840.0, 600.0], 2.0);
+        tree.draw(&mut mesh, 2.0);
+        assert_eq!(mesh.vertices.len(), 4);
+    }
+
+    #[test]
+    fn hit_testing_and_focus_order() {
+        let mut tree = LayoutTree::new(Style::row());
+        let panel = tree.add(tree.root(), Style::column().with_size(Size::Pixels(100.0), Size::Pixels(100.0)));
+        let first = tree.add(panel, Style::default().with_size(Size::Pixels(100.0), Size::Pixels(20.0)));
+        let second = tree.add(panel, Style::default().with_size(Size::Pixels(100.0), Size::Pixels(20.0)));
+        let spacer = tree.add(tree.root(), Style::default().with_size(Size::Pixels(50.0), Size::Pixels(50.0)));
+        tree.set_visual(panel, Some(Visual::Rect(Fill::WHITE)));
+        tree.set_focusable(second, true);
+        tree.set_focusable(first, true);
+        tree.layout([400.0, 300.0], 1.0);
+
+        // Boxes inside others are on top, boxes without a visual or interaction are see through
+        assert_eq!(tree.hit_test([50.0, 10.0]), Some(first));
+        assert_eq!(tree.hit_test([50.0, 30.0]), Some(second));
+        assert_eq!(tree.hit_test([50.0, 80.0]), Some(panel));
+        assert_eq!(tree.hit_test([120.0, 10.0]), None);
+        tree.set_interactive(spacer, true);
+        assert_eq!(tree.hit_test([120.0, 10.0]), Some(spacer));
+
+        assert_eq!(tree.focusables(), vec![first, second]);
+        tree.remove(panel);
+        assert!(tree.focusables().is_empty());
+    }
+}
@@ -0,0 +1,312 @@
+//!
+//! UI primitives
+//!
+//! Geometry for the UI pass, built on the CPU into a `UiMesh` of 2D vertices in pixels from the top left of the view.
+//! Panels, buttons and bars are made of a few shapes: plain and `rounded_rect`s, their `rounded_outline`s, and
+//! `nine_slice` images whose corners keep their size while the edges and centre stretch, so one small image frames a
+//! panel of any size. Every shape takes a `Fill`, a solid color or a two color linear gradient at any angle, which is
+//! exact when interpolated across the shape's vertices so gradients need no shader support. Images are regions of an
+//! atlas page, so a whole screen of UI draws from one texture
+//!
+//! Screens are arranged by the `layout` tree, which places boxes of these shapes and reflows them when the window is
+//! resized or its scale changes. `input` hit tests the pointer against those boxes, moves keyboard focus between them
+//! and keeps what the UI takes from reaching gameplay
+//!
+
+use crate::asset::atlas::AtlasRegion;
+
+pub mod layout;
+pub mod input;
+
+/// A rectangle in pixels from the top left of the view
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UiRect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+/// How a shape is colored, multiplied with its image if it has one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fill {
+    Solid([f32; 4]),
+    /// From `from` on one side of the shape to `to` on the other, along `angle` radians clockwise from left to right
+    Linear { from: [f32; 4], to: [f32; 4], angle: f32 },
+}
+
+/// An image cut into a 3x3 grid by its insets. Corners are drawn at their size, edges stretch along their length and
+/// the centre stretches both ways
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NineSlice {
+    /// Where the image is in its texture
+    pub uv_min: [f32; 2],
+    pub uv_max: [f32; 2],
+    /// Size of the image in pixels
+    pub size: [f32; 2],
+    /// Pixels from the left, top, right and bottom edges of the image where the slices are cut
+    pub insets: [f32; 4],
+    /// Whether the centre is drawn, frames around other content leave it out
+    pub center: bool,
+}
+
+/// A UI vertex, laid out to match the UI vertex shader's input
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UiVertex {
+    pub position: [f32; 2],
+    pub uv: [f32; 2],
+    pub color: [f32; 4],
+}
+
+/// Triangles to draw with the UI pass, in the order they were added
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UiMesh {
+    pub vertices: Vec<UiVertex>,
+    pub indices: Vec<u32>,
+}
+
+// Impls
+
+impl UiRect {
+    pub fn new(x: f32, y: f32, width: f32, height: f32) -> Self {
+        UiRect { x, y, width, height }
+    }
+
+    pub fn right(&self) -> f32 {
+        self.x + self.width
+    }
+
+    pub fn bottom(&self) -> f32 {
+        self.y + self.height
+    }
+
+    pub fn center(&self) -> [f32; 2] {
+        [self.x + self.width * 0.5, self.y + self.height * 0.5]
+    }
+
+    /// Shrunk by `amount` on every side, never past its centre
+    pub fn inset(&self, amount: f32) -> UiRect {
+        let (x, y) = (amount.min(self.width * 0.5), amount.min(self.height * 0.5));
+        UiRect::new(self.x + x, self.y + y, self.width - 2.0 * x, self.height - 2.0 * y)
+    }
+}
+
+impl Fill {
+    pub const WHITE: Fill = Fill::Solid([1.0; 4]);
+
+    /// Top to bottom
+    pub fn vertical(top: [f32; 4], bottom: [f32; 4]) -> Self {
+        Fill::Linear { from: top, to: bottom, angle: std::f32::consts::FRAC_PI_2 }
+    }
+
+    /// Left to right
+    pub fn horizontal(left: [f32; 4], right: [f32; 4]) -> Self {
+        Fill::Linear { from: left, to: right, angle: 0.0 }
+    }
+
+    /// The color at `point` of a shape within `bounds`
+    pub fn color_at(&self, bounds: &UiRect, point: [f32; 2]) -> [f32; 4] {
+        match *self {
+            Fill::Solid(color) => color,
+            Fill::Linear { from, to, angle } => {
+                let direction = [angle.cos(), angle.sin()];
+                // The gradient runs between the corners furthest back and furthest along its direction
+                let reach = (bounds.width * direction[0].abs() + bounds.height * direction[1].abs()) * 0.5;
+                let center = bounds.center();
+                let along = (point[0] - center[0]) * direction[0] + (point[1] - center[1]) * direction[1];
+                let t = if reach > 0.0 { (along / reach * 0.5 + 0.5).clamp(0.0, 1.0) } else { 0.0 };
+                std::array::from_fn(|i| from[i] + (to[i] - from[i]) * t)
+            },
+        }
+    }
+}
+
+impl NineSlice {
+    /// Slices an atlas image, `insets` in pixels from its left, top, right and bottom
+    pub fn from_region(region: &AtlasRegion, insets: [f32; 4]) -> Self {
+        NineSlice {
+            uv_min: region.uv_min,
+            uv_max: region.uv_max,
+            size: [region.rect.width as f32, region.rect.height as f32],
+            insets,
+            center: true,
+        }
+    }
+
+    pub fn with_center(mut self, center: bool) -> Self {
+        self.center = center;
+        self
+    }
+}
+
+impl UiVertex {
+    /// The vertex buffer's contents
+    pub fn as_bytes(vertices: &[UiVertex]) -> &[u8] {
+        // Safety: `UiVertex` is `repr(C)` and made only of 4 byte fields, so it has no padding
+        unsafe { std::slice::from_raw_parts(vertices.as_ptr() as *const u8, std::mem::size_of_val(vertices)) }
+    }
+}
+
+impl UiMesh {
+    pub fn clear(&mut self) {
+        self.vertices.clear();
+        self.indices.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    /// A rectangle drawn without an image
+    pub fn rect(&mut self, rect: UiRect, fill: Fill) {
+        self.image(rect, [0.0; 2], [0.0; 2], fill);
+    }
+
+    /// A rectangle showing the part of the texture between `uv_min` and `uv_max`
+    pub fn image(&mut self, rect: UiRect, uv_min: [f32; 2], uv_max: [f32; 2], fill: Fill) {
+        let first = self.vertices.len() as u32;
+        for (x, y, u, v) in [(rect.x, rect.y, uv_min[0], uv_min[1]), (rect.right(), rect.y, uv_max[0], uv_min[1]), (rect.x, rect.bottom(), uv_min[0], uv_max[1]), (rect.right(), rect.bottom(), uv_max[0], uv_max[1])] {
+            self.vertices.push(UiVertex { position: [x, y], uv: [u, v], color: fill.color_at(&rect, [x, y]) });
+        }
+        self.indices.extend([first, first + 2, first + 1, first + 1, first + 2, first + 3]);
+    }
+
+    /// An image stretched over `rect` by its slices. Insets that don't fit in `rect` are scaled down together so the
+    /// corners never overlap
+    pub fn nine_slice(&mut self, rect: UiRect, slice: &NineSlice, fill: Fill) {
+        let [left, top, right, bottom] = slice.insets;
+        let scale = |near: f32, far: f32, length: f32| if near + far > length && near + far > 0.0 { length / (near + far) } else { 1.0 };
+        let (horizontal, vertical) = (scale(left, right, rect.width), scale(top, bottom, rect.height));
+
+        let xs = [rect.x, rect.x + left * horizontal, rect.right() - right * horizontal, rect.right()];
+        let ys = [rect.y, rect.y + top * vertical, rect.bottom() - bottom * vertical, rect.bottom()];
+        let uv = |min: f32, max: f32, size: f32, inset: f32| if size > 0.0 { (max - min) * inset / size } else { 0.0 };
+        let us = [slice.uv_min[0], slice.uv_min[0] + uv(slice.uv_min[0], slice.uv_max[0], slice.size[0], left), slice.uv_max[0] - uv(slice.uv_min[0], slice.uv_max[0], slice.size[0], right), slice.uv_max[0]];
+        let vs = [slice.uv_min[1], slice.uv_min[1] + uv(slice.uv_min[1], slice.uv_max[1], slice.size[1], top), slice.uv_max[1] - uv(slice.uv_min[1], slice.uv_max[1], slice.size[1], bottom), slice.uv_max[1]];
+
+        let first = self.vertices.len() as u32;
+        for row in 0..4 {
+            for column in 0..4 {
+                let position = [xs[column], ys[row]];
+                self.vertices.push(UiVertex { position, uv: [us[column], vs[row]], color: fill.color_at(&rect, position) });
+            }
+        }
+        for row in 0..3u32 {
+            for column in 0..3u32 {
+                if row == 1 && column == 1 && !slice.center {
+                    continue
+                }
+                let corner = first + row * 4 + column;
+                self.indices.extend([corner, corner + 4, corner + 1, corner + 1, corner + 4, corner + 5]);
+            }
+        }
+    }
+
+    /// A rectangle with its corners rounded by `radius`, drawn as a fan around its centre
+    pub fn rounded_rect(&mut self, rect: UiRect, radius: f32, fill: Fill) {
+        let outline = rounded_outline_points(rect, radius);
+        let first = self.vertices.len() as u32;
+        let center = rect.center();
+        self.vertices.push(UiVertex { position: center, uv: [0.0; 2], color: fill.color_at(&rect, center) });
+        self.vertices.extend(outline.iter().map(|point| UiVertex { position: *point, uv: [0.0; 2], color: fill.color_at(&rect, *point) }));
+        let count = outline.len() as u32;
+        for i in 0..count {
+            self.indices.extend([first, first + 1 + i, first + 1 + (i + 1) % count]);
+        }
+    }
+
+    /// A border `thickness` pixels wide around the inside of a rounded rectangle
+    pub fn rounded_outline(&mut self, rect: UiRect, radius: f32, thickness: f32, fill: Fill) {
+        let outer = rounded_outline_points(rect, radius);
+        // The inner edge follows the same corners with the radius reduced, so both edges have as many points
+        let inner_rect = rect.inset(thickness);
+        let inner_radius = (radius - thickness).max(0.0);
+        let inner: Vec<[f32; 2]> = outer.iter().map(|point| {
+            let corner_center = |value: f32, min: f32, max: f32, radius: f32| value.clamp(min + radius, max - radius);
+            let radius = radius.min(rect.width * 0.5).min(rect.height * 0.5);
+            let center = [corner_center(point[0], rect.x, rect.right(), radius), corner_center(point[1], rect.y, rect.bottom(), radius)];
+            let inner_center = [corner_center(center[0], inner_rect.x, inner_rect.right(), inner_radius), corner_center(center[1], inner_rect.y, inner_rect.bottom(), inner_radius)];
+            let offset = [point[0] - center[0], point[1] - center[1]];
+            let scale = if radius > 0.0 { inner_radius / radius } else { 0.0 };
+            [inner_center[0] + offset[0] * scale, inner_center[1] + offset[1] * scale]
+        }).collect();
+
+        let first = self.vertices.len() as u32;
+        for (outer, inner) in outer.iter().zip(&inner) {
+            self.vertices.push(UiVertex { position: *outer, uv: [0.0; 2], color: fill.color_at(&rect, *outer) });
+            self.vertices.push(UiVertex { position: *inner, uv: [0.0; 2], color: fill.color_at(&rect, *inner) });
+        }
+        let count = outer.len() as u32;
+        for i in 0..count {
+            let (a, b) = (first + i * 2, first + (i + 1) % count * 2);
+            self.indices.extend([a, a + 1, b, b, a + 1, b + 1]);
+        }
+    }
+}
+
+/// How many segments a corner of `radius` pixels is rounded with, enough that the steps aren't visible
+pub fn corner_segments(radius: f32) -> u32 {
+    (radius.max(0.0).sqrt() * 2.0).ceil().clamp(1.0, 16.0) as u32
+}
+
+/// Points around a rounded rectangle clockwise from the top of its top left corner, each corner running through
+/// `corner_segments` steps. Radii larger than half the rectangle are reduced to fit
+fn rounded_outline_points(rect: UiRect, radius: f32) -> Vec<[f32; 2]> {
+    let radius = radius.max(0.0).min(rect.width * 0.5).min(rect.height * 0.5);
+    let segments = corner_segments(radius);
+    let corners = [
+        ([rect.right() - radius, rect.y + radius], -std::f32::consts::FRAC_PI_2),
+        ([rect.right() - radius, rect.bottom() - radius], 0.0),
+        ([rect.x + radius, rect.bottom() - radius], std::f32::consts::FRAC_PI_2),
+        ([rect.x + radius, rect.y + radius], std::f32::consts::PI),
+    ];
+    corners.iter().flat_map(|(center, start)| (0..=segments).map(move |step| {
+        let angle = start + std::f32::consts::FRAC_PI_2 * step as f32 / segments as f32;
+        [center[0] + radius * angle.cos(), center[1] + radius * angle.sin()]
+    })).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nine_slices_keep_their_corners() {
+        let slice = NineSlice { uv_min: [0.0, 0.0], uv_max: [0.5, 0.5], size: [32.0, 32.0], insets: [8.0, 8.0, 8.0, 8.0], center: false };
+        let mut mesh = UiMesh::default();
+        mesh.nine_slice(UiRect::new(10.0, 10.0, 100.0, 40.0), &slice, Fill::WHITE);
+        assert_eq!((mesh.vertices.len(), mesh.indices.len()), (16, 48));
+        assert_eq!((mesh.vertices[5].position, mesh.vertices[5].uv), ([18.0, 18.0], [0.125, 0.125]));
+        assert_eq!((mesh.vertices[10].position, mesh.vertices[10].uv), ([102.0, 42.0], [0.375, 0.375]));
+
+        // Too small for the insets, they shrink together
+        mesh.clear();
+        mesh.nine_slice(UiRect::new(0.0, 0.0, 8.0, 40.0), &slice.with_center(true), Fill::WHITE);
+        assert_eq!((mesh.vertices[1].position, mesh.vertices[2].position), ([4.0, 0.0], [4.0, 0.0]));
+        assert_eq!(mesh.indices.len(), 54);
+    }
+
+    #[test]
+    fn rounded_rects_and_gradients() {
+        let rect = UiRect::new(0.0, 0.0, 40.0, 20.0);
+        let fill = Fill::vertical([1.0, 0.0, 0.0, 1.0], [0.0, 0.0, 1.0, 1.0]);
+        let mut mesh = UiMesh::default();
+        mesh.rounded_rect(rect, 4.0, fill);
+        let inside = |point: [f32; 2]| point[0] >= -1e-4 && point[0] <= 40.0 + 1e-4 && point[1] >= -1e-4 && point[1] <= 20.0 + 1e-4;
+        assert!(mesh.vertices.iter().all(|vertex| inside(vertex.position)));
+        assert_eq!(mesh.vertices.len(), 1 + 4 * (corner_segments(4.0) as usize + 1));
+        assert_eq!(mesh.vertices[0].color, [0.5, 0.0, 0.5, 1.0]);
+
+        let close = |a: [f32; 4], b: [f32; 4]| a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-4);
+        let top = mesh.vertices.iter().find(|vertex| vertex.position[1].abs() < 1e-4).unwrap();
+        assert!(close(top.color, [1.0, 0.0, 0.0, 1.0]));
+        let diagonal = Fill::Linear { from: [0.0; 4], to: [1.0; 4], angle: std::f32::consts::FRAC_PI_4 };
+        assert!(close(diagonal.color_at(&rect, [0.0, 0.0]), [0.0; 4]) && close(diagonal.color_at(&rect, [40.0, 20.0]), [1.0; 4]));
+
+        mesh.clear();
+        mesh.rounded_outline(rect, 4.0, 2.0, fill);
+        assert_eq!(mesh.indices.len(), mesh.vertices.len() * 3);
+        assert!(mesh.vertices.iter().skip(1).step_by(2).all(|vertex| inside(vertex.position) && vertex.position[1] >= 2.0 - 1e-4));
+    }
+}
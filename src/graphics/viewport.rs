@@ -0,0 +1,144 @@
+//!
+//! Viewports
+//!
+//! Every camera drawing to the window covers a rectangle of it, the whole window by default. Giving several cameras
+//! their own rectangles splits the screen, e.g. for local multiplayer. Extraction gathers each of them into
+//! `ExtractedViewports` with the meshes it sees, and the renderer records them as consecutive draw ranges into the same
+//! swapchain image, setting each one's viewport and scissor before its draws
+//!
+
+use ash::vk;
+
+use crate::{extent::{Extent2, Rect}, system::{world::World, transform}};
+
+use super::{camera::Camera, extract::ExtractedView, lod::{self, ExtractedMeshes}, targets::RenderTarget};
+
+/// A rectangle of the window in fractions of its size, from the top left
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+/// A window camera with its rectangle and what it sees
+#[derive(Debug, Clone, PartialEq)]
+pub struct ViewportView {
+    pub viewport: Viewport,
+    pub view: ExtractedView,
+    pub meshes: ExtractedMeshes,
+}
+
+/// World resource of this frame's window views in draw order, written by extraction
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExtractedViewports {
+    pub views: Vec<ViewportView>,
+}
+
+// Impls
+
+impl Default for Viewport {
+    fn default() -> Self {
+        Viewport::FULL
+    }
+}
+
+impl Viewport {
+    pub const FULL: Viewport = Viewport { x: 0.0, y: 0.0, width: 1.0, height: 1.0 };
+
+    /// The usual layout for `players` local players: side by side for two, then a grid of two columns
+    pub fn split(players: usize) -> Vec<Viewport> {
+        match players {
+            0 => Vec::new(),
+            1 => vec![Viewport::FULL],
+            2 => vec![
+                Viewport { x: 0.0, y: 0.0, width: 0.5, height: 1.0 },
+                Viewport { x: 0.5, y: 0.0, width: 0.5, height: 1.0 },
+            ],
+            _ => {
+                let rows = players.div_ceil(2);
+                let height = 1.0 / rows as f32;
+                (0..players).map(|player| Viewport {
+                    x: (player % 2) as f32 * 0.5,
+                    y: (player / 2) as f32 * height,
+                    width: 0.5,
+                    height,
+                }).collect()
+            },
+        }
+    }
+
+    /// Width over height of the rectangle on a surface of `extent`
+    pub fn aspect(&self, extent: vk::Extent2D) -> f32 {
+        self.rect(extent.into()).extent().aspect()
+    }
+
+    /// The rectangle in pixels of a surface of `extent`, rounded so neighbouring viewports share edges exactly
+    pub fn rect(&self, extent: Extent2) -> Rect {
+        let (width, height) = (extent.width as f32, extent.height as f32);
+        let corner = |fraction: f32, length: f32| (fraction * length).round().clamp(0.0, length) as i32;
+        Rect::from_corners(corner(self.x, width), corner(self.y, height), corner(self.x + self.width, width), corner(self.y + self.height, height))
+    }
+
+    pub fn scissor(&self, extent: vk::Extent2D) -> vk::Rect2D {
+        self.rect(extent.into()).into()
+    }
+
+    pub fn to_vk(&self, extent: vk::Extent2D) -> vk::Viewport {
+        let scissor = self.scissor(extent);
+        vk::Viewport {
+            x: scissor.offset.x as f32,
+            y: scissor.offset.y as f32,
+            width: scissor.extent.width as f32,
+            height: scissor.extent.height as f32,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        }
+    }
+}
+
+/// Extracts every active camera drawing to the window into `ExtractedViewports`. The first is the main view and the
+/// only one moving level of detail selections
+pub fn extract_viewports(world: &World, alpha: f64) {
+    let mut views: Vec<ViewportView> = Vec::new();
+    for entity in world.query::<Camera, ()>() {
+        let Some(camera) = world.component::<Camera, _>(entity, |camera| *camera).filter(|camera| camera.active) else { continue };
+        if camera.target != RenderTarget::Window {
+            continue
+        }
+        let Some(transform) = transform::render_transform(world, entity) else { continue };
+
+        let view = ExtractedView { camera, transform, alpha };
+        let meshes = lod::select_meshes(world, &view, views.is_empty());
+        views.push(ViewportView { viewport: camera.viewport, view, meshes });
+    }
+    world.insert_resource(ExtractedViewports { views });
+}
+
+/// Points the following draws at `viewport` of a surface of `extent`
+pub(crate) fn set_viewport(device: &ash::Device, command_buffer: vk::CommandBuffer, viewport: &Viewport, extent: vk::Extent2D) {
+    unsafe {
+        device.cmd_set_viewport(command_buffer, 0, &[viewport.to_vk(extent)]);
+        device.cmd_set_scissor(command_buffer, 0, &[viewport.scissor(extent)]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_layouts_cover_the_window() {
+        let extent = vk::Extent2D { width: 1281, height: 721 };
+        for players in 1..=4 {
+            let area: u32 = Viewport::split(players).iter().map(|viewport| viewport.scissor(extent)).map(|rect| rect.extent.width * rect.extent.height).sum();
+            let expected = if players == 3 { extent.width * extent.height - 640 * 360 } else { extent.width * extent.height };
+            assert_eq!(area, expected);
+        }
+
+        let halves = Viewport::split(2);
+        assert_eq!(halves[1].scissor(extent).offset.x as u32 + halves[1].scissor(extent).extent.width, extent.width);
+        assert_eq!(halves[0].scissor(extent).extent.width + halves[1].scissor(extent).extent.width, extent.width);
+    }
+}
@@ -1,11 +1,52 @@
-use std::{rc::Rc, collections::HashSet, hash::Hash};
+use std::{rc::Rc, collections::HashSet, hash::Hash, ffi::CStr, sync::{Arc, Mutex}};
 use ash::vk;
 
+use crate::debug::{self, log, breadcrumbs};
+
 use super::vulkan_experimental::VulkanInstance;
 
 pub struct VulkanDebugUtils {
     loader: ash::extensions::ext::DebugUtils,
     messenger: vk::DebugUtilsMessengerEXT,
+    /// Read by the callback through its user data pointer, must outlive the messenger
+    _filter: Box<DebugMessageFilter>,
+}
+
+/// State shared with `vulkan_debug_callback`
+pub(super) struct DebugMessageFilter {
+    ignored_ids: HashSet<i32>,
+    ignored_names: HashSet<String>,
+    break_on: HashSet<DebugUtilsMessageSeverity>,
+    log: log::Logger,
+    collector: Option<ValidationCollector>,
+}
+
+/// A message received by the debug callback
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ValidationMessage {
+    pub(crate) severity: DebugUtilsMessageSeverity,
+    pub(crate) id_name: Option<String>,
+    pub(crate) message: String,
+}
+
+/// Keeps every message that passes the filter, so tests can assert on what the validation layers reported
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ValidationCollector {
+    messages: Arc<Mutex<Vec<ValidationMessage>>>,
+}
+
+impl ValidationCollector {
+    pub(crate) fn messages(&self) -> Vec<ValidationMessage> {
+        self.messages.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+
+    pub(crate) fn with_severity(&self, severity: DebugUtilsMessageSeverity) -> Vec<ValidationMessage> {
+        self.messages().into_iter().filter(|message| message.severity == severity).collect()
+    }
+
+    fn push(&self, message: ValidationMessage) {
+        self.messages.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(message);
+    }
 }
 
 impl Drop for VulkanDebugUtils {
@@ -23,6 +64,11 @@ pub(super) struct VulkanDebugUtilsBuilder<'a> {
     debug_message_types: HashSet<DebugUtilsMessageType>,
     debug_message_severities: HashSet<DebugUtilsMessageSeverity>,
     messenger_callback: Option<VulkanDebugUtilsMessengerCallbackType>,
+    ignored_ids: HashSet<i32>,
+    ignored_names: HashSet<String>,
+    break_on: HashSet<DebugUtilsMessageSeverity>,
+    breadcrumb_capacity: Option<usize>,
+    collector: Option<ValidationCollector>,
 }
 
 impl<'a> VulkanDebugUtilsBuilder<'a> {
@@ -33,6 +79,11 @@ impl<'a> VulkanDebugUtilsBuilder<'a> {
             debug_message_types: HashSet::new(),
             debug_message_severities: HashSet::new(),
             messenger_callback: None,
+            ignored_ids: HashSet::new(),
+            ignored_names: HashSet::new(),
+            break_on: HashSet::new(),
+            breadcrumb_capacity: None,
+            collector: None,
         }
     }
 
@@ -46,11 +97,42 @@ impl<'a> VulkanDebugUtilsBuilder<'a> {
         self
     }
 
+    /// Replaces the default callback. Custom callbacks receive the `DebugMessageFilter` as user data
     pub(super) fn with_messenger_callback(mut self, callback: VulkanDebugUtilsMessengerCallbackType) -> Self {
         self.messenger_callback = Some(callback);
         self
     }
 
+    /// Ignores messages by their `messageIdNumber`
+    pub(super) fn with_ignored_message_ids(mut self, ids: &[i32]) -> Self {
+        self.ignored_ids.extend(ids);
+        self
+    }
+
+    /// Ignores messages by their `pMessageIdName`, e.g. `UNASSIGNED-BestPractices-vkCreateInstance-specialuse-extension`
+    pub(super) fn with_ignored_message_names(mut self, names: &[&str]) -> Self {
+        self.ignored_names.extend(names.iter().map(|name| String::from(*name)));
+        self
+    }
+
+    /// Traps into the debugger when a message of one of these severities arrives
+    pub(super) fn with_break_on(mut self, severities: &[DebugUtilsMessageSeverity]) -> Self {
+        self.break_on.extend(severities);
+        self
+    }
+
+    /// Number of recent messages kept as crash report breadcrumbs
+    pub(super) fn with_breadcrumb_capacity(mut self, capacity: usize) -> Self {
+        self.breadcrumb_capacity = Some(capacity);
+        self
+    }
+
+    /// Also hands every message that passes the filter to `collector`
+    pub(super) fn with_collector(mut self, collector: ValidationCollector) -> Self {
+        self.collector = Some(collector);
+        self
+    }
+
     pub(super) fn build(self) -> Result<VulkanDebugUtils, vk::Result> {
         let mut create_info = vk::DebugUtilsMessengerCreateInfoEXT::builder();
 
@@ -78,14 +160,29 @@ impl<'a> VulkanDebugUtilsBuilder<'a> {
                 create_info = create_info.message_severity(severity_flags);
             }
         }
-        create_info = create_info.pfn_user_callback(self.messenger_callback);
+        if let Some(capacity) = self.breadcrumb_capacity {
+            breadcrumbs::set_capacity(capacity);
+        }
+
+        let mut filter = Box::new(DebugMessageFilter {
+            ignored_ids: self.ignored_ids,
+            ignored_names: self.ignored_names,
+            break_on: self.break_on,
+            log: log::get(),
+            collector: self.collector,
+        });
+
+        create_info = create_info
+            .pfn_user_callback(Some(self.messenger_callback.unwrap_or(vulkan_debug_callback)))
+            .user_data(&mut *filter as *mut DebugMessageFilter as *mut std::ffi::c_void);
         
         let loader = ash::extensions::ext::DebugUtils::new(self.entry, self.instance);
         let messenger = unsafe { loader.create_debug_utils_messenger(&create_info, None)? };
         
         Ok(VulkanDebugUtils {
             loader,
-            messenger
+            messenger,
+            _filter: filter,
         })
 
     }
@@ -99,24 +196,75 @@ pub(super) enum DebugUtilsMessageType {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub(super) enum DebugUtilsMessageSeverity {
+pub(crate) enum DebugUtilsMessageSeverity {
     Verbose,
     Info,
     Warning,
     Error,
 }
 
-/// Debug Callback
-pub unsafe extern "system" fn vulkan_debug_callback_println(
+impl DebugUtilsMessageSeverity {
+    fn from_flags(flags: vk::DebugUtilsMessageSeverityFlagsEXT) -> Self {
+        if flags.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+            DebugUtilsMessageSeverity::Error
+        } else if flags.contains(vk::DebugUtilsMessageSeverityFlagsEXT::WARNING) {
+            DebugUtilsMessageSeverity::Warning
+        } else if flags.contains(vk::DebugUtilsMessageSeverityFlagsEXT::INFO) {
+            DebugUtilsMessageSeverity::Info
+        } else {
+            DebugUtilsMessageSeverity::Verbose
+        }
+    }
+}
+
+impl DebugMessageFilter {
+    fn is_ignored(&self, id: i32, name: Option<&str>) -> bool {
+        self.ignored_ids.contains(&id) || name.is_some_and(|name| self.ignored_names.contains(name))
+    }
+}
+
+/// Debug Callback. Filters messages, logs them, records them as breadcrumbs and optionally breaks into the debugger
+pub unsafe extern "system" fn vulkan_debug_callback(
     message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
     message_type: vk::DebugUtilsMessageTypeFlagsEXT,
     p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
-    _p_user_data: *mut std::ffi::c_void,
+    p_user_data: *mut std::ffi::c_void,
 ) -> vk::Bool32 {
-    let message = std::ffi::CStr::from_ptr((*p_callback_data).p_message);
-    let severity = format!("{:?}", message_severity).to_lowercase();
-    let ty = format!("{:?}", message_type).to_lowercase();
-    println!("[Debug][{}][{}] {:?}", severity, ty, message);
+    let data = &*p_callback_data;
+    let filter = (p_user_data as *const DebugMessageFilter).as_ref();
+    let name = (!data.p_message_id_name.is_null()).then(|| CStr::from_ptr(data.p_message_id_name).to_string_lossy());
+
+    if filter.is_some_and(|f| f.is_ignored(data.message_id_number, name.as_deref())) {
+        return vk::FALSE
+    }
+
+    let message = if data.p_message.is_null() { Default::default() } else { CStr::from_ptr(data.p_message).to_string_lossy() };
+    let severity = DebugUtilsMessageSeverity::from_flags(message_severity);
+    let text = format!("[{}] {}", format!("{:?}", message_type).to_lowercase(), message);
+
+    breadcrumbs::push("vulkan", &text);
+
+    if let Some(collector) = filter.and_then(|f| f.collector.as_ref()) {
+        collector.push(ValidationMessage {
+            severity,
+            id_name: name.as_deref().map(String::from),
+            message: message.to_string(),
+        });
+    }
+
+    match filter {
+        Some(filter) => match severity {
+            DebugUtilsMessageSeverity::Error => filter.log.error(text),
+            DebugUtilsMessageSeverity::Warning => filter.log.warn(text),
+            _ => filter.log.info(text),
+        },
+        None => println!("[Debug][{:?}]{}", severity, text),
+    }
+
+    if filter.is_some_and(|f| f.break_on.contains(&severity)) {
+        debug::debug_break();
+    }
+
     vk::FALSE
 }
 
@@ -1,10 +1,10 @@
-use std::{rc::Rc, mem::ManuallyDrop, collections::{HashMap, BTreeMap, HashSet}};
+use std::{sync::Arc, mem::ManuallyDrop, collections::{HashMap, BTreeMap, HashSet}, ffi::CStr};
 use ash::{vk::{self, QueueFlags, QueueFamilyProperties}, extensions::khr};
 use serde::{Serialize, Deserialize};
 use winit::window::Window;
 
-use crate::{graphics::{vulkan_debug, vulkan_experimental::builders::{InstanceValidationLayer, VulkanLogicalDeviceBuilder}}, debug};
-use super::vulkan_debug::{VulkanDebugUtils, ValidationLayersDescriptor, DebugUtilsMessageType, DebugUtilsMessageSeverity};
+use crate::{config, graphics::{vulkan_debug, memory_budget, diagnostics, backend::RendererConfig, resources, device::SharedDevice, submission::{Submission, SubmissionBatcher}, bindless::BindlessSupport, capabilities, device_report::{self, DeviceReport, DeviceInfo, MemoryHeapInfo, QueueFamilyReport}, vulkan_experimental::builders::{InstanceValidationLayer, VulkanLogicalDeviceBuilder}}, debug};
+use super::vulkan_debug::{VulkanDebugUtils, ValidationLayersDescriptor, DebugUtilsMessageType, DebugUtilsMessageSeverity, ValidationCollector};
 
 pub(crate) struct VulkanInstance {
     instance: ash::Instance,
@@ -26,7 +26,8 @@ impl std::ops::DerefMut for VulkanInstance {
 }
 
 pub(crate) struct VulkanGraphics {
-    window: Rc<winit::window::Window>,
+    /// `None` when running headless
+    window: Option<Arc<winit::window::Window>>,
 
     entry: ash::Entry,
     instance: VulkanInstance,
@@ -39,6 +40,8 @@ pub(crate) struct VulkanGraphics {
 
     scene: Option<RenderStyle>,
     ui: Option<RenderStyle>,
+
+    submissions: SubmissionBatcher,
 }
 
 enum DebugImpl {
@@ -66,10 +69,14 @@ pub(crate) struct PhysicalDevice {
 }
 
 struct LogicalDevice {
-    queues: Vec<vk::Queue>,
     family_indices: Vec<u32>,
-    device: Option<ash::Device>,
+    /// Shared with every owned resource in `resources`, which destroy themselves through it
+    device: Option<Arc<SharedDevice>>,
     command_pools: Vec<vk::CommandPool>,
+    /// `VK_EXT_memory_budget` is enabled
+    memory_budget: bool,
+    /// Descriptor indexing is enabled for bindless textures
+    bindless: Option<BindlessSupport>,
 }
 
 struct Swapchain {
@@ -150,26 +157,43 @@ struct QueueFamilyInfo {
 
 // Impls
 
+/// Outcome of `smoke_test`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SmokeTestReport {
+    pub frames: u32,
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
 impl VulkanGraphics {
-    pub(crate) fn new(window: Rc<winit::window::Window>) -> Result<Self, VulkanResult> {
-        let entry = load_entry();
+    pub(crate) fn new(window: Arc<winit::window::Window>) -> Result<Self, VulkanResult> {
+        Self::init(Some(window), None)
+    }
+
+    /// Initializes without a window or surface, optionally collecting every validation message
+    pub(crate) fn headless(collector: Option<ValidationCollector>) -> Result<Self, VulkanResult> {
+        Self::init(None, collector)
+    }
+
+    fn init(window: Option<Arc<winit::window::Window>>, collector: Option<ValidationCollector>) -> Result<Self, VulkanResult> {
+        let entry = load_entry()?;
 
         use builders::InstanceExtension;
+        let extensions: &[InstanceExtension] = match window {
+            Some(_) => &[InstanceExtension::ExtDebugUtils, InstanceExtension::KhrSurface, InstanceExtension::KhrWaylandSurface],
+            None => &[InstanceExtension::ExtDebugUtils],
+        };
         let instance = builders::VulkanInstanceBuilder::new(&entry)
             .with_app_name("Test App Name")
             .with_engine_name("Test Engine Name")
-            .with_extensions(&[
-                InstanceExtension::ExtDebugUtils,
-                InstanceExtension::KhrSurface,
-                InstanceExtension::KhrWaylandSurface,
-            ])
+            .with_extensions(extensions)
             .with_validation_layers(&[
                 InstanceValidationLayer::LunarGApiDump,
                 InstanceValidationLayer::KhronosValidation,
             ])
             .build()?;
             
-        let debug = vulkan_debug::VulkanDebugUtilsBuilder::new(&entry, &instance)
+        let mut debug = vulkan_debug::VulkanDebugUtilsBuilder::new(&entry, &instance)
             .with_debug_message_types(&[
                 DebugUtilsMessageType::General,
                 DebugUtilsMessageType::Performance,
@@ -181,14 +205,36 @@ impl VulkanGraphics {
                 DebugUtilsMessageSeverity::Error,
                 DebugUtilsMessageSeverity::Verbose,
             ])
-            .with_messenger_callback(vulkan_debug::vulkan_debug_callback_println)
-            .build()?;
+            .with_breadcrumb_capacity(64)
+            .with_break_on(match std::env::var_os("HADRON_VK_BREAK") {
+                Some(_) => &[DebugUtilsMessageSeverity::Error],
+                None => &[],
+            });
+        if let Some(collector) = collector {
+            debug = debug.with_collector(collector);
+        }
+        let debug = debug.build()?;
 
-        let surface = SurfaceImpl::Wayland(WaylandSurface::new(&entry, &instance, &window)?);
+        let surface = match &window {
+            Some(window) => SurfaceImpl::Wayland(WaylandSurface::new(&entry, &instance, window)?),
+            None => SurfaceImpl::None,
+        };
         let physical = PhysicalDevice::new(&instance, &surface)?;
+        capabilities::detect(&instance, physical.device);
+        match build_device_report(&instance, &surface, physical.device) {
+            Ok(report) => device_report::publish(report),
+            Err(error) => debug::log::get().warn(format!("unable to build the device report: {:?}", error)),
+        }
+        if config::get().section::<RendererConfig>().dump_vulkan_environment {
+            match diagnostics::collect(&entry, &instance) {
+                Ok(environment) => diagnostics::publish(environment),
+                Err(error) => debug::log::get().warn(format!("unable to dump the vulkan environment: {}", error)),
+            }
+        }
         let logical = VulkanLogicalDeviceBuilder::new(&instance, &physical, &surface, instance.validation_layers.clone())
             .build()?;
-        
+        memory_budget::publish(memory_budget::query(&instance, physical.device, logical.memory_budget));
+
         Ok(VulkanGraphics {
             window: window,
             entry: entry,
@@ -196,18 +242,83 @@ impl VulkanGraphics {
             debug: ManuallyDrop::new(debug),
             physical: physical,
             logical: Some(logical),
-            surface: None,
+            surface: Some(surface),
             swapchain: None,
             scene: None,
-            ui: None
+            ui: None,
+            submissions: SubmissionBatcher::new(),
         })
     }
+
+    /// Reads the device's current memory budget and publishes it, see `memory_budget`
+    pub(crate) fn update_memory_budget(&self) {
+        let extension = self.logical.as_ref().is_some_and(|logical| logical.memory_budget);
+        memory_budget::publish(memory_budget::query(&self.instance, self.physical.device, extension));
+    }
+
+    /// Records and submits `frames` empty command buffers on the primary queue, waiting for each. Exercises the
+    /// submission path when there's no swapchain to present to
+    pub(crate) fn submit_empty_frames(&mut self, frames: u32) -> Result<(), VulkanResult> {
+        let logical = self.logical.as_ref().ok_or(VulkanResult::Error(VulkanError::InitializationFailed))?;
+        let device = logical.device.as_ref().ok_or(VulkanResult::Error(VulkanError::InitializationFailed))?;
+        let pool = logical.command_pools[0];
+
+        let allocate_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1);
+        let command_buffer = unsafe { device.allocate_command_buffers(&allocate_info)? }[0];
+        let fence = unsafe { device.create_fence(&vk::FenceCreateInfo::builder(), None)? };
+
+        let submitted = (0..frames).try_for_each(|_| unsafe {
+            device.begin_command_buffer(command_buffer, &vk::CommandBufferBeginInfo::builder())?;
+            device.end_command_buffer(command_buffer)?;
+            // Waited on right away, so flushed immediately rather than at the end of a frame
+            self.submissions.push(0, Submission::new(&[command_buffer]));
+            self.submissions.flush(device, 0, fence)?;
+            device.wait_for_fences(&[fence], true, u64::MAX)?;
+            device.reset_fences(&[fence])
+        });
+
+        unsafe {
+            device.destroy_fence(fence, None);
+            device.free_command_buffers(pool, &[command_buffer]);
+        }
+        Ok(submitted?)
+    }
 }
 
 impl Drop for VulkanGraphics {
+    /// Tears down in reverse order of creation: device, surface, debug messenger, then the instance
     fn drop(&mut self) {
-        todo!()
+        unsafe {
+            if let Some(logical) = self.logical.take() {
+                logical.destroy();
+            }
+            if let Some(SurfaceImpl::Wayland(surface)) = self.surface.take() {
+                surface.surface_loader.destroy_surface(surface.surface_khr, None);
+            }
+            ManuallyDrop::drop(&mut self.debug);
+            self.instance.destroy_instance(None);
+        }
+    }
+}
+
+/// Initializes the backend headlessly with validation enabled, submits a few frames and tears it down again,
+/// reporting every validation error and warning raised along the way. Catches regressions in init and teardown order
+pub fn smoke_test(frames: u32) -> Result<SmokeTestReport, Box<dyn std::error::Error>> {
+    let collector = ValidationCollector::default();
+    {
+        let mut graphics = VulkanGraphics::headless(Some(collector.clone())).map_err(VulkanResult::into_error)?;
+        graphics.submit_empty_frames(frames).map_err(VulkanResult::into_error)?;
     }
+
+    let text = |severity| collector.with_severity(severity).into_iter().map(|message| message.message).collect();
+    Ok(SmokeTestReport {
+        frames,
+        errors: text(DebugUtilsMessageSeverity::Error),
+        warnings: text(DebugUtilsMessageSeverity::Warning),
+    })
 }
 
 impl Default for DebugImpl {
@@ -287,7 +398,7 @@ impl PhysicalDevice {
         let mut queue_family_map: BTreeMap<QueueFamilyGroup, Vec<QueueFamilyInfo>> = BTreeMap::new();
         for (index, family) in queue_family_properties.iter().enumerate() {
             let surface_support = match surface {
-                SurfaceImpl::None => return Err(VulkanResult::Error(VulkanError::MissingSurfaceImplementation)),
+                SurfaceImpl::None => false,
                 SurfaceImpl::Wayland(wayland_surface) => unsafe {
                     wayland_surface.surface_loader.get_physical_device_surface_support(physical_device, index as u32, wayland_surface.surface_khr)?
                 },
@@ -316,13 +427,99 @@ impl PhysicalDevice {
     }
 }
 
+/// Describes every physical device, its memory heaps and queue families, and the device groups they form
+fn build_device_report(instance: &ash::Instance, surface: &SurfaceImpl, selected: vk::PhysicalDevice) -> Result<DeviceReport, VulkanResult> {
+    let physical_devices = unsafe { instance.enumerate_physical_devices()? };
+
+    let mut devices = Vec::with_capacity(physical_devices.len());
+    for (index, &device) in physical_devices.iter().enumerate() {
+        let properties = unsafe { instance.get_physical_device_properties(device) };
+        let memory = unsafe { instance.get_physical_device_memory_properties(device) };
+        let queue_families = unsafe { instance.get_physical_device_queue_family_properties(device) };
+
+        let memory_heaps = memory.memory_heaps[..memory.memory_heap_count as usize].iter()
+            .map(|heap| MemoryHeapInfo {
+                size: heap.size,
+                device_local: heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL),
+                multi_instance: heap.flags.contains(vk::MemoryHeapFlags::MULTI_INSTANCE),
+            })
+            .collect();
+
+        let mut families = Vec::with_capacity(queue_families.len());
+        for (family_index, family) in queue_families.iter().enumerate() {
+            let present = match surface {
+                SurfaceImpl::None => false,
+                SurfaceImpl::Wayland(wayland_surface) => unsafe {
+                    wayland_surface.surface_loader.get_physical_device_surface_support(device, family_index as u32, wayland_surface.surface_khr)?
+                },
+            };
+            families.push(QueueFamilyReport {
+                index: family_index as u32,
+                queue_count: family.queue_count,
+                graphics: family.queue_flags.contains(QueueFlags::GRAPHICS),
+                compute: family.queue_flags.contains(QueueFlags::COMPUTE),
+                transfer: family.queue_flags.contains(QueueFlags::TRANSFER),
+                sparse_binding: family.queue_flags.contains(QueueFlags::SPARSE_BINDING),
+                present,
+            });
+        }
+
+        devices.push(DeviceInfo {
+            index,
+            name: unsafe { CStr::from_ptr(properties.device_name.as_ptr()) }.to_string_lossy().into_owned(),
+            device_type: diagnostics::device_type(properties.device_type),
+            vendor_id: properties.vendor_id,
+            vendor: String::from(device_report::vendor_name(properties.vendor_id)),
+            device_id: properties.device_id,
+            api_version: diagnostics::version_string(properties.api_version),
+            driver_version: properties.driver_version,
+            max_image_dimension_2d: properties.limits.max_image_dimension2_d,
+            memory_heaps,
+            queue_families: families,
+        });
+    }
+
+    let group_count = unsafe { instance.enumerate_physical_device_groups_len()? };
+    let mut groups = vec![vk::PhysicalDeviceGroupProperties::default(); group_count];
+    unsafe { instance.enumerate_physical_device_groups(&mut groups)? };
+    let device_groups = groups.iter()
+        .map(|group| group.physical_devices[..group.physical_device_count as usize].iter()
+            .filter_map(|device| physical_devices.iter().position(|d| d == device))
+            .collect())
+        .collect();
+
+    Ok(DeviceReport {
+        devices,
+        device_groups,
+        selected: physical_devices.iter().position(|d| *d == selected),
+    })
+}
+
 impl LogicalDevice {
     fn new() -> Self {
         LogicalDevice {
-            queues: Vec::new(),
             family_indices: Vec::new(),
             device: None,
             command_pools: Vec::new(),
+            memory_budget: false,
+            bindless: None,
+        }
+    }
+
+    /// Waits for the device to go idle, then destroys its command pools and the device itself. Every owned resource
+    /// must have been dropped by now
+    unsafe fn destroy(mut self) {
+        if let Some(device) = self.device.take() {
+            let live = resources::live_resources();
+            debug_assert!(live.is_empty() && Arc::strong_count(&device) == 1, "destroying the device with resources still alive: {}", live);
+            let _ = device.wait_idle();
+            for pool in self.command_pools.drain(..) {
+                device.destroy_command_pool(pool, None);
+            }
+            match Arc::try_unwrap(device) {
+                Ok(device) => device.destroy(),
+                Err(_) => debug::log::get().error("device still shared at teardown, leaking it rather than destroying it in use"),
+            }
         }
     }
 }
@@ -356,8 +553,17 @@ impl From<vk::Result> for VulkanResult {
             vk::Result::ERROR_TOO_MANY_OBJECTS => VulkanResult::Error(VulkanError::TooManyObjects),
             vk::Result::ERROR_FORMAT_NOT_SUPPORTED => VulkanResult::Error(VulkanError::FormatNotSupported),
             vk::Result::ERROR_FRAGMENTED_POOL => VulkanResult::Error(VulkanError::FragmentedPool),
-            vk::Result::ERROR_UNKNOWN => VulkanResult::Error(VulkanError::Unknown),
-            _ => todo!()
+            _ => VulkanResult::Error(VulkanError::Unknown),
+        }
+    }
+}
+
+impl VulkanResult {
+    /// Boxes any result as an error, including the non-error status codes, for callers that only expect success
+    pub(crate) fn into_error(self) -> Box<dyn std::error::Error> {
+        match self {
+            VulkanResult::Error(error) => Box::new(error),
+            status => format!("unexpected vulkan status {:?}", status).into(),
         }
     }
 }
@@ -405,10 +611,11 @@ impl From<&vk::QueueFamilyProperties> for QueueFamilyGroup {
 
 /// Builders
 mod builders {
-    use std::{collections::{HashSet, VecDeque}, ffi::CString, hash::Hash};
+    use std::{collections::{HashSet, VecDeque}, ffi::CString, hash::Hash, sync::Arc};
     use ash::vk;
     use serde::{Serialize, Deserialize};
     use crate::debug::log;
+    use crate::graphics::capabilities::{self, Feature};
 
     use super::{VulkanResult, LogicalDevice, PhysicalDevice, QueueFamilyGroup, SurfaceImpl, VulkanError, QueueFamilyInfo, VulkanInstance};
 
@@ -564,7 +771,8 @@ mod builders {
                 dbg!(&queue_fams);
                 for queue_fam_info in queue_fams {
                     dbg!(queue_fam_info.surface_support);
-                    if queue_fam_info.surface_support {
+                    // Without a surface (headless) any graphics queue will do
+                    if queue_fam_info.surface_support || matches!(self.surface, SurfaceImpl::None) {
                         gtc_surface_support_queues.push_back(queue_fam_info);
                     }
                 }
@@ -611,24 +819,60 @@ mod builders {
                 self.log.warn("no available transfer only queues");
             }
             
-            let device_extension_name_pointers: Vec<*const i8> = vec![ash::extensions::khr::Swapchain::name().as_ptr()];
+            let mut device_extension_name_pointers: Vec<*const i8> = match self.surface {
+                SurfaceImpl::None => Vec::new(),
+                _ => vec![ash::extensions::khr::Swapchain::name().as_ptr()],
+            };
+            let memory_budget = super::memory_budget::is_supported(self.instance, self.physical.device);
+            if memory_budget {
+                device_extension_name_pointers.push(vk::ExtMemoryBudgetFn::name().as_ptr());
+            } else {
+                self.log.warn("VK_EXT_memory_budget is unsupported, memory budgets are estimated from heap sizes");
+            }
             let validation_layer_name_pointers: Vec<*const i8> = self.validation_layers.iter().map(|l| l.layer_name_pointer()).collect();
-            let device_create_info = vk::DeviceCreateInfo::builder()
+            let bindless = super::BindlessSupport::query(self.instance, self.physical.device).filter(|_| capabilities::supports(Feature::BindlessTextures));
+            let mut indexing_features = super::BindlessSupport::features();
+            let mut device_create_info = vk::DeviceCreateInfo::builder()
                 .queue_create_infos(&queue_create_infos)
                 .enabled_extension_names(&device_extension_name_pointers)
                 .enabled_layer_names(&validation_layer_name_pointers);
+            match bindless {
+                Some(support) => {
+                    self.log.info(format!("enabling bindless textures, up to {} per set", support.max_textures));
+                    device_create_info = device_create_info.push_next(&mut indexing_features);
+                },
+                None => self.log.warn("bindless textures are unavailable, textures will be bound per material"),
+            }
 
             let logical_device = unsafe {
                 self.instance.create_device(self.physical.device, &device_create_info, None)?
             };
             
-            // queues
+            let family_indices: Vec<u32> = primary_queue_info.iter().chain(transfer_queue_info.iter()).map(|info| info.index as u32).collect();
+
+            let mut command_pools = Vec::with_capacity(family_indices.len());
+            for index in &family_indices {
+                let pool_info = vk::CommandPoolCreateInfo::builder()
+                    .queue_family_index(*index)
+                    .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER);
+                match unsafe { logical_device.create_command_pool(&pool_info, None) } {
+                    Ok(pool) => command_pools.push(pool),
+                    Err(error) => {
+                        unsafe {
+                            command_pools.iter().for_each(|pool| logical_device.destroy_command_pool(*pool, None));
+                            logical_device.destroy_device(None);
+                        }
+                        return Err(error.into())
+                    },
+                }
+            }
 
             Ok(LogicalDevice {
-                queues: todo!(),
-                family_indices: todo!(),
-                device: Some(logical_device),
-                command_pools: todo!(),
+                device: Some(Arc::new(super::SharedDevice::new(logical_device, &family_indices))),
+                family_indices,
+                command_pools,
+                memory_budget,
+                bindless,
             })
         } 
 
@@ -647,10 +891,9 @@ mod builders {
 
 
 // Fn
-fn load_entry() -> ash::Entry {
-    unsafe {
-        ash::Entry::load().expect("unable to load vulkan entry point")
-    }
+fn load_entry() -> Result<ash::Entry, VulkanResult> {
+    let entry = unsafe { ash::Entry::load() }.map_err(|_| vk::Result::ERROR_INITIALIZATION_FAILED);
+    Ok(super::audit::check(entry, "loading the vulkan entry point")?)
 }
 
 #[deprecated]
@@ -658,3 +901,15 @@ fn load_entry() -> ash::Entry {
 fn make_validation_layer_descriptor() -> ValidationLayersDescriptor {
     ValidationLayersDescriptor::new()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore = "needs a Vulkan driver with the Khronos validation layer"]
+    fn validation_smoke_test() {
+        let report = smoke_test(3).expect("vulkan smoke test failed to initialize");
+        assert!(report.errors.is_empty(), "validation errors: {:#?}", report.errors);
+    }
+}
@@ -1,7 +1,14 @@
-use std::rc::Rc;
+use std::{sync::Arc, ffi::CStr, time::{Duration, Instant}};
 
 use ash::vk;
-use crate::graphics::{ debug, surface, render };
+use crate::graphics::{ audit, bindless::BindlessSupport, capabilities::{self, Feature}, culling, debug, device::SharedDevice, frame, surface, render, resources, capture::{CapturedFrame, PixelFormat}, events::SwapchainRefreshed, render_graph::ResourceKind, watchdog::{self, GpuWatchdog} };
+use crate::graphics::backend::{GraphicsBackend, FrameStatus, BackendError, FrameTiming, TimingSource};
+use crate::unique::UniqueId;
+use crate::system::world::World;
+use crate::debug::log;
+
+/// Longest a frame waits on the previous present before falling back to its fence, in nanoseconds
+const PRESENT_WAIT_TIMEOUT: u64 = 100_000_000;
 
 /**
  * Setup
@@ -26,7 +33,7 @@ use crate::graphics::{ debug, surface, render };
 
 #[deprecated]
 pub(crate) struct TVulkanGraphics {
-    window: Rc<winit::window::Window>,
+    window: Arc<winit::window::Window>,
     entry: ash::Entry,
     instance: ash::Instance,
     debug: std::mem::ManuallyDrop<debug::VulkanDebugWidget>,
@@ -38,29 +45,37 @@ pub(crate) struct TVulkanGraphics {
     swapchain: surface::Swapchain,
     renderpass: vk::RenderPass,
     pipeline: render::Pipeline,
+    frame: std::mem::ManuallyDrop<frame::FrameRenderer>,
     command_pools: CommandPools,
     command_buffers: Vec<vk::CommandBuffer>,
+    vsync: bool,
+    /// Image acquired by `begin_frame` and whether the swapchain was suboptimal
+    acquired: Option<(usize, bool)>,
+    timing: Option<FrameTiming>,
+    watchdog: GpuWatchdog,
+    /// Acquire or present reported the swapchain suboptimal, it's refreshed before the next frame
+    suboptimal: bool,
 }
 
 impl TVulkanGraphics {
-    pub(crate) fn init(window: Rc<winit::window::Window>) -> Result<Self, vk::Result> {
-        let entry = unsafe { ash::Entry::load().expect("couldn't load Vulkan entry point") };
+    pub(crate) fn init(window: Arc<winit::window::Window>) -> Result<Self, vk::Result> {
+        let entry = audit::check(unsafe { ash::Entry::load() }.map_err(|_| vk::Result::ERROR_INITIALIZATION_FAILED), "loading the Vulkan entry point")?;
         
         let layers = debug::ValidationLayers::init()?;
         let instance = init_vulkan_instance(&entry, &layers)?;
         let debug = debug::VulkanDebugWidget::init(&entry, &instance)?;
         let surfaces = surface::GraphicsSurface::init(&window, &entry, &instance)?;
         let (physical_device, physical_device_properties) = choose_physical_device(&instance)?;
+        capabilities::detect(&instance, physical_device);
         let queue_families = QueueFamilies::init(&instance, physical_device, &surfaces)?;
         let graphics_device = GraphicsDevice::init(&instance, physical_device, &queue_families, layers)?;
-        let mut swapchain = surface::Swapchain::init(&instance, physical_device, &graphics_device, &surfaces, &queue_families)?;
+        let mut swapchain = surface::Swapchain::init(&instance, physical_device, &graphics_device, &surfaces, &queue_families, true)?;
         let renderpass = render::init_renderpass(&graphics_device, physical_device, &surfaces)?;
         swapchain.create_framebuffers(&graphics_device, renderpass)?;
-        let pipeline = render::Pipeline::init(&graphics_device, &swapchain, &renderpass)?;
+        let frame = frame::FrameRenderer::new(&instance, physical_device, graphics_device.shared(), graphics_device.bindless(), graphics_device.culls_on_gpu())?;
+        let pipeline = render::Pipeline::init(&graphics_device, &renderpass, frame.textures())?;
         let command_pools = CommandPools::init(&graphics_device, &queue_families)?;
         let command_buffers = create_commandbuffers(&graphics_device, &command_pools, swapchain.framebuffer_count())?;
-        
-        fill_command_buffers(&command_buffers, renderpass, &swapchain, &pipeline, &graphics_device)?;
 
         Ok(TVulkanGraphics {
             window,
@@ -75,8 +90,14 @@ impl TVulkanGraphics {
             swapchain,
             renderpass,
             pipeline,
+            frame: std::mem::ManuallyDrop::new(frame),
             command_pools,
             command_buffers,
+            vsync: true,
+            acquired: None,
+            timing: None,
+            watchdog: GpuWatchdog::default(),
+            suboptimal: false,
         })
     }
 
@@ -92,29 +113,331 @@ impl TVulkanGraphics {
         &self.command_buffers
     }
 
-    pub(crate) fn wait_for_fences(&self) {
-        self.graphics_device.wait_for_fences(&self.swapchain)
+    /// Waits for the current frame's fence under the watchdog
+    pub(crate) fn wait_for_fences(&mut self) -> Result<(), vk::Result> {
+        let fences = [self.swapchain.frame_fence()];
+        audit::check(self.watchdog.wait(watchdog::poll_fences(self.graphics_device.logical_device(), &fences)), "vkWaitForFences")
+    }
+
+    pub(crate) fn set_watchdog(&mut self, threshold: Duration, recover: bool) {
+        self.watchdog.configure(threshold, recover);
+    }
+
+    pub(crate) fn recovers_from_hangs(&self) -> bool {
+        self.watchdog.recovers()
+    }
+
+    /// Rebuilds everything from the instance up after the device was lost, keeping the window, vsync and watchdog
+    pub(crate) fn recover_device(&mut self) -> Result<(), vk::Result> {
+        log::get().with_topic("watchdog").warn("rebuilding the graphics device");
+        let mut recovered = TVulkanGraphics::init(self.window.clone())?;
+        recovered.watchdog = std::mem::take(&mut self.watchdog);
+        let vsync = self.vsync;
+        *self = recovered;
+        self.set_vsync(vsync)
+    }
+
+    /// Waits until the next frame can be recorded and records when the last one reached the display. With present
+    /// wait that's the previous present being shown, which keeps at most one frame queued, otherwise the frame's fence
+    /// signaling is taken as an estimate
+    pub(crate) fn pace_frame(&mut self) -> Result<(), vk::Result> {
+        let shown = match (self.graphics_device.present_wait(), self.swapchain.last_present_id()) {
+            (Some(present_wait), Some(id)) => unsafe { present_wait.wait_for_present(self.swapchain.handle(), id, PRESENT_WAIT_TIMEOUT) }.is_ok(),
+            _ => false,
+        };
+        if shown {
+            self.timing = Some(FrameTiming::next(self.timing, TimingSource::PresentWait, Instant::now()));
+        }
+
+        self.wait_for_fences()?;
+        if !shown {
+            self.timing = Some(FrameTiming::next(self.timing, TimingSource::Fence, Instant::now()));
+        }
+        Ok(())
+    }
+
+    /// Records the acquired image's command buffer, drawing what extraction left in `world`. Without a world the image
+    /// is only cleared. Call after the frame's fence has been waited on and before it's reset
+    pub(crate) fn record_frame(&mut self, world: Option<&World>, image_index: usize) -> Result<(), vk::Result> {
+        let target = frame::FrameTarget {
+            command_buffer: self.command_buffers[image_index],
+            render_pass: self.renderpass,
+            framebuffer: self.swapchain.framebuffer(image_index),
+            extent: self.swapchain.extent(),
+            format: self.swapchain.format().format,
+            frame: self.swapchain.frame_index(),
+        };
+        self.frame.record(&target, &self.pipeline, world)
+    }
+
+    /// Whether the renderer places particles with its simulation pass, see `SimulationBackend::Compute`
+    pub(crate) fn simulates_particles(&self) -> bool {
+        self.frame.simulates_particles()
     }
 
-    pub(crate) fn reset_fences(&self) {
+    pub(crate) fn reset_fences(&self) -> Result<(), vk::Result> {
         self.graphics_device.reset_fences(&self.swapchain)
     }
     
-    pub(crate) fn submit_commandbuffer(&self, image_index: usize) {
-        self.graphics_device.submit_commandbuffer(image_index, &self.command_buffers, &self.swapchain)
+    pub(crate) fn submit_commandbuffer(&mut self, image_index: usize) -> Result<(), vk::Result> {
+        self.graphics_device.submit_commandbuffer(image_index, &self.command_buffers, &self.swapchain)?;
+        self.frame.finish_frame(self.swapchain.frame_fence());
+        self.watchdog.submitted("graphics", &["main pass"]);
+        self.watchdog.next_frame();
+        Ok(())
+    }
+
+    /// Acquires the next image, waiting for any earlier frame that's still rendering into it
+    pub(crate) fn next_image(&mut self) -> Result<surface::AcquireResult, vk::Result> {
+        let acquired = self.swapchain.next_image()?;
+        if let surface::AcquireResult::Acquired { index, suboptimal } = acquired {
+            self.suboptimal |= suboptimal;
+            if let Some(fence) = self.swapchain.claim_image(index) {
+                audit::check(self.watchdog.wait(watchdog::poll_fences(self.graphics_device.logical_device(), &[fence])), "vkWaitForFences")?;
+            }
+        }
+        Ok(acquired)
+    }
+
+    pub(crate) fn present(&mut self, image_index: usize) -> Result<surface::PresentResult, vk::Result> {
+        let presented = self.swapchain.present(image_index, self.graphics_device.graphics_queue())?;
+        self.suboptimal |= presented == surface::PresentResult::Suboptimal;
+        Ok(presented)
+    }
+
+    /// Copies a submitted swapchain image into host memory, waiting on the graphics queue for it. The image is left in
+    /// the present layout
+    pub(crate) fn read_image(&self, image_index: usize) -> Result<CapturedFrame, vk::Result> {
+        let format = match self.swapchain.format().format {
+            vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => PixelFormat::Bgra8,
+            vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => PixelFormat::Rgba8,
+            _ => return Err(vk::Result::ERROR_FORMAT_NOT_SUPPORTED),
+        };
+        if !self.swapchain.is_readable() {
+            return Err(vk::Result::ERROR_FORMAT_NOT_SUPPORTED)
+        }
+
+        let device = self.graphics_device.logical_device();
+        let extent = self.swapchain.extent();
+        let image = self.swapchain.image(image_index);
+        let size = u64::from(extent.width) * u64::from(extent.height) * 4;
+
+        let buffer_info = vk::BufferCreateInfo::builder().size(size).usage(vk::BufferUsageFlags::TRANSFER_DST).sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let buffer = audit::check(unsafe { device.create_buffer(&buffer_info, None) }, "vkCreateBuffer")?;
+        let requirements = unsafe { device.get_buffer_memory_requirements(buffer) };
+        let properties = unsafe { self.instance.get_physical_device_memory_properties(self.physical_device) };
+        let flags = vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+        let memory_type = resources::memory_type(&properties, requirements.memory_type_bits, flags).ok_or(vk::Result::ERROR_OUT_OF_DEVICE_MEMORY)?;
+        let allocate_info = vk::MemoryAllocateInfo::builder().allocation_size(requirements.size).memory_type_index(memory_type);
+        let command_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(self.command_pools.commandpool_graphics)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1);
+
+        let mut memory = vk::DeviceMemory::null();
+        let mut command_buffers = Vec::new();
+        let copied = (|| -> Result<Vec<u8>, vk::Result> { unsafe {
+            memory = audit::check(device.allocate_memory(&allocate_info, None), "vkAllocateMemory")?;
+            device.bind_buffer_memory(buffer, memory, 0)?;
+            command_buffers = device.allocate_command_buffers(&command_info)?;
+            let command_buffer = command_buffers[0];
+
+            let range = vk::ImageSubresourceRange::builder().aspect_mask(vk::ImageAspectFlags::COLOR).level_count(1).layer_count(1).build();
+            let barrier = |src_access, dst_access, old_layout, new_layout| vk::ImageMemoryBarrier::builder()
+                .src_access_mask(src_access)
+                .dst_access_mask(dst_access)
+                .old_layout(old_layout)
+                .new_layout(new_layout)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image)
+                .subresource_range(range)
+                .build();
+            let to_transfer = barrier(vk::AccessFlags::COLOR_ATTACHMENT_WRITE, vk::AccessFlags::TRANSFER_READ, vk::ImageLayout::PRESENT_SRC_KHR, vk::ImageLayout::TRANSFER_SRC_OPTIMAL);
+            let to_present = barrier(vk::AccessFlags::TRANSFER_READ, vk::AccessFlags::empty(), vk::ImageLayout::TRANSFER_SRC_OPTIMAL, vk::ImageLayout::PRESENT_SRC_KHR);
+            let region = vk::BufferImageCopy::builder()
+                .image_subresource(vk::ImageSubresourceLayers::builder().aspect_mask(vk::ImageAspectFlags::COLOR).layer_count(1).build())
+                .image_extent(vk::Extent3D { width: extent.width, height: extent.height, depth: 1 })
+                .build();
+
+            device.begin_command_buffer(command_buffer, &vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT))?;
+            device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::ALL_COMMANDS, vk::PipelineStageFlags::TRANSFER, vk::DependencyFlags::empty(), &[], &[], &[to_transfer]);
+            device.cmd_copy_image_to_buffer(command_buffer, image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL, buffer, &[region]);
+            device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TRANSFER, vk::PipelineStageFlags::BOTTOM_OF_PIPE, vk::DependencyFlags::empty(), &[], &[], &[to_present]);
+            device.end_command_buffer(command_buffer)?;
+
+            let submit = vk::SubmitInfo::builder().command_buffers(&command_buffers).build();
+            audit::check(device.queue_submit(self.graphics_device.graphics_queue(), &[submit], vk::Fence::null()), "vkQueueSubmit")?;
+            audit::check(device.queue_wait_idle(self.graphics_device.graphics_queue()), "vkQueueWaitIdle")?;
+
+            let mapped = device.map_memory(memory, 0, size, vk::MemoryMapFlags::empty())?;
+            let pixels = std::slice::from_raw_parts(mapped as *const u8, size as usize).to_vec();
+            device.unmap_memory(memory);
+            Ok(pixels)
+        }})();
+
+        unsafe {
+            if !command_buffers.is_empty() {
+                device.free_command_buffers(self.command_pools.commandpool_graphics, &command_buffers);
+            }
+            device.destroy_buffer(buffer, None);
+            if memory != vk::DeviceMemory::null() {
+                device.free_memory(memory, None);
+            }
+        }
+        Ok(CapturedFrame { width: extent.width, height: extent.height, format, pixels: copied? })
     }
 
-    pub(crate) fn next_image(&mut self) -> usize {
-        self.swapchain.next_image()
+    pub(crate) fn is_suboptimal(&self) -> bool {
+        self.suboptimal
+    }
+
+    /// Requeries the surface and rebuilds the swapchain to match it. When the surface's preferred format changed the
+    /// renderpass and pipeline are rebuilt for it as well, the new ones are created before the old are destroyed so a
+    /// failure leaves everything as it was
+    pub(crate) fn refresh_swapchain(&mut self) -> Result<SwapchainRefreshed, vk::Result> {
+        let format = *audit::require(self.surfaces.get_formats(self.physical_device)?.first(), "choosing a surface format", vk::Result::ERROR_FORMAT_NOT_SUPPORTED)?;
+        let format_changed = format != self.swapchain.format();
+        if format_changed {
+            let logical_device = self.graphics_device.logical_device();
+            audit::check(unsafe { logical_device.device_wait_idle() }, "vkDeviceWaitIdle")?;
+            let renderpass = render::init_renderpass(&self.graphics_device, self.physical_device, &self.surfaces)?;
+            let pipeline = match render::Pipeline::init(&self.graphics_device, &renderpass, self.frame.textures()) {
+                Ok(pipeline) => pipeline,
+                Err(error) => {
+                    unsafe { logical_device.destroy_render_pass(renderpass, None) };
+                    return Err(error)
+                },
+            };
+            self.pipeline.cleanup(logical_device);
+            unsafe { logical_device.destroy_render_pass(self.renderpass, None) };
+            self.renderpass = renderpass;
+            self.pipeline = pipeline;
+        }
+
+        self.recreate_swapchain()?;
+        self.suboptimal = false;
+        Ok(SwapchainRefreshed {
+            format: format.format,
+            color_space: format.color_space,
+            extent: self.swapchain.extent().into(),
+            format_changed,
+        })
+    }
+
+    /// Rebuilds the swapchain, its framebuffers and a command buffer for each of its images, after the surface changed
+    /// underneath it. The device, renderpass and pipeline are kept
+    pub(crate) fn recreate_swapchain(&mut self) -> Result<(), vk::Result> {
+        unsafe {
+            self.graphics_device.logical_device().device_wait_idle()?;
+            self.graphics_device.logical_device().free_command_buffers(self.command_pools.commandpool_graphics, &self.command_buffers);
+            self.swapchain.cleanup(&self.graphics_device);
+        }
+
+        let mut swapchain = surface::Swapchain::init(&self.instance, self.physical_device, &self.graphics_device, &self.surfaces, &self.queue_families, self.vsync)?;
+        swapchain.create_framebuffers(&self.graphics_device, self.renderpass)?;
+        let command_buffers = create_commandbuffers(&self.graphics_device, &self.command_pools, swapchain.framebuffer_count())?;
+
+        self.swapchain = swapchain;
+        self.command_buffers = command_buffers;
+        Ok(())
+    }
+
+    /// Switches vsync on or off, recreating the swapchain if it changed
+    pub(crate) fn set_vsync(&mut self, vsync: bool) -> Result<(), vk::Result> {
+        if self.vsync == vsync {
+            return Ok(())
+        }
+        self.vsync = vsync;
+        self.recreate_swapchain()
+    }
+
+    /// Tears down and recreates the window surface and the swapchain built on it, after the surface was lost or the
+    /// window destroyed. The device, renderpass and pipeline are kept
+    pub(crate) fn recreate_surface(&mut self) -> Result<(), vk::Result> {
+        unsafe {
+            self.swapchain.cleanup(&self.graphics_device);
+            std::mem::ManuallyDrop::drop(&mut self.surfaces);
+        }
+
+        // Until a new surface exists `surfaces` is dropped, put something valid back before handing out errors
+        let surfaces = match surface::GraphicsSurface::init(&self.window, &self.entry, &self.instance) {
+            Ok(surfaces) => surfaces,
+            Err(error) => {
+                self.surfaces = std::mem::ManuallyDrop::new(surface::GraphicsSurface::null(&self.entry, &self.instance));
+                return Err(error)
+            },
+        };
+        self.surfaces = std::mem::ManuallyDrop::new(surfaces);
+
+        let graphics_queue_index = self.queue_families.graphics_queue_index().ok_or(vk::Result::ERROR_SURFACE_LOST_KHR)?;
+        if !self.surfaces.get_physical_device_surface_support(self.physical_device, graphics_queue_index as usize)? {
+            return Err(vk::Result::ERROR_SURFACE_LOST_KHR)
+        }
+
+        self.recreate_swapchain()
     }
 }
 
+impl GraphicsBackend for TVulkanGraphics {
+    fn name(&self) -> &'static str {
+        "vulkan"
+    }
+
+    fn begin_frame(&mut self) -> Result<FrameStatus, BackendError> {
+        self.pace_frame()?;
+        let acquired = self.next_image()?;
+        if let surface::AcquireResult::Acquired { index, suboptimal } = acquired {
+            self.acquired = Some((index, suboptimal));
+        }
+        Ok(acquired.status())
+    }
+
+    fn submit(&mut self) -> Result<(), BackendError> {
+        let (index, _) = self.acquired.ok_or(BackendError::Other(String::from("submit without an acquired image")))?;
+        self.record_frame(None, index)?;
+        self.reset_fences()?;
+        Ok(self.submit_commandbuffer(index)?)
+    }
+
+    fn present(&mut self) -> Result<FrameStatus, BackendError> {
+        let (index, suboptimal) = self.acquired.take().ok_or(BackendError::Other(String::from("present without an acquired image")))?;
+        Ok(self.swapchain.present(index, self.graphics_device.graphics_queue())?.status(suboptimal))
+    }
+
+    fn read_frame(&mut self) -> Result<CapturedFrame, BackendError> {
+        let (index, _) = self.acquired.ok_or(BackendError::Other(String::from("read_frame without an acquired image")))?;
+        Ok(self.read_image(index)?)
+    }
+
+    fn recreate(&mut self) -> Result<Option<SwapchainRefreshed>, BackendError> {
+        Ok(Some(self.refresh_swapchain()?))
+    }
+
+    fn recreate_surface(&mut self) -> Result<(), BackendError> {
+        Ok(Self::recreate_surface(self)?)
+    }
+
+    fn frame_timing(&self) -> Option<FrameTiming> {
+        self.timing
+    }
+
+    fn create_resource(&mut self, _name: &str, _kind: ResourceKind) -> Result<UniqueId, BackendError> {
+        Err(BackendError::Unsupported("create_resource"))
+    }
+
+    fn destroy_resource(&mut self, _resource: UniqueId) {}
+}
+
 impl Drop for TVulkanGraphics {
     fn drop(&mut self) {
         unsafe {
             // Extracts the logical device and drops the queues
             let logical_device = self.graphics_device.logical_device();
 
+            // Frames still in flight may be reading what the frame renderer owns
+            let _ = logical_device.device_wait_idle();
+            std::mem::ManuallyDrop::take(&mut self.frame).destroy();
             self.pipeline.cleanup(&logical_device);
             
             logical_device.destroy_render_pass(self.renderpass, None);
@@ -169,7 +492,7 @@ pub(crate) struct CommandPools {
 impl CommandPools {
     pub(crate) fn init(graphics_device: &GraphicsDevice, queue_families: &QueueFamilies) -> Result<CommandPools, vk::Result> {
         let graphics_commandpool_info = vk::CommandPoolCreateInfo::builder()
-            .queue_family_index(queue_families.graphics_queue_index().unwrap())
+            .queue_family_index(audit::require(queue_families.graphics_queue_index(), "finding a graphics queue family", vk::Result::ERROR_INITIALIZATION_FAILED)?)
             .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER);
 
         let commandpool_graphics = graphics_device.create_command_pool(&graphics_commandpool_info)?;
@@ -211,50 +534,19 @@ pub(crate) fn create_commandbuffers(graphics_device: &GraphicsDevice, pools: &Co
     graphics_device.allocate_command_buffers(&command_allocate_info)
 }
 
-pub(crate) fn fill_command_buffers(
-    command_buffers: &Vec<vk::CommandBuffer>,
-    renderpass: vk::RenderPass,
-    swapchain: &surface::Swapchain,
-    pipeline: &render::Pipeline,
-    graphics_device: &GraphicsDevice,
-) -> Result<(), vk::Result> {
-    for (i, &command_buffer) in command_buffers.iter().enumerate() {
-        unsafe {
-            let commandbuffer_begin_info = vk::CommandBufferBeginInfo::builder();
-            graphics_device.begin_command_buffer(command_buffer, &commandbuffer_begin_info)?;
-
-            let clear_values = [vk::ClearValue {
-                color: vk::ClearColorValue {
-                    float32: [0.0, 0.0, 0.0, 1.0],
-                },
-            }];
-
-            let renderpass_begininfo = vk::RenderPassBeginInfo::builder()
-                .render_pass(renderpass)
-                .framebuffer(swapchain.framebuffer(i))
-                .render_area(vk::Rect2D {
-                    offset: vk::Offset2D { x: 0, y: 0 },
-                    extent: swapchain.extent()
-                })
-                .clear_values(&clear_values);
-            
-            let logical_device = graphics_device.logical_device();
-            logical_device.cmd_begin_render_pass(command_buffer, &renderpass_begininfo, vk::SubpassContents::INLINE);
-            logical_device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline.pipeline());
-            logical_device.cmd_draw(command_buffer, 1, 1, 0, 0);
-            logical_device.cmd_end_render_pass(command_buffer);
-            logical_device.end_command_buffer(command_buffer)?;
-
-        }
-    }
-
-    Ok(())
-}
-
 pub struct GraphicsDevice {
     graphics_queue: vk::Queue,
     transfer_queue: Option<vk::Queue>,
     logical_device: ash::Device,
+    /// The same device for resources that destroy themselves. It's never destroyed through this, the renderer destroys
+    /// `logical_device` once everything holding it is gone
+    shared: Arc<SharedDevice>,
+    /// Present id and present wait are both enabled, or neither
+    present_wait: Option<ash::extensions::khr::PresentWait>,
+    /// Descriptor indexing was enabled, for bindless textures
+    bindless: Option<BindlessSupport>,
+    /// Draw indirect count and multi draw indirect were enabled, for the culling pass
+    gpu_culling: bool,
 }
 
 impl GraphicsDevice {
@@ -267,7 +559,7 @@ impl GraphicsDevice {
         let mut graphics_queue_info = None;
         let mut transfer_queue_info = None;
 
-        let graphics_queue_index = queue_families.graphics_queue_index.unwrap();
+        let graphics_queue_index = audit::require(queue_families.graphics_queue_index, "finding a graphics queue family", vk::Result::ERROR_INITIALIZATION_FAILED)?;
         if let Some(transfer_queue_index) = queue_families.transfer_queue_index {
             println!("Has transfer queue index");
             
@@ -291,19 +583,42 @@ impl GraphicsDevice {
         if let Some(graphics_queue_info) = graphics_queue_info { queue_create_infos.push(graphics_queue_info); }
         if let Some(transfer_queue_info) = transfer_queue_info { queue_create_infos.push(transfer_queue_info); }
 
-        let device_extension_name_pointers = vec![ash::extensions::khr::Swapchain::name().as_ptr()];
-        let device_create_info = vk::DeviceCreateInfo::builder()
+        let present_wait_supported = supports_present_wait(instance, physical_device);
+        let mut device_extension_name_pointers = vec![ash::extensions::khr::Swapchain::name().as_ptr()];
+        if present_wait_supported {
+            device_extension_name_pointers.push(vk::KhrPresentIdFn::name().as_ptr());
+            device_extension_name_pointers.push(ash::extensions::khr::PresentWait::name().as_ptr());
+        }
+
+        // Descriptor indexing is core from 1.2, its features are enabled through the 1.2 feature struct
+        let core_1_2 = unsafe { instance.get_physical_device_properties(physical_device) }.api_version >= vk::API_VERSION_1_2;
+        let bindless = BindlessSupport::query(instance, physical_device).filter(|_| core_1_2 && capabilities::supports(Feature::BindlessTextures));
+        let mut vulkan_12_features = vk::PhysicalDeviceVulkan12Features::default();
+        if bindless.is_some() {
+            BindlessSupport::enable(&mut vulkan_12_features);
+        }
+        // The culling pass writes every view's draws for one indirect count draw, which needs both
+        let multi_draw_indirect = unsafe { instance.get_physical_device_features(physical_device) }.multi_draw_indirect == vk::TRUE;
+        let gpu_culling = core_1_2 && multi_draw_indirect && culling::culls_on_gpu();
+        vulkan_12_features.draw_indirect_count = gpu_culling.into();
+        let enabled_features = vk::PhysicalDeviceFeatures::builder().multi_draw_indirect(gpu_culling);
+
+        let mut present_id_features = vk::PhysicalDevicePresentIdFeaturesKHR::builder().present_id(true);
+        let mut present_wait_features = vk::PhysicalDevicePresentWaitFeaturesKHR::builder().present_wait(true);
+        let mut device_create_info = vk::DeviceCreateInfo::builder()
             .queue_create_infos(&queue_create_infos)
             .enabled_extension_names(&device_extension_name_pointers)
-            .enabled_layer_names(&layer_name_pointers);
+            .enabled_layer_names(&layer_name_pointers)
+            .enabled_features(&enabled_features);
+        if present_wait_supported {
+            device_create_info = device_create_info.push_next(&mut present_id_features).push_next(&mut present_wait_features);
+        }
+        if core_1_2 {
+            device_create_info = device_create_info.push_next(&mut vulkan_12_features);
+        }
         
         let logical_device = unsafe { instance.create_device(physical_device, &device_create_info, None)? };
-        let graphics_queue = 
-            if let Some(graphics_queue_index) = queue_families.graphics_queue_index {
-                Some(unsafe { logical_device.get_device_queue(graphics_queue_index, 0) })
-            } else { 
-                None
-            }.unwrap(); // unwrap/expect for now
+        let graphics_queue = unsafe { logical_device.get_device_queue(graphics_queue_index, 0) };
         
         let transfer_queue = 
             if let Some(transfer_queue_index) = queue_families.graphics_queue_index {
@@ -312,17 +627,47 @@ impl GraphicsDevice {
                 None
             };
         
+        let shared = Arc::new(SharedDevice::new(logical_device.clone(), &[graphics_queue_index]));
+        let present_wait = present_wait_supported.then(|| ash::extensions::khr::PresentWait::new(instance, &logical_device));
+        match present_wait.is_some() {
+            true => log::get().info("frames are paced with present wait"),
+            false => log::get().info("present wait is unsupported, frames are paced with fences"),
+        }
+
         Ok(GraphicsDevice {
             graphics_queue,
             transfer_queue,
             logical_device,
+            shared,
+            present_wait,
+            bindless,
+            gpu_culling,
         })
     }
 
+    /// Set when the device was created with descriptor indexing
+    pub(crate) fn bindless(&self) -> Option<BindlessSupport> {
+        self.bindless
+    }
+
+    /// Whether the device was created with what the culling pass needs
+    pub(crate) fn culls_on_gpu(&self) -> bool {
+        self.gpu_culling
+    }
+
+    /// Present wait, if the device supports it along with present ids
+    pub(crate) fn present_wait(&self) -> Option<&ash::extensions::khr::PresentWait> {
+        self.present_wait.as_ref()
+    }
+
     pub(crate) fn logical_device(&self) -> &ash::Device {
         &self.logical_device
     }
 
+    pub(crate) fn shared(&self) -> &Arc<SharedDevice> {
+        &self.shared
+    }
+
     pub(crate) fn graphics_queue(&self) -> vk::Queue {
         self.graphics_queue
     }
@@ -339,10 +684,9 @@ impl GraphicsDevice {
         }        
     }
 
-    pub fn create_graphics_pipelines(&self, create_infos: &[vk::GraphicsPipelineCreateInfo]) -> Vec<vk::Pipeline> {
-        unsafe { 
-            self.logical_device.create_graphics_pipelines(vk::PipelineCache::null(), create_infos, None).unwrap()
-        }
+    pub fn create_graphics_pipelines(&self, create_infos: &[vk::GraphicsPipelineCreateInfo]) -> Result<Vec<vk::Pipeline>, vk::Result> {
+        let pipelines = unsafe { self.logical_device.create_graphics_pipelines(vk::PipelineCache::null(), create_infos, None) };
+        audit::check(pipelines.map_err(|(_, error)| error), "vkCreateGraphicsPipelines")
     }
 
     pub fn create_shader_module(&self, create_info: &vk::ShaderModuleCreateInfoBuilder) -> Result<vk::ShaderModule, vk::Result> {
@@ -385,12 +729,6 @@ impl GraphicsDevice {
         self.logical_device.destroy_command_pool(pool, None);
     }
 
-    pub(crate) fn begin_command_buffer(&self, command_buffer: vk::CommandBuffer, begin_info: &vk::CommandBufferBeginInfoBuilder) -> Result<(), vk::Result> {
-        unsafe {
-            self.logical_device.begin_command_buffer(command_buffer, begin_info)
-        }
-    }
-    
     pub unsafe fn destroy_semaphore(&self, semaphore: vk::Semaphore) {
         self.logical_device.destroy_semaphore(semaphore, None);
     }
@@ -404,26 +742,11 @@ impl GraphicsDevice {
         self.logical_device
     }
     
-    pub(crate) fn wait_for_fences(&self, swapchain: &surface::Swapchain) {
-        unsafe {
-            self.logical_device.wait_for_fences(
-                &[swapchain.draw_fences()[swapchain.current_image()]],
-                true,
-                100_000_000u64,
-            )
-            .expect("wait_for_fences error during wait");
-        }
+    pub(crate) fn reset_fences(&self, swapchain: &surface::Swapchain) -> Result<(), vk::Result> {
+        audit::check(unsafe { self.logical_device.reset_fences(&[swapchain.frame_fence()]) }, "vkResetFences")
     }
     
-    pub(crate) fn reset_fences(&self, swapchain: &surface::Swapchain) {
-        unsafe {
-            self.logical_device.reset_fences(&[
-                swapchain.draw_fences()[swapchain.current_image()]
-            ]).expect("reset_fences error resetting swapchain fences");
-        }
-    }
-    
-    pub(crate) fn submit_commandbuffer(&self, image_index: usize, command_buffers: &[vk::CommandBuffer], swapchain: &surface::Swapchain) {
+    pub(crate) fn submit_commandbuffer(&self, image_index: usize, command_buffers: &[vk::CommandBuffer], swapchain: &surface::Swapchain) -> Result<(), vk::Result> {
         let semaphores_available = [swapchain.image_available_semaphore()];
         let waiting_stages = [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
         let semaphores_finished = [swapchain.image_finished_semaphore()];
@@ -436,13 +759,8 @@ impl GraphicsDevice {
             .build()
         ];
 
-        unsafe {
-            self.logical_device.queue_submit(
-                self.graphics_queue,
-                &submit_info,
-                swapchain.draw_fences()[swapchain.current_image()]
-            ).expect("submit_commandbuffer queue_submit failed");
-        }
+        let submitted = unsafe { self.logical_device.queue_submit(self.graphics_queue, &submit_info, swapchain.frame_fence()) };
+        audit::check(submitted, "vkQueueSubmit")
     }
 }
 
@@ -478,7 +796,7 @@ impl QueueFamilies {
             }
         }
 
-        assert!(graphics_queue_index.is_some());
+        audit::require(graphics_queue_index, "finding a graphics queue family that can present", vk::Result::ERROR_INITIALIZATION_FAILED)?;
         Ok(Self {
             graphics_queue_index,
             transfer_queue_index,
@@ -513,5 +831,20 @@ pub(crate) fn choose_physical_device(instance: &ash::Instance) -> Result<(vk::Ph
         }
         chosen
     };
-    Ok(chosen.unwrap())
+    audit::require(chosen, "choosing a physical device", vk::Result::ERROR_INCOMPATIBLE_DRIVER)
+}
+
+/// Whether the device has both `VK_KHR_present_id` and `VK_KHR_present_wait`, with their features available
+fn supports_present_wait(instance: &ash::Instance, physical_device: vk::PhysicalDevice) -> bool {
+    let extensions = unsafe { instance.enumerate_device_extension_properties(physical_device) }.unwrap_or_default();
+    let has_extension = |name: &CStr| extensions.iter().any(|extension| unsafe { CStr::from_ptr(extension.extension_name.as_ptr()) } == name);
+    if !has_extension(vk::KhrPresentIdFn::name()) || !has_extension(ash::extensions::khr::PresentWait::name()) {
+        return false
+    }
+
+    let mut present_id = vk::PhysicalDevicePresentIdFeaturesKHR::default();
+    let mut present_wait = vk::PhysicalDevicePresentWaitFeaturesKHR::default();
+    let mut features = vk::PhysicalDeviceFeatures2::builder().push_next(&mut present_id).push_next(&mut present_wait);
+    unsafe { instance.get_physical_device_features2(physical_device, &mut features) };
+    present_id.present_id == vk::TRUE && present_wait.present_wait == vk::TRUE
 }
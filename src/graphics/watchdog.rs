@@ -0,0 +1,179 @@
+//!
+//! GPU hang watchdog
+//!
+//! Frame fences are waited on through `GpuWatchdog::wait`, which waits in slices of the hang threshold instead of
+//! forever. When a fence still hasn't signalled after the threshold, or the device is lost while waiting, the watchdog
+//! logs a `HangReport` under the "watchdog" topic with the work submitted most recently, the passes on each queue, so
+//! the report points at what the GPU was running. It then either keeps waiting, or with recovery enabled gives up with
+//! `ERROR_DEVICE_LOST` so the renderer can rebuild its device
+//!
+
+use std::{collections::VecDeque, time::{Duration, Instant}};
+
+use ash::vk;
+use serde::Serialize;
+
+use crate::debug::log;
+
+/// Submissions kept for reports
+const RECENT_SUBMISSIONS: usize = 8;
+
+/// Work submitted to a queue, as it appears in a report
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SubmittedWork {
+    pub frame: u64,
+    pub queue: String,
+    pub passes: Vec<String>,
+    /// Milliseconds between the submission and the report
+    pub age_ms: u64,
+    #[serde(skip)]
+    at: Option<Instant>,
+}
+
+/// Logged when a fence wait runs over the threshold or the device is lost
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct HangReport {
+    pub waited_ms: u64,
+    pub threshold_ms: u64,
+    pub device_lost: bool,
+    /// Whether the watchdog gave up on the wait for the renderer to rebuild the device
+    pub recovering: bool,
+    /// Most recent submissions, oldest first
+    pub submissions: Vec<SubmittedWork>,
+}
+
+pub struct GpuWatchdog {
+    threshold: Duration,
+    recover: bool,
+    frame: u64,
+    recent: VecDeque<SubmittedWork>,
+    last_report: Option<HangReport>,
+}
+
+// Impls
+
+impl Default for GpuWatchdog {
+    fn default() -> Self {
+        GpuWatchdog::new(Duration::from_secs(2), false)
+    }
+}
+
+impl GpuWatchdog {
+    pub fn new(threshold: Duration, recover: bool) -> Self {
+        GpuWatchdog { threshold, recover, frame: 0, recent: VecDeque::with_capacity(RECENT_SUBMISSIONS), last_report: None }
+    }
+
+    pub fn configure(&mut self, threshold: Duration, recover: bool) {
+        self.threshold = threshold;
+        self.recover = recover;
+    }
+
+    pub fn recovers(&self) -> bool {
+        self.recover
+    }
+
+    /// The last hang reported, if any
+    pub fn last_report(&self) -> Option<&HangReport> {
+        self.last_report.as_ref()
+    }
+
+    /// Records work submitted to `queue`, named by the passes it runs
+    pub fn submitted(&mut self, queue: &str, passes: &[&str]) {
+        if self.recent.len() == RECENT_SUBMISSIONS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(SubmittedWork {
+            frame: self.frame,
+            queue: String::from(queue),
+            passes: passes.iter().map(|pass| String::from(*pass)).collect(),
+            age_ms: 0,
+            at: Some(Instant::now()),
+        });
+    }
+
+    /// Marks the start of the next frame's submissions
+    pub fn next_frame(&mut self) {
+        self.frame += 1;
+    }
+
+    /// Waits until `poll` reports its fences signalled. `poll` waits for at most the given nanoseconds and returns
+    /// whether the fences signalled in time
+    pub fn wait(&mut self, mut poll: impl FnMut(u64) -> Result<bool, vk::Result>) -> Result<(), vk::Result> {
+        let start = Instant::now();
+        let slice = self.threshold.as_nanos().clamp(1, u64::MAX as u128) as u64;
+        let mut reported = false;
+        loop {
+            match poll(slice) {
+                Ok(true) => return Ok(()),
+                Ok(false) if reported => (),
+                Ok(false) => {
+                    reported = true;
+                    self.report(start.elapsed(), false);
+                    if self.recover {
+                        return Err(vk::Result::ERROR_DEVICE_LOST)
+                    }
+                },
+                Err(vk::Result::ERROR_DEVICE_LOST) => {
+                    self.report(start.elapsed(), true);
+                    return Err(vk::Result::ERROR_DEVICE_LOST)
+                },
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    fn report(&mut self, waited: Duration, device_lost: bool) {
+        let now = Instant::now();
+        let report = HangReport {
+            waited_ms: waited.as_millis() as u64,
+            threshold_ms: self.threshold.as_millis() as u64,
+            device_lost,
+            recovering: self.recover,
+            submissions: self.recent.iter().map(|work| SubmittedWork {
+                age_ms: work.at.map_or(0, |at| now.saturating_duration_since(at).as_millis() as u64),
+                ..work.clone()
+            }).collect(),
+        };
+        let message = match device_lost {
+            true => "device lost while waiting on a frame",
+            false => "frame fence has not signalled, the gpu may be hung",
+        };
+        log::get().with_topic("watchdog").state(message, &report);
+        self.last_report = Some(report);
+    }
+}
+
+/// Polls `fences` with `vkWaitForFences`, a timeout counting as not signalled
+pub(crate) fn poll_fences<'a>(device: &'a ash::Device, fences: &'a [vk::Fence]) -> impl FnMut(u64) -> Result<bool, vk::Result> + 'a {
+    move |timeout| match unsafe { device.wait_for_fences(fences, true, timeout) } {
+        Ok(()) => Ok(true),
+        Err(vk::Result::TIMEOUT) => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_hangs_and_gives_up_when_recovering() {
+        let mut watchdog = GpuWatchdog::new(Duration::from_millis(5), false);
+        watchdog.submitted("graphics", &["shadows", "main"]);
+        watchdog.next_frame();
+        watchdog.submitted("compute", &["gpu culling"]);
+
+        // Keeps waiting past the threshold without recovery, reporting once
+        let mut polls = 0;
+        assert_eq!(watchdog.wait(|_| { polls += 1; Ok(polls == 3) }), Ok(()));
+        let report = watchdog.last_report().unwrap();
+        assert!(!report.device_lost && !report.recovering);
+        assert_eq!(report.submissions.iter().map(|work| (work.frame, work.passes.len())).collect::<Vec<_>>(), vec![(0, 2), (1, 1)]);
+
+        watchdog.configure(Duration::from_millis(5), true);
+        assert_eq!(watchdog.wait(|_| Ok(false)), Err(vk::Result::ERROR_DEVICE_LOST));
+        assert!(watchdog.last_report().unwrap().recovering);
+        assert_eq!(watchdog.wait(|_| Err(vk::Result::ERROR_DEVICE_LOST)), Err(vk::Result::ERROR_DEVICE_LOST));
+        assert!(watchdog.last_report().unwrap().device_lost);
+    }
+}
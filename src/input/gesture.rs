@@ -0,0 +1,254 @@
+//!
+//! Touch gestures
+//!
+//! A `GestureRecognizer` follows the fingers on a touch screen and turns their raw touch events into `Gesture`s, which
+//! the app sends as events. One finger lifted soon after it went down without moving far is a tap, and one that moves
+//! further drags. Two fingers pinch and rotate together, every move reporting how far they spread and turned since the
+//! last one. Once a second finger lands the gesture stays a two finger one until every finger is lifted, so lifting
+//! one finger of a pinch doesn't start a drag
+//!
+//! Positions are in physical pixels from the top left of the window. Gameplay should only act on gestures while the
+//! `GAMEPLAY` input context receives the pointer
+//!
+
+use std::{collections::BTreeMap, time::{Duration, Instant}};
+
+use serde::{Serialize, Deserialize};
+use winit::event::{Touch, TouchPhase};
+
+use crate::config::{self, ConfigSection};
+use crate::system::{event, world::World};
+
+/// Gesture options from the `gestures` config section
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct GestureConfig {
+    /// Physical pixels a finger can move and still tap, beyond which it drags
+    pub tap_slop: f32,
+    /// Longest a finger can stay down and still tap
+    pub tap_max_ms: u64,
+}
+
+/// Sent as touches are recognized
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Gesture {
+    Tap { position: [f32; 2] },
+    DragStarted { position: [f32; 2] },
+    /// Moved by `delta` since the last drag event
+    Drag { position: [f32; 2], delta: [f32; 2] },
+    DragEnded { position: [f32; 2] },
+    /// The fingers are `scale` times as far apart as at the last pinch event, around `center`
+    Pinch { center: [f32; 2], scale: f32 },
+    /// The fingers turned by `angle` radians clockwise on screen since the last rotate event, around `center`
+    Rotate { center: [f32; 2], angle: f32 },
+}
+
+/// World resource recognizing gestures from the fingers currently down
+#[derive(Debug, Clone)]
+pub struct GestureRecognizer {
+    config: GestureConfig,
+    touches: BTreeMap<u64, TouchPoint>,
+    state: GestureState,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TouchPoint {
+    start: [f32; 2],
+    position: [f32; 2],
+    started: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum GestureState {
+    Idle,
+    /// One finger down that hasn't moved past the tap slop
+    Pressed,
+    Dragging,
+    /// Two or more fingers down, with the distance and angle between the first two at the last move
+    MultiTouch { distance: f32, angle: f32 },
+    /// Some fingers of a multi touch gesture lifted, waiting for the rest
+    Lifting,
+}
+
+// Impls
+
+impl Default for GestureConfig {
+    fn default() -> Self {
+        GestureConfig { tap_slop: 12.0, tap_max_ms: 300 }
+    }
+}
+
+impl ConfigSection for GestureConfig {
+    const NAME: &'static str = "gestures";
+}
+
+impl Default for GestureRecognizer {
+    fn default() -> Self {
+        GestureRecognizer::new(GestureConfig::default())
+    }
+}
+
+impl GestureRecognizer {
+    pub fn new(config: GestureConfig) -> Self {
+        GestureRecognizer { config, touches: BTreeMap::new(), state: GestureState::Idle }
+    }
+
+    /// Fingers currently down
+    pub fn touches(&self) -> usize {
+        self.touches.len()
+    }
+
+    /// Follows finger `id` through `phase` at `position` and `time`, returning the gestures it completes or continues
+    pub fn touch(&mut self, id: u64, phase: TouchPhase, position: [f32; 2], time: Instant) -> Vec<Gesture> {
+        let mut gestures = Vec::new();
+        match phase {
+            TouchPhase::Started => {
+                let dragged = self.touches.values().next().map(|touch| touch.position);
+                self.touches.insert(id, TouchPoint { start: position, position, started: time });
+                if self.touches.len() == 1 {
+                    self.state = GestureState::Pressed;
+                } else {
+                    if let (GestureState::Dragging, Some(position)) = (self.state, dragged) {
+                        gestures.push(Gesture::DragEnded { position });
+                    }
+                    let (distance, angle) = self.span();
+                    self.state = GestureState::MultiTouch { distance, angle };
+                }
+            },
+            TouchPhase::Moved => {
+                let Some(touch) = self.touches.get_mut(&id) else { return gestures };
+                let previous = std::mem::replace(&mut touch.position, position);
+                let (start, slop) = (touch.start, self.config.tap_slop);
+                match self.state {
+                    GestureState::Pressed if length(sub(position, start)) > slop => {
+                        self.state = GestureState::Dragging;
+                        gestures.push(Gesture::DragStarted { position: start });
+                        gestures.push(Gesture::Drag { position, delta: sub(position, start) });
+                    },
+                    GestureState::Dragging => gestures.push(Gesture::Drag { position, delta: sub(position, previous) }),
+                    GestureState::MultiTouch { distance, angle } => {
+                        let (new_distance, new_angle) = self.span();
+                        let center = self.center();
+                        if distance > 0.0 && new_distance != distance {
+                            gestures.push(Gesture::Pinch { center, scale: new_distance / distance });
+                        }
+                        let turned = wrap_angle(new_angle - angle);
+                        if turned != 0.0 {
+                            gestures.push(Gesture::Rotate { center, angle: turned });
+                        }
+                        self.state = GestureState::MultiTouch { distance: new_distance, angle: new_angle };
+                    },
+                    _ => (),
+                }
+            },
+            TouchPhase::Ended | TouchPhase::Cancelled => {
+                let Some(touch) = self.touches.remove(&id) else { return gestures };
+                let tap_max = Duration::from_millis(self.config.tap_max_ms);
+                match self.state {
+                    GestureState::Pressed if phase == TouchPhase::Ended && time.duration_since(touch.started) <= tap_max => {
+                        gestures.push(Gesture::Tap { position: touch.start });
+                    },
+                    GestureState::Dragging => gestures.push(Gesture::DragEnded { position }),
+                    GestureState::MultiTouch { .. } if self.touches.len() >= 2 => {
+                        let (distance, angle) = self.span();
+                        self.state = GestureState::MultiTouch { distance, angle };
+                    },
+                    GestureState::MultiTouch { .. } => self.state = GestureState::Lifting,
+                    _ => (),
+                }
+                if self.touches.is_empty() {
+                    self.state = GestureState::Idle;
+                }
+            },
+        }
+        gestures
+    }
+
+    /// The first two fingers, by id
+    fn pair(&self) -> ([f32; 2], [f32; 2]) {
+        let mut fingers = self.touches.values().map(|touch| touch.position);
+        let first = fingers.next().unwrap_or_default();
+        (first, fingers.next().unwrap_or(first))
+    }
+
+    /// Distance and angle from the first finger to the second
+    fn span(&self) -> (f32, f32) {
+        let (first, second) = self.pair();
+        let [x, y] = sub(second, first);
+        (length([x, y]), y.atan2(x))
+    }
+
+    fn center(&self) -> [f32; 2] {
+        let (first, second) = self.pair();
+        [(first[0] + second[0]) * 0.5, (first[1] + second[1]) * 0.5]
+    }
+}
+
+/// Recognizes gestures from a touch event and sends them as `Gesture` events, returning whether any were recognized
+pub fn handle_touch(world: &World, touch: &Touch) -> bool {
+    if !world.contains_resource::<GestureRecognizer>() {
+        world.insert_resource(GestureRecognizer::new(config::get().section()));
+    }
+    let position = [touch.location.x as f32, touch.location.y as f32];
+    let gestures = world.with_resource_mut::<GestureRecognizer, _>(|recognizer| {
+        recognizer.touch(touch.id, touch.phase, position, Instant::now())
+    }).unwrap_or_default();
+    let recognized = !gestures.is_empty();
+    for gesture in gestures {
+        event::send_event(world, gesture);
+    }
+    recognized
+}
+
+fn sub(a: [f32; 2], b: [f32; 2]) -> [f32; 2] {
+    [a[0] - b[0], a[1] - b[1]]
+}
+
+fn length(v: [f32; 2]) -> f32 {
+    v[0].hypot(v[1])
+}
+
+/// Wraps an angle difference into -pi to pi, so turning across the negative x axis isn't a full turn
+fn wrap_angle(angle: f32) -> f32 {
+    use std::f32::consts::{PI, TAU};
+    (angle + PI).rem_euclid(TAU) - PI
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn taps_drags_pinches_and_rotations() {
+        let mut recognizer = GestureRecognizer::default();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        // Small wobble still taps, waiting too long doesn't
+        recognizer.touch(1, TouchPhase::Started, [100.0, 100.0], at(0));
+        assert!(recognizer.touch(1, TouchPhase::Moved, [104.0, 103.0], at(50)).is_empty());
+        assert_eq!(recognizer.touch(1, TouchPhase::Ended, [104.0, 103.0], at(100)), vec![Gesture::Tap { position: [100.0, 100.0] }]);
+        recognizer.touch(1, TouchPhase::Started, [100.0, 100.0], at(0));
+        assert!(recognizer.touch(1, TouchPhase::Ended, [100.0, 100.0], at(500)).is_empty());
+
+        recognizer.touch(2, TouchPhase::Started, [0.0, 0.0], at(0));
+        assert_eq!(recognizer.touch(2, TouchPhase::Moved, [20.0, 0.0], at(10)), vec![
+            Gesture::DragStarted { position: [0.0, 0.0] },
+            Gesture::Drag { position: [20.0, 0.0], delta: [20.0, 0.0] },
+        ]);
+        assert_eq!(recognizer.touch(2, TouchPhase::Moved, [25.0, 5.0], at(20)), vec![Gesture::Drag { position: [25.0, 5.0], delta: [5.0, 5.0] }]);
+
+        // A second finger ends the drag, spreading and turning the pair pinches and rotates
+        assert_eq!(recognizer.touch(3, TouchPhase::Started, [125.0, 5.0], at(30)), vec![Gesture::DragEnded { position: [25.0, 5.0] }]);
+        let gestures = recognizer.touch(3, TouchPhase::Moved, [25.0, 205.0], at(40));
+        assert_eq!(gestures[0], Gesture::Pinch { center: [25.0, 105.0], scale: 2.0 });
+        let Gesture::Rotate { angle, .. } = gestures[1] else { panic!("expected a rotation") };
+        assert!((angle - std::f32::consts::FRAC_PI_2).abs() < 1e-5);
+
+        // Lifting one finger of a pinch neither taps nor drags
+        assert!(recognizer.touch(2, TouchPhase::Ended, [25.0, 5.0], at(50)).is_empty());
+        assert!(recognizer.touch(3, TouchPhase::Moved, [100.0, 300.0], at(60)).is_empty());
+        assert!(recognizer.touch(3, TouchPhase::Ended, [100.0, 300.0], at(70)).is_empty());
+        assert_eq!(recognizer.touches(), 0);
+    }
+}
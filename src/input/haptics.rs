@@ -0,0 +1,297 @@
+//!
+//! Haptics
+//!
+//! Gameplay asks for rumble by sending `PlayHaptic` events, each a `HapticEffect` for one device or all of them, and
+//! stops it with `StopHaptics`. An effect drives a gamepad's low and high frequency motors through an `Envelope` each,
+//! strength from 0 to 1 over time. Every frame `update_haptics` mixes the effects playing on each device, taking the
+//! strongest per motor, scales them by `haptics.strength` and hands the motor strengths to the `HapticsBackend`
+//!
+//! The backend is whatever drives the hardware, a gamepad library or a platform's haptics API, and is set on the
+//! `Haptics` resource. Without one effects still play out and finish on time, they just aren't felt
+//!
+
+use std::{collections::BTreeMap, time::Duration};
+
+use serde::{Serialize, Deserialize};
+
+use crate::config::{self, ConfigSection};
+use crate::system::{event, time::Time, world::World};
+
+/// Haptics options from the `haptics` config section
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct HapticsConfig {
+    pub enabled: bool,
+    /// Multiplies the strength of every effect, from 0 to 1
+    pub strength: f32,
+}
+
+/// A device with motors, as numbered by the backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DeviceId(pub u32);
+
+/// Drives the motors of the devices it knows about
+pub trait HapticsBackend: Send + Sync + 'static {
+    /// Devices connected now
+    fn devices(&self) -> Vec<DeviceId>;
+
+    /// Sets the strength of a device's low and high frequency motors, from 0 to 1
+    fn set_motors(&mut self, device: DeviceId, low: f32, high: f32);
+}
+
+/// Strength from 0 to 1 over time, linear between keyframes and 0 after the last
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Envelope {
+    /// Times from the start of the effect and the strength at them, in time order
+    keyframes: Vec<(Duration, f32)>,
+}
+
+/// What a device's motors do over time
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HapticEffect {
+    /// The heavy, low frequency motor
+    pub low: Envelope,
+    /// The light, high frequency motor
+    pub high: Envelope,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HapticTarget {
+    All,
+    Device(DeviceId),
+}
+
+/// Starts an effect
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayHaptic {
+    pub target: HapticTarget,
+    pub effect: HapticEffect,
+}
+
+/// Stops the effects started for the target, or every effect for `All`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StopHaptics {
+    pub target: HapticTarget,
+}
+
+/// World resource of the haptics backend and the effects playing
+#[derive(Default)]
+pub struct Haptics {
+    backend: Option<Box<dyn HapticsBackend>>,
+    playing: Vec<Playing>,
+    /// Motor strengths last sent to each device
+    motors: BTreeMap<DeviceId, [f32; 2]>,
+}
+
+struct Playing {
+    target: HapticTarget,
+    effect: HapticEffect,
+    elapsed: Duration,
+}
+
+// Impls
+
+impl Default for HapticsConfig {
+    fn default() -> Self {
+        HapticsConfig { enabled: true, strength: 1.0 }
+    }
+}
+
+impl ConfigSection for HapticsConfig {
+    const NAME: &'static str = "haptics";
+}
+
+impl Envelope {
+    /// Keyframes of time and strength, sorted by time
+    pub fn new(keyframes: &[(Duration, f32)]) -> Self {
+        let mut keyframes = keyframes.to_vec();
+        keyframes.sort_by_key(|(time, _)| *time);
+        Envelope { keyframes }
+    }
+
+    /// `strength` for `duration`
+    pub fn constant(strength: f32, duration: Duration) -> Self {
+        Envelope::new(&[(Duration::ZERO, strength), (duration, strength)])
+    }
+
+    /// Rises to `strength` over `attack`, holds it for `hold` and falls off over `release`
+    pub fn pulse(strength: f32, attack: Duration, hold: Duration, release: Duration) -> Self {
+        Envelope::new(&[(Duration::ZERO, 0.0), (attack, strength), (attack + hold, strength), (attack + hold + release, 0.0)])
+    }
+
+    /// How long until it's over
+    pub fn duration(&self) -> Duration {
+        self.keyframes.last().map(|(time, _)| *time).unwrap_or_default()
+    }
+
+    /// The strength `elapsed` into the envelope, clamped from 0 to 1
+    pub fn strength(&self, elapsed: Duration) -> f32 {
+        let after = self.keyframes.partition_point(|(time, _)| *time <= elapsed);
+        let strength = match (after.checked_sub(1).map(|index| self.keyframes[index]), self.keyframes.get(after)) {
+            (Some((from, start)), Some((to, end))) => {
+                let t = (elapsed - from).as_secs_f32() / (*to - from).as_secs_f32();
+                start + (end - start) * t
+            },
+            // Exactly on the last keyframe it still holds its strength
+            (Some((time, strength)), None) if time == elapsed => strength,
+            _ => 0.0,
+        };
+        strength.clamp(0.0, 1.0)
+    }
+}
+
+impl HapticEffect {
+    /// Both motors at `strength` for `duration`
+    pub fn rumble(strength: f32, duration: Duration) -> Self {
+        HapticEffect { low: Envelope::constant(strength, duration), high: Envelope::constant(strength, duration) }
+    }
+
+    pub fn with_low(mut self, low: Envelope) -> Self {
+        self.low = low;
+        self
+    }
+
+    pub fn with_high(mut self, high: Envelope) -> Self {
+        self.high = high;
+        self
+    }
+
+    /// How long until both motors are done
+    pub fn duration(&self) -> Duration {
+        self.low.duration().max(self.high.duration())
+    }
+}
+
+impl HapticTarget {
+    pub fn includes(&self, device: DeviceId) -> bool {
+        match self {
+            HapticTarget::All => true,
+            HapticTarget::Device(target) => *target == device,
+        }
+    }
+}
+
+impl Haptics {
+    pub fn with_backend(mut self, backend: impl HapticsBackend) -> Self {
+        self.set_backend(Box::new(backend));
+        self
+    }
+
+    /// Replaces the backend, stopping the motors of the old one
+    pub fn set_backend(&mut self, backend: Box<dyn HapticsBackend>) {
+        if let Some(mut old) = self.backend.take() {
+            for device in std::mem::take(&mut self.motors).into_keys() {
+                old.set_motors(device, 0.0, 0.0);
+            }
+        }
+        self.backend = Some(backend);
+    }
+
+    pub fn play(&mut self, target: HapticTarget, effect: HapticEffect) {
+        self.playing.push(Playing { target, effect, elapsed: Duration::ZERO });
+    }
+
+    /// Stops the effects started for `target`, or every effect for `All`
+    pub fn stop(&mut self, target: HapticTarget) {
+        self.playing.retain(|playing| match (target, playing.target) {
+            (HapticTarget::All, _) => false,
+            (HapticTarget::Device(device), playing) => playing != HapticTarget::Device(device),
+        });
+    }
+
+    /// Effects still playing
+    pub fn playing(&self) -> usize {
+        self.playing.len()
+    }
+
+    /// Advances the effects by `delta` and sends the mixed motor strengths, scaled by `strength`, to the backend
+    pub fn update(&mut self, delta: Duration, strength: f32) {
+        for playing in &mut self.playing {
+            playing.elapsed += delta;
+        }
+        self.playing.retain(|playing| playing.elapsed <= playing.effect.duration());
+
+        let Some(backend) = self.backend.as_mut() else { return };
+        let strength = strength.clamp(0.0, 1.0);
+        for device in backend.devices() {
+            let mut motors = [0.0f32; 2];
+            for playing in self.playing.iter().filter(|playing| playing.target.includes(device)) {
+                motors[0] = motors[0].max(playing.effect.low.strength(playing.elapsed));
+                motors[1] = motors[1].max(playing.effect.high.strength(playing.elapsed));
+            }
+            let motors = motors.map(|motor| motor * strength);
+            // Devices are only written to when their motors change
+            if self.motors.get(&device).copied().unwrap_or_default() != motors {
+                backend.set_motors(device, motors[0], motors[1]);
+                self.motors.insert(device, motors);
+            }
+        }
+    }
+}
+
+/// Starts and stops effects from this frame's `PlayHaptic` and `StopHaptics` events and drives the motors, muted
+/// while `haptics.enabled` is off
+pub fn update_haptics(world: &World) {
+    let stops = event::drain_events::<StopHaptics>(world);
+    let plays = event::drain_events::<PlayHaptic>(world);
+    let delta = world.with_resource::<Time, _>(Time::real_delta).unwrap_or_default();
+    let settings: HapticsConfig = config::get().section();
+    world.with_resource_mut::<Haptics, _>(|haptics| {
+        for stop in stops {
+            haptics.stop(stop.target);
+        }
+        for play in plays {
+            haptics.play(play.target, play.effect);
+        }
+        haptics.update(delta, if settings.enabled { settings.strength } else { 0.0 });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    type Writes = Arc<Mutex<Vec<(DeviceId, f32, f32)>>>;
+
+    struct Recorder(Writes);
+
+    impl HapticsBackend for Recorder {
+        fn devices(&self) -> Vec<DeviceId> {
+            vec![DeviceId(0), DeviceId(1)]
+        }
+
+        fn set_motors(&mut self, device: DeviceId, low: f32, high: f32) {
+            self.0.lock().unwrap().push((device, low, high));
+        }
+    }
+
+    #[test]
+    fn effects_mix_per_device_and_finish() {
+        let ms = Duration::from_millis;
+        let pulse = Envelope::pulse(1.0, ms(100), ms(100), ms(200));
+        assert_eq!(pulse.duration(), ms(400));
+        assert_eq!(pulse.strength(ms(50)), 0.5);
+        assert_eq!(pulse.strength(ms(150)), 1.0);
+        assert_eq!(pulse.strength(ms(300)), 0.5);
+        assert_eq!(pulse.strength(ms(500)), 0.0);
+
+        let writes = Writes::default();
+        let mut haptics = Haptics::default().with_backend(Recorder(writes.clone()));
+        haptics.play(HapticTarget::All, HapticEffect::rumble(0.25, ms(200)));
+        haptics.play(HapticTarget::Device(DeviceId(1)), HapticEffect::default().with_high(Envelope::constant(1.0, ms(100))));
+        haptics.update(ms(50), 0.5);
+        assert_eq!(*writes.lock().unwrap(), vec![(DeviceId(0), 0.125, 0.125), (DeviceId(1), 0.125, 0.5)]);
+
+        // Nothing changed on device 0, the strong effect on device 1 ran out
+        writes.lock().unwrap().clear();
+        haptics.update(ms(100), 0.5);
+        assert_eq!(*writes.lock().unwrap(), vec![(DeviceId(1), 0.125, 0.125)]);
+
+        haptics.stop(HapticTarget::Device(DeviceId(0)));
+        assert_eq!(haptics.playing(), 1);
+        haptics.update(ms(100), 0.5);
+        assert_eq!(haptics.playing(), 0);
+        assert_eq!(writes.lock().unwrap().last(), Some(&(DeviceId(1), 0.0, 0.0)));
+    }
+}
@@ -0,0 +1,334 @@
+//!
+//! Input contexts
+//!
+//! Whatever wants input, gameplay, the UI, a console, holds a context on the `InputContexts` stack. Contexts higher on
+//! the stack can capture the pointer or the keyboard, and while one does the contexts below it don't receive that
+//! device. Gameplay systems check `receives_pointer` and `receives_keyboard` for `GAMEPLAY` before acting on input, so
+//! a click on a menu or typing into a console never leaks into the game underneath
+//!
+//! Each context has an `ActionMap` of the actions it handles and their default keys, so the same key can mean one
+//! thing in gameplay and another in the UI or console. A key triggers the action of the topmost context that binds it
+//! and receives the keyboard. Players rebind actions per context through `rebind`, which writes the `input` config
+//! section and saves it to the config file so bindings persist between runs
+//!
+
+use std::collections::BTreeMap;
+
+use serde::{Serialize, Deserialize};
+use winit::event::VirtualKeyCode;
+
+use crate::config::{self, ConfigError, ConfigSection};
+use crate::system::world::World;
+
+pub mod gesture;
+pub mod haptics;
+
+/// The context gameplay input is read in, at the bottom of the stack
+pub const GAMEPLAY: &str = "gameplay";
+
+/// The context of the UI, pushed above gameplay while the pointer is over it or it has keyboard focus
+pub const UI: &str = "ui";
+
+/// The context of the debug console, which captures the keyboard while open
+pub const CONSOLE: &str = "console";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputContext {
+    pub name: String,
+    /// Contexts below this one don't receive pointer input
+    pub captures_pointer: bool,
+    /// Contexts below this one don't receive keyboard input
+    pub captures_keyboard: bool,
+    pub actions: ActionMap,
+}
+
+/// The actions a context handles and the keys bound to them by default
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ActionMap {
+    defaults: BTreeMap<String, Vec<VirtualKeyCode>>,
+}
+
+/// Player bindings from the `input` config section
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct InputConfig {
+    /// Keys bound to each context's actions by winit key name, e.g. `"F10"`, replacing the action's default keys
+    pub bindings: BTreeMap<String, BTreeMap<String, Vec<String>>>,
+}
+
+/// World resource of the input contexts, the last one being on top
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputContexts {
+    stack: Vec<InputContext>,
+}
+
+// Impls
+
+impl InputContext {
+    /// A context that doesn't capture either device
+    pub fn new(name: &str) -> Self {
+        InputContext { name: String::from(name), captures_pointer: false, captures_keyboard: false, actions: ActionMap::default() }
+    }
+
+    pub fn with_captures_pointer(mut self, captures: bool) -> Self {
+        self.captures_pointer = captures;
+        self
+    }
+
+    pub fn with_captures_keyboard(mut self, captures: bool) -> Self {
+        self.captures_keyboard = captures;
+        self
+    }
+
+    pub fn with_actions(mut self, actions: ActionMap) -> Self {
+        self.actions = actions;
+        self
+    }
+
+    /// Whether `key` triggers `action` in this context, going by its defaults unless `config` rebinds it
+    pub fn is_bound(&self, config: &InputConfig, action: &str, key: VirtualKeyCode) -> bool {
+        match config.binding(&self.name, action) {
+            Some(keys) => keys.iter().any(|name| *name == key_name(key)),
+            None => self.actions.defaults(action).is_some_and(|keys| keys.contains(&key)),
+        }
+    }
+
+    /// The first of this context's actions, by name, that `key` triggers
+    pub fn action(&self, config: &InputConfig, key: VirtualKeyCode) -> Option<&str> {
+        self.actions.actions().find(|action| self.is_bound(config, action, key))
+    }
+}
+
+impl ActionMap {
+    pub fn with_action(mut self, action: &str, keys: &[VirtualKeyCode]) -> Self {
+        self.add(action, keys);
+        self
+    }
+
+    /// Adds `action` bound to `keys` by default, replacing its defaults if it was already in the map
+    pub fn add(&mut self, action: &str, keys: &[VirtualKeyCode]) -> &mut Self {
+        self.defaults.insert(String::from(action), keys.to_vec());
+        self
+    }
+
+    pub fn defaults(&self, action: &str) -> Option<&[VirtualKeyCode]> {
+        self.defaults.get(action).map(Vec::as_slice)
+    }
+
+    /// The actions in the map by name
+    pub fn actions(&self) -> impl Iterator<Item = &str> {
+        self.defaults.keys().map(String::as_str)
+    }
+}
+
+impl ConfigSection for InputConfig {
+    const NAME: &'static str = "input";
+}
+
+impl InputConfig {
+    /// The keys bound to `action` in `context`, `None` if it keeps its defaults
+    pub fn binding(&self, context: &str, action: &str) -> Option<&[String]> {
+        self.bindings.get(context)?.get(action).map(Vec::as_slice)
+    }
+
+    /// Binds `keys` to `action` in `context` in place of its defaults. An empty list leaves the action unbound
+    pub fn bind(&mut self, context: &str, action: &str, keys: &[VirtualKeyCode]) {
+        let keys = keys.iter().copied().map(key_name).collect();
+        self.bindings.entry(String::from(context)).or_default().insert(String::from(action), keys);
+    }
+
+    /// Goes back to the default keys of `action` in `context`
+    pub fn unbind(&mut self, context: &str, action: &str) {
+        if let Some(actions) = self.bindings.get_mut(context) {
+            actions.remove(action);
+            if actions.is_empty() {
+                self.bindings.remove(context);
+            }
+        }
+    }
+
+    /// Goes back to the default keys of every action in `context`
+    pub fn unbind_context(&mut self, context: &str) {
+        self.bindings.remove(context);
+    }
+}
+
+impl Default for InputContexts {
+    fn default() -> Self {
+        InputContexts { stack: vec![InputContext::new(GAMEPLAY)] }
+    }
+}
+
+impl InputContexts {
+    /// Puts a context on top, moving it there if it was already on the stack
+    pub fn push(&mut self, context: InputContext) {
+        self.remove(&context.name);
+        self.stack.push(context);
+    }
+
+    /// Takes a context off the stack wherever it is, returning whether it was on it
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.stack.len();
+        self.stack.retain(|context| context.name != name);
+        self.stack.len() != before
+    }
+
+    pub fn get(&self, name: &str) -> Option<&InputContext> {
+        self.stack.iter().find(|context| context.name == name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut InputContext> {
+        self.stack.iter_mut().find(|context| context.name == name)
+    }
+
+    pub fn top(&self) -> Option<&InputContext> {
+        self.stack.last()
+    }
+
+    /// Whether `name` is on the stack and no context above it captures the pointer
+    pub fn receives_pointer(&self, name: &str) -> bool {
+        self.receives(name, |context| context.captures_pointer)
+    }
+
+    /// Whether `name` is on the stack and no context above it captures the keyboard
+    pub fn receives_keyboard(&self, name: &str) -> bool {
+        self.receives(name, |context| context.captures_keyboard)
+    }
+
+    /// The context and action `key` triggers: the first action bound to it from the top of the stack down, stopping
+    /// below the first context that captures the keyboard
+    pub fn action(&self, config: &InputConfig, key: VirtualKeyCode) -> Option<(&str, &str)> {
+        for context in self.stack.iter().rev() {
+            if let Some(action) = context.action(config, key) {
+                return Some((&context.name, action))
+            }
+            if context.captures_keyboard {
+                break
+            }
+        }
+        None
+    }
+
+    /// Whether `key` triggers `action` of `context`, rather than another context's action or nothing
+    pub fn triggers(&self, config: &InputConfig, context: &str, action: &str, key: VirtualKeyCode) -> bool {
+        self.action(config, key) == Some((context, action))
+    }
+
+    fn receives(&self, name: &str, captures: impl Fn(&InputContext) -> bool) -> bool {
+        match self.stack.iter().position(|context| context.name == name) {
+            Some(index) => !self.stack[index + 1..].iter().any(captures),
+            None => false,
+        }
+    }
+}
+
+/// Runs `f` on the world's `InputContexts`, adding them if it has none
+pub fn with_input_contexts<R>(world: &World, f: impl FnOnce(&mut InputContexts) -> R) -> R {
+    if !world.contains_resource::<InputContexts>() {
+        world.insert_resource(InputContexts::default());
+    }
+    world.with_resource_mut::<InputContexts, _>(f).expect("no input contexts")
+}
+
+/// Whether the context `name` receives pointer input, true for gameplay when nothing has been pushed
+pub fn receives_pointer(world: &World, name: &str) -> bool {
+    world.with_resource::<InputContexts, _>(|contexts| contexts.receives_pointer(name)).unwrap_or(name == GAMEPLAY)
+}
+
+/// Whether the context `name` receives keyboard input, true for gameplay when nothing has been pushed
+pub fn receives_keyboard(world: &World, name: &str) -> bool {
+    world.with_resource::<InputContexts, _>(|contexts| contexts.receives_keyboard(name)).unwrap_or(name == GAMEPLAY)
+}
+
+/// The context and action `key` triggers with the player's bindings, see `InputContexts::action`
+pub fn action_for_key(world: &World, key: VirtualKeyCode) -> Option<(String, String)> {
+    let config = bindings();
+    world.with_resource::<InputContexts, _>(|contexts| {
+        contexts.action(&config, key).map(|(context, action)| (String::from(context), String::from(action)))
+    }).flatten()
+}
+
+/// Whether `key` triggers `action` of `context` with the player's bindings
+pub fn triggers(world: &World, context: &str, action: &str, key: VirtualKeyCode) -> bool {
+    let config = bindings();
+    world.with_resource::<InputContexts, _>(|contexts| contexts.triggers(&config, context, action, key)).unwrap_or(false)
+}
+
+/// The player's current bindings
+pub fn bindings() -> InputConfig {
+    config::get().section()
+}
+
+/// Binds `keys` to `action` in `context` and saves the bindings, see `update`
+pub fn rebind(context: &str, action: &str, keys: &[VirtualKeyCode]) -> Result<InputConfig, ConfigError> {
+    update(|config| config.bind(context, action, keys))
+}
+
+/// Puts `action` in `context` back on its default keys and saves the bindings, see `update`
+pub fn reset_binding(context: &str, action: &str) -> Result<InputConfig, ConfigError> {
+    update(|config| config.unbind(context, action))
+}
+
+/// Changes the bindings with `f` and saves them to the config file. Without a config file the change still applies
+/// for this run
+pub fn update(f: impl FnOnce(&mut InputConfig)) -> Result<InputConfig, ConfigError> {
+    let mut bindings = bindings();
+    f(&mut bindings);
+    config::get().set(InputConfig::NAME, &bindings)?;
+    match config::get().save() {
+        Ok(()) | Err(ConfigError::NoFile) => Ok(bindings),
+        Err(err) => Err(err),
+    }
+}
+
+/// Keys are stored by their winit name
+fn key_name(key: VirtualKeyCode) -> String {
+    format!("{:?}", key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capturing_contexts_hide_input_below() {
+        let mut contexts = InputContexts::default();
+        assert!(contexts.receives_pointer(GAMEPLAY) && contexts.receives_keyboard(GAMEPLAY));
+        contexts.push(InputContext::new(UI).with_captures_pointer(true));
+        contexts.push(InputContext::new("console").with_captures_keyboard(true));
+        assert!(!contexts.receives_pointer(GAMEPLAY) && !contexts.receives_keyboard(GAMEPLAY));
+        assert!(contexts.receives_pointer(UI) && !contexts.receives_keyboard(UI));
+        assert!(contexts.receives_pointer("console") && contexts.receives_keyboard("console"));
+        assert!(!contexts.receives_pointer("menu"));
+
+        assert!(contexts.remove("console"));
+        contexts.get_mut(UI).unwrap().captures_pointer = false;
+        assert!(contexts.receives_pointer(GAMEPLAY) && contexts.receives_keyboard(GAMEPLAY));
+        contexts.push(InputContext::new(GAMEPLAY));
+        assert_eq!(contexts.top().map(|context| context.name.as_str()), Some(GAMEPLAY));
+    }
+
+    #[test]
+    fn keys_trigger_the_topmost_contexts_action() {
+        let mut contexts = InputContexts::default();
+        contexts.push(InputContext::new(GAMEPLAY).with_actions(ActionMap::default().with_action("pause", &[VirtualKeyCode::Escape]).with_action("jump", &[VirtualKeyCode::Space])));
+        contexts.push(InputContext::new(UI).with_actions(ActionMap::default().with_action("back", &[VirtualKeyCode::Escape])));
+        let mut config = InputConfig::default();
+        assert_eq!(contexts.action(&config, VirtualKeyCode::Escape), Some((UI, "back")));
+        assert_eq!(contexts.action(&config, VirtualKeyCode::Space), Some((GAMEPLAY, "jump")));
+        assert!(!contexts.triggers(&config, GAMEPLAY, "pause", VirtualKeyCode::Escape));
+
+        // A console capturing the keyboard hides everything below it
+        contexts.push(InputContext::new(CONSOLE).with_captures_keyboard(true));
+        assert_eq!(contexts.action(&config, VirtualKeyCode::Space), None);
+        contexts.remove(CONSOLE);
+
+        config.bind(UI, "back", &[VirtualKeyCode::Back]);
+        assert!(contexts.triggers(&config, GAMEPLAY, "pause", VirtualKeyCode::Escape));
+        assert!(contexts.triggers(&config, UI, "back", VirtualKeyCode::Back));
+        let saved: InputConfig = serde_json::from_value(serde_json::to_value(&config).unwrap()).unwrap();
+        assert_eq!(saved, config);
+        config.unbind(UI, "back");
+        assert!(config.bindings.is_empty());
+    }
+}
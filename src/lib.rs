@@ -1,9 +1,21 @@
 #![feature(option_result_contains)]
 
 pub mod debug;
+pub mod config;
 pub mod app;
 pub mod graphics;
 pub mod unique;
+pub mod random;
 pub mod streaming;
+pub mod vfs;
 pub mod extent;
-pub mod system;
\ No newline at end of file
+pub mod spatial;
+pub mod input;
+pub mod system;
+pub mod asset;
+pub mod memory;
+pub mod audio;
+pub mod localization;
+pub mod accessibility;
+#[cfg(feature = "scripting")]
+pub mod script;
\ No newline at end of file
@@ -0,0 +1,559 @@
+//!
+//! Localization
+//!
+//! Text shown to players is looked up by key in language packs, loaded through the asset manager from
+//! `localization.directory` as `<language>.ftl` or `<language>.json`. FTL packs use a subset of Fluent: `key = value`
+//! messages continued on indented lines, `.attribute`s, `-term`s and `{ $variable }`, `{ -term }` and `{ message }`
+//! placeables. JSON packs are an object of keys to values with the same placeables, nested objects joining their keys
+//! with dots
+//!
+//! A message missing from the current language is looked up along a fallback chain, the language's parents first so
+//! `fr-CA` falls back to `fr`, then the configured fallbacks. The language can be switched at runtime with
+//! `set_language`, and with `localization.hot_reload` packs are reloaded as translators save them. Both send a
+//! `LanguageChanged` event for text to be looked up again
+//!
+
+use std::{collections::HashMap, fmt::{Display, Write}, path::{Path, PathBuf}, sync::Arc, time::{Duration, Instant}};
+
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::asset::{self, AssetError, AssetLoader, AssetManager};
+use crate::config::ConfigSection;
+use crate::debug::log;
+use crate::random;
+use crate::system::{world::World, event};
+
+/// File extensions tried for a language's pack, in order
+const PACK_EXTENSIONS: [&str; 2] = ["ftl", "json"];
+
+/// Messages referring to messages this deep are assumed to be cyclic
+const MAX_REFERENCE_DEPTH: usize = 8;
+
+/// Localization options from the `localization` config section
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct LocalizationConfig {
+    pub language: String,
+    /// Languages tried in order when the current one is missing a message
+    pub fallback: Vec<String>,
+    /// Directory of the packs, relative to the asset root
+    pub directory: PathBuf,
+    /// Reloads packs whose files changed
+    pub hot_reload: bool,
+    /// How often pack files are checked for changes
+    pub poll_interval_ms: u64,
+}
+
+/// The messages of one language
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LanguagePack {
+    pub language: String,
+    /// Keyed by message id, `message.attribute` for attributes and `-term` for terms
+    messages: HashMap<String, Vec<Segment>>,
+}
+
+/// Sent when the language changes or one of its packs is reloaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LanguageChanged {
+    pub language: String,
+}
+
+/// World resource of the current language and its loaded packs
+#[derive(Debug)]
+pub struct Localization {
+    config: LocalizationConfig,
+    language: String,
+    /// The language followed by every language tried when it's missing a message
+    chain: Vec<String>,
+    packs: HashMap<String, LoadedPack>,
+    last_poll: Option<Instant>,
+}
+
+/// Loads `.ftl` and `.json` language packs
+pub struct LanguagePackLoader;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Variable(String),
+    /// Another message or term
+    Reference(String),
+}
+
+#[derive(Debug, Clone)]
+struct LoadedPack {
+    /// `None` for packs that weren't loaded from a file
+    path: Option<PathBuf>,
+    /// Hash of the file's contents, to notice when it changes
+    hash: u64,
+    pack: Arc<LanguagePack>,
+}
+
+// Impls
+
+impl Default for LocalizationConfig {
+    fn default() -> Self {
+        LocalizationConfig {
+            language: String::from("en"),
+            fallback: vec![String::from("en")],
+            directory: PathBuf::from("locales"),
+            hot_reload: cfg!(debug_assertions),
+            poll_interval_ms: 1000,
+        }
+    }
+}
+
+impl ConfigSection for LocalizationConfig {
+    const NAME: &'static str = "localization";
+}
+
+impl LanguagePack {
+    pub fn contains(&self, key: &str) -> bool {
+        self.messages.contains_key(key)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.messages.keys().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+impl AssetLoader for LanguagePackLoader {
+    type Asset = LanguagePack;
+
+    fn extensions(&self) -> &[&'static str] {
+        &PACK_EXTENSIONS
+    }
+
+    fn load(&self, bytes: &[u8], path: &Path) -> Result<Self::Asset, AssetError> {
+        let text = std::str::from_utf8(bytes).map_err(|err| AssetError::Parse(path.to_path_buf(), err.to_string()))?;
+        let messages = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => parse_json(text),
+            _ => parse_ftl(text),
+        }.map_err(|err| AssetError::Parse(path.to_path_buf(), err))?;
+        let language = path.file_stem().map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+        Ok(LanguagePack { language, messages })
+    }
+}
+
+impl Localization {
+    pub fn new(config: LocalizationConfig) -> Self {
+        let language = config.language.clone();
+        let chain = fallback_chain(&language, &config.fallback);
+        Localization { config, language, chain, packs: HashMap::new(), last_poll: None }
+    }
+
+    pub fn config(&self) -> &LocalizationConfig {
+        &self.config
+    }
+
+    pub fn language(&self) -> &str {
+        &self.language
+    }
+
+    /// The current language followed by its fallbacks, in the order messages are looked up
+    pub fn chain(&self) -> &[String] {
+        &self.chain
+    }
+
+    pub fn is_loaded(&self, language: &str) -> bool {
+        self.packs.contains_key(language)
+    }
+
+    /// Adds a pack that wasn't loaded from a file, replacing any pack of its language
+    pub fn insert_pack(&mut self, pack: LanguagePack) {
+        self.packs.insert(pack.language.clone(), LoadedPack { path: None, hash: 0, pack: Arc::new(pack) });
+    }
+
+    /// The message `key` with `args` filled in, from the first language in the chain that has it. Missing variables are
+    /// left as `{$name}`
+    pub fn lookup(&self, key: &str, args: &[(&str, &dyn Display)]) -> Option<String> {
+        let mut text = String::new();
+        self.resolve(key, args, 0, &mut text).then_some(text)
+    }
+
+    /// Like `lookup`, but falls back to the key itself so missing messages still show up
+    pub fn format(&self, key: &str, args: &[(&str, &dyn Display)]) -> String {
+        self.lookup(key, args).unwrap_or_else(|| String::from(key))
+    }
+
+    /// Keys the fallbacks have that the first language of the chain with a pack is missing, for translators
+    pub fn untranslated(&self) -> Vec<String> {
+        let mut packs = self.chain.iter().filter_map(|language| self.packs.get(language));
+        let Some(primary) = packs.next() else {
+            return Vec::new()
+        };
+        let mut missing: Vec<String> = packs
+            .flat_map(|loaded| loaded.pack.keys())
+            .filter(|key| !primary.pack.contains(key))
+            .map(String::from)
+            .collect();
+        missing.sort();
+        missing.dedup();
+        missing
+    }
+
+    fn set_language(&mut self, language: &str) {
+        self.language = String::from(language);
+        self.chain = fallback_chain(language, &self.config.fallback);
+    }
+
+    fn find(&self, key: &str) -> Option<&[Segment]> {
+        self.chain.iter().filter_map(|language| self.packs.get(language)).find_map(|loaded| loaded.pack.messages.get(key)).map(Vec::as_slice)
+    }
+
+    /// Appends the message to `text`, returning false without appending anything if no pack has it
+    fn resolve(&self, key: &str, args: &[(&str, &dyn Display)], depth: usize, text: &mut String) -> bool {
+        let Some(pattern) = self.find(key) else {
+            return false
+        };
+        for segment in pattern {
+            match segment {
+                Segment::Text(literal) => text.push_str(literal),
+                Segment::Variable(name) => match args.iter().find(|(arg, _)| *arg == name.as_str()) {
+                    Some((_, value)) => { let _ = write!(text, "{}", value); },
+                    None => { let _ = write!(text, "{{${}}}", name); },
+                },
+                Segment::Reference(id) => {
+                    if depth >= MAX_REFERENCE_DEPTH || !self.resolve(id, args, depth + 1, text) {
+                        let _ = write!(text, "{{{}}}", id);
+                    }
+                },
+            }
+        }
+        true
+    }
+
+    /// Whether it's time to check the pack files for changes again
+    fn poll_due(&mut self, now: Instant) -> bool {
+        if !self.config.hot_reload {
+            return false
+        }
+        let interval = Duration::from_millis(self.config.poll_interval_ms);
+        if self.last_poll.is_some_and(|last| now.duration_since(last) < interval) {
+            return false
+        }
+        self.last_poll = Some(now);
+        true
+    }
+}
+
+/// Registers the pack loader and loads the packs of the configured language and its fallbacks
+pub fn init_localization(world: &World, config: LocalizationConfig) {
+    world.with_resource_mut::<AssetManager, _>(|manager| manager.add_loader(LanguagePackLoader));
+    let language = config.language.clone();
+    world.insert_resource(Localization::new(config));
+    load_packs(world);
+
+    let (loaded, any) = world.with_resource::<Localization, _>(|localization| (localization.is_loaded(&language), !localization.packs.is_empty())).unwrap_or_default();
+    if any && !loaded {
+        log::get().with_topic("localization").warn(format!("no language pack for {}, using its fallbacks", language));
+    }
+}
+
+/// Switches to `language`, loading any of its packs that aren't loaded yet
+pub fn set_language(world: &World, language: &str) {
+    if world.with_resource_mut::<Localization, _>(|localization| localization.set_language(language)).is_none() {
+        return
+    }
+    load_packs(world);
+    if !world.with_resource::<Localization, _>(|localization| localization.is_loaded(language)).unwrap_or(false) {
+        log::get().with_topic("localization").warn(format!("no language pack for {}, using its fallbacks", language));
+    }
+    log::get().with_topic("localization").info(format!("language set to {}", language));
+    event::send_event(world, LanguageChanged { language: String::from(language) });
+}
+
+/// The message `key` in the current language, or the key itself if it's missing
+pub fn text(world: &World, key: &str) -> String {
+    format(world, key, &[])
+}
+
+/// The message `key` in the current language with `args` filled in, or the key itself if it's missing
+pub fn format(world: &World, key: &str, args: &[(&str, &dyn Display)]) -> String {
+    world.with_resource::<Localization, _>(|localization| localization.format(key, args)).unwrap_or_else(|| String::from(key))
+}
+
+/// Reloads packs whose files changed since they were loaded, when hot reloading is on. Run once a frame
+pub fn reload_language_packs(world: &World) {
+    let Some(loaded) = world.with_resource_mut::<Localization, _>(|localization| {
+        if !localization.poll_due(Instant::now()) {
+            return Vec::new()
+        }
+        localization.packs.iter()
+            .filter_map(|(language, loaded)| Some((language.clone(), loaded.path.clone()?, loaded.hash)))
+            .collect::<Vec<_>>()
+    }) else {
+        return
+    };
+
+    let mut reloaded = false;
+    for (language, path, hash) in loaded {
+        let Some(Ok(bytes)) = world.with_resource::<AssetManager, _>(|manager| manager.read_bytes(&path)) else {
+            continue
+        };
+        let changed = random::stable_hash(&bytes);
+        if changed == hash {
+            continue
+        }
+
+        // The new hash is kept even if the pack doesn't parse, so a broken file is reported once rather than every poll
+        let pack = store_pack(world, &path, &bytes);
+        world.with_resource_mut::<Localization, _>(|localization| {
+            let Some(loaded) = localization.packs.get_mut(&language) else {
+                return
+            };
+            loaded.hash = changed;
+            match pack {
+                Ok(pack) => {
+                    loaded.pack = pack;
+                    reloaded = true;
+                    log::get().with_topic("localization").info(format!("reloaded language pack {}", path.display()));
+                },
+                Err(error) => log::get().with_topic("localization").warn(format!("keeping the previous {} pack: {}", language, error)),
+            }
+        });
+    }
+
+    if reloaded {
+        let language = world.with_resource::<Localization, _>(|localization| localization.language.clone()).unwrap_or_default();
+        event::send_event(world, LanguageChanged { language });
+    }
+}
+
+/// Loads the packs of every language in the chain that isn't loaded yet
+fn load_packs(world: &World) {
+    let Some((directory, missing)) = world.with_resource::<Localization, _>(|localization| {
+        let missing: Vec<String> = localization.chain.iter().filter(|language| !localization.is_loaded(language)).cloned().collect();
+        (localization.config.directory.clone(), missing)
+    }) else {
+        return
+    };
+
+    for language in missing {
+        let Some((path, bytes)) = read_pack(world, &directory, &language) else {
+            continue
+        };
+        match store_pack(world, &path, &bytes) {
+            Ok(pack) => {
+                let loaded = LoadedPack { path: Some(path), hash: random::stable_hash(&bytes), pack };
+                world.with_resource_mut::<Localization, _>(|localization| localization.packs.insert(language, loaded));
+            },
+            Err(error) => log::get().with_topic("localization").warn(format!("unable to load the {} language pack: {}", language, error)),
+        }
+    }
+}
+
+/// The path and contents of the first pack file found for `language`
+fn read_pack(world: &World, directory: &Path, language: &str) -> Option<(PathBuf, Vec<u8>)> {
+    world.with_resource::<AssetManager, _>(|manager| {
+        PACK_EXTENSIONS.iter()
+            .map(|extension| directory.join(format!("{}.{}", language, extension)))
+            .find_map(|path| manager.read_bytes(&path).ok().map(|bytes| (path, bytes)))
+    }).flatten()
+}
+
+/// Parses a pack through the asset manager, storing it in `Assets<LanguagePack>`
+fn store_pack(world: &World, path: &Path, bytes: &[u8]) -> Result<Arc<LanguagePack>, AssetError> {
+    let id = world.with_resource::<AssetManager, _>(|manager| manager.load_bytes::<LanguagePack>(world, path, bytes))
+        .unwrap_or_else(|| Err(AssetError::NoLoader(path.to_path_buf())))?;
+    asset::get::<LanguagePack>(world, id).ok_or(AssetError::NotLoaded(id.uid()))
+}
+
+/// `language`, its parents and then each fallback and its parents, without repeats
+fn fallback_chain(language: &str, fallback: &[String]) -> Vec<String> {
+    let mut chain: Vec<String> = Vec::new();
+    for mut language in std::iter::once(language).chain(fallback.iter().map(String::as_str)) {
+        loop {
+            if !chain.iter().any(|existing| existing == language) {
+                chain.push(String::from(language));
+            }
+            match language.rsplit_once(['-', '_']) {
+                Some((parent, _)) => language = parent,
+                None => break,
+            }
+        }
+    }
+    chain
+}
+
+fn parse_ftl(text: &str) -> Result<HashMap<String, Vec<Segment>>, String> {
+    let mut messages = HashMap::new();
+    // The entry being read, and the message its attributes belong to
+    let mut entry: Option<(String, String)> = None;
+    let mut message: Option<String> = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue
+        }
+        if !line.starts_with([' ', '\t']) && trimmed.starts_with('#') {
+            insert_entry(&mut messages, entry.take())?;
+            message = None;
+            continue
+        }
+
+        if !line.starts_with([' ', '\t']) {
+            insert_entry(&mut messages, entry.take())?;
+            let (id, value) = split_entry(trimmed).ok_or_else(|| format!("line {}: expected `key = value`", index + 1))?;
+            message = Some(id.clone());
+            entry = Some((id, value));
+        } else if let Some(attribute) = trimmed.strip_prefix('.') {
+            let id = message.as_ref().ok_or_else(|| format!("line {}: attribute outside of a message", index + 1))?;
+            let (name, value) = split_entry(attribute).ok_or_else(|| format!("line {}: expected `.attribute = value`", index + 1))?;
+            insert_entry(&mut messages, entry.take())?;
+            entry = Some((format!("{}.{}", id, name), value));
+        } else {
+            let (_, value) = entry.as_mut().ok_or_else(|| format!("line {}: indented line outside of a message", index + 1))?;
+            if !value.is_empty() {
+                value.push('\n');
+            }
+            value.push_str(trimmed);
+        }
+    }
+    insert_entry(&mut messages, entry)?;
+    Ok(messages)
+}
+
+/// Adds a finished entry, leaving out messages without a value of their own
+fn insert_entry(messages: &mut HashMap<String, Vec<Segment>>, entry: Option<(String, String)>) -> Result<(), String> {
+    if let Some((id, value)) = entry.filter(|(_, value)| !value.is_empty()) {
+        let pattern = parse_pattern(&value).map_err(|err| format!("{}: {}", id, err))?;
+        messages.insert(id, pattern);
+    }
+    Ok(())
+}
+
+fn parse_json(text: &str) -> Result<HashMap<String, Vec<Segment>>, String> {
+    fn flatten(prefix: &str, value: &Value, messages: &mut HashMap<String, Vec<Segment>>) -> Result<(), String> {
+        match value {
+            Value::Object(map) => map.iter().try_for_each(|(key, value)| match prefix.is_empty() {
+                true => flatten(key, value, messages),
+                false => flatten(&format!("{}.{}", prefix, key), value, messages),
+            }),
+            Value::String(text) => {
+                messages.insert(String::from(prefix), parse_pattern(text).map_err(|err| format!("{}: {}", prefix, err))?);
+                Ok(())
+            },
+            _ => Err(format!("{}: expected a string or an object", prefix)),
+        }
+    }
+
+    let value: Value = serde_json::from_str(text).map_err(|err| err.to_string())?;
+    let mut messages = HashMap::new();
+    flatten("", &value, &mut messages)?;
+    Ok(messages)
+}
+
+/// Splits `id = value`, checking the id is a valid identifier
+fn split_entry(line: &str) -> Option<(String, String)> {
+    let (id, value) = line.split_once('=')?;
+    let id = id.trim();
+    is_identifier(id.strip_prefix('-').unwrap_or(id)).then(|| (String::from(id), String::from(value.trim())))
+}
+
+fn is_identifier(id: &str) -> bool {
+    id.starts_with(|c: char| c.is_ascii_alphabetic()) && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn parse_pattern(text: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    let mut rest = text;
+    while let Some(open) = rest.find('{') {
+        if open > 0 {
+            segments.push(Segment::Text(String::from(&rest[..open])));
+        }
+        let inner = rest[open + 1..].trim_start();
+        // String literals may hold braces, so they're read up to their closing quote first
+        let (segment, after) = match inner.strip_prefix('"') {
+            Some(literal) => {
+                let end = literal.find('"').ok_or_else(|| String::from("unclosed string literal"))?;
+                (Segment::Text(String::from(&literal[..end])), &literal[end + 1..])
+            },
+            None => {
+                let end = inner.find('}').ok_or_else(|| String::from("unclosed placeable"))?;
+                let expression = inner[..end].trim();
+                let segment = match expression.strip_prefix('$') {
+                    Some(name) if is_identifier(name) => Segment::Variable(String::from(name)),
+                    None if expression.strip_prefix('-').unwrap_or(expression).split('.').all(is_identifier) => Segment::Reference(String::from(expression)),
+                    _ => return Err(format!("unsupported placeable {{ {} }}", expression)),
+                };
+                (segment, &inner[end..])
+            },
+        };
+        rest = after.trim_start().strip_prefix('}').ok_or_else(|| String::from("unclosed placeable"))?;
+        segments.push(segment);
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Text(String::from(rest)));
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRENCH: &str = "
+# Comments and blank lines are skipped
+-brand = Hadron
+welcome = Bienvenue dans { -brand }, { $name } !
+inventory = Inventaire
+    .title = Votre { inventory }
+credits =
+    Fait avec
+    amour
+";
+
+    #[test]
+    fn messages_fall_back_along_the_chain() {
+        let french = LanguagePackLoader.load(FRENCH.as_bytes(), Path::new("locales/fr.ftl")).unwrap();
+        let english = LanguagePackLoader.load(br#"{ "welcome": "Welcome", "menu": { "quit": "Quit {\"{\"}now{\"}\"}" } }"#, Path::new("locales/en.json")).unwrap();
+        assert_eq!(french.language, "fr");
+        assert!(LanguagePackLoader.load(b"broken = { $ }", Path::new("de.ftl")).is_err());
+
+        let mut localization = Localization::new(LocalizationConfig { language: String::from("fr-CA"), ..LocalizationConfig::default() });
+        assert_eq!(localization.chain(), ["fr-CA", "fr", "en"]);
+        localization.insert_pack(french);
+        localization.insert_pack(english);
+
+        assert_eq!(localization.format("welcome", &[("name", &"Ada")]), "Bienvenue dans Hadron, Ada !");
+        assert_eq!(localization.format("welcome", &[]), "Bienvenue dans Hadron, {$name} !");
+        assert_eq!(localization.format("inventory.title", &[]), "Votre Inventaire");
+        assert_eq!(localization.format("credits", &[]), "Fait avec\namour");
+        assert_eq!(localization.format("menu.quit", &[]), "Quit {now}");
+        assert_eq!(localization.format("missing", &[]), "missing");
+        assert_eq!(localization.untranslated(), vec![String::from("menu.quit")]);
+
+        localization.set_language("en");
+        assert_eq!(localization.format("welcome", &[]), "Welcome");
+        assert_eq!(localization.lookup("inventory", &[]), None);
+    }
+
+    #[test]
+    fn changed_packs_are_reloaded() {
+        let directory = std::env::temp_dir().join(format!("hadron_localization_{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("locales")).unwrap();
+        std::fs::write(directory.join("locales/en.ftl"), "greeting = Hello").unwrap();
+
+        let world = World::new();
+        world.insert_resource(AssetManager::new(&directory));
+        init_localization(&world, LocalizationConfig { hot_reload: true, poll_interval_ms: 0, ..LocalizationConfig::default() });
+        assert_eq!(text(&world, "greeting"), "Hello");
+
+        std::fs::write(directory.join("locales/en.ftl"), "greeting = Howdy").unwrap();
+        reload_language_packs(&world);
+        assert_eq!(text(&world, "greeting"), "Howdy");
+        assert_eq!(event::read_events::<LanguageChanged>(&world), vec![LanguageChanged { language: String::from("en") }]);
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
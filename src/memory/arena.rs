@@ -0,0 +1,229 @@
+//!
+//! Bump arena for data that only lives for one frame
+//!
+//! Allocations return typed handles rather than references, so the arena can grow without invalidating anything
+//! already allocated. Resetting bumps a generation, any handle from an earlier frame is rejected after that. The
+//! backing buffer is kept between frames, so once it has grown to the frame's peak usage it stops touching the heap
+//!
+
+use std::{marker::PhantomData, mem, sync::atomic::{AtomicU64, Ordering}};
+
+use crate::system::world::World;
+
+/// Alignment of the backing buffer, and the largest alignment the arena supports
+const BLOCK_ALIGN: usize = 16;
+
+/// Source of arena ids, so slices can't be used with an arena they weren't allocated from
+static NEXT_ARENA: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy)]
+#[repr(C, align(16))]
+struct Block([u8; BLOCK_ALIGN]);
+
+/// A slice of `T` allocated in a `FrameArena`, valid until the arena is next reset
+#[derive(Debug)]
+pub struct FrameSlice<T> {
+    arena: u64,
+    offset: usize,
+    len: usize,
+    generation: u64,
+    _marker: PhantomData<fn() -> T>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ArenaStats {
+    pub used: usize,
+    pub capacity: usize,
+    /// Largest `used` seen since the arena was created
+    pub high_water: usize,
+    /// Number of times the backing buffer had to grow
+    pub grows: u64,
+}
+
+/// World resource, reset at the start of every frame
+pub struct FrameArena {
+    id: u64,
+    blocks: Vec<Block>,
+    used: usize,
+    generation: u64,
+    high_water: usize,
+    grows: u64,
+}
+
+// Impls
+
+impl<T> Clone for FrameSlice<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for FrameSlice<T> {}
+
+impl<T> FrameSlice<T> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Default for FrameArena {
+    fn default() -> Self {
+        FrameArena::with_capacity(64 * 1024)
+    }
+}
+
+impl std::fmt::Debug for FrameArena {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FrameArena").field("generation", &self.generation).field("stats", &self.stats()).finish()
+    }
+}
+
+impl FrameArena {
+    pub fn with_capacity(bytes: usize) -> Self {
+        FrameArena {
+            id: NEXT_ARENA.fetch_add(1, Ordering::Relaxed),
+            blocks: vec![Block([0; BLOCK_ALIGN]); bytes.div_ceil(BLOCK_ALIGN)],
+            used: 0,
+            generation: 0,
+            high_water: 0,
+            grows: 0,
+        }
+    }
+
+    /// Copies `values` into the arena
+    pub fn alloc_slice<T: Copy>(&mut self, values: &[T]) -> FrameSlice<T> {
+        let slice = self.reserve::<T>(values.len());
+        self.get_mut(&slice).expect("fresh arena slice").copy_from_slice(values);
+        slice
+    }
+
+    pub fn alloc<T: Copy>(&mut self, value: T) -> FrameSlice<T> {
+        self.alloc_slice(std::slice::from_ref(&value))
+    }
+
+    /// Allocates `len` copies of `value`, for buffers that are filled in afterwards through `get_mut`
+    pub fn alloc_filled<T: Copy>(&mut self, value: T, len: usize) -> FrameSlice<T> {
+        let slice = self.reserve::<T>(len);
+        self.get_mut(&slice).expect("fresh arena slice").fill(value);
+        slice
+    }
+
+    /// Returns the allocation, or `None` if it was made before the last reset or by another arena
+    pub fn get<T: Copy>(&self, slice: &FrameSlice<T>) -> Option<&[T]> {
+        if !self.owns(slice) {
+            return None
+        }
+        // Safety: `owns` checked the range was reserved for `T` in this arena this generation, which aligned it and
+        // initialized it on allocation
+        unsafe {
+            let ptr = (self.blocks.as_ptr() as *const u8).add(slice.offset) as *const T;
+            Some(std::slice::from_raw_parts(ptr, slice.len))
+        }
+    }
+
+    pub fn get_mut<T: Copy>(&mut self, slice: &FrameSlice<T>) -> Option<&mut [T]> {
+        if !self.owns(slice) {
+            return None
+        }
+        // Safety: as in `get`, and `&mut self` guarantees the range isn't otherwise borrowed
+        unsafe {
+            let ptr = (self.blocks.as_mut_ptr() as *mut u8).add(slice.offset) as *mut T;
+            Some(std::slice::from_raw_parts_mut(ptr, slice.len))
+        }
+    }
+
+    /// Frees every allocation at once, invalidating outstanding handles
+    pub fn reset(&mut self) {
+        self.used = 0;
+        self.generation += 1;
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn stats(&self) -> ArenaStats {
+        ArenaStats {
+            used: self.used,
+            capacity: self.capacity(),
+            high_water: self.high_water,
+            grows: self.grows,
+        }
+    }
+
+    /// Whether `slice` was allocated by this arena since the last reset and lies within what has been used
+    fn owns<T>(&self, slice: &FrameSlice<T>) -> bool {
+        let end = slice.len.checked_mul(mem::size_of::<T>()).and_then(|size| size.checked_add(slice.offset));
+        slice.arena == self.id && slice.generation == self.generation && end.is_some_and(|end| end <= self.used)
+            && slice.offset.is_multiple_of(mem::align_of::<T>())
+    }
+
+    fn capacity(&self) -> usize {
+        self.blocks.len() * BLOCK_ALIGN
+    }
+
+    fn reserve<T: Copy>(&mut self, len: usize) -> FrameSlice<T> {
+        assert!(mem::align_of::<T>() <= BLOCK_ALIGN, "frame arena doesn't support alignment {}", mem::align_of::<T>());
+
+        let align = mem::align_of::<T>();
+        let offset = (self.used + align - 1) & !(align - 1);
+        let end = offset + mem::size_of::<T>() * len;
+
+        if end > self.capacity() {
+            let blocks = end.max(self.capacity() * 2).div_ceil(BLOCK_ALIGN);
+            self.blocks.resize(blocks, Block([0; BLOCK_ALIGN]));
+            self.grows += 1;
+        }
+
+        self.used = end;
+        self.high_water = self.high_water.max(end);
+        FrameSlice { arena: self.id, offset, len, generation: self.generation, _marker: PhantomData }
+    }
+}
+
+/// Adds the frame arena to a world
+pub(crate) fn init_frame_arena(world: &World) {
+    if !world.contains_resource::<FrameArena>() {
+        world.insert_resource(FrameArena::default());
+    }
+}
+
+/// Resets the world's frame arena, called by the main loop at the start of each frame
+pub(crate) fn reset_frame_arena(world: &World) {
+    world.with_resource_mut::<FrameArena, _>(|arena| arena.reset());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handles_survive_growth_but_not_reset() {
+        let mut arena = FrameArena::with_capacity(16);
+        let bytes = arena.alloc_slice(&[1u8, 2, 3]);
+        let floats = arena.alloc_slice(&[1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let big = arena.alloc_filled(7u64, 100);
+
+        assert!(arena.stats().grows > 0);
+        assert_eq!(arena.get(&bytes), Some(&[1u8, 2, 3][..]));
+        assert_eq!(arena.get(&floats).unwrap()[5], 6.0);
+        assert!(arena.get(&big).unwrap().iter().all(|v| *v == 7));
+
+        let capacity = arena.stats().capacity;
+        arena.reset();
+        assert!(arena.get(&bytes).is_none());
+        assert_eq!(arena.stats().used, 0);
+        assert_eq!(arena.stats().capacity, capacity);
+
+        // Another arena at the same generation doesn't accept the first one's slices
+        let mut other = FrameArena::with_capacity(16);
+        other.reset();
+        let theirs = other.alloc_slice(&[9u8; 16]);
+        assert!(arena.get(&theirs).is_none());
+        assert!(arena.get_mut(&theirs).is_none());
+    }
+}
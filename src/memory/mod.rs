@@ -0,0 +1,7 @@
+//! 
+//! Allocators for engine data with predictable lifetimes
+//! 
+
+pub mod arena;
+pub mod pool;
+pub mod pressure;
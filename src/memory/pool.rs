@@ -0,0 +1,282 @@
+//!
+//! Pools of recycled objects
+//!
+//! Released objects are kept and handed out again by later acquires instead of being dropped and rebuilt, which suits
+//! hot, expensive-to-construct types like streaming units, command recorders and particle batches. Handles are
+//! indexed `UniqueId`s, so lookups are a single array access and a stale handle is never confused with the slot's new
+//! occupant
+//!
+
+use std::marker::PhantomData;
+
+use crate::unique::UniqueId;
+
+/// How a pool grows once every object is in use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrowthPolicy {
+    /// Acquires fail once the initial capacity is reached
+    Fixed,
+    /// Capacity grows by this many objects
+    Linear(usize),
+    /// Capacity doubles
+    Double,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolError {
+    /// The pool is at capacity and its growth policy is `Fixed`
+    Exhausted,
+}
+
+pub struct PoolHandle<T> {
+    id: UniqueId,
+    _marker: PhantomData<fn() -> T>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PoolStats {
+    pub capacity: usize,
+    /// Objects constructed so far, in use or waiting to be recycled
+    pub allocated: usize,
+    pub in_use: usize,
+    pub high_water: usize,
+    pub grows: u64,
+    /// Acquires that failed because the pool was exhausted
+    pub exhausted: u64,
+}
+
+/// Clears an object's state as it's released
+type Reset<T> = Box<dyn Fn(&mut T) + Send + Sync>;
+
+struct Slot<T> {
+    id: Option<UniqueId>,
+    value: T,
+}
+
+pub struct Pool<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<usize>,
+    capacity: usize,
+    policy: GrowthPolicy,
+    factory: Box<dyn Fn() -> T + Send + Sync>,
+    reset: Option<Reset<T>>,
+    high_water: usize,
+    grows: u64,
+    exhausted: u64,
+}
+
+// Impls
+
+impl std::error::Error for PoolError {}
+
+impl std::fmt::Display for PoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PoolError::Exhausted => write!(f, "pool exhausted"),
+        }
+    }
+}
+
+impl<T> Clone for PoolHandle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for PoolHandle<T> {}
+
+impl<T> PartialEq for PoolHandle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<T> Eq for PoolHandle<T> {}
+
+impl<T> std::hash::Hash for PoolHandle<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
+
+impl<T> std::fmt::Debug for PoolHandle<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PoolHandle({})", self.id)
+    }
+}
+
+impl<T> PoolHandle<T> {
+    pub fn id(&self) -> UniqueId {
+        self.id
+    }
+}
+
+impl PoolStats {
+    /// Fraction of the capacity in use
+    pub fn occupancy(&self) -> f64 {
+        if self.capacity == 0 { 0.0 } else { self.in_use as f64 / self.capacity as f64 }
+    }
+}
+
+impl<T> Pool<T> {
+    /// Creates an empty pool, objects are constructed by `factory` the first time they're needed
+    pub fn new<F>(capacity: usize, factory: F) -> Self
+    where
+        F: Fn() -> T + Send + Sync + 'static
+    {
+        Pool {
+            slots: Vec::with_capacity(capacity),
+            free: Vec::new(),
+            capacity,
+            policy: GrowthPolicy::Double,
+            factory: Box::new(factory),
+            reset: None,
+            high_water: 0,
+            grows: 0,
+            exhausted: 0,
+        }
+    }
+
+    pub fn with_policy(mut self, policy: GrowthPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Called on objects as they're released, to clear state before reuse
+    pub fn with_reset<F>(mut self, reset: F) -> Self
+    where
+        F: Fn(&mut T) + Send + Sync + 'static
+    {
+        self.reset = Some(Box::new(reset));
+        self
+    }
+
+    /// Constructs objects up front until `count` are allocated
+    pub fn prefill(&mut self, count: usize) {
+        while self.slots.len() < count.min(self.capacity) {
+            self.free.push(self.slots.len());
+            self.slots.push(Slot { id: None, value: (self.factory)() });
+        }
+    }
+
+    /// Hands out a recycled object if one is free, constructing one otherwise
+    pub fn acquire(&mut self) -> Result<PoolHandle<T>, PoolError> {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                if self.slots.len() >= self.capacity && !self.grow() {
+                    self.exhausted += 1;
+                    return Err(PoolError::Exhausted)
+                }
+                self.slots.push(Slot { id: None, value: (self.factory)() });
+                self.slots.len() - 1
+            },
+        };
+
+        let id = UniqueId::get_with_index(index);
+        self.slots[index].id = Some(id);
+        self.high_water = self.high_water.max(self.in_use());
+        Ok(PoolHandle { id, _marker: PhantomData })
+    }
+
+    /// Returns an object to the pool. Returns false if the handle was already released
+    pub fn release(&mut self, handle: PoolHandle<T>) -> bool {
+        let Some(index) = self.slot_index(handle) else {
+            return false
+        };
+
+        let slot = &mut self.slots[index];
+        slot.id = None;
+        if let Some(reset) = &self.reset {
+            reset(&mut slot.value);
+        }
+        self.free.push(index);
+        true
+    }
+
+    pub fn get(&self, handle: PoolHandle<T>) -> Option<&T> {
+        self.slot_index(handle).map(|index| &self.slots[index].value)
+    }
+
+    pub fn get_mut(&mut self, handle: PoolHandle<T>) -> Option<&mut T> {
+        self.slot_index(handle).map(|index| &mut self.slots[index].value)
+    }
+
+    pub fn contains(&self, handle: PoolHandle<T>) -> bool {
+        self.slot_index(handle).is_some()
+    }
+
+    pub fn in_use(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    /// Objects currently in use
+    pub fn iter(&self) -> impl Iterator<Item = (PoolHandle<T>, &T)> {
+        self.slots.iter().filter_map(|slot| slot.id.map(|id| (PoolHandle { id, _marker: PhantomData }, &slot.value)))
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            capacity: self.capacity,
+            allocated: self.slots.len(),
+            in_use: self.in_use(),
+            high_water: self.high_water,
+            grows: self.grows,
+            exhausted: self.exhausted,
+        }
+    }
+
+    fn slot_index(&self, handle: PoolHandle<T>) -> Option<usize> {
+        let index = handle.id.index()?;
+        (self.slots.get(index)?.id == Some(handle.id)).then_some(index)
+    }
+
+    fn grow(&mut self) -> bool {
+        let capacity = match self.policy {
+            GrowthPolicy::Fixed => return false,
+            GrowthPolicy::Linear(step) => self.capacity + step.max(1),
+            GrowthPolicy::Double => (self.capacity * 2).max(1),
+        };
+        self.capacity = capacity;
+        self.grows += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn released_objects_are_recycled() {
+        let mut pool = Pool::new(2, Vec::<u32>::new).with_reset(|v| v.clear()).with_policy(GrowthPolicy::Fixed);
+
+        let a = pool.acquire().unwrap();
+        pool.get_mut(a).unwrap().extend([1, 2, 3]);
+        let _b = pool.acquire().unwrap();
+        assert_eq!(pool.acquire(), Err(PoolError::Exhausted));
+
+        assert!(pool.release(a));
+        assert!(!pool.release(a));
+        assert!(pool.get(a).is_none());
+
+        let c = pool.acquire().unwrap();
+        assert_ne!(a, c);
+        assert!(pool.get(c).unwrap().is_empty());
+        assert!(pool.get(c).unwrap().capacity() >= 3);
+
+        let stats = pool.stats();
+        assert_eq!((stats.allocated, stats.in_use, stats.exhausted), (2, 2, 1));
+        assert_eq!(stats.occupancy(), 1.0);
+    }
+
+    #[test]
+    fn growth_policies() {
+        let mut pool = Pool::new(1, || 0u8).with_policy(GrowthPolicy::Linear(3));
+        for _ in 0..5 {
+            pool.acquire().unwrap();
+        }
+        assert_eq!(pool.stats().capacity, 7);
+        assert_eq!(pool.stats().grows, 2);
+    }
+}
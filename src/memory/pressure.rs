@@ -0,0 +1,195 @@
+//!
+//! Memory pressure
+//!
+//! Once a frame the monitor reads how much of the host and device memory budgets are in use, the host side from the
+//! tracking allocator against `memory_pressure.host_budget` and the device side from the Vulkan memory budget, and sends
+//! a `MemoryPressure` event whenever the higher of the two crosses into a different `PressureLevel`. Levels only drop
+//! again once usage falls a little below the threshold that raised them, so usage hovering around a threshold doesn't
+//! flip quality back and forth every frame
+//!
+//! Systems holding onto memory subscribe to the events and trade quality for memory while the pressure lasts, render
+//! targets shrink, textures drop their largest mips and streamed components get a smaller resident budget
+//!
+
+use serde::{Serialize, Deserialize};
+
+use crate::config::{self, ConfigSection};
+use crate::debug::{self, log};
+use crate::graphics::memory_budget;
+use crate::system::{world::World, event};
+
+/// How close the app is to running out of memory, ordered from least to most
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PressureLevel {
+    #[default]
+    Normal,
+    Elevated,
+    Critical,
+}
+
+/// Sent when the pressure level changes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryPressure {
+    pub level: PressureLevel,
+    pub previous: PressureLevel,
+    /// Fraction of the host budget in use, `None` without a host budget or the tracking allocator
+    pub host: Option<f64>,
+    /// Fraction of the device local budget in use, `None` before the renderer reads it
+    pub device: Option<f64>,
+}
+
+/// Memory pressure options from the `memory_pressure` config section
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct MemoryPressureConfig {
+    pub enabled: bool,
+    /// Bytes of host memory the app should stay under. Host memory isn't monitored without one
+    pub host_budget: Option<u64>,
+    /// Fraction of a budget in use that raises the level to `Elevated`
+    pub elevated: f64,
+    /// Fraction of a budget in use that raises the level to `Critical`
+    pub critical: f64,
+    /// How far below a threshold usage has to fall before its level drops
+    pub hysteresis: f64,
+}
+
+/// World resource tracking the current pressure level
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MemoryPressureMonitor {
+    level: PressureLevel,
+    host: Option<f64>,
+    device: Option<f64>,
+}
+
+// Impls
+
+impl PressureLevel {
+    /// Fraction of their full quality systems keep at this level
+    pub fn quality(&self) -> f64 {
+        match self {
+            PressureLevel::Normal => 1.0,
+            PressureLevel::Elevated => 0.75,
+            PressureLevel::Critical => 0.5,
+        }
+    }
+}
+
+impl Default for MemoryPressureConfig {
+    fn default() -> Self {
+        MemoryPressureConfig { enabled: true, host_budget: None, elevated: 0.8, critical: 0.95, hysteresis: 0.05 }
+    }
+}
+
+impl ConfigSection for MemoryPressureConfig {
+    const NAME: &'static str = "memory_pressure";
+}
+
+impl MemoryPressureMonitor {
+    pub fn level(&self) -> PressureLevel {
+        self.level
+    }
+
+    /// Fraction of the host budget in use as of the last sample
+    pub fn host(&self) -> Option<f64> {
+        self.host
+    }
+
+    /// Fraction of the device local budget in use as of the last sample
+    pub fn device(&self) -> Option<f64> {
+        self.device
+    }
+
+    /// Takes a reading of each budget, returning the change if the level moved
+    pub fn sample(&mut self, host: Option<f64>, device: Option<f64>, config: &MemoryPressureConfig) -> Option<MemoryPressure> {
+        (self.host, self.device) = (host, device);
+        let pressure = host.into_iter().chain(device).fold(0.0, f64::max);
+        let holds = |level: PressureLevel, threshold: f64| pressure >= threshold || (self.level >= level && pressure >= threshold - config.hysteresis);
+        let level = if holds(PressureLevel::Critical, config.critical) {
+            PressureLevel::Critical
+        } else if holds(PressureLevel::Elevated, config.elevated) {
+            PressureLevel::Elevated
+        } else {
+            PressureLevel::Normal
+        };
+        if level == self.level {
+            return None
+        }
+
+        let previous = std::mem::replace(&mut self.level, level);
+        Some(MemoryPressure { level, previous, host, device })
+    }
+}
+
+/// The current pressure level, `Normal` without a monitor
+pub fn pressure_level(world: &World) -> PressureLevel {
+    world.with_resource::<MemoryPressureMonitor, _>(MemoryPressureMonitor::level).unwrap_or_default()
+}
+
+/// The level of the latest pending `MemoryPressure` event, for subscribers to act on
+pub fn latest_pressure(world: &World) -> Option<PressureLevel> {
+    event::read_events::<MemoryPressure>(world).last().map(|pressure| pressure.level)
+}
+
+/// Samples host and device memory use, sending a `MemoryPressure` event when the level changes. Run once a frame
+pub fn monitor_memory_pressure(world: &World) {
+    let config: MemoryPressureConfig = config::get().section();
+    if !config.enabled {
+        return
+    }
+    let host = config.host_budget.filter(|budget| *budget > 0).zip(debug::allocated_bytes()).map(|(budget, allocated)| allocated as f64 / budget as f64);
+    let device = memory_budget::memory_budget().map(|budget| budget.pressure());
+
+    let changed = world.with_resource_mut::<MemoryPressureMonitor, _>(|monitor| monitor.sample(host, device, &config)).flatten();
+    if let Some(pressure) = changed {
+        let message = format!("memory pressure {:?} to {:?}, host {:?}, device {:?}", pressure.previous, pressure.level, pressure.host, pressure.device);
+        match pressure.level > pressure.previous {
+            true => log::get().with_topic("memory").warn(message),
+            false => log::get().with_topic("memory").info(message),
+        }
+        event::send_event(world, pressure);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_rise_and_fall_with_hysteresis() {
+        let config = MemoryPressureConfig::default();
+        let mut monitor = MemoryPressureMonitor::default();
+
+        assert_eq!(monitor.sample(Some(0.5), None, &config), None);
+        let raised = monitor.sample(Some(0.5), Some(0.96), &config).unwrap();
+        assert_eq!((raised.previous, raised.level), (PressureLevel::Normal, PressureLevel::Critical));
+
+        // Just under the threshold holds the level, past the hysteresis it drops
+        assert_eq!(monitor.sample(None, Some(0.92), &config), None);
+        assert_eq!(monitor.sample(None, Some(0.85), &config).map(|pressure| pressure.level), Some(PressureLevel::Elevated));
+        assert_eq!(monitor.sample(None, Some(0.77), &config), None);
+        assert_eq!(monitor.sample(None, Some(0.7), &config).map(|pressure| pressure.level), Some(PressureLevel::Normal));
+    }
+
+    #[test]
+    fn subscribers_trade_quality_for_memory() {
+        use crate::asset::{self, pipeline::formats::{Texture, TextureQuality}};
+        use crate::graphics::backend::{self, RendererConfig};
+        use crate::unique::UniqueId;
+
+        let world = World::new();
+        world.insert_resource(RendererConfig::default());
+        event::send_event(&world, MemoryPressure { level: PressureLevel::Critical, previous: PressureLevel::Normal, host: None, device: Some(0.97) });
+        backend::scale_resolution_for_pressure(&world);
+        asset::drop_mips_for_pressure(&world);
+
+        let configured = config::get().section::<RendererConfig>().resolution_scale;
+        assert_eq!(world.with_resource::<RendererConfig, _>(|renderer| renderer.resolution_scale), Some(configured * 0.5));
+        let dropped_mips = world.with_resource::<TextureQuality, _>(|quality| quality.dropped_mips).unwrap();
+        assert_eq!(dropped_mips, 2);
+
+        let texture = Texture { uid: UniqueId::get(), width: 4, height: 2, pixels: (0..32).collect() };
+        let mip = texture.mip(dropped_mips);
+        assert_eq!((mip.width, mip.height), (1, 1));
+        assert_eq!(texture.mip(1).pixels[..4], [10, 11, 12, 13]);
+    }
+}
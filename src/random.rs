@@ -0,0 +1,290 @@
+//!
+//! Random numbers and noise
+//!
+//! A `Random` world resource makes a simulation deterministic. Systems draw from named streams instead of the thread
+//! rng, each seeded from the world seed, the stream's name and how many times it has been drawn from, so adding
+//! randomness to one system doesn't shift the numbers any other system sees. Without the resource streams are seeded
+//! from entropy. `UniqueId`s are generated globally rather than per world, so they have their own switch in
+//! `seed_unique_ids`
+//!
+//! `Perlin` and `Simplex` are seeded gradient noise for procedural content, with `Fbm` layering octaves of either
+//!
+
+use std::{collections::HashMap, sync::{Mutex, atomic::{AtomicBool, Ordering}}};
+
+use once_cell::sync::Lazy;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use serde::{Serialize, Deserialize};
+
+use crate::system::world::World;
+
+static SEEDED_IDS: AtomicBool = AtomicBool::new(false);
+static UNIQUE_ID_RNG: Lazy<Mutex<Option<StdRng>>> = Lazy::new(|| Mutex::new(None));
+
+/// World resource seeding every named random stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Random {
+    seed: u64,
+    /// Seeds handed out by each stream so far
+    draws: HashMap<String, u64>,
+}
+
+/// Seeded 2D and 3D Perlin noise, roughly in -1 to 1
+#[derive(Clone)]
+pub struct Perlin {
+    permutation: [u8; 512],
+}
+
+/// Seeded 2D simplex noise, roughly in -1 to 1. Cheaper than `Perlin` and without its axis aligned artifacts
+#[derive(Clone)]
+pub struct Simplex {
+    permutation: [u8; 512],
+}
+
+/// Layered octaves of noise, each `lacunarity` times the frequency and `gain` times the amplitude of the last
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Fbm {
+    pub octaves: u32,
+    pub lacunarity: f64,
+    pub gain: f64,
+}
+
+// Impls
+
+impl Random {
+    pub fn seeded(seed: u64) -> Self {
+        Random { seed, draws: HashMap::new() }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The next seed of the stream `name`
+    pub fn next_seed(&mut self, name: &str) -> u64 {
+        let draw = self.draws.entry(String::from(name)).or_default();
+        let seed = mix(mix(self.seed ^ stable_hash(name.as_bytes())) ^ *draw);
+        *draw += 1;
+        seed
+    }
+
+    /// A generator seeded with the next seed of the stream `name`
+    pub fn stream(&mut self, name: &str) -> StdRng {
+        StdRng::seed_from_u64(self.next_seed(name))
+    }
+}
+
+impl std::fmt::Debug for Perlin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Perlin").finish_non_exhaustive()
+    }
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Self {
+        Perlin { permutation: permutation(seed) }
+    }
+
+    pub fn noise2(&self, x: f64, y: f64) -> f64 {
+        let (xi, yi) = (x.floor(), y.floor());
+        let (x, y) = (x - xi, y - yi);
+        let (xi, yi) = (xi as i64 as usize & 255, yi as i64 as usize & 255);
+        let p = &self.permutation;
+        let hash = |dx: usize, dy: usize| p[p[xi + dx] as usize + yi + dy];
+        let (u, v) = (fade(x), fade(y));
+
+        let bottom = lerp(grad2(hash(0, 0), x, y), grad2(hash(1, 0), x - 1.0, y), u);
+        let top = lerp(grad2(hash(0, 1), x, y - 1.0), grad2(hash(1, 1), x - 1.0, y - 1.0), u);
+        lerp(bottom, top, v)
+    }
+
+    pub fn noise3(&self, x: f64, y: f64, z: f64) -> f64 {
+        let (xi, yi, zi) = (x.floor(), y.floor(), z.floor());
+        let (x, y, z) = (x - xi, y - yi, z - zi);
+        let (xi, yi, zi) = (xi as i64 as usize & 255, yi as i64 as usize & 255, zi as i64 as usize & 255);
+        let p = &self.permutation;
+        let hash = |dx: usize, dy: usize, dz: usize| p[p[p[xi + dx] as usize + yi + dy] as usize + zi + dz];
+        let (u, v, w) = (fade(x), fade(y), fade(z));
+
+        let corner = |dx: usize, dy: usize, dz: usize| grad3(hash(dx, dy, dz), x - dx as f64, y - dy as f64, z - dz as f64);
+        let near = lerp(lerp(corner(0, 0, 0), corner(1, 0, 0), u), lerp(corner(0, 1, 0), corner(1, 1, 0), u), v);
+        let far = lerp(lerp(corner(0, 0, 1), corner(1, 0, 1), u), lerp(corner(0, 1, 1), corner(1, 1, 1), u), v);
+        lerp(near, far, w)
+    }
+}
+
+impl std::fmt::Debug for Simplex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Simplex").finish_non_exhaustive()
+    }
+}
+
+impl Simplex {
+    pub fn new(seed: u64) -> Self {
+        Simplex { permutation: permutation(seed) }
+    }
+
+    pub fn noise2(&self, x: f64, y: f64) -> f64 {
+        let skew = 0.5 * (3f64.sqrt() - 1.0);
+        let unskew = (3.0 - 3f64.sqrt()) / 6.0;
+
+        let s = (x + y) * skew;
+        let (i, j) = ((x + s).floor(), (y + s).floor());
+        let t = (i + j) * unskew;
+        let (x0, y0) = (x - (i - t), y - (j - t));
+        let (i1, j1) = if x0 > y0 { (1, 0) } else { (0, 1) };
+        let corners = [
+            (0, 0, x0, y0),
+            (i1, j1, x0 - i1 as f64 + unskew, y0 - j1 as f64 + unskew),
+            (1, 1, x0 - 1.0 + 2.0 * unskew, y0 - 1.0 + 2.0 * unskew),
+        ];
+
+        let (i, j) = (i as i64 as usize & 255, j as i64 as usize & 255);
+        let p = &self.permutation;
+        let total: f64 = corners.iter().map(|&(di, dj, x, y)| {
+            let falloff = 0.5 - x * x - y * y;
+            match falloff > 0.0 {
+                true => falloff.powi(4) * grad2(p[i + di + p[j + dj] as usize], x, y),
+                false => 0.0,
+            }
+        }).sum();
+        total * 70.0
+    }
+}
+
+impl Default for Fbm {
+    fn default() -> Self {
+        Fbm { octaves: 4, lacunarity: 2.0, gain: 0.5 }
+    }
+}
+
+impl Fbm {
+    /// Sums the octaves of `noise` at a point, normalized back to the range of a single octave
+    pub fn sample(&self, x: f64, y: f64, noise: impl Fn(f64, f64) -> f64) -> f64 {
+        let (mut frequency, mut amplitude) = (1.0, 1.0);
+        let (mut total, mut range) = (0.0, 0.0);
+        for _ in 0..self.octaves.max(1) {
+            total += noise(x * frequency, y * frequency) * amplitude;
+            range += amplitude;
+            frequency *= self.lacunarity;
+            amplitude *= self.gain;
+        }
+        total / range
+    }
+}
+
+/// The next seed of the stream `name`, from entropy when the world has no `Random`
+pub fn seed(world: &World, name: &str) -> u64 {
+    world.with_resource_mut::<Random, _>(|random| random.next_seed(name)).unwrap_or_else(rand::random)
+}
+
+/// A generator for the stream `name`, from entropy when the world has no `Random`
+pub fn stream(world: &World, name: &str) -> StdRng {
+    StdRng::seed_from_u64(seed(world, name))
+}
+
+/// Generates every following `UniqueId` from `seed`, or from entropy again when `None`. Ids stay unique within a
+/// process either way, but only match between runs when every id is generated in the same order
+pub fn seed_unique_ids(seed: Option<u64>) {
+    let mut rng = UNIQUE_ID_RNG.lock().expect("unique id rng poisoned");
+    *rng = seed.map(StdRng::seed_from_u64);
+    SEEDED_IDS.store(seed.is_some(), Ordering::Release);
+}
+
+/// A random positive i128, drawn from the seeded unique id stream when there is one
+pub(crate) fn unique_entropy() -> i128 {
+    if SEEDED_IDS.load(Ordering::Acquire) {
+        if let Some(rng) = UNIQUE_ID_RNG.lock().expect("unique id rng poisoned").as_mut() {
+            return rng.gen_range(0..i128::MAX)
+        }
+    }
+    rand::thread_rng().gen_range(0..i128::MAX)
+}
+
+/// The numbers 0 to 255 shuffled by `seed`, repeated so lookups can overflow by a cell without wrapping
+fn permutation(seed: u64) -> [u8; 512] {
+    let mut shuffled: Vec<u8> = (0..=255).collect();
+    let mut rng = StdRng::seed_from_u64(seed);
+    for i in (1..shuffled.len()).rev() {
+        shuffled.swap(i, rng.gen_range(0..=i));
+    }
+    std::array::from_fn(|i| shuffled[i & 255])
+}
+
+/// SplitMix64's finalizer, spreading every input bit over the output
+fn mix(mut value: u64) -> u64 {
+    value = value.wrapping_add(0x9e3779b97f4a7c15);
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d049bb133111eb);
+    value ^ (value >> 31)
+}
+
+/// FNV-1a, stable between runs and builds unlike the std hasher
+pub(crate) fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t
+}
+
+fn grad2(hash: u8, x: f64, y: f64) -> f64 {
+    match hash & 7 {
+        0 => x + y,
+        1 => x - y,
+        2 => -x + y,
+        3 => -x - y,
+        4 => x,
+        5 => -x,
+        6 => y,
+        _ => -y,
+    }
+}
+
+fn grad3(hash: u8, x: f64, y: f64, z: f64) -> f64 {
+    let u = if hash & 15 < 8 { x } else { y };
+    let v = match hash & 15 {
+        0..=3 => y,
+        12 | 14 => x,
+        _ => z,
+    };
+    (if hash & 1 == 0 { u } else { -u }) + (if hash & 2 == 0 { v } else { -v })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streams_are_deterministic_and_independent() {
+        let (mut first, mut second) = (Random::seeded(42), Random::seeded(42));
+        assert_eq!(first.next_seed("particles"), second.next_seed("particles"));
+        // Drawing from another stream doesn't shift this one
+        first.next_seed("terrain");
+        assert_eq!(first.next_seed("particles"), second.next_seed("particles"));
+        assert_ne!(first.next_seed("particles"), first.next_seed("particles"));
+        assert_ne!(Random::seeded(1).next_seed("particles"), Random::seeded(2).next_seed("particles"));
+    }
+
+    #[test]
+    fn noise_is_seeded_and_bounded() {
+        let (perlin, simplex) = (Perlin::new(7), Simplex::new(7));
+        // Gradient noise is zero on the integer lattice
+        assert_eq!(perlin.noise2(3.0, -4.0), 0.0);
+        assert_eq!(perlin.noise2(0.3, 0.7), Perlin::new(7).noise2(0.3, 0.7));
+        assert_ne!(perlin.noise2(0.3, 0.7), Perlin::new(8).noise2(0.3, 0.7));
+
+        let samples = (0..400).map(|i| (i as f64 * 0.173, i as f64 * -0.091));
+        for (x, y) in samples {
+            assert!(perlin.noise2(x, y).abs() <= 1.0);
+            assert!(perlin.noise3(x, y, x * 0.5).abs() <= 1.1);
+            assert!(simplex.noise2(x, y).abs() <= 1.0);
+            assert!(Fbm::default().sample(x, y, |x, y| simplex.noise2(x, y)).abs() <= 1.0);
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//!
+//! Lua scripting, enabled with the `scripting` feature
+//!
+//! Each script runs in its own environment so several scripts can define the same callbacks. Scripts see a curated
+//! `hadron` table rather than the engine itself, and are reloaded when their file changes on disk. A script's top level
+//! runs on every (re)load; a global `update(dt)` function, if defined, runs once per frame
+//!
+
+use std::{path::{Path, PathBuf}, sync::Mutex, time::{Duration, Instant, SystemTime}};
+
+use collider::EntityId;
+use mlua::{Lua, Function, RegistryKey, Table, UserData, UserDataRef, Variadic};
+
+use crate::{debug::log, audio::PlaySound, system::{world::World, time::Time, transform::Transform, event, schedule::{Schedule, stage}}};
+
+#[derive(Debug)]
+pub enum ScriptError {
+    Io(PathBuf, std::io::Error),
+    Lua(PathBuf, mlua::Error),
+}
+
+/// An entity handed to scripts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScriptEntity(pub EntityId);
+
+struct Script {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    env: RegistryKey,
+}
+
+struct ScriptState {
+    lua: Lua,
+    scripts: Vec<Script>,
+    last_poll: Instant,
+}
+
+/// Owns the Lua state and every loaded script
+pub struct ScriptHost {
+    state: Mutex<ScriptState>,
+    reload_interval: Duration,
+    log: log::Logger,
+}
+
+// Impls
+
+impl std::error::Error for ScriptError {}
+
+impl std::fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScriptError::Io(path, err) => write!(f, "unable to read script {}: {}", path.display(), err),
+            ScriptError::Lua(path, err) => write!(f, "error in script {}: {}", path.display(), err),
+        }
+    }
+}
+
+impl UserData for ScriptEntity {}
+
+impl ScriptHost {
+    pub fn new(world: &World) -> Result<Self, ScriptError> {
+        let lua = Lua::new();
+        lua.set_app_data(world.clone());
+        install_api(&lua).map_err(|err| ScriptError::Lua(PathBuf::from("<api>"), err))?;
+
+        Ok(ScriptHost {
+            state: Mutex::new(ScriptState { lua, scripts: Vec::new(), last_poll: Instant::now() }),
+            reload_interval: Duration::from_millis(500),
+            log: log::get(),
+        })
+    }
+
+    /// How often script files are checked for changes
+    pub fn with_reload_interval(mut self, interval: Duration) -> Self {
+        self.reload_interval = interval;
+        self
+    }
+
+    /// Loads and runs a script file, keeping it for hot reloading
+    pub fn add_script(&self, path: impl AsRef<Path>) -> Result<(), ScriptError> {
+        let path = path.as_ref().to_path_buf();
+        let mut state = self.state.lock().expect("script state poisoned");
+        let env = load(&state.lua, &path)?;
+        state.scripts.push(Script { modified: modified(&path), path, env });
+        self.log.info(format!("loaded script {}", state.scripts.last().unwrap().path.display()));
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().expect("script state poisoned").scripts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reloads changed scripts then calls every script's `update`. Errors are logged and don't stop other scripts
+    pub fn run(&self, world: &World) {
+        let mut state = self.state.lock().expect("script state poisoned");
+
+        if state.last_poll.elapsed() >= self.reload_interval {
+            state.last_poll = Instant::now();
+            self.reload_changed(&mut state);
+        }
+
+        let dt = world.with_resource::<Time, _>(|time| time.delta_secs()).unwrap_or_default();
+        for script in &state.scripts {
+            let result = state.lua.registry_value::<Table>(&script.env)
+                .and_then(|env| env.raw_get::<_, Option<Function>>("update"))
+                .and_then(|update| match update {
+                    Some(update) => update.call::<_, ()>(dt),
+                    None => Ok(()),
+                });
+
+            if let Err(err) = result {
+                self.log.error(ScriptError::Lua(script.path.clone(), err).to_string());
+            }
+        }
+    }
+
+    /// Registers `run` as a system in the update stage
+    pub fn add_to_schedule(self, schedule: &mut Schedule) {
+        schedule.add_system(stage::UPDATE, "scripts", move |world| self.run(world));
+    }
+
+    fn reload_changed(&self, state: &mut ScriptState) {
+        let ScriptState { lua, scripts, .. } = state;
+        for script in scripts.iter_mut() {
+            let current = modified(&script.path);
+            if current == script.modified {
+                continue
+            }
+            script.modified = current;
+
+            match load(lua, &script.path) {
+                Ok(env) => {
+                    let old = std::mem::replace(&mut script.env, env);
+                    let _ = lua.remove_registry_value(old);
+                    self.log.info(format!("reloaded script {}", script.path.display()));
+                },
+                // Keep running the previous version until the script is fixed
+                Err(err) => self.log.error(err.to_string()),
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Runs a script in a fresh environment that falls back to the globals, returning the environment
+fn load(lua: &Lua, path: &Path) -> Result<RegistryKey, ScriptError> {
+    let source = std::fs::read_to_string(path).map_err(|err| ScriptError::Io(path.to_path_buf(), err))?;
+    let lua_err = |err| ScriptError::Lua(path.to_path_buf(), err);
+
+    let env = lua.create_table().map_err(lua_err)?;
+    let meta = lua.create_table().map_err(lua_err)?;
+    meta.set("__index", lua.globals()).map_err(lua_err)?;
+    env.set_metatable(Some(meta));
+
+    lua.load(source.as_str())
+        .set_name(path.display().to_string())
+        .set_environment(env.clone())
+        .exec()
+        .map_err(lua_err)?;
+
+    lua.create_registry_value(env).map_err(lua_err)
+}
+
+fn world(lua: &Lua) -> mlua::Result<World> {
+    lua.app_data_ref::<World>()
+        .map(|world| world.clone())
+        .ok_or_else(|| mlua::Error::RuntimeError(String::from("script host has no world")))
+}
+
+/// Builds the `hadron` global table
+fn install_api(lua: &Lua) -> mlua::Result<()> {
+    let api = lua.create_table()?;
+
+    let logger = log::get();
+    api.set("log", lua.create_function(move |_, message: String| {
+        logger.info(format!("[script] {}", message));
+        Ok(())
+    })?)?;
+
+    api.set("spawn", lua.create_function(|lua, ()| {
+        Ok(ScriptEntity(world(lua)?.spawn_entity()))
+    })?)?;
+
+    api.set("despawn", lua.create_function(|lua, entity: UserDataRef<ScriptEntity>| {
+        world(lua)?.despawn_entity(entity.0);
+        Ok(())
+    })?)?;
+
+    api.set("transforms", lua.create_function(|lua, ()| {
+        let entities = world(lua)?.query::<Transform, ()>();
+        lua.create_sequence_from(entities.into_iter().map(ScriptEntity))
+    })?)?;
+
+    api.set("translation", lua.create_function(|lua, entity: UserDataRef<ScriptEntity>| {
+        let translation = world(lua)?.component::<Transform, _>(entity.0, |t| t.translation);
+        Ok(translation.map(Variadic::from_iter).unwrap_or_default())
+    })?)?;
+
+    api.set("set_translation", lua.create_function(|lua, (entity, x, y, z): (UserDataRef<ScriptEntity>, f64, f64, f64)| {
+        let world = world(lua)?;
+        if world.component_mut::<Transform, _>(entity.0, |mut t| t.translation = [x, y, z]).is_none() {
+            world.insert_component(entity.0, Transform::from_translation(x, y, z));
+        }
+        Ok(())
+    })?)?;
+
+    api.set("play_sound", lua.create_function(|lua, (name, volume): (String, Option<f32>)| {
+        event::send_event(&world(lua)?, PlaySound { name, volume: volume.unwrap_or(1.0) });
+        Ok(())
+    })?)?;
+
+    lua.globals().set("hadron", api)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripts_have_separate_environments() {
+        let lua = Lua::new();
+        let dir = std::env::temp_dir();
+        let a = dir.join(format!("hadron_script_a_{}.lua", std::process::id()));
+        let b = dir.join(format!("hadron_script_b_{}.lua", std::process::id()));
+        std::fs::write(&a, "value = 1").unwrap();
+        std::fs::write(&b, "value = 2").unwrap();
+
+        let env_a: Table = lua.registry_value(&load(&lua, &a).unwrap()).unwrap();
+        let env_b: Table = lua.registry_value(&load(&lua, &b).unwrap()).unwrap();
+        assert_eq!(env_a.get::<_, i64>("value").unwrap(), 1);
+        assert_eq!(env_b.get::<_, i64>("value").unwrap(), 2);
+        assert!(lua.globals().get::<_, Option<i64>>("value").unwrap().is_none());
+
+        std::fs::remove_file(a).unwrap();
+        std::fs::remove_file(b).unwrap();
+    }
+}
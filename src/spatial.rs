@@ -0,0 +1,420 @@
+//!
+//! Spatial index
+//!
+//! Entities with `Bounds` are kept in a bounding volume hierarchy by their world space boxes, one structure answering
+//! frustum culling, ray picking and the proximity queries streaming decides on. `update_spatial_index` runs after
+//! transform propagation and only touches entities whose `GlobalTransform` or `Bounds` changed. The boxes stored in the
+//! tree are grown by `SpatialIndex::MARGIN` on every side so an entity moving a little stays in place, only leaving its
+//! box moves it in the tree. Despawned entities are dropped from the index, `remove_bounds` takes an entity out of it
+//!
+//! The tree is built incrementally, each box is inserted beside the node that grows the tree's surface area least
+//!
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use collider::EntityId;
+use serde::{Serialize, Deserialize};
+
+use crate::graphics::culling::Frustum;
+use crate::system::{world::World, transform::{Transform, GlobalTransform}};
+
+/// An axis aligned box
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: [f64; 3],
+    pub max: [f64; 3],
+}
+
+/// An entity's bounding box in its own space, the index places it with the entity's transform
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Bounds(pub Aabb);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RayHit<K> {
+    pub key: K,
+    /// Along the ray's direction, 0 when the ray starts inside the box
+    pub distance: f64,
+}
+
+/// A bounding volume hierarchy over boxes identified by `K`
+#[derive(Debug, Clone)]
+pub struct Bvh<K> {
+    nodes: Vec<Node<K>>,
+    /// Indices of unused nodes
+    free: Vec<usize>,
+    root: Option<usize>,
+    leaves: HashMap<K, usize>,
+    margin: f64,
+}
+
+/// World resource indexing every entity with `Bounds`
+pub struct SpatialIndex(pub Bvh<EntityId>);
+
+#[derive(Debug, Clone)]
+struct Node<K> {
+    /// Covers everything below, grown by the margin for leaves
+    aabb: Aabb,
+    parent: Option<usize>,
+    kind: NodeKind<K>,
+}
+
+#[derive(Debug, Clone)]
+enum NodeKind<K> {
+    Leaf { key: K, tight: Aabb },
+    Branch { children: [usize; 2] },
+    Free,
+}
+
+// Impls
+
+impl Aabb {
+    pub fn new(min: [f64; 3], max: [f64; 3]) -> Self {
+        Aabb { min, max }
+    }
+
+    pub fn from_center(center: [f64; 3], half_extents: [f64; 3]) -> Self {
+        Aabb { min: std::array::from_fn(|i| center[i] - half_extents[i]), max: std::array::from_fn(|i| center[i] + half_extents[i]) }
+    }
+
+    pub fn center(&self) -> [f64; 3] {
+        std::array::from_fn(|i| (self.min[i] + self.max[i]) * 0.5)
+    }
+
+    pub fn half_extents(&self) -> [f64; 3] {
+        std::array::from_fn(|i| (self.max[i] - self.min[i]) * 0.5)
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb { min: std::array::from_fn(|i| self.min[i].min(other.min[i])), max: std::array::from_fn(|i| self.max[i].max(other.max[i])) }
+    }
+
+    /// Grown by `margin` on every side
+    pub fn expanded(&self, margin: f64) -> Aabb {
+        Aabb { min: self.min.map(|v| v - margin), max: self.max.map(|v| v + margin) }
+    }
+
+    pub fn contains(&self, other: &Aabb) -> bool {
+        (0..3).all(|i| self.min[i] <= other.min[i] && other.max[i] <= self.max[i])
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        (0..3).all(|i| self.min[i] <= other.max[i] && other.min[i] <= self.max[i])
+    }
+
+    pub fn intersects_sphere(&self, center: [f64; 3], radius: f64) -> bool {
+        let distance: f64 = (0..3).map(|i| (center[i] - center[i].clamp(self.min[i], self.max[i])).powi(2)).sum();
+        distance <= radius * radius
+    }
+
+    /// Whether any of the box may be inside `frustum`. Boxes near a corner of the frustum can pass without being in it
+    pub fn intersects_frustum(&self, frustum: &Frustum) -> bool {
+        frustum.planes.iter().all(|plane| {
+            // The corner furthest along the plane's normal
+            let corner: [f64; 3] = std::array::from_fn(|i| if plane[i] >= 0.0 { self.max[i] } else { self.min[i] });
+            (0..3).map(|i| plane[i] as f64 * corner[i]).sum::<f64>() + plane[3] as f64 >= 0.0
+        })
+    }
+
+    /// Distance along `direction` to where the ray enters the box, if it does within `max_distance`
+    pub fn intersects_ray(&self, origin: [f64; 3], direction: [f64; 3], max_distance: f64) -> Option<f64> {
+        let (mut near, mut far) = (0.0f64, max_distance);
+        for i in 0..3 {
+            let inverse = 1.0 / direction[i];
+            let (a, b) = ((self.min[i] - origin[i]) * inverse, (self.max[i] - origin[i]) * inverse);
+            // A ray parallel to a slab it starts outside of gives NaN and misses
+            if a.is_nan() || b.is_nan() {
+                return None
+            }
+            near = near.max(a.min(b));
+            far = far.min(a.max(b));
+        }
+        (near <= far).then_some(near)
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let [x, y, z] = std::array::from_fn::<f64, 3, _>(|i| self.max[i] - self.min[i]);
+        2.0 * (x * y + y * z + z * x)
+    }
+
+    /// The box around this one once moved into the space `transform` places it in
+    pub fn transformed(&self, transform: &Transform) -> Aabb {
+        let [x, y, z] = self.center();
+        let center = transform.compose(&Transform::from_translation(x, y, z)).translation;
+        let half = self.half_extents();
+        // Each world axis spans the absolute projections of the box's scaled, rotated axes
+        let axes = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]].map(|axis| transform.rotate(axis));
+        let extents = std::array::from_fn(|i| (0..3).map(|axis| (axes[axis][i] * half[axis] * transform.scale[axis]).abs()).sum());
+        Aabb::from_center(center, extents)
+    }
+}
+
+impl<K: Copy + Eq + Hash> Default for Bvh<K> {
+    fn default() -> Self {
+        Bvh::with_margin(SpatialIndex::MARGIN)
+    }
+}
+
+impl<K: Copy + Eq + Hash> Bvh<K> {
+    pub fn with_margin(margin: f64) -> Self {
+        Bvh { nodes: Vec::new(), free: Vec::new(), root: None, leaves: HashMap::new(), margin }
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    pub fn contains(&self, key: K) -> bool {
+        self.leaves.contains_key(&key)
+    }
+
+    /// The box `key` was last set to
+    pub fn get(&self, key: K) -> Option<Aabb> {
+        match self.nodes[*self.leaves.get(&key)?].kind {
+            NodeKind::Leaf { tight, .. } => Some(tight),
+            _ => None,
+        }
+    }
+
+    /// Inserts `key` or moves it to `aabb`, returning whether it had to move in the tree
+    pub fn set(&mut self, key: K, aabb: Aabb) -> bool {
+        if let Some(&leaf) = self.leaves.get(&key) {
+            if self.nodes[leaf].aabb.contains(&aabb) {
+                self.nodes[leaf].kind = NodeKind::Leaf { key, tight: aabb };
+                return false
+            }
+            self.remove_leaf(leaf);
+            self.nodes[leaf].aabb = aabb.expanded(self.margin);
+            self.nodes[leaf].kind = NodeKind::Leaf { key, tight: aabb };
+            self.insert_leaf(leaf);
+            return true
+        }
+
+        let leaf = self.allocate(Node { aabb: aabb.expanded(self.margin), parent: None, kind: NodeKind::Leaf { key, tight: aabb } });
+        self.leaves.insert(key, leaf);
+        self.insert_leaf(leaf);
+        true
+    }
+
+    pub fn remove(&mut self, key: K) -> bool {
+        let Some(leaf) = self.leaves.remove(&key) else {
+            return false
+        };
+        self.remove_leaf(leaf);
+        self.nodes[leaf].kind = NodeKind::Free;
+        self.free.push(leaf);
+        true
+    }
+
+    /// Every key whose box `overlaps` accepts, descending only into nodes it accepts as well
+    pub fn query(&self, overlaps: impl Fn(&Aabb) -> bool) -> Vec<K> {
+        let mut found = Vec::new();
+        let mut stack: Vec<usize> = self.root.into_iter().collect();
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !overlaps(&node.aabb) {
+                continue
+            }
+            match node.kind {
+                NodeKind::Leaf { key, tight } if overlaps(&tight) => found.push(key),
+                NodeKind::Branch { children } => stack.extend(children),
+                _ => (),
+            }
+        }
+        found
+    }
+
+    pub fn query_aabb(&self, aabb: &Aabb) -> Vec<K> {
+        self.query(|node| node.intersects(aabb))
+    }
+
+    pub fn query_sphere(&self, center: [f64; 3], radius: f64) -> Vec<K> {
+        self.query(|node| node.intersects_sphere(center, radius))
+    }
+
+    pub fn query_frustum(&self, frustum: &Frustum) -> Vec<K> {
+        self.query(|node| node.intersects_frustum(frustum))
+    }
+
+    /// Every box the ray passes through within `max_distance`, nearest first
+    pub fn raycast(&self, origin: [f64; 3], direction: [f64; 3], max_distance: f64) -> Vec<RayHit<K>> {
+        let mut hits = Vec::new();
+        let mut stack: Vec<usize> = self.root.into_iter().collect();
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if node.aabb.intersects_ray(origin, direction, max_distance).is_none() {
+                continue
+            }
+            match node.kind {
+                NodeKind::Leaf { key, tight } => hits.extend(tight.intersects_ray(origin, direction, max_distance).map(|distance| RayHit { key, distance })),
+                NodeKind::Branch { children } => stack.extend(children),
+                NodeKind::Free => (),
+            }
+        }
+        hits.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        hits
+    }
+
+    fn allocate(&mut self, node: Node<K>) -> usize {
+        match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            },
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            },
+        }
+    }
+
+    /// Links a detached leaf in beside the node it grows the tree least next to
+    fn insert_leaf(&mut self, leaf: usize) {
+        let Some(mut sibling) = self.root else {
+            self.nodes[leaf].parent = None;
+            self.root = Some(leaf);
+            return
+        };
+
+        let aabb = self.nodes[leaf].aabb;
+        while let NodeKind::Branch { children } = self.nodes[sibling].kind {
+            let area = self.nodes[sibling].aabb.surface_area();
+            let combined = self.nodes[sibling].aabb.union(&aabb).surface_area();
+            // Pairing with this node creates a parent covering both, going further grows this node on the way down
+            let here = 2.0 * combined;
+            let inherited = 2.0 * (combined - area);
+            let cost = |child: usize| {
+                let node = &self.nodes[child];
+                let grown = node.aabb.union(&aabb).surface_area();
+                match node.kind {
+                    NodeKind::Leaf { .. } => grown + inherited,
+                    _ => grown - node.aabb.surface_area() + inherited,
+                }
+            };
+            let (left, right) = (cost(children[0]), cost(children[1]));
+            if here < left && here < right {
+                break
+            }
+            sibling = if left <= right { children[0] } else { children[1] };
+        }
+
+        let old_parent = self.nodes[sibling].parent;
+        let parent = self.allocate(Node {
+            aabb: self.nodes[sibling].aabb.union(&aabb),
+            parent: old_parent,
+            kind: NodeKind::Branch { children: [sibling, leaf] },
+        });
+        self.nodes[sibling].parent = Some(parent);
+        self.nodes[leaf].parent = Some(parent);
+        match old_parent {
+            Some(old_parent) => self.replace_child(old_parent, sibling, parent),
+            None => self.root = Some(parent),
+        }
+        self.refit(old_parent);
+    }
+
+    /// Unlinks a leaf from the tree, its parent is freed and its sibling takes the parent's place
+    fn remove_leaf(&mut self, leaf: usize) {
+        let Some(parent) = self.nodes[leaf].parent else {
+            self.root = None;
+            return
+        };
+        let NodeKind::Branch { children } = self.nodes[parent].kind else {
+            unreachable!("a leaf's parent is always a branch")
+        };
+        let sibling = if children[0] == leaf { children[1] } else { children[0] };
+        let grandparent = self.nodes[parent].parent;
+        self.nodes[sibling].parent = grandparent;
+        match grandparent {
+            Some(grandparent) => self.replace_child(grandparent, parent, sibling),
+            None => self.root = Some(sibling),
+        }
+        self.nodes[parent].kind = NodeKind::Free;
+        self.free.push(parent);
+        self.nodes[leaf].parent = None;
+        self.refit(grandparent);
+    }
+
+    fn replace_child(&mut self, parent: usize, old: usize, new: usize) {
+        if let NodeKind::Branch { children } = &mut self.nodes[parent].kind {
+            children.iter_mut().filter(|child| **child == old).for_each(|child| *child = new);
+        }
+    }
+
+    /// Recomputes the boxes of `index` and every node above it
+    fn refit(&mut self, mut index: Option<usize>) {
+        while let Some(current) = index {
+            if let NodeKind::Branch { children } = self.nodes[current].kind {
+                self.nodes[current].aabb = self.nodes[children[0]].aabb.union(&self.nodes[children[1]].aabb);
+            }
+            index = self.nodes[current].parent;
+        }
+    }
+}
+
+impl SpatialIndex {
+    /// World units the stored boxes are grown by
+    pub const MARGIN: f64 = 0.1;
+}
+
+/// Adds an empty `SpatialIndex` to the world, and drops despawned entities from it
+pub fn init_spatial_index(world: &World) {
+    world.insert_resource(SpatialIndex(Bvh::default()));
+    world.set_component_remover::<Bounds>(remove_bounds);
+}
+
+/// Takes `Bounds` off an entity and the entity out of the index
+pub fn remove_bounds(world: &World, entity: EntityId) {
+    world.remove_component::<Bounds>(entity);
+    world.with_resource_mut::<SpatialIndex, _>(|index| index.0.remove(entity));
+}
+
+/// Moves entities whose bounds or global transform changed since the system last ran to their new boxes
+pub fn update_spatial_index(world: &World) {
+    let mut changed = world.changed::<Bounds>();
+    changed.extend(world.changed::<GlobalTransform>());
+    let boxes: Vec<(EntityId, Aabb)> = changed.into_iter().filter_map(|entity| {
+        let bounds = world.component::<Bounds, _>(entity, |bounds| bounds.0)?;
+        let transform = world.component::<GlobalTransform, _>(entity, |global| global.0)
+            .or_else(|| world.component::<Transform, _>(entity, |transform| *transform))
+            .unwrap_or_default();
+        Some((entity, bounds.transformed(&transform)))
+    }).collect();
+    world.with_resource_mut::<SpatialIndex, _>(|index| {
+        for (entity, aabb) in boxes {
+            index.0.set(entity, aabb);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::camera::Camera;
+
+    #[test]
+    fn queries_find_boxes_as_they_move() {
+        let mut bvh: Bvh<u32> = Bvh::with_margin(0.5);
+        for key in 0..64 {
+            bvh.set(key, Aabb::from_center([key as f64 * 4.0, 0.0, 0.0], [1.0; 3]));
+        }
+        assert_eq!(bvh.query_aabb(&Aabb::new([7.5, -1.0, -1.0], [8.5, 1.0, 1.0])), vec![2]);
+
+        // Nudging a box stays within its margin, moving it far relinks it
+        assert!(!bvh.set(2, Aabb::from_center([8.2, 0.0, 0.0], [1.0; 3])));
+        assert!(bvh.set(2, Aabb::from_center([0.0, 10.0, 0.0], [1.0; 3])));
+        assert!(bvh.query_sphere([8.0, 0.0, 0.0], 0.5).is_empty());
+
+        let hits = bvh.raycast([-10.0, 0.0, 0.0], [1.0, 0.0, 0.0], 20.0);
+        assert_eq!(hits.iter().map(|hit| (hit.key, hit.distance)).collect::<Vec<_>>(), vec![(0, 9.0), (1, 13.0)]);
+
+        assert!(bvh.remove(0) && !bvh.remove(0));
+        assert_eq!(bvh.len(), 63);
+        let frustum = Frustum::new(&Camera::default(), &Transform::from_translation(0.0, 10.0, 5.0), 1.0);
+        assert_eq!(bvh.query_frustum(&frustum), vec![2]);
+    }
+}
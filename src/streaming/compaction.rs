@@ -0,0 +1,282 @@
+//!
+//! Compaction of streaming pack files
+//!
+//! Every overwrite or removal leaves a dead record behind, so after many load/unload cycles live units end up spread
+//! thinly over many mostly-garbage packs. Compaction copies the live records out of fragmented packs into fresh ones,
+//! swaps the index over to the new locations in a single step under the index lock, rewrites the index file, and only
+//! then deletes the old packs. Readers that raced the swap find their old pack gone and look the unit up again
+//!
+
+use std::{collections::HashSet, io, sync::{Arc, Condvar, Mutex}, thread::JoinHandle, time::Duration};
+
+use crate::debug::log;
+use crate::unique::UniqueId;
+
+use super::{pack::{self, PackWriter}, PackInfo, Streaming, StreamingError, UnitLocation};
+
+/// When a pack is worth compacting
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompactionPolicy {
+    /// Fraction of a pack that must be garbage
+    pub min_garbage_ratio: f64,
+    /// Packs with less garbage than this are left alone, however fragmented
+    pub min_garbage_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionReport {
+    pub packs_compacted: usize,
+    pub units_moved: usize,
+    pub bytes_reclaimed: u64,
+}
+
+/// Background thread compacting a `Streaming` store on an interval, stopped when dropped
+pub struct Compactor {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// A record copied into a new pack, swapped into the index only if the unit hasn't changed since
+struct Move {
+    uid: UniqueId,
+    from: UnitLocation,
+    to: UnitLocation,
+    tombstone: bool,
+}
+
+// Impls
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        CompactionPolicy {
+            min_garbage_ratio: 0.3,
+            min_garbage_bytes: 64 * 1024,
+        }
+    }
+}
+
+impl CompactionPolicy {
+    pub fn with_min_garbage_ratio(mut self, ratio: f64) -> Self {
+        self.min_garbage_ratio = ratio;
+        self
+    }
+
+    pub fn with_min_garbage_bytes(mut self, bytes: u64) -> Self {
+        self.min_garbage_bytes = bytes;
+        self
+    }
+
+    fn should_compact(&self, info: &PackInfo) -> bool {
+        let garbage = info.garbage();
+        info.total > 0 && garbage >= self.min_garbage_bytes && garbage as f64 / info.total as f64 >= self.min_garbage_ratio
+    }
+}
+
+impl Streaming {
+    /// Merges fragmented packs into new ones and deletes the originals. Stores and loads carry on while the copy runs,
+    /// only the final index swap holds the lock
+    pub fn compact(&self) -> Result<CompactionReport, StreamingError> {
+        let _compacting = self.compacting.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let (victims, records, drop_tombstones) = {
+            let mut internal = self.lock();
+            let victims: HashSet<u32> = internal.packs.iter()
+                .filter(|(_, info)| self.config.compaction.should_compact(info))
+                .map(|(id, _)| *id)
+                .collect();
+            if victims.is_empty() {
+                return Ok(CompactionReport::default())
+            }
+
+            // The active pack is still being appended to, start a new one before copying out of it
+            if internal.writer.as_ref().is_some_and(|writer| victims.contains(&writer.id())) {
+                internal.writer = None;
+            }
+
+            // A tombstone only has to outlive the older records it hides. If every pack is being compacted, those are
+            // all about to be deleted
+            let drop_tombstones = internal.packs.keys().all(|id| victims.contains(id));
+
+            let records: Vec<(UniqueId, UnitLocation, bool)> = internal.index.units.iter().map(|(uid, l)| (*uid, *l, false))
+                .chain(internal.index.tombstones.iter().map(|(uid, l)| (*uid, *l, true)))
+                .filter(|(_, location, _)| victims.contains(&location.pack))
+                .collect();
+            (victims, records, drop_tombstones)
+        };
+
+        let mut moves = Vec::with_capacity(records.len());
+        let mut outputs: Vec<PackWriter> = Vec::new();
+        for (uid, from, tombstone) in records {
+            if tombstone && drop_tombstones {
+                continue
+            }
+
+            // Leave a corrupt record where it is rather than copying it into a pack with a fresh history
+            let corrupt = StreamingError::Corrupt { pack: from.pack, offset: from.offset };
+            let (header, data) = match pack::read_record(&pack::pack_path(&self.directory, from.pack), from.offset, from.len) {
+                Ok(record) => record,
+                Err(error) if error.kind() == io::ErrorKind::InvalidData => return Err(corrupt),
+                Err(error) => return Err(error.into()),
+            };
+            if header.uid != uid || !header.verify(&data) {
+                return Err(corrupt)
+            }
+
+            if outputs.last().is_none_or(|output| output.len() >= self.config.max_pack_size) {
+                let id = self.lock().allocate_pack();
+                outputs.push(PackWriter::create(&self.directory, id)?);
+            }
+            let output = outputs.last_mut().expect("compaction output");
+            let offset = output.append(&header, &data)?;
+            moves.push(Move { uid, from, to: UnitLocation { pack: output.id(), offset, ..from }, tombstone });
+        }
+        for output in &outputs {
+            output.sync()?;
+        }
+
+        let mut report = CompactionReport { packs_compacted: victims.len(), ..Default::default() };
+        let mut tombstone_packs = HashSet::new();
+        {
+            let mut internal = self.lock();
+            for output in &outputs {
+                internal.packs.insert(output.id(), PackInfo { total: output.len(), live: 0 });
+            }
+
+            for moved in &moves {
+                let entries = match moved.tombstone {
+                    true => &mut internal.index.tombstones,
+                    false => &mut internal.index.units,
+                };
+                // Anything stored or removed during the copy is newer, leaving the copied record as garbage
+                if entries.get(&moved.uid) == Some(&moved.from) {
+                    entries.insert(moved.uid, moved.to);
+                    internal.packs.entry(moved.to.pack).or_default().live += pack::HEADER_LEN + moved.to.len;
+                    report.units_moved += !moved.tombstone as usize;
+                }
+            }
+
+            if drop_tombstones {
+                let dropped: Vec<UniqueId> = internal.index.tombstones.iter()
+                    .filter(|(_, location)| victims.contains(&location.pack))
+                    .map(|(uid, _)| *uid)
+                    .collect();
+                for uid in dropped {
+                    if let Some(location) = internal.index.tombstones.remove(&uid) {
+                        tombstone_packs.insert(location.pack);
+                    }
+                }
+            }
+
+            let before: u64 = victims.iter().filter_map(|id| internal.packs.remove(id)).map(|info| info.total).sum();
+            report.bytes_reclaimed = before.saturating_sub(outputs.iter().map(|output| output.len()).sum());
+
+            // The index file has to stop pointing at the old packs before they go
+            internal.snapshot(&self.directory);
+        }
+
+        // Packs holding dropped tombstones go last, so a crash part way through can't resurrect a removed unit from an
+        // older pack that survived
+        let mut victims: Vec<u32> = victims.into_iter().collect();
+        victims.sort_by_key(|id| (tombstone_packs.contains(id), *id));
+        for id in victims {
+            self.reader.evict(id);
+            std::fs::remove_file(pack::pack_path(&self.directory, id))?;
+        }
+
+        Ok(report)
+    }
+
+    /// Runs `compact` every `interval` on a background thread
+    pub fn spawn_compactor(self: &Arc<Self>, interval: Duration) -> Compactor {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let streaming = Arc::clone(self);
+        let signal = Arc::clone(&stop);
+
+        let thread = std::thread::Builder::new()
+            .name(String::from("hadron streaming compactor"))
+            .spawn(move || {
+                let (stopped, condvar) = &*signal;
+                let mut guard = stopped.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                while !*guard {
+                    guard = condvar.wait_timeout(guard, interval).unwrap_or_else(|poisoned| poisoned.into_inner()).0;
+                    if *guard {
+                        break
+                    }
+                    match streaming.compact() {
+                        Ok(report) if report.packs_compacted > 0 => log::get().info(format!("streaming compaction: {:?}", report)),
+                        Ok(_) => (),
+                        Err(error) => log::get().error(format!("streaming compaction failed: {}", error)),
+                    }
+                }
+            })
+            .expect("unable to spawn streaming compactor");
+
+        Compactor { stop, thread: Some(thread) }
+    }
+}
+
+impl Compactor {
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        let (stopped, condvar) = &*self.stop;
+        *stopped.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = true;
+        condvar.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::StreamingConfig;
+
+    #[test]
+    fn compaction_reclaims_garbage_and_keeps_units() {
+        let dir = std::env::temp_dir().join(format!("hadron_compaction_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = StreamingConfig::default()
+            .with_max_pack_size(4096)
+            .with_compaction(CompactionPolicy::default().with_min_garbage_bytes(0));
+
+        let units: Vec<UniqueId> = (0..8).map(|_| UniqueId::get()).collect();
+        let removed = units[0];
+        {
+            let streaming = Streaming::open_with(&dir, config).unwrap();
+            for cycle in 0..20u8 {
+                for uid in &units {
+                    streaming.store(*uid, &[cycle; 256]).unwrap();
+                }
+            }
+            streaming.remove(removed).unwrap();
+
+            let before = streaming.fragmentation();
+            let report = streaming.compact().unwrap();
+            let after = streaming.fragmentation();
+            assert!(report.packs_compacted > 0 && report.bytes_reclaimed > 0);
+            assert!(after.packs < before.packs && after.garbage_ratio() < before.garbage_ratio());
+
+            for uid in &units[1..] {
+                assert_eq!(streaming.load(*uid).unwrap(), vec![19u8; 256]);
+            }
+            streaming.store(units[1], b"after compaction").unwrap();
+        }
+
+        let streaming = Streaming::open_with(&dir, config).unwrap();
+        assert_eq!(streaming.len(), units.len() - 1);
+        assert!(!streaming.contains(removed));
+        assert_eq!(streaming.load(units[1]).unwrap(), b"after compaction");
+        assert_eq!(streaming.load(units[2]).unwrap(), vec![19u8; 256]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,238 @@
+//!
+//! Streamed components
+//!
+//! A `StreamedComponent<T>` holds component data too big to keep every copy of in memory. While resident it's read like
+//! any other component. Once evicted to the world's `ComponentStore`, reading it returns `Streamed::Loading` and asks for
+//! it back, and `stream_components::<T>` loads it again before the next frame. Components smaller than the store's
+//! threshold always stay resident. Past the resident budget the ones that went longest without being read are evicted,
+//! writing back data that changed since it was last stored
+//!
+
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{debug::log, unique::UniqueId, system::world::World, memory::pressure::{self, PressureLevel}};
+
+use super::{Streaming, StreamingError};
+
+/// World resource of the store streamed components are evicted to
+#[derive(Clone)]
+pub struct ComponentStore {
+    pub store: Arc<Streaming>,
+    /// Components with less data than this, in bytes, are never evicted
+    pub threshold: u64,
+    /// Bytes of evictable data kept resident for each component type
+    pub budget: u64,
+}
+
+/// A streamed component's data, if it's in memory
+#[derive(Debug, PartialEq, Eq)]
+pub enum Streamed<T> {
+    Resident(T),
+    /// Evicted, and requested to load before the next frame
+    Loading,
+}
+
+/// Component data that can be evicted to a `Streaming` store and loaded back on demand
+pub struct StreamedComponent<T> {
+    uid: UniqueId,
+    value: Option<T>,
+    /// Changed since it was last stored
+    dirty: bool,
+    /// Size of the stored data as of the last store or load, estimated when created
+    size: u64,
+    /// Read since the last `stream_components`
+    accessed: AtomicBool,
+    /// Runs of `stream_components` since it was last read
+    idle: u32,
+    /// Where its data was stored, once it's been evicted
+    store: Option<Arc<Streaming>>,
+}
+
+// Impls
+
+impl<T> Streamed<T> {
+    pub fn is_resident(&self) -> bool {
+        matches!(self, Streamed::Resident(_))
+    }
+
+    pub fn resident(self) -> Option<T> {
+        match self {
+            Streamed::Resident(value) => Some(value),
+            Streamed::Loading => None,
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> StreamedComponent<T> {
+    pub fn new(value: T) -> Self {
+        let size = serde_json::to_vec(&value).map_or(0, |bytes| bytes.len() as u64);
+        StreamedComponent { uid: UniqueId::get(), value: Some(value), dirty: true, size, accessed: AtomicBool::new(true), idle: 0, store: None }
+    }
+
+    /// The unit its data is stored under
+    pub fn uid(&self) -> UniqueId {
+        self.uid
+    }
+
+    pub fn is_resident(&self) -> bool {
+        self.value.is_some()
+    }
+
+    /// Whether it changed since it was last stored, and has to be written back before it's evicted
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn get(&self) -> Streamed<&T> {
+        self.accessed.store(true, Ordering::Relaxed);
+        self.value.as_ref().map_or(Streamed::Loading, Streamed::Resident)
+    }
+
+    /// Marks the data dirty when it's resident
+    pub fn get_mut(&mut self) -> Streamed<&mut T> {
+        *self.accessed.get_mut() = true;
+        match self.value.as_mut() {
+            Some(value) => {
+                self.dirty = true;
+                Streamed::Resident(value)
+            },
+            None => Streamed::Loading,
+        }
+    }
+
+    /// Drops the data from memory, storing it in `store` first if it changed
+    pub fn evict(&mut self, store: &Arc<Streaming>) -> Result<(), StreamingError> {
+        let Some(value) = self.value.as_ref() else { return Ok(()) };
+        if self.dirty || self.store.is_none() {
+            let bytes = serde_json::to_vec(value).map_err(|error| StreamingError::Encoding(self.uid, error.to_string()))?;
+            store.store(self.uid, &bytes)?;
+            self.size = bytes.len() as u64;
+            self.store = Some(Arc::clone(store));
+            self.dirty = false;
+        }
+        self.value = None;
+        Ok(())
+    }
+
+    /// Reads the data back from the store it was evicted to
+    pub fn load(&mut self) -> Result<(), StreamingError> {
+        let Some(store) = self.store.as_ref().filter(|_| self.value.is_none()) else { return Ok(()) };
+        let bytes = store.load(self.uid)?;
+        self.value = Some(serde_json::from_slice(&bytes).map_err(|error| StreamingError::Encoding(self.uid, error.to_string()))?);
+        self.size = bytes.len() as u64;
+        Ok(())
+    }
+}
+
+impl<T> Drop for StreamedComponent<T> {
+    fn drop(&mut self) {
+        if let Some(store) = &self.store {
+            if let Err(error) = store.remove(self.uid) {
+                log::get().warn(format!("unable to remove streamed component {}: {}", self.uid, error));
+            }
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for StreamedComponent<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StreamedComponent").field("uid", &self.uid).field("resident", &self.value.is_some()).field("dirty", &self.dirty).field("size", &self.size).finish()
+    }
+}
+
+/// Loads the `StreamedComponent<T>`s read while evicted, then evicts the longest unread ones until the rest fit the
+/// `ComponentStore` budget. Does nothing without a `ComponentStore`
+pub fn stream_components<T: Serialize + DeserializeOwned + Send + Sync + 'static>(world: &World) {
+    let Some(config) = world.with_resource::<ComponentStore, _>(ComponentStore::clone) else { return };
+    let logger = log::get().with_topic("streaming");
+
+    let mut evictable = Vec::new();
+    for entity in world.query::<StreamedComponent<T>, ()>() {
+        world.component_mut::<StreamedComponent<T>, _>(entity, |mut component| {
+            // Streaming data in and out doesn't change it
+            let component = component.bypass_change_detection();
+            match std::mem::take(component.accessed.get_mut()) {
+                true => component.idle = 0,
+                false => component.idle = component.idle.saturating_add(1),
+            }
+            if component.idle == 0 && !component.is_resident() {
+                if let Err(error) = component.load() {
+                    logger.warn(format!("unable to load streamed component {}: {}", component.uid, error));
+                }
+            }
+            if component.is_resident() && component.size >= config.threshold {
+                evictable.push((entity, component.idle, component.size));
+            }
+        });
+    }
+
+    let mut resident: u64 = evictable.iter().map(|(_, _, size)| size).sum();
+    evictable.sort_by_key(|(_, idle, _)| std::cmp::Reverse(*idle));
+    for (entity, idle, size) in evictable {
+        // Components read since the last run are in use, so they stay even over budget
+        if resident <= config.budget || idle == 0 {
+            break
+        }
+        let evicted = world.component_mut::<StreamedComponent<T>, _>(entity, |mut component| component.bypass_change_detection().evict(&config.store));
+        match evicted {
+            Some(Ok(())) => resident -= size,
+            Some(Err(error)) => logger.warn(format!("unable to evict streamed component: {}", error)),
+            None => (),
+        }
+    }
+}
+
+/// Subscribes the `ComponentStore` budget to `MemoryPressure`, keeping `PressureLevel::quality` of the budget it had
+/// before the pressure started
+pub fn scale_budget_for_pressure() -> impl FnMut(&World) + Send + Sync + 'static {
+    let mut full_budget = None;
+    move |world| {
+        let Some(level) = pressure::latest_pressure(world) else { return };
+        world.with_resource_mut::<ComponentStore, _>(|config| {
+            let full = *full_budget.get_or_insert(config.budget);
+            config.budget = (full as f64 * level.quality()) as u64;
+            if level == PressureLevel::Normal {
+                full_budget = None;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dirty_data_is_written_back_on_eviction() {
+        let dir = std::env::temp_dir().join(format!("hadron_streamed_component_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = Arc::new(Streaming::open(&dir).unwrap());
+
+        let mut component = StreamedComponent::new(vec![1u32, 2, 3]);
+        component.evict(&store).unwrap();
+        assert_eq!(component.get(), Streamed::Loading);
+        assert!(store.contains(component.uid()));
+
+        component.load().unwrap();
+        assert_eq!(component.get(), Streamed::Resident(&vec![1, 2, 3]));
+        assert!(!component.is_dirty());
+        if let Streamed::Resident(values) = component.get_mut() {
+            values.push(4);
+        }
+        assert!(component.is_dirty());
+        component.evict(&store).unwrap();
+        component.load().unwrap();
+        assert_eq!(component.get().resident(), Some(&vec![1, 2, 3, 4]));
+
+        let uid = component.uid();
+        drop(component);
+        assert!(!store.contains(uid));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
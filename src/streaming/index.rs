@@ -0,0 +1,238 @@
+//!
+//! The persisted streaming index
+//!
+//! Opening a store would otherwise mean scanning every record header of every pack. Instead the index is kept on disk
+//! next to the packs as a journal: a header with the counters the store resumes from, followed by one checksummed entry
+//! per record written, mapping the unit to its pack, offset, length and sequence number. Stores and removals append an
+//! entry, and compaction or a reopen that found the journal out of date rewrites it as a snapshot of the live entries
+//!
+//! On open the entries are replayed, newest sequence winning just as with records. Each pack is only scanned past the
+//! end of the last record the journal knows about, which picks up records written after a crash that lost their entry.
+//! A torn or corrupt entry ends the journal early, with the packs' tails covering the rest, and a journal that points
+//! at records no longer on disk is thrown away and the index rebuilt by scanning every pack
+//!
+
+use std::{fs::{File, OpenOptions}, io::{self, Write}, path::{Path, PathBuf}};
+
+use crate::unique::UniqueId;
+
+use super::{pack, UnitLocation};
+
+/// Name of the index file inside a store's directory
+pub(crate) const INDEX_FILE: &str = "index.hidx";
+
+const INDEX_MAGIC: u32 = u32::from_le_bytes(*b"HIDX");
+const INDEX_VERSION: u32 = 1;
+const FLAG_TOMBSTONE: u32 = 1;
+
+const HEADER_LEN: u64 = 24;
+const ENTRY_LEN: u64 = 56;
+
+/// Entry bytes covered by the checksum, everything before the checksum itself
+const CHECKSUMMED_LEN: usize = 48;
+
+/// Superseded entries tolerated beyond one per live entry before the journal is rewritten
+const JOURNAL_SLACK: u64 = 1024;
+
+/// How the index of an opened store was built
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexStatus {
+    /// Read from the index file, with `caught_up` records found past the end of what it covered
+    Loaded { caught_up: usize },
+    /// Built by scanning every pack
+    Rebuilt(RebuildReason),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebuildReason {
+    /// There was no index file, as with a new store
+    Missing,
+    /// The index file's header couldn't be read
+    Unreadable,
+    /// The index pointed at packs or records that aren't on disk
+    Stale,
+}
+
+/// One record's place in the index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct IndexEntry {
+    pub(crate) uid: UniqueId,
+    pub(crate) location: UnitLocation,
+    pub(crate) tombstone: bool,
+}
+
+/// The readable part of an index file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct IndexContents {
+    pub(crate) next_sequence: u64,
+    pub(crate) next_pack: u32,
+    pub(crate) entries: Vec<IndexEntry>,
+    /// Whether reading stopped at a torn or corrupt entry
+    pub(crate) damaged: bool,
+}
+
+/// Appends entries to the index file
+pub(crate) struct IndexWriter {
+    file: File,
+    entries: u64,
+}
+
+// Impls
+
+impl IndexEntry {
+    fn encode(&self) -> [u8; ENTRY_LEN as usize] {
+        let mut bytes = [0u8; ENTRY_LEN as usize];
+        bytes[0..4].copy_from_slice(&(if self.tombstone { FLAG_TOMBSTONE } else { 0 }).to_le_bytes());
+        bytes[4..20].copy_from_slice(&self.uid.to_bytes());
+        bytes[20..24].copy_from_slice(&self.location.pack.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.location.offset.to_le_bytes());
+        bytes[32..40].copy_from_slice(&self.location.len.to_le_bytes());
+        bytes[40..48].copy_from_slice(&self.location.sequence.to_le_bytes());
+        let checksum = pack::crc32(0, &bytes[..CHECKSUMMED_LEN]);
+        bytes[48..52].copy_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let field = |start: usize, len: usize| &bytes[start..start + len];
+        if pack::crc32(0, &bytes[..CHECKSUMMED_LEN]).to_le_bytes() != field(48, 4) {
+            return None
+        }
+        Some(IndexEntry {
+            tombstone: u32::from_le_bytes(field(0, 4).try_into().ok()?) & FLAG_TOMBSTONE != 0,
+            uid: UniqueId::from_bytes(field(4, 16).try_into().ok()?),
+            location: UnitLocation {
+                pack: u32::from_le_bytes(field(20, 4).try_into().ok()?),
+                offset: u64::from_le_bytes(field(24, 8).try_into().ok()?),
+                len: u64::from_le_bytes(field(32, 8).try_into().ok()?),
+                sequence: u64::from_le_bytes(field(40, 8).try_into().ok()?),
+            },
+        })
+    }
+
+    /// End of the entry's record in its pack
+    pub(crate) fn end(&self) -> u64 {
+        self.location.offset + pack::HEADER_LEN + self.location.len
+    }
+}
+
+impl IndexWriter {
+    /// Replaces the index file with one holding `entries`, written beside it and renamed over so a crash leaves either
+    /// the old index or the new one
+    pub(crate) fn snapshot<'a, I>(directory: &Path, next_sequence: u64, next_pack: u32, entries: I) -> io::Result<Self>
+    where
+        I: IntoIterator<Item = &'a IndexEntry>,
+    {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&INDEX_MAGIC.to_le_bytes());
+        bytes.extend_from_slice(&INDEX_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&next_sequence.to_le_bytes());
+        bytes.extend_from_slice(&next_pack.to_le_bytes());
+        bytes.extend_from_slice(&[0u8; 4]);
+        let mut count = 0;
+        for entry in entries {
+            bytes.extend_from_slice(&entry.encode());
+            count += 1;
+        }
+
+        let temporary = directory.join(format!("{}.tmp", INDEX_FILE));
+        let mut file = File::create(&temporary)?;
+        file.write_all(&bytes)?;
+        file.sync_data()?;
+        std::fs::rename(&temporary, index_path(directory))?;
+
+        IndexWriter::open(directory, count)
+    }
+
+    /// Appends to the index file in `directory`, which holds `entries` intact entries and nothing after them
+    pub(crate) fn open(directory: &Path, entries: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().append(true).open(index_path(directory))?;
+        Ok(IndexWriter { file, entries })
+    }
+
+    pub(crate) fn append(&mut self, entry: &IndexEntry) -> io::Result<()> {
+        self.file.write_all(&entry.encode())?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    pub(crate) fn is_bloated(&self, live: usize) -> bool {
+        is_bloated(self.entries, live)
+    }
+}
+
+pub(crate) fn index_path(directory: &Path) -> PathBuf {
+    directory.join(INDEX_FILE)
+}
+
+/// Whether a journal of `entries` has grown enough past the `live` entries of the index to be worth rewriting
+pub(crate) fn is_bloated(entries: u64, live: usize) -> bool {
+    entries > 2 * live as u64 + JOURNAL_SLACK
+}
+
+/// Reads the index file in `directory`, `None` if there isn't one. Entries are read up to the first one that's torn or
+/// fails its checksum
+pub(crate) fn read(directory: &Path) -> io::Result<Option<IndexContents>> {
+    let bytes = match std::fs::read(index_path(directory)) {
+        Ok(bytes) => bytes,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error),
+    };
+    let header = bytes.get(..HEADER_LEN as usize).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated index header"))?;
+    let word = |start: usize| u32::from_le_bytes(header[start..start + 4].try_into().expect("index header field"));
+    if word(0) != INDEX_MAGIC || word(4) != INDEX_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a streaming index"))
+    }
+
+    let mut contents = IndexContents {
+        next_sequence: u64::from_le_bytes(header[8..16].try_into().expect("index header field")),
+        next_pack: word(16),
+        ..Default::default()
+    };
+    for chunk in bytes[HEADER_LEN as usize..].chunks(ENTRY_LEN as usize) {
+        let entry = (chunk.len() == ENTRY_LEN as usize).then(|| IndexEntry::decode(chunk)).flatten();
+        match entry {
+            Some(entry) => contents.entries.push(entry),
+            None => {
+                contents.damaged = true;
+                break
+            },
+        }
+    }
+    Ok(Some(contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_read_back_up_to_damage() {
+        let dir = std::env::temp_dir().join(format!("hadron_streaming_index_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(read(&dir).unwrap(), None);
+
+        let entry = |sequence| IndexEntry { uid: UniqueId::get(), location: UnitLocation { pack: 1, offset: sequence * 100, len: 52, sequence }, tombstone: sequence == 2 };
+        let entries = [entry(0), entry(1)];
+        let mut writer = IndexWriter::snapshot(&dir, 2, 3, &entries).unwrap();
+        writer.append(&entry(2)).unwrap();
+        assert!(!writer.is_bloated(0));
+
+        let contents = read(&dir).unwrap().unwrap();
+        assert_eq!((contents.next_sequence, contents.next_pack, contents.entries.len(), contents.damaged), (2, 3, 3, false));
+        assert_eq!(contents.entries[..2], entries);
+        assert!(contents.entries[2].tombstone);
+
+        // A flipped bit in the second entry drops it and everything after
+        let mut bytes = std::fs::read(index_path(&dir)).unwrap();
+        bytes[(HEADER_LEN + ENTRY_LEN + 30) as usize] ^= 1;
+        std::fs::write(index_path(&dir), &bytes).unwrap();
+        let contents = read(&dir).unwrap().unwrap();
+        assert_eq!((contents.entries.len(), contents.damaged), (1, true));
+
+        std::fs::write(index_path(&dir), b"HIDX").unwrap();
+        assert!(read(&dir).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,146 @@
+//!
+//! Corruption handling for streamed data
+//!
+//! Every record is checksummed when written and checked when loaded. What happens to a unit that fails the check is up
+//! to the store's `CorruptionPolicy`, and every failure is logged as structured state so corrupt packs can be tracked
+//! down from the logs. A pack found damaged while rebuilding the index is quarantined, moved aside with its intact
+//! records copied back, and logged the same way
+//!
+
+use serde::{Serialize, Deserialize};
+
+use crate::debug::log;
+use crate::unique::UniqueId;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionPolicy {
+    /// Return `StreamingError::Corrupt`
+    Fail,
+    /// Read the record again up to this many times before failing, in case the bad read was transient
+    Retry(u32),
+    /// Hand out the store's placeholder data instead, see `Streaming::set_placeholder`
+    LoadPlaceholder,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionKind {
+    /// The header at the unit's location couldn't be parsed
+    BadHeader,
+    /// The header belongs to a different unit or a removal
+    WrongRecord,
+    Checksum { expected: u32, actual: u32 },
+}
+
+/// What was done about a corrupt read
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionAction {
+    Retried,
+    Failed,
+    Placeholder,
+    /// The pack was found damaged while rebuilding the index and moved aside
+    Quarantined,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorruptionReport {
+    /// `None` for a quarantined pack, where the damaged record's uid can't be trusted
+    pub uid: Option<UniqueId>,
+    pub pack: u32,
+    pub offset: u64,
+    pub kind: CorruptionKind,
+    /// Starts at 1
+    pub attempt: u32,
+    pub action: CorruptionAction,
+}
+
+// Impls
+
+impl Default for CorruptionPolicy {
+    fn default() -> Self {
+        CorruptionPolicy::Retry(2)
+    }
+}
+
+impl CorruptionPolicy {
+    /// What to do about the `attempt`th failed read of a unit
+    pub fn action(&self, attempt: u32) -> CorruptionAction {
+        match self {
+            CorruptionPolicy::Fail => CorruptionAction::Failed,
+            CorruptionPolicy::Retry(attempts) if attempt <= *attempts => CorruptionAction::Retried,
+            CorruptionPolicy::Retry(_) => CorruptionAction::Failed,
+            CorruptionPolicy::LoadPlaceholder => CorruptionAction::Placeholder,
+        }
+    }
+}
+
+impl CorruptionReport {
+    pub(crate) fn log(&self) {
+        match self.uid {
+            Some(uid) => log::get().state(format!("corrupt streaming unit {}", uid), self),
+            None => log::get().state(format!("corrupt streaming pack {}", self.pack), self),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{pack, IoBackend, Streaming, StreamingConfig, StreamingError};
+
+    #[test]
+    fn corrupt_units_follow_the_policy() {
+        let dir = std::env::temp_dir().join(format!("hadron_streaming_integrity_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let uid = UniqueId::get();
+        let config = StreamingConfig::default().with_io_backend(IoBackend::Buffered);
+        {
+            let streaming = Streaming::open_with(&dir, config).unwrap();
+            streaming.store(uid, &[1u8; 32]).unwrap();
+        }
+
+        // Flip a bit in the unit's data
+        let path = pack::pack_path(&dir, 0);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[pack::HEADER_LEN as usize + 8] ^= 0x04;
+        std::fs::write(&path, bytes).unwrap();
+
+        for policy in [CorruptionPolicy::Fail, CorruptionPolicy::Retry(2)] {
+            let streaming = Streaming::open_with(&dir, config.with_corruption_policy(policy)).unwrap();
+            assert!(matches!(streaming.load(uid), Err(StreamingError::Corrupt { pack: 0, offset: 0 })));
+        }
+
+        let streaming = Streaming::open_with(&dir, config.with_corruption_policy(CorruptionPolicy::LoadPlaceholder)).unwrap();
+        streaming.set_placeholder(b"missing");
+        let loaded = streaming.read(uid).unwrap();
+        assert!(loaded.is_placeholder());
+        assert_eq!(&*loaded, b"missing");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn corrupt_lengths_follow_the_policy() {
+        let dir = std::env::temp_dir().join(format!("hadron_streaming_integrity_len_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let uid = UniqueId::get();
+        {
+            let streaming = Streaming::open(&dir).unwrap();
+            streaming.store(uid, &[1u8; 32]).unwrap();
+        }
+
+        // Flip a high bit of the record's length, which would ask for exabytes if trusted
+        let path = pack::pack_path(&dir, 0);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[39] ^= 0x40;
+        std::fs::write(&path, bytes).unwrap();
+
+        for backend in [IoBackend::Buffered, IoBackend::Mapped] {
+            let config = StreamingConfig::default().with_io_backend(backend);
+            let streaming = Streaming::open_with(&dir, config.with_corruption_policy(CorruptionPolicy::Retry(1))).unwrap();
+            assert!(matches!(streaming.load(uid), Err(StreamingError::Corrupt { pack: 0, offset: 0 })));
+
+            let streaming = Streaming::open_with(&dir, config.with_corruption_policy(CorruptionPolicy::LoadPlaceholder)).unwrap();
+            assert!(streaming.read(uid).unwrap().is_placeholder());
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,8 +1,36 @@
-use std::{path::PathBuf, sync::Mutex};
+//!
+//! Streaming of unit data to and from disk
+//!
+//! Units are opaque byte blobs identified by a `UniqueId`, stored in append-only pack files (see `pack`). The
+//! `StreamingIndex` maps every live unit to its newest record, and is persisted beside the packs so opening a store
+//! doesn't mean scanning them (see `index`).
+//! Overwrites and removals leave garbage behind in older packs, reclaimed by `compaction`. Reads go through the buffered
+//! or memory-mapped backend in `reader`, and records that fail their checksum are dealt with as `integrity` describes
+//!
+
+use std::{collections::{BTreeMap, HashMap}, io, path::{Path, PathBuf}, sync::{Arc, Mutex, MutexGuard, RwLock}};
 
 use serde::{Serialize, Deserialize};
 
-use crate::unique::UniqueId;
+use crate::{unique::UniqueId, vfs::Vfs, debug::log};
+
+pub mod compaction;
+pub mod component;
+pub mod index;
+pub mod integrity;
+pub mod reader;
+pub(crate) mod pack;
+
+pub use compaction::{CompactionPolicy, CompactionReport, Compactor};
+pub use component::{ComponentStore, Streamed, StreamedComponent};
+pub use index::{IndexStatus, RebuildReason};
+pub use integrity::{CorruptionPolicy, CorruptionReport};
+pub use reader::{IoBackend, PackResidency, UnitBytes};
+
+use index::{IndexContents, IndexEntry, IndexWriter};
+use pack::{PackWriter, RecordHeader};
+use integrity::{CorruptionAction, CorruptionKind};
+use reader::PackReader;
 
 /// Represents one unit of streamable data that can be shuffled to and from the disk
 #[derive(Serialize, Deserialize, Debug)]
@@ -13,36 +41,680 @@ struct StreamingUnit<T> {
     data: Mutex<T>,
 }
 
-impl<T> StreamingUnit<T> {
+/// Where a unit's newest record lives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnitLocation {
+    pub pack: u32,
+    pub offset: u64,
+    /// Length of the unit's data, excluding the record header
+    pub len: u64,
+    pub sequence: u64,
+}
 
+/// Maps units to their records
+#[derive(Debug, Default)]
+pub struct StreamingIndex {
+    units: HashMap<UniqueId, UnitLocation>,
+    /// Removal records, kept while older records for the unit may still exist in other packs
+    tombstones: HashMap<UniqueId, UnitLocation>,
 }
 
-struct StreamingIndex<T>(T) where T: PartialEq + Eq;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StreamingConfig {
+    /// Packs roll over to a new file once they reach this size
+    pub max_pack_size: u64,
+    pub compaction: CompactionPolicy,
+    pub io_backend: IoBackend,
+    pub corruption: CorruptionPolicy,
+}
+
+/// How much of the on-disk data is garbage
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FragmentationStats {
+    pub packs: usize,
+    pub total_bytes: u64,
+    pub live_bytes: u64,
+}
 
-impl<T: PartialEq + Eq> StreamingIndex<T> {
+#[derive(Debug)]
+pub enum StreamingError {
+    Io(io::Error),
+    NotFound(UniqueId),
+    /// A record failed its checksum or didn't hold the unit the index said it did
+    Corrupt { pack: u32, offset: u64 },
+    /// A streamed component couldn't be converted to or from its stored bytes
+    Encoding(UniqueId, String),
+}
 
+#[derive(Debug, Clone, Copy, Default)]
+struct PackInfo {
+    total: u64,
+    live: u64,
 }
 
-struct Streaming {
-    // a fixed unit size?
-    // a fixed number of loaded units?
-    // soft caps instead?
-    // a priority queue of units that want to be loaded
-    // a priority queue of units to be unloaded
-    // heirarchical and spatial heuristics
-    // can systems deal with not being able to load the data at that moment? notify when?
+struct StreamingInternal {
+    index: StreamingIndex,
+    packs: BTreeMap<u32, PackInfo>,
+    writer: Option<PackWriter>,
+    next_pack: u32,
+    next_sequence: u64,
+    /// The index file, `None` if it couldn't be written, leaving the next open to scan the packs
+    journal: Option<IndexWriter>,
 }
 
 // should be able to just hand off data to the streaming system and it be mostly automatic
 // need prediction to make it work smoothly?
-struct StreamingInternal {
+// a priority queue of units that want to be loaded, a priority queue of units to be unloaded
+// heirarchical and spatial heuristics
+/// A store of streaming units backed by a directory of pack files
+pub struct Streaming {
+    directory: PathBuf,
+    config: StreamingConfig,
+    index_status: IndexStatus,
+    reader: PackReader,
+    /// Handed out for corrupt units under `CorruptionPolicy::LoadPlaceholder`
+    placeholder: RwLock<Arc<[u8]>>,
+    internal: Mutex<StreamingInternal>,
+    /// Held for the duration of a compaction so only one runs at a time
+    compacting: Mutex<()>,
+}
+
+// Impls
+
+impl std::error::Error for StreamingError {}
+
+impl std::fmt::Display for StreamingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamingError::Io(error) => write!(f, "streaming io error: {}", error),
+            StreamingError::NotFound(uid) => write!(f, "no streaming unit {}", uid),
+            StreamingError::Corrupt { pack, offset } => write!(f, "corrupt record in pack {} at offset {}", pack, offset),
+            StreamingError::Encoding(uid, error) => write!(f, "unable to encode streaming unit {}: {}", uid, error),
+        }
+    }
+}
+
+impl From<io::Error> for StreamingError {
+    fn from(error: io::Error) -> Self {
+        StreamingError::Io(error)
+    }
+}
+
+impl StreamingIndex {
+    pub fn get(&self, uid: UniqueId) -> Option<UnitLocation> {
+        self.units.get(&uid).copied()
+    }
+
+    pub fn contains(&self, uid: UniqueId) -> bool {
+        self.units.contains_key(&uid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.units.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.units.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (UniqueId, UnitLocation)> + '_ {
+        self.units.iter().map(|(uid, location)| (*uid, *location))
+    }
+
+    /// Records `header` at `location` if it's newer than what the index already has. Returns the location it replaced
+    fn apply(&mut self, header: &RecordHeader, location: UnitLocation) -> Option<UnitLocation> {
+        self.place(IndexEntry { uid: header.uid, location, tombstone: header.tombstone })
+    }
 
+    fn place(&mut self, entry: IndexEntry) -> Option<UnitLocation> {
+        let newest = self.units.get(&entry.uid).or(self.tombstones.get(&entry.uid)).map_or(0, |current| current.sequence);
+        if entry.location.sequence < newest {
+            return None
+        }
+        let replaced = self.units.remove(&entry.uid).or_else(|| self.tombstones.remove(&entry.uid));
+        match entry.tombstone {
+            true => self.tombstones.insert(entry.uid, entry.location),
+            false => self.units.insert(entry.uid, entry.location),
+        };
+        replaced
+    }
+
+    /// Every unit and tombstone
+    fn entries(&self) -> impl Iterator<Item = IndexEntry> + '_ {
+        self.units.iter().map(|(uid, location)| IndexEntry { uid: *uid, location: *location, tombstone: false })
+            .chain(self.tombstones.iter().map(|(uid, location)| IndexEntry { uid: *uid, location: *location, tombstone: true }))
+    }
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        StreamingConfig {
+            max_pack_size: 64 * 1024 * 1024,
+            compaction: CompactionPolicy::default(),
+            io_backend: IoBackend::platform_default(),
+            corruption: CorruptionPolicy::default(),
+        }
+    }
+}
+
+impl StreamingConfig {
+    pub fn with_max_pack_size(mut self, bytes: u64) -> Self {
+        self.max_pack_size = bytes;
+        self
+    }
+
+    pub fn with_compaction(mut self, policy: CompactionPolicy) -> Self {
+        self.compaction = policy;
+        self
+    }
+
+    pub fn with_io_backend(mut self, backend: IoBackend) -> Self {
+        self.io_backend = backend;
+        self
+    }
+
+    pub fn with_corruption_policy(mut self, policy: CorruptionPolicy) -> Self {
+        self.corruption = policy;
+        self
+    }
+}
+
+impl FragmentationStats {
+    /// Fraction of the on-disk bytes that are garbage
+    pub fn garbage_ratio(&self) -> f64 {
+        if self.total_bytes == 0 { 0.0 } else { 1.0 - self.live_bytes as f64 / self.total_bytes as f64 }
+    }
+}
+
+impl PackInfo {
+    fn garbage(&self) -> u64 {
+        self.total.saturating_sub(self.live)
+    }
+}
+
+impl StreamingInternal {
+    fn new(pack_ids: &[u32]) -> Self {
+        StreamingInternal {
+            index: StreamingIndex::default(),
+            packs: BTreeMap::new(),
+            writer: None,
+            next_pack: pack_ids.last().map_or(0, |id| id + 1),
+            next_sequence: 0,
+            journal: None,
+        }
+    }
+
+    /// Builds the index by scanning every pack. A damaged pack is quarantined, keeping the records before the damage
+    fn rebuild(directory: &Path, pack_ids: &[u32]) -> Result<Self, StreamingError> {
+        let mut internal = StreamingInternal::new(pack_ids);
+        for id in pack_ids {
+            let path = pack::pack_path(directory, *id);
+            let scan = pack::scan(&path)?;
+            if let Some((offset, kind)) = scan.damage {
+                let quarantined = pack::quarantine(directory, *id, scan.valid_len)?;
+                log::get().with_topic("streaming").warn(format!("damaged record in {} at offset {}, moved the pack to {}", path.display(), offset, quarantined.display()));
+                CorruptionReport { uid: None, pack: *id, offset, kind, attempt: 1, action: CorruptionAction::Quarantined }.log();
+                if scan.valid_len == 0 {
+                    continue
+                }
+            }
+            internal.apply_records(*id, scan.records);
+            internal.add_pack(&path, *id, scan.valid_len)?;
+        }
+        internal.count_live();
+        Ok(internal)
+    }
+
+    /// Picks the index up from the index file, scanning each pack only past the last record the file covers. Returns
+    /// the records found that way, or `None` if the file doesn't match the packs on disk or a pack's tail is damaged
+    fn restore(directory: &Path, pack_ids: &[u32], contents: IndexContents) -> Result<Option<(Self, usize)>, StreamingError> {
+        let mut internal = StreamingInternal::new(pack_ids);
+        internal.next_pack = internal.next_pack.max(contents.next_pack);
+        internal.next_sequence = contents.next_sequence;
+        let mut covered: HashMap<u32, u64> = HashMap::new();
+        for entry in &contents.entries {
+            internal.index.place(*entry);
+            internal.next_sequence = internal.next_sequence.max(entry.location.sequence + 1);
+            internal.next_pack = internal.next_pack.max(entry.location.pack + 1);
+            let end = covered.entry(entry.location.pack).or_default();
+            *end = (*end).max(entry.end());
+        }
+        if internal.index.entries().any(|entry| pack_ids.binary_search(&entry.location.pack).is_err()) {
+            return Ok(None)
+        }
+
+        let mut caught_up = 0;
+        for id in pack_ids {
+            let path = pack::pack_path(directory, *id);
+            let len = std::fs::metadata(&path)?.len();
+            let start = covered.get(id).copied().unwrap_or(0);
+            if len < start {
+                return Ok(None)
+            }
+            let valid_len = match len > start {
+                true => {
+                    let scan = pack::scan_from(&path, start)?;
+                    if scan.damage.is_some() {
+                        return Ok(None)
+                    }
+                    caught_up += scan.records.len();
+                    internal.apply_records(*id, scan.records);
+                    scan.valid_len
+                },
+                false => len,
+            };
+            internal.add_pack(&path, *id, valid_len)?;
+        }
+        internal.count_live();
+
+        if !contents.damaged && caught_up == 0 && !index::is_bloated(contents.entries.len() as u64, internal.index.units.len() + internal.index.tombstones.len()) {
+            internal.journal = Some(IndexWriter::open(directory, contents.entries.len() as u64)?);
+        }
+        Ok(Some((internal, caught_up)))
+    }
+
+    fn apply_records(&mut self, pack: u32, records: Vec<(u64, RecordHeader)>) {
+        for (offset, header) in records {
+            self.next_sequence = self.next_sequence.max(header.sequence + 1);
+            self.index.apply(&header, UnitLocation { pack, offset, len: header.len, sequence: header.sequence });
+        }
+    }
+
+    /// Adds a pack whose records end at `valid_len`, truncating a torn write past it and deleting the pack if that's
+    /// all it holds. A damaged pack is quarantined first, so nothing past `valid_len` is ever a record
+    fn add_pack(&mut self, path: &Path, id: u32, valid_len: u64) -> io::Result<()> {
+        if valid_len == 0 {
+            return std::fs::remove_file(path)
+        }
+        if valid_len < std::fs::metadata(path)?.len() {
+            std::fs::OpenOptions::new().write(true).open(path)?.set_len(valid_len)?;
+        }
+        self.packs.insert(id, PackInfo { total: valid_len, live: 0 });
+        Ok(())
+    }
+
+    /// Totals the live bytes of each pack from the index
+    fn count_live(&mut self) {
+        let live = self.index.entries().map(|entry| (entry.location.pack, pack::HEADER_LEN + entry.location.len)).collect::<Vec<_>>();
+        for (pack, bytes) in live {
+            if let Some(info) = self.packs.get_mut(&pack) {
+                info.live += bytes;
+            }
+        }
+    }
+
+    /// Adds `entry` to the index file, rewriting the file once it's mostly superseded entries
+    fn journal(&mut self, directory: &Path, entry: IndexEntry) {
+        let Some(journal) = self.journal.as_mut() else {
+            return
+        };
+        match journal.append(&entry) {
+            Ok(()) if journal.is_bloated(self.index.units.len() + self.index.tombstones.len()) => self.snapshot(directory),
+            Ok(()) => (),
+            Err(error) => {
+                log::get().with_topic("streaming").warn(format!("unable to update the streaming index in {}: {}", directory.display(), error));
+                self.journal = None;
+            },
+        }
+    }
+
+    /// Rewrites the index file from the index
+    fn snapshot(&mut self, directory: &Path) {
+        let entries: Vec<IndexEntry> = self.index.entries().collect();
+        self.journal = match IndexWriter::snapshot(directory, self.next_sequence, self.next_pack, &entries) {
+            Ok(journal) => Some(journal),
+            Err(error) => {
+                log::get().with_topic("streaming").warn(format!("unable to write the streaming index in {}: {}", directory.display(), error));
+                None
+            },
+        };
+    }
+
+    /// The pack new records go to, rolling over to a fresh one once the current pack is full
+    fn writer(&mut self, directory: &Path, max_pack_size: u64) -> io::Result<&mut PackWriter> {
+        if self.writer.as_ref().is_none_or(|writer| writer.len() >= max_pack_size) {
+            self.roll_writer(directory)?;
+        }
+        Ok(self.writer.as_mut().expect("pack writer"))
+    }
+
+    fn roll_writer(&mut self, directory: &Path) -> io::Result<()> {
+        let id = self.allocate_pack();
+        self.writer = Some(PackWriter::create(directory, id)?);
+        self.packs.insert(id, PackInfo::default());
+        Ok(())
+    }
+
+    fn allocate_pack(&mut self) -> u32 {
+        let id = self.next_pack;
+        self.next_pack += 1;
+        id
+    }
+
+    /// Updates pack accounting after the index replaced `old` with a record of `new_len` bytes in `pack`
+    fn account(&mut self, pack: u32, record_len: u64, live: bool, replaced: Option<UnitLocation>) {
+        let info = self.packs.entry(pack).or_default();
+        info.total += record_len;
+        if live {
+            info.live += record_len;
+        }
+        if let Some(old) = replaced {
+            if let Some(info) = self.packs.get_mut(&old.pack) {
+                info.live = info.live.saturating_sub(pack::HEADER_LEN + old.len);
+            }
+        }
+    }
 }
 
 impl Streaming {
-    pub fn request(uid: UniqueId) {
-        // request data given a UID using the index part of the UID to reference the streaming unit the data belongs to
-        // async?
+    pub fn open<P: Into<PathBuf>>(directory: P) -> Result<Self, StreamingError> {
+        Streaming::open_with(directory, StreamingConfig::default())
+    }
+
+    /// Opens a store at a virtual path, like `/cache/streaming`. The path has to be backed by a directory on disk
+    pub fn open_vfs(vfs: &Vfs, path: &str, config: StreamingConfig) -> Result<Self, StreamingError> {
+        let directory = vfs.real_path(path).map_err(io::Error::from)?;
+        Streaming::open_with(directory, config)
+    }
+
+    /// Opens or creates a store, reading the index file in `directory` or rebuilding the index from the packs if it's
+    /// missing or out of date. A torn header at the end of a pack, left by a crash mid-write, is truncated away,
+    /// while a pack with a damaged record is quarantined (see `integrity`) rather than rewritten or deleted
+    pub fn open_with<P: Into<PathBuf>>(directory: P, config: StreamingConfig) -> Result<Self, StreamingError> {
+        let directory = directory.into();
+        std::fs::create_dir_all(&directory)?;
+
+        let mut pack_ids: Vec<u32> = std::fs::read_dir(&directory)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| pack::pack_id(&entry.path()))
+            .collect();
+        pack_ids.sort_unstable();
+
+        let contents = match index::read(&directory) {
+            Ok(contents) => contents.ok_or(RebuildReason::Missing),
+            Err(error) => {
+                log::get().with_topic("streaming").warn(format!("unable to read the streaming index in {}: {}", directory.display(), error));
+                Err(RebuildReason::Unreadable)
+            },
+        };
+        let restored = match contents {
+            Ok(contents) => StreamingInternal::restore(&directory, &pack_ids, contents)?.ok_or(RebuildReason::Stale),
+            Err(reason) => Err(reason),
+        };
+        let (mut internal, index_status) = match restored {
+            Ok((internal, caught_up)) => (internal, IndexStatus::Loaded { caught_up }),
+            Err(reason) => {
+                if reason == RebuildReason::Stale {
+                    log::get().with_topic("streaming").warn(format!("the streaming index in {} is out of date, rebuilding it", directory.display()));
+                }
+                (StreamingInternal::rebuild(&directory, &pack_ids)?, IndexStatus::Rebuilt(reason))
+            },
+        };
+        if internal.journal.is_none() {
+            internal.snapshot(&directory);
+        }
+
+        Ok(Streaming {
+            directory,
+            index_status,
+            reader: PackReader::new(config.io_backend),
+            placeholder: RwLock::new(Arc::from(Vec::new())),
+            config,
+            internal: Mutex::new(internal),
+            compacting: Mutex::new(()),
+        })
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub fn config(&self) -> &StreamingConfig {
+        &self.config
+    }
+
+    /// How the index was built when the store was opened
+    pub fn index_status(&self) -> IndexStatus {
+        self.index_status
+    }
+
+    /// The backend reads go through, which may differ from the configured one if it isn't available
+    pub fn io_backend(&self) -> IoBackend {
+        self.reader.backend()
+    }
+
+    /// Touched pages of every mapped pack, empty with the buffered backend
+    pub fn residency(&self) -> Vec<PackResidency> {
+        self.reader.residency()
+    }
+
+    /// Sets the data loaded in place of corrupt units under `CorruptionPolicy::LoadPlaceholder`, empty by default
+    pub fn set_placeholder(&self, data: &[u8]) {
+        *self.placeholder.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::from(data);
+    }
+
+    /// Writes a unit, replacing any earlier data stored under `uid`
+    pub fn store(&self, uid: UniqueId, data: &[u8]) -> Result<UnitLocation, StreamingError> {
+        self.append(uid, data, false)
+    }
+
+    /// Removes a unit. Returns false if there was nothing stored under `uid`
+    pub fn remove(&self, uid: UniqueId) -> Result<bool, StreamingError> {
+        if !self.contains(uid) {
+            return Ok(false)
+        }
+        self.append(uid, &[], true)?;
+        Ok(true)
+    }
+
+    pub fn load(&self, uid: UniqueId) -> Result<Vec<u8>, StreamingError> {
+        self.read(uid).map(UnitBytes::into_vec)
+    }
+
+    /// Reads a unit without copying it when the mapped backend is in use. Corrupt units are handled according to the
+    /// configured `CorruptionPolicy`
+    pub fn read(&self, uid: UniqueId) -> Result<UnitBytes, StreamingError> {
+        let mut attempt = 0;
+        loop {
+            let (location, record) = self.read_record(uid)?;
+            let kind = match record {
+                Some((header, data)) if header.uid == uid && !header.tombstone => {
+                    let actual = header.compute_checksum(&data);
+                    if actual == header.checksum {
+                        return Ok(data)
+                    }
+                    CorruptionKind::Checksum { expected: header.checksum, actual }
+                },
+                Some(_) => CorruptionKind::WrongRecord,
+                None => CorruptionKind::BadHeader,
+            };
+
+            attempt += 1;
+            let action = self.config.corruption.action(attempt);
+            CorruptionReport { uid: Some(uid), pack: location.pack, offset: location.offset, kind, attempt, action }.log();
+            match action {
+                CorruptionAction::Retried => self.reader.evict(location.pack),
+                CorruptionAction::Failed | CorruptionAction::Quarantined => return Err(StreamingError::Corrupt { pack: location.pack, offset: location.offset }),
+                CorruptionAction::Placeholder => {
+                    let placeholder = self.placeholder.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+                    return Ok(UnitBytes::placeholder(Arc::clone(&placeholder)))
+                },
+            }
+        }
+    }
+
+    /// Reads the record the index holds for `uid`, `None` if its header is unreadable
+    fn read_record(&self, uid: UniqueId) -> Result<(UnitLocation, Option<(RecordHeader, UnitBytes)>), StreamingError> {
+        // Compaction may move the unit and delete its old pack between the lookup and the read, so look it up again once
+        let mut location = self.location(uid).ok_or(StreamingError::NotFound(uid))?;
+        loop {
+            match self.reader.read(&self.directory, location) {
+                Ok(record) => return Ok((location, Some(record))),
+                Err(error) => match self.location(uid) {
+                    Some(moved) if moved != location => location = moved,
+                    _ if error.kind() == io::ErrorKind::InvalidData => return Ok((location, None)),
+                    _ => return Err(error.into()),
+                },
+            }
+        }
+    }
+
+    pub fn contains(&self, uid: UniqueId) -> bool {
+        self.lock().index.contains(uid)
+    }
+
+    pub fn location(&self, uid: UniqueId) -> Option<UnitLocation> {
+        self.lock().index.get(uid)
+    }
+
+    /// Number of stored units
+    pub fn len(&self) -> usize {
+        self.lock().index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn fragmentation(&self) -> FragmentationStats {
+        let internal = self.lock();
+        FragmentationStats {
+            packs: internal.packs.len(),
+            total_bytes: internal.packs.values().map(|info| info.total).sum(),
+            live_bytes: internal.packs.values().map(|info| info.live).sum(),
+        }
+    }
+
+    fn append(&self, uid: UniqueId, data: &[u8], tombstone: bool) -> Result<UnitLocation, StreamingError> {
+        let mut internal = self.lock();
+        let sequence = internal.next_sequence;
+        internal.next_sequence += 1;
+
+        let header = RecordHeader::new(uid, sequence, data, tombstone);
+        let writer = internal.writer(&self.directory, self.config.max_pack_size)?;
+        let pack = writer.id();
+        let offset = writer.append(&header, data)?;
+
+        let location = UnitLocation { pack, offset, len: header.len, sequence };
+        let replaced = internal.index.apply(&header, location);
+        internal.account(pack, header.record_len(), true, replaced);
+        internal.journal(&self.directory, IndexEntry { uid, location, tombstone });
+        Ok(location)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, StreamingInternal> {
+        self.internal.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn units_survive_reopen_and_torn_writes() {
+        let dir = std::env::temp_dir().join(format!("hadron_streaming_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (a, b) = (UniqueId::get(), UniqueId::get());
+        {
+            let streaming = Streaming::open(&dir).unwrap();
+            streaming.store(a, b"first").unwrap();
+            streaming.store(b, b"other").unwrap();
+            streaming.store(a, b"second").unwrap();
+            assert!(streaming.remove(b).unwrap());
+            assert!(!streaming.remove(b).unwrap());
+            assert_eq!(streaming.load(a).unwrap(), b"second");
+            assert!(streaming.fragmentation().garbage_ratio() > 0.0);
+        }
+
+        // Half a header at the end of the pack, as if the process died mid-write
+        let pack = pack::pack_path(&dir, 0);
+        let mut bytes = std::fs::read(&pack).unwrap();
+        bytes.extend_from_slice(&[0x48, 0x53, 0x54]);
+        std::fs::write(&pack, bytes).unwrap();
+
+        let streaming = Streaming::open(&dir).unwrap();
+        assert_eq!(streaming.load(a).unwrap(), b"second");
+        assert!(matches!(streaming.load(b), Err(StreamingError::NotFound(_))));
+        assert_eq!(streaming.len(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn damaged_packs_are_quarantined_by_the_rebuild() {
+        let dir = std::env::temp_dir().join(format!("hadron_streaming_damaged_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let uids = [UniqueId::get(), UniqueId::get(), UniqueId::get()];
+        {
+            let streaming = Streaming::open(&dir).unwrap();
+            for uid in uids {
+                streaming.store(uid, &[5u8; 64]).unwrap();
+            }
+        }
+        let pack = pack::pack_path(&dir, 0);
+        let intact = std::fs::read(&pack).unwrap();
+        let record_len = pack::HEADER_LEN as usize + 64;
+
+        // The high byte of the first record's length, sending it far past the end of the pack
+        let mut bytes = intact.clone();
+        bytes[39] ^= 0x40;
+        std::fs::write(&pack, &bytes).unwrap();
+        std::fs::remove_file(index::index_path(&dir)).unwrap();
+
+        let streaming = Streaming::open(&dir).unwrap();
+        assert_eq!(streaming.len(), 0);
+        assert!(!pack.exists());
+        assert_eq!(std::fs::read(dir.join("00000000.pack.0.quarantine")).unwrap(), bytes);
+        drop(streaming);
+
+        // A flipped bit in the second record's data keeps the first record readable
+        let mut bytes = intact.clone();
+        bytes[record_len + pack::HEADER_LEN as usize] ^= 0x01;
+        std::fs::write(&pack, &bytes).unwrap();
+        std::fs::remove_file(index::index_path(&dir)).unwrap();
+
+        let streaming = Streaming::open(&dir).unwrap();
+        assert_eq!(streaming.load(uids[0]).unwrap(), [5u8; 64]);
+        assert!(matches!(streaming.load(uids[1]), Err(StreamingError::NotFound(_))));
+        assert_eq!(std::fs::read(&pack).unwrap(), intact[..record_len]);
+        assert_eq!(std::fs::read(dir.join("00000000.pack.1.quarantine")).unwrap(), bytes);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn index_file_spares_the_scan() {
+        let dir = std::env::temp_dir().join(format!("hadron_streaming_index_file_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let units: Vec<UniqueId> = (0..4).map(|_| UniqueId::get()).collect();
+        {
+            let streaming = Streaming::open(&dir).unwrap();
+            assert_eq!(streaming.index_status(), IndexStatus::Rebuilt(RebuildReason::Missing));
+            for uid in &units {
+                streaming.store(*uid, uid.to_bytes().as_slice()).unwrap();
+            }
+            streaming.remove(units[3]).unwrap();
+        }
+        let reopened = Streaming::open(&dir).unwrap();
+        assert_eq!(reopened.index_status(), IndexStatus::Loaded { caught_up: 0 });
+        assert_eq!((reopened.len(), reopened.contains(units[3])), (3, false));
+        drop(reopened);
+
+        // Losing the last two entries, as if the process died before they were written, only rescans the pack's tail
+        let index = index::index_path(&dir);
+        let bytes = std::fs::read(&index).unwrap();
+        std::fs::write(&index, &bytes[..bytes.len() - 56 - 20]).unwrap();
+        let reopened = Streaming::open(&dir).unwrap();
+        assert_eq!(reopened.index_status(), IndexStatus::Loaded { caught_up: 2 });
+        assert_eq!(reopened.load(units[2]).unwrap(), units[2].to_bytes());
+        assert!(!reopened.contains(units[3]));
+        drop(reopened);
+
+        // An index pointing into a pack that's gone is rebuilt from what's left
+        std::fs::copy(pack::pack_path(&dir, 0), pack::pack_path(&dir, 1)).unwrap();
+        std::fs::remove_file(pack::pack_path(&dir, 0)).unwrap();
+        let reopened = Streaming::open(&dir).unwrap();
+        assert_eq!(reopened.index_status(), IndexStatus::Rebuilt(RebuildReason::Stale));
+        assert_eq!(reopened.load(units[0]).unwrap(), units[0].to_bytes());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,334 @@
+//!
+//! Pack files, the on-disk storage behind streaming
+//!
+//! A pack is an append-only sequence of records, each a fixed size header followed by the unit's bytes. Overwriting or
+//! removing a unit appends a new record and leaves the old one behind as garbage, which compaction later reclaims.
+//! Records carry a global sequence number, so the newest record for a unit wins no matter which pack it ended up in.
+//! A CRC32 over the header fields and data is checked on load to catch corruption of data at rest
+//!
+
+use std::{fs::{File, OpenOptions}, io::{self, Read, Seek, SeekFrom, Write, BufReader}, path::{Path, PathBuf}};
+
+use crate::unique::UniqueId;
+
+use super::integrity::CorruptionKind;
+
+const RECORD_MAGIC: u32 = u32::from_le_bytes(*b"HST2");
+const FLAG_TOMBSTONE: u32 = 1;
+
+/// Size of a record header in bytes
+pub(crate) const HEADER_LEN: u64 = 48;
+
+/// Header bytes covered by the checksum, everything before the checksum itself
+const CHECKSUMMED_LEN: usize = 40;
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RecordHeader {
+    pub(crate) uid: UniqueId,
+    pub(crate) sequence: u64,
+    pub(crate) len: u64,
+    /// Marks the unit as removed, a tombstone has no data
+    pub(crate) tombstone: bool,
+    pub(crate) checksum: u32,
+}
+
+/// The records found by scanning a pack
+#[derive(Debug)]
+pub(crate) struct Scan {
+    /// Every intact record up to `valid_len`, by offset
+    pub(crate) records: Vec<(u64, RecordHeader)>,
+    /// Where the intact records end
+    pub(crate) valid_len: u64,
+    /// The record the scan stopped at, if it stopped at a damaged one rather than the end of the pack
+    pub(crate) damage: Option<(u64, CorruptionKind)>,
+}
+
+/// Appends records to one pack file
+pub(crate) struct PackWriter {
+    id: u32,
+    file: File,
+    len: u64,
+}
+
+// Impls
+
+impl RecordHeader {
+    pub(crate) fn new(uid: UniqueId, sequence: u64, data: &[u8], tombstone: bool) -> Self {
+        let mut header = RecordHeader { uid, sequence, len: data.len() as u64, tombstone, checksum: 0 };
+        header.checksum = header.compute_checksum(data);
+        header
+    }
+
+    /// True if `data` and the header fields match the stored checksum
+    pub(crate) fn verify(&self, data: &[u8]) -> bool {
+        self.compute_checksum(data) == self.checksum
+    }
+
+    pub(crate) fn compute_checksum(&self, data: &[u8]) -> u32 {
+        crc32(crc32(0, &self.encode()[..CHECKSUMMED_LEN]), data)
+    }
+
+    fn encode(&self) -> [u8; HEADER_LEN as usize] {
+        let mut bytes = [0u8; HEADER_LEN as usize];
+        bytes[0..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&(if self.tombstone { FLAG_TOMBSTONE } else { 0 }).to_le_bytes());
+        bytes[8..24].copy_from_slice(&self.uid.to_bytes());
+        bytes[24..32].copy_from_slice(&self.sequence.to_le_bytes());
+        bytes[32..40].copy_from_slice(&self.len.to_le_bytes());
+        bytes[40..44].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes
+    }
+
+    pub(crate) fn decode(bytes: &[u8; HEADER_LEN as usize]) -> Option<Self> {
+        if u32::from_le_bytes(field(bytes, 0)) != RECORD_MAGIC {
+            return None
+        }
+        Some(RecordHeader {
+            tombstone: u32::from_le_bytes(field(bytes, 4)) & FLAG_TOMBSTONE != 0,
+            uid: UniqueId::from_bytes(field(bytes, 8)),
+            sequence: u64::from_le_bytes(field(bytes, 24)),
+            len: u64::from_le_bytes(field(bytes, 32)),
+            checksum: u32::from_le_bytes(field(bytes, 40)),
+        })
+    }
+
+    /// Bytes the record takes up in its pack
+    pub(crate) fn record_len(&self) -> u64 {
+        HEADER_LEN + self.len
+    }
+}
+
+impl PackWriter {
+    pub(crate) fn create(directory: &Path, id: u32) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(pack_path(directory, id))?;
+        let len = file.metadata()?.len();
+        Ok(PackWriter { id, file, len })
+    }
+
+    pub(crate) fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Appends a record, returning its offset in the pack
+    pub(crate) fn append(&mut self, header: &RecordHeader, data: &[u8]) -> io::Result<u64> {
+        debug_assert_eq!(header.len, data.len() as u64);
+        let offset = self.len;
+        let mut record = Vec::with_capacity(header.record_len() as usize);
+        record.extend_from_slice(&header.encode());
+        record.extend_from_slice(data);
+        self.file.write_all(&record)?;
+        self.len += record.len() as u64;
+        Ok(offset)
+    }
+
+    /// Flushes the pack to disk
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// CRC32 (IEEE) of `bytes`, continuing from `seed`
+pub(crate) fn crc32(seed: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !seed;
+    for byte in bytes {
+        crc = CRC_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xEDB8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// The `N` bytes of a header starting at `start`
+fn field<const N: usize>(bytes: &[u8; HEADER_LEN as usize], start: usize) -> [u8; N] {
+    bytes[start..start + N].try_into().expect("header field out of range")
+}
+
+pub(crate) fn pack_path(directory: &Path, id: u32) -> PathBuf {
+    directory.join(format!("{:08}.pack", id))
+}
+
+/// Pack id from a pack file's name
+pub(crate) fn pack_id(path: &Path) -> Option<u32> {
+    if path.extension()? != "pack" {
+        return None
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Moves a damaged pack aside under a name `pack_id` skips, copying the records before `valid_len` back into its
+/// place. Nothing is deleted, and the returned path holds the pack exactly as it was found
+pub(crate) fn quarantine(directory: &Path, id: u32, valid_len: u64) -> io::Result<PathBuf> {
+    let path = pack_path(directory, id);
+    let mut attempt = 0;
+    let quarantined = loop {
+        let candidate = directory.join(format!("{:08}.pack.{}.quarantine", id, attempt));
+        if !candidate.exists() {
+            break candidate
+        }
+        attempt += 1;
+    };
+    std::fs::rename(&path, &quarantined)?;
+    if valid_len > 0 {
+        let mut intact = File::open(&quarantined)?.take(valid_len);
+        let mut copy = File::create(&path)?;
+        io::copy(&mut intact, &mut copy)?;
+        copy.sync_all()?;
+    }
+    Ok(quarantined)
+}
+
+/// Reads the record at `offset`, which the index says holds `len` bytes of data. A header disagreeing with that, or
+/// running past the end of the pack, is `InvalidData` rather than trusted with an allocation
+pub(crate) fn read_record(path: &Path, offset: u64, len: u64) -> io::Result<(RecordHeader, Vec<u8>)> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut header = [0u8; HEADER_LEN as usize];
+    file.read_exact(&mut header)?;
+    let header = RecordHeader::decode(&header).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad record header"))?;
+    let end = HEADER_LEN.checked_add(header.len).and_then(|record_len| offset.checked_add(record_len));
+    if header.len != len || end.is_none_or(|end| end > file_len) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("record length {} doesn't match the index's {}", header.len, len)))
+    }
+
+    let mut data = vec![0u8; header.len as usize];
+    file.read_exact(&mut data)?;
+    Ok((header, data))
+}
+
+/// Lists the records in a pack by offset, checking each one's checksum. Only a tail too short to hold a header, or
+/// zeroes to the end of the file, counts as a torn write that can be truncated away at `valid_len`. A record that
+/// doesn't decode, runs past the end of the pack or fails its checksum stops the scan as damage instead
+pub(crate) fn scan(path: &Path) -> io::Result<Scan> {
+    scan_from(path, 0)
+}
+
+/// Lists the records from `start` on, which has to be the offset of a record or the end of one, see `scan`
+pub(crate) fn scan_from(path: &Path, start: u64) -> io::Result<Scan> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(start))?;
+    let mut reader = BufReader::new(file);
+
+    let mut records = Vec::new();
+    let mut offset = start;
+    let mut header = [0u8; HEADER_LEN as usize];
+    let mut data = Vec::new();
+    let damage = loop {
+        if offset + HEADER_LEN > file_len {
+            break None
+        }
+        reader.read_exact(&mut header)?;
+        let Some(decoded) = RecordHeader::decode(&header) else {
+            // Some filesystems zero fill the tail of a file after a crash
+            if header.iter().all(|byte| *byte == 0) && is_zero_to_end(&mut reader)? {
+                break None
+            }
+            break Some((offset, CorruptionKind::BadHeader))
+        };
+        // Checked against the file before reading, so a damaged length never decides an allocation
+        let Some(end) = decoded.len.checked_add(offset + HEADER_LEN).filter(|end| *end <= file_len) else {
+            break Some((offset, CorruptionKind::BadHeader))
+        };
+        data.resize(decoded.len as usize, 0);
+        reader.read_exact(&mut data)?;
+        let actual = decoded.compute_checksum(&data);
+        if actual != decoded.checksum {
+            break Some((offset, CorruptionKind::Checksum { expected: decoded.checksum, actual }))
+        }
+        records.push((offset, decoded));
+        offset = end;
+    };
+    Ok(Scan { records, valid_len: offset, damage })
+}
+
+fn is_zero_to_end(reader: &mut impl Read) -> io::Result<bool> {
+    let mut buffer = [0u8; 4096];
+    loop {
+        match reader.read(&mut buffer)? {
+            0 => return Ok(true),
+            read if buffer[..read].iter().any(|byte| *byte != 0) => return Ok(false),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_catches_flipped_bits() {
+        assert_eq!(crc32(0, b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xCBF4_3926);
+
+        let mut data = vec![7u8; 100];
+        let header = RecordHeader::new(UniqueId::get(), 3, &data, false);
+        let decoded = RecordHeader::decode(&header.encode()).unwrap();
+        assert_eq!(decoded, header);
+        assert!(decoded.verify(&data));
+
+        data[50] ^= 0x10;
+        assert!(!decoded.verify(&data));
+        assert!(!RecordHeader { sequence: 4, ..header }.verify(&[7u8; 100]));
+    }
+
+    #[test]
+    fn scans_stop_at_damage_without_calling_it_torn() {
+        let dir = std::env::temp_dir().join(format!("hadron_streaming_scan_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut writer = PackWriter::create(&dir, 0).unwrap();
+        for sequence in 0..3 {
+            writer.append(&RecordHeader::new(UniqueId::get(), sequence, &[sequence as u8; 16], false), &[sequence as u8; 16]).unwrap();
+        }
+        let path = pack_path(&dir, 0);
+        let intact = std::fs::read(&path).unwrap();
+        let record_len = HEADER_LEN as usize + 16;
+
+        // A partial header or zeroes past the last record are a torn write
+        for tail in [&[0x48u8, 0x53, 0x54][..], &[0u8; 100][..]] {
+            std::fs::write(&path, [&intact[..], tail].concat()).unwrap();
+            let scanned = scan(&path).unwrap();
+            assert_eq!((scanned.records.len(), scanned.valid_len, scanned.damage), (3, intact.len() as u64, None));
+        }
+
+        // A flipped bit in the first record's length runs it past the end of the pack
+        let mut bytes = intact.clone();
+        bytes[39] ^= 0x01;
+        std::fs::write(&path, &bytes).unwrap();
+        let scanned = scan(&path).unwrap();
+        assert_eq!((scanned.records.len(), scanned.damage), (0, Some((0, CorruptionKind::BadHeader))));
+
+        // A flipped bit in the second record's data fails its checksum
+        let mut bytes = intact.clone();
+        bytes[record_len + HEADER_LEN as usize] ^= 0x01;
+        std::fs::write(&path, &bytes).unwrap();
+        let scanned = scan(&path).unwrap();
+        assert_eq!((scanned.records.len(), scanned.valid_len), (1, record_len as u64));
+        assert!(matches!(scanned.damage, Some((offset, CorruptionKind::Checksum { .. })) if offset == record_len as u64));
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
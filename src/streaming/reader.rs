@@ -0,0 +1,275 @@
+//!
+//! How pack records are read back from disk
+//!
+//! The buffered backend reads every unit into a fresh buffer. With the `mmap` feature, the mapped backend instead maps
+//! each pack once and hands out views straight into the mapping, so large read-mostly units are never copied and the
+//! OS pages them in on demand. Touched pages are tracked per pack to give a picture of what's resident
+//!
+
+use std::{io, ops::Deref, path::Path, sync::Arc};
+
+#[cfg(feature = "mmap")]
+use std::{collections::HashMap, ops::Range, sync::Mutex};
+
+use serde::{Serialize, Deserialize};
+
+use crate::debug::log;
+
+use super::{pack::{self, RecordHeader}, UnitLocation};
+
+/// Granularity of residency tracking
+#[cfg(feature = "mmap")]
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoBackend {
+    /// Reads records into owned buffers
+    Buffered,
+    /// Maps packs into memory, requires the `mmap` feature and falls back to `Buffered` without it
+    Mapped,
+}
+
+/// A loaded unit's bytes, either owned, borrowed from a mapped pack or the store's placeholder
+pub struct UnitBytes(Repr);
+
+enum Repr {
+    Owned(Vec<u8>),
+    Placeholder(Arc<[u8]>),
+    #[cfg(feature = "mmap")]
+    Mapped(Arc<memmap2::Mmap>, Range<usize>),
+}
+
+/// How much of a mapped pack has been touched
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackResidency {
+    pub pack: u32,
+    pub mapped_bytes: u64,
+    pub pages: usize,
+    pub touched_pages: usize,
+}
+
+#[cfg(feature = "mmap")]
+struct MappedPack {
+    map: Arc<memmap2::Mmap>,
+    /// One bit per page
+    touched: Vec<u64>,
+}
+
+/// Reads records with the configured backend
+pub(crate) struct PackReader {
+    backend: IoBackend,
+    #[cfg(feature = "mmap")]
+    maps: Mutex<HashMap<u32, MappedPack>>,
+}
+
+// Impls
+
+impl Default for IoBackend {
+    fn default() -> Self {
+        IoBackend::platform_default()
+    }
+}
+
+impl IoBackend {
+    /// Mapped on 64 bit targets built with `mmap`, where address space is plentiful, buffered everywhere else
+    pub fn platform_default() -> Self {
+        if cfg!(all(feature = "mmap", target_pointer_width = "64")) { IoBackend::Mapped } else { IoBackend::Buffered }
+    }
+
+    /// The backend that will actually be used
+    pub fn effective(&self) -> Self {
+        match self {
+            IoBackend::Mapped if cfg!(feature = "mmap") => IoBackend::Mapped,
+            _ => IoBackend::Buffered,
+        }
+    }
+}
+
+impl Deref for UnitBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.0 {
+            Repr::Owned(bytes) => bytes,
+            Repr::Placeholder(bytes) => bytes,
+            #[cfg(feature = "mmap")]
+            Repr::Mapped(map, range) => &map[range.clone()],
+        }
+    }
+}
+
+impl AsRef<[u8]> for UnitBytes {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl std::fmt::Debug for UnitBytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UnitBytes")
+            .field("len", &self.len())
+            .field("mapped", &self.is_mapped())
+            .field("placeholder", &self.is_placeholder())
+            .finish()
+    }
+}
+
+impl UnitBytes {
+    pub(crate) fn placeholder(bytes: Arc<[u8]>) -> Self {
+        UnitBytes(Repr::Placeholder(bytes))
+    }
+
+    /// True if the bytes are a view into a mapped pack rather than a copy
+    pub fn is_mapped(&self) -> bool {
+        !matches!(self.0, Repr::Owned(_) | Repr::Placeholder(_))
+    }
+
+    /// True if the unit was corrupt and these are the store's placeholder bytes
+    pub fn is_placeholder(&self) -> bool {
+        matches!(self.0, Repr::Placeholder(_))
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        match self.0 {
+            Repr::Owned(bytes) => bytes,
+            Repr::Placeholder(bytes) => bytes.to_vec(),
+            #[cfg(feature = "mmap")]
+            Repr::Mapped(map, range) => map[range].to_vec(),
+        }
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl MappedPack {
+    fn map(path: &Path) -> io::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        // SAFETY: packs are append-only while the store is open. Torn tails are only truncated in `Streaming::open`,
+        // before anything is mapped, and compaction evicts a pack's mapping before deleting the file
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        let pages = (map.len() as u64).div_ceil(PAGE_SIZE) as usize;
+        Ok(MappedPack { map: Arc::new(map), touched: vec![0; pages.div_ceil(64)] })
+    }
+
+    fn touch(&mut self, range: &Range<usize>) {
+        let first = range.start as u64 / PAGE_SIZE;
+        let last = (range.end.max(range.start + 1) as u64 - 1) / PAGE_SIZE;
+        for page in first..=last {
+            self.touched[(page / 64) as usize] |= 1 << (page % 64);
+        }
+    }
+
+    fn residency(&self, pack: u32) -> PackResidency {
+        PackResidency {
+            pack,
+            mapped_bytes: self.map.len() as u64,
+            pages: (self.map.len() as u64).div_ceil(PAGE_SIZE) as usize,
+            touched_pages: self.touched.iter().map(|word| word.count_ones() as usize).sum(),
+        }
+    }
+}
+
+impl PackReader {
+    pub(crate) fn new(backend: IoBackend) -> Self {
+        if backend.effective() != backend {
+            log::get().warn(format!("streaming io backend {:?} isn't available in this build, using {:?}", backend, backend.effective()));
+        }
+        PackReader {
+            backend: backend.effective(),
+            #[cfg(feature = "mmap")]
+            maps: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn backend(&self) -> IoBackend {
+        self.backend
+    }
+
+    pub(crate) fn read(&self, directory: &Path, location: UnitLocation) -> io::Result<(RecordHeader, UnitBytes)> {
+        let path = pack::pack_path(directory, location.pack);
+        match self.backend {
+            #[cfg(feature = "mmap")]
+            IoBackend::Mapped => self.read_mapped(&path, location),
+            _ => pack::read_record(&path, location.offset, location.len).map(|(header, data)| (header, UnitBytes(Repr::Owned(data)))),
+        }
+    }
+
+    /// Drops the mapping of a pack about to be deleted
+    pub(crate) fn evict(&self, _pack: u32) {
+        #[cfg(feature = "mmap")]
+        self.maps.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(&_pack);
+    }
+
+    pub(crate) fn residency(&self) -> Vec<PackResidency> {
+        #[cfg(feature = "mmap")]
+        {
+            let maps = self.maps.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let mut residency: Vec<PackResidency> = maps.iter().map(|(pack, mapped)| mapped.residency(*pack)).collect();
+            residency.sort_by_key(|pack| pack.pack);
+            residency
+        }
+        #[cfg(not(feature = "mmap"))]
+        Vec::new()
+    }
+
+    #[cfg(feature = "mmap")]
+    fn read_mapped(&self, path: &Path, location: UnitLocation) -> io::Result<(RecordHeader, UnitBytes)> {
+        let start = location.offset as usize;
+        let end = (location.offset + pack::HEADER_LEN + location.len) as usize;
+
+        let mut maps = self.maps.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // The active pack grows after it's mapped, map it again once a record lands past the end
+        if maps.get(&location.pack).is_none_or(|mapped| mapped.map.len() < end) {
+            let mut fresh = MappedPack::map(path)?;
+            if let Some(stale) = maps.remove(&location.pack) {
+                fresh.touched.iter_mut().zip(stale.touched).for_each(|(word, stale)| *word |= stale);
+            }
+            maps.insert(location.pack, fresh);
+        }
+        let mapped = maps.get_mut(&location.pack).expect("mapped pack");
+        if mapped.map.len() < end {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "record past the end of its pack"))
+        }
+
+        let header_bytes = mapped.map[start..start + pack::HEADER_LEN as usize].try_into().expect("header length");
+        let header = RecordHeader::decode(header_bytes).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad record header"))?;
+        if header.len != location.len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "record length doesn't match the index"))
+        }
+        let data = start + pack::HEADER_LEN as usize..end;
+        mapped.touch(&(start..end));
+        Ok((header, UnitBytes(Repr::Mapped(Arc::clone(&mapped.map), data))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Streaming, StreamingConfig};
+    use super::*;
+    use crate::unique::UniqueId;
+
+    #[test]
+    fn both_backends_read_the_same_bytes() {
+        let dir = std::env::temp_dir().join(format!("hadron_streaming_io_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (small, large) = (UniqueId::get(), UniqueId::get());
+        let large_data: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+
+        for backend in [IoBackend::Buffered, IoBackend::Mapped] {
+            let streaming = Streaming::open_with(&dir, StreamingConfig::default().with_io_backend(backend)).unwrap();
+            streaming.store(small, b"small unit").unwrap();
+            streaming.store(large, &large_data).unwrap();
+
+            let bytes = streaming.read(large).unwrap();
+            assert_eq!(&*bytes, &large_data[..]);
+            assert_eq!(bytes.is_mapped(), backend.effective() == IoBackend::Mapped);
+            assert_eq!(streaming.load(small).unwrap(), b"small unit");
+
+            if backend.effective() == IoBackend::Mapped {
+                let residency = streaming.residency();
+                assert_eq!(residency.len(), 1);
+                assert!(residency[0].touched_pages >= 16 && residency[0].touched_pages <= residency[0].pages);
+            }
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,199 @@
+//!
+//! Deferred structural changes to the `World`
+//!
+//! Systems record spawns, despawns and component/resource insertions into `Commands`. Recorded commands are moved to
+//! the world's `CommandQueue` when the `Commands` is dropped and applied by the schedule at the next stage boundary,
+//! so nothing structural changes while a system is iterating
+//!
+
+use collider::EntityId;
+
+use super::{world::World, component::Component, resource::Resource};
+
+type CommandFn = Box<dyn FnOnce(&World) + Send + Sync>;
+type InsertFn = Box<dyn FnOnce(&World, EntityId) + Send + Sync>;
+
+/// World resource holding commands waiting for the next stage boundary
+#[derive(Default)]
+pub struct CommandQueue {
+    commands: Vec<CommandFn>,
+}
+
+/// Records commands locally and submits them to the world's queue on drop
+pub struct Commands {
+    world: World,
+    commands: Vec<CommandFn>,
+}
+
+/// Builder for an entity spawned through `Commands::spawn`, submitted when dropped
+pub struct SpawnCommands<'a> {
+    commands: &'a mut Commands,
+    inserts: Vec<InsertFn>,
+    spawned: Option<InsertFn>,
+}
+
+// Impls
+
+impl CommandQueue {
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+}
+
+impl std::fmt::Debug for CommandQueue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CommandQueue").field("len", &self.commands.len()).finish()
+    }
+}
+
+impl Commands {
+    pub fn new(world: &World) -> Self {
+        Commands {
+            world: world.clone(),
+            commands: Vec::new(),
+        }
+    }
+
+    /// Queues an arbitrary mutation of the world
+    pub fn add<F>(&mut self, command: F)
+    where
+        F: FnOnce(&World) + Send + Sync + 'static
+    {
+        self.commands.push(Box::new(command));
+    }
+
+    /// Queues a new entity, components added to the returned builder are inserted once it exists
+    pub fn spawn(&mut self) -> SpawnCommands<'_> {
+        SpawnCommands {
+            commands: self,
+            inserts: Vec::new(),
+            spawned: None,
+        }
+    }
+
+    pub fn despawn(&mut self, entity: EntityId) {
+        self.add(move |world| world.despawn_entity(entity));
+    }
+
+    /// Despawns `entity` and its descendants, see `World::despawn_recursive`
+    pub fn despawn_recursive(&mut self, entity: EntityId) {
+        self.add(move |world| { world.despawn_recursive(entity); });
+    }
+
+    pub fn insert<T: Component>(&mut self, entity: EntityId, component: T) {
+        self.add(move |world| { world.insert_component(entity, component); });
+    }
+
+    pub fn remove<T: Component>(&mut self, entity: EntityId) {
+        self.add(move |world| { world.remove_component::<T>(entity); });
+    }
+
+    pub fn insert_resource<T: Resource>(&mut self, resource: T) {
+        self.add(move |world| { world.insert_resource(resource); });
+    }
+
+    pub fn remove_resource<T: Resource>(&mut self) {
+        self.add(move |world| { world.remove_resource::<T>(); });
+    }
+
+    /// Moves recorded commands to the world's queue, done automatically on drop
+    pub fn submit(&mut self) {
+        if self.commands.is_empty() {
+            return
+        }
+
+        let mut commands = std::mem::take(&mut self.commands);
+        self.world.with_resource_mut::<CommandQueue, _>(|queue| queue.commands.append(&mut commands))
+            .expect("no command queue in world");
+    }
+}
+
+impl Drop for Commands {
+    fn drop(&mut self) {
+        self.submit();
+    }
+}
+
+impl<'a> SpawnCommands<'a> {
+    pub fn insert<T: Component>(mut self, component: T) -> Self {
+        self.inserts.push(Box::new(move |world, entity| { world.insert_component(entity, component); }));
+        self
+    }
+
+    /// Runs `f` with the new entity id once it has been spawned and its components inserted
+    pub fn on_spawned<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&World, EntityId) + Send + Sync + 'static
+    {
+        self.spawned = Some(Box::new(f));
+        self
+    }
+}
+
+impl<'a> Drop for SpawnCommands<'a> {
+    fn drop(&mut self) {
+        let inserts = std::mem::take(&mut self.inserts);
+        let spawned = self.spawned.take();
+        self.commands.add(move |world| {
+            let entity = world.spawn_entity();
+            for insert in inserts {
+                insert(world, entity);
+            }
+            if let Some(spawned) = spawned {
+                spawned(world, entity);
+            }
+        });
+    }
+}
+
+/// Applies every queued command in submission order. Commands queued while applying are left for the next boundary
+pub(crate) fn apply_commands(world: &World) {
+    let commands = world.with_resource_mut::<CommandQueue, _>(|queue| std::mem::take(&mut queue.commands)).unwrap_or_default();
+    for command in commands {
+        command(world);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_deferred_until_applied() {
+        let world = World::new();
+
+        {
+            let mut commands = Commands::new(&world);
+            commands.insert_resource(5u32);
+            commands.add(|world| { world.with_resource_mut::<u32, _>(|n| *n *= 2); });
+            assert!(!world.contains_resource::<u32>());
+        }
+
+        assert_eq!(world.with_resource::<CommandQueue, _>(|q| q.len()), Some(2));
+        apply_commands(&world);
+        assert_eq!(world.with_resource::<u32, _>(|n| *n), Some(10));
+        assert_eq!(world.with_resource::<CommandQueue, _>(|q| q.len()), Some(0));
+    }
+
+    #[test]
+    fn queued_spawns_get_their_components() {
+        let world = World::new();
+        world.insert_resource(Vec::<EntityId>::new());
+
+        let mut commands = world.commands();
+        commands.spawn().insert(7u32).insert(String::from("spawned"))
+            .on_spawned(|world, entity| { world.with_resource_mut::<Vec<EntityId>, _>(|spawned| spawned.push(entity)); });
+        drop(commands);
+        assert_eq!(world.with_resource::<Vec<EntityId>, _>(Vec::len), Some(0));
+
+        apply_commands(&world);
+        let spawned = world.with_resource::<Vec<EntityId>, _>(Vec::clone).unwrap();
+        assert_eq!(spawned.len(), 1);
+        assert_eq!(world.component::<u32, _>(spawned[0], |n| *n), Some(7));
+        assert_eq!(world.component::<String, _>(spawned[0], String::clone).as_deref(), Some("spawned"));
+    }
+}
@@ -0,0 +1,207 @@
+//!
+//! Per-type component storage with change ticks
+//!
+//! Every insertion and mutable access stamps the component with the world's current change tick. Systems remember the
+//! tick they last ran at, so `Added<T>` and `Changed<T>` filters only match components touched since then
+//!
+//! Components of each type are kept in a `MapStorage` keyed by entity, or with the `table-storage` feature in a dense
+//! `TableStorage`, see `table`. Both have the same interface, the world uses whichever `ComponentStorage` names
+//!
+
+use std::{collections::HashMap, hash::Hash, marker::PhantomData, ops::{Deref, DerefMut}};
+
+use collider::EntityId;
+
+/// Anything that can be attached to an entity
+pub trait Component: Send + Sync + 'static {}
+
+impl<T: Send + Sync + 'static> Component for T {}
+
+/// A monotonically increasing world change counter
+pub type Tick = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComponentTicks {
+    pub added: Tick,
+    pub changed: Tick,
+}
+
+#[derive(Debug)]
+struct ComponentCell<T> {
+    value: T,
+    ticks: ComponentTicks,
+}
+
+/// Storage for every component of type `T`, keyed by entity
+#[derive(Debug)]
+pub struct MapStorage<T, K = EntityId> {
+    cells: HashMap<K, ComponentCell<T>>,
+}
+
+/// The storage the world keeps components in
+#[cfg(not(feature = "table-storage"))]
+pub type ComponentStorage<T, K = EntityId> = MapStorage<T, K>;
+
+#[cfg(feature = "table-storage")]
+pub type ComponentStorage<T, K = EntityId> = super::table::TableStorage<T, K>;
+
+/// Mutable access to a component that marks it changed when written through
+pub struct Mut<'a, T> {
+    value: &'a mut T,
+    changed: &'a mut Tick,
+    tick: Tick,
+}
+
+/// Filters components by their change ticks relative to the running system
+pub trait QueryFilter<T> {
+    fn matches(ticks: ComponentTicks, last_run: Tick) -> bool;
+}
+
+/// Matches components added since the system last ran
+pub struct Added<T>(PhantomData<T>);
+
+/// Matches components added or mutated since the system last ran
+pub struct Changed<T>(PhantomData<T>);
+
+// Impls
+
+impl<T> QueryFilter<T> for () {
+    fn matches(_ticks: ComponentTicks, _last_run: Tick) -> bool {
+        true
+    }
+}
+
+impl<T> QueryFilter<T> for Added<T> {
+    fn matches(ticks: ComponentTicks, last_run: Tick) -> bool {
+        ticks.added > last_run
+    }
+}
+
+impl<T> QueryFilter<T> for Changed<T> {
+    fn matches(ticks: ComponentTicks, last_run: Tick) -> bool {
+        ticks.changed > last_run
+    }
+}
+
+impl<T, K> Default for MapStorage<T, K> {
+    fn default() -> Self {
+        MapStorage { cells: HashMap::new() }
+    }
+}
+
+impl<T, K: Copy + Eq + Hash> MapStorage<T, K> {
+    /// Inserts or replaces a component. Replacing counts as a change but not an addition
+    pub fn insert(&mut self, key: K, value: T, tick: Tick) -> Option<T> {
+        match self.cells.get_mut(&key) {
+            Some(cell) => {
+                cell.ticks.changed = tick;
+                Some(std::mem::replace(&mut cell.value, value))
+            },
+            None => {
+                self.cells.insert(key, ComponentCell { value, ticks: ComponentTicks { added: tick, changed: tick } });
+                None
+            },
+        }
+    }
+
+    pub fn remove(&mut self, key: K) -> Option<T> {
+        self.cells.remove(&key).map(|cell| cell.value)
+    }
+
+    pub fn get(&self, key: K) -> Option<&T> {
+        self.cells.get(&key).map(|cell| &cell.value)
+    }
+
+    pub fn get_mut(&mut self, key: K, tick: Tick) -> Option<Mut<'_, T>> {
+        self.cells.get_mut(&key).map(|cell| Mut { value: &mut cell.value, changed: &mut cell.ticks.changed, tick })
+    }
+
+    pub fn ticks(&self, key: K) -> Option<ComponentTicks> {
+        self.cells.get(&key).map(|cell| cell.ticks)
+    }
+
+    pub fn contains(&self, key: K) -> bool {
+        self.cells.contains_key(&key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (K, &T)> {
+        self.cells.iter().map(|(key, cell)| (*key, &cell.value))
+    }
+
+    pub fn iter_mut(&mut self, tick: Tick) -> impl Iterator<Item = (K, Mut<'_, T>)> {
+        self.cells.iter_mut().map(move |(key, cell)| (*key, Mut { value: &mut cell.value, changed: &mut cell.ticks.changed, tick }))
+    }
+
+    /// Iterates the components matching filter `F` for a system that last ran at `last_run`
+    pub fn iter_filtered<F: QueryFilter<T>>(&self, last_run: Tick) -> impl Iterator<Item = (K, &T)> {
+        self.cells.iter()
+            .filter(move |(_, cell)| F::matches(cell.ticks, last_run))
+            .map(|(key, cell)| (*key, &cell.value))
+    }
+}
+
+impl<'a, T> Mut<'a, T> {
+    /// Marks `changed` with `tick` once written through
+    pub(super) fn new(value: &'a mut T, changed: &'a mut Tick, tick: Tick) -> Self {
+        Mut { value, changed, tick }
+    }
+
+    /// Reborrows the value without marking it changed
+    pub fn bypass_change_detection(&mut self) -> &mut T {
+        self.value
+    }
+
+    /// Marks the value changed without writing to it
+    pub fn set_changed(&mut self) {
+        *self.changed = self.tick;
+    }
+}
+
+impl<'a, T> Deref for Mut<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.value
+    }
+}
+
+impl<'a, T> DerefMut for Mut<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        *self.changed = self.tick;
+        self.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn added_and_changed_filters() {
+        let mut storage: ComponentStorage<f32, u32> = ComponentStorage::default();
+        storage.insert(0, 1.0, 1);
+        storage.insert(1, 2.0, 1);
+
+        // A system that last ran at tick 1 sees nothing new
+        assert_eq!(storage.iter_filtered::<Changed<f32>>(1).count(), 0);
+
+        storage.insert(2, 3.0, 2);
+        *storage.get_mut(0, 2).unwrap() += 1.0;
+        let _read_only = *storage.get_mut(1, 2).unwrap();
+
+        let mut added: Vec<u32> = storage.iter_filtered::<Added<f32>>(1).map(|(k, _)| k).collect();
+        let mut changed: Vec<u32> = storage.iter_filtered::<Changed<f32>>(1).map(|(k, _)| k).collect();
+        added.sort();
+        changed.sort();
+        assert_eq!(added, vec![2]);
+        assert_eq!(changed, vec![0, 2]);
+    }
+}
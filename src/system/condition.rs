@@ -0,0 +1,150 @@
+//!
+//! Run conditions
+//!
+//! A system or stage given a run condition is skipped by the schedule whenever the condition is false, so systems don't
+//! need to check for themselves whether they should do anything. Conditions are closures over the world, built from
+//! `in_state`, `resource_exists`, `resource_matches` and `every_n_ticks` and combined with `and`, `or` and `not`
+//!
+//! Systems and stages can also be switched off by name while the app runs through the `DisabledSystems` resource, or
+//! from the console with `system off <name>`, `system on <name>` and `system list`
+//!
+
+use std::{collections::BTreeSet, sync::atomic::{AtomicU64, Ordering}};
+
+use super::{world::World, resource::Resource, state::{self, State}};
+
+/// Decides whether a system or stage runs, evaluated each time it would
+pub type RunCondition = Box<dyn Fn(&World) -> bool + Send + Sync>;
+
+/// World resource naming the systems and stages switched off at runtime
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DisabledSystems {
+    names: BTreeSet<String>,
+}
+
+// Impls
+
+impl DisabledSystems {
+    pub fn disable(&mut self, name: &str) {
+        self.names.insert(String::from(name));
+    }
+
+    /// Switches a system or stage back on, returning whether it was off
+    pub fn enable(&mut self, name: &str) -> bool {
+        self.names.remove(name)
+    }
+
+    pub fn is_disabled(&self, name: &str) -> bool {
+        self.names.contains(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.names.iter().map(String::as_str)
+    }
+}
+
+/// While `state` is the active (topmost) state
+pub fn in_state<S: State>(state: S) -> RunCondition {
+    Box::new(move |world| state::is_active(world, &state))
+}
+
+pub fn resource_exists<R: Resource>() -> RunCondition {
+    Box::new(|world| world.contains_resource::<R>())
+}
+
+/// While the resource of type `R` exists and `f` returns true for it, e.g. a flag being set
+pub fn resource_matches<R: Resource, F>(f: F) -> RunCondition
+where
+    F: Fn(&R) -> bool + Send + Sync + 'static
+{
+    Box::new(move |world| world.with_resource::<R, _>(|resource| f(resource)).unwrap_or(false))
+}
+
+/// The first time it's evaluated and every `n`th time after. On a fixed stage that's every `n` fixed ticks
+pub fn every_n_ticks(n: u64) -> RunCondition {
+    let evaluated = AtomicU64::new(0);
+    Box::new(move |_| evaluated.fetch_add(1, Ordering::Relaxed).is_multiple_of(n.max(1)))
+}
+
+pub fn not(condition: RunCondition) -> RunCondition {
+    Box::new(move |world| !condition(world))
+}
+
+/// Both conditions, `b` is only evaluated if `a` holds
+pub fn and(a: RunCondition, b: RunCondition) -> RunCondition {
+    Box::new(move |world| a(world) && b(world))
+}
+
+/// Either condition, `b` is only evaluated if `a` doesn't hold
+pub fn or(a: RunCondition, b: RunCondition) -> RunCondition {
+    Box::new(move |world| a(world) || b(world))
+}
+
+/// Whether the system or stage `name` has been switched off
+pub fn is_disabled(world: &World, name: &str) -> bool {
+    world.with_resource::<DisabledSystems, _>(|disabled| disabled.is_disabled(name)).unwrap_or(false)
+}
+
+pub fn with_disabled_systems<R>(world: &World, f: impl FnOnce(&mut DisabledSystems) -> R) -> R {
+    if !world.contains_resource::<DisabledSystems>() {
+        world.insert_resource(DisabledSystems::default());
+    }
+    world.with_resource_mut::<DisabledSystems, _>(f).expect("no disabled systems")
+}
+
+/// Console binding, runs `system off <name>`, `system on <name>` or `system list` and returns a line describing the
+/// result
+pub fn command(world: &World, line: &str) -> Result<String, String> {
+    let line = line.trim();
+    let arguments = line.strip_prefix("system").unwrap_or(line).trim();
+    let (action, name) = arguments.split_once(' ').map_or((arguments, ""), |(action, name)| (action, name.trim()));
+    with_disabled_systems(world, |disabled| match (action, name) {
+        ("list", "") => Ok(match disabled.names.is_empty() {
+            true => String::from("no systems switched off"),
+            false => format!("switched off: {}", disabled.iter().collect::<Vec<_>>().join(", ")),
+        }),
+        ("off", name) if !name.is_empty() => {
+            disabled.disable(name);
+            Ok(format!("{} switched off", name))
+        },
+        ("on", name) if !name.is_empty() => match disabled.enable(name) {
+            true => Ok(format!("{} switched on", name)),
+            false => Err(format!("{} wasn't switched off", name)),
+        },
+        _ => Err(format!("unknown system command {}", line)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::schedule::{Schedule, stage};
+
+    struct Paused(bool);
+
+    #[test]
+    fn conditions_and_switches_skip_systems() {
+        let world = World::new();
+        world.insert_resource(Paused(false));
+        world.insert_resource(Vec::<&'static str>::new());
+        let mut schedule = Schedule::default();
+        let log = |name: &'static str| move |world: &World| { world.with_resource_mut::<Vec<&'static str>, _>(|ran| ran.push(name)); };
+        schedule.add_conditional_system(stage::UPDATE, "gameplay", resource_matches::<Paused, _>(|paused| !paused.0), log("gameplay"));
+        schedule.add_conditional_system(stage::UPDATE, "autosave", every_n_ticks(2), log("autosave"));
+        schedule.add_system(stage::POST_UPDATE, "render", log("render"));
+
+        let mut run = || {
+            schedule.run(&world);
+            world.with_resource_mut::<Vec<&'static str>, _>(std::mem::take).unwrap()
+        };
+        assert_eq!(run(), vec!["gameplay", "autosave", "render"]);
+        world.with_resource_mut::<Paused, _>(|paused| paused.0 = true);
+        assert_eq!(run(), vec!["render"]);
+
+        assert_eq!(command(&world, "system off post_update"), Ok(String::from("post_update switched off")));
+        assert_eq!(run(), vec!["autosave"]);
+        assert!(command(&world, "system on post_update").is_ok());
+        assert!(command(&world, "system on post_update").is_err());
+        assert_eq!(command(&world, "system list"), Ok(String::from("no systems switched off")));
+    }
+}
@@ -0,0 +1,317 @@
+//!
+//! Deterministic simulation
+//!
+//! With `determinism.enabled` a run plays out the same way every time it's given the same seed and inputs. Named
+//! random streams and `UniqueId`s are seeded from `determinism.seed`, the fixed timestep runs at `tick_rate` with
+//! exactly one tick a frame however long frames take, and systems and state transitions run in the order they were
+//! added. After every fixed tick the world's serializable components are hashed, see `WorldSnapshot::checksum`
+//!
+//! The checksums can be written to the `record` file as JSON lines, and a later run given that file as `compare`
+//! checks each of its ticks against it. The first tick that differs is logged along with the components that changed
+//! during it and sent as a `Divergence` event. Networked sessions can check their checksums against a peer's the same
+//! way, with a `Recording` built from the peer's checksums
+//!
+
+use std::{fs::File, io::{self, BufRead, BufReader, Write}, path::{Path, PathBuf}, time::Duration};
+
+use serde::{Serialize, Deserialize};
+
+use crate::config::ConfigSection;
+use crate::debug::{log, snapshot::{WorldDiff, WorldSnapshot}};
+use crate::random::{self, Random};
+use super::{world::World, event, time::FixedTime};
+
+/// Determinism options from the `determinism` config section
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct DeterminismConfig {
+    pub enabled: bool,
+    pub seed: u64,
+    /// Fixed ticks per second
+    pub tick_rate: f64,
+    /// Writes every tick's checksum to this file
+    pub record: Option<PathBuf>,
+    /// Checks every tick's checksum against a file written with `record`
+    pub compare: Option<PathBuf>,
+}
+
+/// The checksums of a run, one for each fixed tick from the first
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Recording {
+    pub seed: u64,
+    pub tick_rate: f64,
+    pub checksums: Vec<u64>,
+}
+
+/// Sent for the first tick whose checksum differs from the reference run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divergence {
+    pub tick: u64,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+/// World resource of a deterministic run, present only while determinism is enabled
+#[derive(Debug)]
+pub struct Determinism {
+    config: DeterminismConfig,
+    checksums: Vec<u64>,
+    reference: Option<Recording>,
+    divergence: Option<Divergence>,
+    /// The record file, written to as ticks finish
+    output: Option<File>,
+    /// The last tick's snapshot, kept while comparing to show what changed during a diverging tick
+    previous: Option<WorldSnapshot>,
+}
+
+/// First line of a recording
+#[derive(Serialize, Deserialize)]
+struct RecordingHeader {
+    seed: u64,
+    tick_rate: f64,
+}
+
+/// Every following line of a recording
+#[derive(Serialize, Deserialize)]
+struct RecordedTick {
+    tick: u64,
+    checksum: u64,
+}
+
+// Impls
+
+impl Default for DeterminismConfig {
+    fn default() -> Self {
+        DeterminismConfig { enabled: false, seed: 0, tick_rate: 60.0, record: None, compare: None }
+    }
+}
+
+impl ConfigSection for DeterminismConfig {
+    const NAME: &'static str = "determinism";
+}
+
+impl Recording {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let header: RecordingHeader = match lines.next() {
+            Some(line) => serde_json::from_str(&line?)?,
+            None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "recording is empty")),
+        };
+
+        let mut recording = Recording { seed: header.seed, tick_rate: header.tick_rate, checksums: Vec::new() };
+        for line in lines {
+            let recorded: RecordedTick = serde_json::from_str(&line?)?;
+            if recorded.tick != recording.checksums.len() as u64 + 1 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("expected tick {}, found tick {}", recording.checksums.len() + 1, recorded.tick)))
+            }
+            recording.checksums.push(recorded.checksum);
+        }
+        Ok(recording)
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut file = create_recording(path, self.seed, self.tick_rate)?;
+        for (index, checksum) in self.checksums.iter().enumerate() {
+            write_tick(&mut file, index as u64 + 1, *checksum)?;
+        }
+        Ok(())
+    }
+
+    /// The checksum recorded for `tick`, counting from 1
+    pub fn checksum(&self, tick: u64) -> Option<u64> {
+        self.checksums.get(tick.checked_sub(1)? as usize).copied()
+    }
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "tick {} diverged from the reference run, checksum {:016x} where {:016x} was expected", self.tick, self.actual, self.expected)
+    }
+}
+
+impl Determinism {
+    pub fn new(config: DeterminismConfig) -> Self {
+        Determinism { config, checksums: Vec::new(), reference: None, divergence: None, output: None, previous: None }
+    }
+
+    /// Checks every tick against `reference`
+    pub fn with_reference(mut self, reference: Recording) -> Self {
+        self.reference = Some(reference);
+        self
+    }
+
+    pub fn config(&self) -> &DeterminismConfig {
+        &self.config
+    }
+
+    /// The fixed timestep, and the time advanced each frame
+    pub fn step(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.config.tick_rate.max(1.0))
+    }
+
+    /// Ticks checksummed so far
+    pub fn ticks(&self) -> u64 {
+        self.checksums.len() as u64
+    }
+
+    pub fn checksums(&self) -> &[u64] {
+        &self.checksums
+    }
+
+    /// The first divergence from the reference run, if there's been one
+    pub fn divergence(&self) -> Option<Divergence> {
+        self.divergence
+    }
+
+    /// The checksums so far as a recording, for saving or sending to a peer
+    pub fn recording(&self) -> Recording {
+        Recording { seed: self.config.seed, tick_rate: self.config.tick_rate, checksums: self.checksums.clone() }
+    }
+
+    /// Whether ticks are still being checked, they aren't once the run has diverged
+    pub fn is_comparing(&self) -> bool {
+        self.reference.is_some() && self.divergence.is_none()
+    }
+
+    /// Counts a tick with its checksum, returning the divergence if it's the first tick to differ from the reference.
+    /// Ticks past the end of the reference aren't checked
+    pub fn check(&mut self, checksum: u64) -> Option<Divergence> {
+        self.checksums.push(checksum);
+        if !self.is_comparing() {
+            return None
+        }
+        let tick = self.ticks();
+        let expected = self.reference.as_ref().and_then(|reference| reference.checksum(tick))?;
+        if expected == checksum {
+            return None
+        }
+        self.divergence = Some(Divergence { tick, expected, actual: checksum });
+        self.divergence
+    }
+
+    /// Records and checks the tick that just finished, returning the divergence with the tick's changes if it's the
+    /// first to differ
+    fn finish_tick(&mut self, snapshot: WorldSnapshot) -> Option<(Divergence, Option<WorldDiff>)> {
+        let checksum = snapshot.checksum();
+        let tick = self.ticks() + 1;
+        if let Some(output) = self.output.as_mut() {
+            if let Err(error) = write_tick(output, tick, checksum) {
+                log::get().with_topic("determinism").warn(format!("stopped recording checksums at tick {}: {}", tick, error));
+                self.output = None;
+            }
+        }
+
+        match self.check(checksum) {
+            Some(divergence) => Some((divergence, self.previous.take().map(|previous| previous.diff(&snapshot)))),
+            None => {
+                if self.is_comparing() {
+                    self.previous = Some(snapshot);
+                }
+                None
+            },
+        }
+    }
+}
+
+/// Seeds the world's random streams and `UniqueId`s, fixes the timestep and starts checksumming ticks. A record or
+/// compare file that can't be opened is logged and skipped
+pub fn enable(world: &World, config: DeterminismConfig) {
+    random::seed_unique_ids(Some(config.seed));
+    world.insert_resource(Random::seeded(config.seed));
+
+    let mut determinism = Determinism::new(config.clone());
+    let step = determinism.step();
+    if world.with_resource_mut::<FixedTime, _>(|fixed| fixed.set_step(step)).is_none() {
+        world.insert_resource(FixedTime::new(step));
+    }
+
+    if let Some(path) = &config.compare {
+        match Recording::load(path) {
+            Ok(reference) => {
+                if reference.seed != config.seed || reference.tick_rate != config.tick_rate {
+                    log::get().with_topic("determinism").warn(format!("{} was recorded with seed {} at {} ticks per second, expect it to diverge", path.display(), reference.seed, reference.tick_rate));
+                }
+                determinism = determinism.with_reference(reference);
+            },
+            Err(error) => log::get().with_topic("determinism").warn(format!("not comparing against {}: {}", path.display(), error)),
+        }
+    }
+    if let Some(path) = &config.record {
+        match create_recording(path, config.seed, config.tick_rate) {
+            Ok(file) => determinism.output = Some(file),
+            Err(error) => log::get().with_topic("determinism").warn(format!("not recording to {}: {}", path.display(), error)),
+        }
+    }
+
+    log::get().with_topic("determinism").info(format!("deterministic run with seed {} at {} ticks per second", config.seed, config.tick_rate));
+    world.insert_resource(determinism);
+}
+
+pub fn is_deterministic(world: &World) -> bool {
+    world.contains_resource::<Determinism>()
+}
+
+/// The time a deterministic world advances each frame, `None` when determinism is off
+pub fn frame_delta(world: &World) -> Option<Duration> {
+    world.with_resource::<Determinism, _>(Determinism::step)
+}
+
+/// Hash of the world's serializable components
+pub fn checksum(world: &World) -> u64 {
+    WorldSnapshot::take(world).checksum()
+}
+
+/// Checksums the fixed tick that just ran, recording and checking it when determinism is on. Run by the schedule
+/// after every fixed tick
+pub(crate) fn end_tick(world: &World) {
+    if !is_deterministic(world) {
+        return
+    }
+    let snapshot = WorldSnapshot::take(world);
+    let Some((divergence, diff)) = world.with_resource_mut::<Determinism, _>(|determinism| determinism.finish_tick(snapshot)).flatten() else {
+        return
+    };
+
+    log::get().with_topic("determinism").error(divergence.to_string());
+    if let Some(diff) = diff {
+        log::get().with_topic("determinism").state(format!("components changed during tick {}", divergence.tick), &diff);
+    }
+    event::send_event(world, divergence);
+}
+
+fn create_recording(path: impl AsRef<Path>, seed: u64, tick_rate: f64) -> io::Result<File> {
+    let mut file = File::create(path)?;
+    writeln!(file, "{}", serde_json::to_string(&RecordingHeader { seed, tick_rate })?)?;
+    Ok(file)
+}
+
+fn write_tick(file: &mut File, tick: u64, checksum: u64) -> io::Result<()> {
+    writeln!(file, "{}", serde_json::to_string(&RecordedTick { tick, checksum })?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_are_checked_against_a_recording() {
+        let config = DeterminismConfig { enabled: true, seed: 9, ..DeterminismConfig::default() };
+        let mut first = Determinism::new(config.clone());
+        for checksum in [11, 12, 13] {
+            assert_eq!(first.check(checksum), None);
+        }
+
+        let path = std::env::temp_dir().join(format!("hadron_determinism_{}.jsonl", std::process::id()));
+        first.recording().write(&path).unwrap();
+        let reference = Recording::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(reference, first.recording());
+
+        let mut second = Determinism::new(config).with_reference(reference);
+        assert_eq!(second.check(11), None);
+        assert_eq!(second.check(15), Some(Divergence { tick: 2, expected: 12, actual: 15 }));
+        // Only the first divergence is reported, everything after it is expected to differ
+        assert_eq!(second.check(16), None);
+        assert_eq!(second.divergence().map(|divergence| divergence.tick), Some(2));
+    }
+}
@@ -0,0 +1,116 @@
+use super::{world::World, resource::Resource};
+
+/// A queue of events of type `T`, stored as a `World` resource
+///
+/// Events are double buffered, anything not drained within two updates is dropped so queues can't grow without bound
+#[derive(Debug)]
+pub struct Events<T> {
+    current: Vec<T>,
+    previous: Vec<T>,
+}
+
+/// Type erased list of `Events<T>::update` functions for every registered event type
+#[derive(Default)]
+struct EventRegistry {
+    updaters: Vec<fn(&World)>,
+}
+
+impl<T> Default for Events<T> {
+    fn default() -> Self {
+        Events {
+            current: Vec::new(),
+            previous: Vec::new(),
+        }
+    }
+}
+
+impl<T> Events<T> {
+    pub fn send(&mut self, event: T) {
+        self.current.push(event);
+    }
+
+    /// Removes and returns all pending events, oldest first
+    pub fn drain(&mut self) -> Vec<T> {
+        let mut events = std::mem::take(&mut self.previous);
+        events.append(&mut self.current);
+        events
+    }
+
+    /// Iterates pending events without consuming them, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.previous.iter().chain(self.current.iter())
+    }
+
+    pub fn len(&self) -> usize {
+        self.previous.len() + self.current.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Swaps buffers, dropping events that were not consumed since the last update
+    pub fn update(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+    }
+}
+
+/// Registers an event type with the world so its queue is updated each frame. Does nothing if it's already registered
+pub fn init_events<T: Resource>(world: &World) {
+    if world.contains_resource::<Events<T>>() {
+        return
+    }
+
+    world.insert_resource(Events::<T>::default());
+    if !world.contains_resource::<EventRegistry>() {
+        world.insert_resource(EventRegistry::default());
+    }
+    world.with_resource_mut::<EventRegistry, _>(|registry| {
+        registry.updaters.push(|world| {
+            world.with_resource_mut::<Events<T>, _>(|events| events.update());
+        });
+    });
+}
+
+/// Sends an event, registering the event type first if needed
+pub fn send_event<T: Resource>(world: &World, event: T) {
+    init_events::<T>(world);
+    world.with_resource_mut::<Events<T>, _>(|events| events.send(event));
+}
+
+/// Takes all pending events of type `T`
+pub fn drain_events<T: Resource>(world: &World) -> Vec<T> {
+    world.with_resource_mut::<Events<T>, _>(|events| events.drain()).unwrap_or_default()
+}
+
+/// Copies pending events of type `T` without consuming them, for queues several systems subscribe to. Events stay
+/// pending for two updates, so a subscriber sees each of them twice
+pub fn read_events<T: Resource + Clone>(world: &World) -> Vec<T> {
+    world.with_resource::<Events<T>, _>(|events| events.iter().cloned().collect()).unwrap_or_default()
+}
+
+/// Updates every registered event queue, called once per frame by the main loop
+pub(crate) fn update_events(world: &World) {
+    let updaters = world.with_resource::<EventRegistry, _>(|registry| registry.updaters.clone()).unwrap_or_default();
+    for updater in updaters {
+        updater(world);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_expire_after_two_updates() {
+        let world = World::new();
+        send_event(&world, 1u8);
+        update_events(&world);
+        send_event(&world, 2u8);
+        assert_eq!(world.with_resource::<Events<u8>, _>(|e| e.iter().copied().collect::<Vec<_>>()), Some(vec![1, 2]));
+
+        update_events(&world);
+        assert_eq!(drain_events::<u8>(&world), vec![2]);
+        assert!(drain_events::<u8>(&world).is_empty());
+    }
+}
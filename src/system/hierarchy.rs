@@ -0,0 +1,114 @@
+//!
+//! Parent/child relationships between entities
+//!
+
+use collider::EntityId;
+
+use crate::unique::UniqueId;
+use super::{world::World, event, prefab::{AssetRefs, PrefabInstance}, transform::Transform};
+
+/// The entity this entity is attached to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Parent(pub EntityId);
+
+/// Entities attached to this entity, in insertion order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Children(pub Vec<EntityId>);
+
+/// Sent for every entity `despawn_recursive` removes, once its components are gone. Anything keeping state of its own
+/// for the entity or under its ids should drop it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Despawned {
+    pub entity: EntityId,
+    /// The id it was spawned from a prefab with
+    pub uid: Option<UniqueId>,
+    /// Assets it referenced through its `AssetRefs`
+    pub assets: Vec<UniqueId>,
+}
+
+/// Attaches `child` to `parent`, detaching it from any previous parent
+pub fn set_parent(world: &World, child: EntityId, parent: EntityId) {
+    remove_parent(world, child);
+
+    world.insert_component(child, Parent(parent));
+    if world.component_mut::<Children, _>(parent, |mut children| children.0.push(child)).is_none() {
+        world.insert_component(parent, Children(vec![child]));
+    }
+}
+
+/// Detaches `child` from its parent, returning the previous parent
+pub fn remove_parent(world: &World, child: EntityId) -> Option<EntityId> {
+    let Parent(parent) = world.remove_component::<Parent>(child)?;
+    world.component_mut::<Children, _>(parent, |mut children| children.0.retain(|c| *c != child));
+    // Now a root, its global transform has to be recomputed without the parent's
+    world.component_mut::<Transform, _>(child, |mut transform| transform.set_changed());
+    Some(parent)
+}
+
+pub fn parent(world: &World, entity: EntityId) -> Option<EntityId> {
+    world.component::<Parent, _>(entity, |p| p.0)
+}
+
+pub fn children(world: &World, entity: EntityId) -> Vec<EntityId> {
+    world.component::<Children, _>(entity, |c| c.0.clone()).unwrap_or_default()
+}
+
+/// Returns `entity` followed by all of its descendants, depth first
+pub fn descendants(world: &World, entity: EntityId) -> Vec<EntityId> {
+    let mut result = Vec::new();
+    let mut stack = vec![entity];
+    while let Some(next) = stack.pop() {
+        result.push(next);
+        let mut next_children = children(world, next);
+        next_children.reverse();
+        stack.extend(next_children);
+    }
+    result
+}
+
+/// Removes `entity` and all of its descendants, detaching it from its parent first. Children are despawned before their
+/// parents, each sending a `Despawned` event. Returns the entities removed
+pub fn despawn_recursive(world: &World, entity: EntityId) -> Vec<EntityId> {
+    remove_parent(world, entity);
+    let mut entities = descendants(world, entity);
+    entities.reverse();
+    for entity in &entities {
+        let despawned = Despawned {
+            entity: *entity,
+            uid: world.component::<PrefabInstance, _>(*entity, |instance| instance.uid),
+            assets: world.component::<AssetRefs, _>(*entity, |refs| refs.0.values().copied().collect()).unwrap_or_default(),
+        };
+        world.despawn_entity(*entity);
+        event::send_event(world, despawned);
+    }
+    entities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn despawn_recursive_removes_every_descendant() {
+        let world = World::new();
+        let [parent_of_root, root, child, sibling, grandchild] = std::array::from_fn(|_| world.spawn_entity());
+        set_parent(&world, root, parent_of_root);
+        set_parent(&world, child, root);
+        set_parent(&world, sibling, root);
+        set_parent(&world, grandchild, child);
+        world.insert_component(grandchild, Transform::IDENTITY);
+
+        // Children go before their parents
+        let removed = despawn_recursive(&world, root);
+        assert_eq!(removed, vec![sibling, grandchild, child, root]);
+        for entity in &removed {
+            assert_eq!(parent(&world, *entity), None);
+            assert!(children(&world, *entity).is_empty());
+        }
+        assert!(world.component::<Transform, _>(grandchild, |_| ()).is_none());
+
+        let despawned: Vec<EntityId> = event::drain_events::<Despawned>(&world).iter().map(|event| event.entity).collect();
+        assert_eq!(despawned, removed);
+        assert!(children(&world, parent_of_root).is_empty());
+    }
+}
@@ -2,4 +2,20 @@
 //! Primary functionality of Hadron
 //! 
 
-pub mod world;
\ No newline at end of file
+pub mod world;
+pub mod resource;
+pub mod component;
+pub mod table;
+pub mod registry;
+pub mod commands;
+pub mod hierarchy;
+pub mod relation;
+pub mod transform;
+pub mod prefab;
+pub mod time;
+pub mod determinism;
+pub mod event;
+pub mod schedule;
+pub mod condition;
+pub mod state;
+pub mod task;
\ No newline at end of file
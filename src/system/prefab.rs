@@ -0,0 +1,296 @@
+//!
+//! Prefabs: serialized entity trees that can be instantiated into the `World`
+//!
+//! A prefab entity carries a `UniqueId` that is local to the prefab. Components can refer to other entities of the same
+//! prefab by that id; each instantiation gets fresh ids and every reference inside component data is remapped to them
+//!
+//! Prefab files, crash saves included, start with a header of the file's `format` and the version each component was
+//! written at. Components are migrated up to their registered version as they're spawned, so files keep loading as
+//! components change. Files from before the header are format 1 with every component at version 1
+//!
+
+use std::{collections::{BTreeMap, HashMap}, path::{Path, PathBuf}};
+
+use collider::EntityId;
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+
+use crate::{unique::{Handle, UniqueId}, asset::{self, AssetError, AssetLoader, AssetManager, Assets}};
+use super::{world::World, component::Component, hierarchy, registry::{self, ComponentVersions, RegistryError}};
+
+/// Layout version of prefab files written now
+pub const PREFAB_FORMAT: u32 = 2;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Prefab {
+    /// Layout version of the file, see `PREFAB_FORMAT`
+    #[serde(default = "legacy_format")]
+    pub format: u32,
+    /// Version each component was serialized at
+    #[serde(default)]
+    pub versions: ComponentVersions,
+    pub root: PrefabEntity,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PrefabEntity {
+    /// Prefab-local id, remapped to a fresh id on every instantiation
+    pub id: UniqueId,
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Serialized components keyed by their registered name
+    #[serde(default)]
+    pub components: BTreeMap<String, serde_json::Value>,
+    /// Assets loaded alongside the entity, keyed by a name meaningful to its components
+    #[serde(default)]
+    pub assets: BTreeMap<String, PathBuf>,
+    #[serde(default)]
+    pub children: Vec<PrefabEntity>,
+}
+
+/// Attached to every entity spawned from a prefab
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefabInstance {
+    /// Handle of the prefab asset this entity came from
+    pub prefab: Handle<Prefab>,
+    /// This entity's remapped id
+    pub uid: UniqueId,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Name(pub String);
+
+/// Handles of the assets a prefab entity referenced, keyed by the names used in the prefab
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AssetRefs(pub BTreeMap<String, UniqueId>);
+
+#[derive(Debug)]
+pub enum PrefabError {
+    NotLoaded(UniqueId),
+    UnknownComponent(String),
+    Deserialize(String, String),
+    Asset(AssetError),
+    Registry(RegistryError),
+}
+
+/// Loads `.prefab` json files
+pub struct PrefabLoader;
+
+// Impls
+
+impl std::error::Error for PrefabError {}
+
+impl std::fmt::Display for PrefabError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrefabError::NotLoaded(id) => write!(f, "prefab {} is not loaded", id),
+            PrefabError::UnknownComponent(name) => write!(f, "unregistered prefab component {}", name),
+            PrefabError::Deserialize(name, err) => write!(f, "unable to deserialize prefab component {}: {}", name, err),
+            PrefabError::Asset(err) => write!(f, "{}", err),
+            PrefabError::Registry(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<AssetError> for PrefabError {
+    fn from(err: AssetError) -> Self {
+        PrefabError::Asset(err)
+    }
+}
+
+impl From<RegistryError> for PrefabError {
+    fn from(err: RegistryError) -> Self {
+        match err {
+            RegistryError::UnknownComponent(name) => PrefabError::UnknownComponent(name),
+            RegistryError::Deserialize(name, err) => PrefabError::Deserialize(name, err),
+            err => PrefabError::Registry(err),
+        }
+    }
+}
+
+impl AssetLoader for PrefabLoader {
+    type Asset = Prefab;
+
+    fn extensions(&self) -> &[&'static str] {
+        &["prefab"]
+    }
+
+    fn load(&self, bytes: &[u8], path: &Path) -> Result<Self::Asset, AssetError> {
+        let prefab: Prefab = serde_json::from_slice(bytes).map_err(|err| AssetError::Parse(path.to_path_buf(), err.to_string()))?;
+        if prefab.format > PREFAB_FORMAT {
+            return Err(AssetError::Parse(path.to_path_buf(), format!("prefab format {} is newer than the supported format {}", prefab.format, PREFAB_FORMAT)))
+        }
+        Ok(prefab)
+    }
+}
+
+impl Prefab {
+    /// A prefab in the current format, with every component at version 1
+    pub fn new(root: PrefabEntity) -> Self {
+        Prefab { format: PREFAB_FORMAT, versions: ComponentVersions::new(), root }
+    }
+
+    /// Sets the versions the components were serialized at, usually `registry::versions` of the world they came from
+    pub fn with_versions(mut self, versions: ComponentVersions) -> Self {
+        self.versions = versions;
+        self
+    }
+
+    /// Version `component` was serialized at
+    pub fn version(&self, component: &str) -> u32 {
+        self.versions.get(component).copied().unwrap_or(1)
+    }
+}
+
+impl PrefabEntity {
+    fn collect_ids(&self, ids: &mut Vec<UniqueId>) {
+        ids.push(self.id);
+        self.children.iter().for_each(|child| child.collect_ids(ids));
+    }
+}
+
+/// Registers a component type so prefabs can refer to it by `name`. Types that are also `Serialize` should use
+/// `registry::register_component`, which covers prefabs too
+pub fn register_component<T: Component + DeserializeOwned>(world: &World, name: &str) {
+    registry::register_with::<T, _>(world, name, |registration| { registration.with_deserialize(); });
+}
+
+/// Instantiates a loaded prefab, returning the root entity
+pub fn spawn_prefab(world: &World, handle: Handle<Prefab>) -> Result<EntityId, PrefabError> {
+    let prefab = asset::get::<Prefab>(world, handle).ok_or(PrefabError::NotLoaded(handle.uid()))?;
+
+    let mut ids = Vec::new();
+    prefab.root.collect_ids(&mut ids);
+    let remap: HashMap<UniqueId, UniqueId> = ids.into_iter().map(|id| (id, UniqueId::get())).collect();
+
+    spawn_entity(world, handle, &prefab, &prefab.root, &remap, None)
+}
+
+fn spawn_entity(world: &World, handle: Handle<Prefab>, prefab: &Prefab, source: &PrefabEntity, remap: &HashMap<UniqueId, UniqueId>, parent: Option<EntityId>) -> Result<EntityId, PrefabError> {
+    let entity = world.spawn_entity();
+    world.insert_component(entity, PrefabInstance { prefab: handle, uid: remap[&source.id] });
+
+    if let Some(name) = &source.name {
+        world.insert_component(entity, Name(name.clone()));
+    }
+
+    for (name, value) in &source.components {
+        let registration = registry::registration(world, name)?;
+        let mut value = registration.migrate(value.clone(), prefab.version(name))?;
+        remap_ids(&mut value, remap);
+        registration.deserialize(world, entity, value)?;
+    }
+
+    if !source.assets.is_empty() {
+        let mut refs = AssetRefs::default();
+        for (name, path) in &source.assets {
+            let id = world.with_resource::<AssetManager, _>(|manager| manager.load_untyped(world, path))
+                .unwrap_or_else(|| Err(AssetError::NoLoader(path.clone())))?;
+            refs.0.insert(name.clone(), id);
+        }
+        world.insert_component(entity, refs);
+    }
+
+    if let Some(parent) = parent {
+        hierarchy::set_parent(world, entity, parent);
+    }
+
+    for child in &source.children {
+        spawn_entity(world, handle, prefab, child, remap, Some(entity))?;
+    }
+
+    Ok(entity)
+}
+
+/// Files without a format are from before it was recorded
+fn legacy_format() -> u32 {
+    1
+}
+
+/// Rewrites every serialized `UniqueId` in `value` that refers to a prefab-local id
+fn remap_ids(value: &mut serde_json::Value, remap: &HashMap<UniqueId, UniqueId>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            if map.len() == 1 && map.contains_key("_unique") {
+                if let Ok(id) = serde_json::from_value::<UniqueId>(serde_json::Value::Object(map.clone())) {
+                    if let Some(new_id) = remap.get(&id) {
+                        *value = serde_json::to_value(new_id).expect("unable to serialize UniqueId");
+                    }
+                }
+                return
+            }
+            map.values_mut().for_each(|v| remap_ids(v, remap));
+        },
+        serde_json::Value::Array(values) => values.iter_mut().for_each(|v| remap_ids(v, remap)),
+        _ => (),
+    }
+}
+
+/// Registers the prefab asset loader and storage with a world
+pub(crate) fn init_prefabs(world: &World, manager: &mut AssetManager) {
+    manager.add_loader(PrefabLoader);
+    if !world.contains_resource::<Assets<Prefab>>() {
+        world.insert_resource(Assets::<Prefab>::default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remap_nested_unique_ids() {
+        let local = UniqueId::get();
+        let outside = UniqueId::get();
+        let fresh = UniqueId::get();
+        let remap: HashMap<UniqueId, UniqueId> = [(local, fresh)].into_iter().collect();
+
+        let mut value = serde_json::json!({
+            "target": local,
+            "others": [local, outside],
+        });
+        remap_ids(&mut value, &remap);
+
+        let target: UniqueId = serde_json::from_value(value["target"].clone()).unwrap();
+        let others: Vec<UniqueId> = serde_json::from_value(value["others"].clone()).unwrap();
+        assert_eq!(target, fresh);
+        assert_eq!(others, vec![fresh, outside]);
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+    struct Target(UniqueId);
+
+    #[test]
+    fn spawn_remaps_ids_and_rebuilds_hierarchy() {
+        let world = World::new();
+        registry::register_component::<Target>(&world, "target");
+        let entity = |id, name: &str, components: BTreeMap<String, serde_json::Value>, children| PrefabEntity {
+            id, name: Some(String::from(name)), components, assets: BTreeMap::new(), children,
+        };
+        let (root_id, child_id, leaf_id) = (UniqueId::get(), UniqueId::get(), UniqueId::get());
+        let leaf = entity(leaf_id, "leaf", BTreeMap::new(), Vec::new());
+        let child = entity(child_id, "child", BTreeMap::from([(String::from("target"), serde_json::json!(root_id))]), vec![leaf]);
+        let root = entity(root_id, "root", BTreeMap::new(), vec![child]);
+
+        world.insert_resource(Assets::<Prefab>::default());
+        let handle = world.with_resource_mut::<Assets<Prefab>, _>(|assets| {
+            assets.add(Prefab { format: PREFAB_FORMAT, versions: ComponentVersions::default(), root })
+        }).unwrap();
+
+        let spawned = spawn_prefab(&world, handle).unwrap();
+        let again = spawn_prefab(&world, handle).unwrap();
+        assert_ne!(spawned, again);
+
+        let tree = hierarchy::descendants(&world, spawned);
+        assert_eq!(tree.len(), 3);
+        assert_eq!(hierarchy::children(&world, spawned), vec![tree[1]]);
+        assert_eq!(hierarchy::parent(&world, tree[2]), Some(tree[1]));
+        let names: Vec<_> = tree.iter().filter_map(|entity| world.component::<Name, _>(*entity, |name| name.0.clone())).collect();
+        assert_eq!(names, vec!["root", "child", "leaf"]);
+
+        // Every instance gets fresh ids, and references inside it follow them
+        let uid = |entity| world.component::<PrefabInstance, _>(entity, |instance| instance.uid).unwrap();
+        assert!([root_id, child_id, leaf_id].iter().all(|id| !tree.iter().any(|entity| uid(*entity) == *id)));
+        assert_ne!(uid(spawned), uid(again));
+        assert_eq!(world.component::<Target, _>(tree[1], |target| *target), Some(Target(uid(spawned))));
+    }
+}
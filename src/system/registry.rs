@@ -0,0 +1,384 @@
+//!
+//! Component registry
+//!
+//! Component types register a name along with whichever of serialization, deserialization and default construction
+//! they support. Tooling that works over arbitrary components, like prefabs, the inspector, world snapshots and save
+//! files, looks types up here by name instead of hardcoding them, so user defined components work the same as the
+//! engine's own. Every world starts out with the engine components registered
+//!
+//! A serializable component also has a version, 1 unless registered otherwise. When its serialized form changes the
+//! version goes up and a migration is registered to bring the previous version's JSON up to date, so saves and
+//! prefabs written against older versions keep loading. Files record the version each component was written at, see
+//! `Prefab::versions`
+//!
+
+use std::{any::TypeId, collections::{BTreeMap, HashMap}, marker::PhantomData};
+
+use collider::EntityId;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+use crate::graphics::{layers::RenderLayers, lod::LodGroup, particles::ParticleEmitter, terrain::Terrain, tilemap::Tilemap};
+
+use super::{world::World, component::Component, transform::Transform, prefab::Name};
+
+type SerializeFn = fn(&World, EntityId) -> Option<Result<Value, String>>;
+type DeserializeFn = fn(&World, EntityId, Value) -> Result<(), String>;
+type DefaultFn = fn(&World, EntityId);
+/// Brings a component's JSON from one version to the next
+pub type MigrateFn = fn(Value) -> Result<Value, String>;
+
+/// Version each component was serialized at, keyed by registered name. Components missing from it are at version 1
+pub type ComponentVersions = BTreeMap<String, u32>;
+
+/// What the registry knows about one component type
+#[derive(Debug, Clone)]
+pub struct ComponentRegistration {
+    name: String,
+    type_id: TypeId,
+    type_name: &'static str,
+    entities: fn(&World) -> Vec<EntityId>,
+    contains: fn(&World, EntityId) -> bool,
+    remove: fn(&World, EntityId) -> bool,
+    serialize: Option<SerializeFn>,
+    deserialize: Option<DeserializeFn>,
+    default: Option<DefaultFn>,
+    /// Version of the serialized form
+    version: u32,
+    /// Migrations keyed by the version they upgrade from
+    migrations: BTreeMap<u32, MigrateFn>,
+}
+
+/// World resource of registered component types, by name and by type
+#[derive(Debug, Clone, Default)]
+pub struct ComponentRegistry {
+    by_name: BTreeMap<String, ComponentRegistration>,
+    by_type: HashMap<TypeId, String>,
+}
+
+/// Adds capabilities to a registration, each bounded on what `T` implements
+pub struct Registration<'a, T> {
+    registration: &'a mut ComponentRegistration,
+    marker: PhantomData<T>,
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+    UnknownComponent(String),
+    /// The component type wasn't registered with the capability
+    Unsupported(String, &'static str),
+    Serialize(String, String),
+    Deserialize(String, String),
+    /// Serialized data of a version that couldn't be brought up to date
+    Migration(String, u32, String),
+}
+
+// Impls
+
+impl std::error::Error for RegistryError {}
+
+impl std::fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistryError::UnknownComponent(name) => write!(f, "unregistered component {}", name),
+            RegistryError::Unsupported(name, capability) => write!(f, "component {} wasn't registered with {}", name, capability),
+            RegistryError::Serialize(name, err) => write!(f, "unable to serialize component {}: {}", name, err),
+            RegistryError::Deserialize(name, err) => write!(f, "unable to deserialize component {}: {}", name, err),
+            RegistryError::Migration(name, version, err) => write!(f, "unable to migrate component {} from version {}: {}", name, version, err),
+        }
+    }
+}
+
+impl ComponentRegistration {
+    fn new<T: Component>(name: &str) -> Self {
+        ComponentRegistration {
+            name: String::from(name),
+            type_id: TypeId::of::<T>(),
+            type_name: std::any::type_name::<T>(),
+            entities: |world| world.query::<T, ()>(),
+            contains: |world, entity| world.has_component::<T>(entity),
+            remove: |world, entity| world.remove_component::<T>(entity).is_some(),
+            serialize: None,
+            deserialize: None,
+            default: None,
+            version: 1,
+            migrations: BTreeMap::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn type_id(&self) -> TypeId {
+        self.type_id
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+
+    pub fn is_serializable(&self) -> bool {
+        self.serialize.is_some()
+    }
+
+    pub fn is_deserializable(&self) -> bool {
+        self.deserialize.is_some()
+    }
+
+    pub fn has_default(&self) -> bool {
+        self.default.is_some()
+    }
+
+    /// Version of the serialized form, what `serialize` writes
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Every entity with this component
+    pub fn entities(&self, world: &World) -> Vec<EntityId> {
+        (self.entities)(world)
+    }
+
+    pub fn contains(&self, world: &World, entity: EntityId) -> bool {
+        (self.contains)(world, entity)
+    }
+
+    pub fn remove(&self, world: &World, entity: EntityId) -> bool {
+        (self.remove)(world, entity)
+    }
+
+    /// The entity's component as JSON, `None` if it doesn't have one
+    pub fn serialize(&self, world: &World, entity: EntityId) -> Result<Option<Value>, RegistryError> {
+        let serialize = self.serialize.ok_or_else(|| RegistryError::Unsupported(self.name.clone(), "serialization"))?;
+        serialize(world, entity).transpose().map_err(|err| RegistryError::Serialize(self.name.clone(), err))
+    }
+
+    /// Inserts the component read from JSON, replacing any the entity already has
+    pub fn deserialize(&self, world: &World, entity: EntityId, value: Value) -> Result<(), RegistryError> {
+        let deserialize = self.deserialize.ok_or_else(|| RegistryError::Unsupported(self.name.clone(), "deserialization"))?;
+        deserialize(world, entity, value).map_err(|err| RegistryError::Deserialize(self.name.clone(), err))
+    }
+
+    /// Brings JSON serialized at `version` up to the current version, one registered migration at a time
+    pub fn migrate(&self, mut value: Value, version: u32) -> Result<Value, RegistryError> {
+        if version > self.version {
+            return Err(RegistryError::Migration(self.name.clone(), version, format!("newer than the registered version {}", self.version)))
+        }
+        for from in version..self.version {
+            let migrate = self.migrations.get(&from).ok_or_else(|| RegistryError::Migration(self.name.clone(), from, String::from("no migration registered")))?;
+            value = migrate(value).map_err(|err| RegistryError::Migration(self.name.clone(), from, err))?;
+        }
+        Ok(value)
+    }
+
+    /// Inserts the component read from JSON serialized at `version`, migrating it first
+    pub fn deserialize_versioned(&self, world: &World, entity: EntityId, value: Value, version: u32) -> Result<(), RegistryError> {
+        let value = self.migrate(value, version)?;
+        self.deserialize(world, entity, value)
+    }
+
+    pub fn insert_default(&self, world: &World, entity: EntityId) -> Result<(), RegistryError> {
+        let default = self.default.ok_or_else(|| RegistryError::Unsupported(self.name.clone(), "a default"))?;
+        default(world, entity);
+        Ok(())
+    }
+}
+
+impl ComponentRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `T` as `name`, or returns its existing registration to add capabilities to. A name can only belong to
+    /// one type
+    pub fn register<T: Component>(&mut self, name: &str) -> Registration<'_, T> {
+        let type_id = TypeId::of::<T>();
+        if let Some(previous) = self.by_type.get(&type_id).filter(|previous| previous.as_str() != name).cloned() {
+            self.by_name.remove(&previous);
+        }
+        debug_assert!(self.by_name.get(name).is_none_or(|existing| existing.type_id == type_id), "component name {} is already registered to another type", name);
+
+        self.by_type.insert(type_id, String::from(name));
+        let registration = self.by_name.entry(String::from(name))
+            .and_modify(|existing| if existing.type_id != type_id { *existing = ComponentRegistration::new::<T>(name) })
+            .or_insert_with(|| ComponentRegistration::new::<T>(name));
+        Registration { registration, marker: PhantomData }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ComponentRegistration> {
+        self.by_name.get(name)
+    }
+
+    pub fn get_type<T: Component>(&self) -> Option<&ComponentRegistration> {
+        self.by_type.get(&TypeId::of::<T>()).and_then(|name| self.by_name.get(name))
+    }
+
+    pub fn name_of<T: Component>(&self) -> Option<&str> {
+        self.by_type.get(&TypeId::of::<T>()).map(String::as_str)
+    }
+
+    /// Registrations in name order
+    pub fn iter(&self) -> impl Iterator<Item = &ComponentRegistration> {
+        self.by_name.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// Current version of every serializable component, for a file's header
+    pub fn versions(&self) -> ComponentVersions {
+        self.iter().filter(|registration| registration.is_serializable()).map(|registration| (registration.name.clone(), registration.version)).collect()
+    }
+}
+
+impl<'a, T: Component> Registration<'a, T> {
+    pub fn with_serialize(self) -> Self where T: Serialize {
+        self.registration.serialize = Some(|world, entity| world.component::<T, _>(entity, |component| serde_json::to_value(component).map_err(|err| err.to_string())));
+        self
+    }
+
+    pub fn with_deserialize(self) -> Self where T: DeserializeOwned {
+        self.registration.deserialize = Some(|world, entity, value| {
+            let component: T = serde_json::from_value(value).map_err(|err| err.to_string())?;
+            world.insert_component(entity, component);
+            Ok(())
+        });
+        self
+    }
+
+    pub fn with_serde(self) -> Self where T: Serialize + DeserializeOwned {
+        self.with_serialize().with_deserialize()
+    }
+
+    pub fn with_default(self) -> Self where T: Default {
+        self.registration.default = Some(|world, entity| { world.insert_component(entity, T::default()); });
+        self
+    }
+
+    /// Sets the version of the serialized form. Raising it needs a migration from the previous version
+    pub fn with_version(self, version: u32) -> Self {
+        self.registration.version = version.max(1);
+        self
+    }
+
+    /// Adds the migration from version `from` to `from + 1`, raising the version to at least `from + 1`
+    pub fn with_migration(self, from: u32, migrate: MigrateFn) -> Self {
+        self.registration.migrations.insert(from, migrate);
+        self.registration.version = self.registration.version.max(from + 1);
+        self
+    }
+}
+
+/// Registers `T` as `name`, serialized and deserialized through serde
+pub fn register_component<T: Component + Serialize + DeserializeOwned>(world: &World, name: &str) {
+    register_with::<T, _>(world, name, |registration| { registration.with_serde(); });
+}
+
+/// Registers `T` as `name` with the capabilities `f` adds, for types that aren't fully serializable
+pub fn register_with<T: Component, F: FnOnce(Registration<'_, T>)>(world: &World, name: &str, f: F) {
+    if !world.contains_resource::<ComponentRegistry>() {
+        world.insert_resource(ComponentRegistry::default());
+    }
+    world.with_resource_mut::<ComponentRegistry, _>(|registry| f(registry.register::<T>(name)));
+}
+
+/// A copy of the world's registry, so registered functions can be called without holding it locked
+pub fn registry(world: &World) -> ComponentRegistry {
+    world.with_resource::<ComponentRegistry, _>(ComponentRegistry::clone).unwrap_or_default()
+}
+
+pub fn registration(world: &World, name: &str) -> Result<ComponentRegistration, RegistryError> {
+    world.with_resource::<ComponentRegistry, _>(|registry| registry.get(name).cloned())
+        .flatten()
+        .ok_or_else(|| RegistryError::UnknownComponent(String::from(name)))
+}
+
+/// Every serializable component of `entity`, keyed by registered name, e.g. for a save file
+pub fn serialize_entity(world: &World, entity: EntityId) -> Result<BTreeMap<String, Value>, RegistryError> {
+    let mut components = BTreeMap::new();
+    for registration in registry(world).iter().filter(|registration| registration.is_serializable()) {
+        if let Some(value) = registration.serialize(world, entity)? {
+            components.insert(registration.name.clone(), value);
+        }
+    }
+    Ok(components)
+}
+
+/// Inserts components keyed by registered name onto `entity`, the reverse of `serialize_entity`. Each is migrated
+/// from the version in `versions` first
+pub fn deserialize_entity(world: &World, entity: EntityId, components: &BTreeMap<String, Value>, versions: &ComponentVersions) -> Result<(), RegistryError> {
+    for (name, value) in components {
+        let version = versions.get(name).copied().unwrap_or(1);
+        registration(world, name)?.deserialize_versioned(world, entity, value.clone(), version)?;
+    }
+    Ok(())
+}
+
+/// Current version of every serializable component in the world's registry
+pub fn versions(world: &World) -> ComponentVersions {
+    world.with_resource::<ComponentRegistry, _>(ComponentRegistry::versions).unwrap_or_default()
+}
+
+/// The engine's own components
+pub(crate) fn init_registry(world: &World) {
+    let mut registry = ComponentRegistry::default();
+    registry.register::<Transform>("transform").with_serde().with_default();
+    registry.register::<Name>("name").with_serde();
+    registry.register::<ParticleEmitter>("particle_emitter").with_serde().with_default();
+    registry.register::<Terrain>("terrain").with_serde().with_default();
+    registry.register::<Tilemap>("tilemap").with_serde().with_default();
+    registry.register::<LodGroup>("lod_group").with_serde().with_default();
+    registry.register::<RenderLayers>("render_layers").with_serde().with_default();
+    world.insert_resource(registry);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, serde::Deserialize, Default)]
+    struct Health(u32);
+
+    #[test]
+    fn register_and_look_up() {
+        let world = World::new();
+        assert_eq!(registry(&world).name_of::<Transform>(), Some("transform"));
+
+        register_with::<Health, _>(&world, "health", |registration| { registration.with_default(); });
+        register_component::<Health>(&world, "health");
+        let registry = registry(&world);
+        let health = registry.get("health").unwrap();
+        assert!(health.has_default() && health.is_serializable() && health.is_deserializable());
+        assert_eq!(health.type_id(), TypeId::of::<Health>());
+        assert_eq!(registry.get_type::<Health>().map(ComponentRegistration::name), Some("health"));
+
+        // Renaming a type drops its old name
+        register_component::<Health>(&world, "hit_points");
+        assert!(super::registry(&world).get("health").is_none());
+        assert!(matches!(registration(&world, "health"), Err(RegistryError::UnknownComponent(_))));
+        assert!(!registration(&world, "name").unwrap().has_default());
+    }
+
+    #[test]
+    fn old_versions_are_migrated() {
+        let mut registry = ComponentRegistry::new();
+        registry.register::<Health>("health").with_serde()
+            .with_migration(1, |value| value.get("hp").cloned().ok_or_else(|| String::from("missing hp")))
+            .with_migration(2, |value| Ok(Value::from(value.as_u64().unwrap_or(0) * 10)));
+        let health = registry.get("health").unwrap();
+        assert_eq!(health.version(), 3);
+        assert_eq!(registry.versions().get("health"), Some(&3));
+
+        assert_eq!(health.migrate(serde_json::json!({ "hp": 4 }), 1).unwrap(), Value::from(40));
+        assert_eq!(health.migrate(Value::from(4), 2).unwrap(), Value::from(40));
+        assert_eq!(health.migrate(Value::from(40), 3).unwrap(), Value::from(40));
+        assert!(matches!(health.migrate(serde_json::json!({}), 1), Err(RegistryError::Migration(_, 1, _))));
+        assert!(matches!(health.migrate(Value::from(40), 4), Err(RegistryError::Migration(_, 4, _))));
+    }
+}
@@ -0,0 +1,221 @@
+//!
+//! Relationships between entities other than parent/child
+//!
+//! A relation links a source entity to a target, e.g. a weapon `Attachment` to the hand it's held in or an ability's
+//! `Target`. Both ends keep the links of each kind in a `Relations<R>` component, so looking up an entity's targets and
+//! looking up every entity targeting it are equally cheap. Despawning an entity unlinks it from everything it was
+//! related to. An exclusive relation allows one target per source, relating it again replaces the previous target
+//!
+
+use std::{collections::HashSet, marker::PhantomData};
+
+use collider::EntityId;
+
+use super::world::World;
+
+/// A kind of relationship, named by a marker type
+pub trait Relation: Send + Sync + 'static {
+    /// Whether a source can only have one target at a time
+    const EXCLUSIVE: bool;
+}
+
+/// The source is physically attached to the target, and should be loaded and unloaded with it
+pub struct Attachment;
+
+/// The source belongs to the target, e.g. items in an inventory
+pub struct Ownership;
+
+/// The source is acting on the target
+pub struct Target;
+
+/// An entity's links of kind `R`, in the order they were made
+#[derive(Debug)]
+pub struct Relations<R> {
+    targets: Vec<EntityId>,
+    sources: Vec<EntityId>,
+    _marker: PhantomData<fn() -> R>,
+}
+
+// Impls
+
+impl Relation for Attachment {
+    const EXCLUSIVE: bool = true;
+}
+
+impl Relation for Ownership {
+    const EXCLUSIVE: bool = true;
+}
+
+impl Relation for Target {
+    const EXCLUSIVE: bool = false;
+}
+
+impl<R> Default for Relations<R> {
+    fn default() -> Self {
+        Relations { targets: Vec::new(), sources: Vec::new(), _marker: PhantomData }
+    }
+}
+
+impl<R> Clone for Relations<R> {
+    fn clone(&self) -> Self {
+        Relations { targets: self.targets.clone(), sources: self.sources.clone(), _marker: PhantomData }
+    }
+}
+
+impl<R> Relations<R> {
+    /// Entities this one relates to
+    pub fn targets(&self) -> &[EntityId] {
+        &self.targets
+    }
+
+    /// Entities relating to this one
+    pub fn sources(&self) -> &[EntityId] {
+        &self.sources
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty() && self.sources.is_empty()
+    }
+}
+
+/// Relates `source` to `target`, replacing the source's previous target if `R` is exclusive
+pub fn relate<R: Relation>(world: &World, source: EntityId, target: EntityId) {
+    if R::EXCLUSIVE {
+        for previous in targets::<R>(world, source) {
+            unrelate::<R>(world, source, previous);
+        }
+    } else if is_related::<R>(world, source, target) {
+        return
+    }
+
+    world.set_component_remover::<Relations<R>>(|world, entity| clear::<R>(world, entity));
+    edit::<R>(world, source, |relations| relations.targets.push(target));
+    edit::<R>(world, target, |relations| relations.sources.push(source));
+}
+
+/// Removes the link from `source` to `target`, returning whether there was one
+pub fn unrelate<R: Relation>(world: &World, source: EntityId, target: EntityId) -> bool {
+    let removed = world.component_mut::<Relations<R>, _>(source, |mut relations| {
+        let before = relations.targets.len();
+        relations.targets.retain(|entity| *entity != target);
+        relations.targets.len() != before
+    }).unwrap_or(false);
+    if removed {
+        world.component_mut::<Relations<R>, _>(target, |mut relations| relations.sources.retain(|entity| *entity != source));
+        remove_if_empty::<R>(world, source);
+        remove_if_empty::<R>(world, target);
+    }
+    removed
+}
+
+/// Removes every link of kind `R` to and from `entity`
+pub fn clear<R: Relation>(world: &World, entity: EntityId) {
+    let Some(relations) = world.remove_component::<Relations<R>>(entity) else {
+        return
+    };
+    for target in relations.targets {
+        world.component_mut::<Relations<R>, _>(target, |mut relations| relations.sources.retain(|source| *source != entity));
+        remove_if_empty::<R>(world, target);
+    }
+    for source in relations.sources {
+        world.component_mut::<Relations<R>, _>(source, |mut relations| relations.targets.retain(|target| *target != entity));
+        remove_if_empty::<R>(world, source);
+    }
+}
+
+pub fn is_related<R: Relation>(world: &World, source: EntityId, target: EntityId) -> bool {
+    world.component::<Relations<R>, _>(source, |relations| relations.targets.contains(&target)).unwrap_or(false)
+}
+
+/// The entities `entity` relates to
+pub fn targets<R: Relation>(world: &World, entity: EntityId) -> Vec<EntityId> {
+    world.component::<Relations<R>, _>(entity, |relations| relations.targets.clone()).unwrap_or_default()
+}
+
+/// The first entity `entity` relates to, its only one for an exclusive relation
+pub fn target<R: Relation>(world: &World, entity: EntityId) -> Option<EntityId> {
+    world.component::<Relations<R>, _>(entity, |relations| relations.targets.first().copied()).flatten()
+}
+
+/// The entities relating to `entity`, e.g. everything targeting it
+pub fn sources<R: Relation>(world: &World, entity: EntityId) -> Vec<EntityId> {
+    world.component::<Relations<R>, _>(entity, |relations| relations.sources.clone()).unwrap_or_default()
+}
+
+/// Returns `entity` followed by everything relating to it directly or through other sources, depth first. Each entity
+/// is listed once even if the relations form a cycle
+pub fn all_sources<R: Relation>(world: &World, entity: EntityId) -> Vec<EntityId> {
+    let mut result = Vec::new();
+    let mut seen = HashSet::new();
+    let mut stack = vec![entity];
+    while let Some(next) = stack.pop() {
+        if !seen.insert(next) {
+            continue
+        }
+        result.push(next);
+        let mut next_sources = sources::<R>(world, next);
+        next_sources.reverse();
+        stack.extend(next_sources);
+    }
+    result
+}
+
+fn edit<R: Relation>(world: &World, entity: EntityId, f: impl Fn(&mut Relations<R>)) {
+    if world.component_mut::<Relations<R>, _>(entity, |mut relations| f(&mut relations)).is_none() {
+        let mut relations = Relations::default();
+        f(&mut relations);
+        world.insert_component(entity, relations);
+    }
+}
+
+fn remove_if_empty<R: Relation>(world: &World, entity: EntityId) {
+    if world.component::<Relations<R>, _>(entity, Relations::is_empty).unwrap_or(false) {
+        world.remove_component::<Relations<R>>(entity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reverse_index_follows_relate_and_unrelate() {
+        let world = World::new();
+        let [hand, other_hand, sword, enemy] = std::array::from_fn(|_| world.spawn_entity());
+
+        relate::<Target>(&world, sword, enemy);
+        relate::<Target>(&world, hand, enemy);
+        relate::<Target>(&world, hand, enemy);
+        assert_eq!(sources::<Target>(&world, enemy), vec![sword, hand]);
+
+        // Exclusive relations move the source out of the previous target's sources
+        relate::<Attachment>(&world, sword, hand);
+        relate::<Attachment>(&world, sword, other_hand);
+        assert_eq!(target::<Attachment>(&world, sword), Some(other_hand));
+        assert!(sources::<Attachment>(&world, hand).is_empty());
+        assert_eq!(sources::<Attachment>(&world, other_hand), vec![sword]);
+
+        assert!(unrelate::<Target>(&world, sword, enemy));
+        assert!(!unrelate::<Target>(&world, sword, enemy));
+        assert_eq!(sources::<Target>(&world, enemy), vec![hand]);
+        assert!(world.component::<Relations<Attachment>, _>(hand, |_| ()).is_none());
+    }
+
+    #[test]
+    fn despawning_unlinks_both_ends() {
+        let world = World::new();
+        let [owner, item, enemy] = std::array::from_fn(|_| world.spawn_entity());
+        relate::<Ownership>(&world, item, owner);
+        relate::<Target>(&world, owner, enemy);
+        relate::<Target>(&world, item, enemy);
+
+        world.despawn_entity(owner);
+        assert_eq!(target::<Ownership>(&world, item), None);
+        assert_eq!(sources::<Target>(&world, enemy), vec![item]);
+        assert!(world.component::<Relations<Ownership>, _>(item, |_| ()).is_none());
+
+        world.despawn_entity(enemy);
+        assert!(targets::<Target>(&world, item).is_empty());
+        assert!(world.component::<Relations<Target>, _>(item, |_| ()).is_none());
+    }
+}
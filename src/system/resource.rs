@@ -0,0 +1,62 @@
+use std::{any::{Any, TypeId}, collections::HashMap, sync::{Arc, RwLock}};
+
+/// Shared handle to a resource stored in the `World`
+pub type Res<T> = Arc<RwLock<T>>;
+
+/// Anything that can be stored as a singleton in the `World`
+pub trait Resource: Send + Sync + 'static {}
+
+impl<T: Send + Sync + 'static> Resource for T {}
+
+/// A type map of singleton resources. Each resource has its own lock so that holding one
+/// resource doesn't block access to any other
+#[derive(Default)]
+pub(crate) struct Resources {
+    map: RwLock<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>,
+}
+
+impl Resources {
+    /// Inserts a resource, returning the previous one of the same type if there was one
+    pub(crate) fn insert<T: Resource>(&self, resource: T) -> Option<Res<T>> {
+        let resource: Res<T> = Arc::new(RwLock::new(resource));
+        let previous = self.map.write().expect("resource map poisoned").insert(TypeId::of::<T>(), resource);
+        previous.map(|p| Self::downcast(p))
+    }
+
+    pub(crate) fn get<T: Resource>(&self) -> Option<Res<T>> {
+        self.map.read().expect("resource map poisoned").get(&TypeId::of::<T>()).cloned().map(|r| Self::downcast(r))
+    }
+
+    /// Returns the resource of type `T`, inserting the result of `f` first if there isn't one
+    pub(crate) fn get_or_insert_with<T: Resource>(&self, f: impl FnOnce() -> T) -> Res<T> {
+        if let Some(resource) = self.get::<T>() {
+            return resource
+        }
+
+        let mut map = self.map.write().expect("resource map poisoned");
+        let resource = map.entry(TypeId::of::<T>()).or_insert_with(|| Arc::new(RwLock::new(f()))).clone();
+        Self::downcast(resource)
+    }
+
+    pub(crate) fn remove<T: Resource>(&self) -> Option<Res<T>> {
+        self.map.write().expect("resource map poisoned").remove(&TypeId::of::<T>()).map(|r| Self::downcast(r))
+    }
+
+    pub(crate) fn contains<T: Resource>(&self) -> bool {
+        self.map.read().expect("resource map poisoned").contains_key(&TypeId::of::<T>())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.map.read().expect("resource map poisoned").len()
+    }
+
+    fn downcast<T: Resource>(resource: Arc<dyn Any + Send + Sync>) -> Res<T> {
+        resource.downcast::<RwLock<T>>().expect("resource stored under the wrong type id")
+    }
+}
+
+impl std::fmt::Debug for Resources {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Resources").field("count", &self.len()).finish()
+    }
+}
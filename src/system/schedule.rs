@@ -0,0 +1,335 @@
+//!
+//! Ordered stages of systems run once per frame against the `World`
+//!
+
+use std::{any::{Any, TypeId}, time::{Duration, Instant}};
+
+use crate::debug::{frame_step::FrameStep, log, profiler};
+use super::{world::World, state::{State, StateMachine}, component::Tick, commands, condition::{self, RunCondition}, determinism, time::FixedTime};
+
+/// Built-in stage names, run in this order by a default `Schedule`
+pub mod stage {
+    pub const PRE_UPDATE: &str = "pre_update";
+    /// Runs once per `FixedTime` step rather than once per frame
+    pub const FIXED_UPDATE: &str = "fixed_update";
+    pub const UPDATE: &str = "update";
+    pub const POST_UPDATE: &str = "post_update";
+}
+
+pub type SystemFn = Box<dyn FnMut(&World) + Send + Sync>;
+
+/// A named unit of per-frame work
+pub struct System {
+    name: String,
+    run: SystemFn,
+    condition: Option<RunCondition>,
+    last_run: Tick,
+}
+
+pub struct Stage {
+    name: String,
+    systems: Vec<System>,
+    /// Run once per `FixedTime` step
+    fixed: bool,
+    /// Checked on every run, or every tick of a fixed stage
+    condition: Option<RunCondition>,
+}
+
+pub struct Schedule {
+    stages: Vec<Stage>,
+    /// In registration order, so transitions are applied in the same order every run
+    states: Vec<(TypeId, Box<dyn StateDriver>)>,
+}
+
+/// Type erased access to a `StateMachine<S>`
+pub(crate) trait StateDriver: Send + Sync {
+    fn apply_transitions(&mut self, world: &World);
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+// Impls
+
+impl System {
+    pub fn new<F>(name: &str, run: F) -> Self
+    where
+        F: FnMut(&World) + Send + Sync + 'static
+    {
+        System {
+            name: String::from(name),
+            run: Box::new(run),
+            condition: None,
+            last_run: 0,
+        }
+    }
+
+    /// Only runs the system while `condition` holds, see `condition`
+    pub fn with_condition(mut self, condition: RunCondition) -> Self {
+        self.condition = Some(condition);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Runs the system unless it's switched off or its run condition doesn't hold, returns whether it ran
+    pub(crate) fn run(&mut self, world: &World) -> bool {
+        if !should_run(world, &self.name, &self.condition) {
+            return false
+        }
+
+        let _span = log::span(&self.name);
+        // Change detection compares against the tick this system last ran at
+        let this_run = world.increment_change_tick();
+        world.set_last_run_tick(self.last_run);
+        (self.run)(world);
+        self.last_run = this_run;
+        true
+    }
+}
+
+impl std::fmt::Debug for System {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("System").field("name", &self.name).finish()
+    }
+}
+
+impl Stage {
+    pub fn new(name: &str) -> Self {
+        Stage {
+            name: String::from(name),
+            systems: Vec::new(),
+            fixed: false,
+            condition: None,
+        }
+    }
+
+    /// A stage run zero or more times a frame, once for every fixed step of elapsed time
+    pub fn fixed(name: &str) -> Self {
+        Stage { fixed: true, ..Stage::new(name) }
+    }
+
+    /// Only runs the stage's systems while `condition` holds
+    pub fn with_condition(mut self, condition: RunCondition) -> Self {
+        self.condition = Some(condition);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_fixed(&self) -> bool {
+        self.fixed
+    }
+
+    pub fn systems(&self) -> &[System] {
+        &self.systems
+    }
+
+    fn run(&mut self, world: &World) {
+        let _span = log::span(&self.name);
+        if !self.fixed {
+            if should_run(world, &self.name, &self.condition) {
+                self.run_systems(world);
+            }
+            return
+        }
+
+        // Commands are applied between ticks so each one sees the previous tick's results. Skipped ticks still use up
+        // their time
+        while fixed_tick(world) {
+            if should_run(world, &self.name, &self.condition) {
+                self.run_systems(world);
+            }
+            commands::apply_commands(world);
+            determinism::end_tick(world);
+        }
+    }
+
+    /// Runs each system once, timing them for the frame profile if there is one
+    fn run_systems(&mut self, world: &World) {
+        if !profiler::is_profiling(world) {
+            self.systems.iter_mut().for_each(|system| { system.run(world); });
+            return
+        }
+
+        let started = Instant::now();
+        let mut timings: Vec<(&str, Duration)> = Vec::with_capacity(self.systems.len());
+        for system in &mut self.systems {
+            let system_started = Instant::now();
+            if system.run(world) {
+                timings.push((&system.name, system_started.elapsed()));
+            }
+        }
+        profiler::record_stage(world, &self.name, started.elapsed(), &timings);
+    }
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule::empty()
+            .with_stage(stage::PRE_UPDATE)
+            .with_fixed_stage(stage::FIXED_UPDATE)
+            .with_stage(stage::UPDATE)
+            .with_stage(stage::POST_UPDATE)
+    }
+}
+
+impl Schedule {
+    /// A schedule without any stages
+    pub fn empty() -> Self {
+        Schedule {
+            stages: Vec::new(),
+            states: Vec::new(),
+        }
+    }
+
+    pub fn with_stage(mut self, name: &str) -> Self {
+        self.add_stage(name); self
+    }
+
+    pub fn with_fixed_stage(mut self, name: &str) -> Self {
+        self.add_fixed_stage(name); self
+    }
+
+    /// Appends a stage to the end of the schedule
+    pub fn add_stage(&mut self, name: &str) {
+        debug_assert!(self.stage(name).is_none(), "duplicate stage {}", name);
+        self.stages.push(Stage::new(name));
+    }
+
+    /// Appends a stage run once per fixed step, see `FixedTime`
+    pub fn add_fixed_stage(&mut self, name: &str) {
+        debug_assert!(self.stage(name).is_none(), "duplicate stage {}", name);
+        self.stages.push(Stage::fixed(name));
+    }
+
+    /// Inserts a stage directly after an existing one
+    pub fn add_stage_after(&mut self, after: &str, name: &str) {
+        debug_assert!(self.stage(name).is_none(), "duplicate stage {}", name);
+        let index = self.stage_index(after).expect("no such stage");
+        self.stages.insert(index + 1, Stage::new(name));
+    }
+
+    pub fn stage(&self, name: &str) -> Option<&Stage> {
+        self.stages.iter().find(|s| s.name == name)
+    }
+
+    pub fn stages(&self) -> &[Stage] {
+        &self.stages
+    }
+
+    pub fn add_system<F>(&mut self, stage: &str, name: &str, run: F)
+    where
+        F: FnMut(&World) + Send + Sync + 'static
+    {
+        self.push_system(stage, System::new(name, run));
+    }
+
+    /// Adds a system that only runs while `condition` holds, see `condition`
+    pub fn add_conditional_system<F>(&mut self, stage: &str, name: &str, condition: RunCondition, run: F)
+    where
+        F: FnMut(&World) + Send + Sync + 'static
+    {
+        self.push_system(stage, System::new(name, run).with_condition(condition));
+    }
+
+    /// Only runs the systems of `stage` while `condition` holds, replacing any condition it had
+    pub fn set_stage_condition(&mut self, stage: &str, condition: RunCondition) {
+        let index = self.stage_index(stage).unwrap_or_else(|| panic!("no such stage {}", stage));
+        self.stages[index].condition = Some(condition);
+    }
+
+    pub(crate) fn push_system(&mut self, stage: &str, system: System) {
+        let index = self.stage_index(stage).unwrap_or_else(|| panic!("no such stage {}", stage));
+        self.stages[index].systems.push(system);
+    }
+
+    /// Registers a state type with its initial state. Transitions are applied at the start of each run
+    pub fn add_state<S: State>(&mut self, world: &World, initial: S) {
+        debug_assert!(self.states.iter().all(|(id, _)| *id != TypeId::of::<S>()), "state type registered twice");
+        let machine = StateMachine::new(world, initial);
+        self.states.push((TypeId::of::<S>(), Box::new(machine)));
+    }
+
+    /// Adds a system to `stage` that only runs while `state` is the active (topmost) state
+    pub fn add_state_system<S: State, F>(&mut self, state: S, stage: &str, name: &str, run: F)
+    where
+        F: FnMut(&World) + Send + Sync + 'static
+    {
+        self.add_conditional_system(stage, name, condition::in_state(state), run);
+    }
+
+    /// Adds a per-frame update hook for `state`, run in the update stage while it is active
+    pub fn on_update<S: State, F>(&mut self, state: S, name: &str, run: F)
+    where
+        F: FnMut(&World) + Send + Sync + 'static
+    {
+        self.add_state_system(state, stage::UPDATE, name, run);
+    }
+
+    /// Adds a hook that runs when `state` is entered
+    pub fn on_enter<S: State, F>(&mut self, state: S, name: &str, run: F)
+    where
+        F: FnMut(&World) + Send + Sync + 'static
+    {
+        self.state_machine_mut::<S>().add_enter(state, System::new(name, run));
+    }
+
+    /// Adds a hook that runs when `state` is exited
+    pub fn on_exit<S: State, F>(&mut self, state: S, name: &str, run: F)
+    where
+        F: FnMut(&World) + Send + Sync + 'static
+    {
+        self.state_machine_mut::<S>().add_exit(state, System::new(name, run));
+    }
+
+    /// Runs every stage in order, applying queued commands at each stage boundary
+    pub fn run(&mut self, world: &World) {
+        for (_, driver) in &mut self.states {
+            driver.apply_transitions(world);
+        }
+        commands::apply_commands(world);
+
+        for stage in &mut self.stages {
+            stage.run(world);
+            commands::apply_commands(world);
+        }
+    }
+
+    fn stage_index(&self, name: &str) -> Option<usize> {
+        self.stages.iter().position(|s| s.name == name)
+    }
+
+    fn state_machine_mut<S: State>(&mut self) -> &mut StateMachine<S> {
+        self.states
+            .iter_mut()
+            .find(|(id, _)| *id == TypeId::of::<S>())
+            .map(|(_, driver)| driver)
+            .expect("state type not registered, call add_state first")
+            .as_any_mut()
+            .downcast_mut::<StateMachine<S>>()
+            .expect("state machine stored under the wrong type id")
+    }
+}
+
+impl std::fmt::Debug for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let stages: Vec<(&str, usize)> = self.stages.iter().map(|s| (s.name(), s.systems.len())).collect();
+        f.debug_struct("Schedule").field("stages", &stages).finish()
+    }
+}
+
+/// Whether the system or stage `name` should run, being switched on and its condition holding
+fn should_run(world: &World, name: &str, condition: &Option<RunCondition>) -> bool {
+    !condition::is_disabled(world, name) && condition.as_ref().is_none_or(|condition| condition(world))
+}
+
+/// Whether a fixed stage should run another tick. While frame stepping only requested ticks run, regardless of time
+fn fixed_tick(world: &World) -> bool {
+    match world.with_resource_mut::<FrameStep, _>(FrameStep::take).flatten() {
+        Some(step) => step && world.with_resource_mut::<FixedTime, _>(FixedTime::force_tick).is_some(),
+        None => world.with_resource_mut::<FixedTime, _>(FixedTime::expend).unwrap_or(false),
+    }
+}
@@ -0,0 +1,199 @@
+//!
+//! Stack based app states with enter/exit hooks
+//!
+//! Pushing a state suspends the one below it without exiting it, so e.g. `Paused` can be pushed over `InGame`
+//! and popped again without re-entering `InGame`. Only the topmost state is considered active
+//!
+
+use std::{any::Any, collections::HashMap, fmt::Debug, hash::Hash};
+
+use serde::{Serialize, Deserialize};
+
+use crate::debug::log;
+use super::{world::World, event, schedule::{System, StateDriver}};
+
+/// Anything usable as a state in a `StateStack`
+pub trait State: Clone + PartialEq + Eq + Hash + Debug + Send + Sync + 'static {}
+
+impl<T: Clone + PartialEq + Eq + Hash + Debug + Send + Sync + 'static> State for T {}
+
+/// The engine's default set of app states
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AppState {
+    Loading,
+    MainMenu,
+    InGame,
+    Paused,
+}
+
+/// Requested changes to a `StateStack<S>`, sent as events and applied at the start of the next schedule run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateTransition<S> {
+    /// Suspends the current state and enters a new one on top of it
+    Push(S),
+    /// Exits the current state, resuming the one below it
+    Pop,
+    /// Exits the current state and enters a new one in its place
+    Replace(S),
+    /// Exits every state on the stack and enters a new one
+    Reset(S),
+}
+
+/// World resource holding the current stack of states, bottom first
+#[derive(Debug, Clone)]
+pub struct StateStack<S> {
+    stack: Vec<S>,
+}
+
+/// Owns the enter/exit hooks for a state type and applies transitions
+pub(crate) struct StateMachine<S> {
+    on_enter: HashMap<S, Vec<System>>,
+    on_exit: HashMap<S, Vec<System>>,
+    log: log::Logger,
+}
+
+// Impls
+
+impl<S: State> StateStack<S> {
+    pub fn current(&self) -> Option<&S> {
+        self.stack.last()
+    }
+
+    /// Whether `state` is the topmost state
+    pub fn is_active(&self, state: &S) -> bool {
+        self.current() == Some(state)
+    }
+
+    /// Whether `state` is anywhere in the stack, active or suspended
+    pub fn contains(&self, state: &S) -> bool {
+        self.stack.contains(state)
+    }
+
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &S> {
+        self.stack.iter()
+    }
+}
+
+impl<S: State> StateMachine<S> {
+    pub(crate) fn new(world: &World, initial: S) -> Self {
+        event::init_events::<StateTransition<S>>(world);
+        world.insert_resource(StateStack::<S> { stack: Vec::new() });
+
+        // The initial state is entered like any other on the first run
+        event::send_event(world, StateTransition::Push(initial));
+
+        StateMachine {
+            on_enter: HashMap::new(),
+            on_exit: HashMap::new(),
+            log: log::Logger::default(),
+        }
+    }
+
+    pub(crate) fn add_enter(&mut self, state: S, system: System) {
+        self.on_enter.entry(state).or_default().push(system);
+    }
+
+    pub(crate) fn add_exit(&mut self, state: S, system: System) {
+        self.on_exit.entry(state).or_default().push(system);
+    }
+
+    fn enter(&mut self, world: &World, state: S) {
+        self.log.info(format!("entering state {:?}", state));
+        world.with_resource_mut::<StateStack<S>, _>(|stack| stack.stack.push(state.clone()));
+        if let Some(hooks) = self.on_enter.get_mut(&state) {
+            hooks.iter_mut().for_each(|hook| { hook.run(world); });
+        }
+    }
+
+    /// Exits and removes the topmost state, if there is one
+    fn exit(&mut self, world: &World) {
+        let exited = world.with_resource_mut::<StateStack<S>, _>(|stack| stack.stack.pop()).flatten();
+        if let Some(state) = exited {
+            self.log.info(format!("exiting state {:?}", state));
+            if let Some(hooks) = self.on_exit.get_mut(&state) {
+                hooks.iter_mut().for_each(|hook| { hook.run(world); });
+            }
+        }
+    }
+}
+
+impl<S: State> StateDriver for StateMachine<S> {
+    fn apply_transitions(&mut self, world: &World) {
+        // The stack lock is never held while hooks run, hooks are free to read the stack or request further transitions
+        for transition in event::drain_events::<StateTransition<S>>(world) {
+            match transition {
+                StateTransition::Push(state) => self.enter(world, state),
+                StateTransition::Pop => self.exit(world),
+                StateTransition::Replace(state) => {
+                    self.exit(world);
+                    self.enter(world, state);
+                },
+                StateTransition::Reset(state) => {
+                    let depth = world.with_resource::<StateStack<S>, _>(|stack| stack.depth()).unwrap_or(0);
+                    for _ in 0..depth {
+                        self.exit(world);
+                    }
+                    self.enter(world, state);
+                },
+            }
+        }
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Whether `state` is the active state of its stack
+pub fn is_active<S: State>(world: &World, state: &S) -> bool {
+    world.with_resource::<StateStack<S>, _>(|stack| stack.is_active(state)).unwrap_or(false)
+}
+
+/// Returns a copy of the active state
+pub fn current<S: State>(world: &World) -> Option<S> {
+    world.with_resource::<StateStack<S>, _>(|stack| stack.current().cloned()).flatten()
+}
+
+/// Requests a state transition, applied at the start of the next schedule run
+pub fn transition<S: State>(world: &World, transition: StateTransition<S>) {
+    event::send_event(world, transition);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::schedule::{Schedule, stage};
+
+    #[test]
+    fn push_pop_runs_hooks() {
+        let world = World::new();
+        world.insert_resource(Vec::<&'static str>::new());
+        let record = |name: &'static str| move |world: &World| { world.with_resource_mut::<Vec<&'static str>, _>(|v| v.push(name)); };
+
+        let mut schedule = Schedule::default();
+        schedule.add_state(&world, AppState::InGame);
+        schedule.on_enter(AppState::InGame, "enter_game", record("enter_game"));
+        schedule.on_exit(AppState::InGame, "exit_game", record("exit_game"));
+        schedule.on_enter(AppState::Paused, "enter_paused", record("enter_paused"));
+        schedule.on_exit(AppState::Paused, "exit_paused", record("exit_paused"));
+        schedule.on_update(AppState::InGame, "update_game", record("update_game"));
+        schedule.add_state_system(AppState::Paused, stage::POST_UPDATE, "update_paused", record("update_paused"));
+
+        schedule.run(&world);
+        transition(&world, StateTransition::Push(AppState::Paused));
+        schedule.run(&world);
+        assert!(is_active(&world, &AppState::Paused));
+        transition(&world, StateTransition::<AppState>::Pop);
+        schedule.run(&world);
+        transition(&world, StateTransition::Replace(AppState::MainMenu));
+        schedule.run(&world);
+
+        assert_eq!(current::<AppState>(&world), Some(AppState::MainMenu));
+        let log = world.with_resource::<Vec<&'static str>, _>(|v| v.clone()).unwrap();
+        assert_eq!(log, vec!["enter_game", "update_game", "enter_paused", "update_paused", "exit_paused", "update_game", "exit_game"]);
+    }
+}
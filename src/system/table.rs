@@ -0,0 +1,194 @@
+//!
+//! Dense table component storage
+//!
+//! A `TableStorage` keeps every component of a type in one contiguous column, with their entities and change ticks in
+//! columns alongside and a map from entity to row. Iteration walks the columns in order instead of hash buckets, and
+//! removal moves the last row into the hole so the columns stay packed. With the `table-storage` feature the world
+//! stores components in tables instead of `MapStorage` while the two are compared, `benchmark` times both
+//!
+//! Tables are per component type rather than per archetype. Archetype tables, one for each set of component types an
+//! entity can have, would make iterating several types together cheaper, but every component of an entity would sit
+//! behind one lock, where the world locks each component type on its own so a system can read one type while it writes
+//! another
+//!
+
+use std::{collections::HashMap, hash::Hash, hint::black_box, time::Instant};
+
+use collider::EntityId;
+use serde::Serialize;
+
+use super::component::{ComponentTicks, MapStorage, Mut, QueryFilter, Tick};
+
+/// Storage for every component of type `T`, packed into columns
+#[derive(Debug)]
+pub struct TableStorage<T, K = EntityId> {
+    keys: Vec<K>,
+    values: Vec<T>,
+    ticks: Vec<ComponentTicks>,
+    rows: HashMap<K, usize>,
+}
+
+/// Milliseconds a storage took for each step of a benchmark
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct StorageTimings {
+    pub insert: f64,
+    pub iterate: f64,
+    pub iterate_mut: f64,
+    /// Removing every other component
+    pub remove: f64,
+}
+
+/// `MapStorage` and `TableStorage` timed over the same work
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct StorageBenchmark {
+    pub components: usize,
+    pub map: StorageTimings,
+    pub table: StorageTimings,
+}
+
+// Impls
+
+impl<T, K> Default for TableStorage<T, K> {
+    fn default() -> Self {
+        TableStorage { keys: Vec::new(), values: Vec::new(), ticks: Vec::new(), rows: HashMap::new() }
+    }
+}
+
+impl<T, K: Copy + Eq + Hash> TableStorage<T, K> {
+    /// Inserts or replaces a component. Replacing counts as a change but not an addition
+    pub fn insert(&mut self, key: K, value: T, tick: Tick) -> Option<T> {
+        match self.rows.get(&key) {
+            Some(&row) => {
+                self.ticks[row].changed = tick;
+                Some(std::mem::replace(&mut self.values[row], value))
+            },
+            None => {
+                self.rows.insert(key, self.keys.len());
+                self.keys.push(key);
+                self.values.push(value);
+                self.ticks.push(ComponentTicks { added: tick, changed: tick });
+                None
+            },
+        }
+    }
+
+    /// Removes a component, moving the last row into its place
+    pub fn remove(&mut self, key: K) -> Option<T> {
+        let row = self.rows.remove(&key)?;
+        self.keys.swap_remove(row);
+        self.ticks.swap_remove(row);
+        if let Some(moved) = self.keys.get(row) {
+            self.rows.insert(*moved, row);
+        }
+        Some(self.values.swap_remove(row))
+    }
+
+    pub fn get(&self, key: K) -> Option<&T> {
+        self.rows.get(&key).map(|row| &self.values[*row])
+    }
+
+    pub fn get_mut(&mut self, key: K, tick: Tick) -> Option<Mut<'_, T>> {
+        let row = *self.rows.get(&key)?;
+        Some(Mut::new(&mut self.values[row], &mut self.ticks[row].changed, tick))
+    }
+
+    pub fn ticks(&self, key: K) -> Option<ComponentTicks> {
+        self.rows.get(&key).map(|row| self.ticks[*row])
+    }
+
+    pub fn contains(&self, key: K) -> bool {
+        self.rows.contains_key(&key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (K, &T)> {
+        self.keys.iter().copied().zip(self.values.iter())
+    }
+
+    pub fn iter_mut(&mut self, tick: Tick) -> impl Iterator<Item = (K, Mut<'_, T>)> {
+        self.keys.iter().copied()
+            .zip(self.values.iter_mut().zip(self.ticks.iter_mut()))
+            .map(move |(key, (value, ticks))| (key, Mut::new(value, &mut ticks.changed, tick)))
+    }
+
+    /// Iterates the components matching filter `F` for a system that last ran at `last_run`
+    pub fn iter_filtered<F: QueryFilter<T>>(&self, last_run: Tick) -> impl Iterator<Item = (K, &T)> {
+        self.keys.iter().copied()
+            .zip(self.values.iter().zip(self.ticks.iter()))
+            .filter(move |(_, (_, ticks))| F::matches(**ticks, last_run))
+            .map(|(key, (value, _))| (key, value))
+    }
+}
+
+/// Times inserting, iterating and removing `components` components in each storage
+pub fn benchmark(components: usize) -> StorageBenchmark {
+    // Both storages share an interface but no trait, so the same steps are written out for each
+    macro_rules! time_storage {
+        ($storage:ty) => {{
+            let mut storage = <$storage>::default();
+            let mut timings = StorageTimings::default();
+            let time = |step: &mut f64, run: &mut dyn FnMut()| {
+                let started = Instant::now();
+                run();
+                *step = started.elapsed().as_secs_f64() * 1000.0;
+            };
+            time(&mut timings.insert, &mut || for key in 0..components as u64 {
+                storage.insert(key, [key as f32; 4], 1);
+            });
+            time(&mut timings.iterate, &mut || {
+                black_box(storage.iter().map(|(_, value)| value[0]).sum::<f32>());
+            });
+            time(&mut timings.iterate_mut, &mut || for (_, mut value) in storage.iter_mut(2) {
+                value[1] += 1.0;
+            });
+            time(&mut timings.remove, &mut || for key in (0..components as u64).step_by(2) {
+                black_box(storage.remove(key));
+            });
+            timings
+        }};
+    }
+
+    StorageBenchmark {
+        components,
+        map: time_storage!(MapStorage<[f32; 4], u64>),
+        table: time_storage!(TableStorage<[f32; 4], u64>),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::component::Changed;
+
+    #[test]
+    fn removal_keeps_rows_packed() {
+        let mut storage: TableStorage<f32, u32> = TableStorage::default();
+        for key in 0..4 {
+            storage.insert(key, key as f32, 1);
+        }
+        assert_eq!(storage.remove(0), Some(0.0));
+        assert_eq!(storage.remove(0), None);
+        assert_eq!((storage.len(), storage.get(3), storage.get(1)), (3, Some(&3.0), Some(&1.0)));
+
+        *storage.get_mut(3, 2).unwrap() += 1.0;
+        storage.insert(5, 5.0, 2);
+        let mut changed: Vec<u32> = storage.iter_filtered::<Changed<f32>>(1).map(|(key, _)| key).collect();
+        changed.sort();
+        assert_eq!(changed, vec![3, 5]);
+        assert_eq!(storage.ticks(3), Some(ComponentTicks { added: 1, changed: 2 }));
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --release -- --ignored --nocapture"]
+    fn compare_storages() {
+        let report = benchmark(1_000_000);
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    }
+}
@@ -0,0 +1,107 @@
+//!
+//! Splitting per-entity work across threads
+//!
+//! Parallel queries hand chunks of their matches to scoped threads, so the chunks can borrow from the component storage
+//! the query holds locked. `tasks.threads` caps how many threads a query is split across, 0 meaning one per core, and
+//! `tasks.min_batch` keeps small queries on the calling thread where spawning would cost more than it saves
+//!
+
+use serde::{Serialize, Deserialize};
+
+use crate::config::ConfigSection;
+
+/// Options from the `tasks` config section
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct TaskConfig {
+    /// Most threads one query is split across, including the calling thread. 0 uses every core
+    pub threads: usize,
+    /// Fewest items worth handing to a thread of their own
+    pub min_batch: usize,
+}
+
+// Impls
+
+impl Default for TaskConfig {
+    fn default() -> Self {
+        TaskConfig { threads: 0, min_batch: 64 }
+    }
+}
+
+impl ConfigSection for TaskConfig {
+    const NAME: &'static str = "tasks";
+}
+
+impl TaskConfig {
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    pub fn with_min_batch(mut self, min_batch: usize) -> Self {
+        self.min_batch = min_batch;
+        self
+    }
+
+    /// Threads work is split across, at least one
+    pub fn thread_count(&self) -> usize {
+        match self.threads {
+            0 => std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+            threads => threads,
+        }
+    }
+
+    /// How many chunks `len` items are split into
+    pub fn chunks(&self, len: usize) -> usize {
+        (len / self.min_batch.max(1)).clamp(1, self.thread_count())
+    }
+}
+
+/// Calls `f` with every item, split into chunks run on their own threads, the calling thread taking one of them.
+/// Returns once every item is done, a panic in any chunk is resumed on the calling thread
+pub fn for_each<T: Send>(config: &TaskConfig, mut items: Vec<T>, f: impl Fn(T) + Sync) {
+    let chunks = config.chunks(items.len());
+    if chunks <= 1 {
+        items.into_iter().for_each(f);
+        return
+    }
+
+    let chunk_len = items.len().div_ceil(chunks);
+    let mut spawned = Vec::with_capacity(chunks - 1);
+    while items.len() > chunk_len {
+        spawned.push(items.split_off(items.len() - chunk_len));
+    }
+    let f = &f;
+    std::thread::scope(|scope| {
+        for chunk in spawned {
+            scope.spawn(move || chunk.into_iter().for_each(f));
+        }
+        items.into_iter().for_each(f);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Mutex, atomic::{AtomicUsize, Ordering}};
+
+    use super::*;
+
+    #[test]
+    fn items_are_split_across_threads() {
+        let config = TaskConfig::default().with_threads(4).with_min_batch(10);
+        assert_eq!((config.chunks(5), config.chunks(25), config.chunks(1000)), (1, 2, 4));
+
+        let sum = AtomicUsize::new(0);
+        let threads = Mutex::new(Vec::new());
+        for_each(&config, (1..=1000).collect(), |item: usize| {
+            sum.fetch_add(item, Ordering::Relaxed);
+            let id = std::thread::current().id();
+            let mut threads = threads.lock().unwrap();
+            if !threads.contains(&id) {
+                threads.push(id);
+            }
+        });
+        assert_eq!(sum.into_inner(), 500500);
+        assert_eq!(threads.into_inner().unwrap().len(), 4);
+    }
+}
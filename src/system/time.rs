@@ -0,0 +1,550 @@
+//!
+//! Engine timekeeping: the per-frame `Time` resource, the `FixedTime` step, timer/cooldown components and deferred
+//! callbacks
+//!
+//! `Time` can be scaled or paused. The scaled delta is what the fixed timestep accumulates and what deferred callbacks
+//! count down, so gameplay slows down, speeds up or stops with it, while frames keep being drawn at the real rate
+//!
+
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
+
+use crate::{unique::UniqueId, debug::{frame_step, log}};
+use super::world::World;
+
+/// Frame timing resource, advanced once per frame by the main loop
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Time {
+    delta: Duration,
+    total: Duration,
+    real_delta: Duration,
+    real_total: Duration,
+    frame: u64,
+    /// Multiplies real time, below 1 for slow motion and above it to fast forward
+    scale: f64,
+    paused: bool,
+}
+
+/// Fixed timestep resource. Systems in fixed stages run once for every `step` of elapsed time, so zero or more times
+/// a frame
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct FixedTime {
+    step: Duration,
+    accumulator: Duration,
+    ticks: u64,
+    /// Ticks a single frame may catch up on, time beyond that is dropped rather than falling further behind
+    max_steps: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TimerMode {
+    Once,
+    Repeating,
+}
+
+/// A countdown that can be ticked by gameplay systems
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Timer {
+    duration: Duration,
+    elapsed: Duration,
+    mode: TimerMode,
+    finished: bool,
+    times_finished: u32,
+}
+
+/// A timer that starts ready and must elapse again each time it's triggered
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Cooldown {
+    timer: Timer,
+    ready: bool,
+}
+
+/// When a deferred callback should run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Remaining {
+    Time(Duration),
+    Frames(u64),
+}
+
+struct DeferredEntry {
+    id: UniqueId,
+    remaining: Remaining,
+    callback: Box<dyn FnOnce(&World) + Send + Sync>,
+}
+
+/// Resource holding callbacks to run after a number of seconds or frames
+#[derive(Default)]
+pub struct Deferred {
+    pending: Vec<DeferredEntry>,
+}
+
+// Impls
+
+impl Default for Time {
+    fn default() -> Self {
+        Time { delta: Duration::ZERO, total: Duration::ZERO, real_delta: Duration::ZERO, real_total: Duration::ZERO, frame: 0, scale: 1.0, paused: false }
+    }
+}
+
+impl Time {
+    /// Scaled time since the last frame, zero while paused
+    pub fn delta(&self) -> Duration {
+        self.delta
+    }
+
+    pub fn delta_secs(&self) -> f64 {
+        self.delta.as_secs_f64()
+    }
+
+    /// Total scaled time elapsed since the first frame
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    /// Time since the last frame regardless of scale or pause, for anything that should keep real time like UI
+    pub fn real_delta(&self) -> Duration {
+        self.real_delta
+    }
+
+    pub fn real_delta_secs(&self) -> f64 {
+        self.real_delta.as_secs_f64()
+    }
+
+    pub fn real_total(&self) -> Duration {
+        self.real_total
+    }
+
+    /// The number of frames advanced so far, paused or not
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn scale(&self) -> f64 {
+        self.scale
+    }
+
+    /// Sets how fast scaled time runs relative to real time. Negative and non-finite scales are treated as 0
+    pub fn set_scale(&mut self, scale: f64) {
+        self.scale = if scale.is_finite() { scale.max(0.0) } else { 0.0 };
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Stops scaled time without losing the scale
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    /// The scale applied this frame, 0 while paused
+    pub fn effective_scale(&self) -> f64 {
+        if self.paused { 0.0 } else { self.scale }
+    }
+
+    /// Advances by `delta` of real time. `held` stops scaled time like a pause, for frame stepping
+    pub(crate) fn advance(&mut self, delta: Duration, held: bool) {
+        self.real_delta = delta;
+        self.real_total += delta;
+        self.delta = if held { Duration::ZERO } else { delta.mul_f64(self.effective_scale()) };
+        self.total += self.delta;
+        self.frame += 1;
+    }
+}
+
+impl Default for FixedTime {
+    fn default() -> Self {
+        FixedTime::new(Duration::from_secs_f64(1.0 / 60.0))
+    }
+}
+
+impl FixedTime {
+    pub fn new(step: Duration) -> Self {
+        FixedTime {
+            step: step.max(Duration::from_micros(100)),
+            accumulator: Duration::ZERO,
+            ticks: 0,
+            max_steps: 8,
+        }
+    }
+
+    pub fn step(&self) -> Duration {
+        self.step
+    }
+
+    pub fn set_step(&mut self, step: Duration) {
+        self.step = step.max(Duration::from_micros(100));
+    }
+
+    pub fn with_max_steps(mut self, steps: u32) -> Self {
+        self.max_steps = steps.max(1);
+        self
+    }
+
+    /// The number of fixed ticks run so far
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// How far the frame is between the last fixed tick and the next, from 0 to 1
+    pub fn alpha(&self) -> f64 {
+        (self.accumulator.as_secs_f64() / self.step.as_secs_f64()).clamp(0.0, 1.0)
+    }
+
+    pub(crate) fn accumulate(&mut self, delta: Duration) {
+        self.accumulator = (self.accumulator + delta).min(self.step * self.max_steps);
+    }
+
+    /// Shows the last tick in full while frame stepping, leaving the accumulator just short of the next tick so
+    /// resuming doesn't start with an extra one
+    pub(crate) fn hold(&mut self) {
+        self.accumulator = self.step.saturating_sub(Duration::from_nanos(1));
+    }
+
+    /// Counts a tick run without taking time off the accumulator, for frame stepping
+    pub(crate) fn force_tick(&mut self) {
+        self.ticks += 1;
+    }
+
+    /// Takes one step off the accumulator if there's enough time left for a tick
+    pub(crate) fn expend(&mut self) -> bool {
+        if self.accumulator < self.step {
+            return false
+        }
+        self.accumulator -= self.step;
+        self.ticks += 1;
+        true
+    }
+}
+
+impl Timer {
+    pub fn new(duration: Duration, mode: TimerMode) -> Self {
+        Timer {
+            duration,
+            elapsed: Duration::ZERO,
+            mode,
+            finished: false,
+            times_finished: 0,
+        }
+    }
+
+    pub fn from_secs_f64(secs: f64, mode: TimerMode) -> Self {
+        Timer::new(Duration::from_secs_f64(secs), mode)
+    }
+
+    /// Advances the timer, returns `true` if it finished during this tick
+    ///
+    /// Repeating timers wrap and may finish several times in one large tick, see `times_finished()`
+    pub fn tick(&mut self, delta: Duration) -> bool {
+        self.times_finished = 0;
+
+        if self.finished && self.mode == TimerMode::Once {
+            return false
+        }
+
+        self.elapsed += delta;
+        if self.elapsed < self.duration {
+            self.finished = false;
+            return false
+        }
+
+        match self.mode {
+            TimerMode::Once => {
+                self.elapsed = self.duration;
+                self.times_finished = 1;
+            },
+            TimerMode::Repeating => {
+                if self.duration.is_zero() {
+                    self.times_finished = 1;
+                    self.elapsed = Duration::ZERO;
+                } else {
+                    let count = self.elapsed.as_nanos() / self.duration.as_nanos();
+                    self.times_finished = count.min(u32::MAX as u128) as u32;
+                    self.elapsed = Duration::from_nanos((self.elapsed.as_nanos() % self.duration.as_nanos()) as u64);
+                }
+            },
+        }
+        self.finished = true;
+        true
+    }
+
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+
+    /// How many times the timer finished during the last tick
+    pub fn times_finished(&self) -> u32 {
+        self.times_finished
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.duration.saturating_sub(self.elapsed)
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Fraction of the duration that has elapsed, in the range `0.0..=1.0`
+    pub fn fraction(&self) -> f64 {
+        if self.duration.is_zero() {
+            1.0
+        } else {
+            (self.elapsed.as_secs_f64() / self.duration.as_secs_f64()).min(1.0)
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.elapsed = Duration::ZERO;
+        self.finished = false;
+        self.times_finished = 0;
+    }
+}
+
+impl Cooldown {
+    pub fn new(duration: Duration) -> Self {
+        Cooldown {
+            timer: Timer::new(duration, TimerMode::Once),
+            ready: true,
+        }
+    }
+
+    pub fn tick(&mut self, delta: Duration) {
+        if !self.ready && self.timer.tick(delta) {
+            self.ready = true;
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Starts the cooldown if it is ready. Returns `false` if the cooldown is still running
+    pub fn trigger(&mut self) -> bool {
+        if self.ready {
+            self.ready = false;
+            self.timer.reset();
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn remaining(&self) -> Duration {
+        if self.ready { Duration::ZERO } else { self.timer.remaining() }
+    }
+}
+
+impl Deferred {
+    /// Runs `callback` once at least `delay` of frame time has passed. Returns an id that can be used to cancel it
+    pub fn after<F>(&mut self, delay: Duration, callback: F) -> UniqueId
+    where
+        F: FnOnce(&World) + Send + Sync + 'static
+    {
+        self.push(Remaining::Time(delay), Box::new(callback))
+    }
+
+    /// Runs `callback` after `frames` frames have been advanced. Zero runs it on the next frame
+    pub fn after_frames<F>(&mut self, frames: u64, callback: F) -> UniqueId
+    where
+        F: FnOnce(&World) + Send + Sync + 'static
+    {
+        self.push(Remaining::Frames(frames), Box::new(callback))
+    }
+
+    /// Cancels a pending callback, returns `true` if it was still pending
+    pub fn cancel(&mut self, id: UniqueId) -> bool {
+        let before = self.pending.len();
+        self.pending.retain(|entry| entry.id != id);
+        before != self.pending.len()
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn push(&mut self, remaining: Remaining, callback: Box<dyn FnOnce(&World) + Send + Sync>) -> UniqueId {
+        let id = UniqueId::get();
+        self.pending.push(DeferredEntry { id, remaining, callback });
+        id
+    }
+
+    /// Counts down every pending callback and removes the ones that are due
+    fn take_due(&mut self, delta: Duration) -> Vec<DeferredEntry> {
+        let mut due = Vec::new();
+        let mut index = 0usize;
+        while index < self.pending.len() {
+            let entry = &mut self.pending[index];
+            let ready = match &mut entry.remaining {
+                Remaining::Time(remaining) => {
+                    *remaining = remaining.saturating_sub(delta);
+                    remaining.is_zero()
+                },
+                Remaining::Frames(frames) => {
+                    if *frames == 0 {
+                        true
+                    } else {
+                        *frames -= 1;
+                        false
+                    }
+                },
+            };
+
+            if ready {
+                due.push(self.pending.remove(index));
+            } else {
+                index += 1;
+            }
+        }
+        due
+    }
+}
+
+impl std::fmt::Debug for Deferred {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Deferred").field("pending", &self.pending.len()).finish()
+    }
+}
+
+/// Inserts the time resources into a world if they aren't present yet
+pub(crate) fn init_time(world: &World) {
+    if !world.contains_resource::<Time>() {
+        world.insert_resource(Time::default());
+    }
+    if !world.contains_resource::<Deferred>() {
+        world.insert_resource(Deferred::default());
+    }
+    if !world.contains_resource::<FixedTime>() {
+        world.insert_resource(FixedTime::default());
+    }
+}
+
+/// Advances the `Time` resource by `delta` of real time and runs any deferred callbacks that became due. Called once per
+/// frame by the main loop. Scaled time is held while frame stepping
+pub(crate) fn advance_time(world: &World, delta: Duration) {
+    let held = frame_step::is_stepping(world);
+    let delta = world.with_resource_mut::<Time, _>(|time| {
+        time.advance(delta, held);
+        log::set_frame(time.frame());
+        time.delta()
+    }).unwrap_or(delta);
+    world.with_resource_mut::<FixedTime, _>(|fixed| match held {
+        true => fixed.hold(),
+        false => fixed.accumulate(delta),
+    });
+
+    // Callbacks are taken out before running so they are free to schedule more callbacks
+    let due = world.with_resource_mut::<Deferred, _>(|deferred| deferred.take_due(delta)).unwrap_or_default();
+    for entry in due {
+        (entry.callback)(world);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timer_once_and_repeating() {
+        let mut once = Timer::from_secs_f64(1.0, TimerMode::Once);
+        assert!(!once.tick(Duration::from_millis(500)));
+        assert!(once.tick(Duration::from_millis(600)));
+        assert!(once.finished());
+        assert!(!once.tick(Duration::from_millis(600)));
+
+        let mut repeating = Timer::from_secs_f64(1.0, TimerMode::Repeating);
+        assert!(repeating.tick(Duration::from_millis(2500)));
+        assert_eq!(repeating.times_finished(), 2);
+        assert_eq!(repeating.elapsed(), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn cooldown_trigger() {
+        let mut cooldown = Cooldown::new(Duration::from_secs(1));
+        assert!(cooldown.trigger());
+        assert!(!cooldown.trigger());
+        cooldown.tick(Duration::from_secs(1));
+        assert!(cooldown.is_ready());
+    }
+
+    #[test]
+    fn deferred_callbacks() {
+        let world = World::new();
+        init_time(&world);
+        world.insert_resource(0u32);
+
+        let cancelled = world.with_resource_mut::<Deferred, _>(|deferred| {
+            deferred.after_frames(1, |world| { world.with_resource_mut::<u32, _>(|n| *n += 1); });
+            deferred.after(Duration::from_millis(30), |world| { world.with_resource_mut::<u32, _>(|n| *n += 10); });
+            deferred.after_frames(0, |world| { world.with_resource_mut::<u32, _>(|n| *n += 100); })
+        }).unwrap();
+        world.with_resource_mut::<Deferred, _>(|deferred| assert!(deferred.cancel(cancelled)));
+
+        advance_time(&world, Duration::from_millis(16));
+        assert_eq!(world.with_resource::<u32, _>(|n| *n), Some(0));
+        advance_time(&world, Duration::from_millis(16));
+        assert_eq!(world.with_resource::<u32, _>(|n| *n), Some(11));
+        assert_eq!(world.with_resource::<Time, _>(|t| t.frame()), Some(2));
+    }
+
+    #[test]
+    fn fixed_stage_ticks_per_step() {
+        use crate::system::schedule::{Schedule, stage};
+
+        let world = World::new();
+        init_time(&world);
+        world.insert_resource(FixedTime::new(Duration::from_millis(10)).with_max_steps(4));
+        world.insert_resource(0u32);
+        let mut schedule = Schedule::default();
+        schedule.add_system(stage::FIXED_UPDATE, "count", |world| { world.with_resource_mut::<u32, _>(|n| *n += 1); });
+
+        let mut run = |delta| {
+            advance_time(&world, Duration::from_millis(delta));
+            schedule.run(&world);
+            world.with_resource::<u32, _>(|n| *n).unwrap()
+        };
+        assert_eq!(run(5), 0);
+        assert_eq!(run(16), 2);
+        // A long frame only catches up on four steps
+        assert_eq!(run(100), 6);
+        assert_eq!(world.with_resource::<FixedTime, _>(|fixed| fixed.ticks()), Some(6));
+        assert_eq!(world.with_resource::<FixedTime, _>(|fixed| fixed.alpha()), Some(0.0));
+    }
+
+    #[test]
+    fn scaled_time_drives_fixed_ticks() {
+        let world = World::new();
+        init_time(&world);
+        world.insert_resource(FixedTime::new(Duration::from_millis(10)));
+
+        world.with_resource_mut::<Time, _>(|time| time.set_scale(0.5));
+        advance_time(&world, Duration::from_millis(40));
+        assert_eq!(world.with_resource::<Time, _>(|time| (time.delta(), time.real_delta())), Some((Duration::from_millis(20), Duration::from_millis(40))));
+
+        world.with_resource_mut::<Time, _>(Time::pause);
+        advance_time(&world, Duration::from_millis(40));
+        world.with_resource_mut::<Time, _>(|time| {
+            time.resume();
+            time.set_scale(2.0);
+        });
+        advance_time(&world, Duration::from_millis(20));
+
+        let ticks = std::iter::from_fn(|| world.with_resource_mut::<FixedTime, _>(FixedTime::expend).filter(|ticked| *ticked)).count();
+        assert_eq!(ticks, 6);
+        assert_eq!(world.with_resource::<Time, _>(|time| (time.total(), time.real_total(), time.frame())), Some((Duration::from_millis(60), Duration::from_millis(100), 3)));
+    }
+}
@@ -0,0 +1,350 @@
+//!
+//! Transforms and their propagation down the hierarchy
+//!
+//! An entity's `Transform` is relative to its parent. `propagate_transforms` keeps a `GlobalTransform` beside it, the
+//! transform in world space, recomputing it only below entities whose transform or parent changed since the system last
+//! ran. Each changed subtree is recomputed once from its topmost changed entity. An entity without a `Transform` breaks
+//! the chain, its children are placed as if they were roots
+//!
+
+use std::{collections::{HashMap, HashSet}, hash::Hash, time::Instant};
+
+use collider::EntityId;
+use serde::{Serialize, Deserialize};
+
+use super::{world::World, hierarchy::{self, Parent}};
+
+/// Position, orientation and scale of an entity relative to its parent
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub translation: [f64; 3],
+    /// Unit quaternion as `[x, y, z, w]`
+    pub rotation: [f64; 4],
+    pub scale: [f64; 3],
+}
+
+/// An entity's transform in world space, its `Transform` composed with every ancestor's
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GlobalTransform(pub Transform);
+
+/// Milliseconds `propagate_transforms` took over hierarchies of `chains` chains `depth` entities deep
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct PropagationBenchmark {
+    pub chains: usize,
+    pub depth: usize,
+    /// Every entity changed
+    pub all: f64,
+    /// One chain's root changed
+    pub one_root: f64,
+    /// The middle entity of one chain changed
+    pub one_middle: f64,
+    /// Nothing changed
+    pub none: f64,
+}
+
+/// What propagation needs from a hierarchy, so it can run over the world or over plain maps in benchmarks
+trait TransformTree<K> {
+    fn parent(&self, node: K) -> Option<K>;
+    fn children(&self, node: K) -> Vec<K>;
+    fn local(&self, node: K) -> Option<Transform>;
+    fn global(&self, node: K) -> Option<Transform>;
+    fn set_global(&mut self, node: K, global: Transform);
+}
+
+struct WorldTree<'a>(&'a World);
+
+/// A hierarchy held in maps, for benchmarking propagation without a world
+#[derive(Default)]
+struct MapTree {
+    parents: HashMap<u32, u32>,
+    children: HashMap<u32, Vec<u32>>,
+    locals: HashMap<u32, Transform>,
+    globals: HashMap<u32, Transform>,
+}
+
+/// Tracks an entity's transform across fixed ticks so rendering can draw it part way between them. `previous` is the
+/// transform at the start of the latest tick and `rendered` is what the extraction step last produced
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interpolated {
+    pub previous: Transform,
+    pub rendered: Transform,
+}
+
+// Impls
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform::IDENTITY
+    }
+}
+
+impl Transform {
+    pub const IDENTITY: Transform = Transform {
+        translation: [0.0, 0.0, 0.0],
+        rotation: [0.0, 0.0, 0.0, 1.0],
+        scale: [1.0, 1.0, 1.0],
+    };
+
+    pub fn from_translation(x: f64, y: f64, z: f64) -> Self {
+        Transform { translation: [x, y, z], ..Transform::IDENTITY }
+    }
+
+    /// Rotation of `angle` radians about the vertical axis
+    pub fn from_yaw(angle: f64) -> Self {
+        let half = angle * 0.5;
+        Transform { rotation: [0.0, half.sin(), 0.0, half.cos()], ..Transform::IDENTITY }
+    }
+
+    /// `child` moved from this transform's space into the one it's relative to, e.g. a parent's global transform
+    /// composed with its child's local transform
+    pub fn compose(&self, child: &Transform) -> Transform {
+        let [x1, y1, z1, w1] = self.rotation;
+        let [x2, y2, z2, w2] = child.rotation;
+        let scaled: [f64; 3] = std::array::from_fn(|i| child.translation[i] * self.scale[i]);
+        let rotated = self.rotate(scaled);
+        Transform {
+            translation: std::array::from_fn(|i| self.translation[i] + rotated[i]),
+            rotation: [
+                w1 * x2 + x1 * w2 + y1 * z2 - z1 * y2,
+                w1 * y2 - x1 * z2 + y1 * w2 + z1 * x2,
+                w1 * z2 + x1 * y2 - y1 * x2 + z1 * w2,
+                w1 * w2 - x1 * x2 - y1 * y2 - z1 * z2,
+            ],
+            scale: std::array::from_fn(|i| self.scale[i] * child.scale[i]),
+        }
+    }
+
+    /// `vector` rotated by this transform's rotation
+    pub fn rotate(&self, vector: [f64; 3]) -> [f64; 3] {
+        let [x, y, z, w] = self.rotation;
+        let cross = |a: [f64; 3], b: [f64; 3]| [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]];
+        let t = cross([x, y, z], vector).map(|v| v * 2.0);
+        let u = cross([x, y, z], t);
+        std::array::from_fn(|i| vector[i] + t[i] * w + u[i])
+    }
+
+    /// Interpolates towards `other`, taking the shortest path between rotations
+    pub fn lerp(&self, other: &Transform, t: f64) -> Transform {
+        let lerp3 = |a: [f64; 3], b: [f64; 3]| [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t, a[2] + (b[2] - a[2]) * t];
+
+        let dot: f64 = (0..4).map(|i| self.rotation[i] * other.rotation[i]).sum();
+        let sign = if dot < 0.0 { -1.0 } else { 1.0 };
+        let mut rotation: [f64; 4] = std::array::from_fn(|i| self.rotation[i] + (other.rotation[i] * sign - self.rotation[i]) * t);
+        let length = rotation.iter().map(|v| v * v).sum::<f64>().sqrt();
+        if length > f64::EPSILON {
+            rotation.iter_mut().for_each(|v| *v /= length);
+        }
+
+        Transform {
+            translation: lerp3(self.translation, other.translation),
+            rotation,
+            scale: lerp3(self.scale, other.scale),
+        }
+    }
+}
+
+impl Interpolated {
+    pub fn new(transform: Transform) -> Self {
+        Interpolated { previous: transform, rendered: transform }
+    }
+}
+
+impl TransformTree<EntityId> for WorldTree<'_> {
+    fn parent(&self, node: EntityId) -> Option<EntityId> {
+        hierarchy::parent(self.0, node)
+    }
+
+    fn children(&self, node: EntityId) -> Vec<EntityId> {
+        hierarchy::children(self.0, node)
+    }
+
+    fn local(&self, node: EntityId) -> Option<Transform> {
+        self.0.component::<Transform, _>(node, |transform| *transform)
+    }
+
+    fn global(&self, node: EntityId) -> Option<Transform> {
+        self.0.component::<GlobalTransform, _>(node, |global| global.0)
+    }
+
+    fn set_global(&mut self, node: EntityId, global: Transform) {
+        if self.0.component_mut::<GlobalTransform, _>(node, |mut current| current.0 = global).is_none() {
+            self.0.insert_component(node, GlobalTransform(global));
+        }
+    }
+}
+
+impl TransformTree<u32> for MapTree {
+    fn parent(&self, node: u32) -> Option<u32> {
+        self.parents.get(&node).copied()
+    }
+
+    fn children(&self, node: u32) -> Vec<u32> {
+        self.children.get(&node).cloned().unwrap_or_default()
+    }
+
+    fn local(&self, node: u32) -> Option<Transform> {
+        self.locals.get(&node).copied()
+    }
+
+    fn global(&self, node: u32) -> Option<Transform> {
+        self.globals.get(&node).copied()
+    }
+
+    fn set_global(&mut self, node: u32, global: Transform) {
+        self.globals.insert(node, global);
+    }
+}
+
+impl MapTree {
+    /// `chains` chains of `depth` entities, each offset one unit along X from its parent
+    fn chains(chains: usize, depth: usize) -> Self {
+        let mut tree = MapTree::default();
+        for node in 0..(chains * depth) as u32 {
+            tree.locals.insert(node, Transform::from_translation(1.0, 0.0, 0.0));
+            if !(node as usize).is_multiple_of(depth) {
+                tree.parents.insert(node, node - 1);
+                tree.children.insert(node - 1, vec![node]);
+            }
+        }
+        tree
+    }
+}
+
+/// Remembers the current transforms of interpolated entities, run at the start of every fixed tick
+pub fn record_previous_transforms(world: &World) {
+    for entity in world.query::<Interpolated, ()>() {
+        if let Some(current) = world.component::<Transform, _>(entity, |transform| *transform) {
+            world.component_mut::<Interpolated, _>(entity, |mut interpolated| interpolated.bypass_change_detection().previous = current);
+        }
+    }
+}
+
+/// Blends each interpolated entity from its previous transform towards its current one by `alpha`, the fraction of a
+/// fixed step elapsed since the last tick
+pub fn interpolate_transforms(world: &World, alpha: f64) {
+    for entity in world.query::<Interpolated, ()>() {
+        if let Some(current) = world.component::<Transform, _>(entity, |transform| *transform) {
+            world.component_mut::<Interpolated, _>(entity, |mut interpolated| {
+                let interpolated = interpolated.bypass_change_detection();
+                interpolated.rendered = interpolated.previous.lerp(&current, alpha);
+            });
+        }
+    }
+}
+
+/// The transform to draw `entity` with, interpolated if it has `Interpolated` and otherwise in world space once it's
+/// been propagated
+pub fn render_transform(world: &World, entity: EntityId) -> Option<Transform> {
+    world.component::<Interpolated, _>(entity, |interpolated| interpolated.rendered)
+        .or_else(|| world.component::<GlobalTransform, _>(entity, |global| global.0))
+        .or_else(|| world.component::<Transform, _>(entity, |transform| *transform))
+}
+
+/// Updates the `GlobalTransform` of every entity whose transform or parent changed since the system last ran, and of
+/// their descendants. Run once a frame after the update stages
+pub fn propagate_transforms(world: &World) {
+    let mut dirty = world.changed::<Transform>();
+    dirty.extend(world.changed::<Parent>());
+    propagate(&mut WorldTree(world), dirty);
+}
+
+/// Recomputes the global transforms of `dirty` and everything below them, returning how many were recomputed
+fn propagate<K: Copy + Eq + Hash, T: TransformTree<K>>(tree: &mut T, dirty: Vec<K>) -> usize {
+    // Shallowest first, so a subtree is recomputed once from its topmost dirty entity after that entity's parent
+    let mut depths = HashMap::new();
+    let mut dirty: Vec<(usize, K)> = dirty.into_iter().map(|node| (depth(tree, node, &mut depths), node)).collect();
+    dirty.sort_by_key(|(depth, _)| *depth);
+
+    let mut updated = HashSet::new();
+    for (_, node) in dirty {
+        if updated.contains(&node) {
+            continue
+        }
+        let mut stack = vec![(node, tree.parent(node).and_then(|parent| tree.global(parent)))];
+        while let Some((node, parent)) = stack.pop() {
+            let Some(local) = tree.local(node) else { continue };
+            if !updated.insert(node) {
+                continue
+            }
+            let global = parent.map_or(local, |parent| parent.compose(&local));
+            tree.set_global(node, global);
+            stack.extend(tree.children(node).into_iter().map(|child| (child, Some(global))));
+        }
+    }
+    updated.len()
+}
+
+/// Ancestors above `node`, remembering the depth of every entity passed on the way up in `depths` so each is only walked
+/// once. An entity whose ancestors loop back to it counts as a root
+fn depth<K: Copy + Eq + Hash, T: TransformTree<K>>(tree: &T, node: K, depths: &mut HashMap<K, usize>) -> usize {
+    let mut path = Vec::new();
+    let mut seen = HashSet::new();
+    let mut current = Some(node);
+    let mut depth = 0;
+    while let Some(next) = current.filter(|next| seen.insert(*next)) {
+        if let Some(known) = depths.get(&next) {
+            depth = known + 1;
+            break
+        }
+        path.push(next);
+        current = tree.parent(next);
+    }
+    for entity in path.into_iter().rev() {
+        depths.insert(entity, depth);
+        depth += 1;
+    }
+    depths[&node]
+}
+
+/// Times propagating `chains` chains of `depth` entities with different amounts of them changed
+pub fn benchmark(chains: usize, depth: usize) -> PropagationBenchmark {
+    let mut tree = MapTree::chains(chains, depth);
+    let entities = (chains * depth) as u32;
+    let mut time = |dirty: Vec<u32>| {
+        let started = Instant::now();
+        propagate(&mut tree, dirty);
+        started.elapsed().as_secs_f64() * 1000.0
+    };
+    PropagationBenchmark {
+        chains,
+        depth,
+        all: time((0..entities).collect()),
+        one_root: time(vec![0]),
+        one_middle: time(vec![depth as u32 / 2]),
+        none: time(Vec::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_dirty_subtrees_are_recomputed() {
+        let mut tree = MapTree::chains(2, 4);
+        tree.locals.insert(0, Transform { scale: [2.0; 3], ..Transform::from_yaw(std::f64::consts::FRAC_PI_2) });
+        assert_eq!(propagate(&mut tree, (0..8).rev().collect()), 8);
+
+        // Turned a quarter about Y and doubled, each child's step along X lands two units along -Z
+        let near = |tree: &MapTree, node: u32, expected: [f64; 3]| {
+            let translation = tree.global(node).unwrap().translation;
+            assert!((0..3).all(|i| (translation[i] - expected[i]).abs() < 1e-9), "{} at {:?}", node, translation);
+        };
+        near(&tree, 3, [0.0, 0.0, -6.0]);
+        assert_eq!(tree.global(3).map(|global| global.scale), Some([2.0; 3]));
+
+        // Only the changed entity and those below it are recomputed
+        tree.locals.insert(2, Transform::IDENTITY);
+        assert_eq!(propagate(&mut tree, vec![3, 2]), 2);
+        near(&tree, 3, [0.0, 0.0, -4.0]);
+        near(&tree, 5, [2.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --release -- --ignored --nocapture"]
+    fn deep_hierarchies() {
+        for (chains, depth) in [(1, 10_000), (100, 100), (10_000, 1)] {
+            println!("{}", serde_json::to_string(&benchmark(chains, depth)).unwrap());
+        }
+    }
+}
@@ -1,41 +1,253 @@
-use std::sync::Arc;
+use std::{any::TypeId, collections::HashMap, sync::{Arc, RwLock, atomic::{AtomicU64, Ordering}}};
 
 use collider::EntityId;
 use collider::EntityDatabase;
 
+use super::resource::{Resources, Resource, Res};
+use super::component::{Component, ComponentStorage, Mut, QueryFilter, Added, Changed, Tick};
+use super::commands::{Commands, CommandQueue};
+use super::prefab::{self, Prefab, PrefabError};
+use super::registry;
+use super::hierarchy;
+use super::task::{self, TaskConfig};
+use crate::config;
+use crate::unique::Handle;
+
+type ComponentRemover = fn(&World, EntityId);
+
 #[derive(Debug)]
 struct WorldInner {
     db: EntityDatabase,
+    resources: Resources,
+    components: Resources,
+    component_removers: RwLock<HashMap<TypeId, ComponentRemover>>,
+    change_tick: AtomicU64,
+    last_run_tick: AtomicU64,
 }
 
 #[derive(Clone, Debug)]
-struct World {
+pub struct World {
     inner: Arc<WorldInner>
 }
 
-// Impl's 
+// Impl's
 
 impl WorldInner {
     fn spawn_entity(&self) -> EntityId {
-        todo!()
+        self.db.create().expect("unable to create entity")
+    }
+}
+
+impl Default for World {
+    fn default() -> Self {
+        World::new()
     }
 }
 
 impl World {
-    fn new() -> Self {
+    pub fn new() -> Self {
         let inner = WorldInner {
-            db: EntityDatabase::new()
+            db: EntityDatabase::new(),
+            resources: Resources::default(),
+            components: Resources::default(),
+            component_removers: RwLock::new(HashMap::new()),
+            change_tick: AtomicU64::new(1),
+            last_run_tick: AtomicU64::new(0),
         };
 
-        World {
+        let world = World {
             inner: Arc::new(inner)
-        }
+        };
+        world.insert_resource(CommandQueue::default());
+        registry::init_registry(&world);
+        world
     }
 
-    fn spawn_entity(&self) -> EntityId {
+    pub fn spawn_entity(&self) -> EntityId {
         self.inner().spawn_entity()
     }
 
+    /// Removes every component attached to `entity`
+    pub fn despawn_entity(&self, entity: EntityId) {
+        let removers: Vec<ComponentRemover> = self.inner().component_removers.read().expect("component removers poisoned").values().copied().collect();
+        for remove in removers {
+            remove(self, entity);
+        }
+    }
+
+    /// Removes `entity` along with its descendants, see `hierarchy::despawn_recursive`
+    pub fn despawn_recursive(&self, entity: EntityId) -> Vec<EntityId> {
+        hierarchy::despawn_recursive(self, entity)
+    }
+
+    /// Instantiates a loaded prefab asset, returning its root entity
+    pub fn spawn_prefab(&self, handle: Handle<Prefab>) -> Result<EntityId, PrefabError> {
+        prefab::spawn_prefab(self, handle)
+    }
+
+    /// Returns a `Commands` for deferring structural changes to the next stage boundary
+    pub fn commands(&self) -> Commands {
+        Commands::new(self)
+    }
+
+    /// Inserts a singleton resource into the world, replacing and returning any existing resource of the same type
+    pub fn insert_resource<T: Resource>(&self, resource: T) -> Option<Res<T>> {
+        self.inner().resources.insert(resource)
+    }
+
+    /// Returns a shared handle to the resource of type `T`, if one exists
+    pub fn resource<T: Resource>(&self) -> Option<Res<T>> {
+        self.inner().resources.get::<T>()
+    }
+
+    pub fn remove_resource<T: Resource>(&self) -> Option<Res<T>> {
+        self.inner().resources.remove::<T>()
+    }
+
+    pub fn contains_resource<T: Resource>(&self) -> bool {
+        self.inner().resources.contains::<T>()
+    }
+
+    /// Runs `f` with a read lock on the resource of type `T`. Returns `None` if there is no such resource
+    pub fn with_resource<T: Resource, R>(&self, f: impl FnOnce(&T) -> R) -> Option<R> {
+        let resource = self.resource::<T>()?;
+        let guard = resource.read().expect("resource poisoned");
+        Some(f(&guard))
+    }
+
+    /// Runs `f` with a write lock on the resource of type `T`. Returns `None` if there is no such resource
+    pub fn with_resource_mut<T: Resource, R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        let resource = self.resource::<T>()?;
+        let mut guard = resource.write().expect("resource poisoned");
+        Some(f(&mut guard))
+    }
+
+    /// Attaches a component to an entity, returning the component it replaced if any
+    pub fn insert_component<T: Component>(&self, entity: EntityId, component: T) -> Option<T> {
+        let tick = self.change_tick();
+        self.storage::<T>().write().expect("component storage poisoned").insert(entity, component, tick)
+    }
+
+    pub fn remove_component<T: Component>(&self, entity: EntityId) -> Option<T> {
+        self.storage::<T>().write().expect("component storage poisoned").remove(entity)
+    }
+
+    pub fn has_component<T: Component>(&self, entity: EntityId) -> bool {
+        self.storage::<T>().read().expect("component storage poisoned").contains(entity)
+    }
+
+    /// Runs `f` with a reference to an entity's component. Returns `None` if the entity doesn't have one
+    pub fn component<T: Component, R>(&self, entity: EntityId, f: impl FnOnce(&T) -> R) -> Option<R> {
+        let storage = self.storage::<T>();
+        let guard = storage.read().expect("component storage poisoned");
+        guard.get(entity).map(f)
+    }
+
+    /// Runs `f` with mutable access to an entity's component, writing through the `Mut` marks it changed
+    pub fn component_mut<T: Component, R>(&self, entity: EntityId, f: impl FnOnce(Mut<T>) -> R) -> Option<R> {
+        let tick = self.change_tick();
+        let storage = self.storage::<T>();
+        let mut guard = storage.write().expect("component storage poisoned");
+        guard.get_mut(entity, tick).map(f)
+    }
+
+    pub fn for_each<T: Component>(&self, mut f: impl FnMut(EntityId, &T)) {
+        let storage = self.storage::<T>();
+        let guard = storage.read().expect("component storage poisoned");
+        guard.iter().for_each(|(entity, component)| f(entity, component));
+    }
+
+    pub fn for_each_mut<T: Component>(&self, mut f: impl FnMut(EntityId, Mut<T>)) {
+        let tick = self.change_tick();
+        let storage = self.storage::<T>();
+        let mut guard = storage.write().expect("component storage poisoned");
+        guard.iter_mut(tick).for_each(|(entity, component)| f(entity, component));
+    }
+
+    /// Like `for_each` but only visits components matching `F`, e.g. `world.for_each_filtered::<Transform, Changed<Transform>>(..)`
+    pub fn for_each_filtered<T: Component, F: QueryFilter<T>>(&self, mut f: impl FnMut(EntityId, &T)) {
+        let last_run = self.last_run_tick();
+        let storage = self.storage::<T>();
+        let guard = storage.read().expect("component storage poisoned");
+        guard.iter_filtered::<F>(last_run).for_each(|(entity, component)| f(entity, component));
+    }
+
+    /// Like `for_each` but split across threads, see `task`. The storage is read locked for the whole iteration, so
+    /// `f` can read `T` through the world as well but must not write it
+    pub fn par_for_each<T: Component>(&self, f: impl Fn(EntityId, &T) + Sync) {
+        self.par_for_each_filtered::<T, ()>(f)
+    }
+
+    /// Like `for_each_mut` but split across threads, each component is visited by exactly one of them. The storage is
+    /// write locked for the whole iteration, so `f` must not touch `T` through the world, other components are fine
+    pub fn par_for_each_mut<T: Component>(&self, f: impl Fn(EntityId, Mut<T>) + Sync) {
+        let tick = self.change_tick();
+        let storage = self.storage::<T>();
+        let mut guard = storage.write().expect("component storage poisoned");
+        task::for_each(&config::get().section::<TaskConfig>(), guard.iter_mut(tick).collect(), |(entity, component)| f(entity, component));
+    }
+
+    /// Like `for_each_filtered` but split across threads, see `par_for_each`
+    pub fn par_for_each_filtered<T: Component, F: QueryFilter<T>>(&self, f: impl Fn(EntityId, &T) + Sync) {
+        let last_run = self.last_run_tick();
+        let storage = self.storage::<T>();
+        let guard = storage.read().expect("component storage poisoned");
+        task::for_each(&config::get().section::<TaskConfig>(), guard.iter_filtered::<F>(last_run).collect(), |(entity, component)| f(entity, component));
+    }
+
+    /// Returns the entities with a component of type `T` matching filter `F`
+    pub fn query<T: Component, F: QueryFilter<T>>(&self) -> Vec<EntityId> {
+        let mut entities = Vec::new();
+        self.for_each_filtered::<T, F>(|entity, _| entities.push(entity));
+        entities
+    }
+
+    /// Entities whose `T` was added since the running system last ran
+    pub fn added<T: Component>(&self) -> Vec<EntityId> {
+        self.query::<T, Added<T>>()
+    }
+
+    /// Entities whose `T` was added or changed since the running system last ran
+    pub fn changed<T: Component>(&self) -> Vec<EntityId> {
+        self.query::<T, Changed<T>>()
+    }
+
+    /// The tick stamped onto components changed right now
+    pub fn change_tick(&self) -> Tick {
+        self.inner().change_tick.load(Ordering::Acquire)
+    }
+
+    /// The tick the running system last ran at, change filters match anything newer than this
+    pub fn last_run_tick(&self) -> Tick {
+        self.inner().last_run_tick.load(Ordering::Acquire)
+    }
+
+    /// Advances the change tick, returning the new value
+    pub(crate) fn increment_change_tick(&self) -> Tick {
+        self.inner().change_tick.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    pub(crate) fn set_last_run_tick(&self, tick: Tick) {
+        self.inner().last_run_tick.store(tick, Ordering::Release);
+    }
+
+    /// Replaces how `T` is removed from despawned entities, for components that need to unlink other entities as well
+    pub(crate) fn set_component_remover<T: Component>(&self, remove: ComponentRemover) {
+        self.inner().component_removers.write().expect("component removers poisoned").insert(TypeId::of::<T>(), remove);
+    }
+
+    fn storage<T: Component>(&self) -> Res<ComponentStorage<T>> {
+        if let Some(storage) = self.inner().components.get::<ComponentStorage<T>>() {
+            return storage
+        }
+
+        // First use of this component type, remember how to remove it from despawned entities
+        self.inner().component_removers.write().expect("component removers poisoned")
+            .entry(TypeId::of::<T>())
+            .or_insert(|world, entity| { world.remove_component::<T>(entity); });
+        self.inner().components.get_or_insert_with(ComponentStorage::<T>::default)
+    }
+
     fn inner(&self) -> &WorldInner {
         &self.inner
     }
@@ -59,4 +271,18 @@ mod test {
         let world = World::new();
         let entity = world.spawn_entity();
     }
+
+    #[test]
+    fn insert_and_replace_resource() {
+        let world = World::new();
+        assert!(world.insert_resource(1u32).is_none());
+        assert!(world.contains_resource::<u32>());
+
+        world.with_resource_mut::<u32, _>(|r| *r += 1);
+        assert_eq!(world.with_resource::<u32, _>(|r| *r), Some(2));
+
+        let previous = world.insert_resource(10u32).expect("expected a previous resource");
+        assert_eq!(*previous.read().unwrap(), 2);
+        assert!(world.resource::<u64>().is_none());
+    }
 }
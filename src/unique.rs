@@ -1,4 +1,5 @@
-use rand::Rng;
+use std::marker::PhantomData;
+
 use serde::{Serialize, Deserialize};
 
 #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -6,6 +7,34 @@ pub struct UniqueId {
     _unique: i128,
 }
 
+/// A 64 bit form of a UniqueId, for network packets and GPU side ids where 128 bits is too heavy. The UniqueId stays the
+/// canonical identity, a CompactId only stands in for one where both ends agree on the mapping
+///
+/// Unindexed ids keep 63 bits of entropy. The chance of two of N ids colliding is roughly N²/2⁶⁴: one in a million at
+/// 4.3 million ids and even odds at 5 billion, far weaker than the full id. Indexed ids keep their 32 bit index and 31
+/// bits of entropy, so ids sharing an index collide at even odds after about 55 thousand of them
+///
+/// Ids made with `UniqueId::get_compact` or `get_compact_with_index` have no entropy outside those bits and convert both
+/// ways without loss, which `TryFrom<UniqueId>` checks. `UniqueId::compact` truncates any id, for lookups where a
+/// collision is tolerable and the full id is kept alongside
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(transparent)]
+pub struct CompactId(u64);
+
+/// A UniqueId known to refer to a `T`, so handles to different kinds of things can't be mixed up. Serialized as the
+/// plain UniqueId
+#[derive(Serialize, Deserialize)]
+#[serde(transparent, bound = "")]
+pub struct Handle<T: ?Sized> {
+    uid: UniqueId,
+    #[serde(skip)]
+    _type: PhantomData<fn() -> T>,
+}
+
+/// A UniqueId with entropy a CompactId can't hold
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotCompact(pub UniqueId);
+
 impl UniqueId {
     /// A randomly generated unique identifier. UniqueId's have an approximate probability of collision of one in 1.990×10¹⁴, meaning,
     /// for every 200 trillion UniqueId's you generate, you should expect at least one collision. Although the uniqueness of a UniqueId is
@@ -33,7 +62,7 @@ impl UniqueId {
                 if self.index_unchecked() == index as u32 {
                     None
                 } else {
-                    Some(UniqueId { _unique: (self._entropy_part() as i128) | index as i128 })
+                    Some(UniqueId { _unique: i128::MIN | (self._entropy_part() as i128) | index as i128 })
                 }
             }
         } else {
@@ -41,6 +70,40 @@ impl UniqueId {
         }
     }
 
+    /// A random id that fits a `CompactId` without loss, with the weaker uniqueness described there
+    pub fn get_compact() -> UniqueId {
+        UniqueId { _unique: Self::_generate_internal() & Self::_compact_mask(false) }
+    }
+
+    /// An indexed id that fits a `CompactId` without loss
+    pub fn get_compact_with_index(index: usize) -> UniqueId {
+        let entropy = Self::_generate_internal() & Self::_compact_mask(true);
+        UniqueId { _unique: i128::MIN | entropy | (index as i128 & Self::_index_mask()) }
+    }
+
+    /// The compact form of the id, dropping any entropy it can't hold
+    pub fn compact(&self) -> CompactId {
+        let entropy = (self._entropy_part() >> 32) as u64;
+        match self._is_indexed() {
+            true => CompactId(1 << 63 | (entropy & 0x7FFF_FFFF) << 32 | unsafe { self.index_unchecked() } as u64),
+            false => CompactId(entropy & 0x7FFF_FFFF_FFFF_FFFF),
+        }
+    }
+
+    /// A typed handle to whatever the id refers to, the caller vouching that it's a `T`
+    pub fn typed<T: ?Sized>(self) -> Handle<T> {
+        Handle::from_uid(self)
+    }
+
+    /// The id as little endian bytes, for binary formats
+    pub fn to_bytes(&self) -> [u8; 16] {
+        self._unique.to_le_bytes()
+    }
+
+    pub fn from_bytes(bytes: [u8; 16]) -> UniqueId {
+        UniqueId { _unique: i128::from_le_bytes(bytes) }
+    }
+
     pub fn index(&self) -> Option<usize> {
         debug_assert!(self._unique.is_negative());
         if self._is_indexed() {
@@ -54,7 +117,7 @@ impl UniqueId {
     
     /// Returns a positive random i128 with the bottom 4 bytes zeroed
     pub(in self) fn _generate_internal() -> i128 {
-        rand::thread_rng().gen_range(0..i128::MAX) & Self::_entropy_mask()
+        crate::random::unique_entropy() & Self::_entropy_mask()
 
         // Todo: It would be nice to do batching of several thousand ID's in a separate thread with a compilation option 
     }
@@ -83,8 +146,168 @@ impl UniqueId {
     const fn _index_mask() -> i128 {
         0xFFFF_FFFF
     }
+
+    /// The entropy bits a compact id keeps
+    #[inline(always)]
+    const fn _compact_mask(indexed: bool) -> i128 {
+        if indexed { 0x7FFF_FFFF_0000_0000 } else { 0x7FFF_FFFF_FFFF_FFFF_0000_0000 }
+    }
+}
+
+impl<T: ?Sized> Handle<T> {
+    /// A handle with a fresh id
+    pub fn new() -> Self {
+        Handle::from_uid(UniqueId::get())
+    }
+
+    /// A handle with a fresh id carrying `index`, see `UniqueId::get_with_index`
+    pub fn with_index(index: usize) -> Self {
+        Handle::from_uid(UniqueId::get_with_index(index))
+    }
+
+    pub fn from_uid(uid: UniqueId) -> Self {
+        Handle { uid, _type: PhantomData }
+    }
+
+    pub fn uid(&self) -> UniqueId {
+        self.uid
+    }
+
+    /// The index the handle's id carries, for indexing straight into storage without hashing
+    pub fn index(&self) -> Option<usize> {
+        match self.uid._is_indexed() {
+            true => Some(unsafe { self.uid.index_unchecked() } as usize),
+            false => None,
+        }
+    }
+
+    /// The same handle with a new index, see `UniqueId::set_index`
+    pub fn with_new_index(&self, index: usize) -> Self {
+        Handle::from_uid(self.uid.set_index(index).unwrap_or(self.uid))
+    }
+
+    /// Reinterprets the handle as one to a `U`
+    pub fn cast<U: ?Sized>(self) -> Handle<U> {
+        Handle::from_uid(self.uid)
+    }
+}
+
+impl<T: ?Sized> Default for Handle<T> {
+    fn default() -> Self {
+        Handle::new()
+    }
+}
+
+impl<T: ?Sized> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: ?Sized> Copy for Handle<T> {}
+
+impl<T: ?Sized> PartialEq for Handle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.uid == other.uid
+    }
+}
+
+impl<T: ?Sized> Eq for Handle<T> {}
+
+impl<T: ?Sized> PartialOrd for Handle<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: ?Sized> Ord for Handle<T> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.uid.cmp(&other.uid)
+    }
+}
+
+impl<T: ?Sized> std::hash::Hash for Handle<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.uid.hash(state)
+    }
+}
+
+impl<T: ?Sized> From<Handle<T>> for UniqueId {
+    fn from(handle: Handle<T>) -> Self {
+        handle.uid
+    }
 }
 
+impl<T: ?Sized> PartialEq<UniqueId> for Handle<T> {
+    fn eq(&self, other: &UniqueId) -> bool {
+        self.uid == *other
+    }
+}
+
+impl<T: ?Sized> TryFrom<Handle<T>> for CompactId {
+    type Error = NotCompact;
+
+    fn try_from(handle: Handle<T>) -> Result<Self, Self::Error> {
+        CompactId::try_from(handle.uid)
+    }
+}
+
+impl CompactId {
+    pub fn from_bits(bits: u64) -> CompactId {
+        CompactId(bits)
+    }
+
+    pub fn to_bits(&self) -> u64 {
+        self.0
+    }
+
+    pub fn is_indexed(&self) -> bool {
+        self.0 >> 63 == 1
+    }
+}
+
+impl From<CompactId> for UniqueId {
+    fn from(compact: CompactId) -> Self {
+        let bits = compact.0 as i128;
+        match compact.is_indexed() {
+            true => UniqueId { _unique: i128::MIN | (bits & UniqueId::_compact_mask(true)) | (bits & UniqueId::_index_mask()) },
+            false => UniqueId { _unique: (bits << 32) & UniqueId::_compact_mask(false) },
+        }
+    }
+}
+
+impl TryFrom<UniqueId> for CompactId {
+    type Error = NotCompact;
+
+    fn try_from(uid: UniqueId) -> Result<Self, Self::Error> {
+        let compact = uid.compact();
+        match UniqueId::from(compact) == uid {
+            true => Ok(compact),
+            false => Err(NotCompact(uid)),
+        }
+    }
+}
+
+impl From<CompactId> for u64 {
+    fn from(compact: CompactId) -> Self {
+        compact.0
+    }
+}
+
+impl From<u64> for CompactId {
+    fn from(bits: u64) -> Self {
+        CompactId(bits)
+    }
+}
+
+impl std::fmt::Display for NotCompact {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} doesn't fit a compact id", self.0)
+    }
+}
+
+impl std::error::Error for NotCompact {}
+
 impl std::fmt::Debug for UniqueId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut ff = f.debug_struct("UniqueId");
@@ -114,6 +337,35 @@ impl std::fmt::Binary for UniqueId {
     }
 }
 
+impl<T: ?Sized> std::fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = std::any::type_name::<T>();
+        write!(f, "Handle<{}>({:?})", name.rsplit("::").next().unwrap_or(name), self.uid)
+    }
+}
+
+impl<T: ?Sized> std::fmt::Display for Handle<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self.uid, f)
+    }
+}
+
+impl std::fmt::Debug for CompactId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CompactId({:#018x})", self.0)
+    }
+}
+
+impl std::fmt::Display for CompactId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_indexed() {
+            write!(f, "CompactId({}:{})", (self.0 >> 32) & 0x7FFF_FFFF, self.0 as u32)
+        } else {
+            write!(f, "CompactId({})", self.0)
+        }
+    }
+}
+
 #[allow(dead_code)]
 #[allow(unused_variables)]
 mod experimental {
@@ -247,4 +499,37 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn compact_ids_round_trip() {
+        let plain = UniqueId::get_compact();
+        let indexed = UniqueId::get_compact_with_index(42);
+        for uid in [plain, indexed] {
+            let compact = CompactId::try_from(uid).unwrap();
+            assert_eq!(UniqueId::from(CompactId::from(u64::from(compact))), uid);
+        }
+        assert_eq!(indexed.index(), Some(42));
+        assert!(CompactId::try_from(indexed).unwrap().is_indexed());
+
+        // A full id only converts lossily, with the index intact
+        let full = UniqueId::get_with_index(7);
+        assert_eq!(CompactId::try_from(full), Err(NotCompact(full)));
+        assert_eq!(UniqueId::from(full.compact()).index(), Some(7));
+        assert_eq!(serde_json::to_string(&CompactId::from_bits(5)).unwrap(), "5");
+    }
+
+    #[test]
+    fn handles_keep_their_type_and_index() {
+        struct Mesh;
+        let handle = Handle::<Mesh>::with_index(3);
+        assert_eq!(handle.index(), Some(3));
+        assert_eq!(handle.with_new_index(9).index(), Some(9));
+        assert_eq!(Handle::<Mesh>::new().index(), None);
+
+        let uid: UniqueId = handle.into();
+        assert_eq!(uid.typed::<Mesh>(), handle);
+        let json = serde_json::to_string(&handle).unwrap();
+        assert_eq!(json, serde_json::to_string(&uid).unwrap());
+        assert_eq!(serde_json::from_str::<Handle<Mesh>>(&json).unwrap(), handle);
+    }
 }
@@ -0,0 +1,411 @@
+//!
+//! Virtual file system
+//!
+//! Engine code addresses files by virtual path, like `/assets/textures/grass.htex` or `/logs/backtrace.txt`, rather
+//! than paths relative to wherever the process happened to start. Each path is resolved against the mounts whose mount
+//! point it falls under, longest mount point first and the most recently mounted first among equals, so a later mount
+//! overlays an earlier one. The global vfs comes with the standard mount points set up for the platform
+//!
+//! | mount point | backed by |
+//! |-------------|-----------|
+//! | `/assets`   | `HADRON_ASSETS_DIR`, or `assets` in the working directory |
+//! | `/user`     | the platform's per-user data directory |
+//! | `/config`   | the platform's per-user config directory |
+//! | `/cache`    | the platform's per-user cache directory |
+//! | `/logs`     | the log directory |
+//!
+//! Reads with `read_async` run on the io worker pool in `workers`, which sizes itself to the disk and serves reads by
+//! priority. Long files can be streamed a piece at a time with `read_range_async`
+//!
+
+use std::{io, path::PathBuf, sync::{mpsc::{self, Receiver, TryRecvError}, Arc, RwLock}};
+
+use once_cell::sync::Lazy;
+
+use crate::debug::log;
+
+pub mod mounts;
+pub mod platform;
+pub mod workers;
+
+pub use mounts::{DirectoryMount, MemoryMount, PackMount};
+pub use workers::{IoConfig, IoPriority, IoStats};
+
+use workers::IoPool;
+
+/// Name of the per-user directories
+pub const APP_NAME: &str = "hadron";
+
+static GLOBAL_VFS: Lazy<Arc<Vfs>> = Lazy::new(|| Arc::new(Vfs::platform()));
+static IO_POOL: Lazy<IoPool> = Lazy::new(IoPool::start);
+
+/// A source of files, mounted into a `Vfs`. Paths given to a mount are relative to its mount point, `/` separated and
+/// already normalized
+pub trait Mount: Send + Sync + 'static {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>>;
+
+    fn exists(&self, path: &str) -> bool;
+
+    /// Names of the entries directly inside `directory`
+    fn list(&self, directory: &str) -> io::Result<Vec<String>>;
+
+    /// Up to `len` bytes from `offset` into the file, fewer at its end. Mounts that can seek should, rather than read
+    /// the whole file as the default does
+    fn read_range(&self, path: &str, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let data = self.read(path)?;
+        let start = (offset as usize).min(data.len());
+        Ok(data[start..(start + len).min(data.len())].to_vec())
+    }
+
+    fn write(&self, _path: &str, _data: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "read-only mount"))
+    }
+
+    fn is_writable(&self) -> bool {
+        false
+    }
+
+    /// Where the file lives on disk, for code that needs a real path like memory mapping
+    fn real_path(&self, _path: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+#[derive(Debug)]
+pub enum VfsError {
+    /// Not absolute, or escapes the root with `..`
+    InvalidPath(String),
+    NotFound(String),
+    /// No mount point covers the path
+    NotMounted(String),
+    /// Every mount covering the path is read-only
+    ReadOnly(String),
+    Io(String, io::Error),
+}
+
+struct MountPoint {
+    point: String,
+    mount: Arc<dyn Mount>,
+}
+
+/// Mount table resolving virtual paths
+#[derive(Default)]
+pub struct Vfs {
+    mounts: RwLock<Vec<MountPoint>>,
+}
+
+/// A read running on the io threads
+pub struct ReadHandle {
+    path: String,
+    rx: Receiver<Result<Vec<u8>, VfsError>>,
+    result: Option<Result<Vec<u8>, VfsError>>,
+}
+
+/// A mount covering a path, and the path relative to it
+type Resolved = (Arc<dyn Mount>, String);
+
+// Impls
+
+impl std::error::Error for VfsError {}
+
+impl std::fmt::Display for VfsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VfsError::InvalidPath(path) => write!(f, "invalid virtual path {}", path),
+            VfsError::NotFound(path) => write!(f, "{} not found", path),
+            VfsError::NotMounted(path) => write!(f, "nothing mounted at {}", path),
+            VfsError::ReadOnly(path) => write!(f, "{} is read-only", path),
+            VfsError::Io(path, err) => write!(f, "unable to access {}: {}", path, err),
+        }
+    }
+}
+
+impl From<VfsError> for io::Error {
+    fn from(error: VfsError) -> Self {
+        match error {
+            VfsError::Io(_, err) => err,
+            VfsError::NotFound(_) | VfsError::NotMounted(_) => io::Error::new(io::ErrorKind::NotFound, error),
+            VfsError::ReadOnly(_) => io::Error::new(io::ErrorKind::PermissionDenied, error),
+            VfsError::InvalidPath(_) => io::Error::new(io::ErrorKind::InvalidInput, error),
+        }
+    }
+}
+
+impl std::fmt::Debug for Vfs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Vfs").field("mounts", &self.mount_points()).finish()
+    }
+}
+
+impl Vfs {
+    /// An empty vfs with nothing mounted
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The standard mount points for this platform
+    pub fn platform() -> Self {
+        let assets = std::env::var_os("HADRON_ASSETS_DIR").map_or_else(|| PathBuf::from("assets"), PathBuf::from);
+        Vfs::new()
+            .with_mount("/assets", DirectoryMount::new(assets))
+            .with_mount("/user", DirectoryMount::new(platform::data_dir(APP_NAME)))
+            .with_mount("/config", DirectoryMount::new(platform::config_dir(APP_NAME)))
+            .with_mount("/cache", DirectoryMount::new(platform::cache_dir(APP_NAME)))
+            .with_mount("/logs", DirectoryMount::new(log::config().directory))
+    }
+
+    pub fn with_mount<M: Mount>(self, point: &str, mount: M) -> Self {
+        self.mount(point, mount).expect("invalid mount point");
+        self
+    }
+
+    /// Mounts `mount` at `point`, over anything already mounted there
+    pub fn mount<M: Mount>(&self, point: &str, mount: M) -> Result<(), VfsError> {
+        let point = normalize(point)?;
+        log::get().info(format!("vfs: mounted {}", point));
+        self.mounts.write().unwrap_or_else(|poisoned| poisoned.into_inner()).push(MountPoint { point, mount: Arc::new(mount) });
+        Ok(())
+    }
+
+    /// Removes the most recent mount at `point`, returning false if there was none
+    pub fn unmount(&self, point: &str) -> bool {
+        let Ok(point) = normalize(point) else {
+            return false
+        };
+        let mut mounts = self.mounts.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match mounts.iter().rposition(|mount| mount.point == point) {
+            Some(index) => { mounts.remove(index); true },
+            None => false,
+        }
+    }
+
+    pub fn mount_points(&self) -> Vec<String> {
+        self.mounts.read().unwrap_or_else(|poisoned| poisoned.into_inner()).iter().map(|mount| mount.point.clone()).collect()
+    }
+
+    pub fn read(&self, path: &str) -> Result<Vec<u8>, VfsError> {
+        self.read_with(path, |mount, relative| mount.read(relative))
+    }
+
+    /// Up to `len` bytes from `offset` into the file, fewer at its end, for streaming long files in pieces
+    pub fn read_range(&self, path: &str, offset: u64, len: usize) -> Result<Vec<u8>, VfsError> {
+        self.read_with(path, |mount, relative| mount.read_range(relative, offset, len))
+    }
+
+    pub fn read_to_string(&self, path: &str) -> Result<String, VfsError> {
+        let data = self.read(path)?;
+        String::from_utf8(data).map_err(|err| VfsError::Io(String::from(path), io::Error::new(io::ErrorKind::InvalidData, err)))
+    }
+
+    /// Reads on a background io thread. Poll the handle from the frame loop or wait on it
+    pub fn read_async(self: &Arc<Self>, path: &str) -> ReadHandle {
+        self.read_async_with(path, IoPriority::Normal)
+    }
+
+    /// Reads on a background io thread, served in order of `priority`
+    pub fn read_async_with(self: &Arc<Self>, path: &str, priority: IoPriority) -> ReadHandle {
+        self.submit_read(path, priority, |vfs, path| vfs.read(path))
+    }
+
+    /// Reads part of a file like `read_range` on a background io thread, served in order of `priority`
+    pub fn read_range_async(self: &Arc<Self>, path: &str, offset: u64, len: usize, priority: IoPriority) -> ReadHandle {
+        self.submit_read(path, priority, move |vfs, path| vfs.read_range(path, offset, len))
+    }
+
+    /// Writes through the first writable mount covering `path`
+    pub fn write(&self, path: &str, data: &[u8]) -> Result<(), VfsError> {
+        let path = normalize(path)?;
+        let candidates = self.resolve(&path)?;
+        let (mount, relative) = candidates.iter().find(|(mount, _)| mount.is_writable()).ok_or_else(|| VfsError::ReadOnly(path.clone()))?;
+        mount.write(relative, data).map_err(|err| VfsError::Io(path, err))
+    }
+
+    pub fn exists(&self, path: &str) -> bool {
+        let Ok(path) = normalize(path) else {
+            return false
+        };
+        self.resolve(&path).is_ok_and(|candidates| candidates.iter().any(|(mount, relative)| mount.exists(relative)))
+    }
+
+    /// Entries directly inside `directory` across every mount covering it, including mount points below it
+    pub fn list(&self, directory: &str) -> Result<Vec<String>, VfsError> {
+        let directory = normalize(directory)?;
+        let mut names: Vec<String> = self.resolve(&directory).unwrap_or_default().iter()
+            .filter_map(|(mount, relative)| mount.list(relative).ok())
+            .flatten()
+            .collect();
+
+        let prefix = if directory == "/" { String::from("/") } else { format!("{}/", directory) };
+        for point in self.mount_points() {
+            if let Some(child) = point.strip_prefix(prefix.as_str()).and_then(|rest| rest.split('/').next()) {
+                names.push(String::from(child));
+            }
+        }
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    /// The on-disk location of `path` in the first mount covering it that has one
+    pub fn real_path(&self, path: &str) -> Result<PathBuf, VfsError> {
+        let path = normalize(path)?;
+        self.resolve(&path)?.iter().find_map(|(mount, relative)| mount.real_path(relative)).ok_or(VfsError::NotFound(path))
+    }
+
+    /// Reads through the first mount covering `path` that has the file
+    fn read_with(&self, path: &str, read: impl Fn(&dyn Mount, &str) -> io::Result<Vec<u8>>) -> Result<Vec<u8>, VfsError> {
+        let path = normalize(path)?;
+        let candidates = self.resolve(&path)?;
+        for (mount, relative) in &candidates {
+            match read(mount.as_ref(), relative) {
+                Ok(data) => return Ok(data),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(VfsError::Io(path, err)),
+            }
+        }
+        Err(VfsError::NotFound(path))
+    }
+
+    fn submit_read(self: &Arc<Self>, path: &str, priority: IoPriority, read: impl FnOnce(&Vfs, &str) -> Result<Vec<u8>, VfsError> + Send + 'static) -> ReadHandle {
+        let (tx, rx) = mpsc::channel();
+        let vfs = Arc::clone(self);
+        let job_path = String::from(path);
+        IO_POOL.submit(priority, Box::new(move || {
+            let result = read(&vfs, &job_path);
+            let bytes = result.as_ref().map_or(0, |data| data.len() as u64);
+            let _ = tx.send(result);
+            bytes
+        }));
+        ReadHandle { path: String::from(path), rx, result: None }
+    }
+
+    /// Mounts covering `path` in lookup order, with the path relative to each
+    fn resolve(&self, path: &str) -> Result<Vec<Resolved>, VfsError> {
+        let mounts = self.mounts.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut candidates: Vec<(usize, usize, Arc<dyn Mount>, String)> = mounts.iter().enumerate()
+            .filter_map(|(order, mount)| relative_to(&mount.point, path).map(|relative| (mount.point.len(), order, Arc::clone(&mount.mount), relative)))
+            .collect();
+        if candidates.is_empty() {
+            return Err(VfsError::NotMounted(String::from(path)))
+        }
+        candidates.sort_by_key(|(point_len, order, _, _)| std::cmp::Reverse((*point_len, *order)));
+        Ok(candidates.into_iter().map(|(_, _, mount, relative)| (mount, relative)).collect())
+    }
+}
+
+impl ReadHandle {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// True once the read has finished
+    pub fn is_ready(&mut self) -> bool {
+        self.poll();
+        self.result.is_some()
+    }
+
+    /// The result if the read has finished
+    pub fn try_take(&mut self) -> Option<Result<Vec<u8>, VfsError>> {
+        self.poll();
+        self.result.take()
+    }
+
+    /// Blocks until the read finishes
+    pub fn wait(mut self) -> Result<Vec<u8>, VfsError> {
+        match self.result.take() {
+            Some(result) => result,
+            None => self.rx.recv().unwrap_or_else(|_| Err(VfsError::Io(self.path.clone(), io::Error::from(io::ErrorKind::BrokenPipe)))),
+        }
+    }
+
+    fn poll(&mut self) {
+        if self.result.is_none() {
+            match self.rx.try_recv() {
+                Ok(result) => self.result = Some(result),
+                Err(TryRecvError::Disconnected) => self.result = Some(Err(VfsError::Io(self.path.clone(), io::Error::from(io::ErrorKind::BrokenPipe)))),
+                Err(TryRecvError::Empty) => (),
+            }
+        }
+    }
+}
+
+/// The process wide vfs
+pub fn get() -> Arc<Vfs> {
+    Arc::clone(&GLOBAL_VFS)
+}
+
+/// The io worker pool's current size and load
+pub fn io_stats() -> IoStats {
+    IO_POOL.stats()
+}
+
+/// Makes a path absolute with `/` separators, resolving `.` and `..`. Paths may not climb above the root
+pub fn normalize(path: &str) -> Result<String, VfsError> {
+    let path = path.replace('\\', "/");
+    if !path.starts_with('/') {
+        return Err(VfsError::InvalidPath(path))
+    }
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => { parts.pop().ok_or_else(|| VfsError::InvalidPath(path.clone()))?; },
+            part => parts.push(part),
+        }
+    }
+    Ok(format!("/{}", parts.join("/")))
+}
+
+/// `path` relative to mount point `point`, if the point covers it
+fn relative_to(point: &str, path: &str) -> Option<String> {
+    if point == "/" {
+        return Some(String::from(&path[1..]))
+    }
+    match path.strip_prefix(point)? {
+        "" => Some(String::new()),
+        rest => rest.strip_prefix('/').map(String::from),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mounts_overlay_and_resolve() {
+        let dir = std::env::temp_dir().join(format!("hadron_vfs_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("textures")).unwrap();
+        std::fs::write(dir.join("textures/a.txt"), "from disk").unwrap();
+        std::fs::write(dir.join("b.txt"), "disk only").unwrap();
+        mounts::write_pack(dir.join("data.pak"), [("textures/c.txt", &b"from pack"[..])]).unwrap();
+
+        let vfs = Arc::new(Vfs::new().with_mount("/assets", DirectoryMount::read_only(&dir)));
+        vfs.mount("/assets", PackMount::open(dir.join("data.pak")).unwrap()).unwrap();
+        vfs.mount("/assets/textures", MemoryMount::new().with_file("a.txt", b"from memory")).unwrap();
+
+        assert_eq!(vfs.read_to_string("/assets/textures/a.txt").unwrap(), "from memory");
+        assert_eq!(vfs.read_to_string("/assets/textures/../b.txt").unwrap(), "disk only");
+        assert_eq!(vfs.read_async("/assets/textures/c.txt").wait().unwrap(), b"from pack");
+        assert_eq!(vfs.list("/assets/textures").unwrap(), vec!["a.txt", "c.txt"]);
+        assert_eq!(vfs.read_range("/assets/textures/a.txt", 5, 3).unwrap(), b"mem");
+        assert_eq!(vfs.read_range_async("/assets/textures/c.txt", 5, 2, IoPriority::High).wait().unwrap(), b"pa");
+        assert_eq!(vfs.read_range("/assets/textures/c.txt", 5, 100).unwrap(), b"pack");
+        assert_eq!(vfs.list("/").unwrap(), vec!["assets"]);
+
+        assert!(matches!(vfs.read("/assets/missing.txt"), Err(VfsError::NotFound(_))));
+        assert!(matches!(vfs.read("/elsewhere/a.txt"), Err(VfsError::NotMounted(_))));
+        assert!(matches!(vfs.read("/assets/../../etc/passwd"), Err(VfsError::InvalidPath(_))));
+
+        // The memory mount is the only writable one covering textures, the directory mount is read-only
+        vfs.write("/assets/textures/new.txt", b"written").unwrap();
+        assert!(!dir.join("textures/new.txt").exists());
+        assert!(matches!(vfs.write("/assets/new.txt", b"nope"), Err(VfsError::ReadOnly(_))));
+
+        assert!(vfs.unmount("/assets/textures"));
+        assert_eq!(vfs.read_to_string("/assets/textures/a.txt").unwrap(), "from disk");
+        assert_eq!(vfs.read_range("/assets/textures/a.txt", 5, 100).unwrap(), b"disk");
+        assert_eq!(vfs.real_path("/assets/b.txt").unwrap(), dir.join("b.txt"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
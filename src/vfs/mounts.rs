@@ -0,0 +1,248 @@
+//!
+//! Mount implementations: loose directories, read-only pack archives and in-memory files
+//!
+
+use std::{collections::BTreeMap, fs::File, io::{self, Read, Seek, SeekFrom}, path::{Path, PathBuf}, sync::{Arc, Mutex, RwLock}};
+
+use super::Mount;
+
+const PACK_MAGIC: [u8; 4] = *b"HPAK";
+
+/// A directory on disk
+#[derive(Debug, Clone)]
+pub struct DirectoryMount {
+    root: PathBuf,
+    writable: bool,
+}
+
+/// Files held in memory, for tests, generated content and overrides
+#[derive(Debug, Default)]
+pub struct MemoryMount {
+    files: RwLock<BTreeMap<String, Arc<[u8]>>>,
+}
+
+/// A read-only archive of files, built with `write_pack`
+///
+/// Layout: the magic, a little endian entry count, then per entry its path length, path, offset and length, followed by
+/// the file data
+#[derive(Debug)]
+pub struct PackMount {
+    path: PathBuf,
+    file: Mutex<File>,
+    entries: BTreeMap<String, (u64, u64)>,
+}
+
+// Impls
+
+impl DirectoryMount {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        DirectoryMount { root: root.into(), writable: true }
+    }
+
+    pub fn read_only<P: Into<PathBuf>>(root: P) -> Self {
+        DirectoryMount { root: root.into(), writable: false }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+}
+
+impl Mount for DirectoryMount {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        std::fs::read(self.root.join(path))
+    }
+
+    fn read_range(&self, path: &str, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut file = File::open(self.root.join(path))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::with_capacity(len);
+        file.take(len as u64).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.root.join(path).exists()
+    }
+
+    fn list(&self, directory: &str) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(self.root.join(directory))? {
+            names.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+        Ok(names)
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        if !self.writable {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "read-only mount"))
+        }
+        let path = self.root.join(path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, data)
+    }
+
+    fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    fn real_path(&self, path: &str) -> Option<PathBuf> {
+        Some(self.root.join(path))
+    }
+}
+
+impl MemoryMount {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_file(self, path: &str, data: &[u8]) -> Self {
+        self.insert(path, data);
+        self
+    }
+
+    pub fn insert(&self, path: &str, data: &[u8]) {
+        self.files.write().unwrap_or_else(|poisoned| poisoned.into_inner()).insert(String::from(path.trim_start_matches('/')), Arc::from(data));
+    }
+}
+
+impl Mount for MemoryMount {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let files = self.files.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        files.get(path).map(|data| data.to_vec()).ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn read_range(&self, path: &str, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let files = self.files.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let data = files.get(path).ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let start = (offset as usize).min(data.len());
+        Ok(data[start..(start + len).min(data.len())].to_vec())
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.files.read().unwrap_or_else(|poisoned| poisoned.into_inner()).contains_key(path)
+    }
+
+    fn list(&self, directory: &str) -> io::Result<Vec<String>> {
+        let files = self.files.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        Ok(list_children(files.keys().map(String::as_str), directory))
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.insert(path, data);
+        Ok(())
+    }
+
+    fn is_writable(&self) -> bool {
+        true
+    }
+}
+
+impl PackMount {
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let mut file = File::open(&path)?;
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), message));
+
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic)?;
+        if magic != PACK_MAGIC {
+            return Err(invalid("not a pack archive"))
+        }
+
+        let mut entries = BTreeMap::new();
+        for _ in 0..read_u32(&mut file)? {
+            let mut name = vec![0u8; read_u32(&mut file)? as usize];
+            file.read_exact(&mut name)?;
+            let name = String::from_utf8(name).map_err(|_| invalid("entry path isn't utf-8"))?;
+            entries.insert(name, (read_u64(&mut file)?, read_u64(&mut file)?));
+        }
+        Ok(PackMount { path, file: Mutex::new(file), entries })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Mount for PackMount {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let (offset, len) = *self.entries.get(path).ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let mut file = self.file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0u8; len as usize];
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn read_range(&self, path: &str, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let (start, entry_len) = *self.entries.get(path).ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let offset = offset.min(entry_len);
+        let mut file = self.file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        file.seek(SeekFrom::Start(start + offset))?;
+        let mut data = vec![0u8; (len as u64).min(entry_len - offset) as usize];
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.entries.contains_key(path)
+    }
+
+    fn list(&self, directory: &str) -> io::Result<Vec<String>> {
+        Ok(list_children(self.entries.keys().map(String::as_str), directory))
+    }
+}
+
+/// Writes a pack archive readable by `PackMount`, with paths relative to the mount point
+pub fn write_pack<'a, P, I>(path: P, files: I) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    I: IntoIterator<Item = (&'a str, &'a [u8])>,
+{
+    let files: Vec<(&str, &[u8])> = files.into_iter().map(|(name, data)| (name.trim_start_matches('/'), data)).collect();
+    let table_len: usize = 8 + files.iter().map(|(name, _)| 4 + name.len() + 16).sum::<usize>();
+
+    let mut bytes = Vec::with_capacity(table_len + files.iter().map(|(_, data)| data.len()).sum::<usize>());
+    bytes.extend_from_slice(&PACK_MAGIC);
+    bytes.extend_from_slice(&(files.len() as u32).to_le_bytes());
+    let mut offset = table_len as u64;
+    for (name, data) in &files {
+        bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.extend_from_slice(&offset.to_le_bytes());
+        bytes.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        offset += data.len() as u64;
+    }
+    files.iter().for_each(|(_, data)| bytes.extend_from_slice(data));
+    std::fs::write(path, bytes)
+}
+
+/// Names directly inside `directory`, out of a flat list of file paths
+fn list_children<'a>(paths: impl Iterator<Item = &'a str>, directory: &str) -> Vec<String> {
+    let prefix = match directory.trim_end_matches('/') {
+        "" => String::new(),
+        directory => format!("{}/", directory),
+    };
+    let mut names: Vec<String> = paths
+        .filter_map(|path| path.strip_prefix(prefix.as_str()))
+        .filter_map(|rest| rest.split('/').next())
+        .map(String::from)
+        .collect();
+    names.dedup();
+    names
+}
+
+fn read_u32(file: &mut File) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    file.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(file: &mut File) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    file.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
@@ -0,0 +1,52 @@
+//!
+//! Platform-correct per-user directories
+//!
+//! Windows uses the roaming and local app data folders, macOS `~/Library`, everything else the XDG base directories.
+//! Each falls back to a directory under the working directory if the platform doesn't provide one
+//!
+
+use std::path::PathBuf;
+
+/// Saves, logs and other persistent per-user data
+pub fn data_dir(app: &str) -> PathBuf {
+    let base = if cfg!(target_os = "windows") {
+        env_path("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        home().map(|home| home.join("Library").join("Application Support"))
+    } else {
+        env_path("XDG_DATA_HOME").or_else(|| home().map(|home| home.join(".local").join("share")))
+    };
+    base.map_or_else(|| PathBuf::from("data"), |base| base.join(app))
+}
+
+/// User editable configuration
+pub fn config_dir(app: &str) -> PathBuf {
+    let base = if cfg!(target_os = "windows") {
+        env_path("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        home().map(|home| home.join("Library").join("Preferences"))
+    } else {
+        env_path("XDG_CONFIG_HOME").or_else(|| home().map(|home| home.join(".config")))
+    };
+    base.map_or_else(|| PathBuf::from("config"), |base| base.join(app))
+}
+
+/// Data that can be regenerated, like the streaming cache
+pub fn cache_dir(app: &str) -> PathBuf {
+    let base = if cfg!(target_os = "windows") {
+        env_path("LOCALAPPDATA")
+    } else if cfg!(target_os = "macos") {
+        home().map(|home| home.join("Library").join("Caches"))
+    } else {
+        env_path("XDG_CACHE_HOME").or_else(|| home().map(|home| home.join(".cache")))
+    };
+    base.map_or_else(|| PathBuf::from("cache"), |base| base.join(app))
+}
+
+fn env_path(name: &str) -> Option<PathBuf> {
+    std::env::var_os(name).filter(|value| !value.is_empty()).map(PathBuf::from)
+}
+
+fn home() -> Option<PathBuf> {
+    env_path(if cfg!(target_os = "windows") { "USERPROFILE" } else { "HOME" })
+}
@@ -0,0 +1,501 @@
+//!
+//! Adaptive io worker pool
+//!
+//! Background reads, like those streaming assets in, run on a pool of io threads sized to what the disk can take. Each
+//! `scale_interval_ms` the pool looks at its queue and the bytes read since the last look. While reads are queued up
+//! behind busy workers it adds a worker, and keeps it only if throughput rose by at least `min_gain`. A fast NVMe drive
+//! ends up with many readers in flight, while on a slow disk the extra worker is dropped again rather than left
+//! thrashing the heads and starving the readers already there. Once the queue drains, idle workers exit one interval
+//! at a time down to `min_workers`
+//!
+//! The bounds come from the `io` config section and apply as soon as they change
+//!
+//! Every request has an `IoPriority`, and workers take the highest class queued first. Prefetch reads are held back
+//! while a critical read is queued or running, and a critical read arriving while every worker is busy preempts the
+//! prefetching: it starts at once on a worker borrowed past the target, and the next prefetch read to finish hands
+//! its slot back rather than starting another. The time from submitting a request to its completion is tracked per
+//! class and reported with `IoStats`
+//!
+
+use std::{collections::{BTreeMap, VecDeque}, sync::{Arc, Condvar, Mutex, MutexGuard}, time::{Duration, Instant}};
+
+use serde::{Serialize, Deserialize};
+
+use crate::config::{self, ConfigSection};
+use crate::debug::{log, latency::LatencyStats};
+
+const IO_THREAD_NAME: &str = "hadron vfs io";
+
+/// Most recent requests per class the latency stats cover
+const LATENCY_SAMPLES: usize = 128;
+
+/// Every class, lowest first
+const PRIORITIES: [IoPriority; 4] = [IoPriority::Prefetch, IoPriority::Normal, IoPriority::High, IoPriority::Critical];
+
+/// A unit of io, returning the number of bytes it moved
+pub(crate) type IoJob = Box<dyn FnOnce() -> u64 + Send>;
+
+/// Io pool options from the `io` config section
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct IoConfig {
+    /// Workers kept alive while idle
+    pub min_workers: usize,
+    /// Most reads in flight at once, however fast the disk
+    pub max_workers: usize,
+    /// How often throughput is measured and the pool resized
+    pub scale_interval_ms: u64,
+    /// Fraction throughput has to rise by for an added worker to be kept
+    pub min_gain: f64,
+}
+
+/// Order io requests are served in, highest first
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum IoPriority {
+    /// Data that may be needed soon, dropped behind anything else and preempted by critical reads
+    Prefetch,
+    #[default]
+    Normal,
+    High,
+    /// Something is stalled until it arrives, like a state transition or the player's surroundings
+    Critical,
+}
+
+/// A snapshot of the pool
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct IoStats {
+    pub workers: usize,
+    pub busy: usize,
+    pub queued: usize,
+    /// Bytes per second over the last interval
+    pub throughput: f64,
+    /// Times a critical read borrowed a worker from prefetching
+    pub preemptions: u64,
+    /// Submit to completion time of each class with completed requests
+    pub latency: BTreeMap<IoPriority, LatencyStats>,
+}
+
+/// What the pool saw over one interval
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Sample {
+    queued: usize,
+    busy: usize,
+    bytes: u64,
+    elapsed: Duration,
+}
+
+/// Decides the worker count from interval to interval
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Scaling {
+    /// Throughput before the last worker was added, while waiting to see if it helped
+    before_grow: Option<f64>,
+    /// Worker count past which adding workers didn't help, until the queue next drains
+    ceiling: Option<usize>,
+}
+
+pub(crate) struct IoPool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<PoolState>,
+    available: Condvar,
+}
+
+struct PoolState {
+    config: IoConfig,
+    /// Waiting requests of each class, indexed by priority
+    queues: [VecDeque<Queued>; PRIORITIES.len()],
+    /// Requests of each class being worked on
+    running: [usize; PRIORITIES.len()],
+    workers: usize,
+    target: usize,
+    /// Workers lent to critical reads, given back by the next prefetch read to finish
+    borrowed: usize,
+    bytes: u64,
+    since: Instant,
+    throughput: f64,
+    scaling: Scaling,
+    latency: [VecDeque<Duration>; PRIORITIES.len()],
+    preemptions: u64,
+}
+
+struct Queued {
+    job: IoJob,
+    priority: IoPriority,
+    submitted: Instant,
+}
+
+// Impls
+
+impl Default for IoConfig {
+    fn default() -> Self {
+        IoConfig { min_workers: 1, max_workers: 8, scale_interval_ms: 250, min_gain: 0.1 }
+    }
+}
+
+impl ConfigSection for IoConfig {
+    const NAME: &'static str = "io";
+}
+
+impl IoConfig {
+    /// The bounds with at least one worker and the maximum no lower than the minimum
+    pub fn bounds(&self) -> (usize, usize) {
+        let min = self.min_workers.max(1);
+        (min, self.max_workers.max(min))
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_millis(self.scale_interval_ms.max(1))
+    }
+}
+
+impl IoPriority {
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl Scaling {
+    /// The worker count for the next interval, given `workers` ran through `sample`
+    fn next(&mut self, config: &IoConfig, workers: usize, sample: Sample) -> usize {
+        let (min, max) = config.bounds();
+        let throughput = sample.bytes as f64 / sample.elapsed.as_secs_f64().max(f64::EPSILON);
+
+        if let Some(before) = self.before_grow.take() {
+            // The disk was already saturated, more readers only queue up inside it
+            if throughput < before * (1.0 + config.min_gain) {
+                self.ceiling = Some(workers.saturating_sub(1).max(min));
+                return workers.saturating_sub(1).clamp(min, max)
+            }
+        }
+
+        if sample.queued > 0 && sample.busy >= workers {
+            if workers < max && self.ceiling.is_none_or(|ceiling| workers < ceiling) {
+                self.before_grow = Some(throughput);
+                return workers + 1
+            }
+            return workers.clamp(min, max)
+        }
+
+        if sample.queued == 0 {
+            self.ceiling = None;
+            if sample.busy < workers {
+                return workers.saturating_sub(1).clamp(min, max)
+            }
+        }
+        workers.clamp(min, max)
+    }
+}
+
+impl IoPool {
+    /// Starts the pool with the `io` config section's minimum, following later changes to it
+    pub(crate) fn start() -> Self {
+        let config: IoConfig = config::get().section();
+        let pool = IoPool::with_config(config);
+        let shared = Arc::downgrade(&pool.shared);
+        config::get().watch(IoConfig::NAME, move |config, _| {
+            if let Some(shared) = shared.upgrade() {
+                shared.reconfigure(config.section());
+            }
+        });
+        pool
+    }
+
+    pub(crate) fn with_config(config: IoConfig) -> Self {
+        let shared = Arc::new(Shared { state: Mutex::new(PoolState::new(config)), available: Condvar::new() });
+        let spawn = shared.lock().grow();
+        shared.spawn_workers(spawn);
+        IoPool { shared }
+    }
+
+    pub(crate) fn submit(&self, priority: IoPriority, job: IoJob) {
+        let spawn = {
+            let mut state = self.shared.lock();
+            let now = Instant::now();
+            state.queues[priority.index()].push_back(Queued { job, priority, submitted: now });
+            let preempt = match priority {
+                IoPriority::Critical => state.preempt(),
+                _ => 0,
+            };
+            state.rescale(now) + preempt
+        };
+        self.shared.available.notify_one();
+        self.shared.spawn_workers(spawn);
+    }
+
+    pub(crate) fn stats(&self) -> IoStats {
+        let state = self.shared.lock();
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let latency = PRIORITIES.iter().filter_map(|priority| {
+            let samples = &state.latency[priority.index()];
+            let stats = LatencyStats {
+                last_ms: ms(*samples.back()?),
+                average_ms: ms(samples.iter().sum::<Duration>() / samples.len() as u32),
+                max_ms: ms(samples.iter().max().copied()?),
+                samples: samples.len(),
+            };
+            Some((*priority, stats))
+        }).collect();
+        IoStats {
+            workers: state.workers,
+            busy: state.busy(),
+            queued: state.queued(),
+            throughput: state.throughput,
+            preemptions: state.preemptions,
+            latency,
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn reconfigure(self: &Arc<Self>, config: IoConfig) {
+        let spawn = {
+            let mut state = self.lock();
+            let (min, max) = config.bounds();
+            state.config = config;
+            state.target = state.target.clamp(min, max);
+            state.grow()
+        };
+        self.available.notify_all();
+        self.spawn_workers(spawn);
+    }
+
+    fn spawn_workers(self: &Arc<Self>, count: usize) {
+        for _ in 0..count {
+            let shared = Arc::clone(self);
+            let spawned = std::thread::Builder::new()
+                .name(String::from(IO_THREAD_NAME))
+                .spawn(move || shared.work());
+            if let Err(err) = spawned {
+                log::get().with_topic("vfs").warn(format!("unable to spawn io worker: {}", err));
+                self.lock().workers -= 1;
+            }
+        }
+    }
+
+    fn work(self: Arc<Self>) {
+        let mut state = self.lock();
+        loop {
+            if state.workers > state.target + state.borrowed {
+                state.workers -= 1;
+                return
+            }
+            let Some(queued) = state.next_job() else {
+                let interval = state.config.interval();
+                state = self.available.wait_timeout(state, interval).unwrap_or_else(|poisoned| poisoned.into_inner()).0;
+                // Idle workers keep sampling so the pool shrinks without new reads coming in
+                let spawn = state.rescale(Instant::now());
+                if spawn > 0 {
+                    drop(state);
+                    self.spawn_workers(spawn);
+                    state = self.lock();
+                }
+                continue
+            };
+
+            let Queued { job, priority, submitted } = queued;
+            state.running[priority.index()] += 1;
+            drop(state);
+            let bytes = job();
+            state = self.lock();
+            state.running[priority.index()] -= 1;
+            state.bytes += bytes;
+            state.record_latency(priority, submitted.elapsed());
+
+            match priority {
+                IoPriority::Prefetch if state.borrowed > 0 => {
+                    state.borrowed -= 1;
+                    state.workers -= 1;
+                    return
+                },
+                // Releases the prefetch reads held back behind it
+                IoPriority::Critical if !state.is_critical_pending() => self.available.notify_all(),
+                _ => (),
+            }
+        }
+    }
+}
+
+impl PoolState {
+    /// An empty pool aiming for the configured minimum, with no workers started yet
+    fn new(config: IoConfig) -> Self {
+        let (min, _) = config.bounds();
+        PoolState {
+            config,
+            queues: Default::default(),
+            running: [0; PRIORITIES.len()],
+            workers: 0,
+            target: min,
+            borrowed: 0,
+            bytes: 0,
+            since: Instant::now(),
+            throughput: 0.0,
+            scaling: Scaling::default(),
+            latency: Default::default(),
+            preemptions: 0,
+        }
+    }
+
+    /// Takes a sample once an interval has passed and resizes the pool, returning how many workers to spawn
+    fn rescale(&mut self, now: Instant) -> usize {
+        let elapsed = now.duration_since(self.since);
+        if elapsed < self.config.interval() {
+            return 0
+        }
+        let sample = Sample { queued: self.queued(), busy: self.busy(), bytes: self.bytes, elapsed };
+        self.throughput = sample.bytes as f64 / elapsed.as_secs_f64();
+        let config = self.config;
+        let target = self.scaling.next(&config, self.target, sample);
+        if target != self.target {
+            log::get().with_topic("vfs").info(format!("io workers {} -> {} at {:.1} MB/s with {} queued", self.target, target, self.throughput / 1e6, sample.queued));
+        }
+        self.target = target;
+        self.bytes = 0;
+        self.since = now;
+        self.grow()
+    }
+
+    /// Lends a critical read a worker if every worker is busy and some with prefetching, returning how many to spawn
+    fn preempt(&mut self) -> usize {
+        if self.busy() < self.workers || self.running[IoPriority::Prefetch.index()] <= self.borrowed {
+            return 0
+        }
+        self.borrowed += 1;
+        self.workers += 1;
+        self.preemptions += 1;
+        1
+    }
+
+    /// The oldest request of the highest class queued, holding back prefetching while a critical read is pending
+    fn next_job(&mut self) -> Option<Queued> {
+        let hold_prefetch = self.is_critical_pending();
+        PRIORITIES.iter().rev()
+            .filter(|priority| !(hold_prefetch && **priority == IoPriority::Prefetch))
+            .find_map(|priority| self.queues[priority.index()].pop_front())
+    }
+
+    fn is_critical_pending(&self) -> bool {
+        let critical = IoPriority::Critical.index();
+        self.running[critical] > 0 || !self.queues[critical].is_empty()
+    }
+
+    fn busy(&self) -> usize {
+        self.running.iter().sum()
+    }
+
+    fn queued(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+
+    fn record_latency(&mut self, priority: IoPriority, latency: Duration) {
+        let samples = &mut self.latency[priority.index()];
+        if samples.len() == LATENCY_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(latency);
+    }
+
+    /// Counts the workers needed to reach the target as started, returning how many to spawn
+    fn grow(&mut self) -> usize {
+        let spawn = self.target.saturating_sub(self.workers);
+        self.workers += spawn;
+        spawn
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn workers_are_added_only_while_they_raise_throughput() {
+        let config = IoConfig { min_workers: 1, max_workers: 4, ..IoConfig::default() };
+        let mut scaling = Scaling::default();
+        let second = Duration::from_secs(1);
+        let backlog = |bytes| Sample { queued: 10, busy: 4, bytes, elapsed: second };
+
+        // A fast disk keeps scaling up to the maximum while reads are queued
+        assert_eq!(scaling.next(&config, 1, backlog(100)), 2);
+        assert_eq!(scaling.next(&config, 2, backlog(200)), 3);
+        assert_eq!(scaling.next(&config, 3, backlog(300)), 4);
+        assert_eq!(scaling.next(&config, 4, backlog(400)), 4);
+
+        // A saturated disk gives the extra worker back and stays there while the backlog lasts
+        let mut scaling = Scaling::default();
+        assert_eq!(scaling.next(&config, 2, backlog(100)), 3);
+        assert_eq!(scaling.next(&config, 3, backlog(102)), 2);
+        assert_eq!(scaling.next(&config, 2, backlog(100)), 2);
+
+        // Idle workers wind down to the minimum, and a drained queue lets the pool probe again
+        let idle = Sample { queued: 0, busy: 0, bytes: 0, elapsed: second };
+        assert_eq!(scaling.next(&config, 2, idle), 1);
+        assert_eq!(scaling.next(&config, 1, idle), 1);
+        assert_eq!(scaling.next(&config, 2, backlog(100)), 3);
+    }
+
+    #[test]
+    fn jobs_are_taken_by_class_holding_prefetch_behind_critical_reads() {
+        let mut state = PoolState::new(IoConfig::default());
+        let submitted = Instant::now();
+        for (priority, bytes) in [(IoPriority::Prefetch, 1), (IoPriority::Normal, 2), (IoPriority::Critical, 3), (IoPriority::Normal, 4)] {
+            state.queues[priority.index()].push_back(Queued { job: Box::new(move || bytes), priority, submitted });
+        }
+        let next = |state: &mut PoolState| state.next_job().map(|queued| (queued.job)());
+
+        assert_eq!(next(&mut state), Some(3));
+        // While the critical read runs the normal reads go ahead in order, but the prefetch read waits
+        state.running[IoPriority::Critical.index()] += 1;
+        assert_eq!(next(&mut state), Some(2));
+        assert_eq!(next(&mut state), Some(4));
+        assert_eq!(next(&mut state), None);
+        state.running[IoPriority::Critical.index()] -= 1;
+        assert_eq!(next(&mut state), Some(1));
+
+        for _ in 0..LATENCY_SAMPLES + 1 {
+            state.record_latency(IoPriority::High, Duration::from_millis(1));
+        }
+        assert_eq!(state.latency[IoPriority::High.index()].len(), LATENCY_SAMPLES);
+    }
+
+    #[test]
+    fn critical_reads_preempt_prefetching() {
+        let pool = IoPool::with_config(IoConfig { min_workers: 1, max_workers: 1, ..IoConfig::default() });
+        let (done_tx, done) = std::sync::mpsc::channel();
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        let blocked = Mutex::new(blocked);
+
+        // The only worker is stuck on a prefetch read with another one queued behind it
+        let (started_tx, started) = std::sync::mpsc::channel();
+        let tx = done_tx.clone();
+        pool.submit(IoPriority::Prefetch, Box::new(move || { started_tx.send(()).unwrap(); blocked.lock().unwrap().recv().unwrap(); tx.send("prefetch 1").unwrap(); 0 }));
+        started.recv().unwrap();
+        let tx = done_tx.clone();
+        pool.submit(IoPriority::Prefetch, Box::new(move || { tx.send("prefetch 2").unwrap(); 0 }));
+        let tx = done_tx.clone();
+        pool.submit(IoPriority::Critical, Box::new(move || { tx.send("critical").unwrap(); 64 }));
+
+        assert_eq!(done.recv().unwrap(), "critical");
+        release.send(()).unwrap();
+        let mut prefetched: Vec<&str> = done.iter().take(2).collect();
+        prefetched.sort_unstable();
+        assert_eq!(prefetched, vec!["prefetch 1", "prefetch 2"]);
+
+        // Jobs report back before their worker does
+        for _ in 0..1000 {
+            if pool.stats().latency.get(&IoPriority::Prefetch).is_some_and(|latency| latency.samples == 2) {
+                break
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let stats = pool.stats();
+        assert_eq!((stats.workers, stats.preemptions), (1, 1));
+        assert_eq!(stats.latency[&IoPriority::Prefetch].samples, 2);
+        assert!(stats.latency.contains_key(&IoPriority::Critical) && !stats.latency.contains_key(&IoPriority::Normal));
+    }
+}